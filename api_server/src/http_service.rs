@@ -1,33 +1,48 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::cell::Cell;
+use std::fmt;
+use std::net::Ipv4Addr;
 use std::rc::Rc;
 use std::result;
 use std::str;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 
-use futures::future::{self, Either};
+use futures::future::{self, Either, Loop};
+use futures::sync::oneshot;
 use futures::{Future, Stream};
 
 use hyper::{self, Chunk, Headers, Method, StatusCode};
-use serde_json;
+use serde_json::{self, Value};
 
 use logger::{Metric, METRICS};
 use mmds::data_store::{self, Mmds};
 use request::actions::ActionBody;
+use request::batch::{Batch, BatchBody, BatchResult};
 use request::drive::PatchDrivePayload;
+use request::mmds::MmdsConfig;
 use request::{GenerateHyperResponse, IntoParsedRequest, ParsedRequest};
 use sys_util::EventFd;
+use vmm::event_counter::EventCounter;
+#[cfg(feature = "vsock")]
+use vmm::guest_agent::AgentCommand;
 use vmm::vmm_config::boot_source::BootSourceConfig;
 use vmm::vmm_config::drive::BlockDeviceConfig;
-use vmm::vmm_config::instance_info::InstanceInfo;
+use vmm::vmm_config::instance_info::{InstanceInfo, InstanceState};
 use vmm::vmm_config::logger::LoggerConfig;
 use vmm::vmm_config::machine_config::VmConfig;
 use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+use vmm::vmm_config::snapshot::SnapshotConfig;
+#[cfg(feature = "vhost-user-block")]
+use vmm::vmm_config::vhost_user_block::VhostUserBlockDeviceConfig;
+#[cfg(feature = "virtio-fs")]
+use vmm::vmm_config::virtio_fs::VirtioFsDeviceConfig;
 #[cfg(feature = "vsock")]
 use vmm::vmm_config::vsock::VsockDeviceConfig;
-use vmm::VmmAction;
+use vmm::{VmmAction, VmmActionError};
 
 fn build_response_base<B: Into<hyper::Body>>(
     status: StatusCode,
@@ -49,6 +64,14 @@ pub fn empty_response(status: StatusCode) -> hyper::Response {
     build_response_base::<String>(status, None, None)
 }
 
+// An HTTP response carrying binary data.
+#[cfg(target_arch = "aarch64")]
+pub fn octet_stream_response<T: Into<hyper::Body>>(status: StatusCode, body: T) -> hyper::Response {
+    let mut headers = Headers::new();
+    headers.set(hyper::header::ContentType::octet_stream());
+    build_response_base(status, Some(headers), Some(body))
+}
+
 // An HTTP response which also includes a body.
 pub fn json_response<T: Into<hyper::Body>>(status: StatusCode, body: T) -> hyper::Response {
     let mut headers = Headers::new();
@@ -82,35 +105,34 @@ enum Error<'a> {
     SerdeJson(serde_json::Error),
 }
 
-// It's convenient to turn errors into HTTP responses directly.
-impl<'a> Into<hyper::Response> for Error<'a> {
-    fn into(self) -> hyper::Response {
-        match self {
-            Error::Generic(status, msg) => json_response(status, json_fault_message(msg)),
-            Error::EmptyID => json_response(
-                StatusCode::BadRequest,
-                json_fault_message("The ID cannot be empty."),
+impl<'a> fmt::Display for Error<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Generic(_, ref msg) => write!(f, "{}", msg),
+            Error::EmptyID => write!(f, "The ID cannot be empty."),
+            Error::InvalidID => write!(
+                f,
+                "API Resource IDs can only contain alphanumeric characters and underscores."
             ),
-            Error::InvalidID => json_response(
-                StatusCode::BadRequest,
-                json_fault_message(
-                    "API Resource IDs can only contain alphanumeric characters and underscores.",
-                ),
-            ),
-            Error::InvalidPathMethod(path, method) => json_response(
-                StatusCode::BadRequest,
-                json_fault_message(format!(
-                    "Invalid request method and/or path: {} {}",
-                    method, path
-                )),
-            ),
-            Error::SerdeJson(e) => {
-                json_response(StatusCode::BadRequest, json_fault_message(e.to_string()))
+            Error::InvalidPathMethod(path, ref method) => {
+                write!(f, "Invalid request method and/or path: {} {}", method, path)
             }
+            Error::SerdeJson(ref e) => write!(f, "{}", e),
         }
     }
 }
 
+// It's convenient to turn errors into HTTP responses directly.
+impl<'a> Into<hyper::Response> for Error<'a> {
+    fn into(self) -> hyper::Response {
+        let status = match self {
+            Error::Generic(ref status, _) => *status,
+            _ => StatusCode::BadRequest,
+        };
+        json_response(status, json_fault_message(self.to_string()))
+    }
+}
+
 type Result<'a, T> = result::Result<T, Error<'a>>;
 
 // Turns a GET/PUT /actions HTTP request into a ParsedRequest
@@ -135,6 +157,28 @@ fn parse_actions_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<
     }
 }
 
+// Turns a PUT /batch HTTP request into a ParsedRequest
+fn parse_batch_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens.len() {
+        1 if method == Method::Put => {
+            METRICS.put_api_requests.batch_count.inc();
+            Ok(serde_json::from_slice::<BatchBody>(body.as_ref())
+                .map_err(|e| {
+                    METRICS.put_api_requests.batch_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(None, method)
+                .map_err(|msg| {
+                    METRICS.put_api_requests.batch_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, msg)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
 // This function is supposed to do id validation for requests.
 fn checked_id(id: &str) -> Result<&str> {
     // todo: are there any checks we want to do on id's?
@@ -186,20 +230,75 @@ fn parse_mmds_request<'a>(
 ) -> Result<'a, ParsedRequest> {
     let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
 
-    match path_tokens[1..].len() {
-        0 if method == Method::Get => Ok(ParsedRequest::GetMMDS),
-        0 if method == Method::Put => match serde_json::from_slice(&body) {
-            Ok(val) => Ok(ParsedRequest::PutMMDS(val)),
+    if path_tokens[1..].len() == 1 && path_tokens[1] == "config" {
+        if method != Method::Put {
+            return Err(Error::InvalidPathMethod(path, method));
+        }
+        return serde_json::from_slice::<MmdsConfig>(body)
+            .map_err(Error::SerdeJson)?
+            .into_parsed_request(None, method)
+            .map_err(|s| Error::Generic(StatusCode::BadRequest, s));
+    }
+
+    // The data store served to the guest requests coming from a given source address lives
+    // under /mmds/namespaces/{source_address}.
+    let namespace = match path_tokens[1..].len() {
+        0 => None,
+        2 if path_tokens[1] == "namespaces" => {
+            let source = path_tokens[2].parse::<Ipv4Addr>().map_err(|_| {
+                Error::Generic(
+                    StatusCode::BadRequest,
+                    format!("Invalid MMDS namespace address: {}", path_tokens[2]),
+                )
+            })?;
+            Some(source)
+        }
+        _ => return Err(Error::InvalidPathMethod(path, method)),
+    };
+
+    match method {
+        Method::Get => Ok(ParsedRequest::GetMMDS(namespace)),
+        Method::Put => match serde_json::from_slice(&body) {
+            Ok(val) => Ok(ParsedRequest::PutMMDS(namespace, val)),
             Err(e) => Err(Error::SerdeJson(e)),
         },
-        0 if method == Method::Patch => match serde_json::from_slice(&body) {
-            Ok(val) => Ok(ParsedRequest::PatchMMDS(val)),
+        Method::Patch => match serde_json::from_slice(&body) {
+            Ok(val) => Ok(ParsedRequest::PatchMMDS(namespace, val)),
             Err(e) => Err(Error::SerdeJson(e)),
         },
         _ => Err(Error::InvalidPathMethod(path, method)),
     }
 }
 
+// Turns a GET /devices HTTP request into a ParsedRequest
+fn parse_devices_req<'a>(path: &'a str, method: Method) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        0 if method == Method::Get => {
+            METRICS.get_api_requests.devices_count.inc();
+            let (sender, receiver) = oneshot::channel();
+            Ok(ParsedRequest::Sync(VmmAction::GetDevices(sender), receiver))
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
+// Turns a GET /fdt HTTP request into a ParsedRequest
+#[cfg(target_arch = "aarch64")]
+fn parse_fdt_req<'a>(path: &'a str, method: Method) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        0 if method == Method::Get => {
+            METRICS.get_api_requests.fdt_count.inc();
+            let (sender, receiver) = oneshot::channel();
+            Ok(ParsedRequest::Sync(VmmAction::GetFdt(sender), receiver))
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
 // Turns a GET/PUT /drives HTTP request into a ParsedRequest
 fn parse_drives_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a, ParsedRequest> {
     let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
@@ -283,6 +382,22 @@ fn parse_machine_config_req<'a>(
                 mem_size_mib: None,
                 ht_enabled: None,
                 cpu_template: None,
+                cpu_topology: None,
+                prefault_memory: None,
+                zero_memory: None,
+                numa_node: None,
+                vcpu_affinity: None,
+                irq_affinity: None,
+                vcpu_scheduling: None,
+                high_mmio: None,
+                reserved_memory: None,
+                gic_version: None,
+                serial_enabled: None,
+                rtc_base_time: None,
+                clock_source: None,
+                sev_enabled: None,
+                apic_mode: None,
+                guest_panic_action: None,
             };
             Ok(empty_machine_config
                 .into_parsed_request(None, method)
@@ -361,12 +476,112 @@ fn parse_netif_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a
                     Error::Generic(StatusCode::BadRequest, s)
                 })?)
         }
+        1 if method == Method::Delete => {
+            METRICS.delete_api_requests.network_count.inc();
+
+            let (sender, receiver) = oneshot::channel();
+            Ok(ParsedRequest::Sync(
+                VmmAction::DetachNetworkDevice(id_from_path.to_string(), sender),
+                receiver,
+            ))
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
+#[cfg(feature = "vhost-user-block")]
+// Turns a PUT /vhost-user-drives HTTP request into a ParsedRequest.
+fn parse_vhost_user_drives_req<'a>(
+    path: &'a str,
+    method: Method,
+    body: &Chunk,
+) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+    let id_from_path = if path_tokens.len() > 1 {
+        checked_id(path_tokens[1])?
+    } else {
+        return Err(Error::EmptyID);
+    };
+
+    match path_tokens[1..].len() {
+        1 if method == Method::Put => {
+            METRICS.put_api_requests.drive_count.inc();
+
+            let device_cfg =
+                serde_json::from_slice::<VhostUserBlockDeviceConfig>(body).map_err(|e| {
+                    METRICS.put_api_requests.drive_fails.inc();
+                    Error::SerdeJson(e)
+                })?;
+            Ok(device_cfg
+                .into_parsed_request(Some(id_from_path.to_string()), method)
+                .map_err(|s| {
+                    METRICS.put_api_requests.drive_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
+#[cfg(feature = "virtio-fs")]
+// Turns a PUT /fs HTTP request into a ParsedRequest.
+fn parse_fs_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+    let id_from_path = if path_tokens.len() > 1 {
+        checked_id(path_tokens[1])?
+    } else {
+        return Err(Error::EmptyID);
+    };
+
+    match path_tokens[1..].len() {
+        1 if method == Method::Put => {
+            METRICS.put_api_requests.drive_count.inc();
+
+            let device_cfg = serde_json::from_slice::<VirtioFsDeviceConfig>(body).map_err(|e| {
+                METRICS.put_api_requests.drive_fails.inc();
+                Error::SerdeJson(e)
+            })?;
+            Ok(device_cfg
+                .into_parsed_request(Some(id_from_path.to_string()), method)
+                .map_err(|s| {
+                    METRICS.put_api_requests.drive_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
+// Turns a PUT /snapshot/create or PUT /snapshot/load HTTP request into a ParsedRequest.
+fn parse_snapshot_req<'a>(
+    path: &'a str,
+    method: Method,
+    body: &Chunk,
+) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        1 if (path_tokens[1] == "create" || path_tokens[1] == "load") && method == Method::Put => {
+            METRICS.put_api_requests.snapshot_count.inc();
+
+            let snapshot_cfg = serde_json::from_slice::<SnapshotConfig>(body).map_err(|e| {
+                METRICS.put_api_requests.snapshot_fails.inc();
+                Error::SerdeJson(e)
+            })?;
+            let (sender, receiver) = oneshot::channel();
+            let action = if path_tokens[1] == "create" {
+                VmmAction::CreateSnapshot(snapshot_cfg, sender)
+            } else {
+                VmmAction::LoadSnapshot(snapshot_cfg, sender)
+            };
+            Ok(ParsedRequest::Sync(action, receiver))
+        }
         _ => Err(Error::InvalidPathMethod(path, method)),
     }
 }
 
 #[cfg(feature = "vsock")]
-// Turns a GET/PUT /vsocks HTTP request into a ParsedRequest.
+// Turns a PUT /vsocks or a PUT /vsocks/{id}/agent HTTP request into a ParsedRequest.
 fn parse_vsocks_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a, ParsedRequest> {
     let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
     let id_from_path = if path_tokens.len() > 1 {
@@ -383,6 +598,14 @@ fn parse_vsocks_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'
                 METRICS.put_api_requests.network_fails.inc();
                 Error::Generic(StatusCode::BadRequest, s)
             })?),
+        2 if path_tokens[2] == "agent" && method == Method::Put => {
+            let command = serde_json::from_slice::<AgentCommand>(body).map_err(Error::SerdeJson)?;
+            let (sender, receiver) = oneshot::channel();
+            Ok(ParsedRequest::Sync(
+                VmmAction::SendAgentCommand(id_from_path.to_string(), command, sender),
+                receiver,
+            ))
+        }
         _ => Err(Error::InvalidPathMethod(path, method)),
     }
 }
@@ -426,12 +649,21 @@ fn parse_request<'a>(method: Method, path: &'a str, body: &Chunk) -> Result<'a,
 
     match path_tokens[0] {
         "actions" => parse_actions_req(path, method, body),
+        "batch" => parse_batch_req(path, method, body),
         "boot-source" => parse_boot_source_req(path, method, body),
+        "devices" => parse_devices_req(path, method),
         "drives" => parse_drives_req(path, method, body),
+        #[cfg(target_arch = "aarch64")]
+        "fdt" => parse_fdt_req(path, method),
+        #[cfg(feature = "virtio-fs")]
+        "fs" => parse_fs_req(path, method, body),
         "logger" => parse_logger_req(path, method, body),
         "machine-config" => parse_machine_config_req(path, method, body),
         "network-interfaces" => parse_netif_req(path, method, body),
         "mmds" => parse_mmds_request(path, method, body),
+        "snapshot" => parse_snapshot_req(path, method, body),
+        #[cfg(feature = "vhost-user-block")]
+        "vhost-user-drives" => parse_vhost_user_drives_req(path, method, body),
         #[cfg(feature = "vsock")]
         "vsocks" => parse_vsocks_req(path, method, body),
         _ => Err(Error::InvalidPathMethod(path, method)),
@@ -449,8 +681,155 @@ fn send_to_vmm(
     send_event.write(1).map_err(|_| ())
 }
 
+/// Errors associated with handling a request which doesn't come from the API socket.
+#[derive(Debug)]
+pub enum RequestError {
+    /// The request is malformed or not supported.
+    Invalid(String),
+    /// The VMM failed to carry out the request.
+    Vmm(VmmActionError),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RequestError::Invalid(ref msg) => write!(f, "{}", msg),
+            RequestError::Vmm(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Handles a request which doesn't come from the API socket, such as one read from a
+/// configuration file, the same way as `ApiServerHttpService` does. Waits for the VMM to process
+/// the request and returns the error if it fails.
+pub fn handle_request(
+    method: Method,
+    path: &str,
+    body: &Chunk,
+    mmds_info: &Mutex<Mmds>,
+    api_request_sender: &mpsc::Sender<Box<VmmAction>>,
+    vmm_send_event: &EventFd,
+) -> result::Result<(), RequestError> {
+    let body_desc = match method {
+        Method::Get => None,
+        _ => Some(String::from_utf8_lossy(&body.to_vec()).to_string()),
+    };
+    let description = describe(&method, path, &body_desc);
+
+    match parse_request(method, path, body).map_err(|e| RequestError::Invalid(e.to_string()))? {
+        ParsedRequest::Sync(sync_req, outcome_receiver) => {
+            log_received_api_request(description);
+            send_to_vmm(sync_req, api_request_sender, vmm_send_event).map_err(|_| {
+                RequestError::Invalid("Failed to send the request to the VMM.".to_string())
+            })?;
+            match outcome_receiver.wait() {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(e)) => Err(RequestError::Vmm(e)),
+                Err(_) => Err(RequestError::Invalid(
+                    "The VMM did not respond to the request.".to_string(),
+                )),
+            }
+        }
+        // Requests on /mmds should not have the body in the logs as the data store contains
+        // customer data.
+        ParsedRequest::PatchMMDS(namespace, json_value) => {
+            patch_mmds_data(mmds_info, namespace, json_value)
+                .map_err(|e| RequestError::Invalid(e.to_string()))
+        }
+        ParsedRequest::PutMMDS(namespace, json_value) => {
+            put_mmds_data(mmds_info, namespace, json_value)
+                .map_err(|e| RequestError::Invalid(e.to_string()))
+        }
+        ParsedRequest::PutMMDSConfig(config) => {
+            configure_mmds(mmds_info, config);
+            Ok(())
+        }
+        ParsedRequest::GetInstanceInfo | ParsedRequest::GetMMDS(_) => Err(RequestError::Invalid(
+            format!("Unsupported request method and path: GET {}", path),
+        )),
+        ParsedRequest::SealApi => Err(RequestError::Invalid(
+            "The API can only be sealed through the API socket.".to_string(),
+        )),
+        ParsedRequest::Batch(_) => Err(RequestError::Invalid(
+            "Batches can only be submitted through the API socket.".to_string(),
+        )),
+    }
+}
+
+// Replaces the default MMDS data store, or the one of the namespace of the `namespace` source
+// address.
+fn put_mmds_data(
+    mmds_info: &Mutex<Mmds>,
+    namespace: Option<Ipv4Addr>,
+    data: Value,
+) -> result::Result<(), data_store::Error> {
+    let mut mmds = mmds_info
+        .lock()
+        .expect("Failed to acquire lock on MMDS info");
+    match namespace {
+        Some(source) => mmds.put_namespace_data(source, data),
+        None => mmds.put_data(data),
+    }
+}
+
+// Updates the default MMDS data store, or the one of the namespace of the `namespace` source
+// address.
+fn patch_mmds_data(
+    mmds_info: &Mutex<Mmds>,
+    namespace: Option<Ipv4Addr>,
+    patch_data: Value,
+) -> result::Result<(), data_store::Error> {
+    let mut mmds = mmds_info
+        .lock()
+        .expect("Failed to acquire lock on MMDS info");
+    match namespace {
+        Some(source) => mmds.patch_namespace_data(source, patch_data),
+        None => mmds.patch_data(patch_data),
+    }
+}
+
+// Applies the configuration of the MMDS, which takes effect on the next guest request.
+fn configure_mmds(mmds_info: &Mutex<Mmds>, config: MmdsConfig) {
+    mmds_info
+        .lock()
+        .expect("Failed to acquire lock on MMDS info")
+        .set_rate_limiter(config.rate_limiter.map(|bucket| bucket.into_token_bucket()));
+}
+
+// Whether a request is still served once the API is sealed. These are the GET requests, and
+// the actions which don't change the microVM.
+fn allowed_when_sealed(method: &Method, path: &str, body: &Chunk) -> bool {
+    match *method {
+        Method::Get => true,
+        Method::Put if path == "/actions" => serde_json::from_slice::<ActionBody>(body)
+            .map(|action_body| action_body.allowed_when_sealed())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+// Seals the API, which only serves GET requests and FlushMetrics actions from then on. Sealing
+// is only allowed once the microVM is running, since it could not be configured and started
+// afterwards.
+fn seal_api(
+    api_sealed: &Cell<bool>,
+    vmm_shared_info: &RwLock<InstanceInfo>,
+) -> result::Result<(), String> {
+    let shared_info = vmm_shared_info
+        .read()
+        .expect("Failed to read shared_info due to poisoned lock");
+    if shared_info.state != InstanceState::Running {
+        return Err("The API can only be sealed once the microVM is running.".to_string());
+    }
+    api_sealed.set(true);
+    Ok(())
+}
+
+type ResponseFuture = Box<Future<Item = hyper::Response, Error = hyper::Error>>;
+
 // In hyper, a struct that implements the Service trait is created to handle each incoming
 // request. This is the one for our ApiServer.
+#[derive(Clone)]
 pub struct ApiServerHttpService {
     // MMDS info directly accessible from this API thread.
     mmds_info: Arc<Mutex<Mmds>>,
@@ -462,6 +841,12 @@ pub struct ApiServerHttpService {
     api_request_sender: Rc<mpsc::Sender<Box<VmmAction>>>,
     // We write to this EventFd to let the VMM know about new messages.
     vmm_send_event: Rc<EventFd>,
+    // Whether the API was sealed. This is shared by the services of all the connections.
+    api_sealed: Rc<Cell<bool>>,
+    // Whether each request is written to the access log once it gets its response.
+    access_log: bool,
+    // Records the requests as activity for the idle monitor, if enabled.
+    activity: Option<EventCounter>,
 }
 
 impl ApiServerHttpService {
@@ -470,183 +855,334 @@ impl ApiServerHttpService {
         vmm_shared_info: Arc<RwLock<InstanceInfo>>,
         api_request_sender: Rc<mpsc::Sender<Box<VmmAction>>>,
         vmm_send_event: Rc<EventFd>,
+        api_sealed: Rc<Cell<bool>>,
+        access_log: bool,
+        activity: Option<EventCounter>,
     ) -> Self {
         ApiServerHttpService {
             mmds_info,
             vmm_shared_info,
             api_request_sender,
             vmm_send_event,
+            api_sealed,
+            access_log,
+            activity,
         }
     }
-}
 
-impl hyper::server::Service for ApiServerHttpService {
-    type Request = hyper::Request;
-    type Response = hyper::Response;
-    type Error = hyper::error::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
-
-    // This function returns a future that will resolve at some point to the response for
-    // the HTTP request contained in req.
-    fn call(&self, req: Self::Request) -> Self::Future {
-        // We do all this cloning to be able too move everything we need
-        // into the closure that follows.
-        let mmds_info = self.mmds_info.clone();
-        let method = req.method().clone();
-        let method_copy = req.method().clone();
-        let path = String::from(req.path());
-        let shared_info_lock = self.vmm_shared_info.clone();
-        let api_request_sender = self.api_request_sender.clone();
-        let vmm_send_event = self.vmm_send_event.clone();
+    // Serves a request once its body is available. The operations of a batch are served the
+    // same way as the requests received on their own.
+    fn serve(&self, method: Method, path: String, b: Chunk) -> ResponseFuture {
+        let method_copy = method.clone();
 
         // for nice looking match arms
         use request::ParsedRequest::*;
 
-        // The request body is itself a future (a stream of Chunks to be more precise),
-        // so we have to define a future that waits for all the pieces first (via concat2),
-        // and then does something with the newly available body (via and_then).
-        Box::new(req.body().concat2().and_then(move |b| {
-            // When this will be executed, the body is available. We start by parsing the request.
-            match parse_request(method, path.as_ref(), &b) {
-                Ok(parsed_req) => match parsed_req {
-                    GetInstanceInfo => {
-                        METRICS.get_api_requests.instance_info_count.inc();
-                        log_received_api_request(describe(&method_copy, &path, &None));
-                        // unwrap() to crash if the other thread poisoned this lock
-                        let shared_info = shared_info_lock
-                            .read()
-                            .expect("Failed to read shared_info due to poisoned lock");
-                        // Serialize it to a JSON string.
-                        let body_result = serde_json::to_string(&(*shared_info));
-                        match body_result {
-                            Ok(body) => Either::A(future::ok(json_response(StatusCode::Ok, body))),
-                            Err(e) => {
-                                // This is an api server metrics as the shared info is obtained internally.
-                                METRICS.get_api_requests.instance_info_fails.inc();
+        // Once sealed, the API rejects all the requests which could change the microVM.
+        if self.api_sealed.get() && !allowed_when_sealed(&method, &path, &b) {
+            METRICS.api_server.sealed_api_rejected_count.inc();
+            warn!(
+                "Rejected a {} because the API is sealed.",
+                describe(&method, &path, &None)
+            );
+            return Box::new(future::ok(json_response(
+                StatusCode::Forbidden,
+                json_fault_message(
+                    "The API is sealed. Only GET requests and FlushMetrics actions are allowed.",
+                ),
+            )));
+        }
+
+        // We start by parsing the request.
+        Box::new(match parse_request(method, path.as_ref(), &b) {
+            Ok(parsed_req) => match parsed_req {
+                ParsedRequest::Batch(batch) => {
+                    log_received_api_request(describe(&method_copy, &path, &None));
+                    Either::B(self.serve_batch(batch))
+                }
+                GetInstanceInfo => {
+                    METRICS.get_api_requests.instance_info_count.inc();
+                    log_received_api_request(describe(&method_copy, &path, &None));
+                    // unwrap() to crash if the other thread poisoned this lock
+                    let shared_info = self
+                        .vmm_shared_info
+                        .read()
+                        .expect("Failed to read shared_info due to poisoned lock");
+                    // Serialize it to a JSON string.
+                    let body_result = serde_json::to_string(&(*shared_info));
+                    match body_result {
+                        Ok(body) => Either::A(future::ok(json_response(StatusCode::Ok, body))),
+                        Err(e) => {
+                            // This is an api server metrics as the shared info is obtained internally.
+                            METRICS.get_api_requests.instance_info_fails.inc();
+                            Either::A(future::ok(json_response(
+                                StatusCode::InternalServerError,
+                                json_fault_message(e.to_string()),
+                            )))
+                        }
+                    }
+                }
+                PatchMMDS(namespace, json_value) => {
+                    // Requests on /mmds should not have the body in the logs as the data
+                    // store contains customer data.
+                    log_received_api_request(describe(&method_copy, &path, &None));
+                    match patch_mmds_data(&self.mmds_info, namespace, json_value) {
+                        Ok(_) => Either::A(future::ok(empty_response(StatusCode::NoContent))),
+                        Err(e) => match e {
+                            data_store::Error::NotFound => Either::A(future::ok(json_response(
+                                StatusCode::NotFound,
+                                json_fault_message(e.to_string()),
+                            ))),
+                            data_store::Error::UnsupportedValueType => {
+                                Either::A(future::ok(json_response(
+                                    StatusCode::BadRequest,
+                                    json_fault_message(e.to_string()),
+                                )))
+                            }
+                            data_store::Error::BackingFile(_)
+                            | data_store::Error::CorruptBackingFile(_) => {
                                 Either::A(future::ok(json_response(
                                     StatusCode::InternalServerError,
                                     json_fault_message(e.to_string()),
                                 )))
                             }
-                        }
+                        },
                     }
-                    PatchMMDS(json_value) => {
-                        // Requests on /mmds should not have the body in the logs as the data
-                        // store contains customer data.
-                        log_received_api_request(describe(&method_copy, &path, &None));
-                        let response = mmds_info
-                            .lock()
-                            .expect("Failed to acquire lock on MMDS info")
-                            .patch_data(json_value);
-                        match response {
-                            Ok(_) => Either::A(future::ok(empty_response(StatusCode::NoContent))),
-                            Err(e) => match e {
-                                data_store::Error::NotFound => {
-                                    Either::A(future::ok(json_response(
-                                        StatusCode::NotFound,
-                                        json_fault_message(e.to_string()),
-                                    )))
-                                }
-                                data_store::Error::UnsupportedValueType => {
-                                    Either::A(future::ok(json_response(
-                                        StatusCode::BadRequest,
-                                        json_fault_message(e.to_string()),
-                                    )))
-                                }
-                            },
+                }
+                PutMMDS(namespace, json_value) => {
+                    // Requests on /mmds should not have the body in the logs as the data
+                    // store contains customer data.
+                    log_received_api_request(describe(&method_copy, &path, &None));
+                    match put_mmds_data(&self.mmds_info, namespace, json_value) {
+                        Ok(_) => Either::A(future::ok(empty_response(StatusCode::NoContent))),
+                        Err(e @ data_store::Error::BackingFile(_)) => {
+                            Either::A(future::ok(json_response(
+                                StatusCode::InternalServerError,
+                                json_fault_message(e.to_string()),
+                            )))
                         }
+                        Err(e) => Either::A(future::ok(json_response(
+                            StatusCode::BadRequest,
+                            json_fault_message(e.to_string()),
+                        ))),
                     }
-                    PutMMDS(json_value) => {
-                        // Requests on /mmds should not have the body in the logs as the data
-                        // store contains customer data.
-                        log_received_api_request(describe(&method_copy, &path, &None));
-                        let response = mmds_info
-                            .lock()
-                            .expect("Failed to acquire lock on MMDS info")
-                            .put_data(json_value);
-                        match response {
-                            Ok(_) => Either::A(future::ok(empty_response(StatusCode::NoContent))),
-                            Err(e) => Either::A(future::ok(json_response(
-                                StatusCode::BadRequest,
-                                json_fault_message(e.to_string()),
-                            ))),
+                }
+                PutMMDSConfig(config) => {
+                    let body_desc = Some(String::from_utf8_lossy(&b.to_vec()).to_string());
+                    log_received_api_request(describe(&method_copy, &path, &body_desc));
+                    configure_mmds(&self.mmds_info, config);
+                    Either::A(future::ok(empty_response(StatusCode::NoContent)))
+                }
+                SealApi => {
+                    log_received_api_request(describe(&method_copy, &path, &None));
+                    match seal_api(&self.api_sealed, &self.vmm_shared_info) {
+                        Ok(()) => {
+                            info!("The API is sealed. Only GET requests and FlushMetrics actions are allowed.");
+                            Either::A(future::ok(empty_response(StatusCode::NoContent)))
                         }
+                        Err(msg) => Either::A(future::ok(json_response(
+                            StatusCode::BadRequest,
+                            json_fault_message(msg),
+                        ))),
                     }
-                    GetMMDS => {
-                        log_received_api_request(describe(&method_copy, &path, &None));
-                        Either::A(future::ok(json_response(
-                            StatusCode::Ok,
-                            mmds_info
-                                .lock()
-                                .expect("Failed to acquire lock on MMDS info")
-                                .get_data_str(),
-                        )))
+                }
+                GetMMDS(namespace) => {
+                    log_received_api_request(describe(&method_copy, &path, &None));
+                    let mmds = self
+                        .mmds_info
+                        .lock()
+                        .expect("Failed to acquire lock on MMDS info");
+                    let response = match namespace {
+                        Some(source) => mmds.get_namespace_data_str(source),
+                        None => Ok(mmds.get_data_str()),
+                    };
+                    match response {
+                        Ok(data) => Either::A(future::ok(json_response(StatusCode::Ok, data))),
+                        Err(e) => Either::A(future::ok(json_response(
+                            StatusCode::NotFound,
+                            json_fault_message(e.to_string()),
+                        ))),
+                    }
+                }
+                Sync(sync_req, outcome_receiver) => {
+                    if send_to_vmm(sync_req, &self.api_request_sender, &self.vmm_send_event)
+                        .is_err()
+                    {
+                        METRICS.api_server.sync_vmm_send_timeout_count.inc();
+                        return Box::new(future::err(hyper::Error::Timeout));
                     }
-                    Sync(sync_req, outcome_receiver) => {
-                        if send_to_vmm(sync_req, &api_request_sender, &vmm_send_event).is_err() {
-                            METRICS.api_server.sync_vmm_send_timeout_count.inc();
-                            return Either::A(future::err(hyper::Error::Timeout));
-                        }
 
-                        // metric-logging related variables for being able to log response details
-                        let path_copy = path.clone();
-                        let body_desc = match method_copy {
-                            Method::Get => None,
-                            _ => Some(String::from_utf8_lossy(&b.to_vec()).to_string()),
-                        };
-
-                        // We need to clone the description of the request because these are moved
-                        // in the below closure.
-                        let path_copy_err = path_copy.clone();
-                        let method_copy_err = method_copy.clone();
-                        let body_desc_err = body_desc.clone();
-
-                        log_received_api_request(describe(&method_copy, &path, &body_desc));
-
-                        // Sync requests don't receive a response until the outcome is returned.
-                        // Once more, this just registers a closure to run when the result is
-                        // available.
-                        Either::B(
-                            outcome_receiver
-                                .map(move |result| {
-                                    let description =
-                                        describe(&method_copy, &path_copy, &body_desc);
-                                    // `generate_response` and `err` both consume the inner error.
-                                    // Errors aren't `Clone`-able so we can't back it up either,
-                                    // so we'll rely on the fact that the error was previously
-                                    // logged at its point of origin and not log it again.
-                                    let response = result.generate_response();
-                                    let status_code = response.status();
-                                    if result.is_ok() {
-                                        info!(
-                                            "The {} was executed successfully. Status code: {}.",
-                                            description, status_code
-                                        );
-                                    } else {
-                                        error!(
-                                            "Received Error on {}. Status code: {}.",
-                                            description, status_code
-                                        );
-                                    }
-                                    response
-                                })
-                                .map_err(move |_| {
+                    // metric-logging related variables for being able to log response details
+                    let path_copy = path.clone();
+                    let body_desc = match method_copy {
+                        Method::Get => None,
+                        _ => Some(String::from_utf8_lossy(&b.to_vec()).to_string()),
+                    };
+
+                    // We need to clone the description of the request because these are moved
+                    // in the below closure.
+                    let path_copy_err = path_copy.clone();
+                    let method_copy_err = method_copy.clone();
+                    let body_desc_err = body_desc.clone();
+
+                    log_received_api_request(describe(&method_copy, &path, &body_desc));
+
+                    // Sync requests don't receive a response until the outcome is returned.
+                    // Once more, this just registers a closure to run when the result is
+                    // available.
+                    let response: ResponseFuture = Box::new(
+                        outcome_receiver
+                            .map(move |result| {
+                                let description = describe(&method_copy, &path_copy, &body_desc);
+                                // `generate_response` and `err` both consume the inner error.
+                                // Errors aren't `Clone`-able so we can't back it up either,
+                                // so we'll rely on the fact that the error was previously
+                                // logged at its point of origin and not log it again.
+                                let response = result.generate_response();
+                                let status_code = response.status();
+                                if result.is_ok() {
+                                    info!(
+                                        "The {} was executed successfully. Status code: {}.",
+                                        description, status_code
+                                    );
+                                } else {
                                     error!(
-                                        "Timeout on {}",
-                                        describe(&method_copy_err, &path_copy_err, &body_desc_err)
+                                        "Received Error on {}. Status code: {}.",
+                                        description, status_code
                                     );
-                                    METRICS.api_server.sync_outcome_fails.inc();
-                                    hyper::Error::Timeout
-                                }),
+                                }
+                                response
+                            })
+                            .map_err(move |_| {
+                                error!(
+                                    "Timeout on {}",
+                                    describe(&method_copy_err, &path_copy_err, &body_desc_err)
+                                );
+                                METRICS.api_server.sync_outcome_fails.inc();
+                                hyper::Error::Timeout
+                            }),
+                    );
+                    Either::B(response)
+                }
+            },
+            Err(e) => Either::A(future::ok(e.into())),
+        })
+    }
+
+    // Serves the operations of a batch one after the other, each once the previous one got its
+    // response. Unless the batch continues on errors, it stops at the first failed operation,
+    // whose result is the last one in the response.
+    fn serve_batch(&self, batch: Batch) -> ResponseFuture {
+        let service = self.clone();
+        let continue_on_error = batch.continue_on_error;
+        let operations = batch.operations.into_iter().enumerate();
+
+        let results = future::loop_fn(
+            (operations, Vec::new()),
+            move |(mut operations, mut results)| {
+                let (index, operation) = match operations.next() {
+                    Some(next) => next,
+                    None => return Either::A(future::ok(Loop::Break(results))),
+                };
+                Either::B(
+                    service
+                        .serve(
+                            operation.method,
+                            operation.path,
+                            Chunk::from(operation.body),
                         )
-                    }
-                },
-                Err(e) => Either::A(future::ok(e.into())),
-            }
+                        .and_then(|response| {
+                            let status = response.status();
+                            response.body().concat2().map(move |body| (status, body))
+                        })
+                        .map(move |(status, body)| {
+                            results.push(BatchResult {
+                                index,
+                                status_code: status.as_u16(),
+                                body: serde_json::from_slice::<Value>(&body).ok(),
+                            });
+                            if status.is_success() || continue_on_error {
+                                return Loop::Continue((operations, results));
+                            }
+                            warn!(
+                                "The batch stopped at operation {}, which failed with status {}.",
+                                index, status
+                            );
+                            Loop::Break(results)
+                        }),
+                )
+            },
+        );
+
+        Box::new(results.map(
+            |results: Vec<BatchResult>| match serde_json::to_string(&results) {
+                Ok(body) => json_response(StatusCode::Ok, body),
+                Err(e) => json_response(
+                    StatusCode::InternalServerError,
+                    json_fault_message(e.to_string()),
+                ),
+            },
+        ))
+    }
+}
+
+impl hyper::server::Service for ApiServerHttpService {
+    type Request = hyper::Request;
+    type Response = hyper::Response;
+    type Error = hyper::error::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    // This function returns a future that will resolve at some point to the response for
+    // the HTTP request contained in req.
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if let Some(ref activity) = self.activity {
+            activity.record();
+        }
+        let start = Instant::now();
+        let access_log_method = req.method().clone();
+        let access_log_path = String::from(req.path());
+
+        let service = self.clone();
+        let method = req.method().clone();
+        let path = String::from(req.path());
+
+        // The request body is itself a future (a stream of Chunks to be more precise),
+        // so we have to define a future that waits for all the pieces first (via concat2),
+        // and then does something with the newly available body (via and_then).
+        let response = req
+            .body()
+            .concat2()
+            .and_then(move |b| service.serve(method, path, b));
+
+        if !self.access_log {
+            return Box::new(response);
+        }
+        Box::new(response.map(move |response| {
+            let latency = start.elapsed();
+            let latency_us = latency.as_secs() * 1_000_000 + u64::from(latency.subsec_micros());
+            info!(
+                "{}",
+                access_log_line(
+                    &access_log_method,
+                    &access_log_path,
+                    response.status(),
+                    latency_us
+                )
+            );
+            response
         }))
     }
 }
 
+// Describes a served request for the access log. The bodies are left out, since they can hold
+// customer data such as the MMDS contents.
+fn access_log_line(method: &Method, path: &str, status: StatusCode, latency_us: u64) -> String {
+    format!(
+        "API access: {} {} {} in {} us",
+        method, path, status, latency_us
+    )
+}
+
 /// Helper function for writing the received API requests to the log.
 ///
 /// The `info` macro is used for logging.
@@ -676,17 +1212,22 @@ fn describe(method: &Method, path: &str, body: &Option<String>) -> String {
 #[cfg(test)]
 mod tests {
     extern crate net_util;
+    extern crate tempfile;
 
     use self::net_util::MacAddr;
     use super::*;
 
     use serde_json::{Map, Value};
+    use std::fs;
     use std::path::PathBuf;
     use std::result;
 
+    use self::tempfile::NamedTempFile;
     use futures::sync::oneshot;
     use hyper::header::{ContentType, Headers};
     use hyper::Body;
+    use logger::{AppInfo, Level, LOGGER};
+    use vmm::vmm_config::drive::DEFAULT_MAX_RAMDISK_SIZE_MIB;
     use vmm::vmm_config::logger::LoggerLevel;
     use vmm::vmm_config::machine_config::CpuFeaturesTemplate;
     use vmm::VmmAction;
@@ -995,7 +1536,13 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: true,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
@@ -1137,6 +1684,45 @@ mod tests {
         assert!(parse_logger_req(&"/foo/bar", Method::Put, &Chunk::from("foo")) == expected_err);
     }
 
+    #[test]
+    fn test_parse_devices_req() {
+        let (sender, receiver) = oneshot::channel();
+        assert!(
+            parse_devices_req("/devices", Method::Get).eq(&Ok(ParsedRequest::Sync(
+                VmmAction::GetDevices(sender),
+                receiver
+            )))
+        );
+
+        // Error Case: Invalid method.
+        let expected_err = Err(Error::InvalidPathMethod("/devices", Method::Put));
+        assert!(parse_devices_req("/devices", Method::Put) == expected_err);
+
+        // Error Case: Invalid path.
+        let expected_err = Err(Error::InvalidPathMethod("/devices/foo", Method::Get));
+        assert!(parse_devices_req("/devices/foo", Method::Get) == expected_err);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_parse_fdt_req() {
+        let (sender, receiver) = oneshot::channel();
+        assert!(
+            parse_fdt_req("/fdt", Method::Get).eq(&Ok(ParsedRequest::Sync(
+                VmmAction::GetFdt(sender),
+                receiver
+            )))
+        );
+
+        // Error Case: Invalid method.
+        let expected_err = Err(Error::InvalidPathMethod("/fdt", Method::Put));
+        assert!(parse_fdt_req("/fdt", Method::Put) == expected_err);
+
+        // Error Case: Invalid path.
+        let expected_err = Err(Error::InvalidPathMethod("/fdt/foo", Method::Get));
+        assert!(parse_fdt_req("/fdt/foo", Method::Get) == expected_err);
+    }
+
     #[test]
     fn test_parse_machine_config_req() {
         let path = "/machine-config";
@@ -1162,6 +1748,22 @@ mod tests {
             mem_size_mib: Some(1025),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
 
         match vm_config.into_parsed_request(None, Method::Put) {
@@ -1178,6 +1780,22 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         let body = r#"{
             "vcpu_count": 32
@@ -1252,7 +1870,12 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str("12:34:56:78:9a:BC").unwrap()),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         };
 
@@ -1328,6 +1951,23 @@ mod tests {
         }"#;
         let body = Chunk::from(json);
         assert!(parse_netif_req(&"/network-interfaces/2", Method::Patch, &body).is_err());
+
+        // DELETE tests
+        let (sender, receiver) = oneshot::channel();
+        let expected_pr = ParsedRequest::Sync(
+            VmmAction::DetachNetworkDevice(String::from("1"), sender),
+            receiver,
+        );
+        match parse_netif_req(&"/network-interfaces/1", Method::Delete, &Chunk::from("")) {
+            Ok(pr) => assert!(pr.eq(&expected_pr)),
+            _ => assert!(false),
+        };
+
+        // Error Case: Missing id.
+        assert!(
+            parse_netif_req(&"/network-interfaces", Method::Delete, &Chunk::from(""))
+                == Err(Error::EmptyID)
+        );
     }
 
     #[test]
@@ -1338,7 +1978,7 @@ mod tests {
 
         // Test for GET request
         match parse_mmds_request(path, Method::Get, &body) {
-            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::GetMMDS)),
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::GetMMDS(None))),
             Err(_) => assert!(false),
         };
 
@@ -1355,6 +1995,7 @@ mod tests {
         let body = Chunk::from(dummy_json);
         match parse_mmds_request(path, Method::Put, &body) {
             Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::PutMMDS(
+                None,
                 serde_json::from_slice(&body).unwrap()
             ))),
             Err(_) => assert!(false),
@@ -1365,6 +2006,7 @@ mod tests {
         let body = Chunk::from(patch_json);
         match parse_mmds_request(path, Method::Patch, &body) {
             Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::PatchMMDS(
+                None,
                 serde_json::from_slice(&body).unwrap()
             ))),
             Err(_) => assert!(false),
@@ -1390,6 +2032,267 @@ mod tests {
         let path = "/mmds/something";
         let expected_err = Err(Error::InvalidPathMethod(path, Method::Get));
         assert!(parse_mmds_request(path, Method::Get, &body) == expected_err);
+
+        // Test for requests on the namespace of a source address.
+        let source = Some("169.254.0.2".parse().unwrap());
+        let path = "/mmds/namespaces/169.254.0.2";
+        match parse_mmds_request(path, Method::Get, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::GetMMDS(source))),
+            Err(_) => assert!(false),
+        };
+        let body = Chunk::from(dummy_json);
+        match parse_mmds_request(path, Method::Put, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::PutMMDS(
+                source,
+                serde_json::from_slice(&body).unwrap()
+            ))),
+            Err(_) => assert!(false),
+        };
+        let body = Chunk::from(patch_json);
+        match parse_mmds_request(path, Method::Patch, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::PatchMMDS(
+                source,
+                serde_json::from_slice(&body).unwrap()
+            ))),
+            Err(_) => assert!(false),
+        };
+        // Requests on the default data store don't target a namespace.
+        match parse_mmds_request("/mmds", Method::Patch, &body) {
+            Ok(parsed_req) => assert!(!parsed_req.eq(&ParsedRequest::PatchMMDS(
+                source,
+                serde_json::from_slice(&body).unwrap()
+            ))),
+            Err(_) => assert!(false),
+        };
+
+        let path = "/mmds/namespaces/foo";
+        let expected_err = Err(Error::Generic(
+            StatusCode::BadRequest,
+            String::from("Invalid MMDS namespace address: foo"),
+        ));
+        assert!(parse_mmds_request(path, Method::Get, &body) == expected_err);
+        let path = "/mmds/namespaces";
+        let expected_err = Err(Error::InvalidPathMethod(path, Method::Get));
+        assert!(parse_mmds_request(path, Method::Get, &body) == expected_err);
+        let path = "/mmds/namespaces/169.254.0.2";
+        let expected_err = Err(Error::InvalidPathMethod(path, Method::Delete));
+        assert!(parse_mmds_request(path, Method::Delete, &body) == expected_err);
+
+        // Test for requests on the MMDS configuration.
+        let path = "/mmds/config";
+        let body = Chunk::from(r#"{ "rate_limiter": { "size": 10, "refill_time": 100 } }"#);
+        match parse_mmds_request(path, Method::Put, &body) {
+            Ok(parsed_req) => assert!(parsed_req.eq(&ParsedRequest::PutMMDSConfig(
+                serde_json::from_slice(&body).unwrap()
+            ))),
+            Err(_) => assert!(false),
+        };
+        let body = Chunk::from(r#"{ "rate_limiter": { "size": 0, "refill_time": 100 } }"#);
+        let expected_err = Err(Error::Generic(
+            StatusCode::BadRequest,
+            String::from("The MMDS rate limiter must have a positive size and refill time."),
+        ));
+        assert!(parse_mmds_request(path, Method::Put, &body) == expected_err);
+        assert!(parse_mmds_request(path, Method::Put, &Chunk::from(r#"{ "foo": 1 }"#)).is_err());
+        let expected_err = Err(Error::InvalidPathMethod(path, Method::Get));
+        assert!(parse_mmds_request(path, Method::Get, &body) == expected_err);
+    }
+
+    #[cfg(feature = "vhost-user-block")]
+    #[test]
+    fn test_parse_vhost_user_drives_req() {
+        let path = "/vhost-user-drives/blk";
+        let json = "{
+                \"id\": \"blk\",
+                \"socket_path\": \"/tmp/blk.sock\"
+              }";
+        let body: Chunk = Chunk::from(json);
+
+        // PUT
+        let cfg = VhostUserBlockDeviceConfig {
+            id: String::from("blk"),
+            socket_path: String::from("/tmp/blk.sock"),
+        };
+        match cfg.into_parsed_request(Some(String::from("blk")), Method::Put) {
+            Ok(pr) => match parse_vhost_user_drives_req(&path, Method::Put, &body) {
+                Ok(pr_cfg) => assert!(pr.eq(&pr_cfg)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: The id from the path does not match the id from the body.
+        let path = "/vhost-user-drives/other";
+        assert!(
+            parse_vhost_user_drives_req(path, Method::Put, &body)
+                == Err(Error::Generic(
+                    StatusCode::BadRequest,
+                    String::from("The id from the path does not match the id from the body!"),
+                ))
+        );
+
+        // Error Case: Invalid payload.
+        assert!(
+            parse_vhost_user_drives_req(path, Method::Put, &Chunk::from("foo bar"))
+                == Err(Error::SerdeJson(get_dummy_serde_error()))
+        );
+
+        // Error Case: Invalid method.
+        assert!(
+            parse_vhost_user_drives_req(path, Method::Get, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Get))
+        );
+
+        // Error Case: Missing id.
+        assert!(
+            parse_vhost_user_drives_req("/vhost-user-drives", Method::Put, &body)
+                == Err(Error::EmptyID)
+        );
+    }
+
+    #[cfg(feature = "virtio-fs")]
+    #[test]
+    fn test_parse_fs_req() {
+        let path = "/fs/fs0";
+        let json = "{
+                \"id\": \"fs0\",
+                \"shared_dir\": \"/srv/shared\",
+                \"tag\": \"shared\"
+              }";
+        let body: Chunk = Chunk::from(json);
+
+        // PUT
+        let cfg = VirtioFsDeviceConfig {
+            id: String::from("fs0"),
+            shared_dir: String::from("/srv/shared"),
+            tag: String::from("shared"),
+        };
+        match cfg.into_parsed_request(Some(String::from("fs0")), Method::Put) {
+            Ok(pr) => match parse_fs_req(&path, Method::Put, &body) {
+                Ok(pr_cfg) => assert!(pr.eq(&pr_cfg)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: The id from the path does not match the id from the body.
+        let path = "/fs/other";
+        assert!(
+            parse_fs_req(path, Method::Put, &body)
+                == Err(Error::Generic(
+                    StatusCode::BadRequest,
+                    String::from("The id from the path does not match the id from the body!"),
+                ))
+        );
+
+        // Error Case: Invalid payload.
+        assert!(
+            parse_fs_req(path, Method::Put, &Chunk::from("foo bar"))
+                == Err(Error::SerdeJson(get_dummy_serde_error()))
+        );
+
+        // Error Case: Invalid method.
+        assert!(
+            parse_fs_req(path, Method::Get, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Get))
+        );
+
+        // Error Case: Missing id.
+        assert!(parse_fs_req("/fs", Method::Put, &body) == Err(Error::EmptyID));
+    }
+
+    #[test]
+    fn test_parse_snapshot_req() {
+        let path = "/snapshot/create";
+        let body: Chunk = Chunk::from("{\"snapshot_path\": \"/tmp/vm.snap\"}");
+
+        // PUT
+        let (sender, receiver) = oneshot::channel();
+        let snapshot_cfg = SnapshotConfig {
+            snapshot_path: PathBuf::from("/tmp/vm.snap"),
+        };
+        let pr = ParsedRequest::Sync(VmmAction::CreateSnapshot(snapshot_cfg, sender), receiver);
+        match parse_snapshot_req(&path, Method::Put, &body) {
+            Ok(pr_cfg) => assert!(pr.eq(&pr_cfg)),
+            _ => assert!(false),
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let snapshot_cfg = SnapshotConfig {
+            snapshot_path: PathBuf::from("/tmp/vm.snap"),
+        };
+        let pr = ParsedRequest::Sync(VmmAction::LoadSnapshot(snapshot_cfg, sender), receiver);
+        match parse_snapshot_req("/snapshot/load", Method::Put, &body) {
+            Ok(pr_cfg) => assert!(pr.eq(&pr_cfg)),
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: Invalid payload.
+        assert!(
+            parse_snapshot_req(path, Method::Put, &Chunk::from("{\"path\": \"/tmp\"}"))
+                == Err(Error::SerdeJson(get_dummy_serde_error()))
+        );
+
+        // Error Case: Invalid method.
+        assert!(
+            parse_snapshot_req(path, Method::Get, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Get))
+        );
+
+        // Error Case: Invalid path.
+        let path = "/snapshot";
+        assert!(
+            parse_snapshot_req(path, Method::Put, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Put))
+        );
+    }
+
+    #[cfg(feature = "vsock")]
+    #[test]
+    fn test_parse_vsocks_req() {
+        let path = "/vsocks/vsock0/agent";
+        let json = "{
+                \"command\": \"run_script\",
+                \"script\": \"sync\"
+              }";
+        let body: Chunk = Chunk::from(json);
+
+        // PUT
+        let (sender, receiver) = oneshot::channel();
+        let command = AgentCommand::RunScript {
+            script: String::from("sync"),
+        };
+        let pr = ParsedRequest::Sync(
+            VmmAction::SendAgentCommand(String::from("vsock0"), command, sender),
+            receiver,
+        );
+        match parse_vsocks_req(&path, Method::Put, &body) {
+            Ok(pr_cmd) => assert!(pr.eq(&pr_cmd)),
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: Unknown command.
+        assert!(
+            parse_vsocks_req(path, Method::Put, &Chunk::from("{\"command\": \"reboot\"}"))
+                == Err(Error::SerdeJson(get_dummy_serde_error()))
+        );
+
+        // Error Case: Invalid method.
+        assert!(
+            parse_vsocks_req(path, Method::Get, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Get))
+        );
+
+        // Error Case: Invalid path.
+        let path = "/vsocks/vsock0/foo";
+        assert!(
+            parse_vsocks_req(path, Method::Put, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Put))
+        );
     }
 
     #[test]
@@ -1441,6 +2344,259 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sealed_api() {
+        use hyper::server::Service;
+
+        let shared_info = Arc::new(RwLock::new(InstanceInfo {
+            state: InstanceState::Uninitialized,
+            id: String::from("sealed-api"),
+            vmm_version: String::from("1.0"),
+            stop_reason: None,
+        }));
+        let (api_request_sender, _from_api) = mpsc::channel();
+        let service = ApiServerHttpService::new(
+            Arc::new(Mutex::new(Mmds::default())),
+            shared_info.clone(),
+            Rc::new(api_request_sender),
+            Rc::new(EventFd::new().unwrap()),
+            Rc::new(Cell::new(false)),
+            false,
+            None,
+        );
+        let send_request = |method: Method, path: &str, body: &str| {
+            let mut request = hyper::Request::new(method, path.parse().unwrap());
+            request.set_body(body.to_string());
+            service.call(request).wait().unwrap().status()
+        };
+        let seal_body = "{ \"action_type\": \"SealApi\" }";
+
+        // Error case: the microVM is not running yet.
+        assert_eq!(
+            send_request(Method::Put, "/actions", seal_body),
+            StatusCode::BadRequest
+        );
+
+        shared_info.write().unwrap().state = InstanceState::Running;
+        assert_eq!(
+            send_request(Method::Put, "/actions", seal_body),
+            StatusCode::NoContent
+        );
+
+        // Requests which could change the microVM are rejected, even the ones which are invalid.
+        assert_eq!(
+            send_request(Method::Put, "/machine-config", "{ \"vcpu_count\": 2 }"),
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            send_request(Method::Patch, "/mmds", "{}"),
+            StatusCode::Forbidden
+        );
+        assert_eq!(send_request(Method::Put, "/foo", ""), StatusCode::Forbidden);
+        // The seal cannot be undone.
+        assert_eq!(
+            send_request(Method::Put, "/actions", seal_body),
+            StatusCode::Forbidden
+        );
+
+        // The status of the microVM can still be queried.
+        assert_eq!(send_request(Method::Get, "/", ""), StatusCode::Ok);
+        assert_eq!(send_request(Method::Get, "/mmds", ""), StatusCode::Ok);
+
+        // So can the metrics be flushed.
+        let flush_body = Chunk::from("{ \"action_type\": \"FlushMetrics\" }");
+        assert!(allowed_when_sealed(&Method::Put, "/actions", &flush_body));
+        assert!(!allowed_when_sealed(
+            &Method::Put,
+            "/actions",
+            &Chunk::from(seal_body)
+        ));
+        assert!(!allowed_when_sealed(&Method::Put, "/mmds", &flush_body));
+        assert!(!allowed_when_sealed(
+            &Method::Patch,
+            "/actions",
+            &flush_body
+        ));
+    }
+
+    #[test]
+    fn test_batch() {
+        use hyper::server::Service;
+
+        let shared_info = Arc::new(RwLock::new(InstanceInfo {
+            state: InstanceState::Uninitialized,
+            id: String::from("batch"),
+            vmm_version: String::from("1.0"),
+            stop_reason: None,
+        }));
+        let (api_request_sender, from_api) = mpsc::channel();
+        let vmm_send_event = EventFd::new().unwrap();
+        // The microVM is never started, so the seccomp level doesn't matter here.
+        vmm::start_vmm_thread(
+            shared_info.clone(),
+            vmm_send_event.try_clone().unwrap(),
+            from_api,
+            0,
+            None,
+            None,
+            DEFAULT_MAX_RAMDISK_SIZE_MIB,
+        );
+        let mmds_info = Arc::new(Mutex::new(Mmds::default()));
+        let service = ApiServerHttpService::new(
+            mmds_info.clone(),
+            shared_info,
+            Rc::new(api_request_sender),
+            Rc::new(vmm_send_event),
+            Rc::new(Cell::new(false)),
+            false,
+            None,
+        );
+        let send_batch = |body: &str| {
+            let mut request = hyper::Request::new(Method::Put, "/batch".parse().unwrap());
+            request.set_body(body.to_string());
+            let response = service.call(request).wait().unwrap();
+            let status = response.status();
+            (
+                status,
+                serde_json::from_str::<Value>(&body_to_string(response.body())).unwrap(),
+            )
+        };
+
+        let (status, results) = send_batch(
+            r#"{
+                "operations": [
+                    {
+                        "method": "PUT",
+                        "path": "/machine-config",
+                        "body": { "vcpu_count": 2, "mem_size_mib": 256, "ht_enabled": false }
+                    },
+                    { "method": "PUT", "path": "/mmds", "body": { "foo": "bar" } },
+                    { "method": "GET", "path": "/machine-config" }
+                ]
+            }"#,
+        );
+        assert_eq!(status, StatusCode::Ok);
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        for (index, result) in results.iter().enumerate() {
+            assert_eq!(result["index"], index);
+        }
+        assert_eq!(results[0]["status_code"], 204);
+        assert!(results[0].get("body").is_none());
+        assert_eq!(results[1]["status_code"], 204);
+        assert!(mmds_info.lock().unwrap().get_data_str().contains("bar"));
+        // The last operation sees the configuration set by the first one.
+        assert_eq!(results[2]["status_code"], 200);
+        assert_eq!(results[2]["body"]["vcpu_count"], 2);
+        assert_eq!(results[2]["body"]["mem_size_mib"], 256);
+
+        // The batch stops at the first failed operation.
+        let failing_batch = r#"
+            "operations": [
+                {
+                    "method": "PUT",
+                    "path": "/machine-config",
+                    "body": { "vcpu_count": 0, "mem_size_mib": 256, "ht_enabled": false }
+                },
+                { "method": "PUT", "path": "/mmds", "body": { "foo": "baz" } }
+            ]"#;
+        let (status, results) = send_batch(&format!("{{ {} }}", failing_batch));
+        assert_eq!(status, StatusCode::Ok);
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["index"], 0);
+        assert_eq!(results[0]["status_code"], 400);
+        assert_eq!(
+            results[0]["body"]["fault_message"],
+            "The vCPU number is invalid! The vCPU number can only be 1 or an even number when hyperthreading is enabled."
+        );
+        assert!(!mmds_info.lock().unwrap().get_data_str().contains("baz"));
+
+        // Unless it continues on errors.
+        let (_, results) = send_batch(&format!(
+            "{{ {}, \"continue_on_error\": true }}",
+            failing_batch
+        ));
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["status_code"], 400);
+        assert_eq!(results[1]["index"], 1);
+        assert_eq!(results[1]["status_code"], 204);
+        assert!(mmds_info.lock().unwrap().get_data_str().contains("baz"));
+
+        // Error case: nothing is executed when an operation is malformed.
+        let (status, _) = send_batch(
+            r#"{
+                "operations": [
+                    { "method": "PUT", "path": "/mmds", "body": { "foo": "qux" } },
+                    { "method": "PUT", "path": "/batch", "body": { "operations": [] } }
+                ]
+            }"#,
+        );
+        assert_eq!(status, StatusCode::BadRequest);
+        assert!(!mmds_info.lock().unwrap().get_data_str().contains("qux"));
+    }
+
+    #[test]
+    fn test_access_log() {
+        use hyper::server::Service;
+
+        let log_file = NamedTempFile::new().unwrap();
+        let metrics_file = NamedTempFile::new().unwrap();
+        LOGGER.set_level(Level::Info);
+        LOGGER
+            .init(
+                &AppInfo::new("Firecracker", "1.0"),
+                "access-log",
+                log_file.path().to_str().unwrap().to_string(),
+                metrics_file.path().to_str().unwrap().to_string(),
+                &[],
+            )
+            .unwrap();
+
+        let shared_info = Arc::new(RwLock::new(InstanceInfo {
+            state: InstanceState::Uninitialized,
+            id: String::from("access-log"),
+            vmm_version: String::from("1.0"),
+            stop_reason: None,
+        }));
+        let (api_request_sender, _from_api) = mpsc::channel();
+        let service = ApiServerHttpService::new(
+            Arc::new(Mutex::new(Mmds::default())),
+            shared_info,
+            Rc::new(api_request_sender),
+            Rc::new(EventFd::new().unwrap()),
+            Rc::new(Cell::new(false)),
+            true,
+            None,
+        );
+        let send_request = |method: Method, path: &str, body: &str| {
+            let mut request = hyper::Request::new(method, path.parse().unwrap());
+            request.set_body(body.to_string());
+            service.call(request).wait().unwrap().status()
+        };
+
+        assert_eq!(
+            send_request(Method::Put, "/mmds", "{ \"secret\": \"s3cr3t\" }"),
+            StatusCode::NoContent
+        );
+        assert_eq!(
+            send_request(Method::Get, "/foo", ""),
+            StatusCode::BadRequest
+        );
+
+        let log = fs::read_to_string(log_file.path()).unwrap();
+        assert!(log.contains("API access: PUT /mmds 204 No Content in "));
+        assert!(log.contains("API access: GET /foo 400 Bad Request in "));
+        // The MMDS contents are not written to the log.
+        assert!(!log.contains("s3cr3t"));
+
+        assert_eq!(
+            access_log_line(&Method::Patch, "/drives/root", StatusCode::NoContent, 42),
+            "API access: PATCH /drives/root 204 No Content in 42 us"
+        );
+    }
+
     #[test]
     fn test_describe() {
         let body: String = String::from("{ \"foo\": \"bar\" }");
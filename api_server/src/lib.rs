@@ -15,13 +15,16 @@ extern crate fc_util;
 #[macro_use]
 extern crate logger;
 extern crate mmds;
+extern crate seccomp;
 extern crate sys_util;
 extern crate vmm;
 
 mod http_service;
 pub mod request;
 
-use std::path::PathBuf;
+use std::cell::Cell;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex, RwLock};
@@ -29,20 +32,24 @@ use std::{fmt, io};
 
 use futures::{Future, Stream};
 use hyper::server::Http;
+use hyper::{Chunk, Method};
+use serde_json::Value;
 use tokio_core::reactor::Core;
 use tokio_uds::UnixListener;
 
-use http_service::ApiServerHttpService;
+use http_service::{handle_request, ApiServerHttpService, RequestError};
 use logger::{Metric, METRICS};
 use mmds::data_store::Mmds;
 use sys_util::EventFd;
 use vmm::default_syscalls;
+use vmm::event_counter::EventCounter;
 use vmm::vmm_config::instance_info::InstanceInfo;
-use vmm::VmmAction;
+use vmm::{VmmAction, VmmActionError};
 
 pub enum Error {
     Io(io::Error),
     Eventfd(io::Error),
+    Seccomp(seccomp::Error),
 }
 
 impl fmt::Display for Error {
@@ -50,6 +57,7 @@ impl fmt::Display for Error {
         match *self {
             Error::Io(ref err) => write!(f, "IO error: {}", err),
             Error::Eventfd(ref err) => write!(f, "EventFd error: {}", err),
+            Error::Seccomp(ref err) => write!(f, "Seccomp error: {}", err),
         }
     }
 }
@@ -59,12 +67,66 @@ impl fmt::Debug for Error {
         match *self {
             Error::Io(ref err) => write!(f, "IO error: {}", err),
             Error::Eventfd(ref err) => write!(f, "EventFd error: {}", err),
+            Error::Seccomp(ref err) => write!(f, "Seccomp error: {}", err),
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Errors associated with configuring the microVM from a file.
+#[derive(Debug)]
+pub enum ConfigFileError {
+    /// The configuration file cannot be read.
+    Read(io::Error),
+    /// The configuration file is not a JSON array.
+    Parse(serde_json::Error),
+    /// The request at the given index is malformed or not supported.
+    Request(usize, String),
+    /// The VMM failed to carry out the request at the given index.
+    Vmm(usize, VmmActionError),
+}
+
+impl fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigFileError::Read(ref err) => {
+                write!(f, "Cannot read the configuration file: {}", err)
+            }
+            ConfigFileError::Parse(ref err) => write!(
+                f,
+                "The configuration file is not a JSON array of requests: {}",
+                err
+            ),
+            ConfigFileError::Request(index, ref msg) => {
+                write!(
+                    f,
+                    "Request {} from the configuration file failed: {}",
+                    index, msg
+                )
+            }
+            ConfigFileError::Vmm(index, ref err) => {
+                write!(
+                    f,
+                    "Request {} from the configuration file failed: {}",
+                    index, err
+                )
+            }
+        }
+    }
+}
+
+// A request read from a configuration file. It describes an API call the same way as it would
+// be sent over the API socket.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigRequest {
+    method: String,
+    path: String,
+    #[serde(default)]
+    body: Option<Value>,
+}
+
 pub struct ApiServer {
     // MMDS info directly accessible from the API thread.
     mmds_info: Arc<Mutex<Mmds>>,
@@ -73,6 +135,12 @@ pub struct ApiServer {
     // Sender which allows passing messages to the VMM.
     api_request_sender: Rc<mpsc::Sender<Box<VmmAction>>>,
     efd: Rc<EventFd>,
+    // Whether the API was sealed through the `SealApi` action. Once set, it is never cleared.
+    api_sealed: Rc<Cell<bool>>,
+    // Whether the requests served on the API socket are written to the access log.
+    access_log: bool,
+    // Records the requests served on the API socket as activity for the idle monitor.
+    activity: Option<EventCounter>,
 }
 
 impl ApiServer {
@@ -86,9 +154,26 @@ impl ApiServer {
             vmm_shared_info,
             api_request_sender: Rc::new(api_request_sender),
             efd: Rc::new(EventFd::new().map_err(Error::Eventfd)?),
+            api_sealed: Rc::new(Cell::new(false)),
+            access_log: false,
+            activity: None,
         })
     }
 
+    /// Sets the handle through which the requests served on the API socket are recorded as
+    /// activity, which keeps the idle monitor from stopping the microVM.
+    pub fn set_activity(&mut self, activity: EventCounter) {
+        self.activity = Some(activity);
+    }
+
+    /// Turns the access log on or off. When on, the method, path, response status and latency
+    /// of each request served on the API socket are logged at the info level.
+    pub fn set_access_log(&mut self, enabled: bool) {
+        self.access_log = enabled;
+    }
+
+    /// Serves the API on the unix domain socket at `path`. At most `max_connections` clients
+    /// are served at once, the connections opened past this limit are closed right away.
     // TODO: does tokio_uds also support abstract domain sockets?
     pub fn bind_and_run(
         &self,
@@ -96,6 +181,7 @@ impl ApiServer {
         start_time_us: Option<u64>,
         start_time_cpu_us: Option<u64>,
         seccomp_level: u32,
+        max_connections: usize,
     ) -> Result<()> {
         let mut core = Core::new().map_err(Error::Io)?;
         let handle = Rc::new(core.handle());
@@ -119,10 +205,23 @@ impl ApiServer {
         }
 
         let http: Http<hyper::Chunk> = Http::new();
+        let open_connections = Rc::new(Cell::new(0usize));
 
         let f = listener
             .incoming()
             .for_each(|(stream, _)| {
+                if open_connections.get() >= max_connections {
+                    // Dropping the stream closes the connection.
+                    METRICS.api_server.connections_refused_count.inc();
+                    warn!(
+                        "Refused an API connection, {} connections are already open.",
+                        max_connections
+                    );
+                    return Ok(());
+                }
+                open_connections.set(open_connections.get() + 1);
+                let connection_count = open_connections.clone();
+
                 // For the sake of clarity: when we use self.efd.clone(), the intent is to
                 // clone the wrapping Rc, not the EventFd itself.
                 let service = ApiServerHttpService::new(
@@ -130,24 +229,24 @@ impl ApiServer {
                     self.vmm_shared_info.clone(),
                     self.api_request_sender.clone(),
                     self.efd.clone(),
+                    self.api_sealed.clone(),
+                    self.access_log,
+                    self.activity.clone(),
                 );
                 let connection = http.serve_connection(stream, service);
                 // todo: is spawn() any better/worse than execute()?
                 // We have to adjust the future item and error, to fit spawn()'s definition.
-                handle.spawn(connection.map(|_| ()).map_err(|_| ()));
+                handle.spawn(connection.then(move |_| {
+                    connection_count.set(connection_count.get() - 1);
+                    Ok(())
+                }));
                 Ok(())
             })
             .map_err(Error::Io);
 
         // Load seccomp filters on the API thread.
-        // Execution panics if filters cannot be loaded, use --seccomp-level=0 if skipping filters
-        // altogether is the desired behaviour.
-        if let Err(e) = default_syscalls::set_seccomp_level(seccomp_level) {
-            panic!(
-                "Failed to set the requested seccomp filters on the API thread: Error: {:?}",
-                e
-            );
-        }
+        // Use --seccomp-level=0 if skipping filters altogether is the desired behaviour.
+        default_syscalls::set_seccomp_level(seccomp_level).map_err(Error::Seccomp)?;
 
         // This runs forever, unless an error is returned somewhere within f (but nothing happens
         // for errors which might arise inside the connections we spawn from f, unless we explicitly
@@ -156,6 +255,43 @@ impl ApiServer {
         core.run(f)
     }
 
+    /// Configures the microVM from a file holding a JSON array of API requests, each having a
+    /// `method`, a `path` and an optional `body`. The requests are handled in order, just like
+    /// the ones received on the API socket, and the first one that is malformed or fails stops
+    /// the configuration.
+    pub fn apply_config_file(&self, path: &Path) -> std::result::Result<(), ConfigFileError> {
+        let content = fs::read_to_string(path).map_err(ConfigFileError::Read)?;
+        let requests: Vec<Value> =
+            serde_json::from_str(&content).map_err(ConfigFileError::Parse)?;
+
+        for (index, request) in requests.into_iter().enumerate() {
+            let request: ConfigRequest = serde_json::from_value(request)
+                .map_err(|e| ConfigFileError::Request(index, e.to_string()))?;
+            let method = request
+                .method
+                .parse::<Method>()
+                .map_err(|e| ConfigFileError::Request(index, e.to_string()))?;
+            let body = match request.body {
+                Some(body) => Chunk::from(body.to_string()),
+                None => Chunk::from(Vec::new()),
+            };
+
+            handle_request(
+                method,
+                &request.path,
+                &body,
+                &self.mmds_info,
+                &self.api_request_sender,
+                &self.efd,
+            )
+            .map_err(|e| match e {
+                RequestError::Invalid(msg) => ConfigFileError::Request(index, msg),
+                RequestError::Vmm(err) => ConfigFileError::Vmm(index, err),
+            })?;
+        }
+        Ok(())
+    }
+
     pub fn get_event_fd_clone(&self) -> Result<EventFd> {
         self.efd.try_clone().map_err(Error::Eventfd)
     }
@@ -163,8 +299,20 @@ impl ApiServer {
 
 #[cfg(test)]
 mod tests {
+    extern crate tempfile;
+
     use super::*;
 
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use self::tempfile::NamedTempFile;
+    use futures::sync::oneshot;
+    use vmm::vmm_config::drive::DEFAULT_MAX_RAMDISK_SIZE_MIB;
+    use vmm::VmmData;
+
     #[test]
     fn test_error_messages() {
         let e = Error::Io(io::Error::from_raw_os_error(0));
@@ -192,4 +340,183 @@ mod tests {
             format!("EventFd error: {}", io::Error::from_raw_os_error(0))
         );
     }
+
+    fn write_config_file(content: &str) -> NamedTempFile {
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file.write_all(content.as_bytes()).unwrap();
+        config_file
+    }
+
+    #[test]
+    fn test_apply_config_file() {
+        let mmds_info = Arc::new(Mutex::new(Mmds::default()));
+        let shared_info = Arc::new(RwLock::new(InstanceInfo {
+            state: vmm::vmm_config::instance_info::InstanceState::Uninitialized,
+            id: "config-file-test".to_string(),
+            vmm_version: "1.0".to_string(),
+            stop_reason: None,
+        }));
+        let (to_vmm, from_api) = mpsc::channel();
+        let server =
+            ApiServer::new(mmds_info.clone(), shared_info.clone(), to_vmm.clone()).unwrap();
+        // The microVM is never started, so the seccomp level doesn't matter here.
+        vmm::start_vmm_thread(
+            shared_info,
+            server.get_event_fd_clone().unwrap(),
+            from_api,
+            0,
+            None,
+            None,
+            DEFAULT_MAX_RAMDISK_SIZE_MIB,
+        );
+
+        let root_file = NamedTempFile::new().unwrap();
+        let root_path = root_file.path().to_str().unwrap();
+
+        // Error case: the file does not exist.
+        match server.apply_config_file(Path::new("/invalid/config/file")) {
+            Err(ConfigFileError::Read(_)) => (),
+            _ => panic!("Expected a read error."),
+        }
+
+        // Error case: the file does not hold a JSON array.
+        let config_file = write_config_file(r#"{"method": "PUT"}"#);
+        match server.apply_config_file(config_file.path()) {
+            Err(ConfigFileError::Parse(_)) => (),
+            _ => panic!("Expected a parse error."),
+        }
+
+        let config_file = write_config_file(&format!(
+            r#"[
+                {{
+                    "method": "PUT",
+                    "path": "/machine-config",
+                    "body": {{ "vcpu_count": 2, "mem_size_mib": 256, "ht_enabled": false }}
+                }},
+                {{
+                    "method": "PUT",
+                    "path": "/drives/rootfs",
+                    "body": {{
+                        "drive_id": "rootfs",
+                        "path_on_host": "{}",
+                        "is_root_device": true,
+                        "is_read_only": false
+                    }}
+                }},
+                {{
+                    "method": "PUT",
+                    "path": "/mmds",
+                    "body": {{ "latest": {{ "ami-id": "ami-12345678" }} }}
+                }}
+            ]"#,
+            root_path
+        ));
+        assert!(server.apply_config_file(config_file.path()).is_ok());
+
+        // The machine configuration reached the VMM.
+        let (sender, receiver) = oneshot::channel();
+        to_vmm
+            .send(Box::new(VmmAction::GetVmConfiguration(sender)))
+            .unwrap();
+        server.get_event_fd_clone().unwrap().write(1).unwrap();
+        match receiver.wait() {
+            Ok(Ok(VmmData::MachineConfiguration(vm_config))) => {
+                assert_eq!(vm_config.vcpu_count, Some(2));
+                assert_eq!(vm_config.mem_size_mib, Some(256));
+            }
+            _ => panic!("Expected the machine configuration."),
+        }
+        // The MMDS contents were updated.
+        assert!(mmds_info
+            .lock()
+            .unwrap()
+            .get_data_str()
+            .contains("ami-12345678"));
+
+        // Error case: the second request is rejected by the VMM.
+        let config_file = write_config_file(&format!(
+            r#"[
+                {{
+                    "method": "PATCH",
+                    "path": "/machine-config",
+                    "body": {{ "vcpu_count": 1 }}
+                }},
+                {{
+                    "method": "PUT",
+                    "path": "/drives/other",
+                    "body": {{
+                        "drive_id": "other",
+                        "path_on_host": "{}",
+                        "is_root_device": true,
+                        "is_read_only": false
+                    }}
+                }}
+            ]"#,
+            root_path
+        ));
+        match server.apply_config_file(config_file.path()) {
+            Err(ConfigFileError::Vmm(1, err)) => {
+                assert_eq!(err.to_string(), "A root block device already exists!")
+            }
+            _ => panic!("Expected the second request to fail."),
+        }
+
+        // Error case: the first request is malformed.
+        let config_file = write_config_file(r#"[{"method": "PUT", "foo": 1}]"#);
+        match server.apply_config_file(config_file.path()) {
+            Err(ConfigFileError::Request(0, _)) => (),
+            _ => panic!("Expected the first request to be rejected."),
+        }
+
+        // Error case: the path is not handled by the API.
+        let config_file = write_config_file(r#"[{"method": "GET", "path": "/mmds"}]"#);
+        match server.apply_config_file(config_file.path()) {
+            Err(ConfigFileError::Request(0, msg)) => {
+                assert_eq!(msg, "Unsupported request method and path: GET /mmds")
+            }
+            _ => panic!("Expected the first request to be rejected."),
+        }
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let sock_path = dir.path().join("api.socket");
+
+        let server_path = sock_path.clone();
+        thread::spawn(move || {
+            let shared_info = Arc::new(RwLock::new(InstanceInfo {
+                state: vmm::vmm_config::instance_info::InstanceState::Uninitialized,
+                id: "max-connections-test".to_string(),
+                vmm_version: "1.0".to_string(),
+                stop_reason: None,
+            }));
+            let mmds_info = Arc::new(Mutex::new(Mmds::default()));
+            let (to_vmm, _from_api) = mpsc::channel();
+            let server = ApiServer::new(mmds_info, shared_info, to_vmm).unwrap();
+            server.bind_and_run(server_path, None, None, 0, 2).unwrap();
+        });
+        // Connecting to find out whether the server is listening would use up a connection.
+        while !sock_path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut first = UnixStream::connect(&sock_path).unwrap();
+        let _second = UnixStream::connect(&sock_path).unwrap();
+        // The third connection is closed without being served.
+        let mut third = UnixStream::connect(&sock_path).unwrap();
+        third
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0u8; 64];
+        assert_eq!(third.read(&mut buf).unwrap(), 0);
+
+        // The connections within the limit are still served.
+        first
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        first.write_all(b"GET /mmds HTTP/1.1\r\n\r\n").unwrap();
+        let len = first.read(&mut buf).unwrap();
+        assert!(buf[..len].starts_with(b"HTTP/1.1 "));
+    }
 }
@@ -15,10 +15,18 @@ use vmm::VmmAction;
 // struct from the Serde deserialization process.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 enum ActionType {
+    AppendBootArgs,
+    BlockDeviceFlush,
     BlockDeviceRescan,
     FlushMetrics,
+    #[cfg(feature = "vsock")]
+    FsFreeze,
+    #[cfg(feature = "vsock")]
+    FsThaw,
     InstanceStart,
+    SealApi,
     SendCtrlAltDel,
+    SendReset,
 }
 
 // The model of the json body from a sync request. We use Serde to transform each associated
@@ -31,8 +39,32 @@ pub struct ActionBody {
     payload: Option<Value>,
 }
 
+impl ActionBody {
+    /// Whether the action is still allowed once the API is sealed. Only flushing the metrics
+    /// is, since it doesn't change the microVM.
+    pub fn allowed_when_sealed(&self) -> bool {
+        self.action_type == ActionType::FlushMetrics
+    }
+}
+
 fn validate_payload(action_body: &ActionBody) -> Result<(), String> {
     match action_body.action_type {
+        ActionType::AppendBootArgs => {
+            match action_body.payload {
+                Some(ref payload) => {
+                    // Expecting to have the boot arguments as a String in the payload.
+                    if !payload.is_string() {
+                        return Err(
+                            "Invalid payload type. Expected a string representing the boot \
+                             arguments"
+                                .to_string(),
+                        );
+                    }
+                    Ok(())
+                }
+                None => Err("Payload is required for appending boot arguments.".to_string()),
+            }
+        }
         ActionType::BlockDeviceRescan => {
             match action_body.payload {
                 Some(ref payload) => {
@@ -48,8 +80,33 @@ fn validate_payload(action_body: &ActionBody) -> Result<(), String> {
                 None => Err("Payload is required for block device rescan.".to_string()),
             }
         }
-        ActionType::FlushMetrics | ActionType::InstanceStart | ActionType::SendCtrlAltDel => {
-            // Neither FlushMetrics nor InstanceStart should have a payload.
+        #[cfg(feature = "vsock")]
+        ActionType::FsFreeze | ActionType::FsThaw => {
+            match action_body.payload {
+                Some(ref payload) => {
+                    // Expecting to have the id of the vsock device as a String in the payload.
+                    if !payload.is_string() {
+                        return Err(
+                            "Invalid payload type. Expected a string representing the id of \
+                             the vsock device"
+                                .to_string(),
+                        );
+                    }
+                    Ok(())
+                }
+                None => Err(format!(
+                    "Payload is required for {:?}.",
+                    action_body.action_type
+                )),
+            }
+        }
+        ActionType::BlockDeviceFlush
+        | ActionType::FlushMetrics
+        | ActionType::InstanceStart
+        | ActionType::SealApi
+        | ActionType::SendCtrlAltDel
+        | ActionType::SendReset => {
+            // None of these actions should have a payload.
             if action_body.payload.is_some() {
                 return Err(format!(
                     "{:?} does not support a payload.",
@@ -69,6 +126,22 @@ impl IntoParsedRequest for ActionBody {
     ) -> result::Result<ParsedRequest, String> {
         validate_payload(&self)?;
         match self.action_type {
+            ActionType::AppendBootArgs => {
+                // Safe to unwrap because we validated the payload in the validate_payload func.
+                let boot_args = self.payload.unwrap().as_str().unwrap().to_string();
+                let (sync_sender, sync_receiver) = oneshot::channel();
+                Ok(ParsedRequest::Sync(
+                    VmmAction::AppendBootArgs(boot_args, sync_sender),
+                    sync_receiver,
+                ))
+            }
+            ActionType::BlockDeviceFlush => {
+                let (sync_sender, sync_receiver) = oneshot::channel();
+                Ok(ParsedRequest::Sync(
+                    VmmAction::FlushBlockDevices(sync_sender),
+                    sync_receiver,
+                ))
+            }
             ActionType::BlockDeviceRescan => {
                 // Safe to unwrap because we validated the payload in the validate_payload func.
                 let block_device_id = self.payload.unwrap().as_str().unwrap().to_string();
@@ -85,6 +158,26 @@ impl IntoParsedRequest for ActionBody {
                     sync_receiver,
                 ))
             }
+            #[cfg(feature = "vsock")]
+            ActionType::FsFreeze => {
+                // Safe to unwrap because we validated the payload in the validate_payload func.
+                let vsock_id = self.payload.unwrap().as_str().unwrap().to_string();
+                let (sync_sender, sync_receiver) = oneshot::channel();
+                Ok(ParsedRequest::Sync(
+                    VmmAction::FreezeFilesystems(vsock_id, sync_sender),
+                    sync_receiver,
+                ))
+            }
+            #[cfg(feature = "vsock")]
+            ActionType::FsThaw => {
+                // Safe to unwrap because we validated the payload in the validate_payload func.
+                let vsock_id = self.payload.unwrap().as_str().unwrap().to_string();
+                let (sync_sender, sync_receiver) = oneshot::channel();
+                Ok(ParsedRequest::Sync(
+                    VmmAction::ThawFilesystems(vsock_id, sync_sender),
+                    sync_receiver,
+                ))
+            }
             ActionType::InstanceStart => {
                 let (sync_sender, sync_receiver) = oneshot::channel();
                 Ok(ParsedRequest::Sync(
@@ -92,6 +185,8 @@ impl IntoParsedRequest for ActionBody {
                     sync_receiver,
                 ))
             }
+            // Sealing is handled by the API server, without involving the VMM.
+            ActionType::SealApi => Ok(ParsedRequest::SealApi),
             ActionType::SendCtrlAltDel => {
                 let (sync_sender, sync_receiver) = oneshot::channel();
                 Ok(ParsedRequest::Sync(
@@ -99,6 +194,13 @@ impl IntoParsedRequest for ActionBody {
                     sync_receiver,
                 ))
             }
+            ActionType::SendReset => {
+                let (sync_sender, sync_receiver) = oneshot::channel();
+                Ok(ParsedRequest::Sync(
+                    VmmAction::SendReset(sync_sender),
+                    sync_receiver,
+                ))
+            }
         }
     }
 }
@@ -123,6 +225,25 @@ mod tests {
         };
         assert!(validate_payload(&action_body).is_err());
 
+        // Test AppendBootArgs.
+        let action_body = ActionBody {
+            action_type: ActionType::AppendBootArgs,
+            payload: Some(Value::String(String::from("foo=bar"))),
+        };
+        assert!(validate_payload(&action_body).is_ok());
+        // Error case: no payload.
+        let action_body = ActionBody {
+            action_type: ActionType::AppendBootArgs,
+            payload: None,
+        };
+        assert!(validate_payload(&action_body).is_err());
+        // Error case: payload is not String.
+        let action_body = ActionBody {
+            action_type: ActionType::AppendBootArgs,
+            payload: Some(Value::from(1)),
+        };
+        assert!(validate_payload(&action_body).is_err());
+
         // Test BlockDeviceRescan
         let action_body = ActionBody {
             action_type: ActionType::BlockDeviceRescan,
@@ -142,6 +263,44 @@ mod tests {
         };
         assert!(validate_payload(&action_body).is_err());
 
+        // Test BlockDeviceFlush.
+        let action_body = ActionBody {
+            action_type: ActionType::BlockDeviceFlush,
+            payload: None,
+        };
+        assert!(validate_payload(&action_body).is_ok());
+        // Error case: BlockDeviceFlush with payload.
+        let action_body = ActionBody {
+            action_type: ActionType::BlockDeviceFlush,
+            payload: Some(Value::String(String::from("dummy_id"))),
+        };
+        assert!(validate_payload(&action_body).is_err());
+
+        // Test FsFreeze and FsThaw.
+        #[cfg(feature = "vsock")]
+        for action_type in &[ActionType::FsFreeze, ActionType::FsThaw] {
+            let action_body = ActionBody {
+                action_type: action_type.clone(),
+                payload: Some(Value::String(String::from("vsock0"))),
+            };
+            assert!(validate_payload(&action_body).is_ok());
+            // Error case: no payload.
+            let action_body = ActionBody {
+                action_type: action_type.clone(),
+                payload: None,
+            };
+            assert_eq!(
+                validate_payload(&action_body).unwrap_err(),
+                format!("Payload is required for {:?}.", action_type)
+            );
+            // Error case: payload is not String.
+            let action_body = ActionBody {
+                action_type: action_type.clone(),
+                payload: Some(Value::from(3)),
+            };
+            assert!(validate_payload(&action_body).is_err());
+        }
+
         // Test FlushMetrics.
         let action_body = ActionBody {
             action_type: ActionType::FlushMetrics,
@@ -158,6 +317,19 @@ mod tests {
         assert!(res.is_err());
         assert_eq!(res.unwrap_err(), "FlushMetrics does not support a payload.");
 
+        // Test SealApi.
+        let action_body = ActionBody {
+            action_type: ActionType::SealApi,
+            payload: None,
+        };
+        assert!(validate_payload(&action_body).is_ok());
+        // Error case: SealApi with payload.
+        let action_body = ActionBody {
+            action_type: ActionType::SealApi,
+            payload: Some(Value::String("dummy-payload".to_string())),
+        };
+        assert!(validate_payload(&action_body).is_err());
+
         // Test SendCtrlAltDel.
         let action_body = ActionBody {
             action_type: ActionType::SendCtrlAltDel,
@@ -170,10 +342,43 @@ mod tests {
             payload: Some(Value::String("dummy-payload".to_string())),
         };
         assert!(validate_payload(&action_body).is_err());
+
+        // Test SendReset.
+        let action_body = ActionBody {
+            action_type: ActionType::SendReset,
+            payload: None,
+        };
+        assert!(validate_payload(&action_body).is_ok());
+        // Error case: SendReset with payload.
+        let action_body = ActionBody {
+            action_type: ActionType::SendReset,
+            payload: Some(Value::String("dummy-payload".to_string())),
+        };
+        assert!(validate_payload(&action_body).is_err());
     }
 
     #[test]
     fn test_into_parsed_request() {
+        {
+            let json = r#"{
+                "action_type": "AppendBootArgs",
+                "payload": "foo=bar quiet"
+              }"#;
+            let (sender, receiver) = oneshot::channel();
+            let req = ParsedRequest::Sync(
+                VmmAction::AppendBootArgs("foo=bar quiet".to_string(), sender),
+                receiver,
+            );
+
+            let result: Result<ActionBody, serde_json::Error> = serde_json::from_str(json);
+            assert!(result.is_ok());
+            assert!(result
+                .unwrap()
+                .into_parsed_request(None, Method::Put)
+                .unwrap()
+                .eq(&req));
+        }
+
         {
             let json = r#"{
                 "action_type": "BlockDeviceRescan",
@@ -194,6 +399,64 @@ mod tests {
                 .eq(&req));
         }
 
+        {
+            let json = r#"{
+                "action_type": "BlockDeviceFlush"
+            }"#;
+            let (sender, receiver) = oneshot::channel();
+            let req = ParsedRequest::Sync(VmmAction::FlushBlockDevices(sender), receiver);
+
+            let result: Result<ActionBody, serde_json::Error> = serde_json::from_str(json);
+            assert!(result.is_ok());
+            assert!(result
+                .unwrap()
+                .into_parsed_request(None, Method::Put)
+                .unwrap()
+                .eq(&req));
+        }
+
+        #[cfg(feature = "vsock")]
+        {
+            let json = r#"{
+                "action_type": "FsFreeze",
+                "payload": "vsock0"
+              }"#;
+            let (sender, receiver) = oneshot::channel();
+            let req = ParsedRequest::Sync(
+                VmmAction::FreezeFilesystems("vsock0".to_string(), sender),
+                receiver,
+            );
+
+            let result: Result<ActionBody, serde_json::Error> = serde_json::from_str(json);
+            assert!(result.is_ok());
+            assert!(result
+                .unwrap()
+                .into_parsed_request(None, Method::Put)
+                .unwrap()
+                .eq(&req));
+        }
+
+        #[cfg(feature = "vsock")]
+        {
+            let json = r#"{
+                "action_type": "FsThaw",
+                "payload": "vsock0"
+              }"#;
+            let (sender, receiver) = oneshot::channel();
+            let req = ParsedRequest::Sync(
+                VmmAction::ThawFilesystems("vsock0".to_string(), sender),
+                receiver,
+            );
+
+            let result: Result<ActionBody, serde_json::Error> = serde_json::from_str(json);
+            assert!(result.is_ok());
+            assert!(result
+                .unwrap()
+                .into_parsed_request(None, Method::Put)
+                .unwrap()
+                .eq(&req));
+        }
+
         {
             let json = r#"{
                 "action_type": "InstanceStart"
@@ -210,6 +473,20 @@ mod tests {
                 .eq(&req));
         }
 
+        {
+            let json = r#"{
+                "action_type": "SealApi"
+            }"#;
+
+            let result: Result<ActionBody, serde_json::Error> = serde_json::from_str(json);
+            assert!(result.is_ok());
+            assert!(result
+                .unwrap()
+                .into_parsed_request(None, Method::Put)
+                .unwrap()
+                .eq(&ParsedRequest::SealApi));
+        }
+
         {
             let json = r#"{
                 "action_type": "SendCtrlAltDel"
@@ -227,6 +504,22 @@ mod tests {
                 .eq(&req));
         }
 
+        {
+            let json = r#"{
+                "action_type": "SendReset"
+            }"#;
+
+            let (sender, receiver) = oneshot::channel();
+            let req: ParsedRequest = ParsedRequest::Sync(VmmAction::SendReset(sender), receiver);
+            let result: Result<ActionBody, serde_json::Error> = serde_json::from_str(json);
+            assert!(result.is_ok());
+            assert!(result
+                .unwrap()
+                .into_parsed_request(None, Method::Put)
+                .unwrap()
+                .eq(&req));
+        }
+
         {
             let json = r#"{
                 "action_type": "FlushMetrics"
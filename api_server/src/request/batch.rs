@@ -0,0 +1,182 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use hyper::Method;
+use serde_json::Value;
+
+use request::{IntoParsedRequest, ParsedRequest};
+
+// An operation of a batch, described the same way as it would be sent over the API socket.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchOperationBody {
+    method: String,
+    path: String,
+    #[serde(default)]
+    body: Option<Value>,
+}
+
+/// The body of a PUT /batch request.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchBody {
+    operations: Vec<BatchOperationBody>,
+    #[serde(default)]
+    continue_on_error: bool,
+}
+
+/// An API call of a batch, checked before any operation of the batch is executed.
+#[derive(Debug, PartialEq)]
+pub struct BatchOperation {
+    pub method: Method,
+    pub path: String,
+    pub body: String,
+}
+
+/// API calls executed one after the other, each once the previous one got its response.
+#[derive(Debug, PartialEq)]
+pub struct Batch {
+    pub operations: Vec<BatchOperation>,
+    /// Whether the operations following a failed one are still executed.
+    pub continue_on_error: bool,
+}
+
+/// The response to an operation of a batch.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BatchResult {
+    /// The position of the operation in the batch.
+    pub index: usize,
+    pub status_code: u16,
+    /// The JSON body of the response, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
+
+impl IntoParsedRequest for BatchBody {
+    fn into_parsed_request(
+        self,
+        _: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        if self.operations.is_empty() {
+            return Err(String::from("The batch holds no operations."));
+        }
+
+        let mut operations = Vec::with_capacity(self.operations.len());
+        for (index, operation) in self.operations.into_iter().enumerate() {
+            let method = operation.method.parse::<Method>().map_err(|e| {
+                format!(
+                    "Operation {} of the batch has an invalid method: {}",
+                    index, e
+                )
+            })?;
+            if operation.path.split('/').nth(1) == Some("batch") {
+                return Err(format!(
+                    "Operation {} of the batch is a batch itself. Batches cannot be nested.",
+                    index
+                ));
+            }
+            operations.push(BatchOperation {
+                method,
+                path: operation.path,
+                body: operation
+                    .body
+                    .map(|body| body.to_string())
+                    .unwrap_or_default(),
+            });
+        }
+
+        Ok(ParsedRequest::Batch(Batch {
+            operations,
+            continue_on_error: self.continue_on_error,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    fn parse(body: &str) -> result::Result<ParsedRequest, String> {
+        serde_json::from_str::<BatchBody>(body)
+            .unwrap()
+            .into_parsed_request(None, Method::Put)
+    }
+
+    #[test]
+    fn test_batch_into_parsed_request() {
+        let parsed = parse(
+            r#"{
+                "operations": [
+                    { "method": "PUT", "path": "/mmds", "body": { "foo": "bar" } },
+                    { "method": "GET", "path": "/machine-config" }
+                ],
+                "continue_on_error": true
+            }"#,
+        )
+        .unwrap();
+        assert!(parsed.eq(&ParsedRequest::Batch(Batch {
+            operations: vec![
+                BatchOperation {
+                    method: Method::Put,
+                    path: String::from("/mmds"),
+                    body: String::from("{\"foo\":\"bar\"}"),
+                },
+                BatchOperation {
+                    method: Method::Get,
+                    path: String::from("/machine-config"),
+                    body: String::new(),
+                },
+            ],
+            continue_on_error: true,
+        })));
+
+        // The batch stops at the first failed operation by default.
+        match parse(r#"{ "operations": [{ "method": "GET", "path": "/" }] }"#) {
+            Ok(ParsedRequest::Batch(batch)) => assert!(!batch.continue_on_error),
+            _ => panic!("Expected a batch."),
+        }
+
+        assert_eq!(
+            parse(r#"{ "operations": [] }"#).err().unwrap(),
+            "The batch holds no operations."
+        );
+        assert_eq!(
+            parse(
+                r#"{ "operations": [
+                    { "method": "GET", "path": "/" },
+                    { "method": "PUT", "path": "/batch", "body": { "operations": [] } }
+                ] }"#
+            )
+            .err()
+            .unwrap(),
+            "Operation 1 of the batch is a batch itself. Batches cannot be nested."
+        );
+        assert!(parse(r#"{ "operations": [{ "method": "", "path": "/" }] }"#).is_err());
+        assert!(serde_json::from_str::<BatchBody>(r#"{ "operations": [], "foo": 1 }"#).is_err());
+    }
+
+    #[test]
+    fn test_batch_result_serialization() {
+        let results = vec![
+            BatchResult {
+                index: 0,
+                status_code: 204,
+                body: None,
+            },
+            BatchResult {
+                index: 1,
+                status_code: 400,
+                body: Some(serde_json::from_str(r#"{ "fault_message": "foo" }"#).unwrap()),
+            },
+        ];
+        assert_eq!(
+            serde_json::to_string(&results).unwrap(),
+            r#"[{"index":0,"status_code":204},{"index":1,"status_code":400,"body":{"fault_message":"foo"}}]"#
+        );
+    }
+}
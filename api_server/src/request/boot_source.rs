@@ -33,10 +33,18 @@ mod tests {
         let body = BootSourceConfig {
             kernel_image_path: String::from("/foo/bar"),
             boot_args: Some(String::from("foobar")),
+            cmdline_max_size: None,
+            rng_seed: true,
+            console: None,
+            diskless: false,
         };
         let same_body = BootSourceConfig {
             kernel_image_path: String::from("/foo/bar"),
             boot_args: Some(String::from("foobar")),
+            cmdline_max_size: None,
+            rng_seed: true,
+            console: None,
+            diskless: false,
         };
         let (sender, receiver) = oneshot::channel();
         assert!(body
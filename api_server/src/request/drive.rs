@@ -253,8 +253,14 @@ mod tests {
             path_on_host: PathBuf::from(String::from("/foo/bar")),
             is_root_device: true,
             is_read_only: true,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             partuuid: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         assert!(
             desc.into_parsed_request(Some(String::from("foo")), Method::Options)
@@ -267,16 +273,28 @@ mod tests {
             path_on_host: PathBuf::from(String::from("/foo/bar")),
             is_root_device: true,
             is_read_only: true,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             partuuid: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         let same_desc = BlockDeviceConfig {
             drive_id: String::from("foo"),
             path_on_host: PathBuf::from(String::from("/foo/bar")),
             is_root_device: true,
             is_read_only: true,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             partuuid: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         let (sender, receiver) = oneshot::channel();
         assert!(desc
@@ -39,6 +39,7 @@ mod tests {
             level: LoggerLevel::Warning,
             show_level: false,
             show_log_origin: false,
+            max_log_size: None,
             options: Value::Array(vec![]),
         };
         format!("{:?}", desc);
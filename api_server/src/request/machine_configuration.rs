@@ -5,6 +5,7 @@ use std::result;
 
 use futures::sync::oneshot;
 use hyper::{Method, Response, StatusCode};
+use serde_json;
 
 use http_service::json_response;
 use request::{GenerateHyperResponse, IntoParsedRequest, ParsedRequest};
@@ -20,11 +21,110 @@ impl GenerateHyperResponse for VmConfig {
             .cpu_template
             .map_or("Uninitialized".to_string(), |c| c.to_string());
 
+        let cpu_topology = self.cpu_topology.map_or(String::new(), |t| {
+            format!(
+                ",  \"cpu_topology\": {{ \"sockets\": {}, \"cores_per_socket\": {}, \"threads_per_core\": {} }}",
+                t.sockets, t.cores_per_socket, t.threads_per_core
+            )
+        });
+
+        let prefault_memory = self.prefault_memory.map_or(String::new(), |p| {
+            format!(",  \"prefault_memory\": {:?}", p)
+        });
+
+        let zero_memory = self
+            .zero_memory
+            .map_or(String::new(), |z| format!(",  \"zero_memory\": {:?}", z));
+
+        let numa_node = self
+            .numa_node
+            .map_or(String::new(), |n| format!(",  \"numa_node\": {}", n));
+
+        let vcpu_affinity = self.vcpu_affinity.as_ref().map_or(String::new(), |a| {
+            let entries: Vec<String> = a
+                .iter()
+                .map(|(vcpu, cpu)| format!("\"{}\": {}", vcpu, cpu))
+                .collect();
+            format!(",  \"vcpu_affinity\": {{ {} }}", entries.join(", "))
+        });
+
+        let irq_affinity = self
+            .irq_affinity
+            .as_ref()
+            .and_then(|a| serde_json::to_string(a).ok())
+            .map_or(String::new(), |a| format!(",  \"irq_affinity\": {}", a));
+
+        let vcpu_scheduling = self
+            .vcpu_scheduling
+            .and_then(|s| serde_json::to_string(&s).ok())
+            .map_or(String::new(), |s| format!(",  \"vcpu_scheduling\": {}", s));
+
+        let high_mmio = self
+            .high_mmio
+            .as_ref()
+            .and_then(|h| serde_json::to_string(h).ok())
+            .map_or(String::new(), |h| format!(",  \"high_mmio\": {}", h));
+
+        let reserved_memory = self
+            .reserved_memory
+            .as_ref()
+            .and_then(|r| serde_json::to_string(r).ok())
+            .map_or(String::new(), |r| format!(",  \"reserved_memory\": {}", r));
+
+        let gic_version = self
+            .gic_version
+            .and_then(|v| serde_json::to_string(&v).ok())
+            .map_or(String::new(), |v| format!(",  \"gic_version\": {}", v));
+
+        let serial_enabled = self
+            .serial_enabled
+            .map_or(String::new(), |s| format!(",  \"serial_enabled\": {:?}", s));
+
+        let rtc_base_time = self
+            .rtc_base_time
+            .map_or(String::new(), |t| format!(",  \"rtc_base_time\": {}", t));
+
+        let clock_source = self
+            .clock_source
+            .map_or(String::new(), |c| format!(",  \"clock_source\": \"{}\"", c));
+
+        let sev_enabled = self
+            .sev_enabled
+            .map_or(String::new(), |s| format!(",  \"sev_enabled\": {:?}", s));
+
+        let apic_mode = self
+            .apic_mode
+            .and_then(|m| serde_json::to_string(&m).ok())
+            .map_or(String::new(), |m| format!(",  \"apic_mode\": {}", m));
+
+        let guest_panic_action = self.guest_panic_action.map_or(String::new(), |a| {
+            format!(",  \"guest_panic_action\": \"{}\"", a)
+        });
+
         json_response(
             StatusCode::Ok,
             format!(
-                "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?},  \"ht_enabled\": {:?},  \"cpu_template\": {:?} }}",
-                vcpu_count, mem_size, ht_enabled, cpu_template
+                "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?},  \"ht_enabled\": {:?},  \"cpu_template\": {:?}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{} }}",
+                vcpu_count,
+                mem_size,
+                ht_enabled,
+                cpu_template,
+                cpu_topology,
+                prefault_memory,
+                zero_memory,
+                numa_node,
+                vcpu_affinity,
+                irq_affinity,
+                vcpu_scheduling,
+                high_mmio,
+                reserved_memory,
+                gic_version,
+                serial_enabled,
+                rtc_base_time,
+                clock_source,
+                sev_enabled,
+                apic_mode,
+                guest_panic_action
             ),
         )
     }
@@ -47,6 +147,22 @@ impl IntoParsedRequest for VmConfig {
                     && self.mem_size_mib.is_none()
                     && self.cpu_template.is_none()
                     && self.ht_enabled.is_none()
+                    && self.cpu_topology.is_none()
+                    && self.prefault_memory.is_none()
+                    && self.zero_memory.is_none()
+                    && self.numa_node.is_none()
+                    && self.vcpu_affinity.is_none()
+                    && self.irq_affinity.is_none()
+                    && self.vcpu_scheduling.is_none()
+                    && self.high_mmio.is_none()
+                    && self.reserved_memory.is_none()
+                    && self.gic_version.is_none()
+                    && self.serial_enabled.is_none()
+                    && self.rtc_base_time.is_none()
+                    && self.clock_source.is_none()
+                    && self.sev_enabled.is_none()
+                    && self.apic_mode.is_none()
+                    && self.guest_panic_action.is_none()
                 {
                     return Err(String::from("Empty PATCH request."));
                 }
@@ -58,7 +174,7 @@ impl IntoParsedRequest for VmConfig {
             Method::Put => {
                 if self.vcpu_count.is_none()
                     || self.mem_size_mib.is_none()
-                    || self.ht_enabled.is_none()
+                    || (self.ht_enabled.is_none() && self.cpu_topology.is_none())
                 {
                     return Err(String::from("Missing mandatory fields."));
                 }
@@ -75,7 +191,9 @@ impl IntoParsedRequest for VmConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use vmm::vmm_config::machine_config::CpuFeaturesTemplate;
+    use vmm::vmm_config::machine_config::{
+        ApicMode, ClockSource, CpuFeaturesTemplate, CpuTopology, GicVersion,
+    };
 
     #[test]
     fn test_into_parsed_request() {
@@ -84,6 +202,22 @@ mod tests {
             mem_size_mib: Some(1024),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         let (sender, receiver) = oneshot::channel();
         assert!(body
@@ -99,6 +233,22 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         assert!(uninitialized
             .clone()
@@ -111,16 +261,125 @@ mod tests {
             .into_parsed_request(None, Method::Patch)
             .is_err());
 
+        // A PATCH only zeroing the guest memory is not empty.
+        let body = VmConfig {
+            zero_memory: Some(true),
+            ..uninitialized.clone()
+        };
+        assert!(body.into_parsed_request(None, Method::Patch).is_ok());
+
+        // A PATCH only setting the IRQ affinity is not empty.
+        let body = VmConfig {
+            irq_affinity: Some([(String::from("net0"), 1)].iter().cloned().collect()),
+            ..uninitialized.clone()
+        };
+        assert!(body.into_parsed_request(None, Method::Patch).is_ok());
+
+        // A PATCH only setting the GIC version is not empty.
+        let body = VmConfig {
+            gic_version: Some(GicVersion::V2),
+            ..uninitialized.clone()
+        };
+        assert!(body.into_parsed_request(None, Method::Patch).is_ok());
+
+        // A PATCH only disabling the serial device is not empty.
+        let body = VmConfig {
+            serial_enabled: Some(false),
+            ..uninitialized.clone()
+        };
+        assert!(body.into_parsed_request(None, Method::Patch).is_ok());
+
+        // A PATCH only setting the RTC base time is not empty.
+        let body = VmConfig {
+            rtc_base_time: Some(0),
+            ..uninitialized.clone()
+        };
+        assert!(body.into_parsed_request(None, Method::Patch).is_ok());
+
+        // A PATCH only setting the clock source is not empty.
+        let body = VmConfig {
+            clock_source: Some(ClockSource::Tsc),
+            ..uninitialized.clone()
+        };
+        assert!(body.into_parsed_request(None, Method::Patch).is_ok());
+
+        // A PATCH only enabling SEV is not empty.
+        let body = VmConfig {
+            sev_enabled: Some(true),
+            ..uninitialized.clone()
+        };
+        assert!(body.into_parsed_request(None, Method::Patch).is_ok());
+
+        // A PATCH only setting the APIC mode is not empty.
+        let body = VmConfig {
+            apic_mode: Some(ApicMode::X2Apic),
+            guest_panic_action: None,
+            ..uninitialized.clone()
+        };
+        assert!(body.into_parsed_request(None, Method::Patch).is_ok());
+
         // Incomplete PUT payload
         let body = VmConfig {
             vcpu_count: Some(8),
             mem_size_mib: Some(1024),
             ht_enabled: None,
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         match body.into_parsed_request(None, Method::Put) {
             Ok(_) => assert!(false),
             Err(e) => assert_eq!(e, String::from("Missing mandatory fields.")),
         };
+
+        // The CPU topology replaces the hyperthreading flag in PUT requests.
+        let body = VmConfig {
+            vcpu_count: Some(4),
+            mem_size_mib: Some(1024),
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: Some(CpuTopology {
+                sockets: 1,
+                cores_per_socket: 2,
+                threads_per_core: 2,
+            }),
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        let (sender, receiver) = oneshot::channel();
+        assert!(body
+            .clone()
+            .into_parsed_request(None, Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::SetVmConfiguration(body, sender),
+                receiver
+            ))));
     }
 }
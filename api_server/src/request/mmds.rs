@@ -0,0 +1,81 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::TokenBucketConfig;
+
+/// The body of a PUT /mmds/config request.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MmdsConfig {
+    /// Limits the rate of the guest requests to the MMDS, each request taking a token from the
+    /// bucket. The guest requests are not limited when it is not set.
+    #[serde(default)]
+    pub rate_limiter: Option<TokenBucketConfig>,
+}
+
+impl IntoParsedRequest for MmdsConfig {
+    fn into_parsed_request(
+        self,
+        _: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        if let Some(bucket) = self.rate_limiter {
+            if bucket.size == 0 || bucket.refill_time == 0 {
+                return Err(String::from(
+                    "The MMDS rate limiter must have a positive size and refill time.",
+                ));
+            }
+        }
+        Ok(ParsedRequest::PutMMDSConfig(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    #[test]
+    fn test_mmds_config_into_parsed_request() {
+        let config: MmdsConfig = serde_json::from_str(
+            r#"{ "rate_limiter": { "size": 100, "one_time_burst": 50, "refill_time": 1000 } }"#,
+        )
+        .unwrap();
+        let expected = MmdsConfig {
+            rate_limiter: Some(TokenBucketConfig {
+                size: 100,
+                one_time_burst: Some(50),
+                refill_time: 1000,
+            }),
+        };
+        assert!(config
+            .into_parsed_request(None, Method::Put)
+            .unwrap()
+            .eq(&ParsedRequest::PutMMDSConfig(expected)));
+
+        // The rate limit can be lifted.
+        let config: MmdsConfig = serde_json::from_str("{}").unwrap();
+        assert!(config.into_parsed_request(None, Method::Put).unwrap().eq(
+            &ParsedRequest::PutMMDSConfig(MmdsConfig { rate_limiter: None })
+        ));
+
+        for bucket in &[
+            r#"{ "size": 0, "refill_time": 1000 }"#,
+            r#"{ "size": 100, "refill_time": 0 }"#,
+        ] {
+            let config: MmdsConfig =
+                serde_json::from_str(&format!(r#"{{ "rate_limiter": {} }}"#, bucket)).unwrap();
+            assert_eq!(
+                config.into_parsed_request(None, Method::Put).err().unwrap(),
+                "The MMDS rate limiter must have a positive size and refill time."
+            );
+        }
+        assert!(serde_json::from_str::<MmdsConfig>(r#"{ "foo": 1 }"#).is_err());
+    }
+}
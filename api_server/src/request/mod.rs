@@ -2,29 +2,45 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod actions;
+pub mod batch;
 pub mod boot_source;
 pub mod drive;
 pub mod logger;
 pub mod machine_configuration;
+pub mod mmds;
 pub mod net;
+#[cfg(feature = "vhost-user-block")]
+pub mod vhost_user_block;
+#[cfg(feature = "virtio-fs")]
+pub mod virtio_fs;
 #[cfg(feature = "vsock")]
 pub mod vsock;
 
-use serde_json::Value;
+use serde_json::{self, Value};
+use std::net::Ipv4Addr;
 use std::result;
 
 use hyper;
 use hyper::{Method, StatusCode};
 
+#[cfg(target_arch = "aarch64")]
+use http_service::octet_stream_response;
 use http_service::{empty_response, json_fault_message, json_response};
+use request::batch::Batch;
+use request::mmds::MmdsConfig;
 use vmm::{ErrorKind, OutcomeReceiver, VmmAction, VmmActionError, VmmData};
 
 #[allow(clippy::large_enum_variant)]
 pub enum ParsedRequest {
+    Batch(Batch),
     GetInstanceInfo,
-    GetMMDS,
-    PatchMMDS(Value),
-    PutMMDS(Value),
+    // The MMDS requests target the namespace of the given source address, if any, and the
+    // default data store otherwise.
+    GetMMDS(Option<Ipv4Addr>),
+    PatchMMDS(Option<Ipv4Addr>, Value),
+    PutMMDS(Option<Ipv4Addr>, Value),
+    PutMMDSConfig(MmdsConfig),
+    SealApi,
     Sync(VmmAction, OutcomeReceiver),
 }
 
@@ -57,6 +73,23 @@ impl GenerateHyperResponse for VmmData {
     fn generate_response(&self) -> hyper::Response {
         match *self {
             VmmData::MachineConfiguration(ref machine_config) => machine_config.generate_response(),
+            VmmData::Devices(ref devices) => match serde_json::to_string(devices) {
+                Ok(body) => json_response(StatusCode::Ok, body),
+                Err(e) => json_response(
+                    StatusCode::InternalServerError,
+                    json_fault_message(e.to_string()),
+                ),
+            },
+            #[cfg(target_arch = "aarch64")]
+            VmmData::Fdt(ref fdt) => octet_stream_response(StatusCode::Ok, fdt.clone()),
+            #[cfg(feature = "vsock")]
+            VmmData::AgentResponse(ref response) => match serde_json::to_string(response) {
+                Ok(body) => json_response(StatusCode::Ok, body),
+                Err(e) => json_response(
+                    StatusCode::InternalServerError,
+                    json_fault_message(e.to_string()),
+                ),
+            },
             VmmData::Empty => empty_response(StatusCode::NoContent),
         }
     }
@@ -69,6 +102,7 @@ impl GenerateHyperResponse for VmmActionError {
         let status_code = match self.kind() {
             User => StatusCode::BadRequest,
             Internal => StatusCode::InternalServerError,
+            NotFound => StatusCode::NotFound,
         };
 
         json_response(status_code, json_fault_message(self.to_string()))
@@ -83,14 +117,26 @@ impl PartialEq for ParsedRequest {
                 &ParsedRequest::Sync(ref sync_req, _),
                 &ParsedRequest::Sync(ref other_sync_req, _),
             ) => sync_req == other_sync_req,
-            (&ParsedRequest::GetInstanceInfo, &ParsedRequest::GetInstanceInfo) => true,
-            (&ParsedRequest::GetMMDS, &ParsedRequest::GetMMDS) => true,
-            (&ParsedRequest::PutMMDS(ref val), &ParsedRequest::PutMMDS(ref other_val)) => {
-                val == other_val
+            (&ParsedRequest::Batch(ref batch), &ParsedRequest::Batch(ref other_batch)) => {
+                batch == other_batch
             }
-            (&ParsedRequest::PatchMMDS(ref val), &ParsedRequest::PatchMMDS(ref other_val)) => {
-                val == other_val
+            (&ParsedRequest::GetInstanceInfo, &ParsedRequest::GetInstanceInfo) => true,
+            (&ParsedRequest::GetMMDS(namespace), &ParsedRequest::GetMMDS(other_namespace)) => {
+                namespace == other_namespace
             }
+            (&ParsedRequest::SealApi, &ParsedRequest::SealApi) => true,
+            (
+                &ParsedRequest::PutMMDS(namespace, ref val),
+                &ParsedRequest::PutMMDS(other_namespace, ref other_val),
+            ) => namespace == other_namespace && val == other_val,
+            (
+                &ParsedRequest::PatchMMDS(namespace, ref val),
+                &ParsedRequest::PatchMMDS(other_namespace, ref other_val),
+            ) => namespace == other_namespace && val == other_val,
+            (
+                &ParsedRequest::PutMMDSConfig(ref config),
+                &ParsedRequest::PutMMDSConfig(ref other_config),
+            ) => config == other_config,
             _ => false,
         }
     }
@@ -105,6 +151,7 @@ mod tests {
     extern crate net_util;
 
     use self::devices::virtio::net::Error as VirtioNetError;
+    use self::devices::DeviceHealth;
     use self::memory_model::GuestMemoryError;
     use self::net_util::TapError;
     use super::*;
@@ -112,6 +159,7 @@ mod tests {
     use std::io;
 
     use vmm::vmm_config::boot_source::BootSourceConfigError;
+    use vmm::vmm_config::device::{DeviceInfo, DeviceType};
     use vmm::vmm_config::drive::DriveError;
     use vmm::vmm_config::instance_info::StartMicrovmError;
     use vmm::vmm_config::logger::LoggerConfigError;
@@ -169,6 +217,37 @@ mod tests {
         let vm_config_json: serde_json::Value = serde_json::from_str(vm_config_json).unwrap();
         assert_eq!(get_body(hyper_resp).unwrap(), vm_config_json);
 
+        // Test OK response from VMM that contains the devices and their health status.
+        let vmm_resp = Ok(VmmData::Devices(vec![
+            DeviceInfo::new(
+                String::from("rootfs"),
+                DeviceType::Block,
+                &DeviceHealth::Failed(String::from("Read-only file system (os error 30)")),
+            ),
+            DeviceInfo::new(
+                String::from("eth0"),
+                DeviceType::Net,
+                &DeviceHealth::Healthy,
+            ),
+        ]));
+        let hyper_resp = vmm_resp.generate_response();
+        assert_eq!(hyper_resp.status(), StatusCode::Ok);
+        let devices_json = r#"[
+            {
+                "id": "rootfs",
+                "type": "block",
+                "health": "Failed",
+                "reason": "Read-only file system (os error 30)"
+            },
+            {
+                "id": "eth0",
+                "type": "net",
+                "health": "Healthy"
+            }
+        ]"#;
+        let devices_json: serde_json::Value = serde_json::from_str(devices_json).unwrap();
+        assert_eq!(get_body(hyper_resp).unwrap(), devices_json);
+
         // Tests Error Cases
         // Tests for BootSource Errors.
         let vmm_resp =
@@ -249,6 +328,11 @@ mod tests {
             NetworkInterfaceError::HostDeviceNameInUse(String::from("tap_name")),
         );
         check_error_response(vmm_resp, StatusCode::BadRequest);
+        let vmm_resp = VmmActionError::NetworkConfig(
+            ErrorKind::NotFound,
+            NetworkInterfaceError::DeviceIdNotFound,
+        );
+        check_error_response(vmm_resp, StatusCode::NotFound);
 
         // Tests for MicrovmStart Errors.
         // RegisterBlockDevice, RegisterNetDevice, and LegacyIOBus cannot be tested because the
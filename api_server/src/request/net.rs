@@ -62,6 +62,7 @@ mod tests {
 
     use serde_json;
 
+    use self::vmm::vmm_config::net::RxCoalescingConfig;
     use self::vmm::vmm_config::RateLimiterConfig;
 
     fn get_dummy_netif(
@@ -75,7 +76,12 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         }
     }
@@ -119,7 +125,15 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str("12:34:56:78:9A:BC").unwrap()),
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
+            rx_coalescing: Some(RxCoalescingConfig {
+                max_frames: 8,
+                timeout_us: 100,
+            }),
+            rx_buffer_frames: Some(256),
+            virtio_feature_mask: Some(0x1_0000_ffff),
+            gso_passthrough: true,
             allow_mmds_requests: true,
+            mmds_mss_clamp: Some(1200),
             tap: None,
         };
 
@@ -132,7 +146,15 @@ mod tests {
             },
             "tx_rate_limiter": {
             },
-            "allow_mmds_requests": true
+            "rx_coalescing": {
+                "max_frames": 8,
+                "timeout_us": 100
+            },
+            "rx_buffer_frames": 256,
+            "virtio_feature_mask": 4295032831,
+            "gso_passthrough": true,
+            "allow_mmds_requests": true,
+            "mmds_mss_clamp": 1200
         }"#;
 
         let x = serde_json::from_str(jstr).expect("deserialization failed.");
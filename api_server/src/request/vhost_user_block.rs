@@ -0,0 +1,58 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::vhost_user_block::VhostUserBlockDeviceConfig;
+use vmm::VmmAction;
+
+impl IntoParsedRequest for VhostUserBlockDeviceConfig {
+    fn into_parsed_request(
+        self,
+        id_from_path: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let id_from_path = id_from_path.unwrap_or_default();
+        if id_from_path != self.id.as_str() {
+            return Err(String::from(
+                "The id from the path does not match the id from the body!",
+            ));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        Ok(ParsedRequest::Sync(
+            VmmAction::InsertVhostUserBlockDevice(self, sender),
+            receiver,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vhost_user_block_into_parsed_request() {
+        let vhost_user_block = VhostUserBlockDeviceConfig {
+            id: String::from("foo"),
+            socket_path: String::from("/tmp/foo.sock"),
+        };
+        assert!(vhost_user_block
+            .clone()
+            .into_parsed_request(Some(String::from("bar")), Method::Put)
+            .is_err());
+
+        let (sender, receiver) = oneshot::channel();
+        assert!(vhost_user_block
+            .clone()
+            .into_parsed_request(Some(String::from("foo")), Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::InsertVhostUserBlockDevice(vhost_user_block, sender),
+                receiver
+            ))));
+    }
+}
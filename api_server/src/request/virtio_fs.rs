@@ -0,0 +1,59 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::virtio_fs::VirtioFsDeviceConfig;
+use vmm::VmmAction;
+
+impl IntoParsedRequest for VirtioFsDeviceConfig {
+    fn into_parsed_request(
+        self,
+        id_from_path: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let id_from_path = id_from_path.unwrap_or_default();
+        if id_from_path != self.id.as_str() {
+            return Err(String::from(
+                "The id from the path does not match the id from the body!",
+            ));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        Ok(ParsedRequest::Sync(
+            VmmAction::InsertVirtioFsDevice(self, sender),
+            receiver,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtio_fs_into_parsed_request() {
+        let virtio_fs = VirtioFsDeviceConfig {
+            id: String::from("foo"),
+            shared_dir: String::from("/srv/foo"),
+            tag: String::from("foo"),
+        };
+        assert!(virtio_fs
+            .clone()
+            .into_parsed_request(Some(String::from("bar")), Method::Put)
+            .is_err());
+
+        let (sender, receiver) = oneshot::channel();
+        assert!(virtio_fs
+            .clone()
+            .into_parsed_request(Some(String::from("foo")), Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::InsertVirtioFsDevice(virtio_fs, sender),
+                receiver
+            ))));
+    }
+}
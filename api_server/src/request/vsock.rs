@@ -40,6 +40,8 @@ mod tests {
         let vsock = VsockDeviceConfig {
             id: String::from("foo"),
             guest_cid: 42,
+            agent_port: None,
+            file_transfer: None,
         };
         assert!(vsock
             .clone()
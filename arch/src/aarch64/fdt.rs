@@ -7,7 +7,7 @@
 
 use byteorder::{BigEndian, ByteOrder};
 use libc::{c_char, c_int, c_void};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::ffi::{CStr, CString, NulError};
 use std::fmt::Debug;
 use std::ptr::null;
@@ -15,13 +15,21 @@ use std::{io, result};
 
 use super::super::DeviceType;
 use super::get_fdt_addr;
-use super::gic::{get_dist_addr, get_dist_size, get_redists_addr, get_redists_size};
+use super::gic::{
+    get_cpu_addr, get_cpu_size, get_dist_addr, get_dist_size, get_redists_addr, get_redists_size,
+    GicVersion,
+};
 use super::layout::FDT_MAX_SIZE;
 use aarch64::fdt::Error::CstringFDTTransform;
 use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
 
+/// The magic number found at the start of every flattened device tree blob.
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+
 // This is a value for uniquely identifying the FDT node declaring the interrupt controller.
 const GIC_PHANDLE: u32 = 1;
+// This is a value for uniquely identifying the FDT node declaring the clock of the RTC.
+const CLOCK_PHANDLE: u32 = 2;
 // Read the documentation specified when appending the root node to the FDT.
 const ADDRESS_CELLS: u32 = 0x2;
 const SIZE_CELLS: u32 = 0x2;
@@ -38,6 +46,9 @@ const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
 const IRQ_TYPE_EDGE_RISING: u32 = 1;
 const IRQ_TYPE_LEVEL_HI: u32 = 4;
 
+// The affinity fields of the MPIDR (Aff3 and Aff2..Aff0) used in the reg property of cpu nodes.
+const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
 // This links to libfdt which handles the creation of the binary blob
 // flattened device tree (fdt) that is passed to the kernel and indicates
 // the hardware configuration of the machine.
@@ -56,6 +67,10 @@ extern "C" {
 pub trait DeviceInfoForFDT {
     fn addr(&self) -> u64;
     fn irq(&self) -> u32;
+    // The number of consecutive IRQs starting at `irq`, one per interrupt vector of the device.
+    fn irq_count(&self) -> u32 {
+        1
+    }
     fn length(&self) -> u64;
     fn type_(&self) -> &DeviceType;
 }
@@ -84,9 +99,11 @@ pub enum FDTDeviceType {
 // Creates the flattened device tree for this VM.
 pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     guest_mem: &GuestMemory,
-    num_cpus: u32,
+    vcpu_mpidr: &[u64],
+    gic_version: GicVersion,
     cmdline: &CStr,
-    device_info: Option<&HashMap<String, T>>,
+    device_info: Option<&BTreeMap<String, T>>,
+    rng_seed: Option<&[u8]>,
 ) -> Result<(Vec<u8>)> {
     // Alocate stuff necessary for the holding the blob.
     let mut fdt = vec![0; FDT_MAX_SIZE];
@@ -107,10 +124,10 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     // This is not mandatory but we use it to point the root node to the node
     // containing description of the interrupt controller for this VM.
     append_property_u32(&mut fdt, "interrupt-parent", GIC_PHANDLE)?;
-    create_cpu_nodes(&mut fdt, num_cpus)?;
+    create_cpu_nodes(&mut fdt, vcpu_mpidr)?;
     create_memory_node(&mut fdt, guest_mem)?;
-    create_chosen_node(&mut fdt, cmdline)?;
-    create_gic_node(&mut fdt, u64::from(num_cpus))?;
+    create_chosen_node(&mut fdt, cmdline, rng_seed)?;
+    create_gic_node(&mut fdt, vcpu_mpidr.len() as u64, gic_version)?;
     create_timer_node(&mut fdt)?;
     create_psci_node(&mut fdt)?;
     device_info.map_or(Ok(()), |v| create_devices_node(&mut fdt, v))?;
@@ -304,26 +321,26 @@ fn generate_prop64(cells: &[u64]) -> Vec<u8> {
 }
 
 // Following are the auxiliary function for creating the different nodes that we append to our FDT.
-fn create_cpu_nodes(fdt: &mut Vec<u8>, num_cpus: u32) -> Result<()> {
+fn create_cpu_nodes(fdt: &mut Vec<u8>, vcpu_mpidr: &[u64]) -> Result<()> {
     // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/arm/cpus.yaml.
     append_begin_node(fdt, "cpus")?;
     // As per documentation, on ARM v8 64-bit systems value should be set to 2.
     append_property_u32(fdt, "#address-cells", 0x2)?;
     append_property_u32(fdt, "#size-cells", 0x0)?;
 
-    for cpu_id in 0..num_cpus {
-        let cpu_name = format!("cpu@{:x}", cpu_id);
+    for mpidr in vcpu_mpidr.iter() {
+        // The cpu reg property holds the affinity fields of the MPIDR - Multiprocessor
+        // Affinity Register. See aforementioned documentation.
+        let reg = mpidr & MPIDR_AFFINITY_MASK;
+        let cpu_name = format!("cpu@{:x}", reg);
         append_begin_node(fdt, &cpu_name)?;
         append_property_string(fdt, "device_type", "cpu")?;
         append_property_string(fdt, "compatible", "arm,arm-v8")?;
-        if num_cpus > 1 {
+        if vcpu_mpidr.len() > 1 {
             // This is required on armv8 64-bit. See aforementioned documentation.
             append_property_string(fdt, "enable-method", "psci")?;
         }
-        // If we ever see scheduling problems we might need to set the cpu reg property
-        // to contain data from the MPIDR - Multiprocessor Affinity Register
-        // See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0488c/BABHBJCI.html.
-        append_property_u64(fdt, "reg", u64::from(cpu_id))?;
+        append_property_u64(fdt, "reg", reg)?;
         append_end_node(fdt)?;
     }
     append_end_node(fdt)?;
@@ -343,26 +360,45 @@ fn create_memory_node(fdt: &mut Vec<u8>, guest_mem: &GuestMemory) -> Result<()>
     Ok(())
 }
 
-fn create_chosen_node(fdt: &mut Vec<u8>, cmdline: &CStr) -> Result<()> {
+fn create_chosen_node(fdt: &mut Vec<u8>, cmdline: &CStr, rng_seed: Option<&[u8]>) -> Result<()> {
     append_begin_node(fdt, "chosen")?;
     append_property_cstring(fdt, "bootargs", cmdline)?;
+    // Linux seeds its RNG with the content of this property, before wiping it.
+    if let Some(seed) = rng_seed {
+        append_property(fdt, "rng-seed", seed)?;
+    }
     append_end_node(fdt)?;
 
     Ok(())
 }
 
-fn create_gic_node(fdt: &mut Vec<u8>, vcpu_count: u64) -> Result<()> {
+fn create_gic_node(fdt: &mut Vec<u8>, vcpu_count: u64, version: GicVersion) -> Result<()> {
     // Look at https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/interrupt-controller/arm%2Cgic-v3.yaml
+    // and https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/interrupt-controller/arm%2Cgic.yaml
     // for understanding this.
-    let gic_reg_prop = generate_prop64(&[
-        get_dist_addr(),
-        get_dist_size(),
-        get_redists_addr(vcpu_count),
-        get_redists_size(vcpu_count),
-    ]);
+    let (compatible, gic_reg_prop) = match version {
+        GicVersion::V2 => (
+            "arm,cortex-a15-gic",
+            generate_prop64(&[
+                get_dist_addr(version),
+                get_dist_size(version),
+                get_cpu_addr(),
+                get_cpu_size(),
+            ]),
+        ),
+        GicVersion::V3 => (
+            "arm,gic-v3",
+            generate_prop64(&[
+                get_dist_addr(version),
+                get_dist_size(version),
+                get_redists_addr(vcpu_count),
+                get_redists_size(vcpu_count),
+            ]),
+        ),
+    };
 
     append_begin_node(fdt, "intc")?;
-    append_property_string(fdt, "compatible", "arm,gic-v3")?;
+    append_property_string(fdt, "compatible", compatible)?;
     append_property_null(fdt, "interrupt-controller")?;
     // "interrupt-cells" field specifies the number of cells needed to encode an
     // interrupt source. The type shall be a <u32> and the value shall be 3 if no PPI affinity description
@@ -424,7 +460,17 @@ fn create_virtio_node<T: DeviceInfoForFDT + Clone + Debug>(
     dev_info: T,
 ) -> Result<()> {
     let device_reg_prop = generate_prop64(&[dev_info.addr(), dev_info.length()]);
-    let irq = generate_prop32(&[GIC_FDT_IRQ_TYPE_SPI, dev_info.irq(), IRQ_TYPE_EDGE_RISING]);
+    // Each interrupt vector of the device has its own IRQ.
+    let irqs: Vec<u32> = (0..dev_info.irq_count())
+        .flat_map(|i| {
+            vec![
+                GIC_FDT_IRQ_TYPE_SPI,
+                dev_info.irq() + i,
+                IRQ_TYPE_EDGE_RISING,
+            ]
+        })
+        .collect();
+    let irq = generate_prop32(&irqs);
 
     append_begin_node(fdt, &format!("virtio_mmio@{:x}", dev_info.addr()))?;
     append_property_string(fdt, "compatible", "virtio,mmio")?;
@@ -453,14 +499,59 @@ fn create_serial_node<T: DeviceInfoForFDT + Clone + Debug>(
     Ok(())
 }
 
+fn create_rtc_node<T: DeviceInfoForFDT + Clone + Debug>(
+    fdt: &mut Vec<u8>,
+    dev_info: T,
+) -> Result<()> {
+    // The PL031 is an AMBA primecell, which needs an APB clock for getting probed. See
+    // https://www.kernel.org/doc/Documentation/devicetree/bindings/clock/fixed-clock.txt
+    append_begin_node(fdt, "apb-pclk")?;
+    append_property_string(fdt, "compatible", "fixed-clock")?;
+    append_property_u32(fdt, "#clock-cells", 0)?;
+    append_property_u32(fdt, "clock-frequency", 24_000_000)?;
+    append_property_string(fdt, "clock-output-names", "clk24mhz")?;
+    append_property_u32(fdt, "phandle", CLOCK_PHANDLE)?;
+    append_end_node(fdt)?;
+
+    let compatible = b"arm,pl031\0arm,primecell\0";
+    let rtc_reg_prop = generate_prop64(&[dev_info.addr(), dev_info.length()]);
+    let irq = generate_prop32(&[GIC_FDT_IRQ_TYPE_SPI, dev_info.irq(), IRQ_TYPE_LEVEL_HI]);
+
+    append_begin_node(fdt, &format!("rtc@{:x}", dev_info.addr()))?;
+    append_property(fdt, "compatible", compatible)?;
+    append_property(fdt, "reg", &rtc_reg_prop)?;
+    append_property(fdt, "interrupts", &irq)?;
+    append_property_u32(fdt, "clocks", CLOCK_PHANDLE)?;
+    append_property_string(fdt, "clock-names", "apb_pclk")?;
+    append_end_node(fdt)?;
+
+    Ok(())
+}
+
+fn create_pvpanic_node<T: DeviceInfoForFDT + Clone + Debug>(
+    fdt: &mut Vec<u8>,
+    dev_info: T,
+) -> Result<()> {
+    let pvpanic_reg_prop = generate_prop64(&[dev_info.addr(), dev_info.length()]);
+
+    append_begin_node(fdt, &format!("pvpanic@{:x}", dev_info.addr()))?;
+    append_property_string(fdt, "compatible", "qemu,pvpanic-mmio")?;
+    append_property(fdt, "reg", &pvpanic_reg_prop)?;
+    append_end_node(fdt)?;
+
+    Ok(())
+}
+
 fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug>(
     fdt: &mut Vec<u8>,
-    dev_info: &HashMap<String, T>,
+    dev_info: &BTreeMap<String, T>,
 ) -> Result<()> {
     for (_, info) in &*dev_info {
         match info.type_() {
-            DeviceType::Virtio => create_virtio_node(fdt, info.clone())?,
+            DeviceType::Virtio | DeviceType::Fs => create_virtio_node(fdt, info.clone())?,
             DeviceType::Serial => create_serial_node(fdt, info.clone())?,
+            DeviceType::RTC => create_rtc_node(fdt, info.clone())?,
+            DeviceType::PvPanic => create_pvpanic_node(fdt, info.clone())?,
         };
     }
 
@@ -508,7 +599,7 @@ mod tests {
     fn test_create_fdt() {
         let regions = arch_memory_regions(layout::FDT_MAX_SIZE + 0x1000);
         let mem = GuestMemory::new(&regions).expect("Cannot initialize memory");
-        let dev_info: HashMap<String, MMIODeviceInfo> = [
+        let dev_info: BTreeMap<String, MMIODeviceInfo> = [
             (
                 "uart".to_string(),
                 MMIODeviceInfo {
@@ -529,8 +620,15 @@ mod tests {
         .iter()
         .cloned()
         .collect();
-        let mut dtb =
-            create_fdt(&mem, 1, &CString::new("console=tty0").unwrap(), &dev_info).unwrap();
+        let mut dtb = create_fdt(
+            &mem,
+            &[0],
+            GicVersion::V3,
+            &CString::new("console=tty0").unwrap(),
+            Some(&dev_info),
+            None,
+        )
+        .unwrap();
 
         /* Use this code when wanting to generate a new DTB sample.
         {
@@ -558,4 +656,76 @@ mod tests {
         let generated_fdt = device_tree::DeviceTree::load(&dtb).unwrap();
         assert!(format!("{:?}", original_fdt) == format!("{:?}", generated_fdt));
     }
+
+    #[test]
+    fn test_create_fdt_rng_seed() {
+        let regions = arch_memory_regions(layout::FDT_MAX_SIZE + 0x1000);
+        let mem = GuestMemory::new(&regions).expect("Cannot initialize memory");
+        let seed: Vec<u8> = (1..=32).collect();
+        let mut dtb = create_fdt::<MMIODeviceInfo>(
+            &mem,
+            &[0],
+            GicVersion::V3,
+            &CString::new("console=tty0").unwrap(),
+            None,
+            Some(seed.as_slice()),
+        )
+        .unwrap();
+
+        set_size(&mut dtb, 4, layout::FDT_MAX_SIZE);
+        let fdt = device_tree::DeviceTree::load(&dtb).unwrap();
+        let chosen = fdt.find("/chosen").unwrap();
+        assert_eq!(chosen.prop_raw("rng-seed"), Some(&seed));
+    }
+
+    #[test]
+    fn test_create_psci_node() {
+        let regions = arch_memory_regions(layout::FDT_MAX_SIZE + 0x1000);
+        let mem = GuestMemory::new(&regions).expect("Cannot initialize memory");
+        let mut dtb = create_fdt::<MMIODeviceInfo>(
+            &mem,
+            &[0, 1],
+            GicVersion::V3,
+            &CString::new("console=tty0").unwrap(),
+            None,
+            None,
+        )
+        .unwrap();
+
+        set_size(&mut dtb, 4, layout::FDT_MAX_SIZE);
+        let fdt = device_tree::DeviceTree::load(&dtb).unwrap();
+        let psci = fdt.find("/psci").unwrap();
+        assert_eq!(psci.prop_str("compatible").unwrap(), "arm,psci-0.2");
+        assert_eq!(psci.prop_str("method").unwrap(), "hvc");
+        // The secondary vCPUs are brought up through PSCI calls.
+        for cpu in &["/cpus/cpu@0", "/cpus/cpu@1"] {
+            let cpu = fdt.find(cpu).unwrap();
+            assert_eq!(cpu.prop_str("enable-method").unwrap(), "psci");
+        }
+    }
+
+    #[test]
+    fn test_create_gic_node() {
+        let regions = arch_memory_regions(layout::FDT_MAX_SIZE + 0x1000);
+        let mem = GuestMemory::new(&regions).expect("Cannot initialize memory");
+        for &(version, compatible) in &[
+            (GicVersion::V2, "arm,cortex-a15-gic"),
+            (GicVersion::V3, "arm,gic-v3"),
+        ] {
+            let mut dtb = create_fdt::<MMIODeviceInfo>(
+                &mem,
+                &[0],
+                version,
+                &CString::new("console=tty0").unwrap(),
+                None,
+                None,
+            )
+            .unwrap();
+
+            set_size(&mut dtb, 4, layout::FDT_MAX_SIZE);
+            let fdt = device_tree::DeviceTree::load(&dtb).unwrap();
+            let intc = fdt.find("/intc").unwrap();
+            assert_eq!(intc.prop_str("compatible").unwrap(), compatible);
+        }
+    }
 }
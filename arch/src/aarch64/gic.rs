@@ -8,6 +8,8 @@ use kvm_ioctls::{DeviceFd, VmFd};
 // Unfortunately bindgen omits defines that are based on other defines.
 // See arch/arm64/include/uapi/asm/kvm.h file from the linux kernel.
 const SZ_64K: u64 = 0x0001_0000;
+const KVM_VGIC_V2_DIST_SIZE: u64 = kvm_bindings::KVM_VGIC_V2_DIST_SIZE as u64;
+const KVM_VGIC_V2_CPU_SIZE: u64 = kvm_bindings::KVM_VGIC_V2_CPU_SIZE as u64;
 const KVM_VGIC_V3_DIST_SIZE: u64 = SZ_64K;
 const KVM_VGIC_V3_REDIST_SIZE: u64 = (2 * SZ_64K);
 
@@ -17,55 +19,83 @@ pub enum Error {
     CreateGIC(io::Error),
     /// Error while setting device attributes for the GIC.
     SetDeviceAttribute(io::Error),
+    /// The host kernel cannot emulate this version of the GIC.
+    UnsupportedVersion(GicVersion),
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
-/// Create a GICv3 device.
+/// Versions of the GIC which can be presented to the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GicVersion {
+    /// GICv2, which supports at most 8 vCPUs.
+    V2,
+    /// GICv3.
+    V3,
+}
+
+/// Create a GIC device of the given version.
 ///
 /// Logic from this function is based on virt/kvm/arm/vgic/vgic-kvm-device.c from linux kernel.
-pub fn create_gicv3(vm: &VmFd, vcpu_count: u8) -> Result<DeviceFd> {
-    /* We are creating a V3 GIC.
-     As per https://static.docs.arm.com/dai0492/b/GICv3_Software_Overview_Official_Release_B.pdf,
+pub fn create_gic(vm: &VmFd, vcpu_count: u8, version: GicVersion) -> Result<DeviceFd> {
+    /* As per https://static.docs.arm.com/dai0492/b/GICv3_Software_Overview_Official_Release_B.pdf,
      section 3.5 Programmers' model, the register interface of a GICv3 interrupt controller is split
      into three groups: distributor, redistributor, CPU.
      As per Figure 9 from same section, there is 1 Distributor and multiple redistributors (one per
-     each CPU).
+     each CPU). A GICv2 has 1 Distributor and a CPU interface instead of the redistributors.
     */
     let mut gic_device = kvm_bindings::kvm_create_device {
-        type_: kvm_bindings::kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+        type_: match version {
+            GicVersion::V2 => kvm_bindings::kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
+            GicVersion::V3 => kvm_bindings::kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+        },
         fd: 0,
         flags: 0,
     };
 
-    let vgic_fd = vm
-        .create_device(&mut gic_device)
-        .map_err(Error::CreateGIC)?;
+    // KVM fails with ENODEV when it cannot emulate the requested device type.
+    let vgic_fd = vm.create_device(&mut gic_device).map_err(|e| {
+        if e.raw_os_error() == Some(libc::ENODEV) {
+            Error::UnsupportedVersion(version)
+        } else {
+            Error::CreateGIC(e)
+        }
+    })?;
 
     /* Setting up the distributor attribute.
      We are placing the GIC below 1GB so we need to substract the size of the distributor.
     */
     let dist_attr = kvm_bindings::kvm_device_attr {
         group: kvm_bindings::KVM_DEV_ARM_VGIC_GRP_ADDR,
-        attr: u64::from(kvm_bindings::KVM_VGIC_V3_ADDR_TYPE_DIST),
-        addr: &get_dist_addr() as *const u64 as u64,
+        attr: u64::from(match version {
+            GicVersion::V2 => kvm_bindings::KVM_VGIC_V2_ADDR_TYPE_DIST,
+            GicVersion::V3 => kvm_bindings::KVM_VGIC_V3_ADDR_TYPE_DIST,
+        }),
+        addr: &get_dist_addr(version) as *const u64 as u64,
         flags: 0,
     };
     vgic_fd
         .set_device_attr(&dist_attr)
         .map_err(Error::SetDeviceAttribute)?;
 
-    /* Setting up the redistributors' attribute.
-    We are calculating here the start of the redistributors address. We have one per CPU.
+    /* Setting up the CPU interface attribute for GICv2, or the redistributors' attribute for
+    GICv3. We are calculating here the start of the redistributors address. We have one per CPU.
     */
-    let redists_attr = kvm_bindings::kvm_device_attr {
+    let (cpu_attr_type, cpu_attr_addr) = match version {
+        GicVersion::V2 => (kvm_bindings::KVM_VGIC_V2_ADDR_TYPE_CPU, get_cpu_addr()),
+        GicVersion::V3 => (
+            kvm_bindings::KVM_VGIC_V3_ADDR_TYPE_REDIST,
+            get_redists_addr(u64::from(vcpu_count)),
+        ),
+    };
+    let cpu_attr = kvm_bindings::kvm_device_attr {
         group: kvm_bindings::KVM_DEV_ARM_VGIC_GRP_ADDR,
-        attr: u64::from(kvm_bindings::KVM_VGIC_V3_ADDR_TYPE_REDIST),
-        addr: &get_redists_addr(u64::from(vcpu_count)) as *const u64 as u64,
+        attr: u64::from(cpu_attr_type),
+        addr: &cpu_attr_addr as *const u64 as u64,
         flags: 0,
     };
     vgic_fd
-        .set_device_attr(&redists_attr)
+        .set_device_attr(&cpu_attr)
         .map_err(Error::SetDeviceAttribute)?;
 
     /* We need to tell the kernel how many irqs to support with this vgic.
@@ -102,21 +132,34 @@ pub fn create_gicv3(vm: &VmFd, vcpu_count: u8) -> Result<DeviceFd> {
 // Auxiliary functions for getting addresses and size of where the distributor and redistributor
 // are placed.
 /// Get the address of the GIC distributor.
-pub fn get_dist_addr() -> u64 {
-    super::layout::MAPPED_IO_START - KVM_VGIC_V3_DIST_SIZE
+pub fn get_dist_addr(version: GicVersion) -> u64 {
+    super::layout::MAPPED_IO_START - get_dist_size(version)
 }
 
 /// Get the size of the GIC distributor.
-pub fn get_dist_size() -> u64 {
-    KVM_VGIC_V3_DIST_SIZE
+pub fn get_dist_size(version: GicVersion) -> u64 {
+    match version {
+        GicVersion::V2 => KVM_VGIC_V2_DIST_SIZE,
+        GicVersion::V3 => KVM_VGIC_V3_DIST_SIZE,
+    }
+}
+
+/// Get the address of the GICv2 CPU interface.
+pub fn get_cpu_addr() -> u64 {
+    get_dist_addr(GicVersion::V2) - get_cpu_size()
 }
 
-/// Get the address of the GIC redistributors.
+/// Get the size of the GICv2 CPU interface.
+pub fn get_cpu_size() -> u64 {
+    KVM_VGIC_V2_CPU_SIZE
+}
+
+/// Get the address of the GICv3 redistributors.
 pub fn get_redists_addr(vcpu_count: u64) -> u64 {
-    get_dist_addr() - get_redists_size(vcpu_count)
+    get_dist_addr(GicVersion::V3) - get_redists_size(vcpu_count)
 }
 
-/// Get the size of the GIC redistributors.
+/// Get the size of the GICv3 redistributors.
 pub fn get_redists_size(vcpu_count: u64) -> u64 {
     vcpu_count * KVM_VGIC_V3_REDIST_SIZE
 }
@@ -131,6 +174,27 @@ mod tests {
     fn test_create_gicv3() {
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-        assert!(create_gicv3(&vm, 1).is_ok());
+        assert!(create_gic(&vm, 1, GicVersion::V3).is_ok());
+    }
+
+    #[test]
+    fn test_gic_layout() {
+        // Both layouts fit right below the MMIO devices, without overlapping.
+        assert_eq!(
+            get_dist_addr(GicVersion::V2) + get_dist_size(GicVersion::V2),
+            super::super::layout::MAPPED_IO_START
+        );
+        assert_eq!(
+            get_cpu_addr() + get_cpu_size(),
+            get_dist_addr(GicVersion::V2)
+        );
+        assert_eq!(
+            get_dist_addr(GicVersion::V3) + get_dist_size(GicVersion::V3),
+            super::super::layout::MAPPED_IO_START
+        );
+        assert_eq!(
+            get_redists_addr(4) + get_redists_size(4),
+            get_dist_addr(GicVersion::V3)
+        );
     }
 }
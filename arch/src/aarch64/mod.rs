@@ -7,10 +7,11 @@ pub mod layout;
 pub mod regs;
 
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::ffi::CStr;
 use std::fmt::Debug;
 
+use byteorder::{BigEndian, ByteOrder};
 use memory_model::{GuestAddress, GuestMemory};
 
 #[derive(Debug)]
@@ -24,7 +25,7 @@ impl From<Error> for super::Error {
     }
 }
 
-pub use self::fdt::{DeviceInfoForFDT, FDTDeviceType};
+pub use self::fdt::{DeviceInfoForFDT, FDTDeviceType, FDT_MAGIC};
 
 /// Returns a Vec of the valid memory addresses for aarch64.
 /// See [`layout`](layout) module for a drawing of the specific memory model for this platform.
@@ -34,22 +35,60 @@ pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize)> {
 }
 
 /// Configures the system and should be called once per vm before starting vcpu threads.
-/// For aarch64, we only setup the FDT.
+/// For aarch64, we only setup the FDT, which is returned so that it can be inspected later on.
 ///
 /// # Arguments
 ///
 /// * `guest_mem` - The memory to be used by the guest.
 /// * `cmdline_cstring` - The kernel commandline.
-/// * `num_cpus` - Number of virtual CPUs of the system.
+/// * `vcpu_mpidr` - The MPIDR of each virtual CPU of the system.
+/// * `gic_version` - The version of the GIC created for the VM.
+/// * `device_info` - The MMIO devices of the VM, by ID.
+/// * `rng_seed` - Random bytes passed to the guest, for seeding its RNG early during boot.
 pub fn configure_system<T: DeviceInfoForFDT + Clone + Debug>(
     guest_mem: &GuestMemory,
     cmdline_cstring: &CStr,
-    num_cpus: u8,
-    device_info: Option<&HashMap<String, T>>,
-) -> super::Result<()> {
-    fdt::create_fdt(guest_mem, u32::from(num_cpus), cmdline_cstring, device_info)
-        .map_err(Error::SetupFDT)?;
-    Ok(())
+    vcpu_mpidr: &[u64],
+    gic_version: gic::GicVersion,
+    device_info: Option<&BTreeMap<String, T>>,
+    rng_seed: Option<&[u8]>,
+) -> super::Result<Vec<u8>> {
+    let mut fdt = fdt::create_fdt(
+        guest_mem,
+        vcpu_mpidr,
+        gic_version,
+        cmdline_cstring,
+        device_info,
+        rng_seed,
+    )
+    .map_err(Error::SetupFDT)?;
+    // The blob was packed, so only the size written in its header is meaningful.
+    let total_size = BigEndian::read_u32(&fdt[4..8]) as usize;
+    fdt.truncate(total_size);
+    Ok(fdt)
+}
+
+/// Returns the MPIDR (Multiprocessor Affinity Register) of a vCPU, laid out the way Linux
+/// expects it: with multithreading, Aff0 holds the thread, Aff1 the core and Aff2 the socket;
+/// otherwise Aff0 holds the core and Aff1 the socket.
+///
+/// # Arguments
+///
+/// * `cpu_id` - Index of the vCPU.
+/// * `threads_per_core` - Number of threads in each core.
+/// * `cores_per_socket` - Number of cores in each socket.
+pub fn get_mpidr(cpu_id: u8, threads_per_core: u8, cores_per_socket: u8) -> u64 {
+    // Bit 24 of the MPIDR indicates that the lowest affinity level holds threads.
+    const MPIDR_MT: u64 = 1 << 24;
+
+    let thread = u64::from(cpu_id % threads_per_core);
+    let core = u64::from(cpu_id / threads_per_core % cores_per_socket);
+    let socket = u64::from(cpu_id / threads_per_core / cores_per_socket);
+    if threads_per_core > 1 {
+        MPIDR_MT | socket << 16 | core << 8 | thread
+    } else {
+        socket << 8 | core
+    }
 }
 
 /// Function that returns the address reserved for MMIO devices.
@@ -57,6 +96,11 @@ pub fn get_reserved_mem_addr() -> u64 {
     layout::MAPPED_IO_START
 }
 
+/// Function that returns the first address past the area reserved for MMIO devices.
+pub fn get_reserved_mem_end() -> u64 {
+    layout::DRAM_MEM_START as u64
+}
+
 /// Returns the memory address where the kernel could be loaded.
 pub fn get_kernel_start() -> usize {
     layout::DRAM_MEM_START
@@ -96,6 +140,19 @@ mod tests {
         assert_eq!(super::layout::DRAM_MEM_END, regions[0].1);
     }
 
+    #[test]
+    fn test_get_mpidr() {
+        // 1 socket, 2 cores per socket, 2 threads per core.
+        assert_eq!(get_mpidr(0, 2, 2), 0x0100_0000);
+        assert_eq!(get_mpidr(1, 2, 2), 0x0100_0001);
+        assert_eq!(get_mpidr(2, 2, 2), 0x0100_0100);
+        assert_eq!(get_mpidr(3, 2, 2), 0x0100_0101);
+        // 2 sockets, 2 cores per socket, 1 thread per core.
+        assert_eq!(get_mpidr(1, 1, 2), 0x01);
+        assert_eq!(get_mpidr(2, 1, 2), 0x0100);
+        assert_eq!(get_mpidr(3, 1, 2), 0x0101);
+    }
+
     #[test]
     fn test_get_fdt_addr() {
         let regions = arch_memory_regions(layout::FDT_MAX_SIZE - 0x1000);
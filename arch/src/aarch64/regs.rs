@@ -10,12 +10,15 @@ use std::{io, mem, result};
 use kvm_ioctls::VcpuFd;
 
 use super::get_fdt_addr;
-use kvm_bindings::{user_pt_regs, KVM_REG_ARM64, KVM_REG_ARM_CORE, KVM_REG_SIZE_U64};
+use kvm_bindings::{
+    user_pt_regs, KVM_REG_ARM64, KVM_REG_ARM64_SYSREG, KVM_REG_ARM_CORE, KVM_REG_SIZE_U64,
+};
 use memory_model::GuestMemory;
 
 #[derive(Debug)]
 pub enum Error {
     SetCoreRegister(io::Error),
+    SetSystemRegister(io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -80,6 +83,11 @@ macro_rules! arm64_core_reg {
     };
 }
 
+// The id of the MPIDR_EL1 system register (op0 = 3, op1 = 0, CRn = 0, CRm = 0, op2 = 5), as
+// encoded by `ARM64_SYS_REG` in arch/arm64/include/uapi/asm/kvm.h.
+const MPIDR_EL1: u64 =
+    KVM_REG_ARM64 as u64 | KVM_REG_SIZE_U64 as u64 | KVM_REG_ARM64_SYSREG as u64 | (3 << 14) | 5;
+
 /// Configure core registers for a given CPU.
 ///
 /// # Arguments
@@ -109,6 +117,18 @@ pub fn setup_regs(vcpu: &VcpuFd, cpu_id: u8, boot_ip: usize, mem: &GuestMemory)
     Ok(())
 }
 
+/// Sets the MPIDR (Multiprocessor Affinity Register) of a given CPU. It must be called after
+/// the vCPU is initialized, since the initialization resets it.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `mpidr` - The value of the MPIDR.
+pub fn setup_mpidr(vcpu: &VcpuFd, mpidr: u64) -> Result<()> {
+    vcpu.set_one_reg(MPIDR_EL1, mpidr)
+        .map_err(Error::SetSystemRegister)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -125,11 +145,13 @@ mod tests {
 
         match setup_regs(&vcpu, 0, 0x0, &mem).unwrap_err() {
             Error::SetCoreRegister(ref e) => assert_eq!(e.raw_os_error(), Some(libc::ENOEXEC)),
+            _ => assert!(false),
         }
         let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
         vm.get_preferred_target(&mut kvi).unwrap();
         vcpu.vcpu_init(&kvi).unwrap();
 
         assert!(setup_regs(&vcpu, 0, 0x0, &mem).is_ok());
+        assert!(setup_mpidr(&vcpu, 0x0100_0001).is_ok());
     }
 }
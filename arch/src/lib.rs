@@ -28,7 +28,7 @@ pub mod aarch64;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::{
     arch_memory_regions, configure_system, get_kernel_start, get_reserved_mem_addr,
-    layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX,
+    get_reserved_mem_end, layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX,
 };
 
 #[cfg(target_arch = "x86_64")]
@@ -36,14 +36,20 @@ pub mod x86_64;
 
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{
-    arch_memory_regions, configure_system, get_32bit_gap_start as get_reserved_mem_addr,
-    get_kernel_start, layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX,
+    arch_memory_regions, configure_system, get_32bit_gap_end as get_reserved_mem_end,
+    get_32bit_gap_start as get_reserved_mem_addr, get_kernel_start, layout::CMDLINE_MAX_SIZE,
+    layout::IRQ_BASE, layout::IRQ_MAX,
 };
 
 /// Types of devices that can get attached to this platform.
 #[derive(Clone, Debug, PartialEq)]
 pub enum DeviceType {
     Virtio,
+    Fs,
     #[cfg(target_arch = "aarch64")]
     Serial,
+    #[cfg(target_arch = "aarch64")]
+    RTC,
+    #[cfg(target_arch = "aarch64")]
+    PvPanic,
 }
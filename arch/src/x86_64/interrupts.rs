@@ -17,6 +17,8 @@ use kvm_ioctls::VcpuFd;
 pub enum Error {
     GetLapic(io::Error),
     SetLapic(io::Error),
+    GetApicBase(io::Error),
+    SetApicBase(io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -26,6 +28,8 @@ const APIC_LVT0: usize = 0x350;
 const APIC_LVT1: usize = 0x360;
 const APIC_MODE_NMI: u32 = 0x4;
 const APIC_MODE_EXTINT: u32 = 0x7;
+// The x2APIC mode enable bit of the APIC base MSR.
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
 
 fn get_klapic_reg(klapic: &kvm_lapic_state, reg_offset: usize) -> u32 {
     let sliceu8 = unsafe {
@@ -80,13 +84,25 @@ pub fn set_lint(vcpu: &VcpuFd) -> Result<()> {
     vcpu.set_lapic(&klapic).map_err(Error::SetLapic)
 }
 
+/// Switches the LAPIC to x2APIC mode. KVM refuses the switch unless x2APIC is advertised in the
+/// CPUID of the vCPU.
+///
+/// # Arguments
+/// * `vcpu` - The VCPU object to configure.
+pub fn enable_x2apic(vcpu: &VcpuFd) -> Result<()> {
+    // The APIC base MSR is part of the special registers.
+    let mut sregs = vcpu.get_sregs().map_err(Error::GetApicBase)?;
+    sregs.apic_base |= APIC_BASE_X2APIC_ENABLE;
+    vcpu.set_sregs(&sregs).map_err(Error::SetApicBase)
+}
+
 #[cfg(test)]
 mod tests {
     extern crate rand;
     use self::rand::Rng;
 
     use super::*;
-    use kvm_ioctls::Kvm;
+    use kvm_ioctls::{Kvm, MAX_KVM_CPUID_ENTRIES};
 
     const KVM_APIC_REG_SIZE: usize = 0x400;
 
@@ -144,6 +160,28 @@ mod tests {
         assert_eq!(lint1_mode_expected, lint1_mode_actual);
     }
 
+    #[test]
+    fn test_enable_x2apic() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        assert!(vm.create_irq_chip().is_ok());
+        let vcpu = vm.create_vcpu(0).unwrap();
+        // KVM refuses x2APIC mode if the guest can't see it in the CPUID.
+        assert!(enable_x2apic(&vcpu).is_err());
+
+        let mut cpuid = kvm.get_supported_cpuid(MAX_KVM_CPUID_ENTRIES).unwrap();
+        vcpu.set_cpuid2(&cpuid).unwrap();
+        let x2apic = cpuid
+            .mut_entries_slice()
+            .iter()
+            .any(|entry| entry.function == 0x1 && entry.ecx & (1 << 21) != 0);
+        if x2apic {
+            enable_x2apic(&vcpu).unwrap();
+            let sregs = vcpu.get_sregs().unwrap();
+            assert_ne!(sregs.apic_base & APIC_BASE_X2APIC_ENABLE, 0);
+        }
+    }
+
     #[test]
     fn test_setlint_fails() {
         let kvm = Kvm::new().unwrap();
@@ -30,3 +30,6 @@ pub const KVM_TSS_ADDRESS: usize = 0xfffb_d000;
 
 /// The 'zero page', a.k.a linux kernel bootparams.
 pub const ZERO_PAGE_START: usize = 0x7000;
+
+/// Address of the setup_data entry linked from the zero page, right after the page tables.
+pub const SETUP_DATA_START: usize = 0xc000;
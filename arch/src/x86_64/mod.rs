@@ -11,9 +11,11 @@ pub mod layout;
 mod mptable;
 pub mod regs;
 
+use std::cmp;
 use std::mem;
 
-use arch_gen::x86::bootparam::{boot_params, E820_RAM};
+use arch_gen::x86::bootparam::{boot_params, E820_RAM, E820_RESERVED};
+use byteorder::{ByteOrder, LittleEndian};
 use memory_model::{DataInit, GuestAddress, GuestMemory};
 
 // This is a workaround to the Rust enforcement specifying that any implementation of a foreign
@@ -31,8 +33,13 @@ unsafe impl DataInit for BootParamsWrapper {}
 pub enum Error {
     /// Invalid e820 setup params.
     E820Configuration,
+    /// The MMIO gap below 4GiB is empty, not page aligned, crosses 4GiB, leaves no room for the
+    /// kernel or doesn't cover the TSS.
+    InvalidMemoryGap,
     /// Error writing MP table to memory.
     MpTableSetup(mptable::Error),
+    /// Error writing the setup_data entry holding the random seed to guest memory.
+    SetupDataSetup,
     /// The zero page extends past the end of guest_mem.
     ZeroPagePastRamEnd,
     /// Error writing the zero page of guest memory.
@@ -47,38 +54,99 @@ impl From<Error> for super::Error {
 
 // Where BIOS/VGA magic would live on a real PC.
 const EBDA_START: u64 = 0x9fc00;
+// The type of the setup_data entry from which Linux seeds its RNG.
+const SETUP_RNG_SEED: u32 = 9;
+// The size of the setup_data header: next (u64), type (u32) and len (u32).
+const SETUP_DATA_HEADER_SIZE: usize = 16;
 const FIRST_ADDR_PAST_32BITS: usize = (1 << 32);
 const MEM_32BIT_GAP_SIZE: usize = (768 << 20);
+const PAGE_SIZE: usize = 0x1000;
+// The TSS set up by KVM takes 3 pages, which must not be guest RAM.
+const KVM_TSS_SIZE: usize = 3 * PAGE_SIZE;
+
+/// Lays out the guest RAM around the MMIO gap below 4GiB: the RAM fills the memory up to the
+/// start of the gap, and whatever doesn't fit there is placed right past 4GiB.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryLayout {
+    gap_start: usize,
+    gap_size: usize,
+}
+
+impl Default for MemoryLayout {
+    /// The standard layout, with a 768MiB gap right below 4GiB.
+    fn default() -> Self {
+        MemoryLayout {
+            gap_start: FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE,
+            gap_size: MEM_32BIT_GAP_SIZE,
+        }
+    }
+}
+
+impl MemoryLayout {
+    /// Creates a layout with a MMIO gap of `gap_size` bytes starting at `gap_start`.
+    ///
+    /// The gap must be page aligned, start above the memory holding the kernel and end at or
+    /// below 4GiB. It must also cover the TSS which KVM places at `layout::KVM_TSS_ADDRESS`.
+    pub fn new(gap_start: usize, gap_size: usize) -> Result<Self, Error> {
+        let gap_end = gap_start
+            .checked_add(gap_size)
+            .ok_or(Error::InvalidMemoryGap)?;
+        if gap_size == 0
+            || gap_start % PAGE_SIZE != 0
+            || gap_size % PAGE_SIZE != 0
+            || gap_start <= layout::HIMEM_START
+            || gap_end > FIRST_ADDR_PAST_32BITS
+            || gap_start > layout::KVM_TSS_ADDRESS
+            || gap_end < layout::KVM_TSS_ADDRESS + KVM_TSS_SIZE
+        {
+            return Err(Error::InvalidMemoryGap);
+        }
+        Ok(MemoryLayout {
+            gap_start,
+            gap_size,
+        })
+    }
+
+    /// Returns the first address of the MMIO gap.
+    pub fn gap_start(&self) -> usize {
+        self.gap_start
+    }
+
+    /// Returns the first address past the MMIO gap.
+    pub fn gap_end(&self) -> usize {
+        self.gap_start + self.gap_size
+    }
+
+    /// Returns the memory regions holding `size` bytes of guest RAM, sorted by address: one
+    /// below the gap and, if the RAM doesn't fit there, one starting at 4GiB.
+    pub fn regions(&self, size: usize) -> Vec<(GuestAddress, usize)> {
+        if size <= self.gap_start {
+            vec![(GuestAddress(0), size)]
+        } else {
+            vec![
+                (GuestAddress(0), self.gap_start),
+                (GuestAddress(FIRST_ADDR_PAST_32BITS), size - self.gap_start),
+            ]
+        }
+    }
+}
 
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemory structure for the platform.
 /// For x86_64 all addresses are valid from the start of the kernel except a
 /// carve out at the end of 32bit address space.
 pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize)> {
-    let memory_gap_start = GuestAddress(FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE);
-    let memory_gap_end = GuestAddress(FIRST_ADDR_PAST_32BITS);
-    let requested_memory_size = GuestAddress(size);
-    let mut regions = Vec::new();
-
-    // case1: guest memory fits before the gap
-    if requested_memory_size <= memory_gap_start {
-        regions.push((GuestAddress(0), size));
-    // case2: guest memory extends beyond the gap
-    } else {
-        // push memory before the gap
-        regions.push((GuestAddress(0), memory_gap_start.offset()));
-        regions.push((
-            memory_gap_end,
-            requested_memory_size.offset_from(memory_gap_start),
-        ));
-    }
-
-    regions
+    MemoryLayout::default().regions(size)
 }
 
 /// X86 specific memory hole/memory mapped devices/reserved area.
 pub fn get_32bit_gap_start() -> usize {
-    FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE
+    MemoryLayout::default().gap_start()
+}
+
+/// Returns the first address past the X86 specific memory hole.
+pub fn get_32bit_gap_end() -> usize {
+    MemoryLayout::default().gap_end()
 }
 
 /// Returns the memory address where the kernel could be loaded.
@@ -94,11 +162,16 @@ pub fn get_kernel_start() -> usize {
 /// * `cmdline_addr` - Address in `guest_mem` where the kernel command line was loaded.
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `rng_seed` - Random bytes passed to the guest, for seeding its RNG early during boot.
+/// * `reserved_memory` - Guest address ranges, given as start and size, which the e820 map marks
+///                       as reserved. The guest RAM they overlap is left out of the map.
 pub fn configure_system(
     guest_mem: &GuestMemory,
     cmdline_addr: GuestAddress,
     cmdline_size: usize,
     num_cpus: u8,
+    rng_seed: Option<&[u8]>,
+    reserved_memory: &[(GuestAddress, usize)],
 ) -> super::Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
@@ -121,32 +194,34 @@ pub fn configure_system(
     params.0.hdr.cmdline_size = cmdline_size as u32;
     params.0.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
 
-    add_e820_entry(&mut params.0, 0, EBDA_START, E820_RAM)?;
-
+    let mut ram = vec![(0, EBDA_START)];
     let mem_end = guest_mem.end_addr();
     if mem_end < end_32bit_gap_start {
-        add_e820_entry(
-            &mut params.0,
+        ram.push((
             himem_start.offset() as u64,
             mem_end.offset_from(himem_start) as u64,
-            E820_RAM,
-        )?;
+        ));
     } else {
-        add_e820_entry(
-            &mut params.0,
+        ram.push((
             himem_start.offset() as u64,
             end_32bit_gap_start.offset_from(himem_start) as u64,
-            E820_RAM,
-        )?;
+        ));
         if mem_end > first_addr_past_32bits {
-            add_e820_entry(
-                &mut params.0,
+            ram.push((
                 first_addr_past_32bits.offset() as u64,
                 mem_end.offset_from(first_addr_past_32bits) as u64,
-                E820_RAM,
-            )?;
+            ));
         }
     }
+    for (addr, size, mem_type) in e820_entries(&ram, reserved_memory) {
+        add_e820_entry(&mut params.0, addr, size, mem_type)?;
+    }
+
+    if let Some(seed) = rng_seed {
+        let setup_data_addr = GuestAddress(layout::SETUP_DATA_START);
+        write_setup_data(guest_mem, setup_data_addr, SETUP_RNG_SEED, seed)?;
+        params.0.hdr.setup_data = setup_data_addr.offset() as u64;
+    }
 
     let zero_page_addr = GuestAddress(layout::ZERO_PAGE_START);
     guest_mem
@@ -159,6 +234,95 @@ pub fn configure_system(
     Ok(())
 }
 
+/// Returns the guest address ranges, given as start and size, which the host writes while
+/// setting up the guest, besides the kernel: the command line, the boot parameters, the MP
+/// table, the GDT, the IDT and the page tables.
+///
+/// # Arguments
+///
+/// * `cmdline_addr` - Address where the kernel command line was loaded.
+/// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
+/// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `rng_seed_len` - Length of the random bytes passed to the guest, if any.
+pub fn boot_data_ranges(
+    cmdline_addr: GuestAddress,
+    cmdline_size: usize,
+    num_cpus: u8,
+    rng_seed_len: Option<usize>,
+) -> Vec<(GuestAddress, usize)> {
+    let mut ranges = vec![
+        (cmdline_addr, cmdline_size),
+        (
+            GuestAddress(layout::ZERO_PAGE_START),
+            mem::size_of::<boot_params>(),
+        ),
+        mptable::mptable_range(num_cpus),
+    ];
+    if let Some(len) = rng_seed_len {
+        ranges.push((
+            GuestAddress(layout::SETUP_DATA_START),
+            SETUP_DATA_HEADER_SIZE + len,
+        ));
+    }
+    ranges.extend(regs::boot_tables_ranges());
+    ranges
+}
+
+// Writes a setup_data entry, which is the last one of the list, at `addr`.
+fn write_setup_data(
+    guest_mem: &GuestMemory,
+    addr: GuestAddress,
+    type_: u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    let mut entry = vec![0u8; SETUP_DATA_HEADER_SIZE + data.len()];
+    // The next field stays 0, as there are no other entries.
+    LittleEndian::write_u32(&mut entry[8..12], type_);
+    LittleEndian::write_u32(&mut entry[12..16], data.len() as u32);
+    entry[SETUP_DATA_HEADER_SIZE..].copy_from_slice(data);
+
+    guest_mem
+        .write_slice_at_addr(&entry, addr)
+        .ok()
+        .filter(|&written| written == entry.len())
+        .map(|_| ())
+        .ok_or(Error::SetupDataSetup)
+}
+
+// Returns the e820 entries, as address, size and type, of the `ram` ranges split around the
+// `reserved` ranges and of the reserved ranges themselves, sorted by address.
+fn e820_entries(ram: &[(u64, u64)], reserved: &[(GuestAddress, usize)]) -> Vec<(u64, u64, u32)> {
+    let mut entries = Vec::new();
+    for &(addr, size) in ram {
+        let mut ranges = vec![(addr, addr + size)];
+        for &(reserved_addr, reserved_size) in reserved {
+            let reserved_start = reserved_addr.offset() as u64;
+            let reserved_end = reserved_start.saturating_add(reserved_size as u64);
+            ranges = ranges
+                .into_iter()
+                .flat_map(|(start, end)| {
+                    let below = (start, cmp::min(end, reserved_start));
+                    let above = (cmp::max(start, reserved_end), end);
+                    vec![below, above]
+                })
+                .filter(|&(start, end)| start < end)
+                .collect();
+        }
+        entries.extend(
+            ranges
+                .into_iter()
+                .map(|(start, end)| (start, end - start, E820_RAM)),
+        );
+    }
+    entries.extend(
+        reserved
+            .iter()
+            .map(|&(addr, size)| (addr.offset() as u64, size as u64, E820_RESERVED)),
+    );
+    entries.sort_by_key(|&(addr, _, _)| addr);
+    entries
+}
+
 /// Add an e820 region to the e820 map.
 /// Returns Ok(()) if successful, or an error if there is no space left in the map.
 fn add_e820_entry(
@@ -200,19 +364,66 @@ mod tests {
         assert_eq!(GuestAddress(1usize << 32), regions[1].0);
     }
 
+    #[test]
+    fn test_memory_layout() {
+        // 5GiB of RAM straddle the gap: 3.25GiB below it and the remaining 1.75GiB past 4GiB.
+        let layout = MemoryLayout::default();
+        let regions = layout.regions(5 << 30);
+        assert_eq!(
+            regions,
+            vec![
+                (GuestAddress(0), 3328 << 20),
+                (GuestAddress(1 << 32), 1792 << 20),
+            ]
+        );
+        assert_eq!(regions[0].0.offset() + regions[0].1, layout.gap_start());
+        assert_eq!(regions[1].0.offset(), layout.gap_end());
+        let gm = GuestMemory::new(&regions).unwrap();
+        assert_eq!(gm.total_size(), 5 << 30);
+        assert!(!gm.address_in_range(GuestAddress(layout.gap_start())));
+        assert!(!gm.address_in_range(GuestAddress(layout::KVM_TSS_ADDRESS)));
+
+        // A larger gap leaves less RAM below 4GiB.
+        let layout = MemoryLayout::new(3 << 30, 1 << 30).unwrap();
+        assert_eq!(layout.regions(3 << 30), vec![(GuestAddress(0), 3 << 30)]);
+        assert_eq!(
+            layout.regions(5 << 30),
+            vec![(GuestAddress(0), 3 << 30), (GuestAddress(1 << 32), 2 << 30)]
+        );
+
+        // Error cases: empty, unaligned, crossing 4GiB, overlapping the kernel or leaving the
+        // TSS out.
+        for &(start, size) in &[
+            (3 << 30, 0),
+            ((3 << 30) + 1, 1 << 30),
+            (3 << 30, (1 << 30) + PAGE_SIZE),
+            (
+                layout::HIMEM_START,
+                FIRST_ADDR_PAST_32BITS - layout::HIMEM_START,
+            ),
+            (3 << 30, 512 << 20),
+        ] {
+            assert_eq!(MemoryLayout::new(start, size), Err(Error::InvalidMemoryGap));
+        }
+    }
+
     #[test]
     fn test_32bit_gap() {
         assert_eq!(
             get_32bit_gap_start(),
             FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE
         );
+        assert_eq!(
+            get_32bit_gap_end() - get_32bit_gap_start(),
+            MEM_32BIT_GAP_SIZE
+        );
     }
 
     #[test]
     fn test_system_configuration() {
         let no_vcpus = 4;
         let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let config_err = configure_system(&gm, GuestAddress(0), 0, 1);
+        let config_err = configure_system(&gm, GuestAddress(0), 0, 1, None, &[]);
         assert!(config_err.is_err());
         match config_err.unwrap_err() {
             super::super::Error::X86_64Setup(e) => assert_eq!(
@@ -224,19 +435,117 @@ mod tests {
         let mem_size = 128 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
         let gm = GuestMemory::new(&arch_mem_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, &[]).unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
         let gm = GuestMemory::new(&arch_mem_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, &[]).unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
         let gm = GuestMemory::new(&arch_mem_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_rng_seed() {
+        let gm = GuestMemory::new(&arch_memory_regions(128 << 20)).unwrap();
+        let zero_page_addr = GuestAddress(layout::ZERO_PAGE_START);
+        let seed: Vec<u8> = (1..=32).collect();
+        configure_system(&gm, GuestAddress(0), 0, 1, Some(seed.as_slice()), &[]).unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj_from_addr(zero_page_addr).unwrap();
+        assert_eq!(params.0.hdr.setup_data, layout::SETUP_DATA_START as u64);
+        let mut entry = [0u8; SETUP_DATA_HEADER_SIZE + 32];
+        gm.read_slice_at_addr(&mut entry, GuestAddress(layout::SETUP_DATA_START))
+            .unwrap();
+        assert_eq!(LittleEndian::read_u64(&entry[0..8]), 0);
+        assert_eq!(LittleEndian::read_u32(&entry[8..12]), SETUP_RNG_SEED);
+        assert_eq!(LittleEndian::read_u32(&entry[12..16]), 32);
+        assert_eq!(&entry[SETUP_DATA_HEADER_SIZE..], &seed[..]);
+
+        // Without a seed, no setup_data is linked from the zero page.
+        configure_system(&gm, GuestAddress(0), 0, 1, None, &[]).unwrap();
+        let params: BootParamsWrapper = gm.read_obj_from_addr(zero_page_addr).unwrap();
+        assert_eq!(params.0.hdr.setup_data, 0);
+
+        // Error case: the setup_data entry doesn't fit in the guest memory.
+        let gm = GuestMemory::new(&[(GuestAddress(0), layout::SETUP_DATA_START + 8)]).unwrap();
+        assert_eq!(
+            write_setup_data(
+                &gm,
+                GuestAddress(layout::SETUP_DATA_START),
+                SETUP_RNG_SEED,
+                &seed
+            ),
+            Err(Error::SetupDataSetup)
+        );
+    }
+
+    #[test]
+    fn test_boot_data_ranges() {
+        let gm = GuestMemory::new(&arch_memory_regions(128 << 20)).unwrap();
+        let cmdline = b"console=ttyS0\0";
+        let cmdline_addr = GuestAddress(layout::CMDLINE_START);
+        gm.write_slice_at_addr(cmdline, cmdline_addr).unwrap();
+        let seed = [0xffu8; 32];
+        configure_system(&gm, cmdline_addr, cmdline.len(), 2, Some(&seed), &[]).unwrap();
+
+        // Everything written below the kernel is in the ranges.
+        let ranges = boot_data_ranges(cmdline_addr, cmdline.len(), 2, Some(seed.len()));
+        let mut low_mem = vec![0u8; layout::HIMEM_START];
+        gm.read_slice_at_addr(&mut low_mem, GuestAddress(0))
+            .unwrap();
+        for (addr, &byte) in low_mem.iter().enumerate() {
+            if byte != 0 {
+                assert!(ranges
+                    .iter()
+                    .any(|&(start, size)| addr >= start.offset() && addr < start.offset() + size));
+            }
+        }
+        assert!(boot_data_ranges(cmdline_addr, cmdline.len(), 2, None).len() < ranges.len());
+    }
+
+    #[test]
+    fn test_reserved_memory() {
+        let gm = GuestMemory::new(&arch_memory_regions(128 << 20)).unwrap();
+        let reserved = [(GuestAddress(64 << 20), 1 << 20)];
+        configure_system(&gm, GuestAddress(0), 0, 1, None, &reserved).unwrap();
+
+        let params: BootParamsWrapper = gm
+            .read_obj_from_addr(GuestAddress(layout::ZERO_PAGE_START))
+            .unwrap();
+        let entries: Vec<(u64, u64, u32)> = params.0.e820_map[..params.0.e820_entries as usize]
+            .iter()
+            .map(|entry| (entry.addr, entry.size, entry.type_))
+            .collect();
+        let himem_start = layout::HIMEM_START as u64;
+        assert_eq!(
+            entries,
+            vec![
+                (0, EBDA_START, E820_RAM),
+                (himem_start, (64 << 20) - himem_start, E820_RAM),
+                (64 << 20, 1 << 20, E820_RESERVED),
+                (65 << 20, 63 << 20, E820_RAM),
+            ]
+        );
+
+        // A reserved range covering the end of a RAM range, and one outside of the guest memory.
+        let reserved = [
+            (GuestAddress(1 << 32), 1 << 20),
+            (GuestAddress(127 << 20), 2 << 20),
+        ];
+        assert_eq!(
+            e820_entries(&[(himem_start, (128 << 20) - himem_start)], &reserved),
+            vec![
+                (himem_start, (127 << 20) - himem_start, E820_RAM),
+                (127 << 20, 2 << 20, E820_RESERVED),
+                (1 << 32, 1 << 20, E820_RESERVED),
+            ]
+        );
     }
 
     #[test]
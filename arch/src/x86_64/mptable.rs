@@ -124,6 +124,11 @@ fn compute_mp_size(num_cpus: u8) -> usize {
         + mem::size_of::<MpcLintsrcWrapper>() * 2
 }
 
+/// Returns the guest address range, given as start and size, of the MP table for `num_cpus`.
+pub fn mptable_range(num_cpus: u8) -> (GuestAddress, usize) {
+    (GuestAddress(MPTABLE_START), compute_mp_size(num_cpus))
+}
+
 /// Performs setup of the MP table for the given `num_cpus`.
 pub fn setup_mptable(mem: &GuestMemory, num_cpus: u8) -> Result<()> {
     if u32::from(num_cpus) > MAX_SUPPORTED_CPUS {
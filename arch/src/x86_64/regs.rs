@@ -128,6 +128,18 @@ pub fn setup_sregs(mem: &GuestMemory, vcpu: &VcpuFd) -> Result<()> {
     vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)
 }
 
+/// Returns the guest address ranges, given as start and size, to which `setup_sregs` writes the
+/// GDT, the IDT and the page tables.
+pub fn boot_tables_ranges() -> Vec<(GuestAddress, usize)> {
+    vec![
+        (
+            GuestAddress(BOOT_GDT_OFFSET),
+            BOOT_IDT_OFFSET + mem::size_of::<u64>() - BOOT_GDT_OFFSET,
+        ),
+        (GuestAddress(PML4_START), PDE_START + 0x1000 - PML4_START),
+    ]
+}
+
 const BOOT_GDT_OFFSET: usize = 0x500;
 const BOOT_IDT_OFFSET: usize = 0x520;
 
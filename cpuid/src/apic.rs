@@ -0,0 +1,69 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use bit_helper::BitHelper;
+use cpu_leaf::*;
+use kvm_bindings::kvm_cpuid_entry2;
+use transformer::Error;
+
+/// Hides x2APIC from the guest, so that it keeps its local APIC in xAPIC mode.
+pub fn set_xapic(entries: &mut [kvm_cpuid_entry2]) {
+    for entry in entries
+        .iter_mut()
+        .filter(|entry| entry.function == leaf_0x1::LEAF_NUM)
+    {
+        entry.ecx.write_bit(leaf_0x1::ecx::X2APIC_SHIFT, false);
+    }
+}
+
+/// Advertises x2APIC to the guest, failing if KVM doesn't expose it.
+pub fn set_x2apic(entries: &mut [kvm_cpuid_entry2]) -> Result<(), Error> {
+    let supported = entries.iter().any(|entry| {
+        entry.function == leaf_0x1::LEAF_NUM && entry.ecx.read_bit(leaf_0x1::ecx::X2APIC_SHIFT)
+    });
+    if !supported {
+        return Err(Error::X2ApicNotSupported);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32, ecx: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            ecx,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_set_xapic() {
+        let mut entries = [
+            entry(leaf_0x1::LEAF_NUM, (1 << 21) | 1),
+            entry(0x7, 1 << 21),
+        ];
+        set_xapic(&mut entries);
+        assert_eq!(entries[0].ecx, 1);
+        // The other leaves are left alone.
+        assert_eq!(entries[1].ecx, 1 << 21);
+    }
+
+    #[test]
+    fn test_set_x2apic() {
+        let mut entries = [entry(leaf_0x1::LEAF_NUM, 1 << 21)];
+        assert!(set_x2apic(&mut entries).is_ok());
+        assert_eq!(entries[0].ecx, 1 << 21);
+
+        // KVM doesn't expose x2APIC.
+        let mut entries = [entry(leaf_0x1::LEAF_NUM, 0)];
+        match set_x2apic(&mut entries) {
+            Err(Error::X2ApicNotSupported) => (),
+            _ => panic!("x2APIC should not be supported"),
+        }
+        let mut entries = [entry(0x7, 1 << 21)];
+        assert!(set_x2apic(&mut entries).is_err());
+    }
+}
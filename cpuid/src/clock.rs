@@ -0,0 +1,98 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use cpu_leaf::*;
+use kvm_bindings::kvm_cpuid_entry2;
+use transformer::Error;
+
+const KVM_CLOCK_BITS: u32 = (1 << leaf_0x40000001::eax::CLOCKSOURCE_SHIFT)
+    | (1 << leaf_0x40000001::eax::CLOCKSOURCE2_SHIFT)
+    | (1 << leaf_0x40000001::eax::CLOCKSOURCE_STABLE_SHIFT);
+
+/// Exposes kvm-clock to the guest, failing if KVM doesn't support it.
+pub fn set_kvm_clock(entries: &mut [kvm_cpuid_entry2]) -> Result<(), Error> {
+    let entry = entries
+        .iter_mut()
+        .find(|entry| entry.function == leaf_0x40000001::LEAF_NUM)
+        .ok_or(Error::KvmClockNotSupported)?;
+    if entry.eax & (1 << leaf_0x40000001::eax::CLOCKSOURCE2_SHIFT) == 0 {
+        return Err(Error::KvmClockNotSupported);
+    }
+    entry.eax |= 1 << leaf_0x40000001::eax::CLOCKSOURCE_SHIFT;
+    Ok(())
+}
+
+/// Hides kvm-clock from the guest, so that it keeps time with the TSC. Fails if the TSC of the
+/// host is not invariant, since the guest would then see it drift.
+pub fn set_tsc(entries: &mut [kvm_cpuid_entry2]) -> Result<(), Error> {
+    let invariant_tsc = entries.iter().any(|entry| {
+        entry.function == leaf_0x80000007::LEAF_NUM
+            && entry.edx & (1 << leaf_0x80000007::edx::INVARIANT_TSC_SHIFT) != 0
+    });
+    if !invariant_tsc {
+        return Err(Error::TscNotInvariant);
+    }
+    for entry in entries
+        .iter_mut()
+        .filter(|entry| entry.function == leaf_0x40000001::LEAF_NUM)
+    {
+        entry.eax &= !KVM_CLOCK_BITS;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32, eax: u32, edx: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            eax,
+            edx,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_set_kvm_clock() {
+        let mut entries = [entry(leaf_0x40000001::LEAF_NUM, 1 << 3, 0)];
+        assert!(set_kvm_clock(&mut entries).is_ok());
+        assert_eq!(entries[0].eax, (1 << 3) | 1);
+
+        // KVM doesn't expose kvm-clock.
+        let mut entries = [entry(leaf_0x40000001::LEAF_NUM, 0, 0)];
+        match set_kvm_clock(&mut entries) {
+            Err(Error::KvmClockNotSupported) => (),
+            _ => panic!("kvm-clock should not be supported"),
+        }
+        let mut entries = [entry(0x1, 0, 0)];
+        assert!(set_kvm_clock(&mut entries).is_err());
+    }
+
+    #[test]
+    fn test_set_tsc() {
+        let mut entries = [
+            entry(
+                leaf_0x40000001::LEAF_NUM,
+                (1 << 24) | (1 << 3) | (1 << 1) | 1,
+                0,
+            ),
+            entry(leaf_0x80000007::LEAF_NUM, 0, 1 << 8),
+        ];
+        assert!(set_tsc(&mut entries).is_ok());
+        // Only the kvm-clock features are hidden.
+        assert_eq!(entries[0].eax, 1 << 1);
+
+        // The TSC of the host is not invariant.
+        let mut entries = [
+            entry(leaf_0x40000001::LEAF_NUM, 1 << 3, 0),
+            entry(leaf_0x80000007::LEAF_NUM, 0, 0),
+        ];
+        match set_tsc(&mut entries) {
+            Err(Error::TscNotInvariant) => (),
+            _ => panic!("the TSC should not be invariant"),
+        }
+        assert_eq!(entries[0].eax, 1 << 3);
+    }
+}
@@ -47,6 +47,7 @@ pub mod leaf_0x1 {
         // PDCM = Perfmon and Debug Capability
         pub const PDCM_SHIFT: u32 = 15;
         // 18 = DCA Direct Cache Access (prefetch data from a memory mapped device)
+        pub const X2APIC_SHIFT: u32 = 21;
         pub const MOVBE_SHIFT: u32 = 22;
         pub const TSC_DEADLINE_TIMER_BITINDEX: u32 = 24;
         pub const OSXSAVE_SHIFT: u32 = 27;
@@ -201,6 +202,20 @@ pub mod leaf_0xb {
     }
 }
 
+// KVM Paravirtualized Features Leaf
+pub mod leaf_0x40000001 {
+    pub const LEAF_NUM: u32 = 0x4000_0001;
+
+    pub mod eax {
+        // kvm-clock, through the legacy MSRs.
+        pub const CLOCKSOURCE_SHIFT: u32 = 0;
+        // kvm-clock, through the new MSRs.
+        pub const CLOCKSOURCE2_SHIFT: u32 = 3;
+        // The kvm-clock readings don't go backwards across vCPUs.
+        pub const CLOCKSOURCE_STABLE_SHIFT: u32 = 24;
+    }
+}
+
 pub mod leaf_0x80000000 {
     pub const LEAF_NUM: u32 = 0x8000_0000;
 
@@ -225,6 +240,16 @@ pub mod leaf_0x80000001 {
     }
 }
 
+// Advanced Power Management Leaf
+pub mod leaf_0x80000007 {
+    pub const LEAF_NUM: u32 = 0x8000_0007;
+
+    pub mod edx {
+        // The TSC runs at a constant rate in all the ACPI P-, C- and T-states.
+        pub const INVARIANT_TSC_SHIFT: u32 = 8;
+    }
+}
+
 pub mod leaf_0x80000008 {
     pub const LEAF_NUM: u32 = 0x8000_0008;
 
@@ -31,6 +31,12 @@ use transformer::*;
 
 mod brand_string;
 
+/// Selects the mode of the local APIC of the guest.
+pub mod apic;
+
+/// Pins the clock source of the guest.
+pub mod clock;
+
 /// Sets up the CPUID entries for the given vcpu.
 ///
 /// # Arguments
@@ -38,6 +44,7 @@ mod brand_string;
 /// * `cpu_id` - The index of the VCPU for which the CPUID entries are configured.
 /// * `cpu_count` - The total number of present VCPUs.
 /// * `ht_enabled` - Whether or not to enable HT.
+/// * `sockets` - The number of sockets the VCPUs are evenly spread across.
 /// * `kvm_cpuid` - KVM related structure holding the relevant CPUID info.
 ///
 /// # Example
@@ -50,7 +57,7 @@ mod brand_string;
 ///
 /// let kvm = Kvm::new().unwrap();
 /// let mut kvm_cpuid: CpuId = kvm.get_supported_cpuid(MAX_KVM_CPUID_ENTRIES).unwrap();
-/// filter_cpuid(0, 1, true, &mut kvm_cpuid).unwrap();
+/// filter_cpuid(0, 1, true, 1, &mut kvm_cpuid).unwrap();
 ///
 /// // Get expected `kvm_cpuid` entries.
 /// let entries = kvm_cpuid.mut_entries_slice();
@@ -60,10 +67,11 @@ pub fn filter_cpuid(
     cpu_id: u8,
     cpu_count: u8,
     ht_enabled: bool,
+    sockets: u8,
     kvm_cpuid: &mut CpuId,
 ) -> Result<(), Error> {
     let vendor_id = get_vendor_id().map_err(Error::InternalError)?;
-    let vm_spec = VmSpec::new(&vendor_id, cpu_id, cpu_count, ht_enabled);
+    let vm_spec = VmSpec::new(&vendor_id, cpu_id, cpu_count, ht_enabled, sockets);
 
     let maybe_cpuid_transformer: Option<&dyn CpuidTransformer> = match &vendor_id {
         VENDOR_ID_INTEL => Some(&intel::IntelCpuidTransformer {}),
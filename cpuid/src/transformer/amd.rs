@@ -69,11 +69,21 @@ pub fn update_amd_features_entry(
     use cpu_leaf::leaf_0x80000008::*;
 
     // We don't support more then 64 threads right now.
-    // It's safe to put them all on the same processor.
+    // With a single socket, it's safe to put them all on the same processor. With multiple
+    // sockets, the bits above the ones needed to address the threads of a socket hold the
+    // socket id.
+    let thread_id_size = if vm_spec.sockets > 1 {
+        u32::from(vm_spec.cpus_per_socket()).trailing_zeros()
+    } else {
+        THREAD_ID_MAX_SIZE
+    };
     entry
         .ecx
-        .write_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE, THREAD_ID_MAX_SIZE)
-        .write_bits_in_range(&ecx::NUM_THREADS_BITRANGE, u32::from(vm_spec.cpu_count - 1));
+        .write_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE, thread_id_size)
+        .write_bits_in_range(
+            &ecx::NUM_THREADS_BITRANGE,
+            u32::from(vm_spec.cpus_per_socket() - 1),
+        );
 
     Ok(())
 }
@@ -166,7 +176,7 @@ mod test {
         use cpu_leaf::leaf_0x7::index0::*;
 
         // Check that if index == 0 the entry is processed
-        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, 1, false);
+        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, 1, false, 1);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: leaf_0x7::LEAF_NUM,
             index: 0,
@@ -191,7 +201,7 @@ mod test {
     fn test_update_largest_extended_fn_entry() {
         use cpu_leaf::leaf_0x80000000::*;
 
-        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, 1, false);
+        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, 1, false, 1);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: LEAF_NUM,
             index: 0,
@@ -217,7 +227,7 @@ mod test {
     fn test_update_extended_feature_info_entry() {
         use cpu_leaf::leaf_0x80000001::*;
 
-        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, 1, false);
+        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, 1, false, 1);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: LEAF_NUM,
             index: 0,
@@ -237,7 +247,7 @@ mod test {
     fn check_update_amd_features_entry(cpu_count: u8, ht_enabled: bool) {
         use cpu_leaf::leaf_0x80000008::*;
 
-        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, cpu_count, ht_enabled);
+        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, cpu_count, ht_enabled, 1);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: LEAF_NUM,
             index: 0,
@@ -270,7 +280,7 @@ mod test {
     ) {
         use cpu_leaf::leaf_0x8000001e::*;
 
-        let vm_spec = VmSpec::new(VENDOR_ID_AMD, cpu_id, cpu_count, ht_enabled);
+        let vm_spec = VmSpec::new(VENDOR_ID_AMD, cpu_id, cpu_count, ht_enabled, 1);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: LEAF_NUM,
             index: 0,
@@ -313,7 +323,7 @@ mod test {
 
     #[test]
     fn test_update_extended_cache_topology_entry() {
-        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, 1, false);
+        let vm_spec = VmSpec::new(VENDOR_ID_AMD, 0, 1, false, 1);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: leaf_0x8000001d::LEAF_NUM,
             index: 0,
@@ -33,7 +33,8 @@ pub fn update_feature_info_entry(
 ) -> Result<(), Error> {
     use cpu_leaf::leaf_0x1::*;
 
-    let max_cpus_per_package = u32::from(common::get_max_cpus_per_package(vm_spec.cpu_count)?);
+    let max_cpus_per_package =
+        u32::from(common::get_max_cpus_per_package(vm_spec.cpus_per_socket())?);
 
     // X86 hypervisor feature
     entry.ecx.write_bit(ecx::HYPERVISOR_BITINDEX, true);
@@ -47,7 +48,7 @@ pub fn update_feature_info_entry(
     // A value of 1 for HTT indicates the value in CPUID.1.EBX[23:16]
     // (the Maximum number of addressable IDs for logical processors in this package)
     // is valid for the package
-    entry.edx.write_bit(edx::HTT, vm_spec.cpu_count > 1);
+    entry.edx.write_bit(edx::HTT, vm_spec.cpus_per_socket() > 1);
 
     Ok(())
 }
@@ -82,10 +83,10 @@ pub fn update_cache_parameters_entry(
         }
         // L3 Cache
         3 => {
-            // The L3 cache is shared among all the logical threads
+            // The L3 cache is shared among all the logical threads of a socket
             entry.eax.write_bits_in_range(
                 &eax::MAX_CPUS_PER_CORE_BITRANGE,
-                u32::from(vm_spec.cpu_count - 1),
+                u32::from(vm_spec.cpus_per_socket() - 1),
             );
         }
         _ => (),
@@ -160,7 +161,7 @@ mod test {
     fn check_update_feature_info_entry(cpu_count: u8, expected_htt: bool) {
         use cpu_leaf::leaf_0x1::*;
 
-        let vm_spec = VmSpec::new(VENDOR_ID_INTEL, 0, cpu_count, false);
+        let vm_spec = VmSpec::new(VENDOR_ID_INTEL, 0, cpu_count, false, 1);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: 0x0,
             index: 0,
@@ -185,7 +186,7 @@ mod test {
     ) {
         use cpu_leaf::leaf_cache_parameters::*;
 
-        let vm_spec = VmSpec::new(VENDOR_ID_INTEL, 0, cpu_count, ht_enabled);
+        let vm_spec = VmSpec::new(VENDOR_ID_INTEL, 0, cpu_count, ht_enabled, 1);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: 0x0,
             index: 0,
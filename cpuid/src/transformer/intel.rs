@@ -30,10 +30,10 @@ fn update_deterministic_cache_entry(
 
     common::update_cache_parameters_entry(entry, vm_spec)?;
 
-    // Put all the cores in the same socket
+    // Spread the cores evenly across the sockets
     entry.eax.write_bits_in_range(
         &eax::MAX_CORES_PER_PACKAGE_BITRANGE,
-        u32::from(vm_spec.cpu_count - 1),
+        u32::from(vm_spec.cpus_per_socket() - 1),
     );
 
     Ok(())
@@ -103,9 +103,16 @@ fn update_extended_cache_topology_entry(
         }
         // Core Level Processor Topology; index = 1
         1 => {
+            // With multiple sockets, the bits above the ones needed to address the logical
+            // processors of a socket hold the socket id.
+            let apicid_shift = if vm_spec.sockets > 1 {
+                u32::from(vm_spec.cpus_per_socket()).trailing_zeros()
+            } else {
+                LEAFBH_INDEX1_APICID
+            };
             entry
                 .eax
-                .write_bits_in_range(&eax::APICID_BITRANGE, LEAFBH_INDEX1_APICID);
+                .write_bits_in_range(&eax::APICID_BITRANGE, apicid_shift);
             entry
                 .ecx
                 .write_bits_in_range(&ecx::LEVEL_NUMBER_BITRANGE, entry.index as u32);
@@ -117,7 +124,7 @@ fn update_extended_cache_topology_entry(
             } else {
                 entry.ebx.write_bits_in_range(
                     &ebx::NUM_LOGICAL_PROCESSORS_BITRANGE,
-                    u32::from(vm_spec.cpu_count),
+                    u32::from(vm_spec.cpus_per_socket()),
                 );
                 entry
                     .ecx
@@ -176,7 +183,7 @@ mod test {
     ) {
         use cpu_leaf::leaf_0x4::*;
 
-        let vm_spec = VmSpec::new(VENDOR_ID_INTEL, 0, cpu_count, ht_enabled);
+        let vm_spec = VmSpec::new(VENDOR_ID_INTEL, 0, cpu_count, ht_enabled, 1);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: 0x0,
             index: 0,
@@ -201,6 +208,7 @@ mod test {
     fn check_update_extended_cache_topology_entry(
         cpu_count: u8,
         ht_enabled: bool,
+        sockets: u8,
         index: u32,
         expected_apicid: u32,
         expected_num_logical_processors: u32,
@@ -208,7 +216,7 @@ mod test {
     ) {
         use cpu_leaf::leaf_0xb::*;
 
-        let vm_spec = VmSpec::new(VENDOR_ID_INTEL, 0, cpu_count, ht_enabled);
+        let vm_spec = VmSpec::new(VENDOR_ID_INTEL, 0, cpu_count, ht_enabled, sockets);
         let mut entry = &mut kvm_cpuid_entry2 {
             function: 0x0,
             index,
@@ -245,12 +253,13 @@ mod test {
 
         // test update_extended_cache_topology_entry
         // index 0
-        check_update_extended_cache_topology_entry(1, false, 0, 0, 1, LEVEL_TYPE_CORE);
+        check_update_extended_cache_topology_entry(1, false, 1, 0, 0, 1, LEVEL_TYPE_CORE);
         // index 1
         check_update_extended_cache_topology_entry(
             1,
             false,
             1,
+            1,
             LEAFBH_INDEX1_APICID,
             0,
             LEVEL_TYPE_INVALID,
@@ -269,12 +278,13 @@ mod test {
 
         // test update_extended_cache_topology_entry
         // index 0
-        check_update_extended_cache_topology_entry(1, true, 0, 0, 1, LEVEL_TYPE_CORE);
+        check_update_extended_cache_topology_entry(1, true, 1, 0, 0, 1, LEVEL_TYPE_CORE);
         // index 1
         check_update_extended_cache_topology_entry(
             1,
             true,
             1,
+            1,
             LEAFBH_INDEX1_APICID,
             0,
             LEVEL_TYPE_INVALID,
@@ -293,12 +303,13 @@ mod test {
 
         // test update_extended_cache_topology_entry
         // index 0
-        check_update_extended_cache_topology_entry(2, false, 0, 0, 1, LEVEL_TYPE_THREAD);
+        check_update_extended_cache_topology_entry(2, false, 1, 0, 0, 1, LEVEL_TYPE_THREAD);
         // index 1
         check_update_extended_cache_topology_entry(
             2,
             false,
             1,
+            1,
             LEAFBH_INDEX1_APICID,
             2,
             LEVEL_TYPE_CORE,
@@ -317,15 +328,46 @@ mod test {
 
         // test update_extended_cache_topology_entry
         // index 0
-        check_update_extended_cache_topology_entry(2, true, 0, 1, 2, LEVEL_TYPE_THREAD);
+        check_update_extended_cache_topology_entry(2, true, 1, 0, 1, 2, LEVEL_TYPE_THREAD);
         // index 1
         check_update_extended_cache_topology_entry(
             2,
             true,
             1,
+            1,
             LEAFBH_INDEX1_APICID,
             2,
             LEVEL_TYPE_CORE,
         );
     }
+
+    #[test]
+    fn test_1socket_2cores_2threads() {
+        // 1 socket * 2 cores * 2 threads = 4 vCPUs.
+        // test update_deterministic_cache_entry
+        check_update_deterministic_cache_entry(4, true, 1, 3);
+
+        // test update_extended_cache_topology_entry
+        // index 0: 2 threads per core, addressed by 1 bit of the APIC ID.
+        check_update_extended_cache_topology_entry(4, true, 1, 0, 1, 2, LEVEL_TYPE_THREAD);
+        // index 1: 4 logical processors in the socket.
+        check_update_extended_cache_topology_entry(
+            4,
+            true,
+            1,
+            1,
+            LEAFBH_INDEX1_APICID,
+            4,
+            LEVEL_TYPE_CORE,
+        );
+    }
+
+    #[test]
+    fn test_2sockets_2cores_2threads() {
+        // 2 sockets * 2 cores * 2 threads = 8 vCPUs.
+        // index 0: 2 threads per core, addressed by 1 bit of the APIC ID.
+        check_update_extended_cache_topology_entry(8, true, 2, 0, 1, 2, LEVEL_TYPE_THREAD);
+        // index 1: 4 logical processors in each socket, addressed by 2 bits of the APIC ID.
+        check_update_extended_cache_topology_entry(8, true, 2, 1, 2, 4, LEVEL_TYPE_CORE);
+    }
 }
@@ -15,19 +15,32 @@ pub struct VmSpec {
     pub cpu_id: u8,
     pub cpu_count: u8,
     pub ht_enabled: bool,
+    pub sockets: u8,
     brand_string: BrandString,
 }
 
 impl VmSpec {
-    pub fn new(vendor_id: &[u8; 12], cpu_id: u8, cpu_count: u8, ht_enabled: bool) -> VmSpec {
+    pub fn new(
+        vendor_id: &[u8; 12],
+        cpu_id: u8,
+        cpu_count: u8,
+        ht_enabled: bool,
+        sockets: u8,
+    ) -> VmSpec {
         VmSpec {
             cpu_id,
             cpu_count,
             ht_enabled,
+            sockets,
             brand_string: BrandString::from_vendor_id(vendor_id),
         }
     }
 
+    /// Returns the number of logical CPUs in each socket.
+    pub fn cpus_per_socket(&self) -> u8 {
+        self.cpu_count / self.sockets
+    }
+
     pub fn brand_string(&self) -> &BrandString {
         &self.brand_string
     }
@@ -42,6 +55,12 @@ pub enum Error {
     SizeLimitExceeded,
     /// A call to an internal helper method failed
     InternalError(super::common::Error),
+    /// KVM doesn't expose kvm-clock to the guest.
+    KvmClockNotSupported,
+    /// The TSC of the host is not invariant.
+    TscNotInvariant,
+    /// KVM doesn't expose x2APIC to the guest.
+    X2ApicNotSupported,
 }
 
 pub type EntryTransformerFn =
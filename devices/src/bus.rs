@@ -135,6 +135,12 @@ impl Bus {
         Ok(())
     }
 
+    /// Removes the device placed at the `base` address, returning it if there was one.
+    pub fn remove(&mut self, base: u64) -> Option<Arc<Mutex<BusDevice>>> {
+        // Ranges are compared only by their base, so the length doesn't matter here.
+        self.devices.remove(&BusRange(base, 0))
+    }
+
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
     ///
     /// Returns true on success, otherwise `data` is untouched.
@@ -209,6 +215,22 @@ mod tests {
         assert!(bus.insert(dummy.clone(), 0x0, 0x10).is_ok());
     }
 
+    #[test]
+    fn bus_remove() {
+        let mut bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.remove(0x11).is_none());
+        assert!(bus.read(0x10, &mut [0, 0, 0, 0]));
+
+        assert!(bus.remove(0x10).is_some());
+        assert!(!bus.read(0x10, &mut [0, 0, 0, 0]));
+        assert!(bus.remove(0x10).is_none());
+
+        // The range is free again.
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+    }
+
     #[test]
     fn bus_read_write() {
         let mut bus = Bus::new();
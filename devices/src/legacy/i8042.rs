@@ -19,6 +19,8 @@ pub enum Error {
     KbdInterruptDisabled,
     KbdInterruptFailure(io::Error),
     InternalBufferFull,
+    ResetFailure(io::Error),
+    ResetNotAllowedPreBoot,
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -37,6 +39,14 @@ impl fmt::Display for Error {
                 io_err.to_string()
             ),
             Error::InternalBufferFull => write!(f, "i8042 internal buffer full."),
+            Error::ResetFailure(io_err) => write!(
+                f,
+                "Could not trigger the CPU reset: {}.",
+                io_err.to_string()
+            ),
+            Error::ResetNotAllowedPreBoot => {
+                write!(f, "The CPU reset is not allowed before boot.")
+            }
         }
     }
 }
@@ -150,6 +160,12 @@ impl I8042Device {
         }
     }
 
+    /// Asserts the CPU reset line, as the guest does by issuing CMD_RESET_CPU.
+    pub fn trigger_reset(&self) -> Result<()> {
+        METRICS.i8042.reset_count.inc();
+        self.reset_evt.write(1).map_err(Error::ResetFailure)
+    }
+
     #[inline]
     pub fn trigger_ctrl_alt_del(&mut self) -> Result<()> {
         // The CTRL+ALT+DEL sequence is 4 bytes in total (1 extended key + 2 normal keys).
@@ -249,11 +265,10 @@ impl BusDevice for I8042Device {
                 // The guest wants to assert the CPU reset line. We handle that by triggering
                 // our exit event fd. Meaning Firecracker will be exiting as soon as the VMM
                 // thread wakes up to handle this event.
-                if let Err(e) = self.reset_evt.write(1) {
+                if let Err(e) = self.trigger_reset() {
                     error!("Failed to trigger i8042 reset event: {:?}", e);
                     METRICS.i8042.error_count.inc();
                 }
-                METRICS.i8042.reset_count.inc();
             }
             OFS_STATUS if data[0] == CMD_READ_CTR => {
                 // The guest wants to read the control register.
@@ -369,6 +384,17 @@ mod tests {
         assert_eq!(METRICS.i8042.missed_write_count.count(), before + 3);
     }
 
+    #[test]
+    fn test_i8042_trigger_reset() {
+        let i8042 = I8042Device::new(EventFd::new().unwrap(), EventFd::new().unwrap());
+        let reset_evt = i8042.get_reset_evt_clone().unwrap();
+
+        let before = METRICS.i8042.reset_count.count();
+        assert!(i8042.trigger_reset().is_ok());
+        assert_eq!(reset_evt.read().unwrap(), 1);
+        assert_eq!(METRICS.i8042.reset_count.count(), before + 1);
+    }
+
     #[test]
     fn test_i8042_commands() {
         let mut i8042 = I8042Device::new(EventFd::new().unwrap(), EventFd::new().unwrap());
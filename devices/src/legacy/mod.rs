@@ -6,8 +6,12 @@
 // found in the THIRD-PARTY file.
 
 mod i8042;
+mod pvpanic;
+mod rtc_pl031;
 mod serial;
 
 pub use self::i8042::Error as I8042DeviceError;
 pub use self::i8042::I8042Device;
+pub use self::pvpanic::{PvPanic, PVPANIC_PANICKED};
+pub use self::rtc_pl031::RTC;
 pub use self::serial::Serial;
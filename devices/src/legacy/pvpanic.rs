@@ -0,0 +1,48 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The MMIO flavour of the QEMU pvpanic device.
+//!
+//! The guest reads the panic events the device supports from its only register, and writes the
+//! event there when it panics. The write is handled by the vCPU which exits on it, since the
+//! action taken depends on the vCPUs.
+
+use BusDevice;
+
+/// The guest panicked.
+pub const PVPANIC_PANICKED: u8 = 0x1;
+
+/// Emulates the pvpanic register, which only advertises the "panicked" event.
+#[derive(Default)]
+pub struct PvPanic;
+
+impl BusDevice for PvPanic {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if offset != 0 || data.len() != 1 {
+            warn!(
+                "Invalid pvpanic read at offset {:#x} of {} bytes",
+                offset,
+                data.len()
+            );
+            return;
+        }
+        data[0] = PVPANIC_PANICKED;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supported_events() {
+        let mut pvpanic = PvPanic::default();
+        let mut data = [0u8; 1];
+        pvpanic.read(0, &mut data);
+        assert_eq!(data[0], PVPANIC_PANICKED);
+
+        let mut data = [0u8; 4];
+        pvpanic.read(0, &mut data);
+        assert_eq!(data, [0u8; 4]);
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! ARM PrimeCell Real Time Clock (PL031).
+//!
+//! See the "ARM PrimeCell Real Time Clock (PL031) Technical Reference Manual" for the
+//! description of the registers.
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+use sys_util::EventFd;
+
+use BusDevice;
+
+// Data register.
+const RTCDR: u64 = 0x0;
+// Match register.
+const RTCMR: u64 = 0x4;
+// Load register.
+const RTCLR: u64 = 0x8;
+// Control register.
+const RTCCR: u64 = 0xc;
+// Interrupt mask set and clear register.
+const RTCIMSC: u64 = 0x10;
+// Raw interrupt status register.
+const RTCRIS: u64 = 0x14;
+// Masked interrupt status register.
+const RTCMIS: u64 = 0x18;
+// Interrupt clear register.
+const RTCICR: u64 = 0x1c;
+
+// The peripheral and PrimeCell identification registers, which the guest reads for detecting
+// the device.
+const AMBA_ID_LOW: u64 = 0xfe0;
+const AMBA_ID_HIGH: u64 = 0x1000;
+const PL031_ID: [u8; 8] = [0x31, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// Emulates the PL031 RTC, counting seconds from a base time.
+///
+/// The match register is stored, but no alarm interrupt is raised when the counter reaches it.
+pub struct RTC {
+    // The counter value when `base_instant` was taken.
+    base_time: u64,
+    base_instant: Instant,
+    match_value: u32,
+    interrupt_mask: u32,
+    interrupt_status: u32,
+    interrupt_evt: EventFd,
+}
+
+impl RTC {
+    /// Constructs a PL031 RTC which starts counting from `base_time` seconds since the epoch,
+    /// or from the current host time when `base_time` is `None`.
+    pub fn new(interrupt_evt: EventFd, base_time: Option<u64>) -> RTC {
+        let base_time = base_time.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+        RTC {
+            base_time,
+            base_instant: Instant::now(),
+            match_value: 0,
+            interrupt_mask: 0,
+            interrupt_status: 0,
+            interrupt_evt,
+        }
+    }
+
+    // The counter is 32 bits wide, so it wraps around.
+    fn counter(&self) -> u32 {
+        (self.base_time + self.base_instant.elapsed().as_secs()) as u32
+    }
+
+    fn update_interrupt(&self) {
+        if self.interrupt_status & self.interrupt_mask != 0 {
+            if let Err(e) = self.interrupt_evt.write(1) {
+                error!("Failed to signal the RTC interrupt: {:?}", e);
+            }
+        }
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            RTCDR => self.counter(),
+            RTCMR => self.match_value,
+            // The counter is loaded by writing this register, which reads back the counter.
+            RTCLR => self.counter(),
+            // The RTC is always started.
+            RTCCR => 1,
+            RTCIMSC => self.interrupt_mask,
+            RTCRIS => self.interrupt_status,
+            RTCMIS => self.interrupt_status & self.interrupt_mask,
+            AMBA_ID_LOW..=0xfff => u32::from(PL031_ID[((offset - AMBA_ID_LOW) >> 2) as usize]),
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        match offset {
+            RTCMR => self.match_value = value,
+            RTCLR => {
+                self.base_time = u64::from(value);
+                self.base_instant = Instant::now();
+            }
+            RTCIMSC => {
+                self.interrupt_mask = value & 1;
+                self.update_interrupt();
+            }
+            RTCICR => self.interrupt_status &= !value,
+            _ => warn!("Invalid write to RTC register at offset {:#x}", offset),
+        }
+    }
+}
+
+impl BusDevice for RTC {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        // The registers are 32 bits wide and word aligned.
+        if data.len() != 4 || offset % 4 != 0 || offset >= AMBA_ID_HIGH {
+            warn!(
+                "Invalid RTC read at offset {:#x} of {} bytes",
+                offset,
+                data.len()
+            );
+            return;
+        }
+        LittleEndian::write_u32(data, self.read_register(offset));
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.len() != 4 || offset % 4 != 0 {
+            warn!(
+                "Invalid RTC write at offset {:#x} of {} bytes",
+                offset,
+                data.len()
+            );
+            return;
+        }
+        self.write_register(offset, LittleEndian::read_u32(data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(rtc: &mut RTC, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        rtc.read(offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write(rtc: &mut RTC, offset: u64, value: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        rtc.write(offset, &data);
+    }
+
+    #[test]
+    fn test_base_time() {
+        // 2000-01-01T00:00:00Z
+        let base_time = 946_684_800;
+        let mut rtc = RTC::new(EventFd::new().unwrap(), Some(base_time));
+
+        // Allow for the clock ticking while the test runs.
+        let counter = u64::from(read(&mut rtc, RTCDR));
+        assert!(counter >= base_time && counter <= base_time + 1);
+
+        // The guest can set the time too.
+        write(&mut rtc, RTCLR, 1000);
+        let counter = read(&mut rtc, RTCDR);
+        assert!(counter >= 1000 && counter <= 1001);
+    }
+
+    #[test]
+    fn test_host_time() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut rtc = RTC::new(EventFd::new().unwrap(), None);
+        // The counter holds the lower 32 bits of the time.
+        let counter = read(&mut rtc, RTCDR);
+        assert!(counter.wrapping_sub(now as u32) <= 1);
+    }
+
+    #[test]
+    fn test_registers() {
+        let interrupt_evt = EventFd::new().unwrap();
+        let mut rtc = RTC::new(interrupt_evt.try_clone().unwrap(), Some(0));
+
+        assert_eq!(read(&mut rtc, RTCCR), 1);
+        write(&mut rtc, RTCMR, 0x1234);
+        assert_eq!(read(&mut rtc, RTCMR), 0x1234);
+
+        // The interrupt is signaled only when it is both pending and unmasked.
+        rtc.interrupt_status = 1;
+        assert_eq!(read(&mut rtc, RTCMIS), 0);
+        write(&mut rtc, RTCIMSC, 1);
+        assert_eq!(read(&mut rtc, RTCMIS), 1);
+        assert_eq!(interrupt_evt.read().unwrap(), 1);
+        write(&mut rtc, RTCICR, 1);
+        assert_eq!(read(&mut rtc, RTCRIS), 0);
+
+        // The identification registers.
+        let id: Vec<u32> = (0..8)
+            .map(|i| read(&mut rtc, AMBA_ID_LOW + 4 * i))
+            .collect();
+        assert_eq!(id, vec![0x31, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1]);
+
+        // Error cases: unaligned or short accesses are ignored.
+        let mut data = [0xffu8; 2];
+        rtc.read(RTCMR, &mut data);
+        assert_eq!(data, [0xff, 0xff]);
+        let mut data = [0xffu8; 4];
+        rtc.read(RTCMR + 1, &mut data);
+        assert_eq!(data, [0xff; 4]);
+        rtc.write(RTCMR, &[0, 0]);
+        assert_eq!(read(&mut rtc, RTCMR), 0x1234);
+    }
+}
@@ -9,6 +9,7 @@
 extern crate byteorder;
 extern crate epoll;
 extern crate libc;
+extern crate timerfd;
 
 extern crate dumbo;
 #[macro_use]
@@ -17,14 +18,16 @@ extern crate memory_model;
 extern crate net_gen;
 extern crate net_util;
 extern crate rate_limiter;
+#[macro_use]
 extern crate sys_util;
-#[cfg(feature = "vsock")]
+#[cfg(any(feature = "vsock", feature = "vhost-user-block"))]
 extern crate vhost_backend;
 #[cfg(feature = "vsock")]
 extern crate vhost_gen;
 extern crate virtio_gen;
 
 use rate_limiter::{Error as RateLimiterError, TokenBucket};
+use std::fmt;
 use std::fs::File;
 use std::io;
 
@@ -56,6 +59,27 @@ pub enum EpollHandlerPayload {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Health status of an emulated device, as observed by the device backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceHealth {
+    /// The device is working as expected.
+    Healthy,
+    /// The device is still operating, but some of its requests failed. Holds the reason.
+    Degraded(String),
+    /// The device can no longer service guest requests. Holds the reason.
+    Failed(String),
+}
+
+impl fmt::Display for DeviceHealth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceHealth::Healthy => write!(f, "Healthy"),
+            DeviceHealth::Degraded(_) => write!(f, "Degraded"),
+            DeviceHealth::Failed(_) => write!(f, "Failed"),
+        }
+    }
+}
+
 pub trait EpollHandler: Send {
     fn handle_event(
         &mut self,
@@ -63,6 +87,11 @@ pub trait EpollHandler: Send {
         event_flags: u32,
         payload: EpollHandlerPayload,
     ) -> Result<()>;
+
+    /// Returns the current health status of the device served by this handler.
+    fn health(&self) -> DeviceHealth {
+        DeviceHealth::Healthy
+    }
 }
 
 #[derive(Debug)]
@@ -80,4 +109,6 @@ pub enum Error {
         event: DeviceEventT,
     },
     IoError(io::Error),
+    /// The backend servicing the device in another process went away.
+    BackendDisconnected,
 }
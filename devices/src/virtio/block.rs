@@ -10,11 +10,13 @@ use std::cmp;
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::time::Duration;
 
 use super::super::Error as DeviceError;
 use super::{
@@ -22,16 +24,21 @@ use super::{
     TYPE_BLOCK, VIRTIO_MMIO_INT_VRING,
 };
 use logger::{Metric, METRICS};
-use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
+use memory_model::{GuestAddress, GuestMemory, GuestMemoryError, MemoryMappingError};
 use rate_limiter::{RateLimiter, TokenType};
-use sys_util::EventFd;
+use sys_util::{ioctl_with_mut_ref, EventFd};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
 use virtio_gen::virtio_blk::*;
-use {DeviceEventT, EpollHandler};
+use {DeviceEventT, DeviceHealth, EpollHandler};
 
 const CONFIG_SPACE_SIZE: usize = 8;
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
+/// The maximum length of a block device serial, as it must fit in the virtio device ID.
+pub const MAX_SERIAL_LEN: usize = VIRTIO_BLK_ID_BYTES as usize;
 const QUEUE_SIZE: u16 = 256;
+/// The most requests a block device can have in flight, i.e. the size of its queue.
+pub const MAX_IN_FLIGHT_REQUESTS: u16 = QUEUE_SIZE;
 const NUM_QUEUES: usize = 1;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 
@@ -41,8 +48,21 @@ const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
 const RATE_LIMITER_EVENT: DeviceEventT = 1;
 // Backing file on the host has changed.
 pub const FS_UPDATE_EVENT: DeviceEventT = 2;
+// The writes to the backing file have to be persisted on the host.
+pub const FLUSH_EVENT: DeviceEventT = 3;
+// The request at the head of the queue is due to be retried.
+const IO_RETRY_EVENT: DeviceEventT = 4;
 // Number of DeviceEventT events supported by this implementation.
-pub const BLOCK_EVENTS_COUNT: usize = 3;
+pub const BLOCK_EVENTS_COUNT: usize = 5;
+
+// See include/uapi/linux/memfd.h in the kernel code.
+const MFD_CLOEXEC: libc::c_uint = 0x0001;
+const RAMDISK_MEMFD_NAME: &[u8] = b"ramdisk\0";
+
+// Returns the logical block size of a block device.
+ioctl_io_nr!(BLKSSZGET, 0x12, 104);
+// Returns the size in bytes of a block device.
+ioctl_ior_nr!(BLKGETSIZE64, 0x12, 114, u64);
 
 #[derive(Debug)]
 enum Error {
@@ -67,6 +87,7 @@ enum Error {
 #[derive(Debug)]
 enum ExecuteError {
     BadRequest(Error),
+    BounceBuffer(io::Error),
     Flush(io::Error),
     Read(GuestMemoryError),
     Seek(io::Error),
@@ -78,6 +99,7 @@ impl ExecuteError {
     fn status(&self) -> u32 {
         match *self {
             ExecuteError::BadRequest(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::BounceBuffer(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
@@ -85,6 +107,125 @@ impl ExecuteError {
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
         }
     }
+
+    /// Returns the error reported by the backing file, if it caused the request to fail.
+    fn disk_error(&self) -> Option<&io::Error> {
+        match *self {
+            ExecuteError::BounceBuffer(ref e)
+            | ExecuteError::Flush(ref e)
+            | ExecuteError::Seek(ref e) => Some(e),
+            ExecuteError::Read(GuestMemoryError::MemoryAccess(
+                _,
+                MemoryMappingError::ReadFromSource(ref e),
+            ))
+            | ExecuteError::Write(GuestMemoryError::MemoryAccess(
+                _,
+                MemoryMappingError::ReadFromSource(ref e),
+            )) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the backing file failed with an error which may go away on its own,
+    /// so the request is worth retrying.
+    fn is_transient(&self) -> bool {
+        match self.disk_error().and_then(io::Error::raw_os_error) {
+            Some(libc::EAGAIN) | Some(libc::EINTR) | Some(libc::ETIMEDOUT) => true,
+            _ => false,
+        }
+    }
+}
+
+/// How the requests failing with a transient error of the backing file, i.e. `EAGAIN`, `EINTR`
+/// or `ETIMEDOUT`, are retried before the error is reported to the guest. The device serves no
+/// other request while it waits to retry, but the event loop keeps running.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoRetryPolicy {
+    /// The number of times a request is attempted, including the first attempt.
+    pub max_attempts: u32,
+    /// The delay before the first retry, doubled for each following retry.
+    pub backoff: Duration,
+}
+
+impl Default for IoRetryPolicy {
+    fn default() -> Self {
+        IoRetryPolicy {
+            max_attempts: 1,
+            backoff: Duration::from_millis(0),
+        }
+    }
+}
+
+/// Schedules the retries of the request at the head of the queue of a block device, following
+/// an `IoRetryPolicy`. The device waits for a retry on a timer, which is registered in the
+/// event loop, so that the other devices are served in the meantime.
+pub struct IoRetry {
+    policy: IoRetryPolicy,
+    failed_attempts: u32,
+    timer_fd: TimerFd,
+    timer_armed: bool,
+}
+
+impl IoRetry {
+    /// Creates a new retry state following `policy`.
+    ///
+    /// # Errors
+    ///
+    /// If the timerfd creation fails, an error is returned.
+    pub fn new(policy: IoRetryPolicy) -> io::Result<Self> {
+        // The timer is non-blocking, so that a stale expiration can be read without
+        // stalling the event loop.
+        let timer_fd = TimerFd::new_custom(ClockId::Monotonic, true, true)?;
+        Ok(IoRetry {
+            policy,
+            failed_attempts: 0,
+            timer_fd,
+            timer_armed: false,
+        })
+    }
+
+    /// Records a failed attempt at the request at the head of the queue. If the failure is
+    /// `transient` and the request has attempts left, the timer is armed for the retry and
+    /// `true` is returned. Otherwise, the request has failed for good.
+    pub fn schedule_retry(&mut self, transient: bool) -> bool {
+        self.failed_attempts += 1;
+        if !transient || self.failed_attempts >= self.policy.max_attempts {
+            self.failed_attempts = 0;
+            return false;
+        }
+        let backoff = self.policy.backoff * (1 << (self.failed_attempts - 1));
+        // A zero delay would disarm the timer, so the retry is due on the next pass of the
+        // event loop instead.
+        self.timer_fd.set_state(
+            TimerState::Oneshot(cmp::max(backoff, Duration::from_nanos(1))),
+            SetTimeFlags::Default,
+        );
+        self.timer_armed = true;
+        true
+    }
+
+    /// Returns whether a retry is pending, in which case the queue is left alone until the
+    /// timer expires.
+    pub fn is_pending(&self) -> bool {
+        self.timer_armed
+    }
+
+    /// Consumes the expiration of the timer, after which the retry is due.
+    pub fn expire(&mut self) {
+        self.timer_fd.read();
+        self.timer_armed = false;
+    }
+
+    // Records that the request at the head of the queue completed.
+    fn reset(&mut self) {
+        self.failed_attempts = 0;
+    }
+}
+
+impl AsRawFd for IoRetry {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_fd.as_raw_fd()
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -119,6 +260,69 @@ fn sector(mem: &GuestMemory, desc_addr: GuestAddress) -> result::Result<u64, Err
     mem.read_obj_from_addr(addr).map_err(Error::GuestMemory)
 }
 
+// Returns the size of the disk in bytes. Host block devices report a length of 0 in their
+// metadata, so their size is queried from the kernel instead.
+fn get_disk_size(disk_image: &mut File) -> io::Result<u64> {
+    if disk_image.metadata()?.file_type().is_block_device() {
+        let mut size: u64 = 0;
+        // This is safe because the kernel only writes the device size into `size`,
+        // and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(disk_image, BLKGETSIZE64(), &mut size) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(size)
+    } else {
+        disk_image.seek(SeekFrom::End(0))
+    }
+}
+
+// Returns the alignment that O_DIRECT requires for the file offset, length and memory buffer of
+// every transfer on the disk.
+fn get_direct_io_block_size(disk_image: &File) -> io::Result<u64> {
+    let metadata = disk_image.metadata()?;
+    if metadata.file_type().is_block_device() {
+        let mut block_size: libc::c_int = 0;
+        // This is safe because the kernel only writes the logical block size into `block_size`,
+        // and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(disk_image, BLKSSZGET(), &mut block_size) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(block_size as u64)
+    } else {
+        // The file system block size is a multiple of the logical block size of the device
+        // backing the file.
+        Ok(metadata.st_blksize())
+    }
+}
+
+// A heap buffer starting at an address aligned to the O_DIRECT block size.
+struct AlignedBuf {
+    buf: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize, align: usize) -> Self {
+        let buf = vec![0u8; len + align];
+        let offset = match buf.as_ptr() as usize % align {
+            0 => 0,
+            rem => align - rem,
+        };
+        AlignedBuf { buf, offset, len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buf[self.offset..self.offset + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.offset..self.offset + self.len]
+    }
+}
+
 fn build_device_id(disk_image: &File) -> result::Result<String, Error> {
     let blk_metadata = match disk_image.metadata() {
         Err(_) => return Err(Error::GetFileMetadata),
@@ -135,9 +339,13 @@ fn build_device_id(disk_image: &File) -> result::Result<String, Error> {
     Ok(device_id)
 }
 
-fn build_disk_image_id(disk_image: &File) -> Vec<u8> {
+fn build_disk_image_id(disk_image: &File, serial: Option<&String>) -> Vec<u8> {
     let mut default_disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
-    match build_device_id(disk_image) {
+    let device_id = match serial {
+        Some(serial) => Ok(serial.clone()),
+        None => build_device_id(disk_image),
+    };
+    match device_id {
         Err(_) => {
             warn!("Could not generate device id. We'll use a default.");
         }
@@ -221,11 +429,47 @@ impl Request {
         Ok(req)
     }
 
+    // Checks whether the data transfer of this request can be done directly on a disk opened
+    // with O_DIRECT, without going through an aligned bounce buffer.
+    fn is_direct_io_aligned(&self, mem: &GuestMemory, block_size: u64) -> bool {
+        let host_addr = match mem.get_host_address(self.data_addr) {
+            Ok(addr) => addr as u64,
+            Err(_) => return false,
+        };
+        (self.sector << SECTOR_SHIFT) % block_size == 0
+            && u64::from(self.data_len) % block_size == 0
+            && host_addr % block_size == 0
+    }
+
+    // Reads the blocks covering the data of this request into an aligned buffer. Returns the
+    // buffer, along with the offset of the data inside it.
+    fn read_bounce_buffer<T: Seek + Read>(
+        &self,
+        disk: &mut T,
+        block_size: u64,
+    ) -> result::Result<(AlignedBuf, usize), ExecuteError> {
+        let offset = self.sector << SECTOR_SHIFT;
+        let start = offset - offset % block_size;
+        let end = offset + u64::from(self.data_len);
+        let end = match end % block_size {
+            0 => end,
+            rem => end + block_size - rem,
+        };
+        let mut buf = AlignedBuf::new((end - start) as usize, block_size as usize);
+
+        disk.seek(SeekFrom::Start(start))
+            .map_err(ExecuteError::Seek)?;
+        disk.read_exact(buf.as_mut_slice())
+            .map_err(ExecuteError::BounceBuffer)?;
+        Ok((buf, (offset - start) as usize))
+    }
+
     #[allow(clippy::ptr_arg)]
     fn execute<T: Seek + Read + Write>(
         &self,
         disk: &mut T,
         disk_nsectors: u64,
+        direct_io_block_size: Option<u64>,
         mem: &GuestMemory,
         disk_id: &Vec<u8>,
     ) -> result::Result<u32, ExecuteError> {
@@ -243,6 +487,44 @@ impl Request {
         disk.seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
             .map_err(ExecuteError::Seek)?;
 
+        // Transfers which don't meet the alignment constraints of O_DIRECT go through an aligned
+        // bounce buffer, covering all the blocks touched by the request.
+        let bounce_block_size = match direct_io_block_size {
+            Some(block_size) if !self.is_direct_io_aligned(mem, block_size) => Some(block_size),
+            _ => None,
+        };
+        if let Some(block_size) = bounce_block_size {
+            match self.request_type {
+                RequestType::In => {
+                    let (buf, offset) = self.read_bounce_buffer(disk, block_size)?;
+                    mem.write_slice_at_addr(
+                        &buf.as_slice()[offset..offset + self.data_len as usize],
+                        self.data_addr,
+                    )
+                    .map_err(ExecuteError::Write)?;
+                    METRICS.block.read_count.add(self.data_len as usize);
+                    return Ok(self.data_len);
+                }
+                RequestType::Out => {
+                    let (mut buf, offset) = self.read_bounce_buffer(disk, block_size)?;
+                    mem.read_slice_at_addr(
+                        &mut buf.as_mut_slice()[offset..offset + self.data_len as usize],
+                        self.data_addr,
+                    )
+                    .map_err(ExecuteError::Read)?;
+                    disk.seek(SeekFrom::Start(
+                        (self.sector << SECTOR_SHIFT) - offset as u64,
+                    ))
+                    .map_err(ExecuteError::Seek)?;
+                    disk.write_all(buf.as_slice())
+                        .map_err(ExecuteError::BounceBuffer)?;
+                    METRICS.block.write_count.add(self.data_len as usize);
+                    return Ok(0);
+                }
+                _ => (),
+            }
+        }
+
         match self.request_type {
             RequestType::In => {
                 mem.read_to_memory(self.data_addr, disk, self.data_len as usize)
@@ -280,21 +562,35 @@ struct BlockEpollHandler {
     mem: GuestMemory,
     disk_image: File,
     disk_nsectors: u64,
+    direct_io_block_size: Option<u64>,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
     queue_evt: EventFd,
     rate_limiter: RateLimiter,
     disk_image_id: Vec<u8>,
+    serial: Option<String>,
+    health: DeviceHealth,
+    io_retry: Option<IoRetry>,
+    max_in_flight: usize,
 }
 
 impl BlockEpollHandler {
+    // The requests taken from the queue are in flight until they are added to the used ring.
+    // Once `max_in_flight` of them are, the rest of the queue is deferred to the next pass of
+    // the event loop.
     fn process_queue(&mut self, queue_index: usize) -> bool {
         let queue = &mut self.queues[queue_index];
         let mut rate_limited = false;
+        let mut backpressure = false;
+        let mut retry_pending = false;
 
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
         for avail_desc in queue.iter(&self.mem) {
+            if used_count == self.max_in_flight {
+                backpressure = true;
+                break;
+            }
             let len;
             match Request::parse(&avail_desc, &self.mem) {
                 Ok(request) => {
@@ -322,12 +618,38 @@ impl BlockEpollHandler {
                             break;
                         }
                     }
-                    let status = match request.execute(
+                    let result = request.execute(
                         &mut self.disk_image,
                         self.disk_nsectors,
+                        self.direct_io_block_size,
                         &self.mem,
                         &self.disk_image_id,
-                    ) {
+                    );
+                    match (&result, &mut self.io_retry) {
+                        (&Err(ref e), &mut Some(ref mut io_retry)) => {
+                            if io_retry.schedule_retry(e.is_transient()) {
+                                warn!("Retrying request after a transient error: {:?}", e);
+                                METRICS.block.io_retries.inc();
+                                retry_pending = true;
+                            }
+                        }
+                        (&Ok(_), &mut Some(ref mut io_retry)) => io_retry.reset(),
+                        _ => (),
+                    }
+                    if retry_pending {
+                        // The request is taken again once the retry is due, so its tokens are
+                        // given back.
+                        self.rate_limiter.manual_replenish(1, TokenType::Ops);
+                        if request.request_type == RequestType::In
+                            || request.request_type == RequestType::Out
+                        {
+                            self.rate_limiter
+                                .manual_replenish(u64::from(request.data_len), TokenType::Bytes);
+                        }
+                        // Stop processing the queue.
+                        break;
+                    }
+                    let status = match result {
                         Ok(l) => {
                             len = l;
                             VIRTIO_BLK_S_OK
@@ -335,6 +657,17 @@ impl BlockEpollHandler {
                         Err(e) => {
                             error!("Failed to execute request: {:?}", e);
                             METRICS.block.invalid_reqs_count.inc();
+                            if let Some(disk_err) = e.disk_error() {
+                                // A failed read leaves the device usable, while a failed write
+                                // or flush means the guest data can no longer be persisted.
+                                match (request.request_type, &self.health) {
+                                    (RequestType::In, &DeviceHealth::Failed(_)) => (),
+                                    (RequestType::In, _) => {
+                                        self.health = DeviceHealth::Degraded(disk_err.to_string())
+                                    }
+                                    _ => self.health = DeviceHealth::Failed(disk_err.to_string()),
+                                }
+                            }
                             len = 1; // We need at least 1 byte for the status.
                             e.status()
                         }
@@ -354,11 +687,19 @@ impl BlockEpollHandler {
             used_desc_heads[used_count] = (avail_desc.index, len);
             used_count += 1;
         }
-        if rate_limited {
-            // If rate limiting kicked in, queue had advanced one element that we aborted
-            // processing; go back one element so it can be processed next time.
+        if rate_limited || backpressure || retry_pending {
+            // If rate limiting, backpressure or a retry kicked in, queue had advanced one element
+            // that we aborted processing; go back one element so it can be processed next time.
             queue.go_to_previous_position();
         }
+        if backpressure {
+            METRICS.block.in_flight_backpressure_count.inc();
+            // The guest doesn't kick the queue again for the requests it already queued.
+            if let Err(e) = self.queue_evt.write(1) {
+                error!("Failed to defer the processing of the queue: {:?}", e);
+                METRICS.block.event_fails.inc();
+            }
+        }
 
         for &(desc_index, len) in &used_desc_heads[..used_count] {
             queue.add_used(&self.mem, desc_index, len);
@@ -378,15 +719,31 @@ impl BlockEpollHandler {
 
     fn update_disk_image(&mut self, disk_image: File) -> result::Result<(), DeviceError> {
         self.disk_image = disk_image;
-        self.disk_nsectors = self
-            .disk_image
-            .seek(SeekFrom::End(0))
-            .map_err(DeviceError::IoError)?
-            / SECTOR_SIZE;
-        self.disk_image_id = build_disk_image_id(&self.disk_image);
+        let disk_size = get_disk_size(&mut self.disk_image).map_err(DeviceError::IoError)?;
+        self.disk_nsectors = disk_size / SECTOR_SIZE;
+        if self.direct_io_block_size.is_some() {
+            self.direct_io_block_size =
+                Some(get_direct_io_block_size(&self.disk_image).map_err(DeviceError::IoError)?);
+        }
+        self.disk_image_id = build_disk_image_id(&self.disk_image, self.serial.as_ref());
+        // The errors seen so far belong to the previous backing file.
+        self.health = DeviceHealth::Healthy;
         METRICS.block.update_count.inc();
         Ok(())
     }
+
+    fn is_retry_pending(&self) -> bool {
+        self.io_retry.as_ref().map_or(false, IoRetry::is_pending)
+    }
+
+    // Writes back the data of the backing file which only reached the host page cache.
+    fn flush_disk_image(&mut self) -> result::Result<(), DeviceError> {
+        METRICS.block.flush_count.inc();
+        self.disk_image.sync_data().map_err(|e| {
+            error!("Failed to flush the backing file: {:?}", e);
+            DeviceError::IoError(e)
+        })
+    }
 }
 
 impl EpollHandler for BlockEpollHandler {
@@ -406,10 +763,14 @@ impl EpollHandler for BlockEpollHandler {
                         event_type: "queue event",
                         underlying: e,
                     })
-                } else if !self.rate_limiter.is_blocked() && self.process_queue(0) {
+                } else if !self.rate_limiter.is_blocked()
+                    && !self.is_retry_pending()
+                    && self.process_queue(0)
+                {
                     self.signal_used_queue()
                 } else {
-                    // While limiter is blocked, don't process any more requests.
+                    // While limiter is blocked or a retry is pending, don't process any more
+                    // requests.
                     Ok(())
                 }
             }
@@ -417,7 +778,21 @@ impl EpollHandler for BlockEpollHandler {
                 METRICS.block.rate_limiter_event_count.inc();
                 // Upon rate limiter event, call the rate limiter handler
                 // and restart processing the queue.
-                if self.rate_limiter.event_handler().is_ok() && self.process_queue(0) {
+                if self.rate_limiter.event_handler().is_ok()
+                    && !self.is_retry_pending()
+                    && self.process_queue(0)
+                {
+                    self.signal_used_queue()
+                } else {
+                    Ok(())
+                }
+            }
+            IO_RETRY_EVENT => {
+                if let Some(ref mut io_retry) = self.io_retry {
+                    io_retry.expire();
+                }
+                // The queue resumes with the request to retry.
+                if !self.rate_limiter.is_blocked() && self.process_queue(0) {
                     self.signal_used_queue()
                 } else {
                     Ok(())
@@ -430,17 +805,23 @@ impl EpollHandler for BlockEpollHandler {
                     Err(DeviceError::PayloadExpected)
                 }
             }
+            FLUSH_EVENT => self.flush_disk_image(),
             unknown => Err(DeviceError::UnknownEvent {
                 device: "block",
                 event: unknown,
             }),
         }
     }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
 }
 
 pub struct EpollConfig {
     q_avail_token: u64,
     rate_limiter_token: u64,
+    io_retry_token: u64,
     epoll_raw_fd: RawFd,
     sender: mpsc::Sender<Box<EpollHandler>>,
 }
@@ -454,6 +835,7 @@ impl EpollConfig {
         EpollConfig {
             q_avail_token: first_token + u64::from(QUEUE_AVAIL_EVENT),
             rate_limiter_token: first_token + u64::from(RATE_LIMITER_EVENT),
+            io_retry_token: first_token + u64::from(IO_RETRY_EVENT),
             epoll_raw_fd,
             sender,
         }
@@ -464,11 +846,15 @@ impl EpollConfig {
 pub struct Block {
     disk_image: Option<File>,
     disk_nsectors: u64,
+    direct_io_block_size: Option<u64>,
     avail_features: u64,
     acked_features: u64,
     config_space: Vec<u8>,
+    serial: Option<String>,
     epoll_config: EpollConfig,
     rate_limiter: Option<RateLimiter>,
+    io_retry: Option<IoRetry>,
+    max_in_flight: usize,
 }
 
 pub fn build_config_space(disk_size: u64) -> Vec<u8> {
@@ -486,14 +872,36 @@ pub fn build_config_space(disk_size: u64) -> Vec<u8> {
 impl Block {
     /// Create a new virtio block device that operates on the given file.
     ///
-    /// The given file must be seekable and sizable.
+    /// The given file must be either a host block device, or seekable and sizable.
+    /// If it was opened with O_DIRECT, `is_direct_io` must be set, so that the guest requests
+    /// are aligned to the constraints of direct I/O.
+    /// The `serial` is returned to the guest as the device ID, truncated to `VIRTIO_BLK_ID_BYTES`.
+    /// If it is missing, the device ID is derived from the metadata of the disk image.
     pub fn new(
         mut disk_image: File,
         is_disk_read_only: bool,
+        is_direct_io: bool,
+        serial: Option<String>,
         epoll_config: EpollConfig,
         rate_limiter: Option<RateLimiter>,
     ) -> io::Result<Block> {
-        let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
+        let disk_size = get_disk_size(&mut disk_image)?;
+        let direct_io_block_size = if is_direct_io {
+            let block_size = get_direct_io_block_size(&disk_image)?;
+            // The last block of the disk can only be accessed if it is whole.
+            if disk_size % block_size != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Disk size {} is not a multiple of the direct I/O block size {}",
+                        disk_size, block_size
+                    ),
+                ));
+            }
+            Some(block_size)
+        } else {
+            None
+        };
         if disk_size % SECTOR_SIZE != 0 {
             warn!(
                 "Disk size {} is not a multiple of sector size {}; \
@@ -511,13 +919,61 @@ impl Block {
         Ok(Block {
             disk_image: Some(disk_image),
             disk_nsectors: disk_size / SECTOR_SIZE,
+            direct_io_block_size,
             avail_features,
             acked_features: 0u64,
             config_space: build_config_space(disk_size),
+            serial,
             epoll_config,
             rate_limiter,
+            io_retry: None,
+            max_in_flight: MAX_IN_FLIGHT_REQUESTS as usize,
         })
     }
+
+    /// Sets how the requests failing with a transient error of the backing file are retried.
+    /// By default, they are not.
+    ///
+    /// # Errors
+    ///
+    /// If the timer of the retries cannot be created, an error is returned.
+    pub fn set_io_retry_policy(&mut self, policy: IoRetryPolicy) -> io::Result<()> {
+        self.io_retry = Some(IoRetry::new(policy)?);
+        Ok(())
+    }
+
+    /// Sets how many requests the device takes from its queue before completing them, between 1
+    /// and `MAX_IN_FLIGHT_REQUESTS`. The requests the guest queued past this limit wait for the
+    /// next pass of the event loop. By default, the device takes the whole queue.
+    pub fn set_max_in_flight_requests(&mut self, max_in_flight: u16) {
+        self.max_in_flight = usize::from(max_in_flight.max(1).min(MAX_IN_FLIGHT_REQUESTS));
+    }
+
+    /// Create a new virtio block device backed by `size` bytes of anonymous host memory instead
+    /// of a file. The disk starts zeroed, and its contents are discarded along with the device.
+    /// The memory is a memfd, which the device uses as it would use a disk image.
+    pub fn new_ramdisk(
+        size: u64,
+        serial: Option<String>,
+        epoll_config: EpollConfig,
+        rate_limiter: Option<RateLimiter>,
+    ) -> io::Result<Block> {
+        // This is safe because we pass a valid null-terminated name and check the return value.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_memfd_create,
+                RAMDISK_MEMFD_NAME.as_ptr() as *const libc::c_char,
+                MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // This is safe because we own the file descriptor we have just created.
+        let disk_image = unsafe { File::from_raw_fd(fd as RawFd) };
+        disk_image.set_len(size)?;
+        Block::new(disk_image, false, false, serial, epoll_config, rate_limiter)
+    }
 }
 
 impl VirtioDevice for Block {
@@ -611,19 +1067,25 @@ impl VirtioDevice for Block {
             let queue_evt = queue_evts.remove(0);
             let queue_evt_raw_fd = queue_evt.as_raw_fd();
 
-            let disk_image_id = build_disk_image_id(&disk_image);
+            let disk_image_id = build_disk_image_id(&disk_image, self.serial.as_ref());
             let handler = BlockEpollHandler {
                 queues,
                 mem,
                 disk_image,
                 disk_nsectors: self.disk_nsectors,
+                direct_io_block_size: self.direct_io_block_size,
                 interrupt_status: status,
                 interrupt_evt,
                 queue_evt,
                 rate_limiter: self.rate_limiter.take().unwrap_or_default(),
                 disk_image_id,
+                serial: self.serial.clone(),
+                health: DeviceHealth::Healthy,
+                io_retry: self.io_retry.take(),
+                max_in_flight: self.max_in_flight,
             };
             let rate_limiter_rawfd = handler.rate_limiter.as_raw_fd();
+            let io_retry_rawfd = handler.io_retry.as_ref().map(IoRetry::as_raw_fd);
 
             // The channel should be open at this point.
             self.epoll_config
@@ -656,6 +1118,19 @@ impl VirtioDevice for Block {
                 })?;
             }
 
+            if let Some(io_retry_rawfd) = io_retry_rawfd {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    io_retry_rawfd,
+                    epoll::Event::new(epoll::Events::EPOLLIN, self.epoll_config.io_retry_token),
+                )
+                .map_err(|e| {
+                    METRICS.block.activate_fails.inc();
+                    ActivateError::EpollCtl(e)
+                })?;
+            }
+
             return Ok(());
         }
         METRICS.block.activate_fails.inc();
@@ -672,6 +1147,8 @@ mod tests {
 
     use libc;
     use std::fs::{metadata, OpenOptions};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::FromRawFd;
     use std::sync::mpsc::Receiver;
     use std::thread;
     use std::time::Duration;
@@ -721,7 +1198,15 @@ mod tests {
             // Rate limiting is enabled but with a high operation rate (10 million ops/s).
             let rate_limiter = RateLimiter::new(0, None, 0, 100_000, None, 10).unwrap();
             DummyBlock {
-                block: Block::new(f, is_disk_read_only, epoll_config, Some(rate_limiter)).unwrap(),
+                block: Block::new(
+                    f,
+                    is_disk_read_only,
+                    false,
+                    None,
+                    epoll_config,
+                    Some(rate_limiter),
+                )
+                .unwrap(),
                 epoll_raw_fd,
                 _receiver,
             }
@@ -766,11 +1251,16 @@ mod tests {
                 mem: mem.clone(),
                 disk_image,
                 disk_nsectors,
+                direct_io_block_size: None,
                 interrupt_status: status,
                 interrupt_evt,
                 queue_evt,
                 rate_limiter: RateLimiter::default(),
                 disk_image_id,
+                serial: None,
+                health: DeviceHealth::Healthy,
+                io_retry: None,
+                max_in_flight: MAX_IN_FLIGHT_REQUESTS as usize,
             },
             vq,
         )
@@ -815,6 +1305,316 @@ mod tests {
         assert_eq!(h.interrupt_evt.read().unwrap(), 2);
     }
 
+    // Loop device control ioctls, used to expose a file as a host block device.
+    ioctl_io_nr!(LOOP_SET_FD, 0x4C, 0x00);
+    ioctl_io_nr!(LOOP_CLR_FD, 0x4C, 0x01);
+    ioctl_io_nr!(LOOP_CTL_GET_FREE, 0x4C, 0x82);
+
+    #[test]
+    fn test_block_device_capacity() {
+        const DISK_SIZE: u64 = 0x10_0000;
+
+        // This test needs a free loop device, so it is skipped when none can be set up.
+        let loop_control = match OpenOptions::new().read(true).open("/dev/loop-control") {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+        let loop_nr = unsafe { sys_util::ioctl(&loop_control, LOOP_CTL_GET_FREE()) };
+        if loop_nr < 0 {
+            return;
+        }
+        let loop_path = format!("/dev/loop{}", loop_nr);
+        let backing_file = tempfile().unwrap();
+        backing_file.set_len(DISK_SIZE).unwrap();
+        let loop_dev = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&loop_path)
+            .unwrap();
+        let ret = unsafe {
+            sys_util::ioctl_with_val(
+                &loop_dev,
+                LOOP_SET_FD(),
+                backing_file.as_raw_fd() as libc::c_ulong,
+            )
+        };
+        if ret < 0 {
+            return;
+        }
+
+        let disk_image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(&loop_path)
+            .unwrap();
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender);
+        let block = Block::new(disk_image, false, true, None, epoll_config, None).unwrap();
+
+        assert_eq!(block.disk_nsectors, DISK_SIZE / SECTOR_SIZE);
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(capacity, build_config_space(DISK_SIZE)[..]);
+
+        drop(block);
+        unsafe {
+            sys_util::ioctl(&loop_dev, LOOP_CLR_FD());
+            libc::close(epoll_raw_fd);
+        }
+    }
+
+    #[test]
+    fn test_io_retry() {
+        for &errno in &[libc::EAGAIN, libc::EINTR, libc::ETIMEDOUT] {
+            assert!(ExecuteError::Seek(io::Error::from_raw_os_error(errno)).is_transient());
+        }
+        for &errno in &[libc::ENOSPC, libc::EROFS] {
+            assert!(!ExecuteError::Flush(io::Error::from_raw_os_error(errno)).is_transient());
+        }
+
+        let mut io_retry = IoRetry::new(IoRetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(1),
+        })
+        .unwrap();
+        assert!(!io_retry.is_pending());
+
+        // The request is retried after 1ms, then after 2ms.
+        for &backoff_ms in &[1, 2] {
+            assert!(io_retry.schedule_retry(true));
+            assert!(io_retry.is_pending());
+            match io_retry.timer_fd.get_state() {
+                TimerState::Oneshot(d) => assert!(d <= Duration::from_millis(backoff_ms)),
+                _ => panic!("The retry timer should be armed."),
+            }
+            thread::sleep(Duration::from_millis(backoff_ms));
+            io_retry.expire();
+            assert!(!io_retry.is_pending());
+        }
+        // The request fails once it runs out of attempts.
+        assert!(!io_retry.schedule_retry(true));
+        assert!(!io_retry.is_pending());
+        // The permanent errors are reported right away.
+        assert!(!io_retry.schedule_retry(false));
+        assert_eq!(io_retry.timer_fd.get_state(), TimerState::Disarmed);
+
+        // The requests aren't retried by default.
+        let mut io_retry = IoRetry::new(IoRetryPolicy::default()).unwrap();
+        assert!(!io_retry.schedule_retry(true));
+    }
+
+    #[test]
+    fn test_io_retry_event() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+
+        // The guest queues a flush request, made of a header and a status descriptor.
+        vq.avail.ring[0].set(0);
+        vq.dtable[0].set(0x1000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x1100, 1, VIRTQ_DESC_F_WRITE, 0);
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH, GuestAddress(0x1000))
+            .unwrap();
+        vq.avail.idx.set(1);
+
+        // The request at the head of the queue failed with a transient error.
+        let mut io_retry = IoRetry::new(IoRetryPolicy {
+            max_attempts: 2,
+            backoff: Duration::from_millis(1),
+        })
+        .unwrap();
+        assert!(io_retry.schedule_retry(true));
+        h.io_retry = Some(io_retry);
+
+        // The queue is left alone until the retry is due.
+        h.queue_evt.write(1).unwrap();
+        h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(vq.used.idx.get(), 0);
+        assert!(h.interrupt_evt.read().is_err());
+
+        thread::sleep(Duration::from_millis(1));
+        h.handle_event(IO_RETRY_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert!(!h.is_retry_pending());
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x1100)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+    }
+
+    #[test]
+    fn test_direct_io_alignment() {
+        const DISK_SIZE: usize = 0x4000;
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        let mut disk_file = NamedTempFile::new().unwrap();
+        let disk_data: Vec<u8> = (0..DISK_SIZE).map(|i| (i % 251) as u8).collect();
+        disk_file.write_all(&disk_data).unwrap();
+        let mut disk = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(disk_file.path())
+        {
+            Ok(disk) => disk,
+            // Some filesystems, such as tmpfs, don't support O_DIRECT.
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => panic!("Cannot open the disk with O_DIRECT: {}", e),
+        };
+        let block_size = get_direct_io_block_size(&disk).unwrap();
+        let disk_nsectors = DISK_SIZE as u64 / SECTOR_SIZE;
+        let disk_id = vec![0u8; VIRTIO_BLK_ID_BYTES as usize];
+
+        let mut request = Request {
+            request_type: RequestType::In,
+            sector: 0,
+            data_addr: GuestAddress(0),
+            data_len: 0,
+            status_addr: GuestAddress(0),
+        };
+
+        // Aligned read, done directly into guest memory.
+        request.sector = block_size / SECTOR_SIZE;
+        request.data_addr = GuestAddress(0x2000);
+        request.data_len = block_size as u32;
+        assert!(request.is_direct_io_aligned(&mem, block_size));
+        assert_eq!(
+            request
+                .execute(&mut disk, disk_nsectors, Some(block_size), &mem, &disk_id)
+                .unwrap(),
+            block_size as u32
+        );
+        let mut buf = vec![0u8; block_size as usize];
+        mem.read_slice_at_addr(&mut buf, request.data_addr).unwrap();
+        assert_eq!(
+            buf[..],
+            disk_data[block_size as usize..2 * block_size as usize]
+        );
+
+        // Misaligned offset, length and guest buffer, read through the bounce buffer.
+        request.sector = 1;
+        request.data_addr = GuestAddress(0x8001);
+        request.data_len = 0x600;
+        assert!(!request.is_direct_io_aligned(&mem, block_size));
+        assert_eq!(
+            request
+                .execute(&mut disk, disk_nsectors, Some(block_size), &mem, &disk_id)
+                .unwrap(),
+            0x600
+        );
+        let mut buf = vec![0u8; 0x600];
+        mem.read_slice_at_addr(&mut buf, request.data_addr).unwrap();
+        assert_eq!(buf[..], disk_data[0x200..0x800]);
+
+        // Misaligned write, which must leave the rest of the touched blocks unchanged.
+        let write_data = vec![0xaau8; 0x200];
+        mem.write_slice_at_addr(&write_data, GuestAddress(0x9003))
+            .unwrap();
+        request.request_type = RequestType::Out;
+        request.sector = 3;
+        request.data_addr = GuestAddress(0x9003);
+        request.data_len = 0x200;
+        request
+            .execute(&mut disk, disk_nsectors, Some(block_size), &mem, &disk_id)
+            .unwrap();
+        let mut expected_data = disk_data.clone();
+        expected_data[0x600..0x800].copy_from_slice(&write_data);
+        let mut written_data = Vec::new();
+        disk_file.seek(SeekFrom::Start(0)).unwrap();
+        disk_file.read_to_end(&mut written_data).unwrap();
+        assert_eq!(written_data, expected_data);
+    }
+
+    #[test]
+    fn test_ramdisk() {
+        const DISK_SIZE: u64 = 1 << 20;
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender);
+        let mut block = Block::new_ramdisk(DISK_SIZE, None, epoll_config, None).unwrap();
+        assert_eq!(block.features(0) & (1 << VIRTIO_BLK_F_RO), 0);
+        let mut num_sectors = [0u8; 8];
+        block.read_config(0, &mut num_sectors);
+        assert_eq!(u64::from_le_bytes(num_sectors), DISK_SIZE / SECTOR_SIZE);
+
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let queue_evt = EventFd::new().unwrap();
+        block
+            .activate(
+                m.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                vec![vq.create_queue()],
+                vec![queue_evt.try_clone().unwrap()],
+            )
+            .unwrap();
+        let mut h = receiver.recv().unwrap();
+
+        // Write a pattern to the last sector, then read it back in another buffer.
+        let pattern: Vec<u8> = (0..SECTOR_SIZE).map(|i| (i % 251) as u8).collect();
+        let sector = DISK_SIZE / SECTOR_SIZE - 1;
+        for (i, &request_type) in [VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_IN].iter().enumerate() {
+            let header_addr = 0x1000 + 0x3000 * i;
+            let data_addr = header_addr + 0x1000;
+            let status_addr = header_addr + 0x2000;
+            let data_flags = if request_type == VIRTIO_BLK_T_IN {
+                VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE
+            } else {
+                VIRTQ_DESC_F_NEXT
+            };
+            let desc_index = 3 * i as u16;
+            vq.dtable[desc_index as usize].set(
+                header_addr as u64,
+                0x1000,
+                VIRTQ_DESC_F_NEXT,
+                desc_index + 1,
+            );
+            vq.dtable[desc_index as usize + 1].set(
+                data_addr as u64,
+                SECTOR_SIZE as u32,
+                data_flags,
+                desc_index + 2,
+            );
+            vq.dtable[desc_index as usize + 2].set(
+                status_addr as u64,
+                0x1000,
+                VIRTQ_DESC_F_WRITE,
+                0,
+            );
+            vq.avail.ring[i].set(desc_index);
+            vq.avail.idx.set(i as u16 + 1);
+            m.write_obj_at_addr::<u32>(request_type, GuestAddress(header_addr))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(sector, GuestAddress(header_addr + 8))
+                .unwrap();
+            if request_type == VIRTIO_BLK_T_OUT {
+                m.write_slice_at_addr(&pattern, GuestAddress(data_addr))
+                    .unwrap();
+            }
+
+            queue_evt.write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+
+            let status = m
+                .read_obj_from_addr::<u32>(GuestAddress(status_addr))
+                .unwrap();
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+        }
+        let mut buf = vec![0u8; SECTOR_SIZE as usize];
+        m.read_slice_at_addr(&mut buf, GuestAddress(0x5000))
+            .unwrap();
+        assert_eq!(buf, pattern);
+        assert_eq!(h.health(), DeviceHealth::Healthy);
+
+        unsafe { libc::close(epoll_raw_fd) };
+    }
+
     #[test]
     fn test_request_type() {
         let m = &GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
@@ -1127,6 +1927,195 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_flush_event() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _vq) = default_test_blockepollhandler(&m);
+
+        let f = NamedTempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let payload = EpollHandlerPayload::DrivePayload(f.reopen().unwrap());
+        h.handle_event(FS_UPDATE_EVENT, 0, payload).unwrap();
+
+        // The write only reaches the host page cache.
+        h.disk_image.seek(SeekFrom::Start(0x200)).unwrap();
+        h.disk_image.write_all(&[0xaa; 0x200]).unwrap();
+        check_metric_after_block!(
+            &METRICS.block.flush_count,
+            1,
+            h.handle_event(FLUSH_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap()
+        );
+
+        let mut data = vec![0u8; 0x1000];
+        f.reopen().unwrap().read_exact(&mut data).unwrap();
+        assert!(data[..0x200].iter().all(|&b| b == 0));
+        assert!(data[0x200..0x400].iter().all(|&b| b == 0xaa));
+        assert!(data[0x400..].iter().all(|&b| b == 0));
+
+        // Error case: the backing file cannot be synced, as it is a pipe.
+        let mut fds = [0; 2];
+        // Safe because we pass a valid array and check the result.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // Safe because the fds were just created, and nothing else owns them.
+        let (pipe_read, _pipe_write) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        h.disk_image = pipe_read;
+        match h.handle_event(FLUSH_EVENT, 0, EpollHandlerPayload::Empty) {
+            Err(DeviceError::IoError(e)) => assert_eq!(e.raw_os_error(), Some(libc::EINVAL)),
+            _ => panic!("Flushing a pipe should fail."),
+        }
+    }
+
+    #[test]
+    fn test_serial() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+
+        // The serial replaces the device ID derived from the disk image once it's configured.
+        h.serial = Some(String::from("custom-serial"));
+        let f = NamedTempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let payload = EpollHandlerPayload::DrivePayload(f.reopen().unwrap());
+        h.handle_event(FS_UPDATE_EVENT, 0, payload).unwrap();
+
+        vq.avail.ring[0].set(0);
+        vq.dtable[0].set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(
+            0x2000,
+            VIRTIO_BLK_ID_BYTES,
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            2,
+        );
+        vq.dtable[2].set(0x3000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.idx.set(1);
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_GET_ID, GuestAddress(0x1000))
+            .unwrap();
+
+        invoke_handler_for_queue_event(&mut h);
+
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+        let mut buf = [0; VIRTIO_BLK_ID_BYTES as usize];
+        m.read_slice_at_addr(&mut buf, GuestAddress(0x2000))
+            .unwrap();
+        assert_eq!(&buf[..13], b"custom-serial");
+        assert!(buf[13..].iter().all(|&b| b == 0));
+
+        // Longer serials are capped to the size of the device ID.
+        let id = build_disk_image_id(
+            f.as_file(),
+            Some(&String::from("a-serial-which-does-not-fit")),
+        );
+        assert_eq!(id.len(), MAX_SERIAL_LEN);
+        assert_eq!(&id[..], b"a-serial-which-does-");
+    }
+
+    #[test]
+    fn test_health() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        assert_eq!(h.health(), DeviceHealth::Healthy);
+
+        // Back the device by a file which fails every write with ENOSPC.
+        h.disk_image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/full")
+            .unwrap();
+
+        vq.avail.ring[0].set(0);
+        vq.dtable[0].set(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 0x1000, VIRTQ_DESC_F_NEXT, 2);
+        vq.dtable[2].set(0x3000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.idx.set(1);
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+            .unwrap();
+        m.write_obj_at_addr::<u64>(0, GuestAddress(0x1000 + 8))
+            .unwrap();
+
+        invoke_handler_for_queue_event(&mut h);
+
+        assert_eq!(
+            m.read_obj_from_addr::<u32>(GuestAddress(0x3000)).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+        assert_eq!(
+            h.health(),
+            DeviceHealth::Failed(io::Error::from_raw_os_error(libc::ENOSPC).to_string())
+        );
+
+        // A new backing file brings the device back to a healthy state.
+        let f = NamedTempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let payload = EpollHandlerPayload::DrivePayload(f.reopen().unwrap());
+        h.handle_event(FS_UPDATE_EVENT, 0, payload).unwrap();
+        assert_eq!(h.health(), DeviceHealth::Healthy);
+    }
+
+    #[test]
+    fn test_max_in_flight_requests() {
+        let mut dummy = DummyBlock::new(false);
+        dummy.block().set_max_in_flight_requests(0);
+        assert_eq!(dummy.block().max_in_flight, 1);
+        dummy
+            .block()
+            .set_max_in_flight_requests(MAX_IN_FLIGHT_REQUESTS + 1);
+        assert_eq!(dummy.block().max_in_flight, MAX_IN_FLIGHT_REQUESTS as usize);
+
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        h.max_in_flight = 2;
+
+        // The guest queues 5 flush requests, made of a header and a status descriptor each.
+        for i in 0..5 {
+            let header = 2 * i;
+            let header_addr = 0x1000 * (i + 1);
+            vq.avail.ring[i].set(header as u16);
+            vq.dtable[header].set(
+                header_addr as u64,
+                0x10,
+                VIRTQ_DESC_F_NEXT,
+                (header + 1) as u16,
+            );
+            vq.dtable[header + 1].set((header_addr + 0x100) as u64, 1, VIRTQ_DESC_F_WRITE, 0);
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH, GuestAddress(header_addr))
+                .unwrap();
+        }
+        vq.avail.idx.set(5);
+
+        // Each pass takes at most 2 requests off the queue, and defers the others by kicking
+        // the queue again.
+        for &used in &[2, 4] {
+            check_metric_after_block!(
+                &METRICS.block.in_flight_backpressure_count,
+                1,
+                invoke_handler_for_queue_event(&mut h)
+            );
+            assert_eq!(vq.used.idx.get(), used);
+            assert_eq!(h.queue_evt.read().unwrap(), 1);
+        }
+        check_metric_after_block!(
+            &METRICS.block.in_flight_backpressure_count,
+            0,
+            invoke_handler_for_queue_event(&mut h)
+        );
+        assert_eq!(vq.used.idx.get(), 5);
+        // The queue isn't kicked again once it is empty.
+        assert!(h.queue_evt.read().is_err());
+
+        for i in 0..5 {
+            assert_eq!(vq.used.ring[i].get().id, 2 * i as u32);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(GuestAddress(0x1000 * (i + 1) + 0x100))
+                    .unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+    }
+
     #[test]
     #[allow(clippy::cyclomatic_complexity)]
     fn test_handler() {
@@ -0,0 +1,406 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a virtio console device, which the guest sees as `hvc0`.
+//!
+//! Only the output of the guest is supported: what the guest writes to the console is forwarded
+//! to the host, while the guest never receives any input.
+
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use epoll;
+
+use super::super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, EpollHandlerPayload, Queue, VirtioDevice, TYPE_CONSOLE,
+    VIRTIO_MMIO_INT_VRING,
+};
+use memory_model::GuestMemory;
+use sys_util::EventFd;
+use {DeviceEventT, EpollHandler};
+
+/// New descriptors are pending on the receive queue.
+pub const RX_QUEUE_EVENT: DeviceEventT = 0;
+/// New descriptors are pending on the transmit queue.
+pub const TX_QUEUE_EVENT: DeviceEventT = 1;
+/// Number of DeviceEventT events supported by this implementation.
+pub const CONSOLE_EVENTS_COUNT: usize = 2;
+
+const QUEUE_SIZE: u16 = 256;
+// The receive queue, followed by the transmit queue of the only port.
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+const TX_QUEUE: usize = 1;
+
+const VIRTIO_F_VERSION_1: u32 = 32;
+
+struct ConsoleEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemory,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+    queue_evts: Vec<EventFd>,
+    out: Box<io::Write + Send>,
+}
+
+impl ConsoleEpollHandler {
+    // Writes the buffers pending on the transmit queue to the output of the console.
+    fn process_tx(&mut self) -> bool {
+        let queue = &mut self.queues[TX_QUEUE];
+
+        let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        for avail_desc in queue.iter(&self.mem) {
+            let desc_index = avail_desc.index;
+            let mut next = Some(avail_desc);
+            while let Some(desc) = next {
+                if !desc.is_write_only() {
+                    if let Err(e) =
+                        self.mem
+                            .write_from_memory(desc.addr, &mut self.out, desc.len as usize)
+                    {
+                        error!("virtio-console: failed to write the output: {:?}", e);
+                    }
+                }
+                next = desc.next_descriptor();
+            }
+            used_desc_heads[used_count] = desc_index;
+            used_count += 1;
+        }
+        if let Err(e) = self.out.flush() {
+            error!("virtio-console: failed to flush the output: {:?}", e);
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            queue.add_used(&self.mem, desc_index, 0);
+        }
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).map_err(|e| {
+            error!("virtio-console: failed to signal used queue: {:?}", e);
+            DeviceError::FailedSignalingUsedQueue(e)
+        })
+    }
+}
+
+impl EpollHandler for ConsoleEpollHandler {
+    fn handle_event(
+        &mut self,
+        device_event: DeviceEventT,
+        _: u32,
+        _: EpollHandlerPayload,
+    ) -> result::Result<(), DeviceError> {
+        let queue_index = match device_event {
+            RX_QUEUE_EVENT => 0,
+            TX_QUEUE_EVENT => 1,
+            unknown => {
+                return Err(DeviceError::UnknownEvent {
+                    device: "console",
+                    event: unknown,
+                });
+            }
+        };
+        if let Err(e) = self.queue_evts[queue_index].read() {
+            error!("virtio-console: failed to get queue event: {:?}", e);
+            Err(DeviceError::FailedReadingQueue {
+                event_type: "queue event",
+                underlying: e,
+            })
+        } else if queue_index == TX_QUEUE && self.process_tx() {
+            self.signal_used_queue()
+        } else {
+            // The receive buffers stay available, as there is no input to fill them with.
+            Ok(())
+        }
+    }
+}
+
+pub struct EpollConfig {
+    rx_token: u64,
+    tx_token: u64,
+    epoll_raw_fd: RawFd,
+    sender: mpsc::Sender<Box<EpollHandler>>,
+}
+
+impl EpollConfig {
+    pub fn new(
+        first_token: u64,
+        epoll_raw_fd: RawFd,
+        sender: mpsc::Sender<Box<EpollHandler>>,
+    ) -> Self {
+        EpollConfig {
+            rx_token: first_token + u64::from(RX_QUEUE_EVENT),
+            tx_token: first_token + u64::from(TX_QUEUE_EVENT),
+            epoll_raw_fd,
+            sender,
+        }
+    }
+}
+
+/// Virtio device forwarding the console output of the guest to the host.
+pub struct Console {
+    out: Option<Box<io::Write + Send>>,
+    avail_features: u64,
+    acked_features: u64,
+    epoll_config: EpollConfig,
+}
+
+impl Console {
+    /// Creates a new virtio console device, whose output goes to `out`.
+    pub fn new(out: Box<io::Write + Send>, epoll_config: EpollConfig) -> Console {
+        Console {
+            out: Some(out),
+            avail_features: 1u64 << VIRTIO_F_VERSION_1,
+            acked_features: 0u64,
+            epoll_config,
+        }
+    }
+}
+
+impl VirtioDevice for Console {
+    fn device_type(&self) -> u32 {
+        TYPE_CONSOLE
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            // Get the lower 32-bits of the features bitfield.
+            0 => self.avail_features as u32,
+            // Get the upper 32-bits of the features bitfield.
+            1 => (self.avail_features >> 32) as u32,
+            _ => {
+                warn!("Received request for unknown features page.");
+                0u32
+            }
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let mut v = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => {
+                warn!("Cannot acknowledge unknown features page.");
+                0u64
+            }
+        };
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, _: u64, _: &mut [u8]) {
+        // The configuration space only holds the size of the console and the number of ports,
+        // which are only read when the matching features are offered.
+        warn!("virtio-console: the configuration space is empty");
+    }
+
+    fn write_config(&mut self, _: u64, _: &[u8]) {
+        warn!("virtio-console: the configuration space is empty");
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt_evt: EventFd,
+        status: Arc<AtomicUsize>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        if let Some(out) = self.out.take() {
+            let rx_raw_fd = queue_evts[0].as_raw_fd();
+            let tx_raw_fd = queue_evts[1].as_raw_fd();
+            let handler = ConsoleEpollHandler {
+                queues,
+                mem,
+                interrupt_status: status,
+                interrupt_evt,
+                queue_evts,
+                out,
+            };
+
+            // The channel should be open at this point.
+            self.epoll_config
+                .sender
+                .send(Box::new(handler))
+                .expect("Failed to send through the channel");
+
+            for &(fd, token) in [
+                (rx_raw_fd, self.epoll_config.rx_token),
+                (tx_raw_fd, self.epoll_config.tx_token),
+            ]
+            .iter()
+            {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    fd,
+                    epoll::Event::new(epoll::Events::EPOLLIN, token),
+                )
+                .map_err(ActivateError::EpollCtl)?;
+            }
+
+            return Ok(());
+        }
+        Err(ActivateError::BadActivate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use libc;
+    use memory_model::GuestAddress;
+    use virtio::queue::tests::*;
+    use virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    // An output shared with the test, which checks what the guest wrote.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_virtio_device() {
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let mut console = Console::new(
+            Box::new(SharedBuffer::default()),
+            EpollConfig::new(0, epoll_raw_fd, sender),
+        );
+
+        assert_eq!(console.device_type(), TYPE_CONSOLE);
+        assert_eq!(console.queue_max_sizes(), &[QUEUE_SIZE, QUEUE_SIZE]);
+        assert_eq!(console.features(0), 0);
+        assert_eq!(console.features(1), 1);
+        console.ack_features(1, 3);
+        assert_eq!(console.acked_features, 1u64 << VIRTIO_F_VERSION_1);
+
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &m, 16);
+        // Both the receive and the transmit queue are needed.
+        assert!(console
+            .activate(
+                m.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                vec![vq.create_queue()],
+                vec![EventFd::new().unwrap()],
+            )
+            .is_err());
+        assert!(console
+            .activate(
+                m.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                vec![vq.create_queue(), vq.create_queue()],
+                vec![EventFd::new().unwrap(), EventFd::new().unwrap()],
+            )
+            .is_ok());
+        assert!(receiver.try_recv().is_ok());
+        // The output is handed over to the epoll handler.
+        assert!(console
+            .activate(
+                m.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                vec![vq.create_queue(), vq.create_queue()],
+                vec![EventFd::new().unwrap(), EventFd::new().unwrap()],
+            )
+            .is_err());
+
+        unsafe { libc::close(epoll_raw_fd) };
+    }
+
+    #[test]
+    fn test_handler() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rxq = VirtQueue::new(GuestAddress(0), &m, 16);
+        let txq = VirtQueue::new(GuestAddress(0x4000), &m, 16);
+        let out = SharedBuffer::default();
+        let mut h = ConsoleEpollHandler {
+            queues: vec![rxq.create_queue(), txq.create_queue()],
+            mem: m.clone(),
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+            queue_evts: vec![EventFd::new().unwrap(), EventFd::new().unwrap()],
+            out: Box::new(out.clone()),
+        };
+
+        // The output of the guest, split over two descriptors.
+        m.write_slice_at_addr(b"hello ", GuestAddress(0x8000))
+            .unwrap();
+        m.write_slice_at_addr(b"world\n", GuestAddress(0x9000))
+            .unwrap();
+        txq.avail.ring[0].set(0);
+        txq.dtable[0].set(0x8000, 6, VIRTQ_DESC_F_NEXT, 1);
+        txq.dtable[1].set(0x9000, 6, 0, 0);
+        txq.avail.idx.set(1);
+
+        h.queue_evts[1].write(1).unwrap();
+        h.handle_event(TX_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+        assert_eq!(txq.used.idx.get(), 1);
+        assert_eq!(txq.used.ring[0].get().id, 0);
+        assert_eq!(&out.0.lock().unwrap()[..], b"hello world\n");
+
+        // The receive buffers are left untouched.
+        rxq.avail.ring[0].set(0);
+        rxq.dtable[0].set(0xa000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        rxq.avail.idx.set(1);
+        h.queue_evts[0].write(1).unwrap();
+        h.handle_event(RX_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(rxq.used.idx.get(), 0);
+
+        // Error case: unknown event.
+        match h.handle_event(
+            CONSOLE_EVENTS_COUNT as DeviceEventT,
+            0,
+            EpollHandlerPayload::Empty,
+        ) {
+            Err(DeviceError::UnknownEvent { device, .. }) => assert_eq!(device, "console"),
+            _ => panic!("Expected an unknown event error."),
+        }
+    }
+}
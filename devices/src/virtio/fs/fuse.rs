@@ -0,0 +1,272 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The subset of the FUSE protocol, as defined in linux/fuse.h, which is served by the
+//! in-process file system server.
+
+#![allow(non_camel_case_types)]
+
+use std::mem;
+use std::ptr;
+use std::slice;
+
+use memory_model::DataInit;
+
+/// The major version of the protocol spoken by the server.
+pub const KERNEL_VERSION: u32 = 7;
+/// The minor version of the protocol spoken by the server.
+pub const KERNEL_MINOR_VERSION: u32 = 27;
+/// The node ID of the root of the file system.
+pub const ROOT_ID: u64 = 1;
+
+pub const FUSE_LOOKUP: u32 = 1;
+pub const FUSE_FORGET: u32 = 2;
+pub const FUSE_GETATTR: u32 = 3;
+pub const FUSE_READLINK: u32 = 5;
+pub const FUSE_OPEN: u32 = 14;
+pub const FUSE_READ: u32 = 15;
+pub const FUSE_STATFS: u32 = 17;
+pub const FUSE_RELEASE: u32 = 18;
+pub const FUSE_FLUSH: u32 = 25;
+pub const FUSE_INIT: u32 = 26;
+pub const FUSE_OPENDIR: u32 = 27;
+pub const FUSE_READDIR: u32 = 28;
+pub const FUSE_RELEASEDIR: u32 = 29;
+pub const FUSE_DESTROY: u32 = 38;
+pub const FUSE_BATCH_FORGET: u32 = 42;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_in_header {
+    pub len: u32,
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_out_header {
+    pub len: u32,
+    pub error: i32,
+    pub unique: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_init_in {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_init_out {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+    pub max_background: u16,
+    pub congestion_threshold: u16,
+    pub max_write: u32,
+    pub time_gran: u32,
+    pub max_pages: u16,
+    pub padding: u16,
+    pub unused: [u32; 8],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_attr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub atimensec: u32,
+    pub mtimensec: u32,
+    pub ctimensec: u32,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_entry_out {
+    pub nodeid: u64,
+    pub generation: u64,
+    pub entry_valid: u64,
+    pub attr_valid: u64,
+    pub entry_valid_nsec: u32,
+    pub attr_valid_nsec: u32,
+    pub attr: fuse_attr,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_forget_in {
+    pub nlookup: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_batch_forget_in {
+    pub count: u32,
+    pub dummy: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_forget_one {
+    pub nodeid: u64,
+    pub nlookup: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_attr_out {
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    pub dummy: u32,
+    pub attr: fuse_attr,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_open_in {
+    pub flags: u32,
+    pub unused: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_open_out {
+    pub fh: u64,
+    pub open_flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_read_in {
+    pub fh: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub read_flags: u32,
+    pub lock_owner: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_release_in {
+    pub fh: u64,
+    pub flags: u32,
+    pub release_flags: u32,
+    pub lock_owner: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_kstatfs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+    pub padding: u32,
+    pub spare: [u32; 6],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct fuse_dirent {
+    pub ino: u64,
+    pub off: u64,
+    pub namelen: u32,
+    pub type_: u32,
+}
+
+// The structures are plain old data, without implicit padding.
+unsafe impl DataInit for fuse_in_header {}
+unsafe impl DataInit for fuse_out_header {}
+unsafe impl DataInit for fuse_init_in {}
+unsafe impl DataInit for fuse_init_out {}
+unsafe impl DataInit for fuse_attr {}
+unsafe impl DataInit for fuse_entry_out {}
+unsafe impl DataInit for fuse_forget_in {}
+unsafe impl DataInit for fuse_batch_forget_in {}
+unsafe impl DataInit for fuse_forget_one {}
+unsafe impl DataInit for fuse_attr_out {}
+unsafe impl DataInit for fuse_open_in {}
+unsafe impl DataInit for fuse_open_out {}
+unsafe impl DataInit for fuse_read_in {}
+unsafe impl DataInit for fuse_release_in {}
+unsafe impl DataInit for fuse_kstatfs {}
+unsafe impl DataInit for fuse_dirent {}
+
+/// Reads a `T` from the start of `buf`, returning it along with the rest of the buffer.
+pub fn read_obj<T: DataInit>(buf: &[u8]) -> Option<(T, &[u8])> {
+    if buf.len() < mem::size_of::<T>() {
+        return None;
+    }
+    // Safe because the buffer holds enough bytes and any bit pattern is a valid `T`.
+    let obj = unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) };
+    Some((obj, &buf[mem::size_of::<T>()..]))
+}
+
+/// Returns the bytes making up `obj`.
+pub fn as_bytes<T: DataInit>(obj: &T) -> &[u8] {
+    // Safe because `T` is plain old data, and the slice lives as long as the borrow of `obj`.
+    unsafe { slice::from_raw_parts(obj as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abi_sizes() {
+        assert_eq!(mem::size_of::<fuse_in_header>(), 40);
+        assert_eq!(mem::size_of::<fuse_out_header>(), 16);
+        assert_eq!(mem::size_of::<fuse_init_out>(), 64);
+        assert_eq!(mem::size_of::<fuse_attr>(), 88);
+        assert_eq!(mem::size_of::<fuse_entry_out>(), 128);
+        assert_eq!(mem::size_of::<fuse_attr_out>(), 104);
+        assert_eq!(mem::size_of::<fuse_read_in>(), 40);
+        assert_eq!(mem::size_of::<fuse_kstatfs>(), 80);
+        assert_eq!(mem::size_of::<fuse_dirent>(), 24);
+    }
+
+    #[test]
+    fn test_read_obj() {
+        let header = fuse_out_header {
+            len: 16,
+            error: -2,
+            unique: 7,
+        };
+        let mut buf = as_bytes(&header).to_vec();
+        buf.push(0xff);
+
+        let (read, rest): (fuse_out_header, &[u8]) = read_obj(&buf).unwrap();
+        assert_eq!((read.len, read.error, read.unique), (16, -2, 7));
+        assert_eq!(rest, &[0xff]);
+        assert!(read_obj::<fuse_out_header>(&buf[..15]).is_none());
+    }
+}
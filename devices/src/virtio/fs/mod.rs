@@ -0,0 +1,562 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a virtio-fs device sharing a host directory with the guest, read-only.
+//!
+//! The FUSE requests of the guest are served in-process by a `Server` rooted at the shared
+//! directory.
+
+pub mod fuse;
+pub mod server;
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use epoll;
+
+use self::server::Server;
+use super::super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, EpollHandlerPayload, Queue, VirtioDevice,
+    TYPE_FS, VIRTIO_MMIO_INT_VRING,
+};
+use memory_model::{GuestMemory, GuestMemoryError};
+use sys_util::EventFd;
+use {DeviceEventT, EpollHandler};
+
+/// New descriptors are pending on the high priority queue.
+pub const HIPRIO_QUEUE_EVENT: DeviceEventT = 0;
+/// New descriptors are pending on the request queue.
+pub const REQUEST_QUEUE_EVENT: DeviceEventT = 1;
+/// Number of DeviceEventT events supported by this implementation.
+pub const FS_EVENTS_COUNT: usize = 2;
+
+/// The maximum length of the tag by which the guest mounts the shared directory.
+pub const MAX_TAG_LEN: usize = 36;
+
+const QUEUE_SIZE: u16 = 256;
+// The high priority queue, followed by a single request queue.
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+// The tag, followed by the number of request queues.
+const CONFIG_SPACE_SIZE: usize = MAX_TAG_LEN + 4;
+// The requests which don't write to the file system are small.
+const MAX_REQUEST_SIZE: usize = 0x1_0000;
+
+const VIRTIO_F_VERSION_1: u32 = 32;
+
+/// Errors associated with creating a virtio-fs device.
+#[derive(Debug)]
+pub enum Error {
+    /// The tag is empty or longer than `MAX_TAG_LEN` bytes.
+    InvalidTag(String),
+    /// The shared directory can't be opened.
+    OpenSharedDir(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidTag(ref tag) => write!(
+                f,
+                "the tag {:?} must be between 1 and {} bytes long",
+                tag, MAX_TAG_LEN
+            ),
+            Error::OpenSharedDir(ref e) => write!(f, "cannot open the shared directory: {}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum RequestError {
+    /// Guest gave us bad memory addresses.
+    GuestMemory(GuestMemoryError),
+    /// The readable part of the descriptor chain is larger than any supported request.
+    RequestTooLarge,
+    /// The writable part of the descriptor chain can't hold the reply of the given size.
+    ReplyTooLarge(usize),
+}
+
+// Serves the request in the descriptor chain starting at `avail_desc`, returning the length of
+// the reply written back.
+fn handle_request(
+    mem: &GuestMemory,
+    server: &mut Server,
+    avail_desc: DescriptorChain,
+) -> result::Result<u32, RequestError> {
+    let mut request = Vec::new();
+    let mut reply_bufs = Vec::new();
+    let mut next = Some(avail_desc);
+    while let Some(desc) = next {
+        if desc.is_write_only() {
+            reply_bufs.push((desc.addr, desc.len as usize));
+        } else {
+            let start = request.len();
+            if start + desc.len as usize > MAX_REQUEST_SIZE {
+                return Err(RequestError::RequestTooLarge);
+            }
+            request.resize(start + desc.len as usize, 0);
+            mem.read_slice_at_addr(&mut request[start..], desc.addr)
+                .map_err(RequestError::GuestMemory)?;
+        }
+        next = desc.next_descriptor();
+    }
+
+    let reply = match server.handle_message(&request) {
+        Some(reply) => reply,
+        None => return Ok(0),
+    };
+    let capacity: usize = reply_bufs.iter().map(|&(_, len)| len).sum();
+    if reply.len() > capacity {
+        return Err(RequestError::ReplyTooLarge(reply.len()));
+    }
+    let mut written = 0;
+    for (addr, len) in reply_bufs {
+        if written == reply.len() {
+            break;
+        }
+        let end = cmp::min(written + len, reply.len());
+        mem.write_slice_at_addr(&reply[written..end], addr)
+            .map_err(RequestError::GuestMemory)?;
+        written = end;
+    }
+    Ok(written as u32)
+}
+
+struct FsEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemory,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+    queue_evts: Vec<EventFd>,
+    server: Server,
+}
+
+impl FsEpollHandler {
+    fn process_queue(&mut self, queue_index: usize) -> bool {
+        let queue = &mut self.queues[queue_index];
+
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        for avail_desc in queue.iter(&self.mem) {
+            let desc_index = avail_desc.index;
+            let len = match handle_request(&self.mem, &mut self.server, avail_desc) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("virtio-fs: failed to handle request: {:?}", e);
+                    0
+                }
+            };
+            used_desc_heads[used_count] = (desc_index, len);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            queue.add_used(&self.mem, desc_index, len);
+        }
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+        self.interrupt_evt.write(1).map_err(|e| {
+            error!("virtio-fs: failed to signal used queue: {:?}", e);
+            DeviceError::FailedSignalingUsedQueue(e)
+        })
+    }
+}
+
+impl EpollHandler for FsEpollHandler {
+    fn handle_event(
+        &mut self,
+        device_event: DeviceEventT,
+        _: u32,
+        _: EpollHandlerPayload,
+    ) -> result::Result<(), DeviceError> {
+        let queue_index = match device_event {
+            HIPRIO_QUEUE_EVENT => 0,
+            REQUEST_QUEUE_EVENT => 1,
+            unknown => {
+                return Err(DeviceError::UnknownEvent {
+                    device: "fs",
+                    event: unknown,
+                });
+            }
+        };
+        if let Err(e) = self.queue_evts[queue_index].read() {
+            error!("virtio-fs: failed to get queue event: {:?}", e);
+            Err(DeviceError::FailedReadingQueue {
+                event_type: "queue event",
+                underlying: e,
+            })
+        } else if self.process_queue(queue_index) {
+            self.signal_used_queue()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct EpollConfig {
+    hiprio_token: u64,
+    request_token: u64,
+    epoll_raw_fd: RawFd,
+    sender: mpsc::Sender<Box<EpollHandler>>,
+}
+
+impl EpollConfig {
+    pub fn new(
+        first_token: u64,
+        epoll_raw_fd: RawFd,
+        sender: mpsc::Sender<Box<EpollHandler>>,
+    ) -> Self {
+        EpollConfig {
+            hiprio_token: first_token + u64::from(HIPRIO_QUEUE_EVENT),
+            request_token: first_token + u64::from(REQUEST_QUEUE_EVENT),
+            epoll_raw_fd,
+            sender,
+        }
+    }
+}
+
+/// Virtio device sharing a host directory with the guest.
+pub struct Fs {
+    server: Option<Server>,
+    avail_features: u64,
+    acked_features: u64,
+    config_space: Vec<u8>,
+    epoll_config: EpollConfig,
+}
+
+impl Fs {
+    /// Creates a new virtio-fs device exposing `shared_dir`, which the guest mounts by `tag`.
+    pub fn new(
+        shared_dir: &Path,
+        tag: &str,
+        epoll_config: EpollConfig,
+    ) -> result::Result<Fs, Error> {
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            return Err(Error::InvalidTag(tag.to_string()));
+        }
+        let server = Server::new(shared_dir).map_err(Error::OpenSharedDir)?;
+
+        // The tag is NUL padded, and the number of request queues is little endian.
+        let mut config_space = Vec::with_capacity(CONFIG_SPACE_SIZE);
+        config_space.extend_from_slice(tag.as_bytes());
+        config_space.resize(MAX_TAG_LEN, 0);
+        for i in 0..4 {
+            config_space.push(((NUM_QUEUES - 1) >> (8 * i)) as u8);
+        }
+
+        Ok(Fs {
+            server: Some(server),
+            avail_features: 1u64 << VIRTIO_F_VERSION_1,
+            acked_features: 0u64,
+            config_space,
+            epoll_config,
+        })
+    }
+}
+
+impl VirtioDevice for Fs {
+    fn device_type(&self) -> u32 {
+        TYPE_FS
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            // Get the lower 32-bits of the features bitfield.
+            0 => self.avail_features as u32,
+            // Get the upper 32-bits of the features bitfield.
+            1 => (self.avail_features >> 32) as u32,
+            _ => {
+                warn!("Received request for unknown features page.");
+                0u32
+            }
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let mut v = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => {
+                warn!("Cannot acknowledge unknown features page.");
+                0u64
+            }
+        };
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, _: u64, _: &[u8]) {
+        warn!("virtio-fs: the configuration space is read-only");
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt_evt: EventFd,
+        status: Arc<AtomicUsize>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        if let Some(server) = self.server.take() {
+            let hiprio_raw_fd = queue_evts[0].as_raw_fd();
+            let request_raw_fd = queue_evts[1].as_raw_fd();
+            let handler = FsEpollHandler {
+                queues,
+                mem,
+                interrupt_status: status,
+                interrupt_evt,
+                queue_evts,
+                server,
+            };
+
+            // The channel should be open at this point.
+            self.epoll_config
+                .sender
+                .send(Box::new(handler))
+                .expect("Failed to send through the channel");
+
+            for &(fd, token) in [
+                (hiprio_raw_fd, self.epoll_config.hiprio_token),
+                (request_raw_fd, self.epoll_config.request_token),
+            ]
+            .iter()
+            {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    fd,
+                    epoll::Event::new(epoll::Events::EPOLLIN, token),
+                )
+                .map_err(ActivateError::EpollCtl)?;
+            }
+
+            return Ok(());
+        }
+        Err(ActivateError::BadActivate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs::File;
+    use std::mem;
+
+    use self::tempfile::tempdir;
+    use super::fuse::*;
+    use super::*;
+    use libc;
+    use memory_model::GuestAddress;
+    use virtio::queue::tests::*;
+    use virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    fn create_fs(shared_dir: &Path, tag: &str) -> result::Result<Fs, Error> {
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _) = mpsc::channel();
+        Fs::new(shared_dir, tag, EpollConfig::new(0, epoll_raw_fd, sender))
+    }
+
+    #[test]
+    fn test_virtio_device() {
+        let dir = tempdir().unwrap();
+        let mut fs = create_fs(dir.path(), "shared").unwrap();
+
+        assert_eq!(fs.device_type(), TYPE_FS);
+        assert_eq!(fs.queue_max_sizes(), &[QUEUE_SIZE, QUEUE_SIZE]);
+        assert_eq!(fs.features(0), 0);
+        assert_eq!(fs.features(1), 1);
+        fs.ack_features(1, 3);
+        assert_eq!(fs.acked_features, 1u64 << VIRTIO_F_VERSION_1);
+
+        // The configuration space holds the tag and the number of request queues.
+        let mut config = [0xffu8; CONFIG_SPACE_SIZE];
+        fs.read_config(0, &mut config);
+        assert_eq!(&config[..6], b"shared");
+        assert!(config[6..MAX_TAG_LEN].iter().all(|&b| b == 0));
+        assert_eq!(&config[MAX_TAG_LEN..], &[1, 0, 0, 0]);
+        fs.write_config(0, b"other");
+        fs.read_config(0, &mut config);
+        assert_eq!(&config[..6], b"shared");
+
+        // Error cases.
+        match create_fs(dir.path(), "") {
+            Err(Error::InvalidTag(_)) => (),
+            _ => panic!("Expected an invalid tag error."),
+        }
+        match create_fs(dir.path(), &"t".repeat(MAX_TAG_LEN + 1)) {
+            Err(Error::InvalidTag(_)) => (),
+            _ => panic!("Expected an invalid tag error."),
+        }
+        match create_fs(&dir.path().join("missing"), "shared") {
+            Err(Error::OpenSharedDir(_)) => (),
+            _ => panic!("Expected a shared directory error."),
+        }
+        File::create(dir.path().join("file")).unwrap();
+        match create_fs(&dir.path().join("file"), "shared") {
+            Err(Error::OpenSharedDir(_)) => (),
+            _ => panic!("Expected a shared directory error."),
+        }
+    }
+
+    #[test]
+    fn test_activate() {
+        let dir = tempdir().unwrap();
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let mut fs = Fs::new(
+            dir.path(),
+            "shared",
+            EpollConfig::new(0, epoll_raw_fd, sender),
+        )
+        .unwrap();
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &m, 16);
+
+        // Both the high priority and the request queue are needed.
+        assert!(fs
+            .activate(
+                m.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                vec![vq.create_queue()],
+                vec![EventFd::new().unwrap()],
+            )
+            .is_err());
+        assert!(fs
+            .activate(
+                m.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                vec![vq.create_queue(), vq.create_queue()],
+                vec![EventFd::new().unwrap(), EventFd::new().unwrap()],
+            )
+            .is_ok());
+        assert!(receiver.try_recv().is_ok());
+        // The server is handed over to the epoll handler.
+        assert!(fs
+            .activate(
+                m.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                vec![vq.create_queue(), vq.create_queue()],
+                vec![EventFd::new().unwrap(), EventFd::new().unwrap()],
+            )
+            .is_err());
+
+        unsafe { libc::close(epoll_raw_fd) };
+    }
+
+    #[test]
+    fn test_handler() {
+        let dir = tempdir().unwrap();
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &m, 16);
+        let mut h = FsEpollHandler {
+            queues: vec![vq.create_queue(), vq.create_queue()],
+            mem: m.clone(),
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new().unwrap(),
+            queue_evts: vec![EventFd::new().unwrap(), EventFd::new().unwrap()],
+            server: Server::new(dir.path()).unwrap(),
+        };
+
+        // A GETATTR request on the root, with room for the reply in two descriptors.
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + 16) as u32,
+            opcode: FUSE_GETATTR,
+            unique: 7,
+            nodeid: ROOT_ID,
+            ..Default::default()
+        };
+        m.write_slice_at_addr(as_bytes(&header), GuestAddress(0x1000))
+            .unwrap();
+        vq.avail.ring[0].set(0);
+        vq.dtable[0].set(0x1000, header.len, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 16, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+        vq.dtable[2].set(0x3000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.idx.set(1);
+
+        h.queue_evts[1].write(1).unwrap();
+        h.handle_event(REQUEST_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(h.interrupt_evt.read().unwrap(), 1);
+
+        let reply_len = mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_attr_out>();
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(vq.used.ring[0].get().len as usize, reply_len);
+        let out_header: fuse_out_header = m.read_obj_from_addr(GuestAddress(0x2000)).unwrap();
+        assert_eq!(out_header.len as usize, reply_len);
+        assert_eq!(out_header.error, 0);
+        assert_eq!(out_header.unique, 7);
+        let attr_out: fuse_attr_out = m.read_obj_from_addr(GuestAddress(0x3000)).unwrap();
+        assert_eq!(attr_out.attr.mode & libc::S_IFMT, libc::S_IFDIR);
+
+        // A reply which doesn't fit is dropped.
+        vq.dtable[2].set(0x3000, 0x10, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[1].set(0);
+        vq.avail.idx.set(2);
+        h.queue_evts[1].write(1).unwrap();
+        h.handle_event(REQUEST_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+            .unwrap();
+        assert_eq!(vq.used.idx.get(), 2);
+        assert_eq!(vq.used.ring[1].get().len, 0);
+
+        // Error case: unknown event.
+        match h.handle_event(
+            FS_EVENTS_COUNT as DeviceEventT,
+            0,
+            EpollHandlerPayload::Empty,
+        ) {
+            Err(DeviceError::UnknownEvent { device, .. }) => assert_eq!(device, "fs"),
+            _ => panic!("Expected an unknown event error."),
+        }
+    }
+}
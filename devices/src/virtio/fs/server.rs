@@ -0,0 +1,741 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A read-only FUSE server exposing a host directory.
+//!
+//! Every node known to the guest is backed by a file descriptor, and the nodes below it are only
+//! ever opened relative to that descriptor, one path component at a time and without following
+//! symbolic links. The guest can therefore never name a file outside of the shared directory.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+use libc;
+
+use super::fuse::*;
+
+// How long the guest may cache the entries and the attributes, in seconds.
+const TIMEOUT_SECS: u64 = 1;
+// The largest read or directory listing served by a single request.
+const MAX_BUFFER_SIZE: u32 = 1 << 20;
+// The size of the header of a directory entry returned by getdents64, before the name.
+const LINUX_DIRENT64_NAME_OFFSET: usize = 19;
+
+fn errno(err: i32) -> io::Error {
+    io::Error::from_raw_os_error(err)
+}
+
+fn open_at(dir: &File, name: &CStr, flags: libc::c_int) -> io::Result<File> {
+    // Safe because the name is NUL terminated and the result is checked.
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we own the new descriptor.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// Extracts the NUL terminated name at the start of `buf`.
+fn parse_name(buf: &[u8]) -> io::Result<&CStr> {
+    let end = buf
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| errno(libc::EINVAL))?;
+    CStr::from_bytes_with_nul(&buf[..=end]).map_err(|_| errno(libc::EINVAL))
+}
+
+// A name is only looked up in its parent directory, so anything but a single path component
+// could lead out of the shared directory.
+fn check_name(name: &CStr) -> io::Result<()> {
+    match name.to_bytes() {
+        b"" | b"." | b".." => Err(errno(libc::EPERM)),
+        bytes if bytes.contains(&b'/') => Err(errno(libc::EPERM)),
+        _ => Ok(()),
+    }
+}
+
+fn stat(file: &File) -> io::Result<fuse_attr> {
+    let st = file.metadata()?;
+    Ok(fuse_attr {
+        ino: st.st_ino(),
+        size: st.st_size(),
+        blocks: st.st_blocks(),
+        atime: st.st_atime() as u64,
+        mtime: st.st_mtime() as u64,
+        ctime: st.st_ctime() as u64,
+        atimensec: st.st_atime_nsec() as u32,
+        mtimensec: st.st_mtime_nsec() as u32,
+        ctimensec: st.st_ctime_nsec() as u32,
+        mode: st.st_mode(),
+        nlink: st.st_nlink() as u32,
+        uid: st.st_uid(),
+        gid: st.st_gid(),
+        rdev: st.st_rdev() as u32,
+        blksize: st.st_blksize() as u32,
+        padding: 0,
+    })
+}
+
+fn is_type(attr: &fuse_attr, type_: libc::mode_t) -> bool {
+    attr.mode & libc::S_IFMT == type_
+}
+
+struct Inode {
+    // An O_PATH descriptor, or a readable one for the regular files which the VMM may read.
+    file: File,
+    readable: bool,
+    // The host device and inode numbers, which identify the node across lookups.
+    key: (u64, u64),
+    refcount: u64,
+}
+
+/// Serves the FUSE requests of the guest from a host directory, without allowing any change.
+pub struct Server {
+    inodes: BTreeMap<u64, Inode>,
+    ids: BTreeMap<(u64, u64), u64>,
+    next_id: u64,
+    handles: BTreeMap<u64, File>,
+    next_handle: u64,
+}
+
+impl Server {
+    /// Creates a server rooted at the `shared_dir` directory.
+    pub fn new(shared_dir: &Path) -> io::Result<Server> {
+        let path =
+            CString::new(shared_dir.as_os_str().as_bytes()).map_err(|_| errno(libc::EINVAL))?;
+        // Safe because the path is NUL terminated and the result is checked.
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the new descriptor.
+        let root = unsafe { File::from_raw_fd(fd) };
+        let attr = stat(&root)?;
+        let key = (root.metadata()?.st_dev(), attr.ino);
+
+        let mut inodes = BTreeMap::new();
+        inodes.insert(
+            ROOT_ID,
+            Inode {
+                file: root,
+                readable: false,
+                key,
+                refcount: 1,
+            },
+        );
+        let mut ids = BTreeMap::new();
+        ids.insert(key, ROOT_ID);
+        Ok(Server {
+            inodes,
+            ids,
+            next_id: ROOT_ID + 1,
+            handles: BTreeMap::new(),
+            next_handle: 0,
+        })
+    }
+
+    /// Handles the FUSE request in `request`, returning the reply for the guest.
+    ///
+    /// Nothing is returned for the requests which don't expect a reply, and for those which
+    /// can't be parsed.
+    pub fn handle_message(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let (header, args): (fuse_in_header, &[u8]) = read_obj(request)?;
+        let result = match header.opcode {
+            FUSE_FORGET => {
+                if let Some((forget, _)) = read_obj::<fuse_forget_in>(args) {
+                    self.forget(header.nodeid, forget.nlookup);
+                }
+                return None;
+            }
+            FUSE_BATCH_FORGET => {
+                self.batch_forget(args);
+                return None;
+            }
+            FUSE_INIT => self.init(args),
+            FUSE_DESTROY => self.destroy(),
+            FUSE_LOOKUP => self.lookup(header.nodeid, args),
+            FUSE_GETATTR => self.getattr(header.nodeid),
+            FUSE_READLINK => self.readlink(header.nodeid),
+            FUSE_OPEN => self.open(header.nodeid, args),
+            FUSE_READ => self.read(args),
+            FUSE_FLUSH => Ok(Vec::new()),
+            FUSE_RELEASE | FUSE_RELEASEDIR => self.release(args),
+            FUSE_OPENDIR => self.opendir(header.nodeid),
+            FUSE_READDIR => self.readdir(args),
+            FUSE_STATFS => self.statfs(header.nodeid),
+            _ => Err(errno(libc::ENOSYS)),
+        };
+
+        let (error, data) = match result {
+            Ok(data) => (0, data),
+            Err(e) => (-e.raw_os_error().unwrap_or(libc::EIO), Vec::new()),
+        };
+        let out_header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + data.len()) as u32,
+            error,
+            unique: header.unique,
+        };
+        let mut reply = as_bytes(&out_header).to_vec();
+        reply.extend_from_slice(&data);
+        Some(reply)
+    }
+
+    fn inode(&self, nodeid: u64) -> io::Result<&Inode> {
+        self.inodes.get(&nodeid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn init(&mut self, args: &[u8]) -> io::Result<Vec<u8>> {
+        let (init_in, _): (fuse_init_in, &[u8]) =
+            read_obj(args).ok_or_else(|| errno(libc::EINVAL))?;
+        if init_in.major < KERNEL_VERSION {
+            return Err(errno(libc::EPROTO));
+        }
+        let init_out = fuse_init_out {
+            major: KERNEL_VERSION,
+            minor: cmp::min(init_in.minor, KERNEL_MINOR_VERSION),
+            max_readahead: init_in.max_readahead,
+            max_write: MAX_BUFFER_SIZE,
+            time_gran: 1,
+            ..Default::default()
+        };
+        Ok(as_bytes(&init_out).to_vec())
+    }
+
+    fn destroy(&mut self) -> io::Result<Vec<u8>> {
+        self.handles.clear();
+        self.inodes.retain(|&nodeid, _| nodeid == ROOT_ID);
+        let ids = &mut self.ids;
+        ids.clear();
+        for (&nodeid, inode) in self.inodes.iter() {
+            ids.insert(inode.key, nodeid);
+        }
+        Ok(Vec::new())
+    }
+
+    fn lookup(&mut self, parent: u64, args: &[u8]) -> io::Result<Vec<u8>> {
+        let name = parse_name(args)?;
+        check_name(name)?;
+
+        let file = open_at(
+            &self.inode(parent)?.file,
+            name,
+            libc::O_PATH | libc::O_NOFOLLOW,
+        )?;
+        let attr = stat(&file)?;
+        let key = (file.metadata()?.st_dev(), attr.ino);
+
+        let nodeid = match self.ids.get(&key) {
+            Some(&nodeid) => nodeid,
+            None => {
+                // The regular files are opened for reading now, while the name is known. The
+                // descriptor must still refer to the node which was looked up.
+                let mut file = file;
+                let mut readable = false;
+                if is_type(&attr, libc::S_IFREG) {
+                    if let Ok(data) = open_at(
+                        &self.inode(parent)?.file,
+                        name,
+                        libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK,
+                    ) {
+                        let metadata = data.metadata()?;
+                        if (metadata.st_dev(), metadata.st_ino()) == key {
+                            file = data;
+                            readable = true;
+                        }
+                    }
+                }
+                let nodeid = self.next_id;
+                self.next_id += 1;
+                self.inodes.insert(
+                    nodeid,
+                    Inode {
+                        file,
+                        readable,
+                        key,
+                        refcount: 0,
+                    },
+                );
+                self.ids.insert(key, nodeid);
+                nodeid
+            }
+        };
+        if let Some(inode) = self.inodes.get_mut(&nodeid) {
+            inode.refcount += 1;
+        }
+
+        let entry = fuse_entry_out {
+            nodeid,
+            generation: 0,
+            entry_valid: TIMEOUT_SECS,
+            attr_valid: TIMEOUT_SECS,
+            entry_valid_nsec: 0,
+            attr_valid_nsec: 0,
+            attr,
+        };
+        Ok(as_bytes(&entry).to_vec())
+    }
+
+    fn forget(&mut self, nodeid: u64, nlookup: u64) {
+        if nodeid == ROOT_ID {
+            return;
+        }
+        let forgotten = match self.inodes.get_mut(&nodeid) {
+            Some(inode) => {
+                inode.refcount = inode.refcount.saturating_sub(nlookup);
+                inode.refcount == 0
+            }
+            None => false,
+        };
+        if forgotten {
+            if let Some(inode) = self.inodes.remove(&nodeid) {
+                self.ids.remove(&inode.key);
+            }
+        }
+    }
+
+    fn batch_forget(&mut self, args: &[u8]) {
+        if let Some((batch, mut rest)) = read_obj::<fuse_batch_forget_in>(args) {
+            for _ in 0..batch.count {
+                match read_obj::<fuse_forget_one>(rest) {
+                    Some((one, next)) => {
+                        self.forget(one.nodeid, one.nlookup);
+                        rest = next;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn getattr(&mut self, nodeid: u64) -> io::Result<Vec<u8>> {
+        let attr_out = fuse_attr_out {
+            attr_valid: TIMEOUT_SECS,
+            attr_valid_nsec: 0,
+            dummy: 0,
+            attr: stat(&self.inode(nodeid)?.file)?,
+        };
+        Ok(as_bytes(&attr_out).to_vec())
+    }
+
+    fn readlink(&mut self, nodeid: u64) -> io::Result<Vec<u8>> {
+        let inode = self.inode(nodeid)?;
+        let mut target = vec![0u8; libc::PATH_MAX as usize];
+        // Safe because the buffer is as large as advertised and the result is checked. The
+        // empty path refers to the link the descriptor was opened on.
+        let len = unsafe {
+            libc::readlinkat(
+                inode.file.as_raw_fd(),
+                b"\0".as_ptr() as *const libc::c_char,
+                target.as_mut_ptr() as *mut libc::c_char,
+                target.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        target.truncate(len as usize);
+        Ok(target)
+    }
+
+    fn open(&mut self, nodeid: u64, args: &[u8]) -> io::Result<Vec<u8>> {
+        let (open_in, _): (fuse_open_in, &[u8]) =
+            read_obj(args).ok_or_else(|| errno(libc::EINVAL))?;
+        let flags = open_in.flags as libc::c_int;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            return Err(errno(libc::EROFS));
+        }
+        let inode = self.inode(nodeid)?;
+        if !inode.readable {
+            return Err(errno(libc::EACCES));
+        }
+        let file = inode.file.try_clone()?;
+        self.insert_handle(file)
+    }
+
+    fn opendir(&mut self, nodeid: u64) -> io::Result<Vec<u8>> {
+        let dir = open_at(
+            &self.inode(nodeid)?.file,
+            CStr::from_bytes_with_nul(b".\0").unwrap(),
+            libc::O_RDONLY | libc::O_DIRECTORY,
+        )?;
+        self.insert_handle(dir)
+    }
+
+    fn insert_handle(&mut self, file: File) -> io::Result<Vec<u8>> {
+        let fh = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(fh, file);
+        let open_out = fuse_open_out {
+            fh,
+            open_flags: 0,
+            padding: 0,
+        };
+        Ok(as_bytes(&open_out).to_vec())
+    }
+
+    fn release(&mut self, args: &[u8]) -> io::Result<Vec<u8>> {
+        let (release_in, _): (fuse_release_in, &[u8]) =
+            read_obj(args).ok_or_else(|| errno(libc::EINVAL))?;
+        self.handles
+            .remove(&release_in.fh)
+            .ok_or_else(|| errno(libc::EBADF))?;
+        Ok(Vec::new())
+    }
+
+    fn read(&mut self, args: &[u8]) -> io::Result<Vec<u8>> {
+        let (read_in, _): (fuse_read_in, &[u8]) =
+            read_obj(args).ok_or_else(|| errno(libc::EINVAL))?;
+        let file = self
+            .handles
+            .get(&read_in.fh)
+            .ok_or_else(|| errno(libc::EBADF))?;
+
+        let mut data = vec![0u8; cmp::min(read_in.size, MAX_BUFFER_SIZE) as usize];
+        // The guest takes a short read for the end of the file, so the buffer is filled unless
+        // the end is reached.
+        let mut len = 0;
+        while len < data.len() {
+            match file.read_at(&mut data[len..], read_in.offset + len as u64) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+
+    fn readdir(&mut self, args: &[u8]) -> io::Result<Vec<u8>> {
+        let (read_in, _): (fuse_read_in, &[u8]) =
+            read_obj(args).ok_or_else(|| errno(libc::EINVAL))?;
+        let dir = self
+            .handles
+            .get(&read_in.fh)
+            .ok_or_else(|| errno(libc::EBADF))?;
+        let size = cmp::min(read_in.size, MAX_BUFFER_SIZE) as usize;
+
+        // Safe because the descriptor is valid and the result is checked.
+        if unsafe { libc::lseek64(dir.as_raw_fd(), read_in.offset as i64, libc::SEEK_SET) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut entries = vec![0u8; size];
+        // Safe because the buffer is as large as advertised and the result is checked.
+        let len = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                dir.as_raw_fd(),
+                entries.as_mut_ptr(),
+                entries.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The entries which don't fit are returned by the next request, which starts from the
+        // offset of the last entry returned.
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos + LINUX_DIRENT64_NAME_OFFSET <= len as usize {
+            let (ino, rest): (u64, &[u8]) =
+                read_obj(&entries[pos..]).ok_or_else(|| errno(libc::EIO))?;
+            let (off, rest): (u64, &[u8]) = read_obj(rest).ok_or_else(|| errno(libc::EIO))?;
+            let (reclen, rest): (u16, &[u8]) = read_obj(rest).ok_or_else(|| errno(libc::EIO))?;
+            let type_ = u32::from(rest[0]);
+            let name = parse_name(&rest[1..])?.to_bytes();
+
+            let dirent = fuse_dirent {
+                ino,
+                off,
+                namelen: name.len() as u32,
+                type_,
+            };
+            let entry_len = mem::size_of::<fuse_dirent>() + name.len();
+            let padded_len = (entry_len + 7) & !7;
+            if out.len() + padded_len > size {
+                break;
+            }
+            out.extend_from_slice(as_bytes(&dirent));
+            out.extend_from_slice(name);
+            out.resize(out.len() + padded_len - entry_len, 0);
+            pos += reclen as usize;
+        }
+        Ok(out)
+    }
+
+    fn statfs(&mut self, nodeid: u64) -> io::Result<Vec<u8>> {
+        let inode = self.inode(nodeid)?;
+        // Safe because the structure is plain old data which is only read when the call
+        // succeeds.
+        let mut st: libc::statvfs64 = unsafe { mem::zeroed() };
+        // Safe because the descriptor is valid and the result is checked.
+        if unsafe { libc::fstatvfs64(inode.file.as_raw_fd(), &mut st) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let kstatfs = fuse_kstatfs {
+            blocks: st.f_blocks,
+            bfree: st.f_bfree,
+            bavail: st.f_bavail,
+            files: st.f_files,
+            ffree: st.f_ffree,
+            bsize: st.f_bsize as u32,
+            namelen: st.f_namemax as u32,
+            frsize: st.f_frsize as u32,
+            ..Default::default()
+        };
+        Ok(as_bytes(&kstatfs).to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+
+    use self::tempfile::tempdir;
+    use super::*;
+    use memory_model::DataInit;
+
+    fn request<T: DataInit>(opcode: u32, nodeid: u64, arg: &T, name: &[u8]) -> Vec<u8> {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + mem::size_of::<T>() + name.len()) as u32,
+            opcode,
+            unique: 0x42,
+            nodeid,
+            ..Default::default()
+        };
+        let mut buf = as_bytes(&header).to_vec();
+        buf.extend_from_slice(as_bytes(arg));
+        buf.extend_from_slice(name);
+        buf
+    }
+
+    // Sends a request and returns the error and the payload of the reply.
+    fn call<T: DataInit>(
+        server: &mut Server,
+        opcode: u32,
+        nodeid: u64,
+        arg: &T,
+        name: &[u8],
+    ) -> (i32, Vec<u8>) {
+        let reply = server
+            .handle_message(&request(opcode, nodeid, arg, name))
+            .unwrap();
+        let (header, data): (fuse_out_header, &[u8]) = read_obj(&reply).unwrap();
+        assert_eq!(header.unique, 0x42);
+        assert_eq!(header.len as usize, reply.len());
+        (header.error, data.to_vec())
+    }
+
+    fn lookup(server: &mut Server, parent: u64, name: &[u8]) -> Result<fuse_entry_out, i32> {
+        match call(server, FUSE_LOOKUP, parent, &[0u8; 0], name) {
+            (0, data) => Ok(read_obj::<fuse_entry_out>(&data).unwrap().0),
+            (error, _) => Err(-error),
+        }
+    }
+
+    fn open(server: &mut Server, opcode: u32, nodeid: u64, flags: i32) -> Result<u64, i32> {
+        let open_in = fuse_open_in {
+            flags: flags as u32,
+            unused: 0,
+        };
+        match call(server, opcode, nodeid, &open_in, &[]) {
+            (0, data) => Ok(read_obj::<fuse_open_out>(&data).unwrap().0.fh),
+            (error, _) => Err(-error),
+        }
+    }
+
+    fn read(server: &mut Server, opcode: u32, fh: u64, offset: u64, size: u32) -> Vec<u8> {
+        let read_in = fuse_read_in {
+            fh,
+            offset,
+            size,
+            ..Default::default()
+        };
+        let (error, data) = call(server, opcode, 0, &read_in, &[]);
+        assert_eq!(error, 0);
+        data
+    }
+
+    #[test]
+    fn test_init() {
+        let dir = tempdir().unwrap();
+        let mut server = Server::new(dir.path()).unwrap();
+
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 31,
+            max_readahead: 0x1000,
+            flags: 0,
+        };
+        let (error, data) = call(&mut server, FUSE_INIT, 0, &init_in, &[]);
+        assert_eq!(error, 0);
+        let init_out = read_obj::<fuse_init_out>(&data).unwrap().0;
+        assert_eq!(init_out.major, KERNEL_VERSION);
+        assert_eq!(init_out.minor, KERNEL_MINOR_VERSION);
+        assert_eq!(init_out.max_readahead, 0x1000);
+
+        // The unsupported requests, like those changing the file system, are refused.
+        let (error, _) = call(
+            &mut server,
+            10, /* FUSE_UNLINK */
+            ROOT_ID,
+            &[0u8; 0],
+            b"f\0",
+        );
+        assert_eq!(error, -libc::ENOSYS);
+
+        // No reply is expected for forgetting a node, nor for a malformed request.
+        let forget_in = fuse_forget_in { nlookup: 1 };
+        assert!(server
+            .handle_message(&request(FUSE_FORGET, 5, &forget_in, &[]))
+            .is_none());
+        assert!(server.handle_message(&[0u8; 8]).is_none());
+    }
+
+    #[test]
+    fn test_read_file() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::File::create(dir.path().join("sub/file"))
+            .unwrap()
+            .write_all(b"shared data")
+            .unwrap();
+        let mut server = Server::new(dir.path()).unwrap();
+
+        let sub = lookup(&mut server, ROOT_ID, b"sub\0").unwrap();
+        assert!(is_type(&sub.attr, libc::S_IFDIR));
+        let file = lookup(&mut server, sub.nodeid, b"file\0").unwrap();
+        assert!(is_type(&file.attr, libc::S_IFREG));
+        assert_eq!(file.attr.size, 11);
+        // Looking a node up again yields the same node ID.
+        assert_eq!(
+            lookup(&mut server, sub.nodeid, b"file\0").unwrap().nodeid,
+            file.nodeid
+        );
+        assert_eq!(
+            lookup(&mut server, sub.nodeid, b"missing\0").err(),
+            Some(libc::ENOENT)
+        );
+
+        let (error, data) = call(&mut server, FUSE_GETATTR, file.nodeid, &[0u8; 16], &[]);
+        assert_eq!(error, 0);
+        assert_eq!(read_obj::<fuse_attr_out>(&data).unwrap().0.attr.size, 11);
+
+        // The file system is read-only.
+        assert_eq!(
+            open(&mut server, FUSE_OPEN, file.nodeid, libc::O_RDWR),
+            Err(libc::EROFS)
+        );
+        let fh = open(&mut server, FUSE_OPEN, file.nodeid, libc::O_RDONLY).unwrap();
+        assert_eq!(read(&mut server, FUSE_READ, fh, 0, 4096), b"shared data");
+        assert_eq!(read(&mut server, FUSE_READ, fh, 7, 2), b"da");
+        let release_in = fuse_release_in {
+            fh,
+            ..Default::default()
+        };
+        assert_eq!(call(&mut server, FUSE_RELEASE, 0, &release_in, &[]).0, 0);
+        assert_eq!(
+            call(&mut server, FUSE_RELEASE, 0, &release_in, &[]).0,
+            -libc::EBADF
+        );
+
+        // The node is dropped once the guest forgets all its lookups.
+        let forget_in = fuse_forget_in { nlookup: 2 };
+        assert!(server
+            .handle_message(&request(FUSE_FORGET, file.nodeid, &forget_in, &[]))
+            .is_none());
+        assert_eq!(
+            call(&mut server, FUSE_GETATTR, file.nodeid, &[0u8; 16], &[]).0,
+            -libc::EBADF
+        );
+    }
+
+    #[test]
+    fn test_readdir() {
+        let dir = tempdir().unwrap();
+        fs::File::create(dir.path().join("a")).unwrap();
+        fs::create_dir(dir.path().join("b")).unwrap();
+        let mut server = Server::new(dir.path()).unwrap();
+
+        let fh = open(&mut server, FUSE_OPENDIR, ROOT_ID, libc::O_RDONLY).unwrap();
+        let mut names = Vec::new();
+        let mut offset = 0;
+        loop {
+            // Each listing only has room for a couple of entries.
+            let data = read(&mut server, FUSE_READDIR, fh, offset, 64);
+            if data.is_empty() {
+                break;
+            }
+            let mut rest = &data[..];
+            while let Some((dirent, after)) = read_obj::<fuse_dirent>(rest) {
+                let namelen = dirent.namelen as usize;
+                names.push(after[..namelen].to_vec());
+                offset = dirent.off;
+                rest = &after[(namelen + 7) & !7..];
+            }
+        }
+        names.sort();
+        assert_eq!(
+            names,
+            vec![b".".to_vec(), b"..".to_vec(), b"a".to_vec(), b"b".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_path_traversal() {
+        let dir = tempdir().unwrap();
+        let shared = dir.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::File::create(dir.path().join("secret")).unwrap();
+        symlink("../secret", shared.join("link")).unwrap();
+        let mut server = Server::new(&shared).unwrap();
+
+        // Only single components are looked up.
+        for name in [&b"..\0"[..], b".\0", b"../secret\0", b"/etc\0", b"\0"].iter() {
+            assert_eq!(lookup(&mut server, ROOT_ID, name).err(), Some(libc::EPERM));
+        }
+        // A name must be NUL terminated.
+        assert_eq!(
+            lookup(&mut server, ROOT_ID, b"secret").err(),
+            Some(libc::EINVAL)
+        );
+
+        // The links are not followed on the host: the guest sees the link itself and resolves
+        // its target within the shared directory.
+        let link = lookup(&mut server, ROOT_ID, b"link\0").unwrap();
+        assert!(is_type(&link.attr, libc::S_IFLNK));
+        assert_eq!(
+            call(&mut server, FUSE_READLINK, link.nodeid, &[0u8; 0], &[]),
+            (0, b"../secret".to_vec())
+        );
+        assert_eq!(
+            open(&mut server, FUSE_OPEN, link.nodeid, libc::O_RDONLY),
+            Err(libc::EACCES)
+        );
+
+        // The nodes unknown to the server can't be used as a starting point.
+        assert_eq!(
+            lookup(&mut server, 1000, b"secret\0").err(),
+            Some(libc::EBADF)
+        );
+    }
+}
@@ -24,6 +24,31 @@ const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
 //current version specified by the mmio standard (legacy devices used 1 here)
 const MMIO_VERSION: u32 = 2;
 
+// The transport only drives modern devices, so this feature can't be masked.
+const VIRTIO_F_VERSION_1: u32 = 32;
+
+// The sizes of the state saved by `MmioDevice::save_state`, before the queues and for each queue.
+const STATE_HEADER_LEN: usize = 40;
+const STATE_QUEUE_LEN: usize = 28;
+
+// The offsets of the registers of the virtio mmio layout, besides the queue notification one.
+const MMIO_REGISTERS: &[u32] = &[
+    0x00, 0x04, 0x08, 0x0c, 0x10, 0x14, 0x20, 0x24, 0x30, 0x34, 0x38, 0x44, 0x60, 0x64, 0x70, 0x80,
+    0x84, 0x90, 0x94, 0xa0, 0xa4, 0xfc,
+];
+
+/// Checks whether the queue notification register can be placed at `offset` from the MMIO base:
+/// it must be an aligned register of the common area which no other register uses.
+pub fn is_valid_notify_reg_offset(offset: u32) -> bool {
+    offset % 4 == 0 && u64::from(offset) < 0x100 && !MMIO_REGISTERS.contains(&offset)
+}
+
+/// Checks whether `mask` can be applied to the features of a device by `FeatureMask`: it can't
+/// clear the features the transport requires.
+pub fn is_valid_feature_mask(mask: u64) -> bool {
+    mask & (1u64 << VIRTIO_F_VERSION_1) != 0
+}
+
 /// Trait for virtio devices to be driven by a virtio transport.
 ///
 /// The lifecycle of a virtio device is to be moved to a virtio transport, which will then query the
@@ -53,6 +78,18 @@ pub trait VirtioDevice: Send {
     /// Writes to this device configuration space at `offset`.
     fn write_config(&mut self, offset: u64, data: &[u8]);
 
+    /// The number of interrupt vectors this device would like, typically one per queue. The
+    /// vectors may end up sharing a single IRQ when there aren't enough of them available.
+    fn interrupt_vectors(&self) -> usize {
+        1
+    }
+
+    /// Hands over the events of the interrupt vectors past the first one, which is passed to
+    /// `activate`. This is called right before the device is activated.
+    fn set_vector_evts(&mut self, vector_evts: Vec<EventFd>) {
+        let _ = vector_evts;
+    }
+
     /// Activates this device for real usage.
     fn activate(
         &mut self,
@@ -70,6 +107,111 @@ pub trait VirtioDevice: Send {
     }
 }
 
+// Stands in for a device unplugged from its transport. The guest reads the device type 0,
+// which virtio reserves for an empty slot, and can't activate it anymore.
+struct NoDevice;
+
+impl VirtioDevice for NoDevice {
+    fn device_type(&self) -> u32 {
+        0
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &[]
+    }
+
+    fn ack_features(&mut self, _page: u32, _value: u32) {}
+
+    fn read_config(&self, _offset: u64, _data: &mut [u8]) {}
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    fn activate(
+        &mut self,
+        _mem: GuestMemory,
+        _interrupt_evt: EventFd,
+        _status: Arc<AtomicUsize>,
+        _queues: Vec<Queue>,
+        _queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        Err(ActivateError::BadActivate)
+    }
+}
+
+/// Wraps a virtio device, hiding from the guest the features cleared in a mask. The features the
+/// device advertises are ANDed with the mask, and so are the features the driver acknowledges.
+pub struct FeatureMask {
+    device: Box<VirtioDevice>,
+    mask: u64,
+}
+
+impl FeatureMask {
+    /// Wraps `device`, which then only advertises its features set in `mask`. The mask must be
+    /// accepted by `is_valid_feature_mask`.
+    pub fn new(device: Box<VirtioDevice>, mask: u64) -> Self {
+        FeatureMask { device, mask }
+    }
+
+    fn page_mask(&self, page: u32) -> u32 {
+        match page {
+            0 => self.mask as u32,
+            1 => (self.mask >> 32) as u32,
+            _ => 0,
+        }
+    }
+}
+
+impl VirtioDevice for FeatureMask {
+    fn device_type(&self) -> u32 {
+        self.device.device_type()
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        self.device.queue_max_sizes()
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        self.device.features(page) & self.page_mask(page)
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let value = value & self.page_mask(page);
+        self.device.ack_features(page, value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.device.read_config(offset, data)
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.device.write_config(offset, data)
+    }
+
+    fn interrupt_vectors(&self) -> usize {
+        self.device.interrupt_vectors()
+    }
+
+    fn set_vector_evts(&mut self, vector_evts: Vec<EventFd>) {
+        self.device.set_vector_evts(vector_evts)
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt_evt: EventFd,
+        status: Arc<AtomicUsize>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        self.device
+            .activate(mem, interrupt_evt, status, queues, queue_evts)
+    }
+
+    fn reset(&mut self) -> Option<(EventFd, Vec<EventFd>)> {
+        self.device.reset()
+    }
+}
+
 /// Implements the
 /// [MMIO](http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1090002)
 /// transport for virtio devices.
@@ -77,7 +219,7 @@ pub trait VirtioDevice: Send {
 /// This requires 3 points of installation to work with a VM:
 ///
 /// 1. Mmio reads and writes must be sent to this device at what is referred to here as MMIO base.
-/// 1. `Mmio::queue_evts` must be installed at `virtio::NOTIFY_REG_OFFSET` offset from the MMIO
+/// 1. `Mmio::queue_evts` must be installed at `Mmio::notify_reg_offset` offset from the MMIO
 /// base. Each event in the array must be signaled if the index is written at that offset.
 /// 1. `Mmio::interrupt_evt` must signal an interrupt that the guest driver is listening to when it
 /// is written to.
@@ -90,19 +232,34 @@ pub struct MmioDevice {
 
     features_select: u32,
     acked_features_select: u32,
+    // The features the driver acknowledged, which the saved state hands over to the device.
+    acked_features: u64,
     queue_select: u32,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: Option<EventFd>,
+    vector_evts: Vec<EventFd>,
     driver_status: u32,
     config_generation: u32,
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
+    notify_reg_offset: u32,
     mem: Option<GuestMemory>,
 }
 
 impl MmioDevice {
     /// Constructs a new MMIO transport for the given virtio device.
     pub fn new(mem: GuestMemory, device: Box<VirtioDevice>) -> std::io::Result<MmioDevice> {
+        Self::with_notify_reg_offset(mem, device, NOTIFY_REG_OFFSET)
+    }
+
+    /// Constructs a new MMIO transport for the given virtio device, whose queues are notified at
+    /// `notify_reg_offset` instead of the standard offset. The offset must be accepted by
+    /// `is_valid_notify_reg_offset`.
+    pub fn with_notify_reg_offset(
+        mem: GuestMemory,
+        device: Box<VirtioDevice>,
+        notify_reg_offset: u32,
+    ) -> std::io::Result<MmioDevice> {
         let mut queue_evts = Vec::new();
         for _ in device.queue_max_sizes().iter() {
             queue_evts.push(EventFd::new()?)
@@ -112,34 +269,148 @@ impl MmioDevice {
             .iter()
             .map(|&s| Queue::new(s))
             .collect();
+        let mut vector_evts = Vec::new();
+        for _ in 1..device.interrupt_vectors() {
+            vector_evts.push(EventFd::new()?)
+        }
         Ok(MmioDevice {
             device,
             device_activated: false,
             features_select: 0,
             acked_features_select: 0,
+            acked_features: 0,
             queue_select: 0,
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_evt: Some(EventFd::new()?),
+            vector_evts,
             driver_status: DEVICE_INIT,
             config_generation: 0,
             queues,
             queue_evts,
+            notify_reg_offset,
             mem: Some(mem),
         })
     }
 
     /// Gets the list of queue events that must be triggered whenever the VM writes to
-    /// `virtio::NOTIFY_REG_OFFSET` past the MMIO base. Each event must be triggered when the
+    /// `notify_reg_offset` past the MMIO base. Each event must be triggered when the
     /// value being written equals the index of the event in this list.
     pub fn queue_evts(&self) -> &[EventFd] {
         self.queue_evts.as_slice()
     }
 
+    /// Gets the offset from the MMIO base of the register the guest writes to for notifying
+    /// the queues.
+    pub fn notify_reg_offset(&self) -> u32 {
+        self.notify_reg_offset
+    }
+
     /// Gets the event this device uses to interrupt the VM when the used queue is changed.
     pub fn interrupt_evt(&self) -> Option<&EventFd> {
         self.interrupt_evt.as_ref()
     }
 
+    /// Gets the events of all the interrupt vectors of this device, starting with the one
+    /// returned by `interrupt_evt`. Each of them must be wired to an interrupt of the VM.
+    pub fn interrupt_evts(&self) -> Vec<&EventFd> {
+        self.interrupt_evt
+            .iter()
+            .chain(self.vector_evts.iter())
+            .collect()
+    }
+
+    /// Saves the state of the transport: the registers the driver wrote and the layout of the
+    /// queues, in little endian. The progress of the queues is in the used rings, in guest memory.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![0u8; STATE_HEADER_LEN + self.queues.len() * STATE_QUEUE_LEN];
+        LittleEndian::write_u32(&mut state[0..4], self.features_select);
+        LittleEndian::write_u32(&mut state[4..8], self.acked_features_select);
+        LittleEndian::write_u32(&mut state[8..12], self.queue_select);
+        LittleEndian::write_u32(
+            &mut state[12..16],
+            self.interrupt_status.load(Ordering::SeqCst) as u32,
+        );
+        LittleEndian::write_u32(&mut state[16..20], self.driver_status);
+        LittleEndian::write_u32(&mut state[20..24], self.config_generation);
+        LittleEndian::write_u64(&mut state[24..32], self.acked_features);
+        LittleEndian::write_u32(&mut state[32..36], self.device_activated as u32);
+        LittleEndian::write_u32(&mut state[36..40], self.queues.len() as u32);
+        for (i, q) in self.queues.iter().enumerate() {
+            let s = &mut state[STATE_HEADER_LEN + i * STATE_QUEUE_LEN..];
+            LittleEndian::write_u16(&mut s[0..2], q.size);
+            LittleEndian::write_u16(&mut s[2..4], q.ready as u16);
+            LittleEndian::write_u64(&mut s[4..12], q.desc_table.offset() as u64);
+            LittleEndian::write_u64(&mut s[12..20], q.avail_ring.offset() as u64);
+            LittleEndian::write_u64(&mut s[20..28], q.used_ring.offset() as u64);
+        }
+        state
+    }
+
+    /// Restores the state saved by `save_state` into this transport, which must be new and drive
+    /// the same kind of device. A device the driver activated is activated again, with queues
+    /// resuming at the index of their used rings in the guest memory.
+    ///
+    /// A state which doesn't match the queues of the device is rejected with `BadActivate`.
+    pub fn restore_state(&mut self, state: &[u8]) -> ActivateResult {
+        if self.device_activated
+            || state.len() < STATE_HEADER_LEN
+            || LittleEndian::read_u32(&state[36..40]) as usize != self.queues.len()
+            || state.len() != STATE_HEADER_LEN + self.queues.len() * STATE_QUEUE_LEN
+        {
+            return Err(ActivateError::BadActivate);
+        }
+        self.features_select = LittleEndian::read_u32(&state[0..4]);
+        self.acked_features_select = LittleEndian::read_u32(&state[4..8]);
+        self.queue_select = LittleEndian::read_u32(&state[8..12]);
+        self.interrupt_status.store(
+            LittleEndian::read_u32(&state[12..16]) as usize,
+            Ordering::SeqCst,
+        );
+        self.driver_status = LittleEndian::read_u32(&state[16..20]);
+        self.config_generation = LittleEndian::read_u32(&state[20..24]);
+        self.acked_features = LittleEndian::read_u64(&state[24..32]);
+        // The device is told the features the driver acknowledged, as the driver would.
+        self.device.ack_features(0, self.acked_features as u32);
+        self.device
+            .ack_features(1, (self.acked_features >> 32) as u32);
+        for (i, q) in self.queues.iter_mut().enumerate() {
+            let s = &state[STATE_HEADER_LEN + i * STATE_QUEUE_LEN..];
+            q.size = LittleEndian::read_u16(&s[0..2]);
+            q.ready = LittleEndian::read_u16(&s[2..4]) != 0;
+            q.desc_table = GuestAddress(LittleEndian::read_u64(&s[4..12]) as usize);
+            q.avail_ring = GuestAddress(LittleEndian::read_u64(&s[12..20]) as usize);
+            q.used_ring = GuestAddress(LittleEndian::read_u64(&s[20..28]) as usize);
+        }
+
+        if LittleEndian::read_u32(&state[32..36]) == 0 {
+            return Ok(());
+        }
+        if !self.are_queues_valid() {
+            return Err(ActivateError::BadActivate);
+        }
+        if let Some(ref mem) = self.mem {
+            for q in self.queues.iter_mut() {
+                q.resume_at_used_index(mem);
+            }
+        }
+        self.activate()
+    }
+
+    /// Takes the virtio device out of this transport, which is left behind as an empty slot.
+    ///
+    /// The queues and the queue events are released, so the guest can't drive the device
+    /// anymore. An activated device has already handed its resources over to its epoll handler,
+    /// which has to be dropped as well for them to be released.
+    pub fn unplug(&mut self) -> Box<VirtioDevice> {
+        self.device_activated = false;
+        self.driver_status |= DEVICE_NEEDS_RESET;
+        self.queues.clear();
+        self.queue_evts.clear();
+        self.vector_evts.clear();
+        self.mem = None;
+        std::mem::replace(&mut self.device, Box::new(NoDevice))
+    }
+
     fn check_driver_status(&self, set: u32, clr: u32) -> bool {
         self.driver_status & (set | clr) == set
     }
@@ -182,6 +453,31 @@ impl MmioDevice {
         }
     }
 
+    // Hands the guest memory, the events and the queues over to the device, whose queues must be
+    // valid.
+    fn activate(&mut self) -> ActivateResult {
+        let interrupt_evt = match self.interrupt_evt {
+            Some(ref interrupt_evt) => interrupt_evt.try_clone().expect("Failed to clone eventfd"),
+            None => return Err(ActivateError::BadActivate),
+        };
+        let mem = self.mem.take().ok_or(ActivateError::BadActivate)?;
+        self.device.set_vector_evts(
+            self.vector_evts
+                .iter()
+                .map(|evt| evt.try_clone().expect("Failed to clone eventfd"))
+                .collect(),
+        );
+        self.device.activate(
+            mem,
+            interrupt_evt,
+            self.interrupt_status.clone(),
+            self.queues.clone(),
+            self.queue_evts.split_off(0),
+        )?;
+        self.device_activated = true;
+        Ok(())
+    }
+
     fn reset(&mut self) {
         if self.device_activated {
             warn!("reset device while it's still in active state");
@@ -189,6 +485,7 @@ impl MmioDevice {
         }
         self.features_select = 0;
         self.acked_features_select = 0;
+        self.acked_features = 0;
         self.queue_select = 0;
         self.interrupt_status.store(0, Ordering::SeqCst);
         self.driver_status = 0;
@@ -228,19 +525,12 @@ impl MmioDevice {
                 // If the driver incorrectly sets up the queues, the following
                 // check will fail and take the device into an unusable state.
                 if !self.device_activated && self.are_queues_valid() {
-                    if let Some(ref interrupt_evt) = self.interrupt_evt {
-                        if let Some(mem) = self.mem.take() {
-                            self.device
-                                .activate(
-                                    mem,
-                                    interrupt_evt.try_clone().expect("Failed to clone eventfd"),
-                                    self.interrupt_status.clone(),
-                                    self.queues.clone(),
-                                    self.queue_evts.split_off(0),
-                                )
-                                .expect("Failed to activate device");
-                            self.device_activated = true;
-                        }
+                    // A device which relies on an external backend can fail to activate
+                    // without the VMM being at fault, so only this device is taken out of
+                    // service.
+                    if let Err(e) = self.activate() {
+                        error!("Failed to activate device: {:?}", e);
+                        self.driver_status |= DEVICE_NEEDS_RESET;
                     }
                 }
             }
@@ -333,6 +623,11 @@ impl BusDevice for MmioDevice {
                             .check_driver_status(DEVICE_DRIVER, DEVICE_FEATURES_OK | DEVICE_FAILED)
                         {
                             self.device.ack_features(self.acked_features_select, v);
+                            match self.acked_features_select {
+                                0 => self.acked_features |= u64::from(v),
+                                1 => self.acked_features |= u64::from(v) << 32,
+                                _ => (),
+                            }
                         } else {
                             warn!(
                                 "ack virtio features in invalid state 0x{:x}",
@@ -358,6 +653,12 @@ impl BusDevice for MmioDevice {
                     0x94 => self.update_queue_field(|q| hi(&mut q.avail_ring, v)),
                     0xa0 => self.update_queue_field(|q| lo(&mut q.used_ring, v)),
                     0xa4 => self.update_queue_field(|q| hi(&mut q.used_ring, v)),
+                    // The notifications are served by the queue events installed at this
+                    // offset, so only the ones of unknown queues get here.
+                    _ if offset == u64::from(self.notify_reg_offset) => {
+                        warn!("notification of unknown virtio queue {}", v);
+                        return;
+                    }
                     _ => {
                         warn!("unknown virtio mmio register write: 0x{:x}", offset);
                         return;
@@ -400,19 +701,23 @@ mod tests {
     use super::*;
 
     struct DummyDevice {
+        avail_features: u64,
         acked_features: u32,
         interrupt_evt: Option<EventFd>,
         queue_evts: Option<Vec<EventFd>>,
         config_bytes: [u8; 0xeff],
+        fail_activate: bool,
     }
 
     impl DummyDevice {
         fn new() -> Self {
             DummyDevice {
+                avail_features: 0,
                 acked_features: 0,
                 interrupt_evt: None,
                 queue_evts: None,
                 config_bytes: [0; 0xeff],
+                fail_activate: false,
             }
         }
     }
@@ -439,6 +744,14 @@ mod tests {
             }
         }
 
+        fn features(&self, page: u32) -> u32 {
+            match page {
+                0 => self.avail_features as u32,
+                1 => (self.avail_features >> 32) as u32,
+                _ => 0,
+            }
+        }
+
         fn ack_features(&mut self, page: u32, value: u32) {
             self.acked_features = page + value;
         }
@@ -451,6 +764,9 @@ mod tests {
             _queues: Vec<Queue>,
             queue_evts: Vec<EventFd>,
         ) -> ActivateResult {
+            if self.fail_activate {
+                return Err(ActivateError::BadActivate);
+            }
             self.interrupt_evt = Some(interrupt_evt);
             self.queue_evts = Some(queue_evts);
             Ok(())
@@ -477,6 +793,8 @@ mod tests {
         assert_eq!(d.queue_evts().len(), 2);
 
         assert!(d.interrupt_evt().is_some());
+        // The device doesn't ask for more than the default interrupt vector.
+        assert_eq!(d.interrupt_evts().len(), 1);
 
         assert!(!d.are_queues_valid());
 
@@ -576,6 +894,30 @@ mod tests {
         assert_eq!(buf[..], buf_copy[..]);
     }
 
+    #[test]
+    fn test_notify_reg_offset() {
+        assert!(is_valid_notify_reg_offset(NOTIFY_REG_OFFSET));
+        assert!(is_valid_notify_reg_offset(0xc0));
+        // Unaligned, outside of the common area or taken by another register.
+        assert!(!is_valid_notify_reg_offset(0x52));
+        assert!(!is_valid_notify_reg_offset(0x100));
+        assert!(!is_valid_notify_reg_offset(0x70));
+
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let d = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        assert_eq!(d.notify_reg_offset(), NOTIFY_REG_OFFSET);
+
+        let mut d =
+            MmioDevice::with_notify_reg_offset(m, Box::new(DummyDevice::new()), 0xc0).unwrap();
+        assert_eq!(d.notify_reg_offset(), 0xc0);
+        // The notifications which get to the device don't change its registers.
+        let mut buf = vec![0; 4];
+        LittleEndian::write_u32(&mut buf[..], 5);
+        d.write(0xc0, &buf[..]);
+        assert_eq!(d.queue_select, 0);
+        assert_eq!(d.driver_status, DEVICE_INIT);
+    }
+
     #[test]
     #[allow(clippy::cyclomatic_complexity)]
     fn test_bus_device_write() {
@@ -842,4 +1184,180 @@ mod tests {
         assert_eq!(d.driver_status, 0x8f);
         assert!(d.device_activated);
     }
+
+    #[test]
+    fn test_bus_device_activate_failure() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut dummy = DummyDevice::new();
+        dummy.fail_activate = true;
+        let mut d = MmioDevice::new(m, Box::new(dummy)).unwrap();
+
+        set_driver_status(&mut d, DEVICE_ACKNOWLEDGE);
+        set_driver_status(&mut d, DEVICE_ACKNOWLEDGE | DEVICE_DRIVER);
+        set_driver_status(
+            &mut d,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK,
+        );
+        let mut buf = vec![0; 4];
+        for q in 0..d.queues.len() {
+            d.queue_select = q as u32;
+            LittleEndian::write_u32(&mut buf[..], 16);
+            d.write(0x38, &buf[..]);
+            LittleEndian::write_u32(&mut buf[..], 1);
+            d.write(0x44, &buf[..]);
+        }
+
+        // The failure is reported to the guest driver instead of taking down the VMM.
+        set_driver_status(
+            &mut d,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK | DEVICE_DRIVER_OK,
+        );
+        assert_eq!(
+            d.driver_status,
+            DEVICE_ACKNOWLEDGE
+                | DEVICE_DRIVER
+                | DEVICE_FEATURES_OK
+                | DEVICE_DRIVER_OK
+                | DEVICE_NEEDS_RESET
+        );
+        assert!(!d.device_activated);
+    }
+
+    #[test]
+    fn test_unplug() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioDevice::new(m, Box::new(DummyDevice::new())).unwrap();
+        let mut buf = vec![0; 4];
+        activate_device(&mut d);
+
+        let device = d.unplug();
+        assert_eq!(device.device_type(), 123);
+        assert!(!d.device_activated);
+        assert!(d.queue_evts().is_empty());
+        assert_eq!(d.driver_status & DEVICE_NEEDS_RESET, DEVICE_NEEDS_RESET);
+
+        // The guest finds an empty slot.
+        d.read(0x08, &mut buf[..]);
+        assert_eq!(LittleEndian::read_u32(&buf[..]), 0);
+        d.queue_select = 0;
+        d.read(0x34, &mut buf[..]);
+        assert_eq!(LittleEndian::read_u32(&buf[..]), 0);
+
+        // Even after a reset, the slot can't be activated again.
+        set_driver_status(&mut d, 0);
+        set_driver_status(&mut d, DEVICE_ACKNOWLEDGE);
+        set_driver_status(&mut d, DEVICE_ACKNOWLEDGE | DEVICE_DRIVER);
+        set_driver_status(
+            &mut d,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK,
+        );
+        set_driver_status(
+            &mut d,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK | DEVICE_DRIVER_OK,
+        );
+        assert!(!d.device_activated);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioDevice::new(m, Box::new(DummyDevice::new())).unwrap();
+        let mut buf = vec![0; 4];
+        set_driver_status(&mut d, DEVICE_ACKNOWLEDGE);
+        set_driver_status(&mut d, DEVICE_ACKNOWLEDGE | DEVICE_DRIVER);
+        LittleEndian::write_u32(&mut buf[..], 1);
+        d.write(0x24, &buf[..]);
+        d.write(0x20, &buf[..]);
+        activate_device(&mut d);
+        d.interrupt_status
+            .store(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+
+        let state = d.save_state();
+        assert_eq!(state.len(), STATE_HEADER_LEN + 2 * STATE_QUEUE_LEN);
+        assert_eq!(LittleEndian::read_u32(&state[4..8]), 1);
+        assert_eq!(LittleEndian::read_u32(&state[8..12]), 1);
+        assert_eq!(
+            LittleEndian::read_u32(&state[12..16]),
+            VIRTIO_MMIO_INT_VRING
+        );
+        assert_eq!(
+            LittleEndian::read_u32(&state[16..20]),
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK | DEVICE_DRIVER_OK
+        );
+        assert_eq!(LittleEndian::read_u64(&state[24..32]), 1u64 << 32);
+        assert_eq!(LittleEndian::read_u32(&state[32..36]), 1);
+        assert_eq!(LittleEndian::read_u32(&state[36..40]), 2);
+        for q in 0..2 {
+            let s = &state[STATE_HEADER_LEN + q * STATE_QUEUE_LEN..];
+            assert_eq!(LittleEndian::read_u16(&s[0..2]), 16);
+            assert_eq!(LittleEndian::read_u16(&s[2..4]), 1);
+        }
+    }
+
+    #[test]
+    fn test_restore_state() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        set_driver_status(&mut d, DEVICE_ACKNOWLEDGE);
+        let state = d.save_state();
+
+        // A device the driver didn't activate yet stays inactive.
+        let mut restored = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        assert!(restored.restore_state(&state).is_ok());
+        assert!(!restored.device_activated);
+        assert_eq!(restored.driver_status, DEVICE_ACKNOWLEDGE);
+
+        activate_device(&mut d);
+        let state = d.save_state();
+        let mut restored = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        assert!(restored.restore_state(&state).is_ok());
+        assert!(restored.device_activated);
+        assert_eq!(restored.save_state(), state);
+        // The queue events were handed over to the device.
+        assert!(restored.queue_evts().is_empty());
+
+        // Error cases: an activated transport, a truncated state and a device with other queues.
+        match restored.restore_state(&state) {
+            Err(ActivateError::BadActivate) => (),
+            _ => panic!("Expected a bad activate error."),
+        }
+        let mut restored = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        match restored.restore_state(&state[..state.len() - 1]) {
+            Err(ActivateError::BadActivate) => (),
+            _ => panic!("Expected a bad activate error."),
+        }
+        let mut restored = MmioDevice::new(m, Box::new(DummyDevice::new())).unwrap();
+        match restored.restore_state(&state[..STATE_HEADER_LEN + STATE_QUEUE_LEN]) {
+            Err(ActivateError::BadActivate) => (),
+            _ => panic!("Expected a bad activate error."),
+        }
+        assert!(!restored.device_activated);
+    }
+
+    #[test]
+    fn test_feature_mask() {
+        assert!(is_valid_feature_mask(!0));
+        assert!(!is_valid_feature_mask(!(1u64 << VIRTIO_F_VERSION_1)));
+
+        let mut dummy = DummyDevice::new();
+        dummy.avail_features = (1u64 << VIRTIO_F_VERSION_1) | 0b110;
+        // The optional feature 2 is masked off.
+        let masked = FeatureMask::new(Box::new(dummy), !(1u64 << 2));
+        assert_eq!(masked.device_type(), 123);
+        assert_eq!(masked.queue_max_sizes(), &[16, 32]);
+        assert_eq!(masked.features(0), 0b010);
+        assert_eq!(masked.features(1), 0x1);
+        assert_eq!(masked.features(2), 0);
+
+        // The guest doesn't see the masked feature.
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioDevice::new(m, Box::new(masked)).unwrap();
+        let mut buf = vec![0; 4];
+        d.features_select = 0;
+        d.read(0x10, &mut buf[..]);
+        assert_eq!(LittleEndian::read_u32(&buf[..]), 0b010);
+        d.features_select = 1;
+        d.read(0x10, &mut buf[..]);
+        assert_eq!(LittleEndian::read_u32(&buf[..]), 0x1);
+    }
 }
@@ -10,16 +10,24 @@ use std;
 use std::io::Error as IOError;
 
 pub mod block;
+pub mod console;
+#[cfg(feature = "virtio-fs")]
+pub mod fs;
 mod mmio;
 pub mod net;
 mod queue;
-#[cfg(feature = "vsock")]
+#[cfg(any(feature = "vsock", feature = "vhost-user-block"))]
 pub mod vhost;
 
 pub use self::block::*;
+pub use self::console::Console;
+#[cfg(feature = "virtio-fs")]
+pub use self::fs::Fs;
 pub use self::mmio::*;
 pub use self::net::*;
 pub use self::queue::*;
+#[cfg(feature = "vhost-user-block")]
+pub use self::vhost::user_block::VhostUserBlock;
 #[cfg(feature = "vsock")]
 pub use self::vhost::vsock::*;
 
@@ -30,26 +38,30 @@ const DEVICE_ACKNOWLEDGE: u32 = 0x01;
 const DEVICE_DRIVER: u32 = 0x02;
 const DEVICE_DRIVER_OK: u32 = 0x04;
 const DEVICE_FEATURES_OK: u32 = 0x08;
+const DEVICE_NEEDS_RESET: u32 = 0x40;
 const DEVICE_FAILED: u32 = 0x80;
 
 /// Types taken from linux/virtio_ids.h.
-const TYPE_NET: u32 = 1;
-const TYPE_BLOCK: u32 = 2;
+pub const TYPE_NET: u32 = 1;
+pub const TYPE_BLOCK: u32 = 2;
+pub const TYPE_CONSOLE: u32 = 3;
+#[cfg(feature = "virtio-fs")]
+const TYPE_FS: u32 = 26;
 
 /// Interrupt flags (re: interrupt status & acknowledge registers).
 /// See linux/virtio_mmio.h.
 pub const VIRTIO_MMIO_INT_VRING: u32 = 0x01;
 pub const VIRTIO_MMIO_INT_CONFIG: u32 = 0x02;
 
-/// Offset from the base MMIO address of a virtio device used by the guest to notify the device of
-/// queue events.
+/// Standard offset from the base MMIO address of a virtio device used by the guest to notify the
+/// device of queue events.
 pub const NOTIFY_REG_OFFSET: u32 = 0x50;
 
 #[derive(Debug)]
 pub enum ActivateError {
     EpollCtl(IOError),
     BadActivate,
-    #[cfg(feature = "vsock")]
+    #[cfg(any(feature = "vsock", feature = "vhost-user-block"))]
     BadVhostActivate(self::vhost::Error),
 }
 
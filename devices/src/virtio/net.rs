@@ -6,24 +6,27 @@
 // found in the THIRD-PARTY file.
 
 use epoll;
-use libc::EAGAIN;
+use libc::{c_uint, EAGAIN};
 use std::cmp;
+use std::collections::VecDeque;
 #[cfg(not(test))]
 use std::io::Read;
 use std::io::{self, Write};
 use std::mem;
 use std::net::Ipv4Addr;
+use std::num::NonZeroU16;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::time::Duration;
 use std::vec::Vec;
 
 use super::super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHandlerPayload, Queue, VirtioDevice, TYPE_NET,
-    VIRTIO_MMIO_INT_VRING,
+    ActivateError, ActivateResult, DescriptorChain, EpollHandlerPayload, Queue, VirtioDevice,
+    TYPE_NET, VIRTIO_MMIO_INT_VRING,
 };
 use dumbo::{ns::MmdsNetworkStack, pdu::ethernet::EthernetFrame};
 use logger::{Metric, METRICS};
@@ -32,13 +35,26 @@ use net_gen;
 use net_util::{MacAddr, Tap, TapError, MAC_ADDR_LEN};
 use rate_limiter::{RateLimiter, TokenType};
 use sys_util::EventFd;
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
 use virtio_gen::virtio_net::*;
-use {DeviceEventT, EpollHandler};
+use {DeviceEventT, DeviceHealth, EpollHandler};
 
 /// The maximum buffer size when segmentation offload is enabled. This
 /// includes the 12-byte virtio net header.
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
+/// The maximum number of frames the host side RX buffer can hold. This bounds the host memory
+/// the buffer takes to 64 MiB.
+pub const MAX_RX_BUFFER_FRAMES: usize = 1024;
+/// The MTU of the link between the guest and the MMDS network stack. The device doesn't offer
+/// `VIRTIO_NET_F_MTU`, so the guest assumes the standard Ethernet one.
+pub const MMDS_LINK_MTU: u16 = 1500;
+/// The largest MSS clamp of the MMDS connections, which leaves room for the IPv4 and TCP
+/// headers within `MMDS_LINK_MTU`.
+pub const MAX_MMDS_MSS_CLAMP: u16 = MMDS_LINK_MTU - 40;
+/// The offset of the `num_buffers` field in the virtio net header, which tells the guest how
+/// many RX buffers a frame spans when mergeable RX buffers are used.
+const VNET_HDR_NUM_BUFFERS_OFFSET: usize = 10;
 const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 2;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
@@ -53,8 +69,10 @@ const TX_QUEUE_EVENT: DeviceEventT = 2;
 const RX_RATE_LIMITER_EVENT: DeviceEventT = 3;
 // tx rate limiter budget is now available.
 const TX_RATE_LIMITER_EVENT: DeviceEventT = 4;
+// The rx interrupt coalescing timer expired.
+const RX_COALESCING_TIMER_EVENT: DeviceEventT = 5;
 // Number of DeviceEventT events supported by this implementation.
-pub const NET_EVENTS_COUNT: usize = 5;
+pub const NET_EVENTS_COUNT: usize = 6;
 
 // This is not a true DeviceEvent, as we explicitly invoke the handler with this value as a
 // parameter when the VMM handles as PATCH rate limiters request. Thus, there's not epoll event
@@ -79,6 +97,92 @@ pub enum Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Batches the interrupts signaling received frames to the guest. The guest is interrupted
+/// once `max_frames` frames are pending, or once `timeout_us` microseconds have passed since
+/// the first pending frame was delivered, whichever comes first.
+pub struct RxCoalescing {
+    max_frames: u32,
+    timeout: Duration,
+    timer_fd: TimerFd,
+    timer_armed: bool,
+    pending_frames: u32,
+}
+
+impl RxCoalescing {
+    /// Creates a new coalescing state for the RX queue.
+    ///
+    /// # Errors
+    ///
+    /// If the timerfd creation fails, an error is returned.
+    pub fn new(max_frames: u32, timeout_us: u64) -> io::Result<Self> {
+        // The timer is non-blocking, so that a stale expiration can be read without
+        // stalling the event loop.
+        let timer_fd = TimerFd::new_custom(ClockId::Monotonic, true, true)?;
+        Ok(RxCoalescing {
+            max_frames,
+            timeout: Duration::from_micros(timeout_us),
+            timer_fd,
+            timer_armed: false,
+            pending_frames: 0,
+        })
+    }
+
+    // Checks whether the pending frames warrant an interrupt right away. If they don't, the
+    // timer is armed so that the frames are signaled after the timeout at the latest.
+    fn should_signal(&mut self) -> bool {
+        if self.pending_frames >= self.max_frames {
+            self.reset();
+            return true;
+        }
+        if !self.timer_armed {
+            self.timer_fd
+                .set_state(TimerState::Oneshot(self.timeout), SetTimeFlags::Default);
+            self.timer_armed = true;
+        }
+        false
+    }
+
+    // Forgets about the pending frames and disarms the timer.
+    fn reset(&mut self) {
+        self.pending_frames = 0;
+        if self.timer_armed {
+            self.timer_fd
+                .set_state(TimerState::Disarmed, SetTimeFlags::Default);
+            self.timer_armed = false;
+        }
+    }
+}
+
+impl AsRawFd for RxCoalescing {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_fd.as_raw_fd()
+    }
+}
+
+/// Holds on the host side the frames received while the guest has no buffer available for them,
+/// so that bursts of traffic are absorbed until the guest catches up. Once the buffer is full,
+/// the frames are left in the tap device.
+pub struct RxBuffer {
+    max_frames: usize,
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl RxBuffer {
+    /// Creates a buffer holding at most `max_frames` frames, and at most
+    /// `MAX_RX_BUFFER_FRAMES` frames anyway.
+    pub fn new(max_frames: usize) -> Self {
+        let max_frames = cmp::min(max_frames, MAX_RX_BUFFER_FRAMES);
+        RxBuffer {
+            max_frames,
+            frames: VecDeque::with_capacity(max_frames),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.frames.len() >= self.max_frames
+    }
+}
+
 struct TxVirtio {
     queue_evt: EventFd,
     rate_limiter: RateLimiter,
@@ -105,18 +209,28 @@ struct RxVirtio {
     rate_limiter: RateLimiter,
     deferred_frame: bool,
     deferred_irqs: bool,
+    coalescing: Option<RxCoalescing>,
+    buffer: Option<RxBuffer>,
     queue: Queue,
     bytes_read: usize,
     frame_buf: [u8; MAX_BUFFER_SIZE],
 }
 
 impl RxVirtio {
-    fn new(queue: Queue, queue_evt: EventFd, rate_limiter: RateLimiter) -> Self {
+    fn new(
+        queue: Queue,
+        queue_evt: EventFd,
+        rate_limiter: RateLimiter,
+        coalescing: Option<RxCoalescing>,
+        buffer: Option<RxBuffer>,
+    ) -> Self {
         RxVirtio {
             queue_evt,
             rate_limiter,
             deferred_frame: false,
             deferred_irqs: false,
+            coalescing,
+            buffer,
             queue,
             bytes_read: 0,
             frame_buf: [0u8; MAX_BUFFER_SIZE],
@@ -138,6 +252,43 @@ fn frame_bytes_from_buf_mut(buf: &mut [u8]) -> &mut [u8] {
     &mut buf[vnet_hdr_len()..]
 }
 
+// Returns how many bytes the write only descriptors at the start of a chain can hold.
+fn writable_len(head: &DescriptorChain) -> usize {
+    if !head.is_write_only() {
+        return 0;
+    }
+    let mut len = head.len as usize;
+    let mut next_desc = head.next_descriptor();
+    while let Some(desc) = next_desc {
+        if !desc.is_write_only() {
+            break;
+        }
+        len += desc.len as usize;
+        next_desc = desc.next_descriptor();
+    }
+    len
+}
+
+// Returns the offloads the tap may use for the frames it hands over to the device, i.e. the
+// large segments the guest agreed to receive whole. The host segments the other ones itself.
+fn tap_offloads(acked_features: u64) -> c_uint {
+    // The segmentation offloads rely on the checksum offload.
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_CSUM) == 0 {
+        return 0;
+    }
+    let mut offloads = net_gen::TUN_F_CSUM;
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_TSO4) != 0 {
+        offloads |= net_gen::TUN_F_TSO4;
+    }
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_TSO6) != 0 {
+        offloads |= net_gen::TUN_F_TSO6;
+    }
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_UFO) != 0 {
+        offloads |= net_gen::TUN_F_UFO;
+    }
+    offloads
+}
+
 // This initializes to all 0 the VNET hdr part of a buf.
 fn init_vnet_hdr(buf: &mut [u8]) {
     // The buffer should be larger than vnet_hdr_len.
@@ -154,12 +305,10 @@ struct NetEpollHandler {
     tx: TxVirtio,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
-    // TODO(smbarber): http://crbug.com/753630
-    // Remove once MRG_RXBUF is supported and this variable is actually used.
-    #[allow(dead_code)]
     acked_features: u64,
     mmds_ns: Option<MmdsNetworkStack>,
     guest_mac: Option<MacAddr>,
+    health: DeviceHealth,
 
     #[cfg(test)]
     test_mutators: tests::TestMutators,
@@ -176,6 +325,36 @@ impl NetEpollHandler {
         })
     }
 
+    // Signals the frames received so far to the guest, unless rx interrupt coalescing is
+    // enabled and the frames can still wait for more of them to accumulate.
+    fn signal_rx_used_queue(&mut self) -> result::Result<(), DeviceError> {
+        if !self.rx.deferred_irqs {
+            return Ok(());
+        }
+        if let Some(ref mut coalescing) = self.rx.coalescing {
+            if !coalescing.should_signal() {
+                return Ok(());
+            }
+        }
+        self.rx.deferred_irqs = false;
+        self.signal_used_queue()
+    }
+
+    // Signals the frames that have been waiting for the rx interrupt coalescing timer.
+    fn handle_rx_coalescing_timer(&mut self) -> result::Result<(), DeviceError> {
+        if let Some(ref mut coalescing) = self.rx.coalescing {
+            // Consume the expiration, if any. The timer may have been disarmed meanwhile.
+            coalescing.timer_fd.read();
+            coalescing.reset();
+        }
+        if self.rx.deferred_irqs {
+            self.rx.deferred_irqs = false;
+            self.signal_used_queue()
+        } else {
+            Ok(())
+        }
+    }
+
     // Attempts to copy a single frame into the guest if there is enough
     // rate limiting budget.
     // Returns true on successful frame delivery.
@@ -216,6 +395,10 @@ impl NetEpollHandler {
     // if a buffer was used, and false if the frame must be deferred until a buffer
     // is made available by the driver.
     fn rx_single_frame(&mut self) -> bool {
+        if self.acked_features & (1 << VIRTIO_NET_F_MRG_RXBUF) != 0 {
+            return self.rx_single_frame_mergeable();
+        }
+
         let mut next_desc = self.rx.queue.iter(&self.mem).next();
 
         if next_desc.is_none() {
@@ -267,6 +450,79 @@ impl NetEpollHandler {
 
         // Mark that we have at least one pending packet and we need to interrupt the guest.
         self.rx.deferred_irqs = true;
+        if let Some(ref mut coalescing) = self.rx.coalescing {
+            coalescing.pending_frames += 1;
+        }
+
+        if write_count >= self.rx.bytes_read {
+            METRICS.net.rx_bytes_count.add(write_count);
+            METRICS.net.rx_packets_count.inc();
+            true
+        } else {
+            false
+        }
+    }
+
+    // Copies a single frame from `self.rx.frame_buf` into the guest, which accepts mergeable RX
+    // buffers, spreading it over as many descriptor chains as it takes. Returns false, leaving
+    // the chains to the driver, if the available ones can't hold the whole frame yet.
+    fn rx_single_frame_mergeable(&mut self) -> bool {
+        let mut chains = Vec::new();
+        let mut capacity = 0;
+        while capacity < self.rx.bytes_read {
+            match self.rx.queue.iter(&self.mem).next() {
+                Some(head) => {
+                    capacity += writable_len(&head);
+                    chains.push(head);
+                }
+                None => {
+                    for _ in 0..chains.len() {
+                        self.rx.queue.go_to_previous_position();
+                    }
+                    return false;
+                }
+            }
+        }
+
+        // The guest learns from the header how many chains hold the frame.
+        let num_buffers = chains.len() as u16;
+        self.rx.frame_buf[VNET_HDR_NUM_BUFFERS_OFFSET] = num_buffers as u8;
+        self.rx.frame_buf[VNET_HDR_NUM_BUFFERS_OFFSET + 1] = (num_buffers >> 8) as u8;
+
+        let mut used = Vec::with_capacity(chains.len());
+        let mut write_count = 0;
+        for head in chains {
+            let head_index = head.index;
+            let mut chain_count = 0;
+            let mut next_desc = Some(head);
+            while let Some(desc) = next_desc {
+                if !desc.is_write_only() || write_count >= self.rx.bytes_read {
+                    break;
+                }
+                let limit = cmp::min(write_count + desc.len as usize, self.rx.bytes_read);
+                let source_slice = &self.rx.frame_buf[write_count..limit];
+                match self.mem.write_slice_at_addr(source_slice, desc.addr) {
+                    Ok(sz) => {
+                        write_count += sz;
+                        chain_count += sz;
+                    }
+                    Err(e) => {
+                        error!("Failed to write slice: {:?}", e);
+                        METRICS.net.rx_fails.inc();
+                        break;
+                    }
+                }
+                next_desc = desc.next_descriptor();
+            }
+            used.push((head_index, chain_count as u32));
+        }
+        self.rx.queue.add_used_many(&self.mem, &used);
+
+        // Mark that we have at least one pending packet and we need to interrupt the guest.
+        self.rx.deferred_irqs = true;
+        if let Some(ref mut coalescing) = self.rx.coalescing {
+            coalescing.pending_frames += 1;
+        }
 
         if write_count >= self.rx.bytes_read {
             METRICS.net.rx_bytes_count.add(write_count);
@@ -342,52 +598,103 @@ impl NetEpollHandler {
         self.read_tap()
     }
 
+    // Reads the next frame to receive into `self.rx.frame_buf`. The buffered frames come before
+    // the ones still waiting in the tap.
+    fn read_next_frame(&mut self) -> io::Result<usize> {
+        if let Some(frame) = self
+            .rx
+            .buffer
+            .as_mut()
+            .and_then(|buffer| buffer.frames.pop_front())
+        {
+            self.rx.frame_buf[..frame.len()].copy_from_slice(&frame);
+            return Ok(frame.len());
+        }
+        self.read_from_mmds_or_tap()
+    }
+
+    // Checks the error of a tap read. The tap device is non-blocking, so any error aside from
+    // EAGAIN is unexpected.
+    fn check_tap_read_error(&mut self, e: &io::Error) -> result::Result<(), DeviceError> {
+        match e.raw_os_error() {
+            Some(err) if err == EAGAIN => Ok(()),
+            _ => {
+                error!("Failed to read tap: {:?}", e);
+                METRICS.net.rx_fails.inc();
+                self.health = DeviceHealth::Degraded(e.to_string());
+                Err(DeviceError::FailedReadTap)
+            }
+        }
+    }
+
+    // Moves the frame the guest couldn't receive, then the frames waiting in the tap, to the RX
+    // buffer. The tap holds on to the rest once the buffer is full, or while the rate limiter is
+    // blocked. Returns false if there is no RX buffer, in which case the frame stays deferred.
+    fn buffer_rx_frames(&mut self) -> result::Result<bool, DeviceError> {
+        let mut buffer = match self.rx.buffer.take() {
+            Some(buffer) => buffer,
+            None => return Ok(false),
+        };
+        // The frame was either read from the tap while the buffer was empty, or just taken out
+        // of the buffer, so there is room for it.
+        buffer
+            .frames
+            .push_front(self.rx.frame_buf[..self.rx.bytes_read].to_vec());
+        let mut result = Ok(());
+        while !buffer.is_full() && !self.rx.rate_limiter.is_blocked() {
+            match self.read_from_mmds_or_tap() {
+                Ok(count) => buffer.frames.push_back(self.rx.frame_buf[..count].to_vec()),
+                Err(e) => {
+                    result = self.check_tap_read_error(&e);
+                    break;
+                }
+            }
+        }
+        self.rx.buffer = Some(buffer);
+        result.map(|()| true)
+    }
+
+    // Returns whether the RX buffer holds frames waiting for the guest.
+    fn has_buffered_frames(&self) -> bool {
+        self.rx
+            .buffer
+            .as_ref()
+            .map_or(false, |buffer| !buffer.frames.is_empty())
+    }
+
     fn process_rx(&mut self) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
-            match self.read_from_mmds_or_tap() {
+            match self.read_next_frame() {
                 Ok(count) => {
                     self.rx.bytes_read = count;
                     if !self.rate_limited_rx_single_frame() {
-                        self.rx.deferred_frame = true;
+                        if !self.buffer_rx_frames()? {
+                            self.rx.deferred_frame = true;
+                        }
                         break;
                     }
                 }
                 Err(e) => {
-                    // The tap device is non-blocking, so any error aside from EAGAIN is
-                    // unexpected.
-                    match e.raw_os_error() {
-                        Some(err) if err == EAGAIN => (),
-                        _ => {
-                            error!("Failed to read tap: {:?}", e);
-                            METRICS.net.rx_fails.inc();
-                            return Err(DeviceError::FailedReadTap);
-                        }
-                    };
+                    self.check_tap_read_error(&e)?;
                     break;
                 }
             }
         }
-        if self.rx.deferred_irqs {
-            self.rx.deferred_irqs = false;
-            self.signal_used_queue()
-        } else {
-            Ok(())
-        }
+        self.signal_rx_used_queue()
     }
 
     fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
-        if self.rx.deferred_frame {
+        if self.has_buffered_frames() {
+            self.process_rx()
+        } else if self.rx.deferred_frame {
             if self.rate_limited_rx_single_frame() {
                 self.rx.deferred_frame = false;
                 // process_rx() was interrupted possibly before consuming all
                 // packets in the tap; try continuing now.
                 self.process_rx()
-            } else if self.rx.deferred_irqs {
-                self.rx.deferred_irqs = false;
-                self.signal_used_queue()
             } else {
-                Ok(())
+                self.signal_rx_used_queue()
             }
         } else {
             Ok(())
@@ -530,6 +837,10 @@ impl EpollHandler for NetEpollHandler {
                 // While limiter is blocked, don't process any more incoming.
                 if self.rx.rate_limiter.is_blocked() {
                     Ok(())
+                } else if self.has_buffered_frames() {
+                    // The guest gets the buffered frames first, and the frames it can't receive
+                    // yet are moved to the buffer.
+                    self.process_rx()
                 } else if self.rx.deferred_frame
                 // Process a deferred frame first if available. Don't read from tap again
                 // until we manage to receive this deferred frame.
@@ -537,11 +848,8 @@ impl EpollHandler for NetEpollHandler {
                     if self.rate_limited_rx_single_frame() {
                         self.rx.deferred_frame = false;
                         self.process_rx()
-                    } else if self.rx.deferred_irqs {
-                        self.rx.deferred_irqs = false;
-                        self.signal_used_queue()
                     } else {
-                        Ok(())
+                        self.signal_rx_used_queue()
                     }
                 } else {
                     self.process_rx()
@@ -596,6 +904,10 @@ impl EpollHandler for NetEpollHandler {
                     }
                 }
             }
+            RX_COALESCING_TIMER_EVENT => {
+                METRICS.net.rx_coalescing_timer_event_count.inc();
+                self.handle_rx_coalescing_timer()
+            }
             PATCH_RATE_LIMITERS_FAKE_EVENT => {
                 if let EpollHandlerPayload::NetRateLimiterPayload {
                     rx_bytes,
@@ -617,6 +929,10 @@ impl EpollHandler for NetEpollHandler {
             }),
         }
     }
+
+    fn health(&self) -> DeviceHealth {
+        self.health.clone()
+    }
 }
 
 pub struct EpollConfig {
@@ -625,6 +941,7 @@ pub struct EpollConfig {
     tx_queue_token: u64,
     rx_rate_limiter_token: u64,
     tx_rate_limiter_token: u64,
+    rx_coalescing_timer_token: u64,
     epoll_raw_fd: RawFd,
     sender: mpsc::Sender<Box<EpollHandler>>,
 }
@@ -641,6 +958,7 @@ impl EpollConfig {
             tx_queue_token: first_token + u64::from(TX_QUEUE_EVENT),
             rx_rate_limiter_token: first_token + u64::from(RX_RATE_LIMITER_EVENT),
             tx_rate_limiter_token: first_token + u64::from(TX_RATE_LIMITER_EVENT),
+            rx_coalescing_timer_token: first_token + u64::from(RX_COALESCING_TIMER_EVENT),
             epoll_raw_fd,
             sender,
         }
@@ -657,20 +975,29 @@ pub struct Net {
     epoll_config: EpollConfig,
     rx_rate_limiter: Option<RateLimiter>,
     tx_rate_limiter: Option<RateLimiter>,
+    rx_coalescing: Option<RxCoalescing>,
+    rx_buffer: Option<RxBuffer>,
     allow_mmds_requests: bool,
+    mmds_mss_clamp: Option<NonZeroU16>,
 }
 
 impl Net {
     /// Create a new virtio network device with the given TAP interface.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_with_tap(
         tap: Tap,
         guest_mac: Option<&MacAddr>,
         epoll_config: EpollConfig,
         rx_rate_limiter: Option<RateLimiter>,
         tx_rate_limiter: Option<RateLimiter>,
+        rx_coalescing: Option<RxCoalescing>,
+        rx_buffer: Option<RxBuffer>,
         allow_mmds_requests: bool,
+        mmds_mss_clamp: Option<NonZeroU16>,
+        gso_passthrough: bool,
     ) -> Result<Self> {
-        // Set offload flags to match the virtio features below.
+        // Set offload flags to match the virtio features below. Once the guest acknowledged
+        // the features, only the offloads it agreed to are kept.
         tap.set_offload(
             net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
         )
@@ -687,6 +1014,13 @@ impl Net {
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_UFO
             | 1 << VIRTIO_F_VERSION_1;
+        if gso_passthrough {
+            // The large segments, IPv6 ones included, go whole between the tap and the guest,
+            // which receives them in as many RX buffers as they take.
+            avail_features |= 1 << VIRTIO_NET_F_MRG_RXBUF
+                | 1 << VIRTIO_NET_F_GUEST_TSO6
+                | 1 << VIRTIO_NET_F_HOST_TSO6;
+        }
 
         let mut config_space;
         if let Some(mac) = guest_mac {
@@ -709,12 +1043,16 @@ impl Net {
             epoll_config,
             rx_rate_limiter,
             tx_rate_limiter,
+            rx_coalescing,
+            rx_buffer,
             allow_mmds_requests,
+            mmds_mss_clamp,
         })
     }
 
     /// Create a new virtio network device with the given IP address and
     /// netmask.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         ip_addr: Ipv4Addr,
         netmask: Ipv4Addr,
@@ -722,7 +1060,11 @@ impl Net {
         epoll_config: EpollConfig,
         rx_rate_limiter: Option<RateLimiter>,
         tx_rate_limiter: Option<RateLimiter>,
+        rx_coalescing: Option<RxCoalescing>,
+        rx_buffer: Option<RxBuffer>,
         allow_mmds_requests: bool,
+        mmds_mss_clamp: Option<NonZeroU16>,
+        gso_passthrough: bool,
     ) -> Result<Self> {
         let tap = Tap::new().map_err(Error::TapOpen)?;
         tap.set_ip_addr(ip_addr).map_err(Error::TapSetIp)?;
@@ -735,7 +1077,11 @@ impl Net {
             epoll_config,
             rx_rate_limiter,
             tx_rate_limiter,
+            rx_coalescing,
+            rx_buffer,
             allow_mmds_requests,
+            mmds_mss_clamp,
+            gso_passthrough,
         )
     }
 
@@ -836,12 +1182,19 @@ impl VirtioDevice for Net {
         }
 
         if let Some(tap) = self.tap.take() {
+            tap.set_offload(tap_offloads(self.acked_features))
+                .map_err(|e| {
+                    error!("Failed to set the offloads of the tap: {:?}", e);
+                    METRICS.net.activate_fails.inc();
+                    ActivateError::BadActivate
+                })?;
+
             let rx_queue = queues.remove(0);
             let tx_queue = queues.remove(0);
             let rx_queue_evt = queue_evts.remove(0);
             let tx_queue_evt = queue_evts.remove(0);
             let mut mmds_ns = if self.allow_mmds_requests {
-                Some(MmdsNetworkStack::new_with_defaults())
+                Some(MmdsNetworkStack::new_with_defaults(self.mmds_mss_clamp))
             } else {
                 None
             };
@@ -850,6 +1203,8 @@ impl VirtioDevice for Net {
                     rx_queue,
                     rx_queue_evt,
                     self.rx_rate_limiter.take().unwrap_or_default(),
+                    self.rx_coalescing.take(),
+                    self.rx_buffer.take(),
                 ),
                 tap,
                 mem,
@@ -863,6 +1218,7 @@ impl VirtioDevice for Net {
                 acked_features: self.acked_features,
                 mmds_ns,
                 guest_mac: self.guest_mac(),
+                health: DeviceHealth::Healthy,
 
                 #[cfg(test)]
                 test_mutators: tests::TestMutators::default(),
@@ -874,6 +1230,7 @@ impl VirtioDevice for Net {
 
             let rx_rate_limiter_rawfd = handler.rx.rate_limiter.as_raw_fd();
             let tx_rate_limiter_rawfd = handler.tx.rate_limiter.as_raw_fd();
+            let rx_coalescing_rawfd = handler.rx.coalescing.as_ref().map(|c| c.as_raw_fd());
 
             //channel should be open and working
             self.epoll_config
@@ -942,6 +1299,19 @@ impl VirtioDevice for Net {
                 .map_err(ActivateError::EpollCtl)?;
             }
 
+            if let Some(rx_coalescing_rawfd) = rx_coalescing_rawfd {
+                epoll::ctl(
+                    self.epoll_config.epoll_raw_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    rx_coalescing_rawfd,
+                    epoll::Event::new(
+                        epoll::Events::EPOLLIN,
+                        self.epoll_config.rx_coalescing_timer_token,
+                    ),
+                )
+                .map_err(ActivateError::EpollCtl)?;
+            }
+
             return Ok(());
         }
         METRICS.net.activate_fails.inc();
@@ -976,12 +1346,15 @@ mod tests {
 
     pub struct TestMutators {
         pub tap_read_fail: bool,
+        // The number of frames waiting in the tap, which never runs dry if this is None.
+        pub tap_frames: Option<usize>,
     }
 
     impl Default for TestMutators {
         fn default() -> TestMutators {
             TestMutators {
                 tap_read_fail: false,
+                tap_frames: None,
             }
         }
     }
@@ -993,7 +1366,7 @@ mod tests {
     }
 
     impl DummyNet {
-        fn new(guest_mac: Option<&MacAddr>) -> Self {
+        fn new(guest_mac: Option<&MacAddr>, gso_passthrough: bool) -> Self {
             let epoll_raw_fd = epoll::create(true).unwrap();
             let (sender, _receiver) = mpsc::channel();
             let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender);
@@ -1027,7 +1400,11 @@ mod tests {
                         )
                         .unwrap(),
                     ),
+                    None,
+                    None,
                     true,
+                    None,
+                    gso_passthrough,
                 )
                 .unwrap(),
                 epoll_raw_fd,
@@ -1066,9 +1443,15 @@ mod tests {
             }
 
             if self.test_mutators.tap_read_fail {
-                Err(io::Error::new(io::ErrorKind::Other, "oh no!"))
-            } else {
-                Ok(count)
+                return Err(io::Error::new(io::ErrorKind::Other, "oh no!"));
+            }
+            match self.test_mutators.tap_frames {
+                Some(0) => Err(io::Error::from_raw_os_error(EAGAIN)),
+                Some(ref mut frames) => {
+                    *frames -= 1;
+                    Ok(count)
+                }
+                None => Ok(count),
             }
         }
 
@@ -1119,7 +1502,7 @@ mod tests {
         mem: &'a GuestMemory,
         test_mutators: TestMutators,
     ) -> (NetEpollHandler, VirtQueue<'a>, VirtQueue<'a>) {
-        let mut dummy = DummyNet::new(None);
+        let mut dummy = DummyNet::new(None, false);
         let n = dummy.net();
 
         let rxq = VirtQueue::new(GuestAddress(0), &mem, 16);
@@ -1136,16 +1519,17 @@ mod tests {
 
         (
             NetEpollHandler {
-                rx: RxVirtio::new(rx_queue, rx_queue_evt, RateLimiter::default()),
+                rx: RxVirtio::new(rx_queue, rx_queue_evt, RateLimiter::default(), None, None),
                 tap: n.tap.take().unwrap(),
                 mem: mem.clone(),
                 tx: TxVirtio::new(tx_queue, tx_queue_evt, RateLimiter::default()),
                 interrupt_status,
                 interrupt_evt,
                 acked_features: n.acked_features,
-                mmds_ns: Some(MmdsNetworkStack::new_with_defaults()),
+                mmds_ns: Some(MmdsNetworkStack::new_with_defaults(None)),
                 test_mutators,
                 guest_mac: None,
+                health: DeviceHealth::Healthy,
             },
             txq,
             rxq,
@@ -1177,7 +1561,7 @@ mod tests {
     #[allow(clippy::cyclomatic_complexity)]
     fn test_virtio_device() {
         let mac = MacAddr::parse_str("11:22:33:44:55:66").unwrap();
-        let mut dummy = DummyNet::new(Some(&mac));
+        let mut dummy = DummyNet::new(Some(&mac), false);
         let n = dummy.net();
 
         // Test `device_type()`.
@@ -1314,6 +1698,10 @@ mod tests {
             epoll_config,
             None,
             None,
+            None,
+            None,
+            false,
+            None,
             false,
         ) {
             Err(Error::TapSetIp(_)) => (),
@@ -1334,6 +1722,10 @@ mod tests {
             epoll_config,
             None,
             None,
+            None,
+            None,
+            false,
+            None,
             false,
         ) {
             Err(Error::TapSetNetmask(_)) => (),
@@ -1520,6 +1912,7 @@ mod tests {
     fn test_read_tap_fail_event_handler() {
         let test_mutators = TestMutators {
             tap_read_fail: true,
+            ..Default::default()
         };
         let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
         let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, test_mutators);
@@ -1528,6 +1921,7 @@ mod tests {
             Err(DeviceError::FailedReadTap) => (),
             _ => panic!("invalid"),
         }
+        assert_eq!(h.health(), DeviceHealth::Degraded(String::from("oh no!")));
     }
 
     #[test]
@@ -1695,6 +2089,7 @@ mod tests {
         {
             let test_mutators = TestMutators {
                 tap_read_fail: true,
+                ..Default::default()
             };
             let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
             let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, test_mutators);
@@ -1925,6 +2320,235 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_rx_coalescing() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+
+        // Coalesce up to 4 frames, or for at most 50ms.
+        h.rx.coalescing = Some(RxCoalescing::new(4, 50_000).unwrap());
+
+        let daddr = 0x2000;
+        for i in 0..9 {
+            rxq.avail.ring[i].set(i as u16);
+            rxq.dtable[i].set(daddr + i as u64 * 0x800, 0x800, VIRTQ_DESC_F_WRITE, 0);
+        }
+
+        // Deliver a burst of 8 frames, one buffer at a time.
+        {
+            // leave at least one event here so that reading it later won't block
+            h.interrupt_evt.write(1).unwrap();
+
+            rxq.avail.idx.set(1);
+            h.handle_event(RX_TAP_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+            assert!(h.rx.deferred_frame);
+            for i in 2..9 {
+                rxq.avail.idx.set(i);
+                h.rx.queue_evt.write(1).unwrap();
+                h.handle_event(RX_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+                    .unwrap();
+            }
+            assert_eq!(rxq.used.idx.get(), 8);
+
+            // The 8 frames were signaled through only 2 interrupts.
+            assert_eq!(h.interrupt_evt.read().unwrap(), 3);
+            assert!(!h.rx.deferred_irqs);
+        }
+
+        // A lone frame is signaled once the timer expires.
+        {
+            h.interrupt_evt.write(1).unwrap();
+
+            rxq.avail.idx.set(9);
+            h.rx.queue_evt.write(1).unwrap();
+            h.handle_event(RX_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+            assert_eq!(rxq.used.idx.get(), 9);
+            assert!(h.rx.deferred_irqs);
+            assert!(h.rx.coalescing.as_ref().unwrap().timer_armed);
+
+            // wait for 50ms to let the coalescing timer expire
+            // wait for an extra 50ms to make sure the timerfd event makes its way from the kernel
+            thread::sleep(Duration::from_millis(100));
+
+            check_metric_after_block!(
+                &METRICS.net.rx_coalescing_timer_event_count,
+                1,
+                h.handle_event(RX_COALESCING_TIMER_EVENT, 0, EpollHandlerPayload::Empty)
+            );
+            assert_eq!(h.interrupt_evt.read().unwrap(), 2);
+            assert!(!h.rx.deferred_irqs);
+            assert!(!h.rx.coalescing.as_ref().unwrap().timer_armed);
+        }
+    }
+
+    #[test]
+    fn test_rx_buffer() {
+        let test_mutators = TestMutators {
+            tap_frames: Some(6),
+            ..Default::default()
+        };
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, rxq) = default_test_netepollhandler(&mem, test_mutators);
+        h.rx.buffer = Some(RxBuffer::new(3));
+        assert_eq!(
+            RxBuffer::new(usize::max_value()).max_frames,
+            MAX_RX_BUFFER_FRAMES
+        );
+
+        let daddr = 0x2000;
+        for i in 0..6 {
+            rxq.avail.ring[i].set(i as u16);
+            rxq.dtable[i].set(daddr + i as u64 * 0x800, 0x800, VIRTQ_DESC_F_WRITE, 0);
+        }
+
+        // A burst of 6 frames arrives while the guest has a single buffer. The next 3 frames
+        // are buffered, and the last 2 ones are left in the tap.
+        {
+            rxq.avail.idx.set(1);
+            h.handle_event(RX_TAP_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+            assert_eq!(rxq.used.idx.get(), 1);
+            assert!(!h.rx.deferred_frame);
+            assert_eq!(h.rx.buffer.as_ref().unwrap().frames.len(), 3);
+            assert_eq!(h.test_mutators.tap_frames, Some(2));
+        }
+
+        // The guest gets the buffered frames, then the ones in the tap, once it makes buffers
+        // available.
+        {
+            rxq.avail.idx.set(6);
+            h.rx.queue_evt.write(1).unwrap();
+            h.handle_event(RX_QUEUE_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+            assert_eq!(rxq.used.idx.get(), 6);
+            assert!(h.rx.buffer.as_ref().unwrap().frames.is_empty());
+            assert!(!h.rx.deferred_frame);
+            assert_eq!(h.test_mutators.tap_frames, Some(0));
+        }
+
+        // The tap isn't drained while the rate limiter is blocked.
+        {
+            let mut rl = RateLimiter::new(0x1000, None, 100, 0, None, 0).unwrap();
+            assert!(rl.consume(0x1000, TokenType::Bytes));
+            h.set_rx_rate_limiter(rl);
+            h.test_mutators.tap_frames = Some(3);
+            rxq.avail.idx.set(7);
+            rxq.avail.ring[6].set(0);
+
+            h.handle_event(RX_TAP_EVENT, 0, EpollHandlerPayload::Empty)
+                .unwrap();
+            assert!(h.get_rx_rate_limiter().is_blocked());
+            assert_eq!(rxq.used.idx.get(), 6);
+            assert_eq!(h.rx.buffer.as_ref().unwrap().frames.len(), 1);
+            assert_eq!(h.test_mutators.tap_frames, Some(2));
+        }
+    }
+
+    #[test]
+    fn test_mergeable_rx_buffers() {
+        // The mergeable RX buffers are only offered along with the GSO passthrough.
+        {
+            let mut dummy = DummyNet::new(None, false);
+            assert_eq!(dummy.net().features(0) & (1 << VIRTIO_NET_F_MRG_RXBUF), 0);
+
+            let mut dummy = DummyNet::new(None, true);
+            let n = dummy.net();
+            let features = n.features(0);
+            assert_ne!(features & (1 << VIRTIO_NET_F_MRG_RXBUF), 0);
+            assert_ne!(features & (1 << VIRTIO_NET_F_GUEST_TSO6), 0);
+            assert_ne!(features & (1 << VIRTIO_NET_F_HOST_TSO6), 0);
+
+            // The tap gets the offloads of the acked features when the device is activated.
+            n.ack_features(0, features);
+            assert!(activate_some_net(n, false, false).is_ok());
+        }
+
+        // The tap only hands over large segments the guest agreed to receive.
+        {
+            assert_eq!(tap_offloads(0), 0);
+            assert_eq!(tap_offloads(1 << VIRTIO_NET_F_GUEST_TSO6), 0);
+            assert_eq!(
+                tap_offloads((1 << VIRTIO_NET_F_GUEST_CSUM) | (1 << VIRTIO_NET_F_GUEST_TSO6)),
+                net_gen::TUN_F_CSUM | net_gen::TUN_F_TSO6
+            );
+            assert_eq!(
+                tap_offloads(
+                    (1 << VIRTIO_NET_F_GUEST_CSUM)
+                        | (1 << VIRTIO_NET_F_GUEST_TSO4)
+                        | (1 << VIRTIO_NET_F_GUEST_UFO)
+                ),
+                net_gen::TUN_F_CSUM | net_gen::TUN_F_TSO4 | net_gen::TUN_F_UFO
+            );
+        }
+
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        h.acked_features = 1 << VIRTIO_NET_F_MRG_RXBUF;
+
+        // Three buffers of 0x200 bytes, the second one being a chain of two descriptors.
+        let daddr = 0x2000;
+        rxq.dtable[0].set(daddr, 0x200, VIRTQ_DESC_F_WRITE, 0);
+        rxq.dtable[1].set(
+            daddr + 0x200,
+            0x100,
+            VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+            2,
+        );
+        rxq.dtable[2].set(daddr + 0x300, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        rxq.dtable[3].set(daddr + 0x400, 0x200, VIRTQ_DESC_F_WRITE, 0);
+        rxq.avail.ring[0].set(0);
+        rxq.avail.ring[1].set(1);
+        rxq.avail.ring[2].set(3);
+
+        let frame_len = 0x500;
+        for (i, byte) in h.rx.frame_buf[..frame_len].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        h.rx.bytes_read = frame_len;
+
+        // The first two buffers can't hold the frame, which waits for more buffers.
+        {
+            rxq.avail.idx.set(2);
+            assert!(!h.rx_single_frame());
+            assert_eq!(rxq.used.idx.get(), 0);
+            assert!(!h.rx.deferred_irqs);
+        }
+
+        // The frame is spread over the three buffers.
+        {
+            rxq.avail.idx.set(3);
+            assert!(h.rx_single_frame());
+            assert!(h.rx.deferred_irqs);
+            assert_eq!(rxq.used.idx.get(), 3);
+            let used: Vec<(u32, u32)> = (0..3)
+                .map(|i| {
+                    let elem = rxq.used.ring[i].get();
+                    (elem.id, elem.len)
+                })
+                .collect();
+            assert_eq!(used, vec![(0, 0x200), (1, 0x200), (3, 0x100)]);
+
+            // The header tells the guest how many buffers hold the frame.
+            assert_eq!(
+                mem.read_obj_from_addr::<u16>(GuestAddress(
+                    daddr as usize + VNET_HDR_NUM_BUFFERS_OFFSET
+                ))
+                .unwrap(),
+                3
+            );
+            // The rest of the frame is written contiguously.
+            for offset in &[0x1ff, 0x2ff, 0x300, 0x4ff] {
+                assert_eq!(
+                    mem.read_obj_from_addr::<u8>(GuestAddress(daddr as usize + *offset))
+                        .unwrap(),
+                    *offset as u8
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_patch_rate_limiters() {
         let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
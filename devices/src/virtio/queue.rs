@@ -342,25 +342,33 @@ impl Queue {
 
     /// Puts an available descriptor head into the used ring for use by the guest.
     pub fn add_used(&mut self, mem: &GuestMemory, desc_index: u16, len: u32) {
-        if desc_index >= self.actual_size() {
-            error!(
-                "attempted to add out of bounds descriptor to used ring: {}",
-                desc_index
-            );
-            return;
-        }
+        self.add_used_many(mem, &[(desc_index, len)]);
+    }
 
+    /// Puts several available descriptor heads, each with the length written to it, into the
+    /// used ring at once. The guest sees either all of them or none.
+    pub fn add_used_many(&mut self, mem: &GuestMemory, used: &[(u16, u32)]) {
         let used_ring = self.used_ring;
-        let next_used = (self.next_used.0 % self.actual_size()) as usize;
-        let used_elem = used_ring.unchecked_add(4 + next_used * 8);
+        for &(desc_index, len) in used {
+            if desc_index >= self.actual_size() {
+                error!(
+                    "attempted to add out of bounds descriptor to used ring: {}",
+                    desc_index
+                );
+                continue;
+            }
 
-        // These writes can't fail as we are guaranteed to be within the descriptor ring.
-        mem.write_obj_at_addr(u32::from(desc_index), used_elem)
-            .unwrap();
-        mem.write_obj_at_addr(len as u32, used_elem.unchecked_add(4))
-            .unwrap();
+            let next_used = (self.next_used.0 % self.actual_size()) as usize;
+            let used_elem = used_ring.unchecked_add(4 + next_used * 8);
 
-        self.next_used += Wrapping(1);
+            // These writes can't fail as we are guaranteed to be within the descriptor ring.
+            mem.write_obj_at_addr(u32::from(desc_index), used_elem)
+                .unwrap();
+            mem.write_obj_at_addr(len as u32, used_elem.unchecked_add(4))
+                .unwrap();
+
+            self.next_used += Wrapping(1);
+        }
 
         // This fence ensures all descriptor writes are visible before the index update is.
         fence(Ordering::Release);
@@ -375,6 +383,22 @@ impl Queue {
     pub fn go_to_previous_position(&mut self) {
         self.next_avail -= Wrapping(1);
     }
+
+    /// Resumes a queue restored from a snapshot at the index of its used ring. The devices put
+    /// every descriptor they take into the used ring before a snapshot is written, so none of
+    /// them is lost or served twice.
+    pub fn resume_at_used_index(&mut self, mem: &GuestMemory) {
+        let index = mem
+            .checked_offset(self.used_ring, 2)
+            .and_then(|index_addr| mem.read_obj_from_addr::<u16>(index_addr).ok());
+        match index {
+            Some(index) => {
+                self.next_avail = Wrapping(index);
+                self.next_used = Wrapping(index);
+            }
+            None => warn!("Invalid offset"),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -799,4 +823,22 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_resume_at_used_index() {
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        vq.avail.ring[3].set(2);
+        vq.avail.idx.set(4);
+        vq.used.idx.set(3);
+
+        // The restored queue serves the descriptors the driver made available since the snapshot.
+        let mut q = vq.create_queue();
+        q.resume_at_used_index(m);
+        let heads: Vec<u16> = q.iter(m).map(|d| d.index).collect();
+        assert_eq!(heads, vec![2]);
+        q.add_used(m, 2, 0x100);
+        assert_eq!(vq.used.idx.get(), 4);
+        assert_eq!(vq.used.ring[3].get().id, 2);
+    }
 }
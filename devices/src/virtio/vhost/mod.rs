@@ -10,7 +10,13 @@
 use std;
 use std::io;
 
+use super::ActivateError;
+
+#[cfg(feature = "vsock")]
 pub mod handle;
+#[cfg(feature = "vhost-user-block")]
+pub mod user_block;
+#[cfg(feature = "vsock")]
 pub mod vsock;
 
 #[derive(Debug)]
@@ -51,7 +57,24 @@ pub enum Error {
     VhostIrqCreate(io::Error),
     /// Failed to read vhost eventfd.
     VhostIrqRead(io::Error),
+    /// Set vring enable failed.
+    VhostSetVringEnable(vhost_backend::Error),
+    /// Failed to connect to the vhost-user backend.
+    VhostUserConnect(vhost_backend::Error),
+    /// Failed to negotiate the vhost-user protocol features.
+    VhostUserProtocolFeatures(vhost_backend::Error),
+    /// The vhost-user backend can't report the device configuration.
+    VhostUserConfigNotSupported,
+    /// Failed to read the device configuration from the vhost-user backend.
+    VhostUserGetConfig(vhost_backend::Error),
 }
 type Result<T> = std::result::Result<T, Error>;
 const INTERRUPT_STATUS_USED_RING: u32 = 0x1;
+#[cfg(feature = "vsock")]
 const TYPE_VSOCK: u32 = 19;
+
+impl std::convert::From<Error> for ActivateError {
+    fn from(error: Error) -> Self {
+        ActivateError::BadVhostActivate(error)
+    }
+}
@@ -0,0 +1,557 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a virtio block device whose queues are serviced by a vhost-user backend running
+//! in another process.
+
+use std::cmp;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use epoll;
+
+use super::super::super::Error as DeviceError;
+use super::super::TYPE_BLOCK;
+use super::super::{ActivateError, ActivateResult, EpollHandlerPayload, Queue, VirtioDevice};
+use super::{Error, Result, INTERRUPT_STATUS_USED_RING};
+use logger::{Metric, METRICS};
+use memory_model::GuestMemory;
+use sys_util::EventFd;
+use vhost_backend::{
+    VhostUserMaster, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+};
+use virtio_gen::virtio_blk::*;
+use virtio_gen::virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC};
+use {DeviceEventT, EpollHandler};
+
+/// Event signaled by the backend when buffers have been used.
+pub const CALL_EVENT: DeviceEventT = 0;
+/// Event on the control socket of the backend.
+pub const BACKEND_EVENT: DeviceEventT = 1;
+/// Number of DeviceEventT events supported by this implementation.
+pub const VHOST_USER_BLOCK_EVENTS_COUNT: usize = 2;
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 1;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+// Like the in-process block device, only the capacity is exposed from the configuration space.
+const CONFIG_SPACE_SIZE: u32 = 8;
+
+// The features which are forwarded from the backend to the guest. The others either need more
+// of the configuration space or are not supported by the MMIO transport.
+fn supported_features() -> u64 {
+    (1u64 << VIRTIO_F_VERSION_1)
+        | (1u64 << VIRTIO_BLK_F_RO)
+        | (1u64 << VIRTIO_BLK_F_FLUSH)
+        | (1u64 << VIRTIO_RING_F_INDIRECT_DESC)
+        | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+}
+
+pub struct EpollConfig {
+    call_token: u64,
+    backend_token: u64,
+    epoll_raw_fd: RawFd,
+    sender: mpsc::Sender<Box<EpollHandler>>,
+}
+
+impl EpollConfig {
+    pub fn new(
+        first_token: u64,
+        epoll_raw_fd: RawFd,
+        sender: mpsc::Sender<Box<EpollHandler>>,
+    ) -> Self {
+        EpollConfig {
+            call_token: first_token + u64::from(CALL_EVENT),
+            backend_token: first_token + u64::from(BACKEND_EVENT),
+            epoll_raw_fd,
+            sender,
+        }
+    }
+}
+
+/// Relays the used buffer notifications of the backend to the guest and watches the control
+/// socket for the backend going away.
+pub struct VhostUserBlockEpollHandler {
+    master: VhostUserMaster,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: EventFd,
+    call_evt: EventFd,
+    epoll_raw_fd: RawFd,
+    disconnected: bool,
+}
+
+impl VhostUserBlockEpollHandler {
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_status
+            .fetch_or(INTERRUPT_STATUS_USED_RING as usize, Ordering::SeqCst);
+        self.interrupt_evt
+            .write(1)
+            .map_err(DeviceError::FailedSignalingUsedQueue)
+    }
+
+    fn handle_backend_event(&mut self) -> result::Result<(), DeviceError> {
+        // The backend never sends unsolicited messages on the control socket, so an event there
+        // means that the backend went away. The device stops working, but the guest and the
+        // other devices are not affected.
+        if !self.disconnected {
+            error!("vhost-user-block: the backend disconnected");
+            METRICS.block.event_fails.inc();
+            self.disconnected = true;
+            // Stop watching the socket, which would otherwise keep reporting the hang up.
+            if let Err(e) = epoll::ctl(
+                self.epoll_raw_fd,
+                epoll::ControlOptions::EPOLL_CTL_DEL,
+                self.master.as_raw_fd(),
+                epoll::Event::new(epoll::Events::empty(), 0),
+            ) {
+                error!(
+                    "vhost-user-block: failed to unregister the backend socket: {}",
+                    e
+                );
+            }
+        }
+        Err(DeviceError::BackendDisconnected)
+    }
+}
+
+impl EpollHandler for VhostUserBlockEpollHandler {
+    fn handle_event(
+        &mut self,
+        device_event: DeviceEventT,
+        _: u32,
+        _: EpollHandlerPayload,
+    ) -> result::Result<(), DeviceError> {
+        match device_event {
+            CALL_EVENT => {
+                if let Err(e) = self.call_evt.read() {
+                    error!("vhost-user-block: failed reading call EventFd: {:?}", e);
+                    METRICS.block.event_fails.inc();
+                    Err(DeviceError::FailedReadingQueue {
+                        event_type: "call event",
+                        underlying: e,
+                    })
+                } else {
+                    self.signal_used_queue()
+                }
+            }
+            BACKEND_EVENT => self.handle_backend_event(),
+            other => Err(DeviceError::UnknownEvent {
+                device: "vhost-user-block",
+                event: other,
+            }),
+        }
+    }
+}
+
+/// Virtio block device which forwards its queues to a vhost-user backend.
+pub struct VhostUserBlock {
+    master: Option<VhostUserMaster>,
+    avail_features: u64,
+    acked_features: u64,
+    config_space: Vec<u8>,
+    epoll_config: EpollConfig,
+}
+
+impl VhostUserBlock {
+    /// Connects to the vhost-user backend listening at `socket_path` and queries the features
+    /// and the configuration of the device it emulates.
+    pub fn new<P: AsRef<Path>>(socket_path: P, epoll_config: EpollConfig) -> Result<Self> {
+        let mut master = VhostUserMaster::connect(socket_path).map_err(Error::VhostUserConnect)?;
+        master.set_owner().map_err(Error::VhostSetOwner)?;
+
+        let backend_features = master.get_features().map_err(Error::VhostGetFeatures)?;
+        // The capacity of the disk is only known to the backend.
+        if backend_features & VHOST_USER_F_PROTOCOL_FEATURES == 0 {
+            return Err(Error::VhostUserConfigNotSupported);
+        }
+        let protocol_features = master
+            .get_protocol_features()
+            .map_err(Error::VhostUserProtocolFeatures)?;
+        if protocol_features & VHOST_USER_PROTOCOL_F_CONFIG == 0 {
+            return Err(Error::VhostUserConfigNotSupported);
+        }
+        master
+            .set_protocol_features(VHOST_USER_PROTOCOL_F_CONFIG)
+            .map_err(Error::VhostUserProtocolFeatures)?;
+        let config_space = master
+            .get_config(0, CONFIG_SPACE_SIZE)
+            .map_err(Error::VhostUserGetConfig)?;
+
+        Ok(VhostUserBlock {
+            master: Some(master),
+            avail_features: backend_features & supported_features(),
+            acked_features: 0u64,
+            config_space,
+            epoll_config,
+        })
+    }
+
+    fn setup_backend(
+        &self,
+        master: &mut VhostUserMaster,
+        mem: &GuestMemory,
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+        call_evt: &EventFd,
+    ) -> Result<()> {
+        // The protocol features stay negotiated only if the feature bit is acknowledged.
+        master
+            .set_features(self.acked_features | VHOST_USER_F_PROTOCOL_FEATURES)
+            .map_err(Error::VhostSetFeatures)?;
+        master.set_mem_table(mem).map_err(Error::VhostSetMemTable)?;
+
+        for (queue_index, queue) in queues.iter().enumerate() {
+            master
+                .set_vring_num(queue_index, queue.actual_size())
+                .map_err(Error::VhostSetVringNum)?;
+
+            // The backend translates the addresses of this process using the memory table.
+            let desc_addr = mem.get_host_address(queue.desc_table).map_err(|e| {
+                Error::VhostSetVringAddr(vhost_backend::Error::DescriptorTableAddress(e))
+            })?;
+            let used_addr = mem
+                .get_host_address(queue.used_ring)
+                .map_err(|e| Error::VhostSetVringAddr(vhost_backend::Error::UsedAddress(e)))?;
+            let avail_addr = mem
+                .get_host_address(queue.avail_ring)
+                .map_err(|e| Error::VhostSetVringAddr(vhost_backend::Error::AvailAddress(e)))?;
+            master
+                .set_vring_addr(
+                    queue_index,
+                    desc_addr as u64,
+                    used_addr as u64,
+                    avail_addr as u64,
+                )
+                .map_err(Error::VhostSetVringAddr)?;
+
+            master
+                .set_vring_base(queue_index, 0)
+                .map_err(Error::VhostSetVringBase)?;
+            master
+                .set_vring_call(queue_index, call_evt)
+                .map_err(Error::VhostSetVringCall)?;
+            master
+                .set_vring_kick(queue_index, &queue_evts[queue_index])
+                .map_err(Error::VhostSetVringKick)?;
+            // The rings start disabled when the protocol features are negotiated.
+            master
+                .set_vring_enable(queue_index, true)
+                .map_err(Error::VhostSetVringEnable)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VhostUserBlock {
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            // Get the lower 32-bits of the features bitfield.
+            0 => self.avail_features as u32,
+            // Get the upper 32-bits of the features bitfield.
+            1 => (self.avail_features >> 32) as u32,
+            _ => {
+                warn!("Received request for unknown features page.");
+                0u32
+            }
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let mut v = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => {
+                warn!("Cannot acknowledge unknown features page.");
+                0u64
+            }
+        };
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.block.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
+        // The capacity of the disk is read-only.
+        error!("Failed to write config space");
+        METRICS.block.cfg_fails.inc();
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt_evt: EventFd,
+        status: Arc<AtomicUsize>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            METRICS.block.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+
+        let mut master = self.master.take().ok_or(ActivateError::BadActivate)?;
+        let call_evt = EventFd::new().map_err(Error::VhostIrqCreate)?;
+        if let Err(e) = self.setup_backend(&mut master, &mem, &queues, &queue_evts, &call_evt) {
+            error!("vhost-user-block: failed to set up the backend: {:?}", e);
+            METRICS.block.activate_fails.inc();
+            return Err(ActivateError::BadVhostActivate(e));
+        }
+
+        let call_evt_raw_fd = call_evt.as_raw_fd();
+        let backend_raw_fd = master.as_raw_fd();
+        let handler = VhostUserBlockEpollHandler {
+            master,
+            interrupt_status: status,
+            interrupt_evt,
+            call_evt,
+            epoll_raw_fd: self.epoll_config.epoll_raw_fd,
+            disconnected: false,
+        };
+
+        // The channel should be open at this point.
+        self.epoll_config
+            .sender
+            .send(Box::new(handler))
+            .expect("Failed to send through the channel");
+
+        epoll::ctl(
+            self.epoll_config.epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            call_evt_raw_fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, self.epoll_config.call_token),
+        )
+        .map_err(|e| {
+            METRICS.block.activate_fails.inc();
+            ActivateError::EpollCtl(e)
+        })?;
+
+        epoll::ctl(
+            self.epoll_config.epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            backend_raw_fd,
+            epoll::Event::new(
+                epoll::Events::EPOLLIN | epoll::Events::EPOLLRDHUP,
+                self.epoll_config.backend_token,
+            ),
+        )
+        .map_err(|e| {
+            METRICS.block.activate_fails.inc();
+            ActivateError::EpollCtl(e)
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::thread;
+
+    use memory_model::GuestAddress;
+    use virtio::queue::tests::VirtQueue;
+
+    const GET_FEATURES: u32 = 1;
+    const GET_PROTOCOL_FEATURES: u32 = 15;
+    const GET_CONFIG: u32 = 24;
+    const REPLY_FLAGS: u32 = 0x5;
+
+    fn read_u32(bytes: &[u8]) -> u32 {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&bytes[..4]);
+        u32::from_le_bytes(buf)
+    }
+
+    fn reply(mut sock: &UnixStream, request: u32, payload: &[u8]) {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&request.to_le_bytes());
+        msg.extend_from_slice(&REPLY_FLAGS.to_le_bytes());
+        msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        msg.extend_from_slice(payload);
+        sock.write_all(&msg).unwrap();
+    }
+
+    // A mock backend which answers the queries issued while creating the device, then closes
+    // the connection.
+    fn spawn_backend(listener: UnixListener) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            loop {
+                let mut header = [0u8; 12];
+                sock.read_exact(&mut header).unwrap();
+                let request = read_u32(&header[0..4]);
+                let mut payload = vec![0u8; read_u32(&header[8..12]) as usize];
+                sock.read_exact(&mut payload).unwrap();
+                match request {
+                    GET_FEATURES => {
+                        let features = VHOST_USER_F_PROTOCOL_FEATURES
+                            | (1u64 << VIRTIO_F_VERSION_1)
+                            | (1u64 << VIRTIO_BLK_F_FLUSH)
+                            | (1u64 << VIRTIO_BLK_F_MQ);
+                        reply(&sock, request, &features.to_le_bytes());
+                    }
+                    GET_PROTOCOL_FEATURES => {
+                        reply(&sock, request, &VHOST_USER_PROTOCOL_F_CONFIG.to_le_bytes())
+                    }
+                    GET_CONFIG => {
+                        // A capacity of 0x800 sectors.
+                        payload[12..].copy_from_slice(&0x800u64.to_le_bytes());
+                        reply(&sock, request, &payload);
+                        return;
+                    }
+                    _ => (),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_vhost_user_block() {
+        let socket_path =
+            std::env::temp_dir().join(format!("vhost_user_block_{}.sock", std::process::id()));
+        let _ = fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let backend = spawn_backend(listener);
+
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let epoll_config = EpollConfig::new(0, epoll_raw_fd, sender);
+        let mut device = VhostUserBlock::new(&socket_path, epoll_config).unwrap();
+        fs::remove_file(&socket_path).unwrap();
+        backend.join().unwrap();
+
+        assert_eq!(device.device_type(), TYPE_BLOCK);
+        assert_eq!(device.queue_max_sizes(), QUEUE_SIZES);
+        // The unsupported and transport-specific features are not exposed.
+        assert_eq!(device.features(0), 1 << VIRTIO_BLK_F_FLUSH);
+        assert_eq!(device.features(1), 1);
+        device.ack_features(0, 1 << VIRTIO_BLK_F_FLUSH | 1 << VIRTIO_BLK_F_MQ);
+        assert_eq!(device.acked_features, 1 << VIRTIO_BLK_F_FLUSH);
+
+        let mut capacity = [0u8; 8];
+        device.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 0x800);
+
+        // The backend is gone by the time the guest driver activates the device.
+        let mem = GuestMemory::new_shared(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let queues = vec![vq.create_queue()];
+        match device.activate(
+            mem.clone(),
+            EventFd::new().unwrap(),
+            Arc::new(AtomicUsize::new(0)),
+            queues.clone(),
+            vec![EventFd::new().unwrap()],
+        ) {
+            Err(ActivateError::BadVhostActivate(Error::VhostSetFeatures(
+                vhost_backend::Error::VhostUserDisconnected,
+            ))) => (),
+            _ => panic!("Expected a disconnect error."),
+        }
+        // The device can't be activated again.
+        match device.activate(
+            mem.clone(),
+            EventFd::new().unwrap(),
+            Arc::new(AtomicUsize::new(0)),
+            queues,
+            vec![EventFd::new().unwrap()],
+        ) {
+            Err(ActivateError::BadActivate) => (),
+            _ => panic!("Expected an activation error."),
+        }
+    }
+
+    #[test]
+    fn test_handler_backend_disconnect() {
+        let (master_sock, backend_sock) = UnixStream::pair().unwrap();
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        epoll::ctl(
+            epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            master_sock.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN | epoll::Events::EPOLLRDHUP, 1),
+        )
+        .unwrap();
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let mut handler = VhostUserBlockEpollHandler {
+            master: VhostUserMaster::from_stream(master_sock),
+            interrupt_status: interrupt_status.clone(),
+            interrupt_evt: EventFd::new().unwrap(),
+            call_evt: EventFd::new().unwrap(),
+            epoll_raw_fd,
+            disconnected: false,
+        };
+
+        // The used buffer notifications are relayed to the guest.
+        handler.call_evt.write(1).unwrap();
+        assert!(handler
+            .handle_event(CALL_EVENT, 0, EpollHandlerPayload::Empty)
+            .is_ok());
+        assert_eq!(
+            interrupt_status.load(Ordering::SeqCst),
+            INTERRUPT_STATUS_USED_RING as usize
+        );
+        assert_eq!(handler.interrupt_evt.read().unwrap(), 1);
+
+        // The disconnect of the backend is reported as a device error.
+        drop(backend_sock);
+        let event_fails = METRICS.block.event_fails.count();
+        match handler.handle_event(BACKEND_EVENT, 0, EpollHandlerPayload::Empty) {
+            Err(DeviceError::BackendDisconnected) => (),
+            _ => panic!("Expected a disconnect error."),
+        }
+        assert!(handler.disconnected);
+        assert!(METRICS.block.event_fails.count() > event_fails);
+        // The socket is no longer watched.
+        let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 1];
+        assert_eq!(epoll::wait(epoll_raw_fd, 0, &mut events[..]).unwrap(), 0);
+
+        match handler.handle_event(2, 0, EpollHandlerPayload::Empty) {
+            Err(DeviceError::UnknownEvent { .. }) => (),
+            _ => panic!("Expected an unknown event error."),
+        }
+    }
+}
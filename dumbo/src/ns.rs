@@ -6,7 +6,7 @@
 
 use std::convert::From;
 use std::net::Ipv4Addr;
-use std::num::NonZeroUsize;
+use std::num::{NonZeroU16, NonZeroUsize};
 use std::result::Result;
 
 use fc_util::timestamp_cycles;
@@ -71,6 +71,7 @@ impl MmdsNetworkStack {
         tcp_port: u16,
         max_connections: NonZeroUsize,
         max_pending_resets: NonZeroUsize,
+        mss_clamp: Option<NonZeroU16>,
     ) -> Self {
         MmdsNetworkStack {
             mac_addr,
@@ -82,11 +83,12 @@ impl MmdsNetworkStack {
                 tcp_port,
                 max_connections,
                 max_pending_resets,
+                mss_clamp,
             ),
         }
     }
 
-    pub fn new_with_defaults() -> Self {
+    pub fn new_with_defaults(mss_clamp: Option<NonZeroU16>) -> Self {
         // The unwrap is safe if parse_str() is implemented properly.
         let mac_addr = MacAddr::parse_str(DEFAULT_MAC_ADDR).unwrap();
         let ipv4_addr = Ipv4Addr::from(DEFAULT_IPV4_ADDR);
@@ -98,6 +100,7 @@ impl MmdsNetworkStack {
             DEFAULT_TCP_PORT,
             NonZeroUsize::new(DEFAULT_MAX_CONNECTIONS).unwrap(),
             NonZeroUsize::new(DEFAULT_MAX_PENDING_RESETS).unwrap(),
+            mss_clamp,
         )
     }
 
@@ -315,6 +318,7 @@ mod tests {
             buf: &mut [u8],
             addr: Ipv4Addr,
             flags: TcpFlags,
+            mss_option: Option<u16>,
         ) -> usize {
             let mut eth_unsized = self.prepare_eth_unsized(buf, ETHERTYPE_IPV4).unwrap();
             let packet_len = {
@@ -332,8 +336,8 @@ mod tests {
                     1234,
                     flags,
                     10000,
-                    None,
-                    0,
+                    mss_option,
+                    mss_option.unwrap_or(0),
                     None,
                 )
                 .unwrap()
@@ -356,7 +360,7 @@ mod tests {
     #[test]
     #[allow(clippy::cyclomatic_complexity)]
     fn test_ns() {
-        let mut ns = MmdsNetworkStack::new_with_defaults();
+        let mut ns = MmdsNetworkStack::new_with_defaults(None);
         assert_eq!(ns.mac_addr, MacAddr::parse_str(DEFAULT_MAC_ADDR).unwrap());
         assert_eq!(ns.ipv4_addr, Ipv4Addr::from(DEFAULT_IPV4_ADDR));
 
@@ -413,7 +417,8 @@ mod tests {
         // Let's send a TCP segment which will be rejected, because it's heading to the wrong
         // address.
         {
-            let len = ns.write_incoming_tcp_segment(buf.as_mut(), bad_mmds_addr, TcpFlags::ACK);
+            let len =
+                ns.write_incoming_tcp_segment(buf.as_mut(), bad_mmds_addr, TcpFlags::ACK, None);
             assert!(!ns.detour_frame(&buf[..len]));
 
             // Nothing to send in response.
@@ -422,7 +427,7 @@ mod tests {
 
         // Let's send a TCP segment which will cause a RST to come out of the inner TCP handler.
         {
-            let len = ns.write_incoming_tcp_segment(buf.as_mut(), mmds_addr, TcpFlags::ACK);
+            let len = ns.write_incoming_tcp_segment(buf.as_mut(), mmds_addr, TcpFlags::ACK, None);
             assert!(ns.detour_frame(&buf[..len]));
         }
 
@@ -449,7 +454,7 @@ mod tests {
 
         // Let's send a TCP SYN into the ns.
         {
-            let len = ns.write_incoming_tcp_segment(buf.as_mut(), mmds_addr, TcpFlags::SYN);
+            let len = ns.write_incoming_tcp_segment(buf.as_mut(), mmds_addr, TcpFlags::SYN, None);
             assert_eq!(ns.detour_frame(&buf[..len]), true);
         }
 
@@ -473,4 +478,51 @@ mod tests {
         // Nothing else to send.
         assert!(ns.write_next_frame(buf.as_mut()).is_none());
     }
+
+    #[test]
+    fn test_mss_clamp() {
+        let mut buf = [0u8; 2000];
+
+        // The guest advertises a MSS above the clamp, and the SYNACK brings it down.
+        let mut ns = MmdsNetworkStack::new_with_defaults(NonZeroU16::new(1200));
+        let mmds_addr = ns.ipv4_addr;
+        let len = ns.write_incoming_tcp_segment(buf.as_mut(), mmds_addr, TcpFlags::SYN, Some(1460));
+        assert!(ns.detour_frame(&buf[..len]));
+        {
+            let ip = ns.next_frame_as_ipv4_packet(buf.as_mut());
+            let s = TcpSegment::from_bytes(
+                ip.payload(),
+                Some((ip.source_address(), ip.destination_address())),
+            )
+            .unwrap();
+            assert_eq!(s.flags_after_ns(), TcpFlags::SYN | TcpFlags::ACK);
+            assert_eq!(
+                s.parse_mss_option_unchecked(s.header_len())
+                    .unwrap()
+                    .unwrap()
+                    .get(),
+                1200
+            );
+        }
+
+        // A MSS below the clamp is kept as is.
+        let mut ns = MmdsNetworkStack::new_with_defaults(NonZeroU16::new(1200));
+        let len = ns.write_incoming_tcp_segment(buf.as_mut(), mmds_addr, TcpFlags::SYN, Some(1000));
+        assert!(ns.detour_frame(&buf[..len]));
+        {
+            let ip = ns.next_frame_as_ipv4_packet(buf.as_mut());
+            let s = TcpSegment::from_bytes(
+                ip.payload(),
+                Some((ip.source_address(), ip.destination_address())),
+            )
+            .unwrap();
+            assert_eq!(
+                s.parse_mss_option_unchecked(s.header_len())
+                    .unwrap()
+                    .unwrap()
+                    .get(),
+                1000
+            );
+        }
+    }
 }
@@ -6,6 +6,7 @@
 //!
 //! [`Connection`]: struct.Connection.html
 
+use std::cmp::min;
 use std::num::{NonZeroU16, NonZeroU64, NonZeroUsize, Wrapping};
 
 use pdu::bytes::NetworkBytes;
@@ -199,6 +200,8 @@ pub struct Connection {
     send_rst: Option<RstConfig>,
     // The MSS used when sending data segments.
     mss: u16,
+    // When set, the MSS is lowered to this value if the other endpoint advertised a larger one.
+    mss_clamp: Option<NonZeroU16>,
     // If true, send an ACK segment at the first opportunity. ACKs can piggyback data segments, so
     // we'll only send an empty ACK segment if we can't transmit any data.
     pending_ack: bool,
@@ -216,6 +219,13 @@ fn parse_mss_option<T: NetworkBytes>(segment: &TcpSegment<T>) -> Result<u16, Pas
     }
 }
 
+fn clamp_mss(mss: u16, mss_clamp: Option<NonZeroU16>) -> u16 {
+    match mss_clamp {
+        Some(clamp) => min(mss, clamp.get()),
+        None => mss,
+    }
+}
+
 fn is_valid_syn<T: NetworkBytes>(segment: &TcpSegment<T>) -> bool {
     segment.flags_after_ns() == TcpFlags::SYN && segment.payload_len() == 0
 }
@@ -231,11 +241,15 @@ impl Connection {
     ///   the first segment which has not been acknowledged yet. This uses an opaque time unit.
     /// * `rto_count_max` - How many consecutive timeout-based retransmission may occur before
     ///   the connection resets itself.
+    /// * `mss_clamp` - When set, an upper bound on the MSS, which is then lower than the value
+    ///   advertised by the `SYN` whenever the latter is larger. The `SYNACK` advertises the
+    ///   clamped value.
     pub fn passive_open<T: NetworkBytes>(
         segment: &TcpSegment<T>,
         local_rwnd_size: u32,
         rto_period: NonZeroU64,
         rto_count_max: NonZeroU16,
+        mss_clamp: Option<NonZeroU16>,
     ) -> Result<Self, PassiveOpenError> {
         // We don't accepting anything other than a SYN segment here.
         if !is_valid_syn(segment) {
@@ -246,7 +260,7 @@ impl Connection {
         // remote_rwnd_edge below.
 
         // We only care about the MSS option for now.
-        let mss = parse_mss_option(segment)?;
+        let mss = clamp_mss(parse_mss_option(segment)?, mss_clamp);
 
         // This is going to get sent on the SYNACK.
         let ack_to_send = Wrapping(segment.sequence_number()) + Wrapping(1);
@@ -273,6 +287,7 @@ impl Connection {
             send_fin: None,
             send_rst: None,
             mss,
+            mss_clamp,
             pending_ack: false,
             dup_ack: false,
             status_flags: ConnStatusFlags::SYN_RECEIVED,
@@ -324,7 +339,7 @@ impl Connection {
         }
 
         match parse_mss_option(segment) {
-            Ok(mss) if mss == self.mss => true,
+            Ok(mss) if clamp_mss(mss, self.mss_clamp) == self.mss => true,
             _ => false,
         }
     }
@@ -1053,6 +1068,7 @@ pub(crate) mod tests {
         remote_window_size: u16,
         pub mss: u16,
         pub mss_reserved: u16,
+        pub mss_clamp: Option<NonZeroU16>,
         local_rwnd_size: u32,
         remote_isn: u32,
         pub rto_period: u64,
@@ -1069,6 +1085,7 @@ pub(crate) mod tests {
                 remote_window_size: 11000,
                 mss: 1100,
                 mss_reserved: 0,
+                mss_clamp: None,
                 local_rwnd_size: 10000,
                 remote_isn: 12_345_678,
                 rto_period: 100_000,
@@ -1086,6 +1103,7 @@ pub(crate) mod tests {
                 self.local_rwnd_size,
                 NonZeroU64::new(self.rto_period).unwrap(),
                 NonZeroU16::new(self.rto_count_max).unwrap(),
+                self.mss_clamp,
             )
         }
 
@@ -1198,7 +1216,7 @@ pub(crate) mod tests {
             let payload_src = Some((send_buf.as_ref(), c.highest_ack_received));
             let remote_isn = self.remote_isn;
             let conn_isn = c.first_not_sent.0.wrapping_sub(1);
-            let mss = self.mss;
+            let mss = clamp_mss(self.mss, self.mss_clamp);
 
             let s = self.write_next_segment(c, payload_src).unwrap().unwrap();
             // The MSS option is 4 bytes long.
@@ -1207,7 +1225,8 @@ pub(crate) mod tests {
             assert_eq!(s.sequence_number(), conn_isn);
             assert_eq!(s.ack_number(), remote_isn.wrapping_add(1));
 
-            // Our implementation mirrors the received value of the MSS option.
+            // Our implementation mirrors the received value of the MSS option, unless it's above
+            // the clamp.
             assert_eq!(parse_mss_option(&s).unwrap(), mss);
 
             check_synack_sent(c);
@@ -1792,6 +1811,29 @@ pub(crate) mod tests {
         assert!(c.is_done());
     }
 
+    #[test]
+    fn test_mss_clamp() {
+        let mut t = ConnectionTester::new();
+        let mut buf = [0u8; 100];
+        let mut syn = t.write_syn(buf.as_mut());
+        syn.set_flags_after_ns(TcpFlags::SYN);
+
+        // The SYN advertises a MSS above the clamp.
+        t.mss_clamp = NonZeroU16::new(500);
+        let mut c = t.passive_open(&syn).unwrap();
+        assert_eq!(c.mss, 500);
+        // A retransmission of the SYN is still recognized as such.
+        assert!(c.is_same_syn(&syn));
+        // The SYNACK advertises the clamped MSS.
+        t.check_synack_is_next(&mut c);
+
+        // A clamp above the advertised MSS doesn't change it.
+        t.mss_clamp = NonZeroU16::new(1460);
+        let c = t.passive_open(&syn).unwrap();
+        assert_eq!(c.mss, t.mss);
+        assert!(c.is_same_syn(&syn));
+    }
+
     #[test]
     fn test_xor_rng_u32() {
         for _ in 0..1000 {
@@ -11,6 +11,7 @@
 // components, but since the separation/interface is not very well defined yet, we keep the
 // Endpoint in here too for the time being.
 
+use std::net::Ipv4Addr;
 use std::num::{NonZeroU16, NonZeroU64, Wrapping};
 
 use fc_util::timestamp_cycles;
@@ -51,6 +52,9 @@ pub struct Endpoint {
     response_seq: Wrapping<u32>,
     // The TCP connection that does all the receiving/sending work.
     connection: Connection,
+    // Address of the guest interface the connection comes from, which selects the MMDS
+    // namespace the requests are served from.
+    remote_addr: Ipv4Addr,
     // Timestamp (in cycles) associated with the most recent reception of a segment.
     last_segment_received_timestamp: u64,
     // These many time units have to pass since receiving the last segment to make the current
@@ -77,10 +81,12 @@ pub struct Endpoint {
 
 impl Endpoint {
     pub fn new<T: NetworkBytes>(
+        remote_addr: Ipv4Addr,
         segment: &TcpSegment<T>,
         eviction_threshold: NonZeroU64,
         connection_rto_period: NonZeroU64,
         connection_rto_count_max: NonZeroU16,
+        mss_clamp: Option<NonZeroU16>,
     ) -> Result<Self, PassiveOpenError> {
         // TODO: mention this in doc comment for function
         // This simplifies things, and is a very reasonable assumption.
@@ -91,6 +97,7 @@ impl Endpoint {
             RCV_BUF_MAX_SIZE as u32,
             connection_rto_period,
             connection_rto_count_max,
+            mss_clamp,
         )?;
 
         Ok(Endpoint {
@@ -102,6 +109,7 @@ impl Endpoint {
             // the SYNACK. It might stop working like that if/when the implementation changes.
             response_seq: connection.first_not_sent(),
             connection,
+            remote_addr,
             last_segment_received_timestamp: timestamp_cycles(),
             eviction_threshold: eviction_threshold.get(),
             stop_receiving: false,
@@ -109,14 +117,18 @@ impl Endpoint {
     }
 
     pub fn new_with_defaults<T: NetworkBytes>(
+        remote_addr: Ipv4Addr,
         segment: &TcpSegment<T>,
+        mss_clamp: Option<NonZeroU16>,
     ) -> Result<Self, PassiveOpenError> {
         // The unwraps are safe because the constants are greater than 0.
         Self::new(
+            remote_addr,
             segment,
             NonZeroU64::new(EVICTION_THRESHOLD).unwrap(),
             NonZeroU64::new(CONNECTION_RTO_PERIOD).unwrap(),
             NonZeroU16::new(CONNECTION_RTO_COUNT_MAX).unwrap(),
+            mss_clamp,
         )
     }
 
@@ -194,7 +206,7 @@ impl Endpoint {
                         };
 
                         // We found a potential request, let's parse it.
-                        let response = parse_request(&b[..end]);
+                        let response = parse_request(self.remote_addr, &b[..end]);
                         // The unwrap is safe because a Vec will allocate more space until all the
                         // writes succeed.
                         response.write_all(&mut self.response_buf).unwrap();
@@ -313,20 +325,21 @@ mod tests {
         let mut write_buf = [0u8; RCV_BUF_MAX_SIZE + 100];
 
         let mut t = ConnectionTester::new();
+        let remote_addr = Ipv4Addr::new(169, 254, 0, 2);
 
         let mut syn = t.write_syn(buf1.as_mut());
 
         // Put another flag on the SYN so it becomes invalid.
         syn.set_flags_after_ns(TcpFlags::ACK);
         assert_eq!(
-            Endpoint::new_with_defaults(&syn).unwrap_err(),
+            Endpoint::new_with_defaults(remote_addr, &syn, None).unwrap_err(),
             PassiveOpenError::InvalidSyn
         );
 
         // Fix the SYN and create an endpoint.
         syn.set_flags_after_ns(TcpFlags::SYN);
         let remote_isn = syn.sequence_number();
-        let mut e = Endpoint::new_with_defaults(&syn).unwrap();
+        let mut e = Endpoint::new_with_defaults(remote_addr, &syn, None).unwrap();
 
         // Let's complete the three-way handshake. The next segment sent by the endpoint should
         // be a SYNACK.
@@ -7,7 +7,7 @@
 
 use std::collections::{HashMap, HashSet};
 use std::net::Ipv4Addr;
-use std::num::NonZeroUsize;
+use std::num::{NonZeroU16, NonZeroUsize};
 
 use pdu::bytes::NetworkBytes;
 use pdu::ipv4::{Error as IPv4PacketError, IPv4Packet, PROTOCOL_TCP};
@@ -137,6 +137,8 @@ pub struct TcpIPv4Handler {
     rst_queue: Vec<(ConnectionTuple, RstConfig)>,
     // Maximum size of the RST queue.
     max_pending_resets: usize,
+    // Upper bound on the MSS of the new connections, if any.
+    mss_clamp: Option<NonZeroU16>,
 }
 
 // Only used locally, in the receive_packet method, to differentiate between different outcomes
@@ -153,13 +155,16 @@ impl TcpIPv4Handler {
     ///
     /// The handler acts as if bound to `local_addr`:`local_port`, and will accept at most
     /// `max_connections` concurrent connections. `RST` segments generated by unexpected incoming
-    /// segments are placed in a queue which is at most `max_pending_resets` long.
+    /// segments are placed in a queue which is at most `max_pending_resets` long. When
+    /// `mss_clamp` is set, the connections use it as MSS whenever the `SYN` advertised a larger
+    /// one.
     #[inline]
     pub fn new(
         local_addr: Ipv4Addr,
         local_port: u16,
         max_connections: NonZeroUsize,
         max_pending_resets: NonZeroUsize,
+        mss_clamp: Option<NonZeroU16>,
     ) -> Self {
         let max_connections = max_connections.get();
         let max_pending_resets = max_pending_resets.get();
@@ -172,6 +177,7 @@ impl TcpIPv4Handler {
             next_timeout: None,
             rst_queue: Vec::with_capacity(max_pending_resets),
             max_pending_resets,
+            mss_clamp,
         }
     }
 
@@ -228,7 +234,11 @@ impl TcpIPv4Handler {
                 Ok(RecvEvent::Nothing)
             }
             RecvSegmentOutcome::NewConnection => {
-                let endpoint = match Endpoint::new_with_defaults(&segment) {
+                let endpoint = match Endpoint::new_with_defaults(
+                    tuple.remote_addr,
+                    &segment,
+                    self.mss_clamp,
+                ) {
                     Ok(endpoint) => endpoint,
                     Err(_) => return Ok(RecvEvent::FailedNewConnection),
                 };
@@ -565,6 +575,7 @@ mod tests {
             local_port,
             NonZeroUsize::new(max_connections).unwrap(),
             NonZeroUsize::new(max_pending_resets).unwrap(),
+            None,
         );
 
         // We start with a wrong destination address and destination port to check those error
@@ -3,6 +3,9 @@
 
 extern crate libc;
 
+use std::io;
+
+pub mod process_title;
 pub mod validators;
 
 pub fn timestamp_cycles() -> u64 {
@@ -29,6 +32,33 @@ pub fn now_cputime_us() -> u64 {
     timespec_to_us(&time_struct)
 }
 
+/// Fills `buf` with random bytes from the host CSPRNG, as `/dev/urandom` would, without
+/// requiring access to the device file.
+pub fn get_random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        // Safe because the kernel writes at most `buf.len() - filled` bytes past `filled`, and
+        // we check the result.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_getrandom,
+                buf[filled..].as_mut_ptr(),
+                buf.len() - filled,
+                0,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        } else {
+            filled += ret as usize;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -40,6 +70,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_get_random_bytes() {
+        let mut first = [0u8; 32];
+        let mut second = [0u8; 32];
+        get_random_bytes(&mut first).unwrap();
+        get_random_bytes(&mut second).unwrap();
+        assert!(first.iter().any(|&b| b != 0));
+        assert_ne!(first, second);
+
+        let mut empty = [];
+        assert!(get_random_bytes(&mut empty).is_ok());
+    }
+
     #[test]
     fn test_now_cputime_us() {
         for _ in 0..1000 {
@@ -0,0 +1,107 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for changing how the process shows up in tools such as `ps` and `top`.
+
+use std::ffi::CString;
+use std::{cmp, fs, io, slice};
+
+/// Maximum length of a thread name, not counting the terminating null byte.
+pub const MAX_THREAD_NAME_LEN: usize = 15;
+
+// Positions of `arg_start` and `arg_end` among the fields from `/proc/self/stat` which follow
+// the executable name. See `man 5 proc`.
+const ARG_START_FIELD: usize = 45;
+const ARG_END_FIELD: usize = 46;
+
+/// Sets the name of the calling thread, which is what `/proc/<pid>/comm` shows for the main
+/// thread. Names longer than `MAX_THREAD_NAME_LEN` bytes are truncated.
+pub fn set_thread_name(name: &str) -> io::Result<()> {
+    let name: Vec<u8> = name.bytes().take(MAX_THREAD_NAME_LEN).collect();
+    let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // Safe because `c_name` is a valid null-terminated string and we check the return value.
+    let ret = unsafe { libc::prctl(libc::PR_SET_NAME, c_name.as_ptr() as libc::c_ulong, 0, 0, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Overwrites the command line of the process, which is what `/proc/<pid>/cmdline` and `ps`
+/// show, with `title`. The title is truncated to the length of the original command line.
+///
+/// The original arguments are lost, so `std::env::args()` must not be relied on afterwards.
+pub fn set_cmdline(title: &str) -> io::Result<()> {
+    let (arg_start, arg_end) = cmdline_bounds()?;
+    if arg_end <= arg_start {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid command line bounds.",
+        ));
+    }
+
+    // Safe because the kernel reports [arg_start, arg_end) as the memory holding the arguments
+    // of this process, which is mapped and writable for its whole lifetime.
+    let cmdline = unsafe { slice::from_raw_parts_mut(arg_start as *mut u8, arg_end - arg_start) };
+    // Keep the last byte for the terminating null byte.
+    let len = cmp::min(title.len(), cmdline.len() - 1);
+    cmdline[..len].copy_from_slice(&title.as_bytes()[..len]);
+    for byte in cmdline[len..].iter_mut() {
+        *byte = 0;
+    }
+    Ok(())
+}
+
+// Returns the bounds of the memory area which holds the command line arguments.
+fn cmdline_bounds() -> io::Result<(usize, usize)> {
+    let stat = fs::read_to_string("/proc/self/stat")?;
+    // The executable name is in parentheses and may contain spaces, so the fields are counted
+    // from the last closing parenthesis.
+    let fields: Vec<&str> = stat
+        .rsplitn(2, ')')
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .collect();
+    let parse_field = |index: usize| -> io::Result<usize> {
+        fields
+            .get(index)
+            .and_then(|field| field.parse::<usize>().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Cannot parse the command line bounds.",
+                )
+            })
+    };
+    Ok((parse_field(ARG_START_FIELD)?, parse_field(ARG_END_FIELD)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_thread_name() {
+        assert!(set_thread_name("firecracker-instance").is_ok());
+        // Tests don't run on the main thread, so look at the name of the current thread rather
+        // than the one of the process.
+        assert_eq!(
+            fs::read_to_string("/proc/thread-self/comm").unwrap(),
+            "firecracker-ins\n"
+        );
+
+        // Error case: the name holds a null byte.
+        assert!(set_thread_name("fire\0cracker").is_err());
+    }
+
+    #[test]
+    fn test_set_cmdline() {
+        let (arg_start, arg_end) = cmdline_bounds().unwrap();
+        assert!(arg_start < arg_end);
+
+        assert!(set_cmdline("firecracker test-id").is_ok());
+        let cmdline = fs::read("/proc/self/cmdline").unwrap();
+        assert!(cmdline.starts_with(b"firecracker test-id"));
+    }
+}
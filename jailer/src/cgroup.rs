@@ -20,11 +20,27 @@ const CPUSET_MEMS: &str = "cpuset.mems";
 
 const CONTROLLER_PIDS: &str = "pids";
 
+// The memory controller is only needed when a memory limit is set.
+const CONTROLLER_MEMORY: &str = "memory";
+const MEMORY_LIMIT_V1: &str = "memory.limit_in_bytes";
+const MEMORY_LIMIT_V2: &str = "memory.max";
+
+// Files of the cgroup v2 hierarchy.
+const CGROUP_V2_CONTROLLERS: &str = "cgroup.controllers";
+const CGROUP_V2_PROCS: &str = "cgroup.procs";
+const CGROUP_V2_SUBTREE_CONTROL: &str = "cgroup.subtree_control";
+
 // The list of cgroup controllers we're interested in.
 const CONTROLLERS: [&str; 3] = [CONTROLLER_CPU, CONTROLLER_CPUSET, CONTROLLER_PIDS];
 const PROC_MOUNTS: &str = "/proc/mounts";
 const NODE_TO_CPULIST: &str = "/sys/devices/system/node/node";
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CgroupVersion {
+    V1,
+    V2,
+}
+
 pub struct Cgroup {
     tasks_files: Vec<PathBuf>,
 }
@@ -125,8 +141,52 @@ fn inherit_from_parent(path: &mut PathBuf, file_name: &str) -> Result<()> {
     inherit_from_parent_aux(path, file_name, true)
 }
 
+// Writes the hard memory limit of the cgroup at `path`. Once the processes of the cgroup use more
+// memory than that, and it cannot be reclaimed, the kernel OOM killer kills one of them and
+// reports it in the kernel log.
+fn write_memory_limit(path: &Path, version: CgroupVersion, limit_mib: u64) -> Result<()> {
+    let knob = match version {
+        CgroupVersion::V1 => MEMORY_LIMIT_V1,
+        CgroupVersion::V2 => MEMORY_LIMIT_V2,
+    };
+    writeln_special(&path.join(knob), limit_mib.saturating_mul(1 << 20))
+}
+
+// Sets up the cgroup of the memory controller at `<base>/<exec_file_name>/<id>`, and returns the
+// file to which the pid has to be written.
+fn memory_cgroup(
+    base: &Path,
+    version: CgroupVersion,
+    exec_file_name: &OsStr,
+    id: &str,
+    limit_mib: u64,
+) -> Result<PathBuf> {
+    let path_buf = base.join(exec_file_name).join(id);
+    fs::create_dir_all(&path_buf).map_err(|e| Error::CreateDir(path_buf.clone(), e))?;
+
+    if version == CgroupVersion::V2 {
+        // With cgroup v2, the controller has to be enabled for the children of every ancestor.
+        writeln_special(&base.join(CGROUP_V2_SUBTREE_CONTROL), "+memory")?;
+        writeln_special(
+            &base.join(exec_file_name).join(CGROUP_V2_SUBTREE_CONTROL),
+            "+memory",
+        )?;
+    }
+    write_memory_limit(&path_buf, version, limit_mib)?;
+
+    Ok(path_buf.join(match version {
+        CgroupVersion::V1 => "tasks",
+        CgroupVersion::V2 => CGROUP_V2_PROCS,
+    }))
+}
+
 impl Cgroup {
-    pub fn new(id: &str, numa_node: u32, exec_file_name: &OsStr) -> Result<Self> {
+    pub fn new(
+        id: &str,
+        numa_node: u32,
+        exec_file_name: &OsStr,
+        memory_limit_mib: Option<u64>,
+    ) -> Result<Self> {
         let f =
             File::open(PROC_MOUNTS).map_err(|e| Error::FileOpen(PathBuf::from(PROC_MOUNTS), e))?;
 
@@ -137,12 +197,23 @@ impl Cgroup {
         let re = Regex::new(
             r"^(cgroup|none)[[:space:]](?P<dir>.*)[[:space:]]cgroup[[:space:]](?P<options>.*)[[:space:]]0[[:space:]]0$",
         ).map_err(Error::RegEx)?;
+        let re_v2 = Regex::new(r"^cgroup2[[:space:]](?P<dir>.*)[[:space:]]cgroup2[[:space:]]")
+            .map_err(Error::RegEx)?;
+        let mut memory_v1_dir = None;
+        let mut unified_dir = None;
+
         for l in BufReader::new(f).lines() {
             let l = l.map_err(|e| Error::ReadLine(PathBuf::from(PROC_MOUNTS), e))?;
-            if let Some(capture) = re.captures(&l) {
+            if let Some(capture) = re_v2.captures(&l) {
+                unified_dir = Some(PathBuf::from(&capture["dir"]));
+            } else if let Some(capture) = re.captures(&l) {
                 // We could do the search in a more efficient manner but eh.
                 let v: Vec<&str> = capture["options"].split(',').collect();
 
+                if v.contains(&CONTROLLER_MEMORY) {
+                    memory_v1_dir = Some(PathBuf::from(&capture["dir"]));
+                }
+
                 for controller in CONTROLLERS.iter() {
                     if v.contains(controller)
                         && found_controllers
@@ -222,6 +293,33 @@ impl Cgroup {
             }
         }
 
+        if let Some(limit_mib) = memory_limit_mib {
+            // The memory controller is looked up in the cgroup v1 hierarchies first, since a
+            // controller can only be used by one of the versions at a time.
+            let unified_dir = unified_dir.filter(|dir| {
+                readln_special(&dir.join(CGROUP_V2_CONTROLLERS))
+                    .map(|line| line.split(' ').any(|c| c == CONTROLLER_MEMORY))
+                    .unwrap_or(false)
+            });
+            let (version, base) = match (memory_v1_dir, unified_dir) {
+                (Some(dir), _) => (CgroupVersion::V1, dir),
+                (None, Some(dir)) => (CgroupVersion::V2, dir),
+                (None, None) => {
+                    return Err(Error::CgroupLineNotFound(
+                        PROC_MOUNTS.to_string(),
+                        CONTROLLER_MEMORY.to_string(),
+                    ))
+                }
+            };
+            tasks_files.push(memory_cgroup(
+                &base,
+                version,
+                exec_file_name,
+                id,
+                limit_mib,
+            )?);
+        }
+
         Ok(Cgroup { tasks_files })
     }
 
@@ -284,4 +382,64 @@ mod tests {
         let res = readln_special(&child_file).expect("Cannot read from file.");
         assert!(res == some_line);
     }
+
+    #[test]
+    fn test_write_memory_limit() {
+        let dir = tempdir().expect("Cannot create temporary directory.");
+
+        write_memory_limit(dir.path(), CgroupVersion::V1, 16).unwrap();
+        assert_eq!(
+            readln_special(&dir.path().join("memory.limit_in_bytes")).unwrap(),
+            "16777216"
+        );
+        assert!(!dir.path().join("memory.max").exists());
+
+        write_memory_limit(dir.path(), CgroupVersion::V2, 16).unwrap();
+        assert_eq!(
+            readln_special(&dir.path().join("memory.max")).unwrap(),
+            "16777216"
+        );
+
+        // Error case: the cgroup does not exist.
+        let result = write_memory_limit(&dir.path().join("inexistent"), CgroupVersion::V2, 16);
+        assert!(format!("{:?}", result).contains("Write"));
+    }
+
+    #[test]
+    fn test_memory_cgroup() {
+        let dir = tempdir().expect("Cannot create temporary directory.");
+        let exec_file_name = OsStr::new("firecracker");
+
+        let tasks_file =
+            memory_cgroup(dir.path(), CgroupVersion::V1, exec_file_name, "id1", 64).unwrap();
+        let cgroup_dir = dir.path().join("firecracker").join("id1");
+        assert_eq!(tasks_file, cgroup_dir.join("tasks"));
+        assert_eq!(
+            readln_special(&cgroup_dir.join("memory.limit_in_bytes")).unwrap(),
+            "67108864"
+        );
+
+        let tasks_file =
+            memory_cgroup(dir.path(), CgroupVersion::V2, exec_file_name, "id2", 64).unwrap();
+        let cgroup_dir = dir.path().join("firecracker").join("id2");
+        assert_eq!(tasks_file, cgroup_dir.join("cgroup.procs"));
+        assert_eq!(
+            readln_special(&cgroup_dir.join("memory.max")).unwrap(),
+            "67108864"
+        );
+        // The memory controller is enabled for the children of the ancestors.
+        assert_eq!(
+            readln_special(&dir.path().join("cgroup.subtree_control")).unwrap(),
+            "+memory"
+        );
+        assert_eq!(
+            readln_special(
+                &dir.path()
+                    .join("firecracker")
+                    .join("cgroup.subtree_control")
+            )
+            .unwrap(),
+            "+memory"
+        );
+    }
 }
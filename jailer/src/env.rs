@@ -3,7 +3,7 @@
 
 use std::ffi::CStr;
 use std::fs::{self, canonicalize, File};
-use std::os::unix::io::IntoRawFd;
+use std::os::unix::io::{IntoRawFd, RawFd};
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
@@ -14,6 +14,8 @@ use libc;
 use cgroup::Cgroup;
 use chroot::chroot;
 use fc_util::validators;
+use seccomp::SECCOMP_LEVEL_NONE;
+use seccomp_filter;
 use sys_util::SyscallReturnCode;
 use {Error, Result};
 
@@ -36,6 +38,23 @@ fn dup2(old_fd: libc::c_int, new_fd: libc::c_int) -> Result<()> {
         .map_err(Error::Dup2)
 }
 
+// Returns the file descriptor flags of `fd`, failing if it is not open.
+fn fd_flags(fd: RawFd) -> Result<libc::c_int> {
+    // Safe because the fd is only queried, and we check the result.
+    SyscallReturnCode(unsafe { libc::fcntl(fd, libc::F_GETFD) })
+        .into_result()
+        .map_err(|e| Error::PassFd(fd, e))
+}
+
+// Clears the close-on-exec flag of `fd`, so that it stays open in the exec-ed process.
+fn clear_cloexec(fd: RawFd) -> Result<()> {
+    let flags = fd_flags(fd)?;
+    // Safe because only the flags of the fd are changed, and we check the result.
+    SyscallReturnCode(unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) })
+        .into_empty_result()
+        .map_err(|e| Error::PassFd(fd, e))
+}
+
 // Extracts an argument's value or returns a specific error if the argument is missing.
 fn get_value<'a>(args: &'a ArgMatches, arg_name: &'static str) -> Result<&'a str> {
     args.value_of(arg_name)
@@ -52,6 +71,8 @@ pub struct Env {
     netns: Option<String>,
     daemonize: bool,
     seccomp_level: u32,
+    memory_limit_mib: Option<u64>,
+    passed_fds: Vec<RawFd>,
     start_time_us: u64,
     start_time_cpu_us: u64,
 }
@@ -114,6 +135,28 @@ impl Env {
             .parse::<u32>()
             .map_err(Error::SeccompLevel)?;
 
+        let memory_limit_mib = match args.value_of("memory_limit") {
+            Some(s) => match s.parse::<u64>() {
+                Ok(limit) if limit > 0 => Some(limit),
+                _ => return Err(Error::MemoryLimit(String::from(s))),
+            },
+            None => None,
+        };
+
+        let mut passed_fds = Vec::new();
+        if let Some(values) = args.values_of("pass_fd") {
+            for s in values {
+                // The standard I/O file descriptors are always inherited.
+                let fd = match s.parse::<RawFd>() {
+                    Ok(fd) if fd > STDERR_FILENO => fd,
+                    _ => return Err(Error::InvalidFd(String::from(s))),
+                };
+                // Fail early if the file descriptor is not open.
+                fd_flags(fd)?;
+                passed_fds.push(fd);
+            }
+        }
+
         Ok(Env {
             id: id.to_string(),
             numa_node,
@@ -124,6 +167,8 @@ impl Env {
             netns,
             daemonize,
             seccomp_level,
+            memory_limit_mib,
+            passed_fds,
             start_time_us,
             start_time_cpu_us,
         })
@@ -133,6 +178,10 @@ impl Env {
         self.chroot_dir.as_path()
     }
 
+    pub fn passed_fds(&self) -> &[RawFd] {
+        &self.passed_fds
+    }
+
     pub fn gid(&self) -> u32 {
         self.gid
     }
@@ -230,7 +279,12 @@ impl Env {
         }
 
         // We have to setup cgroups at this point, because we can't do it anymore after chrooting.
-        let cgroup = Cgroup::new(self.id.as_str(), self.numa_node, exec_file_name)?;
+        let cgroup = Cgroup::new(
+            self.id.as_str(),
+            self.numa_node,
+            exec_file_name,
+            self.memory_limit_mib,
+        )?;
         cgroup.attach_pid()?;
 
         // If daemonization was requested, open /dev/null before chrooting.
@@ -295,6 +349,16 @@ impl Env {
                 .map_err(Error::CloseDevNullFd)?;
         }
 
+        for fd in &self.passed_fds {
+            clear_cloexec(*fd)?;
+        }
+
+        // Firecracker installs its own seccomp filters during startup, so this one also covers
+        // the syscalls made before that.
+        if self.seccomp_level != SECCOMP_LEVEL_NONE {
+            seccomp_filter::install()?;
+        }
+
         Err(Error::Exec(
             Command::new(chroot_exec_file)
                 .arg(format!("--id={}", self.id))
@@ -316,6 +380,8 @@ impl Env {
 mod tests {
     use super::*;
 
+    use std::os::unix::io::AsRawFd;
+
     use clap_app;
 
     #[allow(clippy::too_many_arguments)]
@@ -465,6 +531,89 @@ mod tests {
 
         // The chroot-base-dir param is not validated by Env::new, but rather in run, when we
         // actually attempt to create the folder structure (the same goes for netns).
+        assert!(good_env.memory_limit_mib.is_none());
+    }
+
+    // Creates an environment with valid mandatory arguments, followed by `extra_args`.
+    fn make_env(extra_args: &[&str]) -> Result<Env> {
+        let mut arg_vec = vec![
+            "jailer",
+            "--node",
+            "0",
+            "--id",
+            "bd65600d-8669-4903-8a14-af88203add38",
+            "--exec-file",
+            "/proc/cpuinfo",
+            "--uid",
+            "1001",
+            "--gid",
+            "1002",
+            "--chroot-base-dir",
+            "/",
+        ];
+        arg_vec.extend_from_slice(extra_args);
+
+        Env::new(clap_app().get_matches_from_safe(arg_vec).unwrap(), 0, 0)
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let env_with_limit = |limit: &str| make_env(&["--memory-limit", limit]);
+
+        assert_eq!(env_with_limit("128").unwrap().memory_limit_mib, Some(128));
+        for limit in &["0", "1.5", "128MiB"] {
+            match env_with_limit(limit) {
+                Err(Error::MemoryLimit(ref s)) => assert_eq!(s, limit),
+                _ => panic!("The memory limit {:?} should be rejected.", limit),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pass_fd() {
+        // Files are opened with the close-on-exec flag set. The fd is moved to a high number, so
+        // that it is not reused by the processes spawned below.
+        let file = File::open("/proc/cpuinfo").unwrap();
+        // Safe because we check the result.
+        let fd = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 500) };
+        assert!(fd >= 500);
+        let fd_str = fd.to_string();
+
+        let env = make_env(&["--pass-fd", &fd_str]).unwrap();
+        assert_eq!(env.passed_fds(), &[fd]);
+
+        let is_fd_inherited = || {
+            Command::new("test")
+                .arg("-e")
+                .arg(format!("/proc/self/fd/{}", fd))
+                .status()
+                .unwrap()
+                .success()
+        };
+        assert!(!is_fd_inherited());
+        clear_cloexec(fd).unwrap();
+        assert!(is_fd_inherited());
+
+        // Safe because the fd is owned by this test.
+        unsafe { libc::close(fd) };
+
+        // Error case: the fd is no longer open.
+        match make_env(&["--pass-fd", &fd_str]) {
+            Err(Error::PassFd(err_fd, ref err)) => {
+                assert_eq!(err_fd, fd);
+                assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+            }
+            _ => panic!("Passing a closed fd should fail."),
+        }
+        assert!(clear_cloexec(fd).is_err());
+
+        // Error case: invalid fd numbers.
+        for fd_str in &["2", "foo"] {
+            match make_env(&["--pass-fd", fd_str]) {
+                Err(Error::InvalidFd(ref s)) => assert_eq!(s, fd_str),
+                _ => panic!("The fd {:?} should be rejected.", fd_str),
+            }
+        }
     }
 
     #[test]
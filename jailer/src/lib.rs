@@ -7,16 +7,19 @@ extern crate libc;
 extern crate regex;
 
 extern crate fc_util;
+extern crate seccomp;
 extern crate sys_util;
 
 mod cgroup;
 mod chroot;
 mod env;
+mod seccomp_filter;
 
 use std::ffi::{CString, NulError, OsString};
 use std::fmt;
 use std::fs;
 use std::io;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::result;
 
@@ -47,7 +50,9 @@ pub enum Error {
     FromBytesWithNul(&'static [u8]),
     GetOldFdFlags(io::Error),
     Gid(String),
+    InvalidFd(String),
     InvalidInstanceId(validators::Error),
+    MemoryLimit(String),
     MissingArgument(&'static str),
     MissingParent(PathBuf),
     MkdirOldRoot(io::Error),
@@ -58,11 +63,13 @@ pub enum Error {
     NumaNode(String),
     OpenDevNull(io::Error),
     OsStringParsing(PathBuf, OsString),
+    PassFd(RawFd, io::Error),
     PivotRoot(io::Error),
     ReadLine(PathBuf, io::Error),
     ReadToString(PathBuf, io::Error),
     RegEx(regex::Error),
     RmOldRootDir(io::Error),
+    Seccomp(seccomp::Error),
     SeccompLevel(std::num::ParseIntError),
     SetCurrentDir(io::Error),
     SetNetNs(io::Error),
@@ -138,7 +145,9 @@ impl fmt::Display for Error {
             }
             GetOldFdFlags(ref err) => write!(f, "Failed to get flags from fd: {}", err),
             Gid(ref gid) => write!(f, "Invalid gid: {}", gid),
+            InvalidFd(ref fd) => write!(f, "Invalid file descriptor: {}", fd),
             InvalidInstanceId(ref err) => write!(f, "Invalid instance ID: {}", err),
+            MemoryLimit(ref limit) => write!(f, "Invalid memory limit: {}", limit),
             MissingArgument(ref arg) => write!(f, "Missing argument: {}", arg),
             MissingParent(ref path) => write!(
                 f,
@@ -173,6 +182,11 @@ impl fmt::Display for Error {
                 "{}",
                 format!("Failed to parse path {:?} into an OsString", path).replace("\"", "")
             ),
+            PassFd(fd, ref err) => write!(
+                f,
+                "Failed to pass file descriptor {} to the exec-ed process: {}",
+                fd, err
+            ),
             PivotRoot(ref err) => write!(f, "Failed to pivot root: {}", err),
             ReadLine(ref path, ref err) => write!(
                 f,
@@ -186,6 +200,7 @@ impl fmt::Display for Error {
             ),
             RegEx(ref err) => write!(f, "Regex failed: {:?}", err),
             RmOldRootDir(ref err) => write!(f, "Failed to remove old jail root directory: {}", err),
+            Seccomp(ref err) => write!(f, "Failed to install the seccomp filter: {}", err),
             SeccompLevel(ref err) => write!(f, "Failed to parse seccomp level: {:?}", err),
             SetCurrentDir(ref err) => write!(f, "Failed to change current directory: {}", err),
             SetNetNs(ref err) => write!(f, "Failed to join network namespace: netns: {}", err),
@@ -282,6 +297,28 @@ pub fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .required(false)
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("memory_limit")
+                .long("memory-limit")
+                .help(
+                    "Hard limit, in MiB, for the memory used by the microVM. The kernel OOM \
+                     killer stops the microVM when the limit is exceeded.",
+                )
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pass_fd")
+                .long("pass-fd")
+                .help(
+                    "File descriptor which is kept open in the exec-ed process, so that it can \
+                     use resources opened beforehand. Can be repeated.",
+                )
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
         .arg(
             Arg::with_name("seccomp-level")
                 .long("seccomp-level")
@@ -300,9 +337,9 @@ pub fn clap_app<'a, 'b>() -> App<'a, 'b> {
         )
 }
 
-fn sanitize_process() {
+fn sanitize_process(passed_fds: &[RawFd]) {
     // First thing to do is make sure we don't keep any inherited FDs
-    // other that IN, OUT and ERR.
+    // other that IN, OUT and ERR, and the ones passed on to the exec-ed process.
     if let Ok(paths) = fs::read_dir("/proc/self/fd") {
         for maybe_path in paths {
             if maybe_path.is_err() {
@@ -313,7 +350,7 @@ fn sanitize_process() {
             let fd_str = file_name.to_str().unwrap_or("0");
             let fd = fd_str.parse::<i32>().unwrap_or(0);
 
-            if fd > 2 {
+            if fd > 2 && !passed_fds.contains(&fd) {
                 // Safe because close() cannot fail when passed a valid parameter.
                 unsafe { libc::close(fd) };
             }
@@ -328,10 +365,12 @@ pub fn run(args: ArgMatches, start_time_us: u64, start_time_cpu_us: u64) -> Resu
     // TODO: can a malicious guest that takes over firecracker use its access to the KVM fd to
     // starve the host of resources? (cgroups should take care of that, but do they currently?)
 
-    sanitize_process();
-
+    // Parsing the arguments does not open any file descriptors, and it checks that the passed
+    // ones are open before the others get closed.
     let env = Env::new(args, start_time_us, start_time_cpu_us)?;
 
+    sanitize_process(env.passed_fds());
+
     // Ensure the folder exists.
     fs::create_dir_all(env.chroot_dir())
         .map_err(|e| Error::CreateDir(env.chroot_dir().to_owned(), e))?;
@@ -372,7 +411,7 @@ mod tests {
             fds.push(maybe_file.unwrap().as_raw_fd());
         }
 
-        sanitize_process();
+        sanitize_process(&[]);
 
         for fd in fds {
             let is_fd_opened = unsafe { libc::fcntl(fd, libc::F_GETFD) } == 0;
@@ -508,6 +547,14 @@ mod tests {
             ),
             "Invalid instance ID: invalid char (a) at position 1",
         );
+        assert_eq!(
+            format!("{}", Error::InvalidFd("foo".to_string())),
+            "Invalid file descriptor: foo",
+        );
+        assert_eq!(
+            format!("{}", Error::MemoryLimit("0".to_string())),
+            "Invalid memory limit: 0",
+        );
         assert_eq!(
             format!("{}", Error::MissingArgument(id)),
             "Missing argument: foobar",
@@ -556,6 +603,11 @@ mod tests {
             ),
             "Failed to parse path /foo/bar into an OsString",
         );
+        assert_eq!(
+            format!("{}", Error::PassFd(42, io::Error::from_raw_os_error(9))),
+            "Failed to pass file descriptor 42 to the exec-ed process: Bad file descriptor \
+             (os error 9)",
+        );
         assert_eq!(
             format!("{}", Error::PivotRoot(io::Error::from_raw_os_error(42))),
             "Failed to pivot root: No message of desired type (os error 42)",
@@ -582,6 +634,11 @@ mod tests {
             format!("{}", Error::RmOldRootDir(io::Error::from_raw_os_error(42))),
             "Failed to remove old jail root directory: No message of desired type (os error 42)",
         );
+        assert_eq!(
+            format!("{}", Error::Seccomp(seccomp::Error::Load(libc::EPERM))),
+            "Failed to install the seccomp filter: Failed to load seccomp rules into the kernel \
+             with error 1.",
+        );
         assert_eq!(
             format!("{}", Error::SeccompLevel(err_parse.clone())),
             "Failed to parse seccomp level: ParseIntError { kind: Overflow }",
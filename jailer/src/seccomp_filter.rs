@@ -0,0 +1,109 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::result;
+
+use libc;
+use seccomp::{self, SeccompAction, SeccompFilter, SeccompRule};
+
+use {Error, Result};
+
+// Syscalls which Firecracker never needs. They administer the host, change the namespaces or
+// the root of the process, or inspect other processes.
+const DENIED_SYSCALLS: &[i64] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_adjtimex,
+    libc::SYS_bpf,
+    libc::SYS_chroot,
+    libc::SYS_clock_adjtime,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_kexec_file_load,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_personality,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_quotactl,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setns,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_syslog,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+];
+
+// Builds a filter which fails the denied syscalls with `EPERM` and allows all the others.
+fn jailer_filter() -> result::Result<SeccompFilter, seccomp::Error> {
+    let rules: BTreeMap<i64, Vec<SeccompRule>> = DENIED_SYSCALLS
+        .iter()
+        .map(|&syscall| {
+            (
+                syscall,
+                vec![SeccompRule::new(
+                    vec![],
+                    SeccompAction::Errno(libc::EPERM as u32),
+                )],
+            )
+        })
+        .collect();
+    SeccompFilter::new(rules, SeccompAction::Allow)
+}
+
+/// Installs the jailer seccomp filter on the calling thread. The filter is kept across exec, so
+/// it guards Firecracker before it installs its own filter. Seccomp filters stack, so the filter
+/// of Firecracker can only restrict the allowed syscalls further.
+pub fn install() -> Result<()> {
+    jailer_filter()
+        .and_then(SeccompFilter::apply)
+        .map_err(Error::Seccomp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+    use std::thread;
+
+    #[test]
+    fn test_install() {
+        // Seccomp filters only apply to the thread installing them, so the rest of the tests
+        // are not affected.
+        thread::spawn(|| {
+            // Safe because this only queries the execution domain of the thread.
+            let query_personality = || unsafe { libc::personality(0xffff_ffff) };
+            assert!(query_personality() >= 0);
+
+            install().unwrap();
+            assert_eq!(query_personality(), -1);
+            assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+            // The syscalls which aren't denied still work.
+            assert!(thread::Builder::new().spawn(|| ()).unwrap().join().is_ok());
+
+            // A filter installed later on, like the one of Firecracker, cannot loosen it.
+            SeccompFilter::new(BTreeMap::new(), SeccompAction::Allow)
+                .unwrap()
+                .apply()
+                .unwrap();
+            assert_eq!(query_personality(), -1);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -63,6 +63,43 @@ fn valid_str(s: &str) -> Result<()> {
     }
 }
 
+// Splits `s` around its first standalone `--`, returning the kernel parameters before it and the
+// init arguments after it.
+fn split_init_args(s: &str) -> (&str, Option<&str>) {
+    for (i, _) in s.match_indices("--") {
+        let (before, after) = (&s[..i], &s[i + 2..]);
+        if (before.is_empty() || before.ends_with(' '))
+            && (after.is_empty() || after.starts_with(' '))
+        {
+            return (before.trim_end(), Some(after.trim_start()));
+        }
+    }
+    (s, None)
+}
+
+// Splits the kernel parameters `s` around the spaces which are not quoted.
+fn split_params(s: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ' ' if !quoted => {
+                if i > start {
+                    params.push(&s[start..i]);
+                }
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if start < s.len() {
+        params.push(&s[start..]);
+    }
+    params
+}
+
 fn valid_element(s: &str) -> Result<()> {
     if !s.chars().all(valid_char) {
         Err(Error::InvalidAscii)
@@ -77,10 +114,15 @@ fn valid_element(s: &str) -> Result<()> {
 
 /// A builder for a kernel command line string that validates the string as its being built. A
 /// `CString` can be constructed from this directly using `CString::new`.
+///
+/// The kernel parameters always come first. The init arguments follow them, after a `--`
+/// separator, no matter in which order they were inserted.
 #[derive(Clone)]
 pub struct Cmdline {
     line: String,
     capacity: usize,
+    // The kernel parameters are the first `kernel_args_len` bytes of `line`.
+    kernel_args_len: usize,
 }
 
 impl Cmdline {
@@ -91,6 +133,7 @@ impl Cmdline {
         Cmdline {
             line: String::with_capacity(capacity),
             capacity,
+            kernel_args_len: 0,
         }
     }
 
@@ -109,6 +152,31 @@ impl Cmdline {
         }
     }
 
+    fn has_init_args(&self) -> bool {
+        self.kernel_args_len < self.line.len()
+    }
+
+    // Inserts `arg` at the end of the kernel parameters, before the init arguments.
+    fn insert_kernel_arg(&mut self, arg: &str) -> Result<()> {
+        self.has_capacity(arg.len())?;
+
+        if !self.has_init_args() {
+            self.start_push();
+            self.line.push_str(arg);
+            self.kernel_args_len = self.line.len();
+        } else if self.kernel_args_len == 0 {
+            self.line.insert_str(0, &format!("{} ", arg));
+            self.kernel_args_len = arg.len();
+        } else {
+            self.line
+                .insert_str(self.kernel_args_len, &format!(" {}", arg));
+            self.kernel_args_len += arg.len() + 1;
+        }
+        self.end_push();
+
+        Ok(())
+    }
+
     fn end_push(&mut self) {
         // This assert is always true because of the `has_capacity` check that each insert method
         // uses.
@@ -132,31 +200,78 @@ impl Cmdline {
 
         valid_element(k)?;
         valid_element(v)?;
-        self.has_capacity(k.len() + v.len() + 1)?;
-
-        self.start_push();
-        self.line.push_str(k);
-        self.line.push('=');
-        self.line.push_str(v);
-        self.end_push();
 
-        Ok(())
+        self.insert_kernel_arg(&format!("{}={}", k, v))
     }
 
-    /// Validates and inserts a string to the end of the current command line.
+    /// Validates and inserts a string to the end of the kernel parameters. What follows a
+    /// standalone `--` in the string is inserted to the end of the init arguments instead.
     pub fn insert_str<T: AsRef<str>>(&mut self, slug: T) -> Result<()> {
         let s = slug.as_ref();
         valid_str(s)?;
 
-        self.has_capacity(s.len())?;
+        match split_init_args(s) {
+            (kernel_args, Some(init_args)) => {
+                // Either both parts fit, or the command line stays as it is.
+                let mut cmdline = self.clone();
+                if !kernel_args.is_empty() {
+                    cmdline.insert_kernel_arg(kernel_args)?;
+                }
+                if !init_args.is_empty() {
+                    cmdline.insert_init_arg(init_args)?;
+                }
+                *self = cmdline;
+                Ok(())
+            }
+            (kernel_args, None) => self.insert_kernel_arg(kernel_args),
+        }
+    }
+
+    /// Validates and inserts a string to the end of the arguments the kernel passes to init,
+    /// which follow the kernel parameters after a `--` separator.
+    pub fn insert_init_arg<T: AsRef<str>>(&mut self, arg: T) -> Result<()> {
+        let s = arg.as_ref();
+        valid_str(s)?;
 
-        self.start_push();
+        if self.has_init_args() {
+            self.has_capacity(s.len())?;
+            self.line.push(' ');
+        } else {
+            self.has_capacity(s.len() + 3)?;
+            self.start_push();
+            self.line.push_str("-- ");
+        }
         self.line.push_str(s);
         self.end_push();
 
         Ok(())
     }
 
+    /// Removes the kernel parameters named `key`, whether they have a value or not. The init
+    /// arguments are left as they are.
+    pub fn remove<T: AsRef<str>>(&mut self, key: T) {
+        let key = key.as_ref();
+        let key_eq = format!("{}=", key);
+
+        let (kernel_args, init_args) = self.line.split_at(self.kernel_args_len);
+        let mut line = split_params(kernel_args)
+            .into_iter()
+            .filter(|param| *param != key && !param.starts_with(&key_eq))
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let kernel_args_len = line.len();
+        let init_args = init_args.trim_start();
+        if !init_args.is_empty() {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(init_args);
+        }
+
+        self.line = line;
+        self.kernel_args_len = kernel_args_len;
+    }
+
     /// Returns the cmdline in progress without nul termination.
     pub fn as_str(&self) -> &str {
         self.line.as_str()
@@ -236,6 +351,104 @@ mod tests {
         assert_eq!(cl.as_str(), cl.as_cstring().unwrap().to_str().unwrap());
     }
 
+    #[test]
+    fn insert_init_args() {
+        let mut cl = Cmdline::new(100);
+        assert!(cl.insert_init_arg("single").is_ok());
+        assert_eq!(cl.as_str(), "-- single");
+        assert!(cl.insert("console", "ttyS0").is_ok());
+        assert_eq!(cl.as_str(), "console=ttyS0 -- single");
+
+        let mut cl = Cmdline::new(100);
+        assert!(cl.insert("console", "ttyS0").is_ok());
+        assert!(cl.insert_str("reboot=k panic=1").is_ok());
+        assert!(cl.insert_init_arg("single").is_ok());
+        assert!(cl.insert_init_arg("foo=bar").is_ok());
+        // The kernel parameters inserted later still go before the separator.
+        assert!(cl.insert("root", "/dev/vda").is_ok());
+        assert!(cl.insert_str("ro").is_ok());
+        assert_eq!(
+            cl.as_str(),
+            "console=ttyS0 reboot=k panic=1 root=/dev/vda ro -- single foo=bar"
+        );
+        assert_eq!(cl.as_str(), cl.as_cstring().unwrap().to_str().unwrap());
+
+        assert_eq!(cl.insert_init_arg("💖"), Err(Error::InvalidAscii));
+    }
+
+    #[test]
+    fn insert_string_with_init_args() {
+        let mut cl = Cmdline::new(100);
+        assert!(cl.insert_str("reboot=k -- single").is_ok());
+        assert!(cl.insert_str("--log-level=debug -- --verbose").is_ok());
+        assert!(cl.insert("root", "/dev/vda").is_ok());
+        assert!(cl.insert_str("-- quiet").is_ok());
+        assert_eq!(
+            cl.as_str(),
+            "reboot=k --log-level=debug root=/dev/vda -- single --verbose quiet"
+        );
+
+        assert_eq!(split_init_args("a=b"), ("a=b", None));
+        assert_eq!(split_init_args("a=b --"), ("a=b", Some("")));
+        assert_eq!(
+            split_init_args("a=--b -- c -- d"),
+            ("a=--b", Some("c -- d"))
+        );
+    }
+
+    #[test]
+    fn insert_init_args_too_large() {
+        let mut cl = Cmdline::new(10);
+        assert!(cl.insert("a", "b").is_ok());
+        // " -- " takes 4 bytes.
+        assert_eq!(cl.insert_init_arg("cde"), Err(Error::TooLarge));
+        assert!(cl.insert_init_arg("cd").is_ok());
+        assert_eq!(cl.as_str(), "a=b -- cd");
+        assert_eq!(cl.insert_init_arg("e"), Err(Error::TooLarge));
+
+        // Nothing is inserted when only a part of the string fits.
+        let mut cl = Cmdline::new(10);
+        assert_eq!(cl.insert_str("a=b -- cdef"), Err(Error::TooLarge));
+        assert_eq!(cl.as_str(), "");
+    }
+
+    #[test]
+    fn remove() {
+        let mut cl = Cmdline::new(100);
+        assert!(cl
+            .insert_str("console=ttyS0 a=\"console=b c\" console 8250.nr_uarts=0 -- console=d")
+            .is_ok());
+        cl.remove("console");
+        assert_eq!(
+            cl.as_str(),
+            "a=\"console=b c\" 8250.nr_uarts=0 -- console=d"
+        );
+        cl.remove("8250.nr_uarts");
+        assert_eq!(cl.as_str(), "a=\"console=b c\" -- console=d");
+        // Only whole keys match.
+        cl.remove("a=");
+        cl.remove("con");
+        assert_eq!(cl.as_str(), "a=\"console=b c\" -- console=d");
+
+        // The kernel parameters still come before the init arguments.
+        cl.remove("a");
+        assert_eq!(cl.as_str(), "-- console=d");
+        assert!(cl.insert("e", "f").is_ok());
+        assert_eq!(cl.as_str(), "e=f -- console=d");
+        cl.remove("e");
+        assert!(cl.insert_init_arg("g").is_ok());
+        assert_eq!(cl.as_str(), "-- console=d g");
+
+        let mut cl = Cmdline::new(100);
+        cl.remove("a");
+        assert!(cl.is_empty());
+        assert!(cl.insert_str("a b").is_ok());
+        cl.remove("a");
+        assert_eq!(cl.as_str(), "b");
+        assert!(cl.insert("c", "d").is_ok());
+        assert_eq!(cl.as_str(), "b c=d");
+    }
+
     #[test]
     fn insert_too_large() {
         let mut cl = Cmdline::new(4);
@@ -20,6 +20,7 @@ pub const ELFMAG0: ::std::os::raw::c_uint = 127;
 
 pub const ELFDATA2LSB: ::std::os::raw::c_uint = 1;
 pub const PT_LOAD: ::std::os::raw::c_uint = 1;
+pub const PT_NOTE: ::std::os::raw::c_uint = 4;
 
 pub const ELFMAG1: u8 = b'E';
 pub const ELFMAG2: u8 = b'L';
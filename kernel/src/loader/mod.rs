@@ -5,7 +5,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-//! Helper for loading a kernel image in the guest memory.
+//! Helper for loading a kernel image and an initrd in the guest memory.
 
 use std;
 use std::ffi::CString;
@@ -21,65 +21,153 @@ use sys_util;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 // Add here any other architecture that uses as kernel image an ELF file.
 mod elf;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod pvh;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use self::pvh::{
+    find_pvh_entry, hvm_memmap_table_entry, hvm_start_info, load_pvh_start_info,
+    XEN_HVM_START_MAGIC_VALUE,
+};
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
     BigEndianElfOnLittle,
+    InitrdSegmentPastRamEnd(usize),
     InvalidElfMagicNumber,
     InvalidEntryAddress,
+    InvalidLoadAddress,
     InvalidProgramHeaderSize,
     InvalidProgramHeaderOffset,
     InvalidProgramHeaderAddress,
+    InvalidPvhNote,
+    KernelPastRamEnd,
+    LoadAddressConflict(usize),
     ReadElfHeader,
+    ReadInitrdSegment(usize),
     ReadKernelImage,
+    ReadNoteHeader,
     ReadProgramHeader,
+    SeekInitrdSegment(usize),
     SeekKernelStart,
     SeekKernelImage,
+    SeekNoteHeader,
     SeekProgramHeader,
+    StartInfoPastRamEnd,
+    StartInfoSetup,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match *self {
-                Error::BigEndianElfOnLittle => "Unsupported ELF File byte order",
-                Error::InvalidElfMagicNumber => "Invalid ELF magic number",
-                Error::InvalidEntryAddress => "Invalid entry address found in ELF header",
-                Error::InvalidProgramHeaderSize => "Invalid ELF program header size",
-                Error::InvalidProgramHeaderOffset => "Invalid ELF program header offset",
-                Error::InvalidProgramHeaderAddress => "Invalid ELF program header address",
-                Error::ReadElfHeader => "Failed to read ELF header",
-                Error::ReadKernelImage => "Failed to write kernel image to guest memory",
-                Error::ReadProgramHeader => "Failed to read ELF program header",
-                Error::SeekKernelStart => {
-                    "Failed to seek to file offset as pointed by the ELF program header"
-                }
-                Error::SeekKernelImage => "Failed to seek to offset of kernel image",
-                Error::SeekProgramHeader => "Failed to seek to ELF program header",
+        match *self {
+            Error::BigEndianElfOnLittle => write!(f, "Unsupported ELF File byte order"),
+            Error::InitrdSegmentPastRamEnd(index) => write!(
+                f,
+                "Initrd segment {} does not fit in the guest memory",
+                index
+            ),
+            Error::InvalidElfMagicNumber => write!(f, "Invalid ELF magic number"),
+            Error::InvalidEntryAddress => write!(f, "Invalid entry address found in ELF header"),
+            Error::InvalidLoadAddress => write!(
+                f,
+                "Kernel load address is not page aligned or is below the kernel start"
+            ),
+            Error::InvalidProgramHeaderSize => write!(f, "Invalid ELF program header size"),
+            Error::InvalidProgramHeaderOffset => write!(f, "Invalid ELF program header offset"),
+            Error::InvalidProgramHeaderAddress => {
+                write!(f, "Invalid ELF program header address")
             }
-        )
+            Error::InvalidPvhNote => write!(f, "Invalid PVH entry point ELF note"),
+            Error::KernelPastRamEnd => write!(f, "Kernel does not fit in the guest memory"),
+            Error::LoadAddressConflict(addr) => write!(
+                f,
+                "Kernel overlaps the reserved guest memory range starting at {:#x}",
+                addr
+            ),
+            Error::ReadElfHeader => write!(f, "Failed to read ELF header"),
+            Error::ReadInitrdSegment(index) => write!(
+                f,
+                "Failed to write initrd segment {} to guest memory",
+                index
+            ),
+            Error::ReadKernelImage => write!(f, "Failed to write kernel image to guest memory"),
+            Error::ReadNoteHeader => write!(f, "Failed to read ELF note"),
+            Error::ReadProgramHeader => write!(f, "Failed to read ELF program header"),
+            Error::SeekInitrdSegment(index) => {
+                write!(f, "Failed to seek in initrd segment {}", index)
+            }
+            Error::SeekKernelStart => write!(
+                f,
+                "Failed to seek to file offset as pointed by the ELF program header"
+            ),
+            Error::SeekKernelImage => write!(f, "Failed to seek to offset of kernel image"),
+            Error::SeekNoteHeader => write!(f, "Failed to seek to ELF note"),
+            Error::SeekProgramHeader => write!(f, "Failed to seek to ELF program header"),
+            Error::StartInfoPastRamEnd => {
+                write!(f, "PVH start info does not fit in the guest memory")
+            }
+            Error::StartInfoSetup => write!(f, "Failed to write PVH start info to guest memory"),
+        }
     }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-/// Loads a kernel from a vmlinux elf image to a slice
-///
-/// # Arguments
-///
-/// * `guest_mem` - The guest memory region the kernel is written to.
-/// * `kernel_image` - Input vmlinux image.
-/// * `start_address` - For x86_64, this is the start of the high memory. Kernel should reside above it.
-///
-/// Returns the entry address of the kernel.
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-pub fn load_kernel<F>(
+/// The kernel load address override must be aligned to a page.
+const KERNEL_LOAD_ALIGNMENT: usize = 0x1000;
+
+/// Places the kernel at an explicit guest address instead of its default load address.
+#[derive(Debug)]
+pub struct KernelLoadOverride<'a> {
+    /// Guest address at which the lowest byte of the kernel is loaded.
+    pub address: GuestAddress,
+    /// Guest address ranges, given as start and size, which the kernel must not overlap, such
+    /// as the MMIO window or the initrd.
+    pub reserved: &'a [(GuestAddress, usize)],
+}
+
+// Checks that a kernel of `size` bytes can be loaded at the override address.
+fn check_load_override(
     guest_mem: &GuestMemory,
-    kernel_image: &mut F,
     start_address: usize,
-) -> Result<GuestAddress>
+    load_override: &KernelLoadOverride,
+    size: usize,
+) -> Result<()> {
+    let start = load_override.address.offset();
+    if start % KERNEL_LOAD_ALIGNMENT != 0 || start < start_address {
+        return Err(Error::InvalidLoadAddress);
+    }
+    // Both the first and the last byte of the kernel must be in the guest memory.
+    if !guest_mem.address_in_range(load_override.address)
+        || guest_mem
+            .checked_offset(load_override.address, size.saturating_sub(1))
+            .is_none()
+    {
+        return Err(Error::KernelPastRamEnd);
+    }
+
+    check_reserved_ranges(start, start + size, load_override.reserved)
+}
+
+// Checks that the kernel, spanning from `start` up to `end`, doesn't overlap any of the
+// `reserved` ranges.
+fn check_reserved_ranges(
+    start: usize,
+    end: usize,
+    reserved: &[(GuestAddress, usize)],
+) -> Result<()> {
+    for &(reserved_addr, reserved_size) in reserved {
+        let reserved_start = reserved_addr.offset();
+        if start < reserved_start.saturating_add(reserved_size) && reserved_start < end {
+            return Err(Error::LoadAddressConflict(reserved_start));
+        }
+    }
+    Ok(())
+}
+
+// Reads the ELF header of a vmlinux image and checks that it is usable.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn read_elf_header<F>(kernel_image: &mut F) -> Result<elf::Elf64_Ehdr>
 where
     F: Read + Seek,
 {
@@ -110,30 +198,140 @@ where
         // If the program header is backwards, bail.
         return Err(Error::InvalidProgramHeaderOffset);
     }
-    if (ehdr.e_entry as usize) < start_address {
-        return Err(Error::InvalidEntryAddress);
-    }
 
+    Ok(ehdr)
+}
+
+// Reads the program headers of a vmlinux image.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn read_program_headers<F>(
+    kernel_image: &mut F,
+    ehdr: &elf::Elf64_Ehdr,
+) -> Result<Vec<elf::Elf64_Phdr>>
+where
+    F: Read + Seek,
+{
     kernel_image
         .seek(SeekFrom::Start(ehdr.e_phoff))
         .map_err(|_| Error::SeekProgramHeader)?;
-    let phdrs: Vec<elf::Elf64_Phdr> = unsafe {
+    unsafe {
         // Reading the structs is safe for a slice of POD structs.
         sys_util::read_struct_slice(kernel_image, ehdr.e_phnum as usize)
-            .map_err(|_| Error::ReadProgramHeader)?
-    };
+            .map_err(|_| Error::ReadProgramHeader)
+    }
+}
 
-    // Read in each section pointed to by the program headers.
-    for phdr in &phdrs {
-        if (phdr.p_type & elf::PT_LOAD) == 0 || phdr.p_filesz == 0 {
-            continue;
+// Returns the lowest and the past the end physical addresses of the loadable segments.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn kernel_span<'a, I>(load_phdrs: I) -> Result<(u64, u64)>
+where
+    I: Iterator<Item = &'a elf::Elf64_Phdr> + Clone,
+{
+    let kernel_start = load_phdrs.clone().map(|phdr| phdr.p_paddr).min();
+    let kernel_end = load_phdrs
+        .map(|phdr| phdr.p_paddr.saturating_add(phdr.p_memsz))
+        .max();
+    match (kernel_start, kernel_end) {
+        (Some(start), Some(end)) => Ok((start, end)),
+        _ => Err(Error::InvalidProgramHeaderAddress),
+    }
+}
+
+/// Returns the guest address range, given as start and size, which a vmlinux image loaded at the
+/// physical addresses of its program headers takes.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn kernel_range<F>(kernel_image: &mut F) -> Result<(GuestAddress, usize)>
+where
+    F: Read + Seek,
+{
+    let ehdr = read_elf_header(kernel_image)?;
+    let phdrs = read_program_headers(kernel_image, &ehdr)?;
+    let (start, end) = kernel_span(
+        phdrs
+            .iter()
+            .filter(|phdr| (phdr.p_type & elf::PT_LOAD) != 0 && phdr.p_filesz != 0),
+    )?;
+    Ok((GuestAddress(start as usize), (end - start) as usize))
+}
+
+/// Checks that a vmlinux image loaded at the physical addresses of its program headers doesn't
+/// overlap any of the `reserved` guest address ranges, given as start and size.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn check_kernel_placement<F>(
+    kernel_image: &mut F,
+    reserved: &[(GuestAddress, usize)],
+) -> Result<()>
+where
+    F: Read + Seek,
+{
+    let (start, size) = kernel_range(kernel_image)?;
+    check_reserved_ranges(start.offset(), start.offset() + size, reserved)
+}
+
+/// Loads a kernel from a vmlinux elf image to a slice
+///
+/// # Arguments
+///
+/// * `guest_mem` - The guest memory region the kernel is written to.
+/// * `kernel_image` - Input vmlinux image.
+/// * `start_address` - For x86_64, this is the start of the high memory. Kernel should reside above it.
+/// * `load_override` - Places the kernel at an explicit address instead of the physical addresses
+///                     of its program headers. The kernel is moved as a whole, entry point
+///                     included.
+///
+/// The image can be any seekable source, e.g. a file opened from a path, a sealed memfd handed
+/// over by another process or an in-memory buffer. It is only read, at offsets from its start,
+/// so it doesn't have to be writable and its current position doesn't matter.
+///
+/// Returns the entry address of the kernel.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn load_kernel<F>(
+    guest_mem: &GuestMemory,
+    kernel_image: &mut F,
+    start_address: usize,
+    load_override: Option<&KernelLoadOverride>,
+) -> Result<GuestAddress>
+where
+    F: Read + Seek,
+{
+    let ehdr = read_elf_header(kernel_image)?;
+    if load_override.is_none() && (ehdr.e_entry as usize) < start_address {
+        return Err(Error::InvalidEntryAddress);
+    }
+
+    let phdrs = read_program_headers(kernel_image, &ehdr)?;
+    let load_phdrs = phdrs
+        .iter()
+        .filter(|phdr| (phdr.p_type & elf::PT_LOAD) != 0 && phdr.p_filesz != 0);
+
+    // With an override, the lowest physical address of the kernel is moved to the override
+    // address, and everything else by the same offset.
+    let mut relocation = None;
+    if let Some(load_override) = load_override {
+        let (kernel_start, kernel_end) = kernel_span(load_phdrs.clone())?;
+        if ehdr.e_entry < kernel_start || ehdr.e_entry >= kernel_end {
+            return Err(Error::InvalidEntryAddress);
         }
+        check_load_override(
+            guest_mem,
+            start_address,
+            load_override,
+            (kernel_end - kernel_start) as usize,
+        )?;
+        relocation = Some((kernel_start, load_override.address.offset()));
+    }
+    let relocate = |paddr: u64| match relocation {
+        Some((kernel_start, address)) => address + (paddr - kernel_start) as usize,
+        None => paddr as usize,
+    };
 
+    // Read in each section pointed to by the program headers.
+    for phdr in load_phdrs {
         kernel_image
             .seek(SeekFrom::Start(phdr.p_offset))
             .map_err(|_| Error::SeekKernelStart)?;
 
-        let mem_offset = GuestAddress(phdr.p_paddr as usize);
+        let mem_offset = GuestAddress(relocate(phdr.p_paddr));
         if mem_offset.offset() < start_address {
             return Err(Error::InvalidProgramHeaderAddress);
         }
@@ -143,7 +341,7 @@ where
             .map_err(|_| Error::ReadKernelImage)?;
     }
 
-    Ok(GuestAddress(ehdr.e_entry as usize))
+    Ok(GuestAddress(relocate(ehdr.e_entry)))
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -151,6 +349,7 @@ pub fn load_kernel<F>(
     guest_mem: &GuestMemory,
     kernel_image: &mut F,
     start_address: usize,
+    load_override: Option<&KernelLoadOverride>,
 ) -> Result<GuestAddress>
 where
     F: Read + Seek,
@@ -214,7 +413,18 @@ where
         .seek(SeekFrom::Start(0))
         .map_err(|_| Error::SeekKernelImage)?;
 
-    kernel_load_offset = kernel_load_offset + start_address;
+    kernel_load_offset = match load_override {
+        Some(load_override) => {
+            check_load_override(
+                guest_mem,
+                start_address,
+                load_override,
+                kernel_size as usize,
+            )?;
+            load_override.address.offset()
+        }
+        None => kernel_load_offset + start_address,
+    };
     guest_mem
         .read_to_memory(
             GuestAddress(kernel_load_offset),
@@ -226,6 +436,78 @@ where
     Ok(GuestAddress(kernel_load_offset))
 }
 
+/// The kernel expects each cpio archive of an initramfs to start at a 4 byte boundary.
+const INITRD_SEGMENT_ALIGNMENT: usize = 4;
+
+/// Describes where the initrd was loaded in the guest memory.
+#[derive(Debug, PartialEq)]
+pub struct InitrdConfig {
+    /// Guest address of the initrd.
+    pub address: GuestAddress,
+    /// Size of the initrd in bytes.
+    pub size: usize,
+}
+
+/// Loads the initrd segments one after the other in the guest memory.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The guest memory the initrd is written to.
+/// * `guest_addr` - The address at which the first segment is loaded.
+/// * `segments` - The initrd images, usually cpio archives, in the order in which they are
+///                concatenated.
+///
+/// Each segment is zero padded up to a 4 byte boundary, so that the next cpio archive starts
+/// where the kernel looks for it. Returns the address and the combined size of the initrd.
+pub fn load_initrd<F>(
+    guest_mem: &GuestMemory,
+    guest_addr: GuestAddress,
+    segments: &mut [F],
+) -> Result<InitrdConfig>
+where
+    F: Read + Seek,
+{
+    let mut size = 0;
+    for (index, segment) in segments.iter_mut().enumerate() {
+        let segment_size = segment
+            .seek(SeekFrom::End(0))
+            .map_err(|_| Error::SeekInitrdSegment(index))? as usize;
+        segment
+            .seek(SeekFrom::Start(0))
+            .map_err(|_| Error::SeekInitrdSegment(index))?;
+
+        let padding =
+            (INITRD_SEGMENT_ALIGNMENT - size % INITRD_SEGMENT_ALIGNMENT) % INITRD_SEGMENT_ALIGNMENT;
+        let padding_addr = guest_addr
+            .checked_add(size)
+            .ok_or(Error::InitrdSegmentPastRamEnd(index))?;
+        let segment_addr = padding_addr
+            .checked_add(padding)
+            .ok_or(Error::InitrdSegmentPastRamEnd(index))?;
+        // Both the first and the last byte of the segment must be in the guest memory.
+        if !guest_mem.address_in_range(segment_addr)
+            || guest_mem
+                .checked_offset(segment_addr, segment_size.saturating_sub(1))
+                .is_none()
+        {
+            return Err(Error::InitrdSegmentPastRamEnd(index));
+        }
+
+        guest_mem
+            .write_slice_at_addr(&[0u8; INITRD_SEGMENT_ALIGNMENT][..padding], padding_addr)
+            .map_err(|_| Error::ReadInitrdSegment(index))?;
+        guest_mem
+            .read_to_memory(segment_addr, segment, segment_size)
+            .map_err(|_| Error::ReadInitrdSegment(index))?;
+        size += padding + segment_size;
+    }
+
+    Ok(InitrdConfig {
+        address: guest_addr,
+        size,
+    })
+}
+
 /// Writes the command line string to the given memory slice.
 ///
 /// # Arguments
@@ -291,7 +573,138 @@ mod tests {
         let load_addr = 0x8_0000;
         assert_eq!(
             Ok(GuestAddress(load_addr)),
-            load_kernel(&gm, &mut Cursor::new(&image), 0)
+            load_kernel(&gm, &mut Cursor::new(&image), 0, None)
+        );
+    }
+
+    #[test]
+    fn test_load_kernel_from_memfd() {
+        extern crate libc;
+
+        use std::fs::File;
+        use std::io::Write;
+        use std::os::unix::io::FromRawFd;
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/loader/test_elf.bin");
+        #[cfg(target_arch = "aarch64")]
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/loader/test_pe.bin");
+        let entry_addr =
+            load_kernel(&create_guest_mem(), &mut File::open(path).unwrap(), 0, None).unwrap();
+
+        // This is safe because we pass a valid null-terminated name and check the return value.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_memfd_create,
+                b"kernel\0".as_ptr() as *const libc::c_char,
+                libc::MFD_ALLOW_SEALING,
+            )
+        };
+        assert!(fd >= 0);
+        // This is safe because we own the file descriptor we have just created.
+        let mut memfd = unsafe { File::from_raw_fd(fd as i32) };
+        memfd.write_all(&make_test_bin()).unwrap();
+        // Seal the memfd the way a supervisor handing over the kernel would.
+        let seals =
+            libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+        // This is safe because the memfd is valid and we check the return value.
+        assert_eq!(
+            unsafe { libc::fcntl(fd as i32, libc::F_ADD_SEALS, seals) },
+            0
+        );
+
+        // The memfd is left positioned at its end.
+        assert_eq!(
+            load_kernel(&create_guest_mem(), &mut memfd, 0, None),
+            Ok(entry_addr)
+        );
+    }
+
+    #[test]
+    fn test_load_kernel_override() {
+        let image = make_test_bin();
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let default_addr = GuestAddress(0x10_0000);
+        #[cfg(target_arch = "aarch64")]
+        let default_addr = GuestAddress(0x8_0000);
+        let default_gm = create_guest_mem();
+        assert!(load_kernel(&default_gm, &mut Cursor::new(&image), 0, None).is_ok());
+
+        let gm = create_guest_mem();
+        let reserved = [(GuestAddress(0x17_f000), 0x1000)];
+        let mut load_override = KernelLoadOverride {
+            address: GuestAddress(0x14_0000),
+            reserved: &reserved,
+        };
+        assert_eq!(
+            Ok(GuestAddress(0x14_0000)),
+            load_kernel(&gm, &mut Cursor::new(&image), 0, Some(&load_override))
+        );
+        // The kernel was moved as a whole.
+        let mut expected = [0u8; 0x100];
+        let mut loaded = [0u8; 0x100];
+        default_gm
+            .read_slice_at_addr(&mut expected, default_addr)
+            .unwrap();
+        gm.read_slice_at_addr(&mut loaded, GuestAddress(0x14_0000))
+            .unwrap();
+        assert_eq!(&expected[..], &loaded[..]);
+
+        // Error cases: the kernel collides with a reserved range, the address is not page
+        // aligned, below the kernel start or too close to the end of the guest memory.
+        let reserved = [(GuestAddress(0x13_f000), 0x2000)];
+        load_override.reserved = &reserved;
+        assert_eq!(
+            Err(Error::LoadAddressConflict(0x13_f000)),
+            load_kernel(&gm, &mut Cursor::new(&image), 0, Some(&load_override))
+        );
+        load_override.reserved = &[];
+        load_override.address = GuestAddress(0x14_0800);
+        assert_eq!(
+            Err(Error::InvalidLoadAddress),
+            load_kernel(&gm, &mut Cursor::new(&image), 0, Some(&load_override))
+        );
+        load_override.address = GuestAddress(0x14_0000);
+        assert_eq!(
+            Err(Error::InvalidLoadAddress),
+            load_kernel(
+                &gm,
+                &mut Cursor::new(&image),
+                0x15_0000,
+                Some(&load_override)
+            )
+        );
+        load_override.address = GuestAddress(MEM_SIZE - 0x1000);
+        assert_eq!(
+            Err(Error::KernelPastRamEnd),
+            load_kernel(&gm, &mut Cursor::new(&image), 0, Some(&load_override))
+        );
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_check_kernel_placement() {
+        // The test kernel spans from 0x10_0000 up to 0x10_102f.
+        let image = make_test_bin();
+        assert_eq!(
+            Ok((GuestAddress(0x10_0000), 0x102f)),
+            kernel_range(&mut Cursor::new(&image))
+        );
+        let reserved = [
+            (GuestAddress(0xf_f000), 0x1000),
+            (GuestAddress(0x10_2000), 0x1000),
+        ];
+        assert_eq!(
+            Ok(()),
+            check_kernel_placement(&mut Cursor::new(&image), &reserved)
+        );
+        let reserved = [
+            (GuestAddress(0x10_2000), 0x1000),
+            (GuestAddress(0x10_1000), 0x1000),
+        ];
+        assert_eq!(
+            Err(Error::LoadAddressConflict(0x10_1000)),
+            check_kernel_placement(&mut Cursor::new(&image), &reserved)
         );
     }
 
@@ -301,7 +714,7 @@ mod tests {
         let image = make_test_bin();
         assert_eq!(
             Err(Error::ReadKernelImage),
-            load_kernel(&gm, &mut Cursor::new(&image), 0)
+            load_kernel(&gm, &mut Cursor::new(&image), 0, None)
         );
     }
 
@@ -313,7 +726,7 @@ mod tests {
         bad_image.truncate(56);
         assert_eq!(
             Err(Error::ReadProgramHeader),
-            load_kernel(&gm, &mut Cursor::new(&bad_image), 0)
+            load_kernel(&gm, &mut Cursor::new(&bad_image), 0, None)
         );
     }
 
@@ -328,7 +741,7 @@ mod tests {
         bad_image[offset] = 0x33;
         assert_eq!(
             Err(Error::InvalidElfMagicNumber),
-            load_kernel(&gm, &mut Cursor::new(&bad_image), 0)
+            load_kernel(&gm, &mut Cursor::new(&bad_image), 0, None)
         );
     }
 
@@ -341,7 +754,7 @@ mod tests {
         bad_image[0x5] = 2;
         assert_eq!(
             Err(Error::BigEndianElfOnLittle),
-            load_kernel(&gm, &mut Cursor::new(&bad_image), 0)
+            load_kernel(&gm, &mut Cursor::new(&bad_image), 0, None)
         );
     }
 
@@ -354,7 +767,7 @@ mod tests {
         bad_image[0x36] = 0x10;
         assert_eq!(
             Err(Error::InvalidProgramHeaderSize),
-            load_kernel(&gm, &mut Cursor::new(&bad_image), 0)
+            load_kernel(&gm, &mut Cursor::new(&bad_image), 0, None)
         );
     }
 
@@ -367,7 +780,7 @@ mod tests {
         bad_image[0x20] = 0x10;
         assert_eq!(
             Err(Error::InvalidProgramHeaderOffset),
-            load_kernel(&gm, &mut Cursor::new(&bad_image), 0)
+            load_kernel(&gm, &mut Cursor::new(&bad_image), 0, None)
         );
     }
 
@@ -379,7 +792,95 @@ mod tests {
         let bad_image = make_test_bin();
         assert_eq!(
             Err(Error::InvalidEntryAddress),
-            load_kernel(&gm, &mut Cursor::new(&bad_image), std::usize::MAX)
+            load_kernel(&gm, &mut Cursor::new(&bad_image), std::usize::MAX, None)
+        );
+    }
+
+    // Builds a newc cpio archive holding a single regular file.
+    fn make_cpio(name: &str, data: &[u8]) -> Vec<u8> {
+        let fields = [
+            0,
+            0o100_644,
+            0,
+            0,
+            1,
+            0,
+            data.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0,
+        ];
+        let mut archive = b"070701".to_vec();
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        // The file data starts at a 4 byte boundary.
+        while archive.len() % 4 != 0 {
+            archive.push(0);
+        }
+        archive.extend_from_slice(data);
+        archive
+    }
+
+    #[test]
+    fn test_load_initrd() {
+        let gm = create_guest_mem();
+        let first = make_cpio("init", b"#!/bin/sh\n");
+        let second = make_cpio("etc/hostname", b"guest");
+        assert_ne!(first.len() % 4, 0);
+
+        let initrd_addr = GuestAddress(0x10_0000);
+        let initrd = load_initrd(
+            &gm,
+            initrd_addr,
+            &mut [Cursor::new(&first), Cursor::new(&second)],
+        )
+        .unwrap();
+
+        // The second archive starts at the next 4 byte boundary.
+        let second_offset = (first.len() + 3) & !3;
+        assert_eq!(
+            initrd,
+            InitrdConfig {
+                address: initrd_addr,
+                size: second_offset + second.len(),
+            }
+        );
+
+        let mut image = vec![0xffu8; initrd.size];
+        gm.read_slice_at_addr(&mut image, initrd_addr).unwrap();
+        assert_eq!(&image[..first.len()], &first[..]);
+        assert!(image[first.len()..second_offset].iter().all(|&b| b == 0));
+        assert_eq!(&image[second_offset..], &second[..]);
+    }
+
+    #[test]
+    fn test_load_initrd_past_ram_end() {
+        let gm = create_guest_mem();
+        let segment = make_cpio("init", &[0x42; 0x100]);
+
+        // The second segment doesn't fit in the guest memory.
+        let initrd_addr = GuestAddress(MEM_SIZE - segment.len() - 0x10);
+        let res = load_initrd(
+            &gm,
+            initrd_addr,
+            &mut [Cursor::new(&segment), Cursor::new(&segment)],
+        );
+        assert_eq!(res, Err(Error::InitrdSegmentPastRamEnd(1)));
+        assert_eq!(
+            format!("{}", res.unwrap_err()),
+            "Initrd segment 1 does not fit in the guest memory"
+        );
+
+        let initrd_addr = GuestAddress(MEM_SIZE);
+        assert_eq!(
+            load_initrd(&gm, initrd_addr, &mut [Cursor::new(&segment)]),
+            Err(Error::InitrdSegmentPastRamEnd(0))
         );
     }
 
@@ -0,0 +1,333 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for booting a vmlinux image through its PVH entry point.
+//!
+//! The structures follow `xen/include/public/arch-x86/hvm/start_info.h`.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::mem;
+
+use super::{elf, read_elf_header, Error, Result};
+use memory_model::{DataInit, GuestAddress, GuestMemory};
+use sys_util;
+
+/// Value of `hvm_start_info.magic`, "xEn3" with the 0x80 bit of the "E" set.
+pub const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+/// Version of the `hvm_start_info` layout written by `load_pvh_start_info`.
+const XEN_HVM_START_INFO_VERSION: u32 = 1;
+/// Type of the ELF note holding the 32 bit physical address of the PVH entry point.
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+/// Memory map entry type of usable RAM, as in the E820 table.
+const E820_RAM: u32 = 1;
+
+/// Start of day information handed to the guest in `ebx` when entering it through PVH.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct hvm_start_info {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub nr_modules: u32,
+    pub modlist_paddr: u64,
+    pub cmdline_paddr: u64,
+    pub rsdp_paddr: u64,
+    pub memmap_paddr: u64,
+    pub memmap_entries: u32,
+    pub reserved: u32,
+}
+
+/// Entry of the memory map pointed to by `hvm_start_info.memmap_paddr`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct hvm_memmap_table_entry {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: u32,
+    pub reserved: u32,
+}
+
+// It is safe to initialize these structs from raw data as they only contain integers.
+unsafe impl DataInit for hvm_start_info {}
+unsafe impl DataInit for hvm_memmap_table_entry {}
+
+// Header of an ELF note, followed by the 4 byte aligned name and descriptor.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Elf64_Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+fn align_note(size: u32) -> u64 {
+    (u64::from(size) + 3) & !3
+}
+
+/// Looks for the PVH entry point in the notes of a vmlinux image.
+///
+/// # Arguments
+///
+/// * `kernel_image` - Input vmlinux image.
+///
+/// Returns the address of the PVH entry point, or `None` if the kernel doesn't support PVH.
+pub fn find_pvh_entry<F>(kernel_image: &mut F) -> Result<Option<GuestAddress>>
+where
+    F: Read + Seek,
+{
+    let ehdr = read_elf_header(kernel_image)?;
+    kernel_image
+        .seek(SeekFrom::Start(ehdr.e_phoff))
+        .map_err(|_| Error::SeekProgramHeader)?;
+    let phdrs: Vec<elf::Elf64_Phdr> = unsafe {
+        // Reading the structs is safe for a slice of POD structs.
+        sys_util::read_struct_slice(kernel_image, ehdr.e_phnum as usize)
+            .map_err(|_| Error::ReadProgramHeader)?
+    };
+
+    for phdr in phdrs.iter().filter(|phdr| phdr.p_type == elf::PT_NOTE) {
+        let end = phdr.p_offset.saturating_add(phdr.p_filesz);
+        let mut offset = phdr.p_offset;
+        while offset + mem::size_of::<Elf64_Nhdr>() as u64 <= end {
+            kernel_image
+                .seek(SeekFrom::Start(offset))
+                .map_err(|_| Error::SeekNoteHeader)?;
+            let mut nhdr: Elf64_Nhdr = Default::default();
+            unsafe {
+                // read_struct is safe when reading a POD struct.
+                sys_util::read_struct(kernel_image, &mut nhdr)
+                    .map_err(|_| Error::ReadNoteHeader)?;
+            }
+            let name_offset = offset + mem::size_of::<Elf64_Nhdr>() as u64;
+            let desc_offset = name_offset + align_note(nhdr.n_namesz);
+            offset = desc_offset + align_note(nhdr.n_descsz);
+
+            let mut name = [0u8; 4];
+            if nhdr.n_type != XEN_ELFNOTE_PHYS32_ENTRY || nhdr.n_namesz as usize != name.len() {
+                continue;
+            }
+            kernel_image
+                .read_exact(&mut name)
+                .map_err(|_| Error::ReadNoteHeader)?;
+            if &name != b"Xen\0" {
+                continue;
+            }
+
+            // The entry point is a 32 bit address, though some kernels store it in 8 bytes.
+            if nhdr.n_descsz != 4 && nhdr.n_descsz != 8 {
+                return Err(Error::InvalidPvhNote);
+            }
+            kernel_image
+                .seek(SeekFrom::Start(desc_offset))
+                .map_err(|_| Error::SeekNoteHeader)?;
+            let mut entry: u32 = 0;
+            unsafe {
+                // read_struct is safe when reading a POD struct.
+                sys_util::read_struct(kernel_image, &mut entry)
+                    .map_err(|_| Error::ReadNoteHeader)?;
+            }
+            return Ok(Some(GuestAddress(u32::from_le(entry) as usize)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Writes the `hvm_start_info` structure and the memory map it points to in the guest memory.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The guest memory the structures are written to.
+/// * `start_info_addr` - The address of the `hvm_start_info` structure. The memory map is placed
+///                       right after it.
+/// * `cmdline_addr` - The address of the kernel command line.
+/// * `memory_regions` - The guest RAM, given as start and size, reported in the memory map.
+pub fn load_pvh_start_info(
+    guest_mem: &GuestMemory,
+    start_info_addr: GuestAddress,
+    cmdline_addr: GuestAddress,
+    memory_regions: &[(GuestAddress, usize)],
+) -> Result<()> {
+    let memmap_addr = start_info_addr
+        .checked_add(mem::size_of::<hvm_start_info>())
+        .ok_or(Error::StartInfoPastRamEnd)?;
+    let size = mem::size_of::<hvm_start_info>()
+        + memory_regions.len() * mem::size_of::<hvm_memmap_table_entry>();
+    // Both the first and the last byte of the structures must be in the guest memory.
+    if !guest_mem.address_in_range(start_info_addr)
+        || guest_mem
+            .checked_offset(start_info_addr, size - 1)
+            .is_none()
+    {
+        return Err(Error::StartInfoPastRamEnd);
+    }
+
+    let start_info = hvm_start_info {
+        magic: XEN_HVM_START_MAGIC_VALUE,
+        version: XEN_HVM_START_INFO_VERSION,
+        cmdline_paddr: cmdline_addr.offset() as u64,
+        memmap_paddr: memmap_addr.offset() as u64,
+        memmap_entries: memory_regions.len() as u32,
+        ..Default::default()
+    };
+    guest_mem
+        .write_obj_at_addr(start_info, start_info_addr)
+        .map_err(|_| Error::StartInfoSetup)?;
+
+    for (index, &(addr, size)) in memory_regions.iter().enumerate() {
+        let entry = hvm_memmap_table_entry {
+            addr: addr.offset() as u64,
+            size: size as u64,
+            type_: E820_RAM,
+            reserved: 0,
+        };
+        guest_mem
+            .write_obj_at_addr(
+                entry,
+                memmap_addr.unchecked_add(index * mem::size_of::<hvm_memmap_table_entry>()),
+            )
+            .map_err(|_| Error::StartInfoSetup)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Appends a POD struct to an image under construction.
+    fn push_struct<T: Copy>(image: &mut Vec<u8>, val: &T) {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(val as *const T as *const u8, mem::size_of::<T>())
+        };
+        image.extend_from_slice(bytes);
+    }
+
+    // Builds an ELF image whose only program header is a note segment with the given notes.
+    fn make_elf(notes: &[(&[u8], u32, &[u8])]) -> Vec<u8> {
+        let mut note_data = Vec::new();
+        for &(name, n_type, desc) in notes {
+            let nhdr = Elf64_Nhdr {
+                n_namesz: name.len() as u32,
+                n_descsz: desc.len() as u32,
+                n_type,
+            };
+            push_struct(&mut note_data, &nhdr);
+            for field in [name, desc].iter() {
+                note_data.extend_from_slice(field);
+                while note_data.len() % 4 != 0 {
+                    note_data.push(0);
+                }
+            }
+        }
+
+        let ehdr_size = mem::size_of::<elf::Elf64_Ehdr>();
+        let phdr_size = mem::size_of::<elf::Elf64_Phdr>();
+        let mut ehdr: elf::Elf64_Ehdr = Default::default();
+        ehdr.e_ident[elf::EI_MAG0 as usize] = elf::ELFMAG0 as u8;
+        ehdr.e_ident[elf::EI_MAG1 as usize] = elf::ELFMAG1;
+        ehdr.e_ident[elf::EI_MAG2 as usize] = elf::ELFMAG2;
+        ehdr.e_ident[elf::EI_MAG3 as usize] = elf::ELFMAG3;
+        ehdr.e_ident[elf::EI_DATA as usize] = elf::ELFDATA2LSB as u8;
+        ehdr.e_entry = 0x100_0000;
+        ehdr.e_phoff = ehdr_size as u64;
+        ehdr.e_phentsize = phdr_size as u16;
+        ehdr.e_phnum = 1;
+        let phdr = elf::Elf64_Phdr {
+            p_type: elf::PT_NOTE,
+            p_flags: 0,
+            p_offset: (ehdr_size + phdr_size) as u64,
+            p_vaddr: 0,
+            p_paddr: 0,
+            p_filesz: note_data.len() as u64,
+            p_memsz: note_data.len() as u64,
+            p_align: 4,
+        };
+
+        let mut image = Vec::new();
+        push_struct(&mut image, &ehdr);
+        push_struct(&mut image, &phdr);
+        image.extend_from_slice(&note_data);
+        image
+    }
+
+    #[test]
+    fn test_find_pvh_entry() {
+        // Notes of other types or owners are skipped.
+        let image = make_elf(&[
+            (b"GNU\0", XEN_ELFNOTE_PHYS32_ENTRY, &[0xff; 20]),
+            (b"Xen\0", 1, &[0xff; 8]),
+            (
+                b"Xen\0",
+                XEN_ELFNOTE_PHYS32_ENTRY,
+                &[0x00, 0x10, 0x20, 0x01],
+            ),
+        ]);
+        assert_eq!(
+            Ok(Some(GuestAddress(0x120_1000))),
+            find_pvh_entry(&mut Cursor::new(&image))
+        );
+
+        let image = make_elf(&[(b"Xen\0", 1, &[0xff; 8])]);
+        assert_eq!(Ok(None), find_pvh_entry(&mut Cursor::new(&image)));
+
+        let image = make_elf(&[(b"Xen\0", XEN_ELFNOTE_PHYS32_ENTRY, &[0xff; 2])]);
+        assert_eq!(
+            Err(Error::InvalidPvhNote),
+            find_pvh_entry(&mut Cursor::new(&image))
+        );
+
+        // The test vmlinux image is not PVH capable.
+        let image = include_bytes!("test_elf.bin").to_vec();
+        assert_eq!(Ok(None), find_pvh_entry(&mut Cursor::new(&image)));
+    }
+
+    #[test]
+    fn test_load_pvh_start_info() {
+        let regions = [
+            (GuestAddress(0), 0x8_0000),
+            (GuestAddress(0x10_0000), 0x8_0000),
+        ];
+        let gm = GuestMemory::new(&regions).unwrap();
+        let start_info_addr = GuestAddress(0x6000);
+        let cmdline_addr = GuestAddress(0x2_0000);
+        assert!(load_pvh_start_info(&gm, start_info_addr, cmdline_addr, &regions).is_ok());
+
+        let start_info: hvm_start_info = gm.read_obj_from_addr(start_info_addr).unwrap();
+        let memmap_addr = start_info_addr.unchecked_add(mem::size_of::<hvm_start_info>());
+        assert_eq!(
+            start_info,
+            hvm_start_info {
+                magic: XEN_HVM_START_MAGIC_VALUE,
+                version: 1,
+                cmdline_paddr: 0x2_0000,
+                memmap_paddr: memmap_addr.offset() as u64,
+                memmap_entries: 2,
+                ..Default::default()
+            }
+        );
+        let entry: hvm_memmap_table_entry = gm
+            .read_obj_from_addr(memmap_addr.unchecked_add(mem::size_of::<hvm_memmap_table_entry>()))
+            .unwrap();
+        assert_eq!(
+            entry,
+            hvm_memmap_table_entry {
+                addr: 0x10_0000,
+                size: 0x8_0000,
+                type_: E820_RAM,
+                reserved: 0,
+            }
+        );
+
+        // The memory map doesn't fit before the end of the first region.
+        assert_eq!(
+            Err(Error::StartInfoPastRamEnd),
+            load_pvh_start_info(&gm, GuestAddress(0x7_ffc0), cmdline_addr, &regions)
+        );
+    }
+}
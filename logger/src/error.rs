@@ -20,12 +20,18 @@ pub enum LoggerError {
     AlreadyInitialized,
     /// Invalid logger option specified.
     InvalidLogOption(String),
+    /// Invalid metrics label specified.
+    InvalidMetricsLabel(String),
     /// Opening named pipe fails.
     OpenFIFO(std::io::Error),
+    /// Connecting to the syslog socket fails.
+    OpenSyslog(std::io::Error),
     /// Writing to named pipe fails.
     LogWrite(std::io::Error),
     /// Flushing to disk fails.
     LogFlush(std::io::Error),
+    /// Rotating the log file fails.
+    LogRotate(std::io::Error),
     /// Error obtaining lock on mutex.
     MutexLockFailure(String),
     /// Error in the logging of the metrics.
@@ -50,15 +56,26 @@ impl fmt::Display for LoggerError {
                 "Reinitialization of logger not allowed.".to_string()
             }
             LoggerError::InvalidLogOption(ref s) => format!("Invalid log option: {}", s),
+            LoggerError::InvalidMetricsLabel(ref s) => format!(
+                "Invalid metrics label: {}. Expected <key>=<value> with a non-empty key.",
+                s
+            ),
             LoggerError::OpenFIFO(ref e) => {
                 format!("Failed to open pipe. Error: {}", e.description())
             }
+            LoggerError::OpenSyslog(ref e) => format!(
+                "Failed to connect to the syslog socket. Error: {}",
+                e.description()
+            ),
             LoggerError::LogWrite(ref e) => {
                 format!("Failed to write logs. Error: {}", e.description())
             }
             LoggerError::LogFlush(ref e) => {
                 format!("Failed to flush logs. Error: {}", e.description())
             }
+            LoggerError::LogRotate(ref e) => {
+                format!("Failed to rotate logs. Error: {}", e.description())
+            }
             LoggerError::MutexLockFailure(ref e) => e.to_string(),
             LoggerError::LogMetricFailure(ref e) => e.to_string(),
             LoggerError::LogMetricRateLimit => "Metric will not yet be logged.".to_string(),
@@ -129,6 +146,19 @@ mod tests {
             "Failed to flush logs. Error: flush"
         );
 
+        assert!(format!(
+            "{:?}",
+            LoggerError::LogRotate(std::io::Error::new(ErrorKind::NotFound, "rotate"))
+        )
+        .contains("LogRotate"));
+        assert_eq!(
+            format!(
+                "{}",
+                LoggerError::LogRotate(std::io::Error::new(ErrorKind::NotFound, "rotate"))
+            ),
+            "Failed to rotate logs. Error: rotate"
+        );
+
         assert!(format!(
             "{:?}",
             LoggerError::MutexLockFailure(String::from("Mutex lock"))
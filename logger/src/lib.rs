@@ -126,6 +126,8 @@
 //! The example above means that inside the structure representing all the metrics there is a field
 //! named `block` which is in turn a serializable child structure collecting metrics for
 //! the block device such as `activate_fails`, `cfg_fails`, etc.
+//! When labels are configured through `add_metrics_label`, they are emitted as a last `labels`
+//! field mapping each key to its value.
 //!
 //! # Limitations
 //! In order to not block the instance if nobody is consuming the logs that are flushed to the two
@@ -150,20 +152,23 @@ pub mod error;
 pub mod metrics;
 mod writers;
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::ops::Deref;
+use std::process;
 use std::result;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::sync::{Mutex, MutexGuard, RwLock};
 
-use chrono::Local;
+use chrono::{Local, Utc};
 use serde_json::Value;
 
 use error::LoggerError;
 pub use log::Level::*;
 pub use log::*;
-use log::{set_logger, set_max_level, Log, Metadata, Record};
+use log::{set_logger, set_max_level, LevelFilter, Log, Metadata, Record};
+use metrics::FirecrackerMetrics;
 pub use metrics::{Metric, METRICS};
 use writers::*;
 
@@ -187,6 +192,14 @@ static STATE: AtomicUsize = ATOMIC_USIZE_INIT;
 // Time format
 const TIME_FMT: &str = "%Y-%m-%dT%H:%M:%S.%f";
 
+// Values used for formatting syslog messages as described in RFC 5424.
+const SYSLOG_TIME_FMT: &str = "%Y-%m-%dT%H:%M:%S%.6fZ";
+const SYSLOG_VERSION: u8 = 1;
+// All the messages are sent with the `user` facility.
+const SYSLOG_FACILITY: u8 = 1;
+const SYSLOG_APP_NAME_MAX_LEN: usize = 48;
+const SYSLOG_NILVALUE: &str = "-";
+
 lazy_static! {
     static ref _LOGGER_INNER: Logger = Logger::new();
 }
@@ -229,6 +242,37 @@ impl FromStr for LogOption {
     }
 }
 
+/// A static key/value pair emitted along with every metrics snapshot.
+///
+#[derive(Debug, PartialEq)]
+pub struct MetricsLabel {
+    key: String,
+    value: String,
+}
+
+impl FromStr for MetricsLabel {
+    type Err = LoggerError;
+
+    fn from_str(s: &str) -> Result<MetricsLabel> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.is_empty() => Ok(MetricsLabel {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(LoggerError::InvalidMetricsLabel(s.to_string())),
+        }
+    }
+}
+
+// The metrics snapshot along with the labels, which are serialized as an extra `labels` field.
+#[derive(Serialize)]
+struct LabeledMetrics<'a> {
+    #[serde(flatten)]
+    metrics: &'a FirecrackerMetrics,
+    labels: &'a BTreeMap<String, String>,
+}
+
 /// A structure containing info about the App that uses the logger.
 pub struct AppInfo {
     name: String,
@@ -262,10 +306,23 @@ impl LevelInfo {
         self.code.load(Ordering::Relaxed)
     }
 
-    fn set_code(&self, level: Level) {
+    fn set_code(&self, level: LevelFilter) {
         self.code.store(level as usize, Ordering::Relaxed)
     }
 
+    fn level_filter(&self) -> LevelFilter {
+        // The code is always stored from a `LevelFilter`, whose values are ordered from `Off` (0)
+        // to `Trace`.
+        match self.code() {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
     fn writer(&self) -> usize {
         self.writer.load(Ordering::Relaxed)
     }
@@ -288,8 +345,13 @@ pub struct Logger {
     log_fifo: Mutex<Option<PipeLogWriter>>,
     // Used in case we want to send metrics to a FIFO.
     metrics_fifo: Mutex<Option<PipeLogWriter>>,
+    // Used in case we want to send logs to syslog.
+    syslog: Mutex<Option<SyslogWriter>>,
     instance_id: RwLock<String>,
     flags: AtomicUsize,
+    // The size in bytes above which the log file is rotated. Zero disables rotation.
+    max_log_size: AtomicUsize,
+    metrics_labels: RwLock<BTreeMap<String, String>>,
 }
 
 // Auxiliary function to get the default destination for some code level.
@@ -303,6 +365,16 @@ fn get_default_destination(level: Level) -> Destination {
     }
 }
 
+// Auxiliary function to get the syslog severity code for some code level.
+fn get_syslog_severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
 // Auxiliary function to flush a message to a PipeLogWriter.
 // This is used by the internal logger to either flush human-readable logs or metrics.
 fn log_to_fifo(mut msg: String, fifo_writer: &mut PipeLogWriter) -> Result<()> {
@@ -329,8 +401,11 @@ impl Logger {
             },
             log_fifo: Mutex::new(None),
             metrics_fifo: Mutex::new(None),
+            syslog: Mutex::new(None),
             instance_id: RwLock::new(String::new()),
             flags: AtomicUsize::new(0),
+            max_log_size: AtomicUsize::new(0),
+            metrics_labels: RwLock::new(BTreeMap::new()),
         }
     }
 
@@ -446,12 +521,82 @@ impl Logger {
     /// message
     /// ```
     pub fn set_level(&self, level: Level) {
-        self.level_info.set_code(level);
+        self.set_level_filter(level.to_level_filter());
+    }
+
+    /// Sets the level filter for the Logger. Unlike `set_level`, this also allows turning
+    /// logging off completely with `LevelFilter::Off`.
+    /// Statements below the threshold are discarded by the logging macros before their
+    /// arguments are formatted.
+    ///
+    /// # Arguments
+    ///
+    /// * `level_filter` - Set the highest log level, or `Off`.
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate logger;
+    /// extern crate log;
+    /// use logger::LOGGER;
+    /// use std::ops::Deref;
+    ///
+    /// fn main() {
+    ///     let l = LOGGER.deref();
+    ///     l.set_level_filter(log::LevelFilter::Error);
+    ///     assert!(l.preinit(Some("MY-INSTANCE".to_string())).is_ok());
+    ///     warn!("A warning log message which is skipped");
+    /// }
+    /// ```
+    pub fn set_level_filter(&self, level_filter: LevelFilter) {
+        self.level_info.set_code(level_filter);
+        set_max_level(level_filter);
         if self.level_info.writer() != Destination::Pipe as usize {
-            self.level_info.set_writer(get_default_destination(level));
+            if let Some(level) = level_filter.to_level() {
+                self.level_info.set_writer(get_default_destination(level));
+            }
         }
     }
 
+    /// Sets the size in bytes above which the log file is rotated. When the threshold is
+    /// exceeded, the file is renamed to `<log_pipe>.1`, older rotated files are shifted by one
+    /// and logging continues in a new file. Only regular files are rotated, named pipes are left
+    /// as they are. Zero disables the rotation, which is the default.
+    /// Must be called before `init` in order to take effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_size` - Maximum size of the log file in bytes.
+    pub fn set_max_log_size(&self, max_size: usize) {
+        self.max_log_size.store(max_size, Ordering::Relaxed);
+    }
+
+    /// Sends the log messages to the syslog daemon listening on the given datagram socket,
+    /// instead of stdout/stderr. Once the logger is initialized, messages are written to both
+    /// syslog and the log pipe.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_path` - Path to the syslog socket, usually `/dev/log`.
+    pub fn init_syslog(&self, socket_path: &str) -> Result<()> {
+        let syslog_writer = SyslogWriter::new(socket_path)?;
+        *self.syslog_guard() = Some(syslog_writer);
+        Ok(())
+    }
+
+    /// Adds a label that is emitted along with every metrics snapshot. A label with an already
+    /// existing key replaces the previous one.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The key/value pair, usually parsed from a `<key>=<value>` string.
+    pub fn add_metrics_label(&self, label: MetricsLabel) {
+        self.metrics_labels
+            .write()
+            .expect("Failed to add metrics label due to poisoned lock")
+            .insert(label.key, label.value);
+    }
+
     /// Returns the configured flags for the logger.
     ///
     pub fn flags(&self) -> usize {
@@ -497,6 +642,40 @@ impl Logger {
         res
     }
 
+    // Creates a RFC 5424 message out of a log record. The instance ID is used as APP-NAME.
+    fn create_syslog_msg(&self, record: &Record) -> String {
+        let app_name: String = {
+            // It's safe to unwrap here, because `instance_id` is only written to
+            // during log initialization, so there aren't any writers that could
+            // poison the lock.
+            let id_guard = self
+                .instance_id
+                .read()
+                .expect("Failed to read instance ID due to poisoned lock");
+            if id_guard.is_empty() {
+                SYSLOG_NILVALUE.to_string()
+            } else {
+                id_guard.chars().take(SYSLOG_APP_NAME_MAX_LEN).collect()
+            }
+        };
+
+        format!(
+            "<{}>{} {} {} {} {} {} {} {}",
+            SYSLOG_FACILITY * 8 + get_syslog_severity(record.level()),
+            SYSLOG_VERSION,
+            Utc::now().format(SYSLOG_TIME_FMT),
+            // HOSTNAME
+            SYSLOG_NILVALUE,
+            app_name,
+            process::id(),
+            // MSGID
+            SYSLOG_NILVALUE,
+            // STRUCTURED-DATA
+            SYSLOG_NILVALUE,
+            record.args()
+        )
+    }
+
     fn log_fifo_guard(&self) -> MutexGuard<Option<PipeLogWriter>> {
         match self.log_fifo.lock() {
             Ok(guard) => guard,
@@ -506,6 +685,14 @@ impl Logger {
         }
     }
 
+    fn syslog_guard(&self) -> MutexGuard<Option<SyslogWriter>> {
+        match self.syslog.lock() {
+            Ok(guard) => guard,
+            // If a thread panics while holding this lock, the writer within should still be usable.
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
     fn metrics_fifo_guard(&self) -> MutexGuard<Option<PipeLogWriter>> {
         match self.metrics_fifo.lock() {
             Ok(guard) => guard,
@@ -592,7 +779,7 @@ impl Logger {
             }
         }
 
-        set_max_level(Level::Trace.to_level_filter());
+        set_max_level(self.level_info.level_filter());
 
         STATE.store(UNINITIALIZED, Ordering::SeqCst);
 
@@ -649,6 +836,12 @@ impl Logger {
 
         match PipeLogWriter::new(&log_pipe) {
             Ok(t) => {
+                let max_log_size = self.max_log_size.load(Ordering::Relaxed);
+                let t = if max_log_size > 0 {
+                    t.with_max_size(max_log_size)
+                } else {
+                    t
+                };
                 // The mutex shouldn't be poisoned before init otherwise panic!.
                 let mut g = LOGGER.log_fifo_guard();
                 *g = Some(t);
@@ -685,7 +878,7 @@ impl Logger {
             )));
         }
 
-        set_max_level(Level::Trace.to_level_filter());
+        set_max_level(self.level_info.level_filter());
 
         self.log_helper(
             format!("Running {} v{}", app_info.name, app_info.version),
@@ -733,12 +926,28 @@ impl Logger {
         }
     }
 
+    // Serializes the metrics to JSON, along with the labels if there are any.
+    fn serialize_metrics(&self, metrics: &FirecrackerMetrics) -> serde_json::Result<String> {
+        let labels = self
+            .metrics_labels
+            .read()
+            .expect("Failed to read metrics labels due to poisoned lock");
+        if labels.is_empty() {
+            serde_json::to_string(metrics)
+        } else {
+            serde_json::to_string(&LabeledMetrics {
+                metrics,
+                labels: &labels,
+            })
+        }
+    }
+
     /// Flushes metrics to the FIFO provided as argument upon initialization of the logger.
     ///
     pub fn log_metrics(&self) -> Result<()> {
         // Check that the logger is initialized.
         if STATE.load(Ordering::Relaxed) == INITIALIZED {
-            match serde_json::to_string(METRICS.deref()) {
+            match self.serialize_metrics(METRICS.deref()) {
                 Ok(msg) => {
                     // Check that the destination is indeed a FIFO.
                     if self.level_info.writer() == Destination::Pipe as usize {
@@ -782,6 +991,19 @@ impl Log for Logger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
+            if let Some(ref syslog_writer) = *self.syslog_guard() {
+                if syslog_writer
+                    .write(&self.create_syslog_msg(&record))
+                    .is_err()
+                {
+                    METRICS.logger.missed_log_count.inc();
+                }
+                // Syslog replaces stdout/stderr, but not the log pipe.
+                if self.level_info.writer() != Destination::Pipe as usize {
+                    return;
+                }
+            }
+
             let msg = format!(
                 "{}{}{}{}",
                 Local::now().format(TIME_FMT),
@@ -809,6 +1031,7 @@ mod tests {
     use std::fs::File;
     use std::io::BufRead;
     use std::io::BufReader;
+    use std::os::unix::net::UnixDatagram;
 
     const TEST_INSTANCE_ID: &str = "TEST-INSTANCE-ID";
     const TEST_APP_NAME: &str = "Firecracker";
@@ -844,6 +1067,7 @@ mod tests {
         assert_eq!(l.show_line_numbers(), true);
         assert_eq!(l.show_level(), true);
         assert_eq!(l.flags.load(Ordering::Relaxed), 0);
+        assert_eq!(l.max_log_size.load(Ordering::Relaxed), 0);
     }
 
     #[test]
@@ -954,6 +1178,21 @@ mod tests {
 
         assert!(l.log_metrics().is_ok());
 
+        // Assert that statements below the configured level don't reach the log file.
+        l.set_level(log::Level::Warn);
+        info!("filtered info");
+        warn!("unfiltered warning");
+        l.flush();
+        let log_lines: Vec<String> = BufReader::new(File::open(&log_file).unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        assert!(!log_lines.iter().any(|line| line.contains("filtered info")));
+        assert!(log_lines
+            .iter()
+            .any(|line| line.contains("WARN") && line.contains("unfiltered warning")));
+        l.set_level(log::Level::Info);
+
         STATE.store(UNINITIALIZED, Ordering::SeqCst);
         let log_file_temp =
             NamedTempFile::new().expect("Failed to create temporary output logging file.");
@@ -1019,6 +1258,89 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_level_filter() {
+        let l = Logger::new();
+        let info_metadata = MetadataBuilder::new().level(Level::Info).build();
+        let error_metadata = MetadataBuilder::new().level(Level::Error).build();
+        assert_eq!(l.level_info.level_filter(), LevelFilter::Warn);
+        assert!(!l.enabled(&info_metadata));
+        assert!(l.enabled(&error_metadata));
+
+        l.level_info.set_code(LevelFilter::Trace);
+        assert_eq!(l.level_info.level_filter(), LevelFilter::Trace);
+        assert!(l.enabled(&info_metadata));
+
+        l.level_info.set_code(LevelFilter::Off);
+        assert_eq!(l.level_info.level_filter(), LevelFilter::Off);
+        assert!(!l.enabled(&error_metadata));
+    }
+
+    #[test]
+    fn test_syslog() {
+        let socket_dir = tempfile::tempdir().expect("Failed to create temporary directory.");
+        let socket_path = socket_dir.path().join("syslog");
+        let receiver = UnixDatagram::bind(&socket_path).unwrap();
+
+        let l = Logger::new();
+        assert!(l.init_syslog("not_found_syslog_socket").is_err());
+        assert!(l.init_syslog(socket_path.to_str().unwrap()).is_ok());
+        *l.instance_id.write().unwrap() = TEST_INSTANCE_ID.to_string();
+
+        let error_metadata = MetadataBuilder::new().level(Level::Error).build();
+        l.log(
+            &log::Record::builder()
+                .metadata(error_metadata)
+                .args(format_args!("syslog message"))
+                .build(),
+        );
+
+        let mut buf = [0u8; 256];
+        let count = receiver.recv(&mut buf).unwrap();
+        let msg = std::str::from_utf8(&buf[..count]).unwrap();
+        let fields: Vec<&str> = msg.splitn(8, ' ').collect();
+        assert_eq!(fields.len(), 8);
+        // PRI is `user` facility * 8 + `error` severity, followed by the version.
+        assert_eq!(fields[0], "<11>1");
+        assert!(fields[1].ends_with('Z'));
+        assert_eq!(fields[2], "-");
+        assert_eq!(fields[3], TEST_INSTANCE_ID);
+        assert_eq!(fields[4], process::id().to_string());
+        assert_eq!(fields[5], "-");
+        assert_eq!(fields[6], "-");
+        assert_eq!(fields[7], "syslog message");
+    }
+
+    #[test]
+    fn test_metrics_labels() {
+        assert!("region=us-east-1".parse::<MetricsLabel>().is_ok());
+        assert!("empty=".parse::<MetricsLabel>().is_ok());
+        assert!("=value".parse::<MetricsLabel>().is_err());
+        assert!("novalue".parse::<MetricsLabel>().is_err());
+        assert_eq!(
+            "key=a=b".parse::<MetricsLabel>().unwrap(),
+            MetricsLabel {
+                key: "key".to_string(),
+                value: "a=b".to_string(),
+            }
+        );
+
+        let l = Logger::new();
+        let metrics = FirecrackerMetrics::default();
+        let msg = l.serialize_metrics(&metrics).unwrap();
+        assert!(!msg.contains("labels"));
+
+        l.add_metrics_label("region=us-east-1".parse().unwrap());
+        l.add_metrics_label("cluster=blue".parse().unwrap());
+        let msg = l.serialize_metrics(&metrics).unwrap();
+        // The timestamp is still the first field.
+        assert!(msg.starts_with("{\"utc_timestamp_ms\":"));
+        let json: Value = serde_json::from_str(&msg).unwrap();
+        assert_eq!(json["labels"]["region"], "us-east-1");
+        assert_eq!(json["labels"]["cluster"], "blue");
+        assert!(json["block"].is_object());
+    }
+
     #[test]
     fn test_get_default_destination() {
         assert!(get_default_destination(log::Level::Error) == Destination::Stderr);
@@ -27,6 +27,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use chrono;
+use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 
 /// Used for defining new types of metrics that can be either incremented with an unit
@@ -116,6 +117,34 @@ impl Serialize for SharedMetric {
     }
 }
 
+/// Representation of a moment of the life of the process, in microseconds elapsed since the
+/// process started. Unlike the counters, it keeps its value when the metrics are flushed.
+#[derive(Default)]
+pub struct TimestampMetric(AtomicUsize);
+
+impl TimestampMetric {
+    /// Sets the moment to `elapsed_us` microseconds since the process started.
+    pub fn set(&self, elapsed_us: usize) {
+        self.0.store(elapsed_us, Ordering::Relaxed);
+    }
+
+    /// Returns the moment, or 0 if it wasn't set yet.
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Serialize for TimestampMetric {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0.load(Ordering::Relaxed) as u64)
+    }
+}
+
+// Returns the wall clock time in microseconds.
+fn now_us() -> usize {
+    (chrono::Utc::now().timestamp_nanos() / 1000) as usize
+}
+
 // The following structs are used to define a certain organization for the set of metrics we
 // are interested in. Whenever the name of a field differs from its ideal textual representation
 // in the serialized form, we can use the #[serde(rename = "name")] attribute to, well, rename it.
@@ -131,11 +160,26 @@ pub struct ApiServerMetrics {
     pub sync_outcome_fails: SharedMetric,
     /// Number of timeouts during communication with the VMM.
     pub sync_vmm_send_timeout_count: SharedMetric,
+    /// Number of requests rejected because the API is sealed.
+    pub sealed_api_rejected_count: SharedMetric,
+    /// Number of connections refused because the maximum number of connections was open.
+    pub connections_refused_count: SharedMetric,
+}
+
+/// Metrics specific to DELETE API Requests for counting user triggered actions.
+#[derive(Default, Serialize)]
+pub struct DeleteRequestsMetrics {
+    /// Number of DELETEs for detaching a network interface.
+    pub network_count: SharedMetric,
 }
 
 /// Metrics specific to GET API Requests for counting user triggered actions and/or failures.
 #[derive(Default, Serialize)]
 pub struct GetRequestsMetrics {
+    /// Number of GETs for getting the devices attached to the microVM.
+    pub devices_count: SharedMetric,
+    /// Number of GETs for getting the flattened device tree of the microVM.
+    pub fdt_count: SharedMetric,
     /// Number of GETs for getting information on the instance.
     pub instance_info_count: SharedMetric,
     /// Number of failures when obtaining information on the current instance.
@@ -153,6 +197,10 @@ pub struct PutRequestsMetrics {
     pub actions_count: SharedMetric,
     /// Number of failures in triggering an action on the VM.
     pub actions_fails: SharedMetric,
+    /// Number of PUTs submitting a batch of API requests.
+    pub batch_count: SharedMetric,
+    /// Number of batches rejected before any of their requests was executed.
+    pub batch_fails: SharedMetric,
     /// Number of PUTs for attaching source of boot.
     pub boot_source_count: SharedMetric,
     /// Number of failures during attaching source of boot.
@@ -173,6 +221,10 @@ pub struct PutRequestsMetrics {
     pub network_count: SharedMetric,
     /// Number of failures in creating a new network interface.
     pub network_fails: SharedMetric,
+    /// Number of PUTs for creating a snapshot of the microVM.
+    pub snapshot_count: SharedMetric,
+    /// Number of failures in creating a snapshot of the microVM.
+    pub snapshot_fails: SharedMetric,
 }
 
 /// Metrics specific to PATCH API Requests for counting user triggered actions and/or failures.
@@ -207,6 +259,11 @@ pub struct BlockDeviceMetrics {
     pub invalid_reqs_count: SharedMetric,
     /// Number of flushes operation triggered on this block device.
     pub flush_count: SharedMetric,
+    /// Number of requests retried after a transient error of the backing file.
+    pub io_retries: SharedMetric,
+    /// Number of times the rest of the queue was deferred because the maximum number of
+    /// requests were in flight.
+    pub in_flight_backpressure_count: SharedMetric,
     /// Number of events triggerd on the queue of this block device.
     pub queue_event_count: SharedMetric,
     /// Number of events ratelimiter-related.
@@ -272,6 +329,8 @@ pub struct MmdsMetrics {
     pub connections_created: SharedMetric,
     /// The number of connections cleaned up by the MMDS TCP handler.
     pub connections_destroyed: SharedMetric,
+    /// The number of guest requests refused because they exceeded the MMDS rate limit.
+    pub requests_throttled: SharedMetric,
 }
 
 /// Network-related metrics.
@@ -287,6 +346,8 @@ pub struct NetDeviceMetrics {
     pub rx_queue_event_count: SharedMetric,
     /// Number of events associated with the rate limiter installed on the receiving path.
     pub rx_event_rate_limiter_count: SharedMetric,
+    /// Number of expirations of the RX interrupt coalescing timer.
+    pub rx_coalescing_timer_event_count: SharedMetric,
     /// Number of events received on the associated tap.
     pub rx_tap_event_count: SharedMetric,
     /// Number of bytes received.
@@ -348,6 +409,81 @@ pub struct VcpuMetrics {
     pub failures: SharedMetric,
     /// Failures in configuring the CPUID.
     pub fitler_cpuid: SharedMetric,
+    /// Number of panics signaled by the guest through the pvpanic I/O port.
+    pub guest_panics: SharedMetric,
+}
+
+/// The number of vCPUs which have their own exit metrics.
+pub const MAX_VCPUS_WITH_EXIT_METRICS: usize = 32;
+
+/// Number of KVM exits of a single vCPU, by exit reason.
+#[derive(Default, Serialize)]
+pub struct VcpuExitMetrics {
+    /// Number of exits for handling input IO.
+    pub io_in: SharedMetric,
+    /// Number of exits for handling output IO.
+    pub io_out: SharedMetric,
+    /// Number of exits for handling MMIO reads.
+    pub mmio_read: SharedMetric,
+    /// Number of exits for handling MMIO writes.
+    pub mmio_write: SharedMetric,
+    /// Number of exits because the vCPU halted.
+    pub hlt: SharedMetric,
+    /// Number of exits because the guest shut down or reset.
+    pub shutdown: SharedMetric,
+    /// Number of exits for handling system events, e.g. the PSCI calls on aarch64.
+    pub system_event: SharedMetric,
+    /// Number of exits because KVM failed to enter the guest.
+    pub fail_entry: SharedMetric,
+    /// Number of exits because of a KVM internal error.
+    pub internal_error: SharedMetric,
+    /// Number of exits for any other reason.
+    pub other: SharedMetric,
+}
+
+impl VcpuExitMetrics {
+    // Returns whether the vCPU ever exited.
+    fn has_exited(&self) -> bool {
+        [
+            &self.io_in,
+            &self.io_out,
+            &self.mmio_read,
+            &self.mmio_write,
+            &self.hlt,
+            &self.shutdown,
+            &self.system_event,
+            &self.fail_entry,
+            &self.internal_error,
+            &self.other,
+        ]
+        .iter()
+        .any(|metric| metric.count() > 0)
+    }
+}
+
+/// Exit metrics of each vCPU, for spotting the vCPUs which exit a lot more than the others.
+/// They are serialized as a map from the vCPU ID to its metrics, leaving out the vCPUs which
+/// never exited.
+#[derive(Default)]
+pub struct PerVcpuExitMetrics([VcpuExitMetrics; MAX_VCPUS_WITH_EXIT_METRICS]);
+
+impl PerVcpuExitMetrics {
+    /// Returns the exit metrics of the vCPU `vcpu_id`, or `None` if it is too large.
+    pub fn get(&self, vcpu_id: usize) -> Option<&VcpuExitMetrics> {
+        self.0.get(vcpu_id)
+    }
+}
+
+impl Serialize for PerVcpuExitMetrics {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (vcpu_id, exits) in self.0.iter().enumerate() {
+            if exits.has_exited() {
+                map.serialize_entry(&vcpu_id, exits)?;
+            }
+        }
+        map.end()
+    }
 }
 
 /// Metrics specific to the machine manager as a whole.
@@ -357,6 +493,26 @@ pub struct VmmMetrics {
     pub device_events: SharedMetric,
     /// Metric for signaling a panic has occurred.
     pub panic_count: SharedMetric,
+    /// Number of times the watchdog found the guest stalled.
+    pub watchdog_expired: SharedMetric,
+    /// Number of times the idle monitor found the microVM idle.
+    pub idle_timeout_expired: SharedMetric,
+}
+
+/// Why the microVM stopped. Firecracker exits right after, so at most one of them is set in
+/// the last metrics it writes.
+#[derive(Default, Serialize)]
+pub struct StopReasonMetrics {
+    /// The guest powered off.
+    pub guest_shutdown: SharedMetric,
+    /// The guest reset, through the i8042 controller or with a triple fault on x86_64.
+    pub guest_reset: SharedMetric,
+    /// The guest panicked and the guest panic action is exit.
+    pub guest_panic: SharedMetric,
+    /// KVM failed to run a vCPU or exited for a reason the VMM can't handle.
+    pub internal_error: SharedMetric,
+    /// The VMM stopped the microVM.
+    pub vmm_stop: SharedMetric,
 }
 
 /// Memory usage metrics.
@@ -366,6 +522,56 @@ pub struct MemoryMetrics {
     pub dirty_pages: SharedMetric,
 }
 
+/// Milestones of the boot sequence of the microVM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootMilestone {
+    /// The kernel was loaded in the guest memory.
+    KernelLoaded,
+    /// The vCPUs were created.
+    VcpusCreated,
+    /// The devices were attached and their events registered.
+    DevicesRegistered,
+    /// The boot vCPU is about to run the first guest instruction.
+    GuestStarted,
+}
+
+/// Moments the boot sequence reached its milestones, in microseconds elapsed since the process
+/// started. The milestones which weren't reached yet are 0.
+#[derive(Default, Serialize)]
+pub struct BootMetrics {
+    #[serde(skip)]
+    process_start_us: AtomicUsize,
+    /// The kernel was loaded in the guest memory.
+    pub kernel_loaded_us: TimestampMetric,
+    /// The vCPUs were created.
+    pub vcpus_created_us: TimestampMetric,
+    /// The devices were attached and their events registered.
+    pub devices_registered_us: TimestampMetric,
+    /// The boot vCPU was about to run the first guest instruction.
+    pub guest_started_us: TimestampMetric,
+}
+
+impl BootMetrics {
+    /// Sets the wall clock time, in microseconds, when the process started. The milestones are
+    /// measured from there.
+    pub fn set_process_start_us(&self, time_us: u64) {
+        self.process_start_us
+            .store(time_us as usize, Ordering::Relaxed);
+    }
+
+    /// Records that the boot sequence reached `milestone` now.
+    pub fn reach(&self, milestone: BootMilestone) {
+        let elapsed_us = now_us().saturating_sub(self.process_start_us.load(Ordering::Relaxed));
+        let metric = match milestone {
+            BootMilestone::KernelLoaded => &self.kernel_loaded_us,
+            BootMilestone::VcpusCreated => &self.vcpus_created_us,
+            BootMilestone::DevicesRegistered => &self.devices_registered_us,
+            BootMilestone::GuestStarted => &self.guest_started_us,
+        };
+        metric.set(elapsed_us);
+    }
+}
+
 // The sole purpose of this struct is to produce an UTC timestamp when an instance is serialized.
 #[derive(Default)]
 struct SerializeToUtcTimestampMs;
@@ -384,6 +590,8 @@ pub struct FirecrackerMetrics {
     pub api_server: ApiServerMetrics,
     /// A block device's related metrics.
     pub block: BlockDeviceMetrics,
+    /// Metrics related to API DELETE requests.
+    pub delete_api_requests: DeleteRequestsMetrics,
     /// Metrics related to API GET requests.
     pub get_api_requests: GetRequestsMetrics,
     /// Metrics relaetd to the i8042 device.
@@ -402,12 +610,18 @@ pub struct FirecrackerMetrics {
     pub seccomp: SeccompMetrics,
     /// Metrics related to a vcpu's functioning.
     pub vcpu: VcpuMetrics,
+    /// KVM exits of each vCPU.
+    pub vcpu_exits: PerVcpuExitMetrics,
     /// Metrics related to the virtual machine manager.
     pub vmm: VmmMetrics,
+    /// Why the microVM stopped.
+    pub stop_reason: StopReasonMetrics,
     /// Metrics related to the UART device.
     pub uart: SerialDeviceMetrics,
     /// Memory usage metrics.
     pub memory: MemoryMetrics,
+    /// Milestones of the boot sequence.
+    pub boot: BootMetrics,
 }
 
 lazy_static! {
@@ -423,6 +637,7 @@ mod tests {
 
     use std::sync::Arc;
     use std::thread;
+    use std::time::Duration;
 
     #[test]
     fn test_metric() {
@@ -468,6 +683,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_vcpu_exit_metrics() {
+        let metrics = PerVcpuExitMetrics::default();
+        assert!(metrics.get(MAX_VCPUS_WITH_EXIT_METRICS).is_none());
+        assert_eq!(serde_json::to_string(&metrics).unwrap(), "{}");
+
+        metrics.get(1).unwrap().io_in.add(2);
+        metrics.get(1).unwrap().hlt.inc();
+        metrics.get(3).unwrap().mmio_write.inc();
+        let json: serde_json::Value = serde_json::to_value(&metrics).unwrap();
+        // Only the vCPUs which exited are listed.
+        assert_eq!(json.as_object().unwrap().len(), 2);
+        assert_eq!(json["1"]["io_in"], 2);
+        assert_eq!(json["1"]["hlt"], 1);
+        assert_eq!(json["1"]["mmio_write"], 0);
+        assert_eq!(json["3"]["mmio_write"], 1);
+
+        // The counters are reset by the serialization, but the vCPUs stay listed.
+        let json: serde_json::Value = serde_json::to_value(&metrics).unwrap();
+        assert_eq!(json.as_object().unwrap().len(), 2);
+        assert_eq!(json["1"]["io_in"], 0);
+    }
+
+    #[test]
+    fn test_boot_metrics() {
+        let metrics = BootMetrics::default();
+        metrics.set_process_start_us(now_us() as u64);
+        assert_eq!(metrics.kernel_loaded_us.get(), 0);
+
+        metrics.reach(BootMilestone::KernelLoaded);
+        thread::sleep(Duration::from_millis(1));
+        metrics.reach(BootMilestone::GuestStarted);
+        let kernel_loaded_us = metrics.kernel_loaded_us.get();
+        assert!(metrics.guest_started_us.get() > kernel_loaded_us);
+        assert_eq!(metrics.vcpus_created_us.get(), 0);
+
+        // The milestones keep their value across flushes, and the origin isn't logged.
+        let json: serde_json::Value = serde_json::to_value(&metrics).unwrap();
+        assert_eq!(json["kernel_loaded_us"], kernel_loaded_us as u64);
+        let json: serde_json::Value = serde_json::to_value(&metrics).unwrap();
+        assert_eq!(json["kernel_loaded_us"], kernel_loaded_us as u64);
+        assert!(json.get("process_start_us").is_none());
+    }
+
     #[test]
     fn test_serialize() {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
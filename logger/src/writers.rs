@@ -1,12 +1,14 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-//! Auxiliary module for flushing some input to a named PIPE (FIFO).
+//! Auxiliary module for flushing some input to a named PIPE (FIFO), to a regular file or to a
+//! syslog socket.
 
 use libc::O_NONBLOCK;
-use std::fs::{File, OpenOptions};
-use std::io::{LineWriter, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{LineWriter, Seek, SeekFrom, Write};
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixDatagram;
 use std::path::PathBuf;
 use std::result;
 use std::sync::{Mutex, MutexGuard};
@@ -15,10 +17,23 @@ use error::LoggerError;
 
 type Result<T> = result::Result<T, LoggerError>;
 
+/// Number of rotated files (`<path>.1` being the newest) kept besides the one in use.
+pub const MAX_ROTATED_FILES: usize = 5;
+
+// The output file along with the number of bytes written to it.
+#[derive(Debug)]
+struct Output {
+    line_writer: LineWriter<File>,
+    size: usize,
+}
+
 /// Structure `PipeLogWriter` used for writing to a file in a thread-safe way.
 #[derive(Debug)]
 pub struct PipeLogWriter {
-    line_writer: Mutex<LineWriter<File>>,
+    path: PathBuf,
+    // The size in bytes above which the output is rotated. Rotation is disabled when `None`.
+    max_size: Option<usize>,
+    output: Mutex<Output>,
 }
 
 impl PipeLogWriter {
@@ -31,32 +46,122 @@ impl PipeLogWriter {
             .open(&fifo)
         {
             Ok(t) => Ok(PipeLogWriter {
-                line_writer: Mutex::new(LineWriter::new(t)),
+                path: fifo,
+                max_size: None,
+                output: Mutex::new(Output {
+                    line_writer: LineWriter::new(t),
+                    size: 0,
+                }),
             }),
             Err(e) => Err(LoggerError::OpenFIFO(e)),
         }
     }
 
+    /// Enables size based rotation of the output. Only regular files are rotated, so this has
+    /// no effect when writing to a named pipe.
+    pub fn with_max_size(mut self, max_size: usize) -> PipeLogWriter {
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            if metadata.is_file() {
+                self.max_size = Some(max_size);
+                // The messages go after those already in the file, which count towards the
+                // limit.
+                if let Ok(output) = self.output.get_mut() {
+                    if output.line_writer.get_mut().seek(SeekFrom::End(0)).is_ok() {
+                        output.size = metadata.len() as usize;
+                    }
+                }
+            }
+        }
+        self
+    }
+
     pub fn write(&self, msg: &str) -> Result<()> {
-        let mut line_writer = self.get_line_writer()?;
-        line_writer
+        // Holding the lock across the rotation keeps concurrent writers, such as the panic
+        // hook, from writing to a file that is being renamed.
+        let mut output = self.get_output()?;
+        if let Some(max_size) = self.max_size {
+            if output.size > 0 && output.size + msg.len() > max_size {
+                self.rotate(&mut output)?;
+            }
+        }
+        output
+            .line_writer
             .write_all(msg.as_bytes())
-            .map_err(LoggerError::LogWrite)
+            .map_err(LoggerError::LogWrite)?;
+        output.size += msg.len();
+        Ok(())
+    }
+
+    // Shifts `<path>.N` to `<path>.N+1`, dropping the oldest file, moves the current file to
+    // `<path>.1` and continues writing to a fresh file.
+    fn rotate(&self, output: &mut Output) -> Result<()> {
+        output.line_writer.flush().map_err(LoggerError::LogFlush)?;
+        for index in (1..MAX_ROTATED_FILES).rev() {
+            let rotated_path = self.rotated_path(index);
+            if rotated_path.exists() {
+                fs::rename(&rotated_path, self.rotated_path(index + 1))
+                    .map_err(LoggerError::LogRotate)?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1)).map_err(LoggerError::LogRotate)?;
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.path)
+            .map_err(LoggerError::LogRotate)?;
+        *output = Output {
+            line_writer: LineWriter::new(file),
+            size: 0,
+        };
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
     }
 
-    fn get_line_writer(&self) -> Result<(MutexGuard<LineWriter<File>>)> {
-        self.line_writer
+    fn get_output(&self) -> Result<(MutexGuard<Output>)> {
+        self.output
             .lock()
             .map_err(|e| LoggerError::MutexLockFailure(format!("{}", e)))
     }
 }
 
+/// Structure `SyslogWriter` used for sending messages to a syslog daemon over a datagram socket.
+#[derive(Debug)]
+pub struct SyslogWriter {
+    socket: UnixDatagram,
+}
+
+impl SyslogWriter {
+    pub fn new(socket_path: &str) -> Result<SyslogWriter> {
+        let socket = UnixDatagram::unbound().map_err(LoggerError::OpenSyslog)?;
+        socket
+            .connect(socket_path)
+            .map_err(LoggerError::OpenSyslog)?;
+        Ok(SyslogWriter { socket })
+    }
+
+    pub fn write(&self, msg: &str) -> Result<()> {
+        self.socket
+            .send(msg.as_bytes())
+            .map(|_| ())
+            .map_err(LoggerError::LogWrite)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate tempfile;
 
     use self::tempfile::NamedTempFile;
     use super::*;
+    use libc;
+    use std::ffi::CString;
 
     #[test]
     fn test_new() {
@@ -78,4 +183,87 @@ mod tests {
         let res = fw.write(&msg);
         assert!(res.is_ok())
     }
+
+    #[test]
+    fn test_rotate() {
+        let log_dir = tempfile::tempdir().expect("Failed to create temporary directory.");
+        let log_path = log_dir.path().join("log");
+        File::create(&log_path).unwrap();
+        let file = String::from(log_path.to_str().unwrap());
+        let rotated_path = |index: usize| PathBuf::from(format!("{}.{}", file, index));
+
+        let fw = PipeLogWriter::new(&file).unwrap().with_max_size(16);
+        assert!(fw.write("first message\n").is_ok());
+        assert!(!rotated_path(1).exists());
+
+        // Going past the threshold moves the full file aside and starts a new one.
+        assert!(fw.write("second message\n").is_ok());
+        assert_eq!(
+            fs::read_to_string(rotated_path(1)).unwrap(),
+            "first message\n"
+        );
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "second message\n");
+
+        // Older files are shifted and only `MAX_ROTATED_FILES` of them are kept.
+        for index in 0..MAX_ROTATED_FILES + 1 {
+            assert!(fw.write(&format!("message {:08}\n", index)).is_ok());
+        }
+        assert_eq!(
+            fs::read_to_string(rotated_path(1)).unwrap(),
+            format!("message {:08}\n", MAX_ROTATED_FILES - 1)
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(MAX_ROTATED_FILES)).unwrap(),
+            "message 00000000\n"
+        );
+        assert!(!rotated_path(MAX_ROTATED_FILES + 1).exists());
+    }
+
+    #[test]
+    fn test_rotate_existing_file() {
+        let log_dir = tempfile::tempdir().expect("Failed to create temporary directory.");
+        let log_path = log_dir.path().join("log");
+        fs::write(&log_path, "old message\n").unwrap();
+        let file = String::from(log_path.to_str().unwrap());
+
+        // The existing content is kept, and the first write already goes past the threshold.
+        let fw = PipeLogWriter::new(&file).unwrap().with_max_size(16);
+        assert!(fw.write("new message\n").is_ok());
+        assert_eq!(
+            fs::read_to_string(format!("{}.1", file)).unwrap(),
+            "old message\n"
+        );
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "new message\n");
+    }
+
+    #[test]
+    fn test_syslog_write() {
+        let socket_dir = tempfile::tempdir().expect("Failed to create temporary directory.");
+        let socket_path = socket_dir.path().join("syslog");
+        let syslog_path = String::from(socket_path.to_str().unwrap());
+
+        assert!(SyslogWriter::new(&syslog_path).is_err());
+
+        let receiver = UnixDatagram::bind(&socket_path).unwrap();
+        let sw = SyslogWriter::new(&syslog_path).unwrap();
+        assert!(sw.write("some message").is_ok());
+
+        let mut buf = [0u8; 64];
+        let count = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..count], b"some message");
+    }
+
+    #[test]
+    fn test_no_rotate_fifo() {
+        let fifo_dir = tempfile::tempdir().expect("Failed to create temporary directory.");
+        let fifo_path = fifo_dir.path().join("fifo");
+        let fifo = CString::new(fifo_path.to_str().unwrap()).unwrap();
+        // Safe because the path is a valid C string and we check the return value.
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+        let fw = PipeLogWriter::new(fifo_path.to_str().unwrap())
+            .unwrap()
+            .with_max_size(1);
+        assert!(fw.max_size.is_none());
+    }
 }
@@ -8,6 +8,7 @@
 //! Track memory regions that are mapped to the guest microVM.
 
 use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
 use std::{mem, result};
 
@@ -24,6 +25,8 @@ pub enum Error {
     InvalidGuestAddressRange(GuestAddress, usize),
     /// Failure in accessing the memory located at some address.
     MemoryAccess(GuestAddress, mmap::Error),
+    /// Failure in binding the memory to a host NUMA node.
+    MemoryBindFailed(mmap::Error),
     /// Failure in creating an anonymous shared mapping.
     MemoryMappingFailed(mmap::Error),
     /// Failure in initializing guest memory.
@@ -63,6 +66,20 @@ impl GuestMemory {
     /// Creates a container for guest memory regions.
     /// Valid memory regions are specified as a Vec of (Address, Size) tuples sorted by Address.
     pub fn new(ranges: &[(GuestAddress, usize)]) -> Result<GuestMemory> {
+        GuestMemory::with_mappings(ranges, MemoryMapping::new)
+    }
+
+    /// Creates a container for guest memory regions which are backed by anonymous files, so that
+    /// they can be shared with other processes, such as vhost-user backends.
+    /// Valid memory regions are specified as a Vec of (Address, Size) tuples sorted by Address.
+    pub fn new_shared(ranges: &[(GuestAddress, usize)]) -> Result<GuestMemory> {
+        GuestMemory::with_mappings(ranges, MemoryMapping::new_shared)
+    }
+
+    fn with_mappings<F>(ranges: &[(GuestAddress, usize)], map: F) -> Result<GuestMemory>
+    where
+        F: Fn(usize) -> result::Result<MemoryMapping, mmap::Error>,
+    {
         if ranges.is_empty() {
             return Err(Error::NoMemoryRegions);
         }
@@ -79,7 +96,7 @@ impl GuestMemory {
                 }
             }
 
-            let mapping = MemoryMapping::new(range.1).map_err(Error::MemoryMappingFailed)?;
+            let mapping = map(range.1).map_err(Error::MemoryMappingFailed)?;
             regions.push(MemoryRegion {
                 mapping,
                 guest_base: range.0,
@@ -111,6 +128,25 @@ impl GuestMemory {
             .map_or(GuestAddress(0), |region| region_end(region))
     }
 
+    /// Returns the number of bytes mapped for the guest, summed over all the memory regions.
+    /// The regions never overlap, so each guest address is counted at most once, and the gaps
+    /// between the regions are left out.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestMemory};
+    /// # fn test_total_size() -> Result<(), ()> {
+    ///     let gm = GuestMemory::new(&vec![(GuestAddress(0x0), 0x400), (GuestAddress(0x800), 0x400)])
+    ///         .map_err(|_| ())?;
+    ///     assert_eq!(gm.total_size(), 0x800);
+    ///     Ok(())
+    /// # }
+    /// ```
+    pub fn total_size(&self) -> u64 {
+        self.regions.iter().map(|region| region.size() as u64).sum()
+    }
+
     /// Returns true if the given address is within the memory range available to the guest.
     pub fn address_in_range(&self, addr: GuestAddress) -> bool {
         for region in self.regions.iter() {
@@ -138,6 +174,49 @@ impl GuestMemory {
         self.regions.len()
     }
 
+    /// Returns whether all the memory regions can be shared with other processes.
+    pub fn is_shared(&self) -> bool {
+        self.regions
+            .iter()
+            .all(|region| region.mapping.fd().is_some())
+    }
+
+    /// Returns the file descriptor backing the memory region at `index`, if the region exists
+    /// and can be shared with other processes.
+    pub fn region_fd(&self, index: usize) -> Option<RawFd> {
+        self.regions
+            .get(index)
+            .and_then(|region| region.mapping.fd())
+    }
+
+    /// Binds all the guest memory to the host NUMA node `node`. Should be called before the
+    /// memory is accessed, since pages which are already resident are not moved.
+    pub fn bind_to_node(&self, node: u32) -> Result<()> {
+        for region in self.regions.iter() {
+            region
+                .mapping
+                .bind_to_node(node)
+                .map_err(Error::MemoryBindFailed)?;
+        }
+        Ok(())
+    }
+
+    /// Faults in every page of the guest memory, so that the guest doesn't stall on demand
+    /// paging at runtime. The contents of the memory are left untouched.
+    pub fn populate(&self) {
+        for region in self.regions.iter() {
+            region.mapping.populate();
+        }
+    }
+
+    /// Overwrites every region of the guest memory with zeros, so that the guest doesn't see
+    /// what the host memory held before. This faults in the whole guest memory.
+    pub fn zero(&self) {
+        for region in self.regions.iter() {
+            region.mapping.zero();
+        }
+    }
+
     /// Perform the specified action on each region's addresses.
     pub fn with_regions<F, E>(&self, cb: F) -> result::Result<(), E>
     where
@@ -494,6 +573,20 @@ mod tests {
         assert!(guest_mem.checked_offset(start_addr2, 0xc00).is_none());
     }
 
+    #[test]
+    fn test_total_size() {
+        // The gap between the regions is not mapped.
+        let guest_mem =
+            GuestMemory::new(&[(GuestAddress(0x0), 0x400), (GuestAddress(0x800), 0x1000)]).unwrap();
+        assert_eq!(guest_mem.total_size(), 0x1400);
+
+        // Adjacent regions are each counted once.
+        let guest_mem =
+            GuestMemory::new(&[(GuestAddress(0x0), 0x1000), (GuestAddress(0x1000), 0x1000)])
+                .unwrap();
+        assert_eq!(guest_mem.total_size(), 0x2000);
+    }
+
     #[test]
     fn overlap_memory() {
         let start_addr1 = GuestAddress(0x0);
@@ -505,6 +598,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_shared_regions() {
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        assert!(!gm.is_shared());
+        assert!(gm.region_fd(0).is_none());
+
+        let gm =
+            GuestMemory::new_shared(&[(GuestAddress(0x0), 0x1000), (GuestAddress(0x2000), 0x1000)])
+                .unwrap();
+        assert!(gm.is_shared());
+        assert!(gm.region_fd(0).is_some());
+        assert!(gm.region_fd(1).is_some());
+        assert!(gm.region_fd(2).is_none());
+        gm.write_obj_at_addr(0xaa55u16, GuestAddress(0x2100))
+            .unwrap();
+        let val: u16 = gm.read_obj_from_addr(GuestAddress(0x2100)).unwrap();
+        assert_eq!(val, 0xaa55);
+    }
+
+    #[test]
+    fn test_zero() {
+        for gm in &[
+            GuestMemory::new(&[(GuestAddress(0x0), 0x2000), (GuestAddress(0x4000), 0x1000)])
+                .unwrap(),
+            GuestMemory::new_shared(&[(GuestAddress(0x0), 0x2000), (GuestAddress(0x4000), 0x1000)])
+                .unwrap(),
+        ] {
+            // Fill the regions as if they were handed over with leftover data.
+            for addr in &[0x0, 0x1ff8, 0x4000, 0x4ff8] {
+                gm.write_obj_at_addr(0xaa55_aa55_aa55_aa55u64, GuestAddress(*addr))
+                    .unwrap();
+            }
+
+            gm.zero();
+            let mut buf = vec![0xffu8; 0x2000];
+            gm.read_slice_at_addr(&mut buf, GuestAddress(0x0)).unwrap();
+            assert!(buf.iter().all(|b| *b == 0));
+            let mut buf = vec![0xffu8; 0x1000];
+            gm.read_slice_at_addr(&mut buf, GuestAddress(0x4000))
+                .unwrap();
+            assert!(buf.iter().all(|b| *b == 0));
+        }
+    }
+
     #[test]
     fn test_read_u64() {
         let start_addr1 = GuestAddress(0x0);
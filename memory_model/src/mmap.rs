@@ -9,7 +9,9 @@
 //! mmap object leaves scope.
 
 use std;
+use std::fs::File;
 use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::ptr::null_mut;
 
 use libc;
@@ -34,10 +36,18 @@ pub enum Error {
 }
 type Result<T> = std::result::Result<T, Error>;
 
+// See include/uapi/linux/memfd.h in the kernel code.
+const MFD_CLOEXEC: libc::c_uint = 0x0001;
+const MEMFD_NAME: &[u8] = b"guest_mem\0";
+// See include/uapi/linux/mempolicy.h in the kernel code.
+const MPOL_BIND: libc::c_int = 2;
+
 /// Wraps an anonymous shared memory mapping in the current process.
 pub struct MemoryMapping {
     addr: *mut u8,
     size: usize,
+    // The anonymous file backing the mapping, if it can be shared with other processes.
+    file: Option<File>,
 }
 
 // Send and Sync aren't automatically inherited for the raw address pointer.
@@ -71,9 +81,104 @@ impl MemoryMapping {
         Ok(MemoryMapping {
             addr: addr as *mut u8,
             size,
+            file: None,
         })
     }
 
+    /// Creates a shared mapping of `size` bytes backed by an anonymous file, which can be passed
+    /// to other processes.
+    ///
+    /// # Arguments
+    /// * `size` - Size of memory region in bytes.
+    pub fn new_shared(size: usize) -> Result<MemoryMapping> {
+        // This is safe because we pass a valid null-terminated name and check the return value.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_memfd_create,
+                MEMFD_NAME.as_ptr() as *const libc::c_char,
+                MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+        }
+        // This is safe because we own the file descriptor we have just created.
+        let file = unsafe { File::from_raw_fd(fd as RawFd) };
+        file.set_len(size as u64).map_err(Error::SystemCallFailed)?;
+
+        // This is safe because we are mapping a file we own in a place not already used by any
+        // other area in this process.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_NORESERVE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+        }
+        Ok(MemoryMapping {
+            addr: addr as *mut u8,
+            size,
+            file: Some(file),
+        })
+    }
+
+    /// Returns the file descriptor backing the memory region, if the region can be shared with
+    /// other processes.
+    pub fn fd(&self) -> Option<RawFd> {
+        self.file.as_ref().map(|file| file.as_raw_fd())
+    }
+
+    /// Faults in every page of the memory region, so that accessing it later doesn't stall on
+    /// demand paging. The contents of the region are left untouched.
+    pub fn populate(&self) {
+        // This is safe because sysconf has no side effects.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        for offset in (0..self.size).step_by(page_size) {
+            // This is safe because the offset is within the mapping. The mapping is shared, so
+            // reading from it allocates the page instead of mapping the zero page.
+            unsafe { std::ptr::read_volatile(self.addr.add(offset)) };
+        }
+    }
+
+    /// Overwrites the whole memory region with zeros. Unlike relying on a fresh anonymous
+    /// mapping, this also clears memory which was used before, e.g. a shared mapping of a file.
+    pub fn zero(&self) {
+        // This is safe because the range is our own mapping.
+        unsafe { std::ptr::write_bytes(self.addr, 0, self.size) };
+    }
+
+    /// Binds the memory region to the host NUMA node `node`, so that its pages are only allocated
+    /// on that node. Pages which are already resident are not moved.
+    pub fn bind_to_node(&self, node: u32) -> Result<()> {
+        let bits = 8 * std::mem::size_of::<libc::c_ulong>();
+        let node = node as usize;
+        let mut nodemask = vec![0 as libc::c_ulong; node / bits + 1];
+        nodemask[node / bits] |= 1 << (node % bits);
+        // This is safe because the range is our own mapping and the kernel doesn't read past
+        // the end of the node mask. The kernel ignores the last bit of `maxnode`, hence the + 1.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                self.addr as *mut libc::c_void,
+                self.size,
+                MPOL_BIND,
+                nodemask.as_ptr(),
+                nodemask.len() * bits + 1,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
     /// Returns a pointer to the beginning of the memory region.  Should only be
     /// used for passing this region to ioctls for setting guest memory.
     pub fn as_ptr(&self) -> *mut u8 {
@@ -317,6 +422,133 @@ mod tests {
     fn basic_map() {
         let m = MemoryMapping::new(1024).unwrap();
         assert_eq!(1024, m.size());
+        assert!(m.fd().is_none());
+    }
+
+    #[test]
+    fn shared_map() {
+        let m = MemoryMapping::new_shared(4096).unwrap();
+        assert_eq!(4096, m.size());
+        assert_eq!(m.write_slice(&[1, 2, 3], 1024).unwrap(), 3);
+
+        // The contents are visible through the backing file.
+        let mut file = unsafe { File::from_raw_fd(libc::dup(m.fd().unwrap())) };
+        let mut buf = [0u8; 1027];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[1024..], &[1, 2, 3]);
+
+        // Error case: the size is invalid.
+        assert!(MemoryMapping::new_shared(0).is_err());
+    }
+
+    // Returns how many pages of the mapping are resident in memory, or None if the host
+    // doesn't support querying it.
+    fn resident_pages(m: &MemoryMapping) -> Option<usize> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut vec = vec![0u8; (m.size() + page_size - 1) / page_size];
+        let ret = unsafe {
+            libc::mincore(
+                m.as_ptr() as *mut libc::c_void,
+                m.size(),
+                vec.as_mut_ptr() as *mut libc::c_uchar,
+            )
+        };
+        if ret < 0 {
+            return None;
+        }
+        Some(vec.iter().filter(|v| *v & 1 == 1).count())
+    }
+
+    #[test]
+    fn test_populate() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        for m in [
+            MemoryMapping::new(16 * page_size).unwrap(),
+            MemoryMapping::new_shared(16 * page_size).unwrap(),
+        ]
+        .iter()
+        {
+            m.write_obj(0x55u8, 3 * page_size).unwrap();
+            let resident = match resident_pages(&m) {
+                Some(count) => count,
+                None => return,
+            };
+            assert!(resident < 16);
+
+            m.populate();
+            assert_eq!(resident_pages(&m), Some(16));
+            assert_eq!(m.read_obj::<u8>(3 * page_size).unwrap(), 0x55);
+        }
+    }
+
+    #[test]
+    fn test_zero() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        for m in [
+            MemoryMapping::new(4 * page_size).unwrap(),
+            MemoryMapping::new_shared(4 * page_size).unwrap(),
+        ]
+        .iter()
+        {
+            for offset in (0..4 * page_size).step_by(page_size / 2) {
+                m.write_obj(0x55u8, offset).unwrap();
+            }
+            m.write_obj(0x55u8, 4 * page_size - 1).unwrap();
+
+            m.zero();
+            for offset in (0..4 * page_size).step_by(page_size / 2) {
+                assert_eq!(m.read_obj::<u8>(offset).unwrap(), 0);
+            }
+            assert_eq!(m.read_obj::<u8>(4 * page_size - 1).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_bind_to_node() {
+        let m = MemoryMapping::new(0x1000).unwrap();
+
+        // Error case: the node doesn't exist.
+        match m.bind_to_node(1 << 16) {
+            Err(Error::SystemCallFailed(_)) => (),
+            _ => panic!("Expected the binding to fail."),
+        }
+
+        // The binding is only meaningful on hosts with multiple NUMA nodes.
+        let mut nodes: Vec<u32> = match std::fs::read_dir("/sys/devices/system/node") {
+            Ok(entries) => entries
+                .filter_map(|entry| {
+                    let name = entry.ok()?.file_name().into_string().ok()?;
+                    name.trim_start_matches("node").parse().ok()
+                })
+                .collect(),
+            Err(_) => return,
+        };
+        if nodes.len() < 2 {
+            return;
+        }
+        nodes.sort();
+        let node = *nodes.last().unwrap();
+        m.bind_to_node(node).unwrap();
+
+        let mut mode: libc::c_int = -1;
+        let mut nodemask = [0 as libc::c_ulong; 16];
+        let bits = 8 * mem::size_of::<libc::c_ulong>();
+        // See include/uapi/linux/mempolicy.h in the kernel code.
+        const MPOL_F_ADDR: libc::c_ulong = 1 << 1;
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut mode as *mut libc::c_int,
+                nodemask.as_mut_ptr(),
+                nodemask.len() * bits,
+                m.as_ptr(),
+                MPOL_F_ADDR,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(mode, MPOL_BIND);
+        let node = node as usize;
+        assert_eq!(nodemask[node / bits], 1 << (node % bits));
     }
 
     #[test]
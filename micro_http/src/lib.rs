@@ -30,6 +30,7 @@
 //! - OK - 200
 //! - Bad Request - 400
 //! - Not Found - 404
+//! - Too Many Requests - 429
 //! - Internal Server Error - 500
 //! - Not Implemented - 501
 //!
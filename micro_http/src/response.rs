@@ -20,6 +20,8 @@ pub enum StatusCode {
     BadRequest,
     /// 404, Not Found
     NotFound,
+    /// 429, Too Many Requests
+    TooManyRequests,
     /// 500, Internal Server Error
     InternalServerError,
     /// 501, Not Implemented
@@ -32,6 +34,7 @@ impl StatusCode {
             StatusCode::OK => b"200",
             StatusCode::BadRequest => b"400",
             StatusCode::NotFound => b"404",
+            StatusCode::TooManyRequests => b"429",
             StatusCode::InternalServerError => b"500",
             StatusCode::NotImplemented => b"501",
         }
@@ -174,6 +177,7 @@ mod tests {
         assert_eq!(StatusCode::OK.raw(), b"200");
         assert_eq!(StatusCode::BadRequest.raw(), b"400");
         assert_eq!(StatusCode::NotFound.raw(), b"404");
+        assert_eq!(StatusCode::TooManyRequests.raw(), b"429");
         assert_eq!(StatusCode::InternalServerError.raw(), b"500");
         assert_eq!(StatusCode::NotImplemented.raw(), b"501");
     }
@@ -1,20 +1,48 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
 use json_patch::merge;
-use serde_json::Value;
+use rate_limiter::TokenBucket;
+use serde_json::{self, Map, Value};
+
+/// Top level key under which Firecracker publishes the metadata it generates itself. It takes
+/// precedence over the data stored by the user under the same key.
+pub const FIRECRACKER_KEY: &str = "firecracker";
 
 /// The Mmds is the Microvm Metadata Service represented as an untyped json.
 #[derive(Clone)]
 pub struct Mmds {
     data_store: Value,
     is_initialized: bool,
+    // Data stores served instead of the default one to the guest requests coming from the
+    // given source addresses, e.g. to give each guest interface its own configuration.
+    namespaces: BTreeMap<Ipv4Addr, Value>,
+    // Description of the devices attached to the microVM, served under
+    // `/firecracker/devices`.
+    device_info: Value,
+    // File the data stores are persisted to on every update, which makes them survive a restart
+    // of Firecracker. The data stores are only read from it when the file is set.
+    backing_file: Option<PathBuf>,
+    // Limits the rate of the guest requests, coming from all the guest interfaces together.
+    rate_limiter: Option<TokenBucket>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
     NotFound,
     UnsupportedValueType,
+    /// The backing file cannot be read or written.
+    BackingFile(String),
+    /// The backing file doesn't hold a data store as written by Firecracker.
+    CorruptBackingFile(String),
 }
 
 impl Error {
@@ -24,6 +52,10 @@ impl Error {
             Error::UnsupportedValueType => {
                 "Cannot add non-strings values to the MMDS data-store.".to_string()
             }
+            Error::BackingFile(ref e) => format!("Cannot access the MMDS backing file: {}", e),
+            Error::CorruptBackingFile(ref e) => {
+                format!("The MMDS backing file is corrupt: {}", e)
+            }
         }
     }
 }
@@ -33,8 +65,91 @@ impl Default for Mmds {
         Mmds {
             data_store: Value::default(),
             is_initialized: false,
+            namespaces: BTreeMap::new(),
+            device_info: Value::default(),
+            backing_file: None,
+            rate_limiter: None,
+        }
+    }
+}
+
+// FNV-1a hash of the serialized data store, stored along with it in the backing file.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+// The backing file holds the checksum of the snapshot of the data stores on its first line, and
+// the snapshot itself on the rest of the file.
+fn encode_backing_file(snapshot: &Value) -> String {
+    let data = snapshot.to_string();
+    format!("{:016x}\n{}", checksum(data.as_bytes()), data)
+}
+
+fn decode_backing_file(content: &str) -> Result<Value, Error> {
+    let mut parts = content.splitn(2, '\n');
+    let (header, data) = match (parts.next(), parts.next()) {
+        (Some(header), Some(data)) => (header, data),
+        _ => return Err(Error::CorruptBackingFile("missing checksum".to_string())),
+    };
+    let expected = u64::from_str_radix(header, 16)
+        .map_err(|_| Error::CorruptBackingFile("invalid checksum".to_string()))?;
+    if checksum(data.as_bytes()) != expected {
+        return Err(Error::CorruptBackingFile("checksum mismatch".to_string()));
+    }
+
+    serde_json::from_str(data).map_err(|e| Error::CorruptBackingFile(e.to_string()))
+}
+
+// Writes the snapshot to a temporary file next to `path`, which then replaces `path`. The
+// backing file thus always holds whole data stores, even if Firecracker stops midway.
+fn write_backing_file(path: &Path, snapshot: &Value) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    // The data store may hold credentials, so only the Firecracker user may read it. The mode
+    // only applies to a new file, hence a temporary file left behind by a failed update is
+    // removed first.
+    if let Err(e) = fs::remove_file(&tmp_path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
         }
     }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(encode_backing_file(snapshot).as_bytes())?;
+    // The API server thread may only flush files with `fdatasync`, which the seccomp filters
+    // allow for the block devices.
+    file.sync_data()?;
+    fs::rename(&tmp_path, path)?;
+
+    // The rename is only durable once the directory holding the file is synced.
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_data()
+}
+
+// Publishes the device description in the data store, unless the data store holds
+// something else than a dictionary.
+fn insert_device_info(data_store: &mut Value, device_info: &Value) {
+    if device_info.is_null() {
+        return;
+    }
+    if data_store.is_null() {
+        *data_store = Value::Object(Map::new());
+    }
+    if let Some(map) = data_store.as_object_mut() {
+        let mut firecracker = Map::new();
+        firecracker.insert(String::from("devices"), device_info.clone());
+        map.insert(String::from(FIRECRACKER_KEY), Value::Object(firecracker));
+    }
 }
 
 impl Mmds {
@@ -67,17 +182,148 @@ impl Mmds {
         Ok(())
     }
 
+    /// Persists the data stores to `path` on every update from now on. If the file exists, the
+    /// data stores are first loaded from it, so that the metadata survives a restart of
+    /// Firecracker.
+    pub fn set_backing_file(&mut self, path: PathBuf) -> Result<(), Error> {
+        match fs::read_to_string(&path) {
+            Ok(content) => self.restore(&decode_backing_file(&content)?)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(Error::BackingFile(e.to_string())),
+        }
+        self.backing_file = Some(path);
+        Ok(())
+    }
+
+    // Builds the contents of the backing file: the default data store, if it was set, and the
+    // namespaces keyed by their source address.
+    fn snapshot(&self) -> Value {
+        let mut snapshot = Map::new();
+        if self.is_initialized {
+            snapshot.insert(String::from("data_store"), self.data_store.clone());
+        }
+        let namespaces = self
+            .namespaces
+            .iter()
+            .map(|(source, data_store)| (source.to_string(), data_store.clone()))
+            .collect();
+        snapshot.insert(String::from("namespaces"), Value::Object(namespaces));
+        Value::Object(snapshot)
+    }
+
+    // Replaces the data stores with the ones of a snapshot, which are checked as if they were
+    // set through the API. Nothing is replaced if the snapshot is invalid.
+    fn restore(&mut self, snapshot: &Value) -> Result<(), Error> {
+        let corrupt = |e: Error| Error::CorruptBackingFile(e.to_string());
+
+        let data_store = snapshot.get("data_store").cloned();
+        if let Some(ref data_store) = data_store {
+            Mmds::check_data_valid(data_store).map_err(corrupt)?;
+        }
+        let mut namespaces = BTreeMap::new();
+        let snapshot_namespaces = snapshot
+            .get("namespaces")
+            .and_then(Value::as_object)
+            .ok_or_else(|| Error::CorruptBackingFile("missing namespaces".to_string()))?;
+        for (source, data_store) in snapshot_namespaces {
+            let source = source.parse::<Ipv4Addr>().map_err(|_| {
+                Error::CorruptBackingFile(format!("invalid namespace address {}", source))
+            })?;
+            Mmds::check_data_valid(data_store).map_err(corrupt)?;
+            namespaces.insert(source, data_store.clone());
+        }
+
+        if let Some(data_store) = data_store {
+            self.data_store = data_store;
+            self.is_initialized = true;
+            insert_device_info(&mut self.data_store, &self.device_info);
+        }
+        for data_store in namespaces.values_mut() {
+            insert_device_info(data_store, &self.device_info);
+        }
+        self.namespaces = namespaces;
+        Ok(())
+    }
+
+    // Replaces the default data store, or the one of the namespace of the `source` address,
+    // once it is persisted to the backing file, if any. The data stores are left untouched if
+    // they cannot be persisted.
+    fn commit(&mut self, source: Option<Ipv4Addr>, mut data_store: Value) -> Result<(), Error> {
+        insert_device_info(&mut data_store, &self.device_info);
+        let previous = match source {
+            Some(source) => self.namespaces.insert(source, data_store),
+            None => Some(mem::replace(&mut self.data_store, data_store)),
+        };
+        let was_initialized = self.is_initialized;
+        if source.is_none() {
+            self.is_initialized = true;
+        }
+
+        if let Some(ref path) = self.backing_file {
+            if let Err(e) = write_backing_file(path, &self.snapshot()) {
+                match (source, previous) {
+                    (Some(source), Some(previous)) => {
+                        self.namespaces.insert(source, previous);
+                    }
+                    (Some(source), None) => {
+                        self.namespaces.remove(&source);
+                    }
+                    (None, previous) => self.data_store = previous.unwrap_or_default(),
+                }
+                self.is_initialized = was_initialized;
+                return Err(Error::BackingFile(e.to_string()));
+            }
+        }
+        Ok(())
+    }
+
     pub fn put_data(&mut self, data: Value) -> Result<(), Error> {
         Mmds::check_data_valid(&data)?;
-        self.data_store = data;
-        self.is_initialized = true;
-        Ok(())
+        self.commit(None, data)
     }
 
     pub fn patch_data(&mut self, patch_data: Value) -> Result<(), Error> {
         Mmds::check_data_valid(&patch_data)?;
         self.check_data_store_initialized()?;
-        merge(&mut self.data_store, &patch_data);
+        let mut data_store = self.data_store.clone();
+        merge(&mut data_store, &patch_data);
+        self.commit(None, data_store)
+    }
+
+    /// Replaces the data store served to the guest requests coming from the `source` address,
+    /// creating its namespace if needed.
+    pub fn put_namespace_data(&mut self, source: Ipv4Addr, data: Value) -> Result<(), Error> {
+        Mmds::check_data_valid(&data)?;
+        self.commit(Some(source), data)
+    }
+
+    /// Updates the data store served to the guest requests coming from the `source` address.
+    /// A NotFound error is returned if there is no namespace for this address.
+    pub fn patch_namespace_data(
+        &mut self,
+        source: Ipv4Addr,
+        patch_data: Value,
+    ) -> Result<(), Error> {
+        Mmds::check_data_valid(&patch_data)?;
+        let mut data_store = self
+            .namespaces
+            .get(&source)
+            .cloned()
+            .ok_or(Error::NotFound)?;
+        merge(&mut data_store, &patch_data);
+        self.commit(Some(source), data_store)
+    }
+
+    /// Replaces the description of the devices attached to the microVM, which the guest can
+    /// read under `/firecracker/devices`. The description is kept across the updates of the
+    /// data store, and only reaches the backing file with the next update.
+    pub fn set_device_info(&mut self, device_info: Value) -> Result<(), Error> {
+        Mmds::check_data_valid(&device_info)?;
+        self.device_info = device_info;
+        insert_device_info(&mut self.data_store, &self.device_info);
+        for data_store in self.namespaces.values_mut() {
+            insert_device_info(data_store, &self.device_info);
+        }
         Ok(())
     }
 
@@ -88,6 +334,29 @@ impl Mmds {
         self.data_store.to_string()
     }
 
+    /// Limits the rate of the guest requests with a token bucket, from which each request takes
+    /// a token. The guest requests are not limited if `rate_limiter` is None.
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<TokenBucket>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// Takes a token for a guest request from the rate limiter, if any. Returns false if the
+    /// request exceeds the rate limit and must not be served.
+    pub fn consume_request(&mut self) -> bool {
+        self.rate_limiter
+            .as_mut()
+            .map_or(true, |rate_limiter| rate_limiter.reduce(1))
+    }
+
+    /// Returns the data store of the namespace of the `source` address, or a NotFound error if
+    /// there is no such namespace.
+    pub fn get_namespace_data_str(&self, source: Ipv4Addr) -> Result<String, Error> {
+        self.namespaces
+            .get(&source)
+            .map(Value::to_string)
+            .ok_or(Error::NotFound)
+    }
+
     /// This function replicates the behavior of the Instance Metadata Service
     /// https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/ec2-instance-metadata.html
     /// 1. For a (key, value) pair where the value is a dictionary, it will return all the keys
@@ -97,12 +366,23 @@ impl Mmds {
     ///
     /// When the path is not found, a NotFound error is returned.
     pub fn get_value(&self, path: String) -> Result<Vec<String>, Error> {
+        Mmds::get_value_from(&self.data_store, path)
+    }
+
+    /// Looks up `path` like `get_value()` does, in the namespace of the `source` address of a
+    /// guest request. The default data store is used when there is no such namespace.
+    pub fn get_value_for(&self, source: Ipv4Addr, path: String) -> Result<Vec<String>, Error> {
+        let data_store = self.namespaces.get(&source).unwrap_or(&self.data_store);
+        Mmds::get_value_from(data_store, path)
+    }
+
+    fn get_value_from(data_store: &Value, path: String) -> Result<Vec<String>, Error> {
         // The pointer function splits the input by "/". With a trailing "/", pointer does not
         // know how to get the object.
         let value = if path.ends_with('/') {
-            self.data_store.pointer(&path.as_str()[..(path.len() - 1)])
+            data_store.pointer(&path.as_str()[..(path.len() - 1)])
         } else {
-            self.data_store.pointer(path.as_str())
+            data_store.pointer(path.as_str())
         };
 
         match value {
@@ -146,8 +426,11 @@ impl Mmds {
 
 #[cfg(test)]
 mod tests {
+    extern crate tempfile;
+
     use super::*;
     use serde_json;
+    use std::os::unix::fs::PermissionsExt;
 
     #[test]
     fn test_mmds() {
@@ -236,6 +519,61 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_device_info() {
+        let mut mmds = Mmds::default();
+        let device_info: Value =
+            serde_json::from_str(r#"{"rootfs": {"addr": "0xd0000000", "irq": "5"}}"#).unwrap();
+        mmds.set_device_info(device_info).unwrap();
+
+        // The devices are served before any data is stored by the user.
+        assert_eq!(
+            mmds.get_value("/firecracker/devices/".to_string()).unwrap(),
+            vec!["rootfs/"]
+        );
+        assert_eq!(
+            mmds.get_value("/firecracker/devices/rootfs/irq".to_string())
+                .unwrap(),
+            vec!["5"]
+        );
+        assert!(mmds.check_data_store_initialized().is_err());
+
+        // The devices are kept across the updates of the user data, and take precedence over
+        // the user data stored under the same key.
+        let data = r#"{"age": "43", "firecracker": {"devices": "none"}}"#;
+        mmds.put_data(serde_json::from_str(data).unwrap()).unwrap();
+        assert_eq!(
+            mmds.get_value("/".to_string()).unwrap(),
+            vec!["age", "firecracker/"]
+        );
+        assert_eq!(
+            mmds.get_value("/firecracker/devices/rootfs/addr".to_string())
+                .unwrap(),
+            vec!["0xd0000000"]
+        );
+        mmds.patch_data(serde_json::from_str(r#"{"firecracker": "none"}"#).unwrap())
+            .unwrap();
+        assert_eq!(
+            mmds.get_value("/firecracker/devices/".to_string()).unwrap(),
+            vec!["rootfs/"]
+        );
+
+        // The description is replaced as a whole.
+        let device_info: Value = serde_json::from_str(r#"{"net0": {"irq": "6"}}"#).unwrap();
+        mmds.set_device_info(device_info).unwrap();
+        assert_eq!(
+            mmds.get_value("/firecracker/devices/".to_string()).unwrap(),
+            vec!["net0/"]
+        );
+
+        // The description follows the same rules as the user data.
+        let device_info: Value = serde_json::from_str(r#"{"net0": {"irq": 6}}"#).unwrap();
+        assert_eq!(
+            mmds.set_device_info(device_info),
+            Err(Error::UnsupportedValueType)
+        );
+    }
+
     #[test]
     fn test_get_element_from_array() {
         let mut mmds = Mmds::default();
@@ -306,4 +644,182 @@ mod tests {
             Err(Error::UnsupportedValueType)
         );
     }
+
+    #[test]
+    fn test_namespaces() {
+        let mut mmds = Mmds::default();
+        let eth0 = Ipv4Addr::new(10, 0, 0, 2);
+        let eth1 = Ipv4Addr::new(10, 0, 1, 2);
+        let unknown = Ipv4Addr::new(10, 0, 2, 2);
+
+        mmds.put_data(serde_json::from_str(r#"{"network":{"mtu":"1500"}}"#).unwrap())
+            .unwrap();
+
+        // A namespace has to be created before it is updated.
+        assert_eq!(
+            mmds.patch_namespace_data(eth0, serde_json::from_str(r#"{"a":"b"}"#).unwrap()),
+            Err(Error::NotFound)
+        );
+        assert_eq!(mmds.get_namespace_data_str(eth0), Err(Error::NotFound));
+        assert_eq!(
+            mmds.put_namespace_data(eth0, Value::Bool(true)),
+            Err(Error::UnsupportedValueType)
+        );
+
+        mmds.put_namespace_data(
+            eth0,
+            serde_json::from_str(r#"{"network":{"ip":"10.0.0.2","mtu":"9000"}}"#).unwrap(),
+        )
+        .unwrap();
+        mmds.put_namespace_data(
+            eth1,
+            serde_json::from_str(r#"{"network":{"ip":"10.0.1.2","mtu":"1500"}}"#).unwrap(),
+        )
+        .unwrap();
+        mmds.patch_namespace_data(
+            eth1,
+            serde_json::from_str(r#"{"network":{"mtu":"1400"}}"#).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            mmds.get_namespace_data_str(eth1).unwrap(),
+            r#"{"network":{"ip":"10.0.1.2","mtu":"1400"}}"#
+        );
+        // The default data store is left untouched.
+        assert_eq!(mmds.get_data_str(), r#"{"network":{"mtu":"1500"}}"#);
+
+        // Each source address gets the metadata of its own namespace.
+        assert_eq!(
+            mmds.get_value_for(eth0, "/network/ip".to_string()).unwrap(),
+            vec!["10.0.0.2"]
+        );
+        assert_eq!(
+            mmds.get_value_for(eth1, "/network/ip".to_string()).unwrap(),
+            vec!["10.0.1.2"]
+        );
+        assert_eq!(
+            mmds.get_value_for(eth1, "/network/mtu".to_string())
+                .unwrap(),
+            vec!["1400"]
+        );
+
+        // The requests from an unknown source get the default data store.
+        assert_eq!(
+            mmds.get_value_for(unknown, "/network/".to_string())
+                .unwrap(),
+            vec!["mtu"]
+        );
+        assert_eq!(
+            mmds.get_value_for(unknown, "/network/ip".to_string()),
+            Err(Error::NotFound)
+        );
+
+        // The device information is published in every namespace.
+        mmds.set_device_info(serde_json::from_str(r#"{"rootfs":{"irq":"5"}}"#).unwrap())
+            .unwrap();
+        for source in &[eth0, eth1, unknown] {
+            assert_eq!(
+                mmds.get_value_for(*source, "/firecracker/devices/rootfs/irq".to_string())
+                    .unwrap(),
+                vec!["5"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_backing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mmds");
+
+        // Nothing is loaded from a missing backing file, which the first update creates.
+        let mut mmds = Mmds::default();
+        mmds.set_backing_file(path.clone()).unwrap();
+        assert_eq!(mmds.check_data_store_initialized(), Err(Error::NotFound));
+        mmds.put_data(serde_json::from_str(r#"{"meta-data":{"iam":"dummy"}}"#).unwrap())
+            .unwrap();
+        mmds.patch_data(serde_json::from_str(r#"{"user-data":"1522850095"}"#).unwrap())
+            .unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // A restarted Firecracker gets the data store back from the backing file.
+        let mut restarted = Mmds::default();
+        restarted.set_backing_file(path.clone()).unwrap();
+        assert!(restarted.check_data_store_initialized().is_ok());
+        assert_eq!(
+            restarted.get_data_str(),
+            "{\"meta-data\":{\"iam\":\"dummy\"},\"user-data\":\"1522850095\"}"
+        );
+        assert_eq!(
+            restarted.get_value("/user-data".to_string()).unwrap(),
+            vec!["1522850095"]
+        );
+
+        // So do the namespaces.
+        let source = Ipv4Addr::new(10, 0, 0, 2);
+        restarted
+            .put_namespace_data(
+                source,
+                serde_json::from_str(r#"{"ip":"10.0.0.2"}"#).unwrap(),
+            )
+            .unwrap();
+        let mut restarted = Mmds::default();
+        restarted.set_backing_file(path.clone()).unwrap();
+        assert_eq!(
+            restarted.get_namespace_data_str(source).unwrap(),
+            r#"{"ip":"10.0.0.2"}"#
+        );
+        assert_eq!(
+            restarted.get_value("/user-data".to_string()).unwrap(),
+            vec!["1522850095"]
+        );
+
+        // The data store is left untouched if it cannot be persisted.
+        let mut mmds = Mmds::default();
+        mmds.set_backing_file(dir.path().join("missing").join("mmds"))
+            .unwrap();
+        match mmds.put_data(serde_json::from_str(r#"{"user-data":"10"}"#).unwrap()) {
+            Err(Error::BackingFile(_)) => (),
+            _ => panic!("Expected a backing file error."),
+        }
+        assert_eq!(mmds.check_data_store_initialized(), Err(Error::NotFound));
+        assert_eq!(mmds.get_data_str(), "{}");
+
+        // A backing file which cannot be read is reported.
+        match Mmds::default().set_backing_file(dir.path().to_path_buf()) {
+            Err(Error::BackingFile(_)) => (),
+            _ => panic!("Expected a backing file error."),
+        }
+
+        // So is a corrupt one, be it altered, truncated or empty.
+        let content = fs::read_to_string(&path).unwrap();
+        for corrupt in &[
+            content.replace("dummy", "dumbo"),
+            content[..content.len() - 1].to_string(),
+            content.lines().next().unwrap().to_string(),
+            String::new(),
+        ] {
+            fs::write(&path, corrupt).unwrap();
+            let mut mmds = Mmds::default();
+            match mmds.set_backing_file(path.clone()) {
+                Err(Error::CorruptBackingFile(_)) => (),
+                _ => panic!("Expected a corrupt backing file error."),
+            }
+            assert_eq!(mmds.get_data_str(), "{}");
+        }
+
+        // The valid JSON of a data store written outside of Firecracker is rejected as well.
+        let snapshot = serde_json::from_str(r#"{"data_store":true,"namespaces":{}}"#).unwrap();
+        fs::write(&path, encode_backing_file(&snapshot)).unwrap();
+        match Mmds::default().set_backing_file(path.clone()) {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "The MMDS backing file is corrupt: Cannot add non-strings values to the MMDS \
+                 data-store."
+            ),
+            Ok(_) => panic!("Expected a corrupt backing file error."),
+        }
+    }
 }
@@ -6,13 +6,17 @@ extern crate json_patch;
 extern crate lazy_static;
 extern crate serde_json;
 
+extern crate logger;
 extern crate micro_http;
+extern crate rate_limiter;
 
 pub mod data_store;
 
+use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
 
 use data_store::{Error as MmdsError, Mmds};
+use logger::{Metric, METRICS};
 use micro_http::{Body, Request, RequestError, Response, StatusCode, Version};
 
 lazy_static! {
@@ -28,10 +32,25 @@ fn build_response(http_version: Version, status_code: StatusCode, body: Body) ->
     response
 }
 
-pub fn parse_request(request_bytes: &[u8]) -> Response {
+/// Builds the response to a guest request, coming from the `source` address, to the MMDS.
+pub fn parse_request(source: Ipv4Addr, request_bytes: &[u8]) -> Response {
     let request = Request::try_from(request_bytes);
     match request {
         Ok(request) => {
+            // The lock can be held by one thread only, so it is safe to unwrap.
+            // If another thread poisoned the lock, we abort the execution.
+            let mut mmds = MMDS
+                .lock()
+                .expect("Failed to build MMDS response due to poisoned lock");
+            if !mmds.consume_request() {
+                METRICS.mmds.requests_throttled.inc();
+                return build_response(
+                    request.http_version(),
+                    StatusCode::TooManyRequests,
+                    Body::new("Too many requests.".to_string()),
+                );
+            }
+
             let uri = request.uri().get_abs_path();
             if uri.is_empty() {
                 return build_response(
@@ -41,12 +60,7 @@ pub fn parse_request(request_bytes: &[u8]) -> Response {
                 );
             }
 
-            // The lock can be held by one thread only, so it is safe to unwrap.
-            // If another thread poisoned the lock, we abort the execution.
-            let response = MMDS
-                .lock()
-                .expect("Failed to build MMDS response due to poisoned lock")
-                .get_value(uri.to_string());
+            let response = mmds.get_value_for(source, uri.to_string());
             match response {
                 Ok(response) => {
                     let response_body = response.join("\n");
@@ -77,6 +91,16 @@ pub fn parse_request(request_bytes: &[u8]) -> Response {
                                 Body::new(error_msg),
                             )
                         }
+                        MmdsError::BackingFile(_) | MmdsError::CorruptBackingFile(_) => {
+                            // The guest is served from memory, so the backing file is never
+                            // involved. Its path is not leaked to the guest anyway.
+                            let error_msg = format!("Cannot read the resource {}.", uri);
+                            build_response(
+                                request.http_version(),
+                                StatusCode::InternalServerError,
+                                Body::new(error_msg),
+                            )
+                        }
                     }
                 }
             }
@@ -107,6 +131,9 @@ pub fn parse_request(request_bytes: &[u8]) -> Response {
 mod tests {
     extern crate serde_json;
     use super::*;
+    use rate_limiter::TokenBucket;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(169, 254, 0, 2);
 
     #[test]
     fn test_parse_request() {
@@ -132,13 +159,13 @@ mod tests {
         // Test invalid request.
         let request = b"HTTP/1.1";
         let dummy_response = Response::new(Version::Http11, StatusCode::BadRequest);
-        assert!(parse_request(request).status() == dummy_response.status());
+        assert!(parse_request(SOURCE, request).status() == dummy_response.status());
 
         // Test unsupported HTTP version.
         let request = b"GET http://169.254.169.255/ HTTP/2.0\r\n";
         let mut expected_response = Response::new(Version::Http11, StatusCode::NotImplemented);
         expected_response.set_body(Body::new("Unsupported HTTP version.".to_string()));
-        let actual_response = parse_request(request);
+        let actual_response = parse_request(SOURCE, request);
 
         assert!(expected_response.status() == actual_response.status());
         assert!(expected_response.body().unwrap() == actual_response.body().unwrap());
@@ -148,7 +175,7 @@ mod tests {
         let request = b"PUT http://169.254.169.255/ HTTP/1.0\r\n";
         let mut expected_response = Response::new(Version::Http11, StatusCode::BadRequest);
         expected_response.set_body(Body::new("Unsupported HTTP method.".to_string()));
-        let actual_response = parse_request(request);
+        let actual_response = parse_request(SOURCE, request);
 
         assert!(expected_response.status() == actual_response.status());
         assert!(expected_response.body().unwrap() == actual_response.body().unwrap());
@@ -158,7 +185,7 @@ mod tests {
         let request = b"GET http:// HTTP/1.0\r\n";
         let mut expected_response = Response::new(Version::Http10, StatusCode::BadRequest);
         expected_response.set_body(Body::new("Invalid URI.".to_string()));
-        let actual_response = parse_request(request);
+        let actual_response = parse_request(SOURCE, request);
 
         assert!(expected_response.status() == actual_response.status());
         assert!(expected_response.body().unwrap() == actual_response.body().unwrap());
@@ -168,7 +195,7 @@ mod tests {
         let request = b"GET http://169.254.169.254/invalid HTTP/1.0\r\n";
         let mut expected_response = Response::new(Version::Http10, StatusCode::NotFound);
         expected_response.set_body(Body::new("Resource not found: /invalid.".to_string()));
-        let actual_response = parse_request(request);
+        let actual_response = parse_request(SOURCE, request);
 
         assert!(expected_response.status() == actual_response.status());
         assert!(expected_response.body().unwrap() == actual_response.body().unwrap());
@@ -179,7 +206,7 @@ mod tests {
         let mut expected_response = Response::new(Version::Http10, StatusCode::OK);
         let body = "age\nname/\nphones/".to_string();
         expected_response.set_body(Body::new(body));
-        let actual_response = parse_request(request);
+        let actual_response = parse_request(SOURCE, request);
 
         assert!(expected_response.status() == actual_response.status());
         assert!(expected_response.body().unwrap() == actual_response.body().unwrap());
@@ -189,12 +216,51 @@ mod tests {
         let mut expected_response = Response::new(Version::Http11, StatusCode::OK);
         let body = "43".to_string();
         expected_response.set_body(Body::new(body));
-        let actual_response = parse_request(request);
+        let actual_response = parse_request(SOURCE, request);
 
         assert!(expected_response.status() == actual_response.status());
         assert!(expected_response.body().unwrap() == actual_response.body().unwrap());
         assert!(expected_response.http_version() == actual_response.http_version());
 
+        // The requests coming from an address with its own namespace are served from it.
+        let other_source = Ipv4Addr::new(169, 254, 1, 2);
+        MMDS.lock()
+            .unwrap()
+            .put_namespace_data(
+                other_source,
+                serde_json::from_str(r#"{"age":"44"}"#).unwrap(),
+            )
+            .unwrap();
+        let request = b"GET /age HTTP/1.1\r\n";
+        let actual_response = parse_request(other_source, request);
+        assert!(actual_response.status() == StatusCode::OK);
+        assert!(actual_response.body().unwrap() == Body::new("44".to_string()));
+        let actual_response = parse_request(SOURCE, request);
+        assert!(actual_response.body().unwrap() == Body::new("43".to_string()));
+
+        // A guest flooding the MMDS is throttled once it exceeds the rate limit, which takes a
+        // minute to allow 10 more requests.
+        MMDS.lock()
+            .unwrap()
+            .set_rate_limiter(Some(TokenBucket::new(10, None, 60_000)));
+        let throttled = METRICS.mmds.requests_throttled.count();
+        let responses: Vec<Response> = (0..20)
+            .map(|_| parse_request(other_source, request))
+            .collect();
+        assert!(responses[..10]
+            .iter()
+            .all(|response| response.status() == StatusCode::OK));
+        for response in &responses[10..] {
+            assert!(response.status() == StatusCode::TooManyRequests);
+            assert!(response.body().unwrap() == Body::new("Too many requests.".to_string()));
+        }
+        assert_eq!(METRICS.mmds.requests_throttled.count(), throttled + 10);
+        // The limit applies to all the guest interfaces together.
+        assert!(parse_request(SOURCE, request).status() == StatusCode::TooManyRequests);
+
+        MMDS.lock().unwrap().set_rate_limiter(None);
+        assert!(parse_request(SOURCE, request).status() == StatusCode::OK);
+
         let data = r#"{
             "name": {
                 "first": "John",
@@ -3,8 +3,10 @@
 
 #[cfg(target_arch = "x86_64")]
 extern crate backtrace;
+extern crate chrono;
 #[macro_use(crate_version, crate_authors)]
 extern crate clap;
+extern crate libc;
 
 extern crate api_server;
 extern crate fc_util;
@@ -19,22 +21,32 @@ extern crate vmm;
 use backtrace::Backtrace;
 use clap::{App, Arg};
 
-use std::io::ErrorKind;
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
 use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 
-use api_server::{ApiServer, Error};
+use api_server::{ApiServer, ConfigFileError, Error};
+use fc_util::process_title::{set_cmdline, set_thread_name};
 use fc_util::validators::validate_instance_id;
-use logger::{Metric, LOGGER, METRICS};
+use logger::{LevelFilter, Metric, MetricsLabel, LOGGER, METRICS};
 use mmds::MMDS;
-use vmm::signal_handler::register_signal_handlers;
+use vmm::idle_monitor::IdleMonitor;
+use vmm::signal_handler::{register_signal_handlers, register_stack_dump_thread};
 use vmm::vmm_config::instance_info::{InstanceInfo, InstanceState};
 
+const DEFAULT_API_MAX_CONNECTIONS: &str = "16";
 const DEFAULT_API_SOCK_PATH: &str = "/tmp/firecracker.socket";
 const DEFAULT_INSTANCE_ID: &str = "anonymous-instance";
+const DEFAULT_MAX_RAMDISK_SIZE_MIB: &str = "1024";
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: &str = "1000";
+const DEFAULT_SYSLOG_SOCK_PATH: &str = "/dev/log";
 
 fn main() {
     LOGGER
@@ -45,6 +57,8 @@ fn main() {
         error!("Failed to register signal handlers: {}", e);
         process::exit(i32::from(vmm::FC_EXIT_CODE_GENERIC_ERROR));
     }
+    // This thread goes on to run the API server.
+    register_stack_dump_thread("fc_api");
     // Start firecracker by setting up a panic hook, which will be called before
     // terminating as we're building with panic = "abort".
     // It's worth noting that the abort is caused by sending a SIG_ABORT signal to the process.
@@ -77,6 +91,43 @@ fn main() {
                 .default_value(DEFAULT_API_SOCK_PATH)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("api-access-log")
+                .long("api-access-log")
+                .help(
+                    "Log the method, path, response status and latency of each API request, \
+                     at the info level",
+                ),
+        )
+        .arg(
+            Arg::with_name("api-max-connections")
+                .long("api-max-connections")
+                .help(
+                    "Maximum number of connections served at once by the API, the connections \
+                     opened past this limit are closed right away",
+                )
+                .takes_value(true)
+                .default_value(DEFAULT_API_MAX_CONNECTIONS)
+                .validator(|s: String| -> Result<(), String> {
+                    match s.parse::<usize>() {
+                        Ok(max) if max > 0 => Ok(()),
+                        _ => Err(
+                            "The maximum number of API connections must be a positive number."
+                                .to_string(),
+                        ),
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("api-cpu")
+                .long("api-cpu")
+                .help(
+                    "Host CPU on which the API server thread runs, so the API stays responsive \
+                     when the vCPUs are busy",
+                )
+                .takes_value(true)
+                .validator(validate_api_cpu),
+        )
         .arg(
             Arg::with_name("id")
                 .long("id")
@@ -102,6 +153,134 @@ fn main() {
                 .default_value("2")
                 .possible_values(&["0", "1", "2"]),
         )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .help(
+                    "Level of the messages written to the log. The level can be changed later \
+                     through the logger API.",
+                )
+                .takes_value(true)
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"]),
+        )
+        .arg(
+            Arg::with_name("log-target")
+                .long("log-target")
+                .help(
+                    "Where log messages are sent. With \"syslog\", messages go to the syslog \
+                     socket instead of stdout/stderr and, once configured, also to the log file.",
+                )
+                .takes_value(true)
+                .default_value("file")
+                .possible_values(&["file", "syslog"]),
+        )
+        .arg(
+            Arg::with_name("syslog-sock")
+                .long("syslog-sock")
+                .help("Path to the unix domain socket of the syslog daemon")
+                .takes_value(true)
+                .default_value(DEFAULT_SYSLOG_SOCK_PATH),
+        )
+        .arg(
+            Arg::with_name("metrics-label")
+                .long("metrics-label")
+                .help("Label emitted with every metrics snapshot, in the <key>=<value> format")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|s: String| -> Result<(), String> {
+                    s.parse::<MetricsLabel>()
+                        .map(|_| ())
+                        .map_err(|e| format!("{}", e))
+                }),
+        )
+        .arg(
+            Arg::with_name("config-file")
+                .long("config-file")
+                .help(
+                    "Path to a file holding a JSON array of API requests, each with a method, \
+                     a path and an optional body, which configure the microVM at startup",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mmds-backing-file")
+                .long("mmds-backing-file")
+                .help(
+                    "Path to a file the MMDS data store is persisted to on every update. The \
+                     data store is loaded from it at startup, if it exists, so that it survives \
+                     a restart of Firecracker",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watchdog-timeout-ms")
+                .long("watchdog-timeout-ms")
+                .help(
+                    "Exit if the guest doesn't signal it is alive within this many milliseconds \
+                     once the microVM is started. The guest signals it is alive by writing 124 \
+                     to the I/O port 0x3f0.",
+                )
+                .takes_value(true)
+                .validator(|s: String| -> Result<(), String> {
+                    // Only x86_64 guests have the I/O port for signaling they are alive.
+                    if cfg!(target_arch = "aarch64") {
+                        return Err("The watchdog is only supported on x86_64.".to_string());
+                    }
+                    match s.parse::<u64>() {
+                        Ok(timeout) if timeout > 0 => Ok(()),
+                        _ => Err("The watchdog timeout must be a positive number.".to_string()),
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("idle-timeout-ms")
+                .long("idle-timeout-ms")
+                .help(
+                    "Exit if neither the guest nor the API clients are active for this many \
+                     milliseconds once the microVM is started. The guest is active when its \
+                     vCPUs exit to Firecracker or when its devices handle events, e.g. the \
+                     virtio queue notifications.",
+                )
+                .takes_value(true)
+                .validator(|s: String| -> Result<(), String> {
+                    match s.parse::<u64>() {
+                        Ok(timeout) if timeout > 0 => Ok(()),
+                        _ => Err("The idle timeout must be a positive number.".to_string()),
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("max-ramdisk-size-mib")
+                .long("max-ramdisk-size-mib")
+                .help(
+                    "Maximum host memory, in MiB, taken by all the ramdisk block devices of the \
+                     microVM together",
+                )
+                .takes_value(true)
+                .default_value(DEFAULT_MAX_RAMDISK_SIZE_MIB)
+                .validator(|s: String| -> Result<(), String> {
+                    match s.parse::<u32>() {
+                        Ok(max) if max > 0 => Ok(()),
+                        _ => Err("The maximum ramdisk size must be a positive number.".to_string()),
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("shutdown-timeout-ms")
+                .long("shutdown-timeout-ms")
+                .help(
+                    "How long to wait for the VMM thread to stop when Firecracker exits because \
+                     of an error, before forcing the exit",
+                )
+                .takes_value(true)
+                .default_value(DEFAULT_SHUTDOWN_TIMEOUT_MS)
+                .validator(|s: String| -> Result<(), String> {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| "The shutdown timeout must be a number.".to_string())
+                }),
+        )
         .arg(
             Arg::with_name("start-time-us")
                 .long("start-time-us")
@@ -116,14 +295,52 @@ fn main() {
         )
         .get_matches();
 
+    if let Some(level) = cmd_arguments.value_of("log-level") {
+        // It's safe to unwrap here because clap only lets through the allowed values, which
+        // are all valid level filter names.
+        LOGGER.set_level_filter(level.parse::<LevelFilter>().unwrap());
+    }
+
+    // It's safe to unwrap here because clap's been provided with a default value.
+    if cmd_arguments.value_of("log-target").unwrap() == "syslog" {
+        let syslog_sock = cmd_arguments.value_of("syslog-sock").unwrap();
+        if let Err(e) = LOGGER.init_syslog(syslog_sock) {
+            error!("Failed to set up the syslog log target: {}", e);
+            process::exit(i32::from(vmm::FC_EXIT_CODE_GENERIC_ERROR));
+        }
+    }
+
+    if let Some(labels) = cmd_arguments.values_of("metrics-label") {
+        for label in labels {
+            // It's safe to unwrap here because clap validated the labels.
+            LOGGER.add_metrics_label(label.parse::<MetricsLabel>().unwrap());
+        }
+    }
+
     let bind_path = cmd_arguments
         .value_of("api_sock")
         .map(PathBuf::from)
         .expect("Missing argument: api_sock");
+    if let Err(e) = validate_api_sock_path(&bind_path) {
+        error!(
+            "Failed to use {} as the API socket: {}",
+            bind_path.display(),
+            e
+        );
+        process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_API_SOCK_PATH));
+    }
 
     // It's safe to unwrap here because clap's been provided with a default value
     let instance_id = cmd_arguments.value_of("id").unwrap().to_string();
 
+    // Make the instance identifiable in `ps` and `top`. This is done once the arguments are
+    // parsed because it overwrites them.
+    if let Err(e) = set_thread_name(&instance_id)
+        .and_then(|_| set_cmdline(&format!("firecracker --id {}", instance_id)))
+    {
+        warn!("Failed to set the process title: {}", e);
+    }
+
     // We disable seccomp filtering when testing, because when running the test_gnutests
     // integration test from test_unittests.py, an invalid syscall is issued, and we crash
     // otherwise.
@@ -148,50 +365,274 @@ fn main() {
             .expect("'start-time-cpu_us' parameter expected to be of 'u64' type.")
     });
 
+    // The boot milestones are measured from the moment the jailer started the process, if
+    // it tells us so.
+    METRICS.boot.set_process_start_us(
+        start_time_us.unwrap_or_else(|| (chrono::Utc::now().timestamp_nanos() / 1000) as u64),
+    );
+
+    // It's safe to unwrap here because clap validated the value.
+    let watchdog_timeout = cmd_arguments
+        .value_of("watchdog-timeout-ms")
+        .map(|s| Duration::from_millis(s.parse::<u64>().unwrap()));
+
+    // It's safe to unwrap here because clap validated the value.
+    let idle_monitor = cmd_arguments
+        .value_of("idle-timeout-ms")
+        .map(|s| IdleMonitor::new(Duration::from_millis(s.parse::<u64>().unwrap())));
+
+    // It's safe to unwrap here because clap's been provided with a default value and validated it.
+    let max_ramdisk_size_mib = cmd_arguments
+        .value_of("max-ramdisk-size-mib")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap();
+
+    // It's safe to unwrap here because clap's been provided with a default value and validated it.
+    let api_max_connections = cmd_arguments
+        .value_of("api-max-connections")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+
+    // It's safe to unwrap here because clap's been provided with a default value and validated it.
+    let shutdown_timeout = Duration::from_millis(
+        cmd_arguments
+            .value_of("shutdown-timeout-ms")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap(),
+    );
+
+    // It's safe to unwrap here because clap validated the value.
+    let api_cpu = cmd_arguments
+        .value_of("api-cpu")
+        .map(|s| s.parse::<usize>().unwrap());
+
     let shared_info = Arc::new(RwLock::new(InstanceInfo {
         state: InstanceState::Uninitialized,
         id: instance_id,
         vmm_version: crate_version!().to_string(),
+        stop_reason: None,
     }));
+    if let Some(backing_file) = cmd_arguments.value_of("mmds-backing-file") {
+        let result = MMDS
+            .lock()
+            .expect("Failed to acquire lock on MMDS info")
+            .set_backing_file(PathBuf::from(backing_file));
+        if let Err(e) = result {
+            error!("Failed to restore the MMDS data store: {}", e.to_string());
+            process::exit(i32::from(vmm::FC_EXIT_CODE_GENERIC_ERROR));
+        }
+    }
     let mmds_info = MMDS.clone();
     let (to_vmm, from_api) = channel();
-    let server =
+    let mut server =
         ApiServer::new(mmds_info, shared_info.clone(), to_vmm).expect("Cannot create API server");
+    server.set_access_log(cmd_arguments.is_present("api-access-log"));
+    if let Some(ref idle_monitor) = idle_monitor {
+        server.set_activity(idle_monitor.api_activity());
+    }
 
     let api_event_fd = server
         .get_event_fd_clone()
         .expect("Cannot clone API eventFD.");
 
-    let _vmm_thread_handle =
-        vmm::start_vmm_thread(shared_info, api_event_fd, from_api, seccomp_level);
-
-    match server.bind_and_run(bind_path, start_time_us, start_time_cpu_us, seccomp_level) {
-        Ok(_) => (),
-        Err(Error::Io(inner)) => match inner.kind() {
-            ErrorKind::AddrInUse => panic!("Failed to open the API socket: {:?}", Error::Io(inner)),
-            _ => panic!(
-                "Failed to communicate with the API socket: {:?}",
-                Error::Io(inner)
-            ),
-        },
-        Err(eventfd_err @ Error::Eventfd(_)) => {
-            panic!("Failed to open the API socket: {:?}", eventfd_err)
+    let vmm_thread_handle = vmm::start_vmm_thread(
+        shared_info,
+        api_event_fd,
+        from_api,
+        seccomp_level,
+        watchdog_timeout,
+        idle_monitor,
+        max_ramdisk_size_mib,
+    );
+    // This has to happen before the API server installs the seccomp filters, which forbid
+    // spawning threads.
+    let vmm_thread_exit = watch_thread(vmm_thread_handle);
+
+    // The threads spawned so far, including the VMM thread which spawns the vCPUs, keep running
+    // on all the host CPUs.
+    if let Some(cpu) = api_cpu {
+        if let Err(e) = pin_api_thread(cpu) {
+            error!("Failed to pin the API server thread: {}", e);
+            shutdown(
+                server,
+                &vmm_thread_exit,
+                shutdown_timeout,
+                vmm::FC_EXIT_CODE_GENERIC_ERROR,
+            );
+        }
+    }
+
+    if let Some(config_file) = cmd_arguments.value_of("config-file") {
+        if let Err(e) = server.apply_config_file(Path::new(config_file)) {
+            error!(
+                "Failed to configure the microVM from {}: {}",
+                config_file, e
+            );
+            shutdown(
+                server,
+                &vmm_thread_exit,
+                shutdown_timeout,
+                config_file_exit_code(&e),
+            );
+        }
+    }
+
+    let result = server.bind_and_run(
+        bind_path,
+        start_time_us,
+        start_time_cpu_us,
+        seccomp_level,
+        api_max_connections,
+    );
+    if let Err(e) = result {
+        error!("Failed to run the API server: {}", e);
+        shutdown(
+            server,
+            &vmm_thread_exit,
+            shutdown_timeout,
+            api_server_exit_code(&e),
+        );
+    }
+}
+
+/// Joins `handle` on a helper thread, since `JoinHandle` has no timed join. The returned
+/// channel is disconnected once the thread has exited.
+fn watch_thread(handle: thread::JoinHandle<()>) -> Receiver<()> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        // A panicking thread aborts the process, so the result carries no information.
+        let _ = handle.join();
+        drop(sender);
+    });
+    receiver
+}
+
+/// Waits at most `timeout` for the thread watched through `thread_exit` to exit. Returns
+/// whether it did.
+fn wait_for_thread(thread_exit: &Receiver<()>, timeout: Duration) -> bool {
+    thread_exit.recv_timeout(timeout) == Err(RecvTimeoutError::Disconnected)
+}
+
+/// Stops the VMM thread by closing its request channel, then exits with `exit_code`. The exit
+/// is forced if the VMM thread is stuck for longer than `timeout`.
+fn shutdown(
+    server: ApiServer,
+    vmm_thread_exit: &Receiver<()>,
+    timeout: Duration,
+    exit_code: u8,
+) -> ! {
+    let api_event_fd = server.get_event_fd_clone();
+    drop(server);
+    // Wake up the VMM thread, so it finds the channel closed.
+    match api_event_fd.map(|fd| fd.write(1)) {
+        Ok(Ok(())) => {
+            if !wait_for_thread(vmm_thread_exit, timeout) {
+                warn!(
+                    "The VMM thread did not stop within {} ms. Forcing the exit.",
+                    timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis())
+                );
+            }
         }
+        _ => warn!("Failed to notify the VMM thread about the shutdown."),
     }
+    process::exit(i32::from(exit_code));
+}
+
+/// Returns the exit code matching an error of the API server.
+fn api_server_exit_code(err: &Error) -> u8 {
+    match *err {
+        Error::Io(ref inner) => match inner.kind() {
+            ErrorKind::AddrInUse => vmm::FC_EXIT_CODE_API_SOCK_IN_USE,
+            ErrorKind::PermissionDenied => vmm::FC_EXIT_CODE_API_SOCK_PERMISSION_DENIED,
+            _ => vmm::FC_EXIT_CODE_GENERIC_ERROR,
+        },
+        Error::Eventfd(_) => vmm::FC_EXIT_CODE_GENERIC_ERROR,
+        Error::Seccomp(_) => vmm::FC_EXIT_CODE_SECCOMP_FAILURE,
+    }
+}
+
+/// Returns the exit code matching an error hit while configuring the microVM from a file.
+fn config_file_exit_code(err: &ConfigFileError) -> u8 {
+    match *err {
+        // A failed start also stops the VMM with this code, whichever of the two exits first.
+        ConfigFileError::Vmm(_, ref e) => e.exit_code(),
+        _ => vmm::FC_EXIT_CODE_GENERIC_ERROR,
+    }
+}
+
+/// Checks that the API server thread can be pinned to the host CPU `value`, i.e. that
+/// Firecracker is allowed to run on it.
+fn validate_api_cpu(value: String) -> Result<(), String> {
+    let cpu = value
+        .parse::<usize>()
+        .map_err(|_| "The API server CPU must be a number.".to_string())?;
+    match vmm::numa::allowed_cpus() {
+        Ok(ref cpus) if !cpus.contains(&cpu) => Err(format!(
+            "The host CPU {} is not available to Firecracker.",
+            cpu
+        )),
+        // If the allowed CPUs are unknown, pinning the thread reports the error.
+        _ => Ok(()),
+    }
+}
+
+/// Restricts the calling thread, which serves the API, to run only on the host CPU `cpu`.
+fn pin_api_thread(cpu: usize) -> Result<(), String> {
+    vmm::numa::set_thread_affinity(&[cpu])
+        .map_err(|e| format!("Cannot run on the host CPU {}: {}", cpu, e))
+}
+
+/// Checks that the API socket can be created at `path`, which means that its parent directory
+/// exists and is writable. Abstract socket addresses start with a null byte and are not bound
+/// to the filesystem, so they are not checked.
+fn validate_api_sock_path(path: &Path) -> Result<(), String> {
+    if path.as_os_str().as_bytes().first() == Some(&0) {
+        return Ok(());
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if !parent.is_dir() {
+        return Err(format!(
+            "The parent directory {} does not exist.",
+            parent.display()
+        ));
+    }
+
+    let c_parent = CString::new(parent.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    // Safe because `c_parent` is a valid null-terminated string and we check the return value.
+    if unsafe { libc::access(c_parent.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+        return Err(format!(
+            "The parent directory {} is not writable: {}",
+            parent.display(),
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     extern crate tempfile;
 
-    use self::tempfile::NamedTempFile;
+    use self::tempfile::{tempdir, NamedTempFile};
     use super::*;
 
+    use std::os::unix::net::UnixListener;
+
+    use vmm::vmm_config::boot_source::BootSourceConfigError::InvalidKernelPath;
+    use vmm::vmm_config::instance_info::StartMicrovmError;
+    use vmm::VmmActionError;
+
     use logger::AppInfo;
     use std::fs::File;
     use std::io::BufRead;
     use std::io::BufReader;
-    use std::path::Path;
     use std::time::Duration;
     use std::{fs, thread};
 
@@ -280,7 +721,154 @@ mod tests {
             ) || println!("Could not validate backtrace!\n {:?}", Backtrace::new()) != ()
         );
 
+        // Request a live stack dump and check that the VMM thread logs the backtraces of the API
+        // server thread and its own without bringing Firecracker down.
+        unsafe { libc::kill(process::id() as libc::pid_t, libc::SIGUSR2) };
+        thread::sleep(Duration::from_millis(FIRECRACKER_INIT_TIMEOUT_MILLIS));
+        assert!(validate_backtrace(
+            log_file.as_str(),
+            &[
+                ("WARN", "lib.rs", "Dumping the thread stacks"),
+                ("WARN", "lib.rs", "Stack of the fc_api thread"),
+                ("WARN", "lib.rs", "Stack of the fc_vmm thread"),
+            ],
+        ));
+        assert!(Path::new(DEFAULT_API_SOCK_PATH).exists());
+
         // Clean up
         fs::remove_file(DEFAULT_API_SOCK_PATH).expect("failure in removing socket file");
     }
+
+    #[test]
+    fn test_validate_api_sock_path() {
+        let dir = tempdir().unwrap();
+        assert!(validate_api_sock_path(&dir.path().join("api.socket")).is_ok());
+        // A relative path without a parent resolves to the current directory.
+        assert!(validate_api_sock_path(Path::new("api.socket")).is_ok());
+        // Abstract socket addresses are not checked.
+        assert!(validate_api_sock_path(Path::new("\0/invalid/api.socket")).is_ok());
+
+        // Error case: the parent directory does not exist.
+        let bad_dir = dir.path().join("invalid");
+        assert_eq!(
+            validate_api_sock_path(&bad_dir.join("api.socket")),
+            Err(format!(
+                "The parent directory {} does not exist.",
+                bad_dir.display()
+            ))
+        );
+
+        // Error case: the parent is not a directory.
+        let file = NamedTempFile::new().unwrap();
+        assert_eq!(
+            validate_api_sock_path(&file.path().join("api.socket")),
+            Err(format!(
+                "The parent directory {} does not exist.",
+                file.path().display()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_api_cpu() {
+        let allowed = vmm::numa::allowed_cpus().unwrap();
+        assert!(validate_api_cpu(allowed[0].to_string()).is_ok());
+        assert_eq!(
+            validate_api_cpu("first".to_string()),
+            Err("The API server CPU must be a number.".to_string())
+        );
+        assert_eq!(
+            validate_api_cpu("65536".to_string()),
+            Err("The host CPU 65536 is not available to Firecracker.".to_string())
+        );
+        assert!(pin_api_thread(1 << 16).is_err());
+
+        // The affinity only applies to the thread setting it, so the rest of the tests are not
+        // affected.
+        let cpu = *allowed.last().unwrap();
+        thread::spawn(move || {
+            pin_api_thread(cpu).unwrap();
+            assert_eq!(vmm::numa::allowed_cpus().unwrap(), vec![cpu]);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_thread() {
+        // A VMM thread which is slow to stop.
+        let vmm_thread = thread::spawn(|| thread::sleep(Duration::from_millis(200)));
+        let vmm_thread_exit = watch_thread(vmm_thread);
+
+        // The timeout expires before the thread exits.
+        assert!(!wait_for_thread(
+            &vmm_thread_exit,
+            Duration::from_millis(10)
+        ));
+        assert!(wait_for_thread(&vmm_thread_exit, Duration::from_secs(5)));
+        // The thread is already gone.
+        assert!(wait_for_thread(&vmm_thread_exit, Duration::from_millis(0)));
+    }
+
+    #[test]
+    fn test_api_server_exit_code() {
+        let dir = tempdir().unwrap();
+        let sock_path = dir.path().join("api.socket");
+        let _listener = UnixListener::bind(&sock_path).unwrap();
+
+        let shared_info = Arc::new(RwLock::new(InstanceInfo {
+            state: InstanceState::Uninitialized,
+            id: "exit-code-test".to_string(),
+            vmm_version: "1.0".to_string(),
+            stop_reason: None,
+        }));
+        let (to_vmm, _from_api) = channel();
+        let server = ApiServer::new(MMDS.clone(), shared_info, to_vmm).unwrap();
+        // The socket address is already in use.
+        let err = server
+            .bind_and_run(sock_path, None, None, seccomp::SECCOMP_LEVEL_NONE, 1)
+            .unwrap_err();
+        assert_eq!(
+            api_server_exit_code(&err),
+            vmm::FC_EXIT_CODE_API_SOCK_IN_USE
+        );
+
+        assert_eq!(
+            api_server_exit_code(&Error::Io(io::Error::from(ErrorKind::PermissionDenied))),
+            vmm::FC_EXIT_CODE_API_SOCK_PERMISSION_DENIED
+        );
+        assert_eq!(
+            api_server_exit_code(&Error::Seccomp(seccomp::Error::IntoBpf)),
+            vmm::FC_EXIT_CODE_SECCOMP_FAILURE
+        );
+        assert_eq!(
+            api_server_exit_code(&Error::Eventfd(io::Error::from_raw_os_error(0))),
+            vmm::FC_EXIT_CODE_GENERIC_ERROR
+        );
+    }
+
+    #[test]
+    fn test_config_file_exit_code() {
+        assert_eq!(
+            config_file_exit_code(&ConfigFileError::Vmm(
+                0,
+                VmmActionError::BootSource(vmm::ErrorKind::User, InvalidKernelPath)
+            )),
+            vmm::FC_EXIT_CODE_KERNEL_LOAD_FAILURE
+        );
+        assert_eq!(
+            config_file_exit_code(&ConfigFileError::Vmm(
+                1,
+                VmmActionError::StartMicrovm(
+                    vmm::ErrorKind::User,
+                    StartMicrovmError::MissingKernelConfig
+                )
+            )),
+            vmm::FC_EXIT_CODE_GENERIC_ERROR
+        );
+        assert_eq!(
+            config_file_exit_code(&ConfigFileError::Request(0, "Invalid request".to_string())),
+            vmm::FC_EXIT_CODE_GENERIC_ERROR
+        );
+    }
 }
@@ -8,7 +8,12 @@ extern crate memory_model;
 extern crate sys_util;
 extern crate vhost_gen;
 
+mod vhost_user;
 mod vsock;
+pub use vhost_user::{
+    VhostUserMaster, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_MAX_MEM_REGIONS,
+    VHOST_USER_PROTOCOL_F_CONFIG,
+};
 pub use vsock::Vsock;
 
 use std::mem;
@@ -35,6 +40,18 @@ pub enum Error {
     AvailAddress(GuestMemoryError),
     /// Invalid log address.
     LogAddress(GuestMemoryError),
+    /// Error connecting to the vhost-user backend socket.
+    VhostUserConnect(std::io::Error),
+    /// Error while exchanging messages with the vhost-user backend.
+    VhostUserSocket(std::io::Error),
+    /// The vhost-user backend closed the connection.
+    VhostUserDisconnected,
+    /// The vhost-user backend sent an unexpected reply.
+    VhostUserInvalidReply,
+    /// The guest memory has more regions than a vhost-user backend accepts.
+    VhostUserTooManyRegions,
+    /// The guest memory is not backed by files which can be shared with the vhost-user backend.
+    VhostUserMemoryNotShared,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -0,0 +1,508 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Master side of the vhost-user protocol.
+//!
+//! The vhost-user protocol lets a backend running in another process service the virtqueues of
+//! a device. The messages are exchanged over a Unix domain socket, and file descriptors, such as
+//! the ones backing the guest memory or the eventfds of the queues, are passed along as
+//! ancillary data. See `docs/interop/vhost-user.txt` in the QEMU tree for the specification.
+
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use libc;
+
+use super::{Error, Result};
+use memory_model::GuestMemory;
+use sys_util::EventFd;
+
+/// Feature bit which signals that the backend supports the protocol feature negotiation.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+/// Protocol feature bit which signals that the backend can report the device configuration.
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 1 << 9;
+/// Maximum number of memory regions which can be shared with the backend.
+pub const VHOST_USER_MAX_MEM_REGIONS: usize = 8;
+
+// Request codes of the messages sent by the master.
+const VHOST_USER_GET_FEATURES: u32 = 1;
+const VHOST_USER_SET_FEATURES: u32 = 2;
+const VHOST_USER_SET_OWNER: u32 = 3;
+const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+const VHOST_USER_SET_VRING_NUM: u32 = 8;
+const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+const VHOST_USER_SET_VRING_BASE: u32 = 10;
+const VHOST_USER_SET_VRING_KICK: u32 = 12;
+const VHOST_USER_SET_VRING_CALL: u32 = 13;
+const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+const VHOST_USER_GET_CONFIG: u32 = 24;
+
+// Flags of the message header.
+const VHOST_USER_VERSION: u32 = 0x1;
+const VHOST_USER_REPLY_MASK: u32 = 0x4;
+
+// Size of the message header: request, flags and payload size.
+const HEADER_SIZE: usize = 12;
+// Size of a memory region description: guest address, size, userspace address and offset.
+const MEM_REGION_SIZE: usize = 32;
+// Size of the GET_CONFIG payload which precedes the configuration space: offset, size, flags.
+const CONFIG_HEADER_SIZE: usize = 12;
+// Maximum size of a payload sent by the backend.
+const MAX_PAYLOAD_SIZE: usize = 256;
+
+/// Handle for driving a vhost-user backend over its control socket.
+pub struct VhostUserMaster {
+    sock: UnixStream,
+}
+
+impl VhostUserMaster {
+    /// Connects to the vhost-user backend listening on the Unix domain socket at `path`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<VhostUserMaster> {
+        let sock = UnixStream::connect(path).map_err(Error::VhostUserConnect)?;
+        Ok(VhostUserMaster::from_stream(sock))
+    }
+
+    /// Creates a master which uses an already connected socket.
+    pub fn from_stream(sock: UnixStream) -> VhostUserMaster {
+        VhostUserMaster { sock }
+    }
+
+    /// Sets the current process as the owner of the session.
+    pub fn set_owner(&mut self) -> Result<()> {
+        self.send(VHOST_USER_SET_OWNER, &[], &[])
+    }
+
+    /// Gets the bitmask of the virtio features supported by the backend.
+    pub fn get_features(&mut self) -> Result<u64> {
+        self.get_u64(VHOST_USER_GET_FEATURES)
+    }
+
+    /// Informs the backend which virtio features to enable.
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        self.send(VHOST_USER_SET_FEATURES, &features.to_le_bytes(), &[])
+    }
+
+    /// Gets the bitmask of the protocol features supported by the backend.
+    pub fn get_protocol_features(&mut self) -> Result<u64> {
+        self.get_u64(VHOST_USER_GET_PROTOCOL_FEATURES)
+    }
+
+    /// Informs the backend which protocol features to enable.
+    pub fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.send(
+            VHOST_USER_SET_PROTOCOL_FEATURES,
+            &features.to_le_bytes(),
+            &[],
+        )
+    }
+
+    /// Shares the guest memory with the backend. All the memory regions must be backed by files.
+    pub fn set_mem_table(&mut self, mem: &GuestMemory) -> Result<()> {
+        let num_regions = mem.num_regions();
+        if num_regions > VHOST_USER_MAX_MEM_REGIONS {
+            return Err(Error::VhostUserTooManyRegions);
+        }
+
+        let mut payload = Vec::with_capacity(8 + num_regions * MEM_REGION_SIZE);
+        payload.extend_from_slice(&(num_regions as u32).to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        let mut fds = Vec::with_capacity(num_regions);
+        mem.with_regions_mut(|index, guest_addr, size, host_addr| {
+            let fd = mem
+                .region_fd(index)
+                .ok_or(Error::VhostUserMemoryNotShared)?;
+            payload.extend_from_slice(&(guest_addr.offset() as u64).to_le_bytes());
+            payload.extend_from_slice(&(size as u64).to_le_bytes());
+            payload.extend_from_slice(&(host_addr as u64).to_le_bytes());
+            // Each region is backed by its own file, starting at offset 0.
+            payload.extend_from_slice(&0u64.to_le_bytes());
+            fds.push(fd);
+            Ok(())
+        })?;
+
+        self.send(VHOST_USER_SET_MEM_TABLE, &payload, &fds)
+    }
+
+    /// Sets the number of descriptors in the vring.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to set descriptor count for.
+    /// * `num` - Number of descriptors in the queue.
+    pub fn set_vring_num(&mut self, queue_index: usize, num: u16) -> Result<()> {
+        self.set_vring_state(VHOST_USER_SET_VRING_NUM, queue_index, u32::from(num))
+    }
+
+    /// Sets the addresses of a given vring, as seen by this process. The backend translates
+    /// them using the memory table.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to set addresses for.
+    /// * `desc_table_addr` - Descriptor table address.
+    /// * `used_ring_addr` - Used ring buffer address.
+    /// * `avail_ring_addr` - Available ring buffer address.
+    pub fn set_vring_addr(
+        &mut self,
+        queue_index: usize,
+        desc_table_addr: u64,
+        used_ring_addr: u64,
+        avail_ring_addr: u64,
+    ) -> Result<()> {
+        let mut payload = Vec::with_capacity(40);
+        payload.extend_from_slice(&(queue_index as u32).to_le_bytes());
+        // Flags.
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        payload.extend_from_slice(&desc_table_addr.to_le_bytes());
+        payload.extend_from_slice(&used_ring_addr.to_le_bytes());
+        payload.extend_from_slice(&avail_ring_addr.to_le_bytes());
+        // Logging is not supported.
+        payload.extend_from_slice(&0u64.to_le_bytes());
+        self.send(VHOST_USER_SET_VRING_ADDR, &payload, &[])
+    }
+
+    /// Sets the first index to look for available descriptors.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `base` - Index where available descriptors start.
+    pub fn set_vring_base(&mut self, queue_index: usize, base: u16) -> Result<()> {
+        self.set_vring_state(VHOST_USER_SET_VRING_BASE, queue_index, u32::from(base))
+    }
+
+    /// Sets the eventfd which the backend triggers when buffers have been used.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `fd` - EventFd to trigger.
+    pub fn set_vring_call(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.set_vring_file(VHOST_USER_SET_VRING_CALL, queue_index, fd)
+    }
+
+    /// Sets the eventfd which the guest signals when buffers are available for the backend.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `fd` - EventFd that will be signaled from guest.
+    pub fn set_vring_kick(&mut self, queue_index: usize, fd: &EventFd) -> Result<()> {
+        self.set_vring_file(VHOST_USER_SET_VRING_KICK, queue_index, fd)
+    }
+
+    /// Enables or disables the processing of a given vring.
+    pub fn set_vring_enable(&mut self, queue_index: usize, enable: bool) -> Result<()> {
+        self.set_vring_state(VHOST_USER_SET_VRING_ENABLE, queue_index, enable as u32)
+    }
+
+    /// Reads `size` bytes from the configuration space of the device, starting at `offset`.
+    pub fn get_config(&mut self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        if size as usize > MAX_PAYLOAD_SIZE - CONFIG_HEADER_SIZE {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        let mut payload = Vec::with_capacity(CONFIG_HEADER_SIZE + size as usize);
+        payload.extend_from_slice(&offset.to_le_bytes());
+        payload.extend_from_slice(&size.to_le_bytes());
+        // Flags.
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        payload.resize(CONFIG_HEADER_SIZE + size as usize, 0);
+        self.send(VHOST_USER_GET_CONFIG, &payload, &[])?;
+
+        let reply = self.recv_reply(VHOST_USER_GET_CONFIG)?;
+        if reply.len() != payload.len() || read_u32(&reply[4..8]) != size {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        Ok(reply[CONFIG_HEADER_SIZE..].to_vec())
+    }
+
+    fn get_u64(&mut self, request: u32) -> Result<u64> {
+        self.send(request, &[], &[])?;
+        let reply = self.recv_reply(request)?;
+        if reply.len() != 8 {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        Ok(read_u64(&reply))
+    }
+
+    fn set_vring_state(&mut self, request: u32, queue_index: usize, num: u32) -> Result<()> {
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&(queue_index as u32).to_le_bytes());
+        payload.extend_from_slice(&num.to_le_bytes());
+        self.send(request, &payload, &[])
+    }
+
+    fn set_vring_file(&mut self, request: u32, queue_index: usize, fd: &EventFd) -> Result<()> {
+        // The low byte holds the queue index, the file descriptor travels as ancillary data.
+        let payload = (queue_index as u64 & 0xff).to_le_bytes();
+        self.send(request, &payload, &[fd.as_raw_fd()])
+    }
+
+    fn send(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        let mut msg = Vec::with_capacity(HEADER_SIZE + payload.len());
+        msg.extend_from_slice(&request.to_le_bytes());
+        msg.extend_from_slice(&VHOST_USER_VERSION.to_le_bytes());
+        msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        msg.extend_from_slice(payload);
+        send_with_fds(self.sock.as_raw_fd(), &msg, fds).map_err(socket_error)
+    }
+
+    fn recv_reply(&mut self, request: u32) -> Result<Vec<u8>> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.sock.read_exact(&mut header).map_err(socket_error)?;
+        let size = read_u32(&header[8..12]) as usize;
+        if read_u32(&header[0..4]) != request
+            || read_u32(&header[4..8]) & VHOST_USER_REPLY_MASK == 0
+            || size > MAX_PAYLOAD_SIZE
+        {
+            return Err(Error::VhostUserInvalidReply);
+        }
+
+        let mut payload = vec![0u8; size];
+        self.sock.read_exact(&mut payload).map_err(socket_error)?;
+        Ok(payload)
+    }
+}
+
+impl AsRawFd for VhostUserMaster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+fn socket_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset => Error::VhostUserDisconnected,
+        _ => Error::VhostUserSocket(e),
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+// Sends `buf` over the socket, along with the file descriptors in `fds`.
+fn send_with_fds(sock: RawFd, buf: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_size = (fds.len() * mem::size_of::<RawFd>()) as libc::c_uint;
+    // Safe because CMSG_SPACE only computes a size.
+    let control_size = if fds.is_empty() {
+        0
+    } else {
+        unsafe { libc::CMSG_SPACE(fds_size) as usize }
+    };
+    // Use u64 elements so that the control buffer is suitably aligned for a cmsghdr.
+    let mut control = vec![0u64; (control_size + 7) / 8];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // The message header is zeroed first because some C libraries define padding fields.
+    // Safe because msghdr is a plain C struct for which all zeroes is a valid value.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if control_size > 0 {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control_size as _;
+        // Safe because the control buffer is large enough to hold a header and `fds`.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_size) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+
+    // Safe because all the pointers in the message header are valid for the duration of the
+    // call and we check the return value. MSG_NOSIGNAL avoids a SIGPIPE when the backend is gone.
+    let ret = unsafe { libc::sendmsg(sock, &msg, libc::MSG_NOSIGNAL) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if ret as usize != buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "Partial vhost-user message.",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+
+    use memory_model::GuestAddress;
+
+    // Receives a message from the master, along with the file descriptors passed to the backend.
+    fn recv_msg(mut sock: &UnixStream) -> Option<(u32, Vec<u8>, Vec<File>)> {
+        // The file descriptors are attached to the header, which is read on its own because the
+        // stream may hold several messages.
+        let mut header = [0u8; HEADER_SIZE];
+        let mut control = vec![0u64; 64];
+        let mut iov = libc::iovec {
+            iov_base: header.as_mut_ptr() as *mut libc::c_void,
+            iov_len: header.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = (control.len() * 8) as _;
+        let len = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_WAITALL) };
+        if len <= 0 {
+            return None;
+        }
+        assert_eq!(len as usize, HEADER_SIZE);
+
+        let mut files = Vec::new();
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            if !cmsg.is_null() && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<RawFd>();
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..count {
+                    files.push(File::from_raw_fd(*data.add(i)));
+                }
+            }
+        }
+        let mut payload = vec![0u8; read_u32(&header[8..12]) as usize];
+        sock.read_exact(&mut payload).unwrap();
+        Some((read_u32(&header[0..4]), payload, files))
+    }
+
+    fn send_reply(sock: &UnixStream, request: u32, payload: &[u8]) {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&request.to_le_bytes());
+        msg.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY_MASK).to_le_bytes());
+        msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        msg.extend_from_slice(payload);
+        send_with_fds(sock.as_raw_fd(), &msg, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_vhost_user_master() {
+        let (master_sock, backend_sock) = UnixStream::pair().unwrap();
+        // A minimal backend which answers the queries and records the other requests.
+        let backend = thread::spawn(move || {
+            let mut requests = Vec::new();
+            while let Some((request, payload, files)) = recv_msg(&backend_sock) {
+                match request {
+                    VHOST_USER_GET_FEATURES => send_reply(
+                        &backend_sock,
+                        request,
+                        &(VHOST_USER_F_PROTOCOL_FEATURES | 1).to_le_bytes(),
+                    ),
+                    VHOST_USER_GET_CONFIG => {
+                        let mut reply = payload.clone();
+                        for (i, byte) in reply[CONFIG_HEADER_SIZE..].iter_mut().enumerate() {
+                            *byte = i as u8;
+                        }
+                        send_reply(&backend_sock, request, &reply);
+                    }
+                    _ => (),
+                }
+                requests.push((request, payload, files.len()));
+            }
+            requests
+        });
+
+        let mem = GuestMemory::new_shared(&[
+            (GuestAddress(0x0), 0x1000),
+            (GuestAddress(0x10000), 0x1000),
+        ])
+        .unwrap();
+        let eventfd = EventFd::new().unwrap();
+        let mut master = VhostUserMaster::from_stream(master_sock);
+        master.set_owner().unwrap();
+        assert_eq!(
+            master.get_features().unwrap(),
+            VHOST_USER_F_PROTOCOL_FEATURES | 1
+        );
+        master.set_features(1).unwrap();
+        master.set_mem_table(&mem).unwrap();
+        master.set_vring_num(0, 256).unwrap();
+        master.set_vring_addr(0, 0x1000, 0x2000, 0x3000).unwrap();
+        master.set_vring_base(0, 0).unwrap();
+        master.set_vring_call(0, &eventfd).unwrap();
+        master.set_vring_kick(0, &eventfd).unwrap();
+        master.set_vring_enable(0, true).unwrap();
+        assert_eq!(master.get_config(0, 4).unwrap(), vec![0, 1, 2, 3]);
+        drop(master);
+
+        let requests = backend.join().unwrap();
+        let codes: Vec<u32> = requests.iter().map(|r| r.0).collect();
+        assert_eq!(
+            codes,
+            vec![
+                VHOST_USER_SET_OWNER,
+                VHOST_USER_GET_FEATURES,
+                VHOST_USER_SET_FEATURES,
+                VHOST_USER_SET_MEM_TABLE,
+                VHOST_USER_SET_VRING_NUM,
+                VHOST_USER_SET_VRING_ADDR,
+                VHOST_USER_SET_VRING_BASE,
+                VHOST_USER_SET_VRING_CALL,
+                VHOST_USER_SET_VRING_KICK,
+                VHOST_USER_SET_VRING_ENABLE,
+                VHOST_USER_GET_CONFIG,
+            ]
+        );
+        // The memory table describes both regions and passes their backing files.
+        let (_, ref mem_table, num_files) = requests[3];
+        assert_eq!(read_u32(&mem_table[0..4]), 2);
+        assert_eq!(read_u64(&mem_table[8 + MEM_REGION_SIZE..]), 0x10000);
+        assert_eq!(num_files, 2);
+        // The eventfds are passed along.
+        assert_eq!(requests[7].2, 1);
+        assert_eq!(read_u64(&requests[5].1[8..16]), 0x1000);
+    }
+
+    #[test]
+    fn test_vhost_user_disconnect() {
+        let (master_sock, backend_sock) = UnixStream::pair().unwrap();
+        let mut master = VhostUserMaster::from_stream(master_sock);
+        drop(backend_sock);
+
+        match master.set_owner() {
+            Err(Error::VhostUserDisconnected) => (),
+            _ => panic!("Expected a disconnect error."),
+        }
+        match master.get_features() {
+            Err(Error::VhostUserDisconnected) => (),
+            _ => panic!("Expected a disconnect error."),
+        }
+    }
+
+    #[test]
+    fn test_vhost_user_memory_not_shared() {
+        let (master_sock, _backend_sock) = UnixStream::pair().unwrap();
+        let mut master = VhostUserMaster::from_stream(master_sock);
+        let mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        match master.set_mem_table(&mem) {
+            Err(Error::VhostUserMemoryNotShared) => (),
+            _ => panic!("Expected a memory sharing error."),
+        }
+    }
+}
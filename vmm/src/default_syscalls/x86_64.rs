@@ -31,6 +31,10 @@ const TIOCGWINSZ: u64 = 0x5413;
 const FIOCLEX: u64 = 0x5451;
 const FIONBIO: u64 = 0x5421;
 
+// See include/uapi/linux/fs.h in the kernel code.
+const BLKSSZGET: u64 = 0x1268;
+const BLKGETSIZE64: u64 = 0x8008_1272;
+
 // See include/uapi/linux/if_tun.h in the kernel code.
 const KVM_GET_API_VERSION: u64 = 0xae00;
 const KVM_CREATE_VM: u64 = 0xae01;
@@ -54,6 +58,16 @@ const KVM_SET_LAPIC: u64 = 0x4400_ae8f;
 const KVM_GET_SREGS: u64 = 0x8138_ae83;
 const KVM_GET_LAPIC: u64 = 0x8400_ae8e;
 const KVM_GET_SUPPORTED_CPUID: u64 = 0xc008_ae05;
+// Read the vCPU and VM state when a snapshot is created.
+const KVM_GET_MP_STATE: u64 = 0x8004_ae98;
+const KVM_GET_CLOCK: u64 = 0x8030_ae7c;
+const KVM_GET_VCPU_EVENTS: u64 = 0x8040_ae9f;
+const KVM_GET_PIT2: u64 = 0x8070_ae9f;
+const KVM_GET_REGS: u64 = 0x8090_ae81;
+const KVM_GET_XCRS: u64 = 0x8188_aea6;
+const KVM_GET_XSAVE: u64 = 0x9000_aea4;
+const KVM_GET_MSRS: u64 = 0xc008_ae88;
+const KVM_GET_IRQCHIP: u64 = 0xc208_ae62;
 
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNSETIFF: u64 = 0x4004_54ca;
@@ -119,7 +133,8 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
         vec![
             #[cfg(target_env = "musl")]
             allow_syscall(libc::SYS_accept),
-            #[cfg(target_env = "gnu")]
+            // SYS_accept4 is also needed for accepting the guest agent connections.
+            #[cfg(any(target_env = "gnu", feature = "vsock"))]
             allow_syscall(libc::SYS_accept4),
             allow_syscall(libc::SYS_brk),
             allow_syscall(libc::SYS_clock_gettime),
@@ -145,7 +160,12 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
                     Cond::new(2, Eq, FCNTL_FD_CLOEXEC)?,
                 ]],
             ),
+            // SYS_fdatasync is needed for flushing the backing files of the block devices.
+            allow_syscall(libc::SYS_fdatasync),
             allow_syscall(libc::SYS_fstat),
+            // SYS_fstatfs is needed for serving statfs requests from the virtio-fs devices.
+            #[cfg(feature = "virtio-fs")]
+            allow_syscall(libc::SYS_fstatfs),
             allow_syscall_if(
                 libc::SYS_futex,
                 or![
@@ -154,7 +174,12 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
                     and![Cond::new(1, Eq, FUTEX_REQUEUE_PRIVATE)?],
                 ],
             ),
+            // SYS_getdents64 is needed for listing the directories shared through virtio-fs.
+            #[cfg(feature = "virtio-fs")]
+            allow_syscall(libc::SYS_getdents64),
             allow_syscall(libc::SYS_getrandom),
+            // SYS_gettid is needed by the threads capturing their stack for a stack dump.
+            allow_syscall(libc::SYS_gettid),
             allow_syscall_if(libc::SYS_ioctl, create_ioctl_seccomp_rule()?),
             allow_syscall(libc::SYS_lseek),
             #[cfg(target_env = "musl")]
@@ -166,17 +191,42 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_munmap),
             #[cfg(target_env = "musl")]
             allow_syscall(libc::SYS_open),
-            #[cfg(target_env = "gnu")]
+            // The virtio-fs devices look up the shared files relative to their parent directory.
+            #[cfg(any(target_env = "gnu", feature = "virtio-fs"))]
             allow_syscall(libc::SYS_openat),
             allow_syscall(libc::SYS_pipe),
+            #[cfg(feature = "virtio-fs")]
+            allow_syscall(libc::SYS_pread64),
             allow_syscall(libc::SYS_read),
+            #[cfg(feature = "virtio-fs")]
+            allow_syscall(libc::SYS_readlinkat),
             allow_syscall(libc::SYS_readv),
+            // SYS_rename is needed for rotating the log file.
+            allow_syscall(libc::SYS_rename),
             // SYS_rt_sigreturn is needed in case a fault does occur, so that the signal handler
             // can return. Otherwise we get stuck in a fault loop.
             allow_syscall(libc::SYS_rt_sigreturn),
+            // SYS_sendmsg is needed for passing file descriptors to the vhost-user backends.
+            allow_syscall(libc::SYS_sendmsg),
+            // SYS_sendto is needed for the syslog log target.
+            allow_syscall(libc::SYS_sendto),
+            // SYS_setsockopt is needed for bounding the time spent sending to the guest agent.
+            #[cfg(feature = "vsock")]
+            allow_syscall_if(
+                libc::SYS_setsockopt,
+                or![and![
+                    Cond::new(1, Eq, libc::SOL_SOCKET as u64)?,
+                    Cond::new(2, Eq, libc::SO_SNDTIMEO as u64)?,
+                ]],
+            ),
             allow_syscall(libc::SYS_stat),
+            // SYS_tgkill is needed for asking the threads to capture their stack on SIGUSR2.
+            allow_syscall(libc::SYS_tgkill),
             allow_syscall(libc::SYS_timerfd_create),
             allow_syscall(libc::SYS_timerfd_settime),
+            // SYS_unlink is needed for discarding the files the guest didn't finish sending.
+            #[cfg(feature = "vsock")]
+            allow_syscall(libc::SYS_unlink),
             allow_syscall(libc::SYS_write),
             allow_syscall(libc::SYS_writev),
         ]
@@ -206,6 +256,8 @@ fn create_common_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, Eq, KVM_SET_USER_MEMORY_REGION,)?],
         and![Cond::new(1, Eq, FIOCLEX)?],
         and![Cond::new(1, Eq, FIONBIO)?],
+        and![Cond::new(1, Eq, BLKSSZGET)?],
+        and![Cond::new(1, Eq, BLKGETSIZE64)?],
         and![Cond::new(1, Eq, TUNSETIFF)?],
         and![Cond::new(1, Eq, TUNSETOFFLOAD)?],
         and![Cond::new(1, Eq, TUNSETVNETHDRSZ)?],
@@ -218,6 +270,15 @@ fn create_common_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, Eq, KVM_SET_MSRS)?],
         and![Cond::new(1, Eq, KVM_SET_REGS)?],
         and![Cond::new(1, Eq, KVM_SET_SREGS)?],
+        and![Cond::new(1, Eq, KVM_GET_CLOCK)?],
+        and![Cond::new(1, Eq, KVM_GET_IRQCHIP)?],
+        and![Cond::new(1, Eq, KVM_GET_MP_STATE)?],
+        and![Cond::new(1, Eq, KVM_GET_MSRS)?],
+        and![Cond::new(1, Eq, KVM_GET_PIT2)?],
+        and![Cond::new(1, Eq, KVM_GET_REGS)?],
+        and![Cond::new(1, Eq, KVM_GET_VCPU_EVENTS)?],
+        and![Cond::new(1, Eq, KVM_GET_XCRS)?],
+        and![Cond::new(1, Eq, KVM_GET_XSAVE)?],
     ])
 }
 
@@ -257,6 +318,11 @@ mod tests {
     extern crate seccomp;
 
     use super::*;
+    use devices::virtio::block::{IoRetry, IoRetryPolicy};
+    use epoll;
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::Duration;
 
     const EXTRA_SYSCALLS: [i64; 5] = [
         libc::SYS_clone,
@@ -292,4 +358,36 @@ mod tests {
         let filter = default_filter().unwrap();
         add_syscalls_install_filter(filter);
     }
+
+    #[test]
+    fn test_block_io_retry_seccomp() {
+        // The retry timer of a block device is created and registered before the filter is
+        // installed, just like when the device is set up.
+        let mut io_retry = IoRetry::new(IoRetryPolicy {
+            max_attempts: 2,
+            backoff: Duration::from_millis(1),
+        })
+        .unwrap();
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        epoll::ctl(
+            epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            io_retry.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, 0),
+        )
+        .unwrap();
+
+        // A syscall missing from the filter kills the whole test process, so the retry runs
+        // in its own thread.
+        thread::spawn(move || {
+            add_syscalls_install_filter(default_filter().unwrap());
+            assert!(io_retry.schedule_retry(true));
+            let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 1];
+            assert_eq!(epoll::wait(epoll_raw_fd, -1, &mut events[..]).unwrap(), 1);
+            io_retry.expire();
+            assert!(!io_retry.is_pending());
+        })
+        .join()
+        .unwrap();
+    }
 }
@@ -5,18 +5,26 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use std::collections::HashMap;
-use std::os::unix::io::AsRawFd;
+use std::collections::BTreeMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(feature = "virtio-fs")]
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::{fmt, io};
+use std::{cmp, fmt, io};
 
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::DeviceInfoForFDT;
 use arch::DeviceType;
 use devices;
 use kernel_cmdline;
+use kvm_bindings::{
+    kvm_ioeventfd, kvm_ioeventfd_flag_nr_datamatch, kvm_ioeventfd_flag_nr_deassign,
+    kvm_ioeventfd_flag_nr_pio, kvm_irqfd, KVMIO, KVM_IRQFD_FLAG_DEASSIGN,
+};
 use kvm_ioctls::{IoEventAddress, VmFd};
 use memory_model::GuestMemory;
+use serde_json::{Map, Value};
+use sys_util::ioctl_with_ref;
 
 /// Errors for MMIO device manager.
 #[derive(Debug)]
@@ -25,18 +33,46 @@ pub enum Error {
     BusError(devices::BusError),
     /// Could not create the mmio device to wrap a VirtioDevice.
     CreateMmioDevice(io::Error),
+    #[cfg(feature = "virtio-fs")]
+    /// Could not create the virtio-fs device.
+    CreateFsDevice(devices::virtio::fs::Error),
     /// Appending to kernel command line failed.
     Cmdline(kernel_cmdline::Error),
+    /// A device with the given id is already registered.
+    DeviceIdInUse(String),
+    /// No device with the given id is registered.
+    DeviceNotFound(String),
     /// Failure in creating or cloning an event fd.
     EventFd(io::Error),
+    /// The high mmio range has no room left for a device of the given size.
+    HighMmioExhausted(String, u64),
+    /// The high mmio range starting at the given address is empty, not page aligned or
+    /// overflows the address space.
+    InvalidHighMmioRange(u64),
+    /// The queue notification register can't be placed at the given offset.
+    InvalidNotifyRegOffset(u32),
     /// No more IRQs are available.
     IrqsExhausted,
+    /// The range reserved for the mmio devices overlaps the guest memory.
+    MmioRangeOverlapsMemory(u64, u64),
     /// Registering an IO Event failed.
     RegisterIoEvent(io::Error),
     /// Registering an IRQ FD failed.
     RegisterIrqFd(io::Error),
+    /// The saved state of the device with the given id can't be restored.
+    RestoreState(String),
+    /// No high mmio range is reserved.
+    NoHighMmioRange,
+    /// Unregistering an IO Event failed.
+    UnregisterIoEvent(io::Error),
+    /// Unregistering an IRQ FD failed.
+    UnregisterIrqFd(io::Error),
+    /// The device at the given address is not a block device.
+    NotABlockDevice(u64),
     /// Failed to update the mmio device.
     UpdateFailed,
+    /// The mmio access of the given length at the given address is not covered by a device.
+    InvalidMmioAccess(u64, usize),
 }
 
 impl fmt::Display for Error {
@@ -44,13 +80,51 @@ impl fmt::Display for Error {
         match *self {
             Error::BusError(ref e) => write!(f, "failed to perform bus operation: {}", e),
             Error::CreateMmioDevice(ref e) => write!(f, "failed to create mmio device: {}", e),
+            #[cfg(feature = "virtio-fs")]
+            Error::CreateFsDevice(ref e) => write!(f, "failed to create virtio-fs device: {}", e),
             Error::Cmdline(ref e) => {
                 write!(f, "unable to add device to kernel command line: {}", e)
             }
+            Error::DeviceIdInUse(ref id) => {
+                write!(f, "a device with id {} is already registered", id)
+            }
+            Error::DeviceNotFound(ref id) => write!(f, "no device with id {} is registered", id),
             Error::EventFd(ref e) => write!(f, "failed to create or clone event descriptor: {}", e),
+            Error::HighMmioExhausted(ref name, len) => write!(
+                f,
+                "high mmio range {} has no room left for 0x{:x} bytes",
+                name, len
+            ),
+            Error::InvalidHighMmioRange(base) => {
+                write!(f, "invalid high mmio range at 0x{:x}", base)
+            }
+            Error::InvalidNotifyRegOffset(offset) => write!(
+                f,
+                "invalid queue notification register offset 0x{:x}",
+                offset
+            ),
+            Error::InvalidMmioAccess(addr, len) => write!(
+                f,
+                "no device covers the mmio access of {} bytes at 0x{:x}",
+                len, addr
+            ),
             Error::IrqsExhausted => write!(f, "no more IRQs are available"),
+            Error::MmioRangeOverlapsMemory(start, end) => write!(
+                f,
+                "mmio range 0x{:x}-0x{:x} overlaps the guest memory",
+                start, end
+            ),
             Error::RegisterIoEvent(ref e) => write!(f, "failed to register IO event: {}", e),
             Error::RegisterIrqFd(ref e) => write!(f, "failed to register irqfd: {}", e),
+            Error::RestoreState(ref id) => {
+                write!(f, "failed to restore the state of the device {}", id)
+            }
+            Error::NoHighMmioRange => write!(f, "no high mmio range is reserved"),
+            Error::UnregisterIoEvent(ref e) => write!(f, "failed to unregister IO event: {}", e),
+            Error::UnregisterIrqFd(ref e) => write!(f, "failed to unregister irqfd: {}", e),
+            Error::NotABlockDevice(addr) => {
+                write!(f, "the device at 0x{:x} is not a block device", addr)
+            }
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
         }
     }
@@ -68,14 +142,180 @@ const MMIO_LEN: u64 = 0x1000;
 /// to its configuration space.
 const MMIO_CFG_SPACE_OFF: u64 = 0x100;
 
+/// Offset of the virtio mmio register holding the device type.
+const MMIO_DEVICE_ID_OFF: u64 = 0x08;
+
+/// The granularity of the high mmio range.
+const HIGH_MMIO_ALIGNMENT: u64 = 0x1000;
+
+// A named address range from which large devices get their mmio window.
+struct HighMmioRange {
+    name: String,
+    start: u64,
+    // The address from which the next device is allocated.
+    next: u64,
+    end: u64,
+}
+
+// Forwards the accesses to a device, handing a line describing each of them to `sink`.
+struct TracingBusDevice {
+    id: String,
+    device: Arc<Mutex<devices::BusDevice>>,
+    sink: fn(&str),
+}
+
+impl TracingBusDevice {
+    fn trace(&self, op: &str, offset: u64, data: &[u8]) {
+        let value: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+        (self.sink)(&format!(
+            "mmio trace: device={} op={} offset=0x{:x} len={} data={}",
+            self.id,
+            op,
+            offset,
+            data.len(),
+            value
+        ));
+    }
+}
+
+impl devices::BusDevice for TracingBusDevice {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.device
+            .lock()
+            .expect("Failed to acquire device lock")
+            .read(offset, data);
+        self.trace("read", offset, data);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        self.trace("write", offset, data);
+        self.device
+            .lock()
+            .expect("Failed to acquire device lock")
+            .write(offset, data);
+    }
+
+    fn interrupt(&self, irq_mask: u32) {
+        self.device
+            .lock()
+            .expect("Failed to acquire device lock")
+            .interrupt(irq_mask);
+    }
+}
+
+fn log_mmio_access(line: &str) {
+    info!("{}", line);
+}
+
+// What it takes to detach a virtio device: its transport, and clones of the eventfds registered
+// for it. The queue events themselves are handed over to the device when the guest activates it.
+struct VirtioDeviceHandle {
+    device: Arc<Mutex<devices::virtio::MmioDevice>>,
+    queue_evts: Vec<sys_util::EventFd>,
+    interrupt_evts: Vec<sys_util::EventFd>,
+    // The queue events are registered at this offset from the mmio base of the device.
+    notify_reg_offset: u32,
+}
+
+// `VmFd` can only register ioeventfds and irqfds, so they are removed through the raw ioctls.
+ioctl_iow_nr!(KVM_IOEVENTFD, KVMIO, 0x79, kvm_ioeventfd);
+ioctl_iow_nr!(KVM_IRQFD, KVMIO, 0x76, kvm_irqfd);
+
+/// The VM operations which wire the eventfds of the mmio devices.
+///
+/// Registering a device takes several of them, which are undone when a later one fails.
+pub trait DeviceEventFds {
+    /// Signals `fd` when the guest writes `datamatch` at `addr`.
+    fn register_ioevent(&self, fd: RawFd, addr: &IoEventAddress, datamatch: u32) -> io::Result<()>;
+    /// Removes an eventfd registered with `register_ioevent`.
+    fn unregister_ioevent(
+        &self,
+        fd: RawFd,
+        addr: &IoEventAddress,
+        datamatch: u32,
+    ) -> io::Result<()>;
+    /// Injects the interrupt `gsi` when `fd` is signaled.
+    fn register_irqfd(&self, fd: RawFd, gsi: u32) -> io::Result<()>;
+    /// Removes an eventfd registered with `register_irqfd`.
+    fn unregister_irqfd(&self, fd: RawFd, gsi: u32) -> io::Result<()>;
+}
+
+impl DeviceEventFds for VmFd {
+    fn register_ioevent(&self, fd: RawFd, addr: &IoEventAddress, datamatch: u32) -> io::Result<()> {
+        VmFd::register_ioevent(self, fd, addr, datamatch)
+    }
+
+    fn unregister_ioevent(
+        &self,
+        fd: RawFd,
+        addr: &IoEventAddress,
+        datamatch: u32,
+    ) -> io::Result<()> {
+        // The ioeventfd is matched on all the fields it was registered with.
+        let mut flags =
+            (1 << kvm_ioeventfd_flag_nr_datamatch) | (1 << kvm_ioeventfd_flag_nr_deassign);
+        let addr = match *addr {
+            IoEventAddress::Pio(addr) => {
+                flags |= 1 << kvm_ioeventfd_flag_nr_pio;
+                addr
+            }
+            IoEventAddress::Mmio(addr) => addr,
+        };
+        let ioeventfd = kvm_ioeventfd {
+            datamatch: u64::from(datamatch),
+            len: ::std::mem::size_of::<u32>() as u32,
+            addr,
+            fd,
+            flags,
+            pad: [0; 36],
+        };
+        // Safe because the kernel only reads the struct, whose size matches the ioctl.
+        let ret = unsafe { ioctl_with_ref(self, KVM_IOEVENTFD(), &ioeventfd) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn register_irqfd(&self, fd: RawFd, gsi: u32) -> io::Result<()> {
+        VmFd::register_irqfd(self, fd, gsi)
+    }
+
+    fn unregister_irqfd(&self, fd: RawFd, gsi: u32) -> io::Result<()> {
+        let irqfd = kvm_irqfd {
+            fd: fd as u32,
+            gsi,
+            flags: KVM_IRQFD_FLAG_DEASSIGN,
+            ..Default::default()
+        };
+        // Safe because the kernel only reads the struct, whose size matches the ioctl.
+        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
 /// Manages the complexities of registering a MMIO device.
 pub struct MMIODeviceManager {
     pub bus: devices::Bus,
     guest_mem: GuestMemory,
     mmio_base: u64,
+    high_mmio: Option<HighMmioRange>,
     irq: u32,
     last_irq: u32,
-    id_to_dev_info: HashMap<String, MMIODeviceInfo>,
+    // Ordered, so that the devices are enumerated the same way across runs.
+    id_to_dev_info: BTreeMap<String, MMIODeviceInfo>,
+    // The devices whose accesses are traced, replaced on the bus by a `TracingBusDevice`.
+    traced_devices: BTreeMap<String, Arc<Mutex<devices::BusDevice>>>,
+    // The virtio devices, which can be detached from the running VM.
+    virtio_devices: BTreeMap<String, VirtioDeviceHandle>,
+    // Clones of the eventfds wired to the IRQs of the devices registered through
+    // `register_bus_device` or `register_high_mmio_device`, to unregister them with the device.
+    irq_evts: BTreeMap<String, Vec<sys_util::EventFd>>,
+    // The lengths of the low mmio slots freed by deregistered devices, by address.
+    free_slots: BTreeMap<u64, u64>,
 }
 
 impl MMIODeviceManager {
@@ -88,139 +328,743 @@ impl MMIODeviceManager {
         MMIODeviceManager {
             guest_mem,
             mmio_base,
+            high_mmio: None,
             irq: irq_interval.0,
             last_irq: irq_interval.1,
             bus: devices::Bus::new(),
-            id_to_dev_info: HashMap::new(),
+            id_to_dev_info: BTreeMap::new(),
+            traced_devices: BTreeMap::new(),
+            virtio_devices: BTreeMap::new(),
+            irq_evts: BTreeMap::new(),
+            free_slots: BTreeMap::new(),
         }
     }
 
-    /// Register a virtio device to be used via MMIO transport.
-    pub fn register_virtio_device(
+    /// Create a new DeviceManager handling mmio devices, making sure that the range where
+    /// the devices will be placed (one `MMIO_LEN` slot per available IRQ, starting at
+    /// `mmio_base`) does not overlap any of the guest memory regions.
+    pub fn new_checked(
+        guest_mem: GuestMemory,
+        mmio_base: u64,
+        irq_interval: (u32, u32),
+    ) -> Result<MMIODeviceManager> {
+        let slots = u64::from(irq_interval.1.saturating_sub(irq_interval.0)) + 1;
+        let mmio_end = slots
+            .checked_mul(MMIO_LEN)
+            .and_then(|len| mmio_base.checked_add(len))
+            .ok_or(Error::MmioRangeOverlapsMemory(mmio_base, u64::max_value()))?;
+
+        guest_mem.with_regions(|_, guest_base, size, _| {
+            let region_start = guest_base.offset() as u64;
+            let region_end = region_start + size as u64;
+            if mmio_base < region_end && region_start < mmio_end {
+                return Err(Error::MmioRangeOverlapsMemory(mmio_base, mmio_end));
+            }
+            Ok(())
+        })?;
+
+        Ok(MMIODeviceManager::new(guest_mem, mmio_base, irq_interval))
+    }
+
+    /// Register a generic bus device at the next free MMIO address.
+    ///
+    /// The device gets `len` bytes of address space, in a slot rounded up to a multiple of
+    /// `MMIO_LEN` so that the following slots stay aligned. When `irq_evt` is provided, the next
+    /// free IRQ is allocated and wired to it through an irqfd; otherwise the device has no
+    /// interrupt and its IRQ is reported as 0.
+    pub fn register_bus_device<V: DeviceEventFds>(
+        &mut self,
+        vm: &V,
+        device: Arc<Mutex<devices::BusDevice>>,
+        len: u64,
+        irq_evt: Option<&sys_util::EventFd>,
+        id: &str,
+        type_: DeviceType,
+    ) -> Result<MMIODeviceInfo> {
+        // The clone is registered, so that it matches when it is unregistered.
+        let irq_evt_clones = clone_irq_evt(irq_evt)?;
+        let irq_evts: Vec<&sys_util::EventFd> = irq_evt_clones.iter().collect();
+        let dev_info =
+            self.register_bus_device_with_vectors(vm, device, len, &irq_evts, id, type_)?;
+        self.irq_evts.insert(id.to_string(), irq_evt_clones);
+
+        Ok(dev_info)
+    }
+
+    // Like `register_bus_device`, but wires each of the `irq_evts` interrupt vectors to an IRQ
+    // of a contiguous block. When there aren't enough IRQs left for all of them, the vectors
+    // fall back to sharing a single IRQ.
+    fn register_bus_device_with_vectors<V: DeviceEventFds>(
+        &mut self,
+        vm: &V,
+        device: Arc<Mutex<devices::BusDevice>>,
+        len: u64,
+        irq_evts: &[&sys_util::EventFd],
+        id: &str,
+        type_: DeviceType,
+    ) -> Result<MMIODeviceInfo> {
+        let dev_info = MMIODeviceInfo {
+            addr: self.next_slot(len),
+            len,
+            irq: 0,
+            irq_count: 0,
+            type_,
+        };
+        let dev_info = self.insert_bus_device(vm, device, irq_evts, id, dev_info)?;
+        self.take_slot(dev_info.addr, len);
+
+        Ok(dev_info)
+    }
+
+    // Returns the address of the low mmio slot for a device of `len` bytes: the lowest slot of
+    // the same length freed by a deregistered device, or the one following the used slots.
+    fn next_slot(&self, len: u64) -> u64 {
+        let slot_len = mmio_slot_len(len);
+        self.free_slots
+            .iter()
+            .find(|&(_, &free_len)| free_len == slot_len)
+            .map_or(self.mmio_base, |(&addr, _)| addr)
+    }
+
+    // Marks the slot returned by `next_slot` as used.
+    fn take_slot(&mut self, addr: u64, len: u64) {
+        if self.free_slots.remove(&addr).is_none() {
+            self.mmio_base += mmio_slot_len(len);
+        }
+    }
+
+    // Hands the slot of a device of `len` bytes at `addr` out again.
+    fn free_slot(&mut self, addr: u64, len: u64) {
+        self.free_slots.insert(addr, mmio_slot_len(len));
+        // The free slots right below the unused range are merged into it.
+        while let Some((addr, len)) = self
+            .free_slots
+            .iter()
+            .next_back()
+            .map(|(&addr, &len)| (addr, len))
+        {
+            if addr + len != self.mmio_base {
+                break;
+            }
+            self.free_slots.remove(&addr);
+            self.mmio_base = addr;
+        }
+    }
+
+    /// Reserve the range of `size` bytes starting at `base` for the mmio windows of large
+    /// devices, which are then registered with `register_high_mmio_device`. The range must
+    /// not overlap any of the guest memory regions.
+    pub fn reserve_high_mmio(&mut self, name: &str, base: u64, size: u64) -> Result<()> {
+        if size == 0 || base % HIGH_MMIO_ALIGNMENT != 0 || size % HIGH_MMIO_ALIGNMENT != 0 {
+            return Err(Error::InvalidHighMmioRange(base));
+        }
+        let end = base
+            .checked_add(size)
+            .ok_or(Error::InvalidHighMmioRange(base))?;
+
+        self.guest_mem
+            .with_regions(|_, guest_base, region_size, _| {
+                let region_start = guest_base.offset() as u64;
+                let region_end = region_start + region_size as u64;
+                if base < region_end && region_start < end {
+                    return Err(Error::MmioRangeOverlapsMemory(base, end));
+                }
+                Ok(())
+            })?;
+
+        self.high_mmio = Some(HighMmioRange {
+            name: name.to_string(),
+            start: base,
+            next: base,
+            end,
+        });
+        Ok(())
+    }
+
+    /// Register a generic bus device in the reserved high mmio range.
+    ///
+    /// Like a PCI BAR, the window of the device is aligned to its size rounded up to a power
+    /// of 2. The IRQ is allocated as in `register_bus_device`.
+    pub fn register_high_mmio_device(
         &mut self,
         vm: &VmFd,
+        device: Arc<Mutex<devices::BusDevice>>,
+        len: u64,
+        irq_evt: Option<&sys_util::EventFd>,
+        id: &str,
+        type_: DeviceType,
+    ) -> Result<MMIODeviceInfo> {
+        let (addr, end) = {
+            let range = self.high_mmio.as_ref().ok_or(Error::NoHighMmioRange)?;
+            let exhausted = || Error::HighMmioExhausted(range.name.clone(), len);
+            let align = len
+                .checked_next_power_of_two()
+                .ok_or_else(exhausted)?
+                .max(HIGH_MMIO_ALIGNMENT);
+            let addr = range.next.checked_add(align - 1).ok_or_else(exhausted)? & !(align - 1);
+            let end = addr.checked_add(len).ok_or_else(exhausted)?;
+            if len == 0 || end > range.end {
+                return Err(exhausted());
+            }
+            (addr, end)
+        };
+
+        let dev_info = MMIODeviceInfo {
+            addr,
+            len,
+            irq: 0,
+            irq_count: 0,
+            type_,
+        };
+        let irq_evt_clones = clone_irq_evt(irq_evt)?;
+        let irq_evts: Vec<&sys_util::EventFd> = irq_evt_clones.iter().collect();
+        let dev_info = self.insert_bus_device(vm, device, &irq_evts, id, dev_info)?;
+        self.irq_evts.insert(id.to_string(), irq_evt_clones);
+        // Safe to unwrap because the range was checked above.
+        self.high_mmio.as_mut().unwrap().next = end;
+
+        Ok(dev_info)
+    }
+
+    // Places the device on the bus in the range described by `dev_info` and allocates the IRQs
+    // of its interrupt vectors.
+    fn insert_bus_device<V: DeviceEventFds>(
+        &mut self,
+        vm: &V,
+        device: Arc<Mutex<devices::BusDevice>>,
+        irq_evts: &[&sys_util::EventFd],
+        id: &str,
+        mut dev_info: MMIODeviceInfo,
+    ) -> Result<MMIODeviceInfo> {
+        if !irq_evts.is_empty() {
+            if self.irq > self.last_irq {
+                return Err(Error::IrqsExhausted);
+            }
+            let vectors = irq_evts.len() as u64;
+            dev_info.irq = self.irq;
+            // The FDT lists every IRQ of the block, while the kernel command line only has room
+            // for one IRQ per device, so the vectors always share it on x86_64.
+            dev_info.irq_count = if cfg!(target_arch = "aarch64")
+                && u64::from(self.irq) + vectors - 1 <= u64::from(self.last_irq)
+            {
+                vectors as u32
+            } else {
+                1
+            };
+            for (i, evt) in irq_evts.iter().enumerate() {
+                if let Err(e) = vm.register_irqfd(evt.as_raw_fd(), dev_info.vector_irq(i)) {
+                    unregister_irqfds(vm, &irq_evts[..i], &dev_info);
+                    return Err(Error::RegisterIrqFd(e));
+                }
+            }
+        }
+
+        if let Err(e) = self.bus.insert(device, dev_info.addr, dev_info.len) {
+            // Best effort, the insertion error is the one worth reporting.
+            unregister_irqfds(vm, irq_evts, &dev_info);
+            return Err(Error::BusError(e));
+        }
+        self.id_to_dev_info.insert(id.to_string(), dev_info.clone());
+        self.irq += dev_info.irq_count;
+
+        Ok(dev_info)
+    }
+
+    /// Remove a device previously registered with the manager from the bus.
+    ///
+    /// The eventfds wired to the device are unregistered from KVM first, and the device stays
+    /// registered when that fails. A virtio device is detached as by `detach_virtio_device`.
+    /// The low mmio slot of the device is handed out again to a device of the same size, while
+    /// its IRQs and its range in the high mmio range are not.
+    pub fn deregister_bus_device<V: DeviceEventFds>(&mut self, vm: &V, id: &str) -> Result<()> {
+        let dev_info = self
+            .id_to_dev_info
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::DeviceNotFound(id.to_string()))?;
+        if self.virtio_devices.contains_key(id) {
+            self.detach_virtio_device(vm, id)?;
+        } else {
+            let irq_evts = self.irq_evts.remove(id).unwrap_or_default();
+            for (i, evt) in irq_evts.iter().enumerate() {
+                if let Err(e) = vm.unregister_irqfd(evt.as_raw_fd(), dev_info.vector_irq(i)) {
+                    // Best effort, the unregistration error is the one worth reporting.
+                    for (j, evt) in irq_evts[..i].iter().enumerate() {
+                        let _ = vm.register_irqfd(evt.as_raw_fd(), dev_info.vector_irq(j));
+                    }
+                    self.irq_evts.insert(id.to_string(), irq_evts);
+                    return Err(Error::UnregisterIrqFd(e));
+                }
+            }
+            self.bus.remove(dev_info.addr);
+            self.id_to_dev_info.remove(id);
+            self.traced_devices.remove(id);
+        }
+
+        let in_high_mmio = self.high_mmio.as_ref().map_or(false, |range| {
+            range.start <= dev_info.addr && dev_info.addr < range.end
+        });
+        if !in_high_mmio {
+            self.free_slot(dev_info.addr, dev_info.len);
+        }
+
+        Ok(())
+    }
+
+    /// Turns the tracing of the accesses to a device on or off. When on, each read and write of
+    /// the device is logged along with its offset, length and data. When off, the device sits
+    /// on the bus directly, so tracing costs nothing.
+    ///
+    /// The vcpus work on a copy of the bus taken when they start, so the setting must be made
+    /// before the microVM starts.
+    pub fn set_mmio_trace(&mut self, id: &str, enabled: bool) -> Result<()> {
+        self.set_mmio_trace_to(id, enabled, log_mmio_access)
+    }
+
+    fn set_mmio_trace_to(&mut self, id: &str, enabled: bool, sink: fn(&str)) -> Result<()> {
+        let (addr, len) = self
+            .id_to_dev_info
+            .get(id)
+            .map(|info| (info.addr, info.len))
+            .ok_or_else(|| Error::DeviceNotFound(id.to_string()))?;
+        if enabled == self.traced_devices.contains_key(id) {
+            return Ok(());
+        }
+
+        let current = self
+            .bus
+            .remove(addr)
+            .ok_or_else(|| Error::DeviceNotFound(id.to_string()))?;
+        let device: Arc<Mutex<devices::BusDevice>> = if enabled {
+            self.traced_devices.insert(id.to_string(), current.clone());
+            Arc::new(Mutex::new(TracingBusDevice {
+                id: id.to_string(),
+                device: current,
+                sink,
+            }))
+        } else {
+            // The traced device was stored when the tracing was turned on.
+            self.traced_devices.remove(id).unwrap()
+        };
+        self.bus.insert(device, addr, len).map_err(Error::BusError)
+    }
+
+    /// Register a virtio device to be used via MMIO transport.
+    ///
+    /// The registration is all or nothing: on failure, the eventfds already registered for the
+    /// device are removed and the device is taken off the bus.
+    pub fn register_virtio_device<V: DeviceEventFds>(
+        &mut self,
+        vm: &V,
+        device: Box<devices::virtio::VirtioDevice>,
+        cmdline: &mut kernel_cmdline::Cmdline,
+        id: &str,
+    ) -> Result<u64> {
+        self.register_virtio_device_with_notify_offset(
+            vm,
+            device,
+            cmdline,
+            id,
+            devices::virtio::NOTIFY_REG_OFFSET,
+        )
+    }
+
+    /// Register a virtio device like `register_virtio_device`, with its queue notification
+    /// register at `notify_reg_offset` from its MMIO base instead of the standard offset. The
+    /// guest driver has to notify the queues at that offset too.
+    pub fn register_virtio_device_with_notify_offset<V: DeviceEventFds>(
+        &mut self,
+        vm: &V,
+        device: Box<devices::virtio::VirtioDevice>,
+        cmdline: &mut kernel_cmdline::Cmdline,
+        id: &str,
+        notify_reg_offset: u32,
+    ) -> Result<u64> {
+        self.register_virtio_device_of_type(
+            vm,
+            device,
+            cmdline,
+            id,
+            DeviceType::Virtio,
+            notify_reg_offset,
+        )
+    }
+
+    #[cfg(feature = "virtio-fs")]
+    /// Register a virtio-fs device sharing the host directory `shared_dir` with the guest, which
+    /// mounts it by `tag`.
+    ///
+    /// The requests of the guest are served in-process, by a FUSE server rooted at `shared_dir`
+    /// which refuses to resolve any path outside of it.
+    pub fn register_virtiofs_device<V: DeviceEventFds>(
+        &mut self,
+        vm: &V,
+        shared_dir: &Path,
+        tag: &str,
+        epoll_config: devices::virtio::fs::EpollConfig,
+        cmdline: &mut kernel_cmdline::Cmdline,
+        id: &str,
+    ) -> Result<u64> {
+        let fs = devices::virtio::Fs::new(shared_dir, tag, epoll_config)
+            .map_err(Error::CreateFsDevice)?;
+        self.register_virtio_device_of_type(
+            vm,
+            Box::new(fs),
+            cmdline,
+            id,
+            DeviceType::Fs,
+            devices::virtio::NOTIFY_REG_OFFSET,
+        )
+    }
+
+    // Registers `device` like `register_virtio_device_with_notify_offset`, recording it as a
+    // device of `type_`.
+    fn register_virtio_device_of_type<V: DeviceEventFds>(
+        &mut self,
+        vm: &V,
         device: Box<devices::virtio::VirtioDevice>,
         cmdline: &mut kernel_cmdline::Cmdline,
         id: &str,
+        type_: DeviceType,
+        notify_reg_offset: u32,
     ) -> Result<u64> {
         if self.irq > self.last_irq {
             return Err(Error::IrqsExhausted);
         }
-        let mmio_device = devices::virtio::MmioDevice::new(self.guest_mem.clone(), device)
-            .map_err(Error::CreateMmioDevice)?;
-        for (i, queue_evt) in mmio_device.queue_evts().iter().enumerate() {
-            let io_addr = IoEventAddress::Mmio(
-                self.mmio_base + u64::from(devices::virtio::NOTIFY_REG_OFFSET),
-            );
-
-            vm.register_ioevent(queue_evt.as_raw_fd(), &io_addr, i as u32)
-                .map_err(Error::RegisterIoEvent)?;
+        if !devices::virtio::is_valid_notify_reg_offset(notify_reg_offset) {
+            return Err(Error::InvalidNotifyRegOffset(notify_reg_offset));
+        }
+        let mmio_device = devices::virtio::MmioDevice::with_notify_reg_offset(
+            self.guest_mem.clone(),
+            device,
+            notify_reg_offset,
+        )
+        .map_err(Error::CreateMmioDevice)?;
+        // The transport and the ioeventfds agree on where the queues are notified.
+        let io_addr = IoEventAddress::Mmio(
+            self.next_slot(MMIO_LEN) + u64::from(mmio_device.notify_reg_offset()),
+        );
+        // The queue eventfds are registered through clones, which are kept to unregister them
+        // whatever step fails, and once the device is detached.
+        let mut queue_evts = Vec::new();
+        for evt in mmio_device.queue_evts() {
+            queue_evts.push(evt.try_clone().map_err(Error::EventFd)?);
+        }
+        let queue_fds: Vec<RawFd> = queue_evts.iter().map(AsRawFd::as_raw_fd).collect();
+        let unregister_ioevents = |fds: &[RawFd]| {
+            for (i, &fd) in fds.iter().enumerate() {
+                // Best effort, the registration error is the one worth reporting.
+                let _ = vm.unregister_ioevent(fd, &io_addr, i as u32);
+            }
+        };
+        for (i, &fd) in queue_fds.iter().enumerate() {
+            if let Err(e) = vm.register_ioevent(fd, &io_addr, i as u32) {
+                unregister_ioevents(&queue_fds[..i]);
+                return Err(Error::RegisterIoEvent(e));
+            }
         }
 
-        if let Some(interrupt_evt) = mmio_device.interrupt_evt() {
-            vm.register_irqfd(interrupt_evt.as_raw_fd(), self.irq)
-                .map_err(Error::RegisterIrqFd)?;
+        let mut interrupt_evt_clones = Vec::new();
+        for evt in mmio_device.interrupt_evts() {
+            match evt.try_clone() {
+                Ok(evt) => interrupt_evt_clones.push(evt),
+                Err(e) => {
+                    unregister_ioevents(&queue_fds);
+                    return Err(Error::EventFd(e));
+                }
+            }
         }
 
-        self.bus
-            .insert(Arc::new(Mutex::new(mmio_device)), self.mmio_base, MMIO_LEN)
-            .map_err(Error::BusError)?;
+        let device = Arc::new(Mutex::new(mmio_device));
+        let dev_info = {
+            let interrupt_evts: Vec<&sys_util::EventFd> = interrupt_evt_clones.iter().collect();
+            let dev_info = match self.register_bus_device_with_vectors(
+                vm,
+                device.clone(),
+                MMIO_LEN,
+                &interrupt_evts,
+                id,
+                type_,
+            ) {
+                Ok(dev_info) => dev_info,
+                Err(e) => {
+                    unregister_ioevents(&queue_fds);
+                    return Err(e);
+                }
+            };
 
-        // as per doc, [virtio_mmio.]device=<size>@<baseaddr>:<irq> needs to be appended
-        // to kernel commandline for virtio mmio devices to get recognized
-        // the size parameter has to be transformed to KiB, so dividing hexadecimal value in
-        // bytes to 1024; further, the '{}' formatting rust construct will automatically
-        // transform it to decimal
+            // as per doc, [virtio_mmio.]device=<size>@<baseaddr>:<irq> needs to be appended
+            // to kernel commandline for virtio mmio devices to get recognized
+            // the size parameter has to be transformed to KiB, so dividing hexadecimal value in
+            // bytes to 1024; further, the '{}' formatting rust construct will automatically
+            // transform it to decimal
 
-        #[cfg(target_arch = "x86_64")]
-        cmdline
-            .insert(
-                "virtio_mmio.device",
-                &format!("{}K@0x{:08x}:{}", MMIO_LEN / 1024, self.mmio_base, self.irq),
-            )
-            .map_err(Error::Cmdline)?;
-        let ret = self.mmio_base;
+            #[cfg(target_arch = "x86_64")]
+            {
+                if let Err(e) = cmdline.insert(
+                    "virtio_mmio.device",
+                    &format!(
+                        "{}K@0x{:08x}:{}",
+                        dev_info.len / 1024,
+                        dev_info.addr,
+                        dev_info.irq
+                    ),
+                ) {
+                    unregister_ioevents(&queue_fds);
+                    self.rollback_bus_device(vm, id, &dev_info, &interrupt_evts);
+                    return Err(Error::Cmdline(e));
+                }
+            }
+            dev_info
+        };
 
-        self.id_to_dev_info.insert(
+        self.virtio_devices.insert(
             id.to_string(),
-            MMIODeviceInfo {
-                addr: ret,
-                len: MMIO_LEN,
-                irq: self.irq,
-                type_: DeviceType::Virtio,
+            VirtioDeviceHandle {
+                device,
+                queue_evts,
+                interrupt_evts: interrupt_evt_clones,
+                notify_reg_offset,
             },
         );
+        Ok(dev_info.addr)
+    }
+
+    /// Detach a virtio device registered with `register_virtio_device` from the running VM.
+    ///
+    /// The eventfds of the device are unregistered first, so that its queues can't be notified
+    /// and it can't interrupt the guest anymore. When that fails, the eventfds already removed
+    /// are registered again and the device stays attached. The device is then unplugged from
+    /// its transport and taken off the bus. Its address range and IRQs are not handed out again.
+    ///
+    /// The vcpus work on a copy of the bus, on which the transport stays as an empty slot.
+    pub fn detach_virtio_device<V: DeviceEventFds>(&mut self, vm: &V, id: &str) -> Result<()> {
+        let not_found = || Error::DeviceNotFound(id.to_string());
+        let dev_info = self.id_to_dev_info.get(id).cloned().ok_or_else(not_found)?;
+        let handle = self.virtio_devices.remove(id).ok_or_else(not_found)?;
+        if let Err(e) = unregister_virtio_eventfds(vm, &handle, &dev_info) {
+            self.virtio_devices.insert(id.to_string(), handle);
+            return Err(e);
+        }
+
+        handle
+            .device
+            .lock()
+            .expect("Failed to acquire device lock")
+            .unplug();
+        self.bus.remove(dev_info.addr);
+        self.id_to_dev_info.remove(id);
+        self.traced_devices.remove(id);
+
+        Ok(())
+    }
+
+    /// Returns the ids of the virtio devices, in the order of `virtio_device_states`.
+    pub fn virtio_device_ids(&self) -> Vec<&str> {
+        self.virtio_devices.keys().map(|id| id.as_str()).collect()
+    }
 
-        self.mmio_base += MMIO_LEN;
-        self.irq += 1;
+    /// Saves the transport state of every virtio device, keyed by device id.
+    pub fn virtio_device_states(&self) -> Vec<(String, Vec<u8>)> {
+        self.virtio_devices
+            .iter()
+            .map(|(id, handle)| {
+                let state = handle
+                    .device
+                    .lock()
+                    .expect("Failed to acquire device lock")
+                    .save_state();
+                (id.clone(), state)
+            })
+            .collect()
+    }
+
+    /// Restores the transport state saved by `virtio_device_states` into the virtio device `id`,
+    /// which must be registered afresh with the configuration of the saved device.
+    pub fn restore_virtio_device(&self, id: &str, state: &[u8]) -> Result<()> {
+        let handle = self
+            .virtio_devices
+            .get(id)
+            .ok_or_else(|| Error::DeviceNotFound(id.to_string()))?;
+        handle
+            .device
+            .lock()
+            .expect("Failed to acquire device lock")
+            .restore_state(state)
+            .map_err(|e| {
+                error!("Failed to restore device {}: {:?}", id, e);
+                Error::RestoreState(id.to_string())
+            })
+    }
 
-        Ok(ret)
+    // Undoes the last `register_bus_device`, handing its address range and IRQ out again.
+    #[cfg(target_arch = "x86_64")]
+    fn rollback_bus_device<V: DeviceEventFds>(
+        &mut self,
+        vm: &V,
+        id: &str,
+        dev_info: &MMIODeviceInfo,
+        irq_evts: &[&sys_util::EventFd],
+    ) {
+        unregister_irqfds(vm, irq_evts, dev_info);
+        self.irq -= dev_info.irq_count;
+        self.bus.remove(dev_info.addr);
+        self.id_to_dev_info.remove(id);
+        self.free_slot(dev_info.addr, dev_info.len);
     }
 
     #[cfg(target_arch = "aarch64")]
-    /// Register an early console at some MMIO address.
-    pub fn enable_earlycon(
+    /// Register a serial port identified by `id` at some MMIO address, its output going to `out`.
+    ///
+    /// The first serial port registered is the early console of the guest. The following ones
+    /// are added as consoles, which the guest addresses by their MMIO address.
+    pub fn register_mmio_serial(
         &mut self,
         vm: &VmFd,
         cmdline: &mut kernel_cmdline::Cmdline,
+        id: &str,
+        out: Box<io::Write + Send>,
     ) -> Result<()> {
+        if self.id_to_dev_info.contains_key(id) {
+            return Err(Error::DeviceIdInUse(id.to_string()));
+        }
         if self.irq > self.last_irq {
             return Err(Error::IrqsExhausted);
         }
+        let first_serial = !self
+            .id_to_dev_info
+            .values()
+            .any(|info| info.type_ == DeviceType::Serial);
 
         let com_evt = sys_util::EventFd::new().map_err(Error::EventFd)?;
         let device = devices::legacy::Serial::new_out(
             com_evt.try_clone().map_err(Error::EventFd)?,
-            Box::new(io::stdout()),
+            out,
             Some(4),
         );
 
-        vm.register_irqfd(com_evt.as_raw_fd(), self.irq)
-            .map_err(Error::RegisterIrqFd)?;
-
-        self.bus
-            .insert(Arc::new(Mutex::new(device)), self.mmio_base, MMIO_LEN)
-            .map_err(|err| Error::BusError(err))?;
+        let dev_info = self.register_bus_device(
+            vm,
+            Arc::new(Mutex::new(device)),
+            MMIO_LEN,
+            Some(&com_evt),
+            id,
+            DeviceType::Serial,
+        )?;
 
+        let console = if first_serial { "earlycon" } else { "console" };
         cmdline
-            .insert("earlycon", &format!("uart,mmio32,0x{:08x}", self.mmio_base))
+            .insert(console, &format!("uart,mmio32,0x{:08x}", dev_info.addr))
             .map_err(Error::Cmdline)?;
 
-        let ret = self.mmio_base;
-        self.id_to_dev_info.insert(
-            "uart".to_string(),
-            MMIODeviceInfo {
-                addr: ret,
-                len: MMIO_LEN,
-                irq: self.irq,
-                type_: DeviceType::Serial,
-            },
-        );
+        Ok(())
+    }
 
-        self.mmio_base += MMIO_LEN;
-        self.irq += 1;
+    #[cfg(target_arch = "aarch64")]
+    /// Register a PL031 RTC at some MMIO address.
+    ///
+    /// The RTC counts from `base_time` seconds since the epoch, or from the current host time
+    /// when `base_time` is `None`.
+    pub fn register_mmio_rtc(&mut self, vm: &VmFd, base_time: Option<u64>) -> Result<()> {
+        let rtc_evt = sys_util::EventFd::new().map_err(Error::EventFd)?;
+        let device =
+            devices::legacy::RTC::new(rtc_evt.try_clone().map_err(Error::EventFd)?, base_time);
+
+        self.register_bus_device(
+            vm,
+            Arc::new(Mutex::new(device)),
+            MMIO_LEN,
+            Some(&rtc_evt),
+            "rtc",
+            DeviceType::RTC,
+        )?;
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Register a pvpanic device at some MMIO address.
+    pub fn register_mmio_pvpanic(&mut self, vm: &VmFd) -> Result<()> {
+        let device = devices::legacy::PvPanic::default();
+
+        self.register_bus_device(
+            vm,
+            Arc::new(Mutex::new(device)),
+            MMIO_LEN,
+            None,
+            "pvpanic",
+            DeviceType::PvPanic,
+        )?;
 
         Ok(())
     }
 
     #[cfg(target_arch = "aarch64")]
     /// Gets the information of the devices registered up to some point in time.
-    pub fn get_device_info(&self) -> &HashMap<String, MMIODeviceInfo> {
+    pub fn get_device_info(&self) -> &BTreeMap<String, MMIODeviceInfo> {
         &self.id_to_dev_info
     }
 
     /// Update a drive by rebuilding its config space and rewriting it on the bus.
     pub fn update_drive(&self, addr: u64, new_size: u64) -> Result<()> {
-        if let Some((_, device)) = self.bus.get_device(addr) {
-            let data = devices::virtio::build_config_space(new_size);
-            let mut busdev = device.lock().map_err(|_| Error::UpdateFailed)?;
+        self.update_drive_deferred(addr, new_size)?;
+        self.signal_config_change(addr)
+    }
 
-            busdev.write(MMIO_CFG_SPACE_OFF, &data[..]);
-            busdev.interrupt(devices::virtio::VIRTIO_MMIO_INT_CONFIG);
+    /// Update a drive like `update_drive`, without notifying the guest. This allows batching
+    /// several updates and notifying the guest once, through `signal_config_change`.
+    pub fn update_drive_deferred(&self, addr: u64, new_size: u64) -> Result<()> {
+        let (_, device) = self.bus.get_device(addr).ok_or(Error::UpdateFailed)?;
+        let mut busdev = device.lock().map_err(|_| Error::UpdateFailed)?;
 
-            Ok(())
+        // Rewriting the config space of any other device would corrupt it.
+        let mut device_id = [0u8; 4];
+        busdev.read(MMIO_DEVICE_ID_OFF, &mut device_id);
+        if u32::from_le_bytes(device_id) != devices::virtio::TYPE_BLOCK {
+            return Err(Error::NotABlockDevice(addr));
+        }
+
+        let data = devices::virtio::build_config_space(new_size);
+        busdev.write(MMIO_CFG_SPACE_OFF, &data[..]);
+        Ok(())
+    }
+
+    /// Notifies the guest that the config space of the device at `addr` changed.
+    pub fn signal_config_change(&self, addr: u64) -> Result<()> {
+        let (_, device) = self.bus.get_device(addr).ok_or(Error::UpdateFailed)?;
+        device
+            .lock()
+            .map_err(|_| Error::UpdateFailed)?
+            .interrupt(devices::virtio::VIRTIO_MMIO_INT_CONFIG);
+        Ok(())
+    }
+
+    /// Routes the mmio access at the guest physical address `offset` to the device covering it.
+    /// A write hands `data` to the device, while a read returns as many bytes as `data` holds.
+    /// The access must fall entirely within the range of a registered device.
+    pub fn dispatch_mmio(&self, offset: u64, data: &[u8], is_write: bool) -> Result<Vec<u8>> {
+        let invalid_access = || Error::InvalidMmioAccess(offset, data.len());
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or_else(invalid_access)?;
+        let covered = self
+            .id_to_dev_info
+            .values()
+            .any(|info| info.addr <= offset && end <= info.addr + info.len);
+        if data.is_empty() || !covered {
+            return Err(invalid_access());
+        }
+
+        let (dev_offset, device) = self.bus.get_device(offset).ok_or_else(invalid_access)?;
+        // A device which panicked during an earlier access can't be trusted anymore.
+        let mut busdev = device.lock().map_err(|_| invalid_access())?;
+        if is_write {
+            busdev.write(dev_offset, data);
+            Ok(Vec::new())
         } else {
-            Err(Error::UpdateFailed)
+            let mut buf = vec![0u8; data.len()];
+            busdev.read(dev_offset, &mut buf);
+            Ok(buf)
         }
     }
 
@@ -231,17 +1075,160 @@ impl MMIODeviceManager {
         }
         None
     }
+
+    /// Gets the IRQ of the specified device, if it has an interrupt.
+    #[allow(dead_code)]
+    pub fn get_irq(&self, id: &str) -> Option<u32> {
+        self.id_to_dev_info
+            .get(id)
+            .map(|dev_info| dev_info.irq)
+            .filter(|&irq| irq != 0)
+    }
+
+    /// Gets the ids and the information of the devices of the given type, ordered by id.
+    #[allow(dead_code)]
+    pub fn devices_of_type(&self, type_: DeviceType) -> Vec<(&str, &MMIODeviceInfo)> {
+        self.id_to_dev_info
+            .iter()
+            .filter(|&(_, info)| info.type_ == type_)
+            .map(|(id, info)| (id.as_str(), info))
+            .collect()
+    }
+
+    /// Describes the registered devices for the guest, as served by the MMDS: the type, the
+    /// base address and the length of the MMIO range and the IRQ of each device, by id.
+    pub fn device_info_metadata(&self) -> Value {
+        let devices = self
+            .id_to_dev_info
+            .iter()
+            .map(|(id, info)| {
+                let mut device = Map::new();
+                device.insert(
+                    String::from("type"),
+                    Value::from(device_type_name(&info.type_)),
+                );
+                device.insert(
+                    String::from("addr"),
+                    Value::from(format!("0x{:x}", info.addr)),
+                );
+                device.insert(
+                    String::from("len"),
+                    Value::from(format!("0x{:x}", info.len)),
+                );
+                device.insert(String::from("irq"), Value::from(info.irq.to_string()));
+                (id.clone(), Value::Object(device))
+            })
+            .collect();
+        Value::Object(devices)
+    }
+}
+
+// Gets the name under which the type of a device is described to the guest.
+fn device_type_name(type_: &DeviceType) -> &'static str {
+    match *type_ {
+        DeviceType::Virtio => "virtio",
+        DeviceType::Fs => "virtio-fs",
+        #[cfg(target_arch = "aarch64")]
+        DeviceType::Serial => "serial",
+        #[cfg(target_arch = "aarch64")]
+        DeviceType::RTC => "rtc",
+        #[cfg(target_arch = "aarch64")]
+        DeviceType::PvPanic => "pvpanic",
+    }
+}
+
+// Rounds `len` up to the length of the low mmio slots.
+fn mmio_slot_len(len: u64) -> u64 {
+    (len + MMIO_LEN - 1) / MMIO_LEN * MMIO_LEN
+}
+
+// Clones the eventfd wired to the IRQ of a bus device, if it has one.
+fn clone_irq_evt(irq_evt: Option<&sys_util::EventFd>) -> Result<Vec<sys_util::EventFd>> {
+    irq_evt
+        .into_iter()
+        .map(|evt| evt.try_clone().map_err(Error::EventFd))
+        .collect()
+}
+
+// Removes the irqfds of the first `irq_evts.len()` interrupt vectors of the device. Errors are
+// ignored, as this only runs when undoing a failed registration.
+fn unregister_irqfds<V: DeviceEventFds>(
+    vm: &V,
+    irq_evts: &[&sys_util::EventFd],
+    dev_info: &MMIODeviceInfo,
+) {
+    for (i, evt) in irq_evts.iter().enumerate() {
+        let _ = vm.unregister_irqfd(evt.as_raw_fd(), dev_info.vector_irq(i));
+    }
+}
+
+// Removes the ioeventfds and the irqfds of a virtio device, all or nothing.
+fn unregister_virtio_eventfds<V: DeviceEventFds>(
+    vm: &V,
+    handle: &VirtioDeviceHandle,
+    dev_info: &MMIODeviceInfo,
+) -> Result<()> {
+    let io_addr = IoEventAddress::Mmio(dev_info.addr + u64::from(handle.notify_reg_offset));
+    // Best effort, the unregistration error is the one worth reporting.
+    let register_ioevents = |evts: &[sys_util::EventFd]| {
+        for (i, evt) in evts.iter().enumerate() {
+            let _ = vm.register_ioevent(evt.as_raw_fd(), &io_addr, i as u32);
+        }
+    };
+
+    for (i, evt) in handle.queue_evts.iter().enumerate() {
+        if let Err(e) = vm.unregister_ioevent(evt.as_raw_fd(), &io_addr, i as u32) {
+            register_ioevents(&handle.queue_evts[..i]);
+            return Err(Error::UnregisterIoEvent(e));
+        }
+    }
+    for (i, evt) in handle.interrupt_evts.iter().enumerate() {
+        if let Err(e) = vm.unregister_irqfd(evt.as_raw_fd(), dev_info.vector_irq(i)) {
+            for (j, evt) in handle.interrupt_evts[..i].iter().enumerate() {
+                let _ = vm.register_irqfd(evt.as_raw_fd(), dev_info.vector_irq(j));
+            }
+            register_ioevents(&handle.queue_evts);
+            return Err(Error::UnregisterIrqFd(e));
+        }
+    }
+    Ok(())
 }
 
 /// Private structure for storing information about the MMIO device registered at some address on the bus.
 #[derive(Clone, Debug)]
 pub struct MMIODeviceInfo {
     addr: u64,
+    // The first IRQ of the contiguous block allocated to the device.
     irq: u32,
+    // The number of IRQs allocated to the device. Interrupt vectors past this count share the
+    // last IRQ of the block.
+    irq_count: u32,
     len: u64,
     type_: DeviceType,
 }
 
+impl MMIODeviceInfo {
+    // Gets the IRQ which interrupt vector `vector` of the device is wired to.
+    fn vector_irq(&self, vector: usize) -> u32 {
+        self.irq + cmp::min(vector as u32, self.irq_count.saturating_sub(1))
+    }
+
+    /// Gets the base address of the device on the bus.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Gets the IRQ of the device.
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    /// Gets the size of the address range of the device.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+}
+
 #[cfg(target_arch = "aarch64")]
 impl DeviceInfoForFDT for MMIODeviceInfo {
     fn addr(&self) -> u64 {
@@ -250,6 +1237,9 @@ impl DeviceInfoForFDT for MMIODeviceInfo {
     fn irq(&self) -> u32 {
         self.irq
     }
+    fn irq_count(&self) -> u32 {
+        cmp::max(self.irq_count, 1)
+    }
     fn length(&self) -> u64 {
         self.len
     }
@@ -260,6 +1250,11 @@ impl DeviceInfoForFDT for MMIODeviceInfo {
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "virtio-fs")]
+    extern crate tempfile;
+
+    #[cfg(feature = "virtio-fs")]
+    use self::tempfile::tempdir;
     use super::super::super::vmm_config::instance_info::{InstanceInfo, InstanceState};
     use super::super::super::Vmm;
     use super::*;
@@ -267,11 +1262,14 @@ mod tests {
     use devices::virtio::{ActivateResult, VirtioDevice};
     use kernel_cmdline;
     use memory_model::{GuestAddress, GuestMemory};
-    use std::sync::atomic::AtomicUsize;
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::mpsc::channel;
-    use std::sync::{Arc, RwLock};
+    use std::sync::{Arc, Mutex, RwLock};
     use sys_util::EventFd;
-    const QUEUE_SIZES: &[u16] = &[64];
+    #[cfg(feature = "virtio-fs")]
+    use {epoll, libc};
+    const QUEUE_SIZES: &[u16] = &[64, 64];
 
     impl MMIODeviceManager {
         // Removing the address of a device will generate an error when you try to update the
@@ -290,7 +1288,7 @@ mod tests {
 
     impl devices::virtio::VirtioDevice for DummyDevice {
         fn device_type(&self) -> u32 {
-            0
+            devices::virtio::TYPE_BLOCK
         }
 
         fn queue_max_sizes(&self) -> &[u16] {
@@ -326,24 +1324,150 @@ mod tests {
         }
     }
 
-    fn create_vmm_object() -> Vmm {
-        let shared_info = Arc::new(RwLock::new(InstanceInfo {
-            state: InstanceState::Uninitialized,
-            id: "TEST_ID".to_string(),
-            vmm_version: "1.0".to_string(),
-        }));
+    // A device reporting the network device type.
+    struct DummyNetDevice;
 
-        let (_to_vmm, from_api) = channel();
-        Vmm::new(
-            shared_info,
-            EventFd::new().expect("cannot create eventFD"),
-            from_api,
-            0,
-        )
-        .expect("Cannot Create VMM")
-    }
+    impl devices::virtio::VirtioDevice for DummyNetDevice {
+        fn device_type(&self) -> u32 {
+            devices::virtio::TYPE_NET
+        }
 
-    #[test]
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+
+        fn ack_features(&mut self, _: u32, _: u32) {}
+
+        fn read_config(&self, _: u64, _: &mut [u8]) {}
+
+        fn write_config(&mut self, _: u64, _: &[u8]) {}
+
+        fn activate(
+            &mut self,
+            _: GuestMemory,
+            _: EventFd,
+            _: Arc<AtomicUsize>,
+            _: Vec<devices::virtio::Queue>,
+            _: Vec<EventFd>,
+        ) -> ActivateResult {
+            Ok(())
+        }
+    }
+
+    // A device with one interrupt vector per queue.
+    struct MultiVectorDevice;
+
+    impl devices::virtio::VirtioDevice for MultiVectorDevice {
+        fn device_type(&self) -> u32 {
+            0
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+
+        fn ack_features(&mut self, _: u32, _: u32) {}
+
+        fn read_config(&self, _: u64, _: &mut [u8]) {}
+
+        fn write_config(&mut self, _: u64, _: &[u8]) {}
+
+        fn interrupt_vectors(&self) -> usize {
+            QUEUE_SIZES.len()
+        }
+
+        fn activate(
+            &mut self,
+            _: GuestMemory,
+            _: EventFd,
+            _: Arc<AtomicUsize>,
+            _: Vec<devices::virtio::Queue>,
+            _: Vec<EventFd>,
+        ) -> ActivateResult {
+            Ok(())
+        }
+    }
+
+    // Keeps track of the registered eventfds, failing the registration of the ioevent of the
+    // queue `failing_queue` and the unregistration of the one of `failing_unregister_queue`.
+    // The ioevents are recorded as (fd, address, datamatch).
+    #[derive(Default)]
+    struct MockEventFds {
+        failing_queue: Option<u32>,
+        failing_unregister_queue: Option<u32>,
+        ioevents: RefCell<Vec<(RawFd, u64, u32)>>,
+        irqfds: RefCell<Vec<(RawFd, u32)>>,
+    }
+
+    fn ioevent_addr(addr: &IoEventAddress) -> u64 {
+        match *addr {
+            IoEventAddress::Pio(addr) | IoEventAddress::Mmio(addr) => addr,
+        }
+    }
+
+    impl DeviceEventFds for MockEventFds {
+        fn register_ioevent(
+            &self,
+            fd: RawFd,
+            addr: &IoEventAddress,
+            datamatch: u32,
+        ) -> io::Result<()> {
+            if self.failing_queue == Some(datamatch) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "no room for the ioevent",
+                ));
+            }
+            self.ioevents
+                .borrow_mut()
+                .push((fd, ioevent_addr(addr), datamatch));
+            Ok(())
+        }
+
+        fn unregister_ioevent(
+            &self,
+            fd: RawFd,
+            addr: &IoEventAddress,
+            datamatch: u32,
+        ) -> io::Result<()> {
+            if self.failing_unregister_queue == Some(datamatch) {
+                return Err(io::Error::new(io::ErrorKind::Other, "ioevent in use"));
+            }
+            let ioevent = (fd, ioevent_addr(addr), datamatch);
+            self.ioevents.borrow_mut().retain(|&other| other != ioevent);
+            Ok(())
+        }
+
+        fn register_irqfd(&self, fd: RawFd, gsi: u32) -> io::Result<()> {
+            self.irqfds.borrow_mut().push((fd, gsi));
+            Ok(())
+        }
+
+        fn unregister_irqfd(&self, fd: RawFd, gsi: u32) -> io::Result<()> {
+            self.irqfds.borrow_mut().retain(|&irqfd| irqfd != (fd, gsi));
+            Ok(())
+        }
+    }
+
+    fn create_vmm_object() -> Vmm {
+        let shared_info = Arc::new(RwLock::new(InstanceInfo {
+            state: InstanceState::Uninitialized,
+            id: "TEST_ID".to_string(),
+            vmm_version: "1.0".to_string(),
+            stop_reason: None,
+        }));
+
+        let (_to_vmm, from_api) = channel();
+        Vmm::new(
+            shared_info,
+            EventFd::new().expect("cannot create eventFD"),
+            from_api,
+            0,
+        )
+        .expect("Cannot Create VMM")
+    }
+
+    #[test]
     fn register_device() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
@@ -360,6 +1484,627 @@ mod tests {
             .is_ok());
     }
 
+    #[test]
+    fn test_register_virtio_device_rollback() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+
+        // The ioevent of the first queue is registered, the one of the second fails.
+        let vm = MockEventFds {
+            failing_queue: Some(1),
+            ..Default::default()
+        };
+        match device_manager.register_virtio_device(
+            &vm,
+            Box::new(DummyDevice { dummy: 0 }),
+            &mut cmdline,
+            "dummy",
+        ) {
+            Err(Error::RegisterIoEvent(_)) => (),
+            _ => unreachable!(),
+        }
+        assert!(vm.ioevents.borrow().is_empty());
+        assert!(vm.irqfds.borrow().is_empty());
+        assert!(device_manager.get_address("dummy").is_none());
+        assert!(device_manager.bus.get_device(0xd000_0000).is_none());
+
+        // Nothing was handed out, so the next device gets the first address and IRQ.
+        let vm = MockEventFds::default();
+        assert_eq!(
+            device_manager
+                .register_virtio_device(
+                    &vm,
+                    Box::new(DummyDevice { dummy: 0 }),
+                    &mut cmdline,
+                    "dummy"
+                )
+                .unwrap(),
+            0xd000_0000
+        );
+        assert_eq!(vm.ioevents.borrow().len(), QUEUE_SIZES.len());
+        assert_eq!(vm.irqfds.borrow().len(), 1);
+        assert_eq!(vm.irqfds.borrow()[0].1, arch::IRQ_BASE);
+
+        // The device is taken off the bus when it doesn't fit in the command line.
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut short_cmdline = kernel_cmdline::Cmdline::new(8);
+            match device_manager.register_virtio_device(
+                &vm,
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut short_cmdline,
+                "other",
+            ) {
+                Err(Error::Cmdline(_)) => (),
+                _ => unreachable!(),
+            }
+            assert_eq!(vm.ioevents.borrow().len(), QUEUE_SIZES.len());
+            assert_eq!(vm.irqfds.borrow().len(), 1);
+            assert!(device_manager.get_address("other").is_none());
+            assert!(device_manager.bus.get_device(0xd000_1000).is_none());
+            assert_eq!(
+                device_manager
+                    .register_virtio_device(
+                        &vm,
+                        Box::new(DummyDevice { dummy: 0 }),
+                        &mut cmdline,
+                        "other"
+                    )
+                    .unwrap(),
+                0xd000_1000
+            );
+            assert_eq!(vm.irqfds.borrow()[1].1, arch::IRQ_BASE + 1);
+        }
+    }
+
+    #[test]
+    fn test_register_virtio_device_notify_offset() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vm = MockEventFds::default();
+
+        // Error case: the offset is taken by the status register.
+        match device_manager.register_virtio_device_with_notify_offset(
+            &vm,
+            Box::new(DummyDevice { dummy: 0 }),
+            &mut cmdline,
+            "dummy",
+            0x70,
+        ) {
+            Err(Error::InvalidNotifyRegOffset(0x70)) => (),
+            _ => unreachable!(),
+        }
+        assert!(vm.ioevents.borrow().is_empty());
+        assert!(device_manager.get_address("dummy").is_none());
+
+        let addr = device_manager
+            .register_virtio_device_with_notify_offset(
+                &vm,
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut cmdline,
+                "dummy",
+                0xc0,
+            )
+            .unwrap();
+        assert_eq!(vm.ioevents.borrow().len(), QUEUE_SIZES.len());
+        assert!(vm
+            .ioevents
+            .borrow()
+            .iter()
+            .all(|&(_, io_addr, _)| io_addr == addr + 0xc0));
+
+        // The device is detached from the same address.
+        device_manager.detach_virtio_device(&vm, "dummy").unwrap();
+        assert!(vm.ioevents.borrow().is_empty());
+
+        // The other devices are notified at the standard offset.
+        let addr = device_manager
+            .register_virtio_device(
+                &vm,
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut cmdline,
+                "other",
+            )
+            .unwrap();
+        assert!(vm.ioevents.borrow().iter().all(
+            |&(_, io_addr, _)| io_addr == addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET)
+        ));
+    }
+
+    #[test]
+    fn test_register_virtio_device_vectors() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_BASE + 2));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vm = MockEventFds::default();
+
+        assert!(device_manager
+            .register_virtio_device(&vm, Box::new(MultiVectorDevice), &mut cmdline, "multi")
+            .is_ok());
+        assert_eq!(vm.irqfds.borrow().len(), 2);
+        assert_ne!(vm.irqfds.borrow()[0].0, vm.irqfds.borrow()[1].0);
+        assert_eq!(vm.irqfds.borrow()[0].1, arch::IRQ_BASE);
+        assert_eq!(device_manager.get_irq("multi"), Some(arch::IRQ_BASE));
+
+        // The kernel command line only advertises the first IRQ, which all the vectors share.
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(vm.irqfds.borrow()[1].1, arch::IRQ_BASE);
+            assert_eq!(device_manager.id_to_dev_info["multi"].irq_count, 1);
+            assert_eq!(
+                cmdline.as_str(),
+                format!("virtio_mmio.device=4K@0xd0000000:{}", arch::IRQ_BASE)
+            );
+        }
+
+        // Each vector gets its own IRQ, all of them listed in the FDT.
+        #[cfg(target_arch = "aarch64")]
+        {
+            assert_eq!(vm.irqfds.borrow()[1].1, arch::IRQ_BASE + 1);
+            assert_eq!(device_manager.id_to_dev_info["multi"].irq_count, 2);
+            assert_eq!(
+                DeviceInfoForFDT::irq_count(&device_manager.id_to_dev_info["multi"]),
+                2
+            );
+
+            // With a single IRQ left, the vectors of the next device share it.
+            assert!(device_manager
+                .register_virtio_device(&vm, Box::new(MultiVectorDevice), &mut cmdline, "shared")
+                .is_ok());
+            assert_eq!(vm.irqfds.borrow().len(), 4);
+            assert_eq!(vm.irqfds.borrow()[2].1, arch::IRQ_BASE + 2);
+            assert_eq!(vm.irqfds.borrow()[3].1, arch::IRQ_BASE + 2);
+            assert_eq!(device_manager.id_to_dev_info["shared"].irq_count, 1);
+
+            match device_manager.register_virtio_device(
+                &vm,
+                Box::new(MultiVectorDevice),
+                &mut cmdline,
+                "exhausted",
+            ) {
+                Err(Error::IrqsExhausted) => (),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_detach_virtio_device() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vm = MockEventFds::default();
+
+        device_manager
+            .register_virtio_device(&vm, Box::new(DummyNetDevice), &mut cmdline, "net0")
+            .unwrap();
+        // The vcpus get a copy of the bus when the microVM starts.
+        let vcpu_bus = device_manager.bus.clone();
+
+        device_manager.detach_virtio_device(&vm, "net0").unwrap();
+        assert!(vm.ioevents.borrow().is_empty());
+        assert!(vm.irqfds.borrow().is_empty());
+        assert!(device_manager.get_address("net0").is_none());
+        assert!(device_manager.bus.get_device(0xd000_0000).is_none());
+        assert!(device_manager.device_info_metadata()["net0"].is_null());
+        // The guest finds an empty slot where the device was.
+        let mut data = [0xffu8; 4];
+        assert!(vcpu_bus.read(0xd000_0008, &mut data));
+        assert_eq!(data, [0, 0, 0, 0]);
+
+        // Error case: the device is already detached.
+        match device_manager.detach_virtio_device(&vm, "net0") {
+            Err(Error::DeviceNotFound(ref id)) => assert_eq!(id, "net0"),
+            _ => unreachable!(),
+        }
+
+        // Error case: the eventfds can't be unregistered, so the device stays attached.
+        let vm = MockEventFds {
+            failing_unregister_queue: Some(1),
+            ..Default::default()
+        };
+        let addr = device_manager
+            .register_virtio_device(&vm, Box::new(DummyNetDevice), &mut cmdline, "net1")
+            .unwrap();
+        match device_manager.detach_virtio_device(&vm, "net1") {
+            Err(Error::UnregisterIoEvent(_)) => (),
+            _ => unreachable!(),
+        }
+        let mut queues: Vec<u32> = vm.ioevents.borrow().iter().map(|&(_, _, q)| q).collect();
+        queues.sort();
+        assert_eq!(queues, vec![0, 1]);
+        assert_eq!(vm.irqfds.borrow().len(), 1);
+        assert_eq!(device_manager.get_address("net1"), Some(&addr));
+        assert!(device_manager.bus.read(addr + 0x08, &mut data));
+        assert_eq!(u32::from_le_bytes(data), devices::virtio::TYPE_NET);
+    }
+
+    #[test]
+    fn test_virtio_device_states() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vm = MockEventFds::default();
+        assert!(device_manager.virtio_device_states().is_empty());
+
+        device_manager
+            .register_virtio_device(&vm, Box::new(DummyNetDevice), &mut cmdline, "net0")
+            .unwrap();
+        let addr = device_manager
+            .register_virtio_device(&vm, Box::new(DummyNetDevice), &mut cmdline, "net1")
+            .unwrap();
+        // The guest selects the second queue of net1.
+        assert!(device_manager.bus.write(addr + 0x30, &1u32.to_le_bytes()));
+
+        let states = device_manager.virtio_device_states();
+        let ids: Vec<&str> = states.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["net0", "net1"]);
+        // The queue selector, then the number of queues.
+        assert_eq!(states[0].1[8..12], 0u32.to_le_bytes());
+        assert_eq!(states[1].1[8..12], 1u32.to_le_bytes());
+        assert_eq!(states[1].1[36..40], 2u32.to_le_bytes());
+
+        // The states are restored into new devices.
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut restored =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        restored
+            .register_virtio_device(&vm, Box::new(DummyNetDevice), &mut cmdline, "net1")
+            .unwrap();
+        restored
+            .restore_virtio_device("net1", &states[1].1)
+            .unwrap();
+        assert_eq!(restored.virtio_device_states()[0], states[1]);
+        match restored.restore_virtio_device("net0", &states[0].1) {
+            Err(Error::DeviceNotFound(id)) => assert_eq!(id, "net0"),
+            _ => panic!("Expected a device not found error."),
+        }
+        match restored.restore_virtio_device("net1", &states[1].1[..8]) {
+            Err(Error::RestoreState(id)) => assert_eq!(id, "net1"),
+            _ => panic!("Expected a restore state error."),
+        }
+    }
+
+    #[cfg(feature = "virtio-fs")]
+    #[test]
+    fn test_register_virtiofs_device() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vm = MockEventFds::default();
+        let shared_dir = tempdir().unwrap();
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, _receiver) = channel();
+
+        assert_eq!(
+            device_manager
+                .register_virtiofs_device(
+                    &vm,
+                    shared_dir.path(),
+                    "shared",
+                    devices::virtio::fs::EpollConfig::new(0, epoll_raw_fd, sender.clone()),
+                    &mut cmdline,
+                    "fs0",
+                )
+                .unwrap(),
+            0xd000_0000
+        );
+        assert_eq!(device_manager.id_to_dev_info["fs0"].type_, DeviceType::Fs);
+        assert_eq!(device_manager.devices_of_type(DeviceType::Fs).len(), 1);
+        assert!(device_manager
+            .devices_of_type(DeviceType::Virtio)
+            .is_empty());
+
+        // The high priority and the request queues are set up, each with its ioevent.
+        let mut data = [0u8; 4];
+        assert!(device_manager.bus.read(0xd000_0008, &mut data));
+        assert_eq!(data, [26, 0, 0, 0]);
+        for queue in 0..2 {
+            assert!(device_manager.bus.write(0xd000_0030, &[queue, 0, 0, 0]));
+            assert!(device_manager.bus.read(0xd000_0034, &mut data));
+            assert_eq!(data, [0, 1, 0, 0]);
+        }
+        let queues: Vec<u32> = vm.ioevents.borrow().iter().map(|&(_, _, q)| q).collect();
+        assert_eq!(queues, vec![0, 1]);
+        assert_eq!(vm.irqfds.borrow().len(), 1);
+
+        // Error case: the shared directory doesn't exist.
+        match device_manager.register_virtiofs_device(
+            &vm,
+            &shared_dir.path().join("missing"),
+            "shared",
+            devices::virtio::fs::EpollConfig::new(0, epoll_raw_fd, sender),
+            &mut cmdline,
+            "fs1",
+        ) {
+            Err(Error::CreateFsDevice(devices::virtio::fs::Error::OpenSharedDir(_))) => (),
+            _ => unreachable!(),
+        }
+        assert!(device_manager.get_address("fs1").is_none());
+        assert_eq!(vm.ioevents.borrow().len(), 2);
+
+        unsafe { libc::close(epoll_raw_fd) };
+    }
+
+    #[test]
+    fn test_new_checked() {
+        let guest_mem =
+            GuestMemory::new(&[(GuestAddress(0x0), 0x1000), (GuestAddress(0x10000), 0x1000)])
+                .unwrap();
+
+        assert!(MMIODeviceManager::new_checked(
+            guest_mem.clone(),
+            0xd000_0000,
+            (arch::IRQ_BASE, arch::IRQ_MAX)
+        )
+        .is_ok());
+
+        // The base itself is outside the memory, but the devices would spill into the
+        // second region.
+        match MMIODeviceManager::new_checked(
+            guest_mem.clone(),
+            0xf000,
+            (arch::IRQ_BASE, arch::IRQ_MAX),
+        ) {
+            Err(Error::MmioRangeOverlapsMemory(start, end)) => {
+                assert_eq!(start, 0xf000);
+                assert_eq!(
+                    end,
+                    0xf000 + u64::from(arch::IRQ_MAX - arch::IRQ_BASE + 1) * MMIO_LEN
+                );
+            }
+            _ => panic!("Expected an overlap error."),
+        }
+
+        // The base sits inside guest RAM.
+        match MMIODeviceManager::new_checked(guest_mem, 0x800, (arch::IRQ_BASE, arch::IRQ_MAX)) {
+            Err(Error::MmioRangeOverlapsMemory(start, _)) => assert_eq!(start, 0x800),
+            _ => panic!("Expected an overlap error."),
+        }
+    }
+
+    struct DummyBusDevice;
+    impl devices::BusDevice for DummyBusDevice {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            data[0] = offset as u8;
+        }
+    }
+
+    #[test]
+    fn test_register_bus_device() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let vmm = create_vmm_object();
+
+        // A device without an interrupt doesn't use up an IRQ.
+        let dev_info = device_manager
+            .register_bus_device(
+                vmm.vm.get_fd(),
+                Arc::new(Mutex::new(DummyBusDevice)),
+                0x20,
+                None,
+                "custom0",
+                DeviceType::Virtio,
+            )
+            .unwrap();
+        assert_eq!(dev_info.addr(), 0xd000_0000);
+        assert_eq!(dev_info.size(), 0x20);
+        assert_eq!(dev_info.irq(), 0);
+        assert_eq!(device_manager.get_irq("custom0"), None);
+
+        let irq_evt = EventFd::new().unwrap();
+        let dev_info = device_manager
+            .register_bus_device(
+                vmm.vm.get_fd(),
+                Arc::new(Mutex::new(DummyBusDevice)),
+                0x20,
+                Some(&irq_evt),
+                "custom1",
+                DeviceType::Virtio,
+            )
+            .unwrap();
+        // The slots are aligned on `MMIO_LEN`.
+        assert_eq!(dev_info.addr(), 0xd000_1000);
+        assert_eq!(dev_info.irq(), arch::IRQ_BASE);
+        assert_eq!(device_manager.get_address("custom1"), Some(&0xd000_1000));
+
+        let mut data = [0xff];
+        assert!(device_manager.bus.read(0xd000_1005, &mut data));
+        assert_eq!(data[0], 5);
+        assert!(!device_manager.bus.read(0xd000_0025, &mut data));
+
+        assert!(device_manager
+            .deregister_bus_device(vmm.vm.get_fd(), "custom1")
+            .is_ok());
+        assert!(!device_manager.bus.read(0xd000_1005, &mut data));
+        assert!(device_manager.get_address("custom1").is_none());
+        match device_manager.deregister_bus_device(vmm.vm.get_fd(), "custom1") {
+            Err(Error::DeviceNotFound(id)) => assert_eq!(id, "custom1"),
+            _ => panic!("Expected a missing device error."),
+        }
+
+        // The freed slot is handed out again, but not the IRQ.
+        let dev_info = device_manager
+            .register_bus_device(
+                vmm.vm.get_fd(),
+                Arc::new(Mutex::new(DummyBusDevice)),
+                0x20,
+                Some(&irq_evt),
+                "custom2",
+                DeviceType::Virtio,
+            )
+            .unwrap();
+        assert_eq!(dev_info.addr(), 0xd000_1000);
+        assert_eq!(dev_info.irq(), arch::IRQ_BASE + 1);
+    }
+
+    #[test]
+    fn test_deregister_bus_device() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vm = MockEventFds::default();
+        let irq_evt = EventFd::new().unwrap();
+
+        device_manager
+            .register_bus_device(
+                &vm,
+                Arc::new(Mutex::new(DummyBusDevice)),
+                0x20,
+                Some(&irq_evt),
+                "custom0",
+                DeviceType::Virtio,
+            )
+            .unwrap();
+        let addr = device_manager
+            .register_virtio_device(&vm, Box::new(DummyNetDevice), &mut cmdline, "net0")
+            .unwrap();
+        assert_eq!(addr, 0xd000_1000);
+        assert_eq!(vm.irqfds.borrow().len(), 2);
+
+        // The irqfd of the device is unregistered along with it.
+        device_manager
+            .deregister_bus_device(&vm, "custom0")
+            .unwrap();
+        assert_eq!(vm.irqfds.borrow().len(), 1);
+        assert!(device_manager.bus.get_device(0xd000_0000).is_none());
+
+        // So are the ioeventfds and the irqfd of a virtio device.
+        device_manager.deregister_bus_device(&vm, "net0").unwrap();
+        assert!(vm.ioevents.borrow().is_empty());
+        assert!(vm.irqfds.borrow().is_empty());
+        assert!(device_manager.bus.get_device(addr).is_none());
+
+        // Both slots are free again.
+        let dev_info = device_manager
+            .register_bus_device(
+                &vm,
+                Arc::new(Mutex::new(DummyBusDevice)),
+                MMIO_LEN,
+                None,
+                "custom1",
+                DeviceType::Virtio,
+            )
+            .unwrap();
+        assert_eq!(dev_info.addr(), 0xd000_0000);
+        let addr = device_manager
+            .register_virtio_device(&vm, Box::new(DummyNetDevice), &mut cmdline, "net1")
+            .unwrap();
+        assert_eq!(addr, 0xd000_1000);
+    }
+
+    #[test]
+    fn test_register_high_mmio_device() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let vmm = create_vmm_object();
+
+        // Error case: no high mmio range is reserved.
+        match device_manager.register_high_mmio_device(
+            vmm.vm.get_fd(),
+            Arc::new(Mutex::new(DummyBusDevice)),
+            0x10_0000,
+            None,
+            "bar0",
+            DeviceType::Virtio,
+        ) {
+            Err(Error::NoHighMmioRange) => (),
+            _ => panic!("Expected a missing high mmio range error."),
+        }
+
+        // Error cases: invalid ranges.
+        match device_manager.reserve_high_mmio("pci64", 0x0, 0x1_0000) {
+            Err(Error::MmioRangeOverlapsMemory(0x0, 0x1_0000)) => (),
+            _ => panic!("Expected a memory overlap error."),
+        }
+        assert!(device_manager
+            .reserve_high_mmio("pci64", 1 << 32, 0)
+            .is_err());
+        assert!(device_manager
+            .reserve_high_mmio("pci64", (1 << 32) + 1, 0x1000)
+            .is_err());
+        assert!(device_manager
+            .reserve_high_mmio("pci64", u64::max_value() & !0xfff, 0x1000)
+            .is_err());
+
+        let (base, size) = (1 << 32, 0x40_0000);
+        assert!(device_manager
+            .reserve_high_mmio("pci64", base, size)
+            .is_ok());
+
+        let dev_info = device_manager
+            .register_high_mmio_device(
+                vmm.vm.get_fd(),
+                Arc::new(Mutex::new(DummyBusDevice)),
+                0x1000,
+                None,
+                "bar0",
+                DeviceType::Virtio,
+            )
+            .unwrap();
+        assert_eq!(dev_info.addr(), base);
+        assert_eq!(dev_info.size(), 0x1000);
+
+        // The window is aligned to its size.
+        let dev_info = device_manager
+            .register_high_mmio_device(
+                vmm.vm.get_fd(),
+                Arc::new(Mutex::new(DummyBusDevice)),
+                0x20_0000,
+                None,
+                "bar1",
+                DeviceType::Virtio,
+            )
+            .unwrap();
+        assert_eq!(dev_info.addr(), base + 0x20_0000);
+        assert!(dev_info.addr() + dev_info.size() <= base + size);
+        assert_eq!(
+            device_manager.get_address("bar1"),
+            Some(&(base + 0x20_0000))
+        );
+
+        let mut data = [0xff];
+        assert!(device_manager.bus.read(base + 0x20_0005, &mut data));
+        assert_eq!(data[0], 5);
+
+        // The low mmio range is not used by the high mmio devices.
+        let dev_info = device_manager
+            .register_bus_device(
+                vmm.vm.get_fd(),
+                Arc::new(Mutex::new(DummyBusDevice)),
+                0x20,
+                None,
+                "custom0",
+                DeviceType::Virtio,
+            )
+            .unwrap();
+        assert_eq!(dev_info.addr(), 0xd000_0000);
+
+        // Error case: the range is full.
+        match device_manager.register_high_mmio_device(
+            vmm.vm.get_fd(),
+            Arc::new(Mutex::new(DummyBusDevice)),
+            0x20_0000,
+            None,
+            "bar2",
+            DeviceType::Virtio,
+        ) {
+            Err(Error::HighMmioExhausted(ref name, 0x20_0000)) => assert_eq!(name, "pci64"),
+            _ => panic!("Expected an exhausted high mmio range error."),
+        }
+    }
+
     #[test]
     fn register_too_many_devices() {
         let start_addr1 = GuestAddress(0x0);
@@ -460,6 +2205,37 @@ mod tests {
             format!("{}", Error::IrqsExhausted),
             "no more IRQs are available"
         );
+        assert_eq!(
+            format!("{}", Error::DeviceIdInUse(String::from("foo"))),
+            "a device with id foo is already registered"
+        );
+        assert_eq!(
+            format!("{}", Error::DeviceNotFound(String::from("foo"))),
+            "no device with id foo is registered"
+        );
+        assert_eq!(
+            format!("{}", Error::MmioRangeOverlapsMemory(0x1000, 0x2000)),
+            "mmio range 0x1000-0x2000 overlaps the guest memory"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::HighMmioExhausted(String::from("pci64"), 0x1000)
+            ),
+            "high mmio range pci64 has no room left for 0x1000 bytes"
+        );
+        assert_eq!(
+            format!("{}", Error::InvalidHighMmioRange(0x1001)),
+            "invalid high mmio range at 0x1001"
+        );
+        assert_eq!(
+            format!("{}", Error::InvalidNotifyRegOffset(0x70)),
+            "invalid queue notification register offset 0x70"
+        );
+        assert_eq!(
+            format!("{}", Error::NoHighMmioRange),
+            "no high mmio range is reserved"
+        );
         assert_eq!(
             format!(
                 "{}",
@@ -477,6 +2253,26 @@ mod tests {
                 io::Error::from_raw_os_error(0)
             )
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::UnregisterIoEvent(io::Error::from_raw_os_error(0))
+            ),
+            format!(
+                "failed to unregister IO event: {}",
+                io::Error::from_raw_os_error(0)
+            )
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::UnregisterIrqFd(io::Error::from_raw_os_error(0))
+            ),
+            format!(
+                "failed to unregister irqfd: {}",
+                io::Error::from_raw_os_error(0)
+            )
+        );
     }
 
     #[test]
@@ -498,6 +2294,304 @@ mod tests {
         assert!(device_manager.update_drive(0xbeef, 1_048_576).is_err());
     }
 
+    #[test]
+    fn test_update_drive_not_block() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vmm = create_vmm_object();
+
+        let addr = device_manager
+            .register_virtio_device(
+                vmm.vm.get_fd(),
+                Box::new(DummyNetDevice),
+                &mut cmdline,
+                "net0",
+            )
+            .unwrap();
+        match device_manager.update_drive(addr, 1_048_576) {
+            Err(Error::NotABlockDevice(a)) => assert_eq!(a, addr),
+            _ => panic!("Updating a net device should fail."),
+        }
+        assert_eq!(
+            format!("{}", Error::NotABlockDevice(addr)),
+            "the device at 0xd0000000 is not a block device"
+        );
+    }
+
+    // Records the config space writes and the interrupts it gets.
+    #[derive(Default)]
+    struct ConfigBusDevice {
+        config_writes: Vec<Vec<u8>>,
+        interrupts: Arc<AtomicUsize>,
+    }
+    impl devices::BusDevice for ConfigBusDevice {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            assert_eq!(offset, MMIO_DEVICE_ID_OFF);
+            data.copy_from_slice(&devices::virtio::TYPE_BLOCK.to_le_bytes());
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            assert_eq!(offset, MMIO_CFG_SPACE_OFF);
+            self.config_writes.push(data.to_vec());
+        }
+
+        fn interrupt(&self, irq_mask: u32) {
+            assert_eq!(irq_mask, devices::virtio::VIRTIO_MMIO_INT_CONFIG);
+            self.interrupts.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_update_drive_deferred() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let vmm = create_vmm_object();
+        let device = Arc::new(Mutex::new(ConfigBusDevice::default()));
+        let addr = device_manager
+            .register_bus_device(
+                vmm.vm.get_fd(),
+                device.clone(),
+                MMIO_LEN,
+                None,
+                "drive",
+                DeviceType::Virtio,
+            )
+            .unwrap()
+            .addr();
+
+        // The deferred updates rewrite the config space without interrupting the guest.
+        assert!(device_manager.update_drive_deferred(addr, 1 << 20).is_ok());
+        assert!(device_manager.update_drive_deferred(addr, 2 << 20).is_ok());
+        assert_eq!(device.lock().unwrap().interrupts.load(Ordering::SeqCst), 0);
+        assert!(device_manager.signal_config_change(addr).is_ok());
+
+        let device = device.lock().unwrap();
+        assert_eq!(device.interrupts.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            device.config_writes,
+            vec![
+                devices::virtio::build_config_space(1 << 20),
+                devices::virtio::build_config_space(2 << 20)
+            ]
+        );
+
+        // Error cases: there is no device at the address.
+        assert!(device_manager
+            .update_drive_deferred(0xbeef, 1 << 20)
+            .is_err());
+        assert!(device_manager.signal_config_change(0xbeef).is_err());
+    }
+
+    // Records the accesses it gets, as (offset, length, is_write).
+    struct RecordingBusDevice {
+        accesses: Arc<Mutex<Vec<(u64, usize, bool)>>>,
+    }
+    impl devices::BusDevice for RecordingBusDevice {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            for byte in data.iter_mut() {
+                *byte = 0xaa;
+            }
+            self.accesses
+                .lock()
+                .unwrap()
+                .push((offset, data.len(), false));
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            self.accesses
+                .lock()
+                .unwrap()
+                .push((offset, data.len(), true));
+        }
+    }
+
+    #[test]
+    fn test_dispatch_mmio() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vmm = create_vmm_object();
+
+        // A virtio device, followed by a device recording the accesses.
+        let virtio_addr = device_manager
+            .register_virtio_device(
+                vmm.vm.get_fd(),
+                Box::new(DummyDevice { dummy: 0 }),
+                &mut cmdline,
+                "virtio",
+            )
+            .unwrap();
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::new(Mutex::new(RecordingBusDevice {
+            accesses: accesses.clone(),
+        }));
+        let recorder_addr = device_manager
+            .register_bus_device(
+                vmm.vm.get_fd(),
+                recorder,
+                MMIO_LEN,
+                None,
+                "recorder",
+                DeviceType::Virtio,
+            )
+            .unwrap()
+            .addr();
+        assert_eq!(recorder_addr, virtio_addr + MMIO_LEN);
+
+        // Reads the virtio magic value.
+        assert_eq!(
+            device_manager
+                .dispatch_mmio(virtio_addr, &[0; 4], false)
+                .unwrap(),
+            vec![b'v', b'i', b'r', b't']
+        );
+
+        // Accesses which aren't covered by a single device.
+        let data = [0u8; 8];
+        for &(addr, len) in &[
+            (virtio_addr, 0),
+            (virtio_addr - 1, 1),
+            (virtio_addr - 4, 8),
+            (recorder_addr - 4, 8),
+            (recorder_addr + MMIO_LEN - 4, 8),
+            (recorder_addr + MMIO_LEN, 1),
+            (std::u64::MAX, 1),
+            (std::u64::MAX - 1, 8),
+        ] {
+            assert!(device_manager
+                .dispatch_mmio(addr, &data[..len], true)
+                .is_err());
+            assert!(device_manager
+                .dispatch_mmio(addr, &data[..len], false)
+                .is_err());
+        }
+        assert!(accesses.lock().unwrap().is_empty());
+
+        // Random accesses around the devices, from a fixed seed xorshift generator.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let data = [0x5au8; 64];
+        for _ in 0..10_000 {
+            let addr = virtio_addr - MMIO_LEN + next() % (4 * MMIO_LEN);
+            let len = (next() % (data.len() as u64 + 1)) as usize;
+            let is_write = next() % 2 == 0;
+            let result = device_manager.dispatch_mmio(addr, &data[..len], is_write);
+
+            let in_virtio = addr >= virtio_addr && addr + len as u64 <= virtio_addr + MMIO_LEN;
+            let in_recorder =
+                addr >= recorder_addr && addr + len as u64 <= recorder_addr + MMIO_LEN;
+            assert_eq!(result.is_ok(), len > 0 && (in_virtio || in_recorder));
+            if in_recorder && len > 0 {
+                let buf = result.unwrap();
+                if is_write {
+                    assert!(buf.is_empty());
+                } else {
+                    assert_eq!(buf, vec![0xaa; len]);
+                }
+                assert_eq!(
+                    accesses.lock().unwrap().pop(),
+                    Some((addr - recorder_addr, len, is_write))
+                );
+            }
+            assert!(accesses.lock().unwrap().is_empty());
+        }
+
+        assert_eq!(
+            format!("{}", Error::InvalidMmioAccess(0xd000_0000, 4)),
+            "no device covers the mmio access of 4 bytes at 0xd0000000"
+        );
+    }
+
+    thread_local! {
+        static TRACE: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    fn record_trace(line: &str) {
+        TRACE.with(|trace| trace.borrow_mut().push(line.to_string()));
+    }
+
+    fn take_trace() -> Vec<String> {
+        TRACE.with(|trace| trace.borrow_mut().split_off(0))
+    }
+
+    #[test]
+    fn test_mmio_trace() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let vmm = create_vmm_object();
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        let addr = device_manager
+            .register_bus_device(
+                vmm.vm.get_fd(),
+                Arc::new(Mutex::new(RecordingBusDevice {
+                    accesses: accesses.clone(),
+                })),
+                MMIO_LEN,
+                None,
+                "recorder",
+                DeviceType::Virtio,
+            )
+            .unwrap()
+            .addr();
+
+        assert!(device_manager
+            .set_mmio_trace_to("foo", true, record_trace)
+            .is_err());
+        device_manager
+            .set_mmio_trace_to("recorder", true, record_trace)
+            .unwrap();
+        // Turning the tracing on twice doesn't trace the accesses twice.
+        device_manager
+            .set_mmio_trace_to("recorder", true, record_trace)
+            .unwrap();
+
+        device_manager
+            .dispatch_mmio(addr + 0x70, &[0x0f, 0, 0, 0], true)
+            .unwrap();
+        device_manager
+            .dispatch_mmio(addr + 0x100, &[0; 2], false)
+            .unwrap();
+        assert_eq!(
+            take_trace(),
+            vec![
+                "mmio trace: device=recorder op=write offset=0x70 len=4 data=0f000000",
+                "mmio trace: device=recorder op=read offset=0x100 len=2 data=aaaa",
+            ]
+        );
+        // The accesses still reach the device.
+        assert_eq!(
+            *accesses.lock().unwrap(),
+            vec![(0x70, 4, true), (0x100, 2, false)]
+        );
+
+        device_manager
+            .set_mmio_trace_to("recorder", false, record_trace)
+            .unwrap();
+        device_manager.dispatch_mmio(addr, &[0; 4], false).unwrap();
+        assert!(take_trace().is_empty());
+        assert_eq!(accesses.lock().unwrap().len(), 3);
+
+        // The traced devices are forgotten along with the device.
+        device_manager
+            .set_mmio_trace_to("recorder", true, record_trace)
+            .unwrap();
+        device_manager
+            .deregister_bus_device(vmm.vm.get_fd(), "recorder")
+            .unwrap();
+        assert!(device_manager.traced_devices.is_empty());
+        assert!(device_manager.dispatch_mmio(addr, &[0; 4], false).is_err());
+    }
+
     #[test]
     fn test_device_info() {
         let start_addr1 = GuestAddress(0x0);
@@ -523,8 +2617,182 @@ mod tests {
                 DeviceType::Virtio,
                 device_manager.id_to_dev_info.get(&id).unwrap().type_
             );
+            assert_eq!(Some(arch::IRQ_BASE), device_manager.get_irq(&id));
         }
         let id = "bar";
         assert_eq!(None, device_manager.get_address(&id));
+        assert_eq!(None, device_manager.get_irq(&id));
+    }
+
+    #[test]
+    fn test_device_info_metadata() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vmm = create_vmm_object();
+        assert_eq!(
+            device_manager.device_info_metadata(),
+            Value::Object(Map::new())
+        );
+
+        for id in &["rootfs", "net0"] {
+            assert!(device_manager
+                .register_virtio_device(
+                    vmm.vm.get_fd(),
+                    Box::new(DummyDevice { dummy: 0 }),
+                    &mut cmdline,
+                    id
+                )
+                .is_ok());
+        }
+
+        let metadata = device_manager.device_info_metadata();
+        let devices = metadata.as_object().unwrap();
+        assert_eq!(devices.len(), device_manager.get_device_info().len());
+        for (id, info) in device_manager.get_device_info() {
+            let device = &devices[id];
+            assert_eq!(device["type"], "virtio");
+            assert_eq!(device["addr"], format!("0x{:x}", info.addr()));
+            assert_eq!(device["len"], format!("0x{:x}", info.size()));
+            assert_eq!(device["irq"], info.irq().to_string());
+        }
+        assert_eq!(devices["rootfs"]["addr"], "0xd0000000");
+        assert_eq!(devices["net0"]["addr"], "0xd0001000");
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_register_mmio_serial() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vmm = create_vmm_object();
+
+        for id in &["uart", "uart1"] {
+            assert!(device_manager
+                .register_mmio_serial(vmm.vm.get_fd(), &mut cmdline, id, Box::new(io::sink()))
+                .is_ok());
+        }
+        match device_manager.register_mmio_serial(
+            vmm.vm.get_fd(),
+            &mut cmdline,
+            "uart1",
+            Box::new(io::sink()),
+        ) {
+            Err(Error::DeviceIdInUse(ref id)) => assert_eq!(id, "uart1"),
+            _ => unreachable!(),
+        }
+
+        let dev_info = device_manager.get_device_info();
+        let first = &dev_info["uart"];
+        let second = &dev_info["uart1"];
+        assert_eq!(first.type_, DeviceType::Serial);
+        assert_eq!(second.type_, DeviceType::Serial);
+        assert_ne!(first.addr, second.addr);
+        assert_ne!(first.irq, second.irq);
+        assert_eq!(
+            cmdline.as_str(),
+            format!(
+                "earlycon=uart,mmio32,0x{:08x} console=uart,mmio32,0x{:08x}",
+                first.addr, second.addr
+            )
+        );
+    }
+
+    #[test]
+    fn test_devices_of_type() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vmm = create_vmm_object();
+
+        for id in &["rootfs", "net0"] {
+            assert!(device_manager
+                .register_virtio_device(
+                    vmm.vm.get_fd(),
+                    Box::new(DummyDevice { dummy: 0 }),
+                    &mut cmdline,
+                    id
+                )
+                .is_ok());
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            assert!(device_manager
+                .register_mmio_serial(vmm.vm.get_fd(), &mut cmdline, "uart", Box::new(io::sink()))
+                .is_ok());
+            assert!(device_manager
+                .register_mmio_rtc(vmm.vm.get_fd(), None)
+                .is_ok());
+            assert!(device_manager
+                .register_mmio_pvpanic(vmm.vm.get_fd())
+                .is_ok());
+        }
+
+        let virtio_devices = device_manager.devices_of_type(DeviceType::Virtio);
+        let ids: Vec<&str> = virtio_devices.iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, vec!["net0", "rootfs"]);
+        assert!(virtio_devices
+            .iter()
+            .all(|&(_, info)| info.type_ == DeviceType::Virtio));
+        assert_eq!(
+            virtio_devices[1].1.addr(),
+            *device_manager.get_address("rootfs").unwrap()
+        );
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            let serial_devices = device_manager.devices_of_type(DeviceType::Serial);
+            assert_eq!(serial_devices.len(), 1);
+            assert_eq!(serial_devices[0].0, "uart");
+            assert_eq!(device_manager.devices_of_type(DeviceType::RTC).len(), 1);
+            let pvpanic_devices = device_manager.devices_of_type(DeviceType::PvPanic);
+            assert_eq!(pvpanic_devices.len(), 1);
+            assert_eq!(pvpanic_devices[0].1.irq(), 0);
+        }
+    }
+
+    #[test]
+    fn test_device_order() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let vmm = create_vmm_object();
+
+        for id in &["rootfs", "net0", "data"] {
+            assert!(device_manager
+                .register_virtio_device(
+                    vmm.vm.get_fd(),
+                    Box::new(DummyDevice { dummy: 0 }),
+                    &mut cmdline,
+                    id
+                )
+                .is_ok());
+        }
+
+        // The devices are enumerated by id, whatever the order of registration.
+        let ids: Vec<&str> = device_manager
+            .id_to_dev_info
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(ids, vec!["data", "net0", "rootfs"]);
+
+        // The command line lists the devices in the order of registration.
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            cmdline.as_str(),
+            format!(
+                "virtio_mmio.device=4K@0xd0000000:{} virtio_mmio.device=4K@0xd0001000:{} \
+                 virtio_mmio.device=4K@0xd0002000:{}",
+                arch::IRQ_BASE,
+                arch::IRQ_BASE + 1,
+                arch::IRQ_BASE + 2
+            )
+        );
     }
 }
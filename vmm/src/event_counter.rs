@@ -0,0 +1,38 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Handle through which other threads record events, e.g. the signs of life from the guest.
+/// The clones of a handle share the same count.
+#[derive(Clone, Default)]
+pub struct EventCounter(Arc<AtomicUsize>);
+
+impl EventCounter {
+    /// Records an event.
+    pub fn record(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_counter() {
+        let counter = EventCounter::default();
+        assert_eq!(counter.count(), 0);
+
+        let clone = counter.clone();
+        counter.record();
+        clone.record();
+        assert_eq!(counter.count(), 2);
+        assert_eq!(clone.count(), 2);
+    }
+}
@@ -0,0 +1,654 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Receiving the files sent by the guest, over vsock.
+//!
+//! The guest connects to a vsock port of the host and sends each file as a line of JSON with its
+//! name and size, e.g. `{"name": "bundle.tar", "size": 1024}`, followed by its contents. Several
+//! files can be sent one after the other on the same connection.
+//!
+//! The contents are written to a hidden file of the receiving directory, which only takes the
+//! name chosen by the guest once all of them arrived. A transfer cut short, e.g. because the
+//! guest hung up, doesn't leave a partial file behind.
+//!
+//! The guest can only send so many files and bytes overall, so that it can't fill the host disk.
+//! The files it started to send count against these limits even if they are discarded.
+
+use std::cmp;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::result;
+
+use epoll;
+use serde_json;
+
+use guest_agent::{accept_vsock, listen_vsock};
+
+// The epoll tokens of the listening socket and of the guest connection.
+const LISTENER_TOKEN: u64 = 0;
+const CONNECTION_TOKEN: u64 = 1;
+
+// The longest header the guest can send.
+const MAX_HEADER_LEN: usize = 4096;
+
+/// Errors associated with the file transfer channel.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot set up the epoll context of the channel.
+    Epoll(io::Error),
+    /// Cannot listen for the guest on the vsock port.
+    Listen(u32, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match *self {
+            Epoll(ref e) => write!(f, "Cannot set up the file transfer channel: {}", e),
+            Listen(port, ref e) => write!(
+                f,
+                "Cannot listen for the file transfers on the vsock port {}: {}",
+                port, e
+            ),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+// Reasons for discarding a file sent by the guest.
+#[derive(Debug)]
+enum TransferError {
+    // The header is not a line of JSON with the name and the size of the file.
+    InvalidHeader,
+    // The name doesn't designate a file right in the receiving directory.
+    InvalidName(String),
+    // The file is larger than allowed.
+    TooLarge(String, u64),
+    // The file would take the guest past the limits on the number of files or bytes it sends.
+    QuotaExceeded(String),
+    // The connection was lost before the whole file arrived. Holds the missing byte count.
+    Truncated(String, u64),
+    // Cannot write the file.
+    Write(PathBuf, io::Error),
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use self::TransferError::*;
+
+        match *self {
+            InvalidHeader => write!(f, "Invalid file transfer header."),
+            InvalidName(ref name) => write!(f, "Invalid file name: {:?}", name),
+            TooLarge(ref name, size) => {
+                write!(f, "The file {} is too large: {} bytes.", name, size)
+            }
+            QuotaExceeded(ref name) => write!(
+                f,
+                "The file {} exceeds the quota of the files sent by the guest.",
+                name
+            ),
+            Truncated(ref name, missing) => write!(
+                f,
+                "The transfer of the file {} stopped {} bytes short.",
+                name, missing
+            ),
+            Write(ref path, ref e) => write!(f, "Cannot write {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Header {
+    name: String,
+    size: u64,
+}
+
+// A file being received.
+struct Transfer {
+    name: String,
+    path: PathBuf,
+    partial_path: PathBuf,
+    file: File,
+    // The number of bytes still to be received.
+    remaining: u64,
+}
+
+impl Transfer {
+    // Gives the received file its final name.
+    fn finish(self) -> result::Result<PathBuf, TransferError> {
+        let result = self
+            .file
+            .sync_data()
+            .and_then(|_| fs::rename(&self.partial_path, &self.path));
+        match result {
+            Ok(()) => Ok(self.path),
+            Err(e) => {
+                let path = self.path.clone();
+                self.discard();
+                Err(TransferError::Write(path, e))
+            }
+        }
+    }
+
+    fn discard(self) {
+        if let Err(e) = fs::remove_file(&self.partial_path) {
+            warn!(
+                "Cannot remove the incomplete file {}: {}",
+                self.partial_path.display(),
+                e
+            );
+        }
+    }
+}
+
+// Checks that `name` designates a file right in the receiving directory. Hidden names are
+// refused, so the guest can't overwrite the files being received.
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/') && !name.contains('\0')
+}
+
+/// How much the guest can send.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// The largest file, in bytes.
+    pub max_file_size: u64,
+    /// The total size of the files, in bytes.
+    pub max_total_size: u64,
+    /// The number of files.
+    pub max_file_count: u64,
+}
+
+/// The host end of the channel through which the guest sends files.
+pub struct FileReceiver {
+    guest_cid: u32,
+    directory: PathBuf,
+    limits: Limits,
+    // The size and the number of the files the guest started to send so far.
+    total_size: u64,
+    file_count: u64,
+    epoll_fd: File,
+    listener: Option<File>,
+    connection: Option<File>,
+    // The bytes of the header received so far.
+    header: Vec<u8>,
+    transfer: Option<Transfer>,
+}
+
+impl FileReceiver {
+    /// Listens for the files of the guest `guest_cid` on the vsock `port`, and writes them to
+    /// `directory`. The files past the `limits` are refused, as well as the connections coming
+    /// from other guests or from the host.
+    pub fn listen(guest_cid: u32, port: u32, directory: &Path, limits: Limits) -> Result<Self> {
+        let listener = listen_vsock(port).map_err(|e| Error::Listen(port, e))?;
+        Self::new(guest_cid, directory, limits, Some(listener))
+    }
+
+    fn new(
+        guest_cid: u32,
+        directory: &Path,
+        limits: Limits,
+        listener: Option<File>,
+    ) -> Result<Self> {
+        let epoll_fd = epoll::create(true).map_err(Error::Epoll)?;
+        let receiver = FileReceiver {
+            guest_cid,
+            directory: directory.to_path_buf(),
+            limits,
+            total_size: 0,
+            file_count: 0,
+            // This is safe because we own the epoll fd we have just created.
+            epoll_fd: unsafe { File::from_raw_fd(epoll_fd) },
+            listener,
+            connection: None,
+            header: Vec::new(),
+            transfer: None,
+        };
+        if let Some(ref listener) = receiver.listener {
+            receiver.watch(listener.as_raw_fd(), LISTENER_TOKEN)?;
+        }
+        Ok(receiver)
+    }
+
+    fn watch(&self, fd: RawFd, token: u64) -> Result<()> {
+        epoll::ctl(
+            self.epoll_fd.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, token),
+        )
+        .map_err(Error::Epoll)
+    }
+
+    /// Returns whether the guest is connected.
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Accepts the connection of the guest and receives its files. Returns the paths of the
+    /// files which were received completely.
+    pub fn handle_event(&mut self) -> Vec<PathBuf> {
+        let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 2];
+        let num_events = epoll::wait(self.epoll_fd.as_raw_fd(), 0, &mut events).unwrap_or(0);
+
+        let mut received = Vec::new();
+        for event in events.iter().take(num_events) {
+            match event.data {
+                LISTENER_TOKEN => self.accept_connections(),
+                CONNECTION_TOKEN => self.receive(&mut received),
+                _ => (),
+            }
+        }
+        received
+    }
+
+    fn accept_connections(&mut self) {
+        loop {
+            let result = match self.listener {
+                Some(ref listener) => accept_vsock(listener),
+                None => return,
+            };
+            let (connection, cid) = match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        warn!("Cannot accept the file transfer connection: {}", e);
+                    }
+                    return;
+                }
+            };
+            if cid != self.guest_cid {
+                warn!(
+                    "Refused a file transfer connection from the vsock CID {}.",
+                    cid
+                );
+                continue;
+            }
+            // A new connection replaces the previous one, and the file it was sending is lost.
+            self.warn_truncated();
+            if let Err(e) = self.connect(connection) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    fn connect(&mut self, connection: File) -> Result<()> {
+        self.disconnect();
+        self.watch(connection.as_raw_fd(), CONNECTION_TOKEN)?;
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        // Closing the connection also removes it from the epoll context.
+        self.connection = None;
+        self.header.clear();
+        if let Some(transfer) = self.transfer.take() {
+            transfer.discard();
+        }
+    }
+
+    fn warn_truncated(&self) {
+        if let Some(ref transfer) = self.transfer {
+            warn!(
+                "Discarded the file sent by the guest: {}",
+                TransferError::Truncated(transfer.name.clone(), transfer.remaining)
+            );
+        }
+    }
+
+    fn receive(&mut self, received: &mut Vec<PathBuf>) {
+        let mut buf = [0u8; 16384];
+        let result = match self.connection {
+            Some(ref mut connection) => connection.read(&mut buf),
+            None => return,
+        };
+        match result {
+            Ok(count) if count > 0 => {
+                if let Err(e) = self.consume(&buf[..count], received) {
+                    warn!("Discarded the file sent by the guest: {}", e);
+                    self.disconnect();
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            _ => {
+                self.warn_truncated();
+                self.disconnect();
+            }
+        }
+    }
+
+    fn consume(
+        &mut self,
+        mut data: &[u8],
+        received: &mut Vec<PathBuf>,
+    ) -> result::Result<(), TransferError> {
+        while !data.is_empty() {
+            let mut transfer = match self.transfer.take() {
+                Some(transfer) => transfer,
+                None => {
+                    let end = data.iter().position(|&byte| byte == b'\n');
+                    let len = end.unwrap_or_else(|| data.len());
+                    if self.header.len() + len > MAX_HEADER_LEN {
+                        return Err(TransferError::InvalidHeader);
+                    }
+                    self.header.extend_from_slice(&data[..len]);
+                    match end {
+                        Some(end) => data = &data[end + 1..],
+                        None => return Ok(()),
+                    }
+                    let header = serde_json::from_slice::<Header>(&self.header)
+                        .map_err(|_| TransferError::InvalidHeader)?;
+                    self.header.clear();
+                    self.start(header)?
+                }
+            };
+
+            let len = cmp::min(data.len() as u64, transfer.remaining) as usize;
+            if let Err(e) = transfer.file.write_all(&data[..len]) {
+                let path = transfer.path.clone();
+                transfer.discard();
+                return Err(TransferError::Write(path, e));
+            }
+            transfer.remaining -= len as u64;
+            data = &data[len..];
+
+            if transfer.remaining == 0 {
+                received.push(transfer.finish()?);
+            } else {
+                self.transfer = Some(transfer);
+            }
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, header: Header) -> result::Result<Transfer, TransferError> {
+        if !is_plain_name(&header.name) {
+            return Err(TransferError::InvalidName(header.name));
+        }
+        if header.size > self.limits.max_file_size {
+            return Err(TransferError::TooLarge(header.name, header.size));
+        }
+        if self.file_count >= self.limits.max_file_count
+            || header.size > self.limits.max_total_size - self.total_size
+        {
+            return Err(TransferError::QuotaExceeded(header.name));
+        }
+        self.file_count += 1;
+        self.total_size += header.size;
+
+        let partial_path = self.directory.join(format!(".{}.partial", header.name));
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&partial_path)
+            .map_err(|e| TransferError::Write(partial_path.clone(), e))?;
+        Ok(Transfer {
+            path: self.directory.join(&header.name),
+            name: header.name,
+            partial_path,
+            file,
+            remaining: header.size,
+        })
+    }
+}
+
+impl AsRawFd for FileReceiver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll_fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn limits(max_file_size: u64) -> Limits {
+        Limits {
+            max_file_size,
+            max_total_size: 1 << 30,
+            max_file_count: 100,
+        }
+    }
+
+    // Connects a mock guest to `receiver`.
+    fn connect_guest(receiver: &mut FileReceiver) -> UnixStream {
+        let (host_end, guest_end) = UnixStream::pair().unwrap();
+        // This is safe because the fd comes from a stream we own.
+        let connection = unsafe { File::from_raw_fd(host_end.into_raw_fd()) };
+        receiver.connect(connection).unwrap();
+        guest_end
+    }
+
+    // Receives files until the guest hangs up, then returns their paths.
+    fn receive_all(receiver: &mut FileReceiver) -> Vec<PathBuf> {
+        let mut received = Vec::new();
+        while receiver.is_connected() {
+            let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 1];
+            assert_eq!(
+                epoll::wait(receiver.as_raw_fd(), 5000, &mut events).unwrap(),
+                1
+            );
+            received.extend(receiver.handle_event());
+        }
+        received
+    }
+
+    // Returns the names of the files in `directory`, hidden ones included.
+    fn list_files(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_is_plain_name() {
+        assert!(is_plain_name("bundle.tar.gz"));
+        assert!(is_plain_name("dmesg..log"));
+
+        assert!(!is_plain_name(""));
+        assert!(!is_plain_name("."));
+        assert!(!is_plain_name(".."));
+        assert!(!is_plain_name("../bundle.tar"));
+        assert!(!is_plain_name("/etc/passwd"));
+        assert!(!is_plain_name("logs/dmesg"));
+        assert!(!is_plain_name(".bundle.tar.partial"));
+        assert!(!is_plain_name("bundle\0.tar"));
+    }
+
+    #[test]
+    fn test_receive_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut receiver = FileReceiver::new(3, directory.path(), limits(1 << 20), None).unwrap();
+        assert!(!receiver.is_connected());
+
+        // The mock guest sends a large file in small chunks, then an empty file and a small one
+        // at once.
+        let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let sent = contents.clone();
+        let mut guest = connect_guest(&mut receiver);
+        let mock_guest = thread::spawn(move || {
+            guest
+                .write_all(
+                    format!("{{\"name\": \"bundle.tar\", \"size\": {}}}\n", sent.len()).as_bytes(),
+                )
+                .unwrap();
+            for chunk in sent.chunks(3000) {
+                guest.write_all(chunk).unwrap();
+            }
+            guest
+                .write_all(
+                    b"{\"name\": \"empty\", \"size\": 0}\n{\"name\": \"dmesg\", \"size\": 5}\nhello",
+                )
+                .unwrap();
+        });
+
+        let received = receive_all(&mut receiver);
+        mock_guest.join().unwrap();
+        assert_eq!(
+            received,
+            vec![
+                directory.path().join("bundle.tar"),
+                directory.path().join("empty"),
+                directory.path().join("dmesg"),
+            ]
+        );
+        assert_eq!(fs::read(&received[0]).unwrap(), contents);
+        assert!(fs::read(&received[1]).unwrap().is_empty());
+        assert_eq!(fs::read(&received[2]).unwrap(), b"hello");
+        assert_eq!(
+            list_files(directory.path()),
+            vec!["bundle.tar", "dmesg", "empty"]
+        );
+    }
+
+    #[test]
+    fn test_truncated_transfer() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut receiver = FileReceiver::new(3, directory.path(), limits(1 << 20), None).unwrap();
+
+        // The guest hangs up halfway through the file.
+        let mut guest = connect_guest(&mut receiver);
+        guest
+            .write_all(b"{\"name\": \"bundle.tar\", \"size\": 100}\n0123456789")
+            .unwrap();
+        let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 1];
+        assert_eq!(
+            epoll::wait(receiver.as_raw_fd(), 5000, &mut events).unwrap(),
+            1
+        );
+        assert!(receiver.handle_event().is_empty());
+        // The incomplete file is kept aside while the transfer goes on.
+        assert_eq!(list_files(directory.path()), vec![".bundle.tar.partial"]);
+
+        drop(guest);
+        assert!(receive_all(&mut receiver).is_empty());
+        assert!(list_files(directory.path()).is_empty());
+
+        // The guest can connect again, and replacing the connection also discards the file.
+        let mut guest = connect_guest(&mut receiver);
+        guest
+            .write_all(b"{\"name\": \"bundle.tar\", \"size\": 100}\n0123456789")
+            .unwrap();
+        assert_eq!(
+            epoll::wait(receiver.as_raw_fd(), 5000, &mut events).unwrap(),
+            1
+        );
+        receiver.handle_event();
+        connect_guest(&mut receiver);
+        assert!(list_files(directory.path()).is_empty());
+    }
+
+    #[test]
+    fn test_refused_transfers() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut receiver = FileReceiver::new(3, directory.path(), limits(10), None).unwrap();
+
+        let headers: [&[u8]; 5] = [
+            b"{\"name\": \"../escape\", \"size\": 1}\n",
+            b"{\"name\": \"/tmp/escape\", \"size\": 1}\n",
+            b"{\"name\": \"bundle.tar\", \"size\": 11}\n",
+            b"{\"name\": \"bundle.tar\"}\n",
+            b"bundle.tar\n",
+        ];
+        for header in headers.iter() {
+            let mut guest = connect_guest(&mut receiver);
+            guest.write_all(header).unwrap();
+            guest.write_all(b"0123456789").unwrap();
+            // The receiver hangs up on its own.
+            assert!(receive_all(&mut receiver).is_empty());
+        }
+
+        // A header which never ends.
+        let mut guest = connect_guest(&mut receiver);
+        let mock_guest = thread::spawn(move || {
+            // The receiver may hang up before everything is sent.
+            let _ = guest.write_all(&[b' '; MAX_HEADER_LEN + 1]);
+            guest
+        });
+        assert!(receive_all(&mut receiver).is_empty());
+        mock_guest.join().unwrap();
+
+        assert!(list_files(directory.path()).is_empty());
+    }
+
+    #[test]
+    fn test_quota() {
+        let directory = tempfile::tempdir().unwrap();
+        let limits = Limits {
+            max_file_size: 10,
+            max_total_size: 15,
+            max_file_count: 3,
+        };
+        let mut receiver = FileReceiver::new(3, directory.path(), limits, None).unwrap();
+
+        // The second file would take the guest past the total size.
+        let mut guest = connect_guest(&mut receiver);
+        guest
+            .write_all(b"{\"name\": \"first\", \"size\": 10}\n0123456789")
+            .unwrap();
+        guest
+            .write_all(b"{\"name\": \"second\", \"size\": 10}\n0123456789")
+            .unwrap();
+        assert_eq!(
+            receive_all(&mut receiver),
+            vec![directory.path().join("first")]
+        );
+
+        // The discarded file counts as well, so the third file is the last one.
+        let mut guest = connect_guest(&mut receiver);
+        guest
+            .write_all(b"{\"name\": \"truncated\", \"size\": 2}\n0")
+            .unwrap();
+        drop(guest);
+        assert!(receive_all(&mut receiver).is_empty());
+        let mut guest = connect_guest(&mut receiver);
+        guest
+            .write_all(b"{\"name\": \"third\", \"size\": 1}\n0")
+            .unwrap();
+        guest
+            .write_all(b"{\"name\": \"fourth\", \"size\": 1}\n0")
+            .unwrap();
+        assert_eq!(
+            receive_all(&mut receiver),
+            vec![directory.path().join("third")]
+        );
+
+        assert_eq!(list_files(directory.path()), vec!["first", "third"]);
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(
+            TransferError::InvalidName(String::from("../escape")).to_string(),
+            "Invalid file name: \"../escape\""
+        );
+        assert_eq!(
+            TransferError::TooLarge(String::from("bundle.tar"), 11).to_string(),
+            "The file bundle.tar is too large: 11 bytes."
+        );
+        assert_eq!(
+            TransferError::QuotaExceeded(String::from("bundle.tar")).to_string(),
+            "The file bundle.tar exceeds the quota of the files sent by the guest."
+        );
+        assert_eq!(
+            TransferError::Truncated(String::from("bundle.tar"), 90).to_string(),
+            "The transfer of the file bundle.tar stopped 90 bytes short."
+        );
+    }
+}
@@ -0,0 +1,713 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Control channel to an agent running in the guest, over vsock.
+//!
+//! The agent connects from the guest to a well-known vsock port of the host. Each command is
+//! sent to the agent as a line of JSON, and the agent answers the commands in order, with a
+//! line of JSON each. The answers arrive on the VMM event loop, so the guest devices keep being
+//! served while the agent runs a command.
+//!
+//! A command fails after its timeout, even if the agent answers later on, so that an agent which
+//! never answers doesn't leave the request hanging. A filesystem freeze which times out is
+//! followed by a thaw, so the guest isn't left frozen.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::result;
+use std::time::{Duration, Instant};
+
+use epoll;
+use libc;
+use serde_json;
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+
+// See include/uapi/linux/vm_sockets.h in the kernel code.
+const VMADDR_CID_ANY: u32 = 0xffff_ffff;
+
+// The epoll tokens of the listening socket, of the agent connection and of the timeout timer.
+const LISTENER_TOKEN: u64 = 0;
+const CONNECTION_TOKEN: u64 = 1;
+const TIMER_TOKEN: u64 = 2;
+
+// An agent which doesn't read its commands can't block the VMM for longer than this.
+const SEND_TIMEOUT_SECS: libc::time_t = 1;
+// The longest line the agent can answer with.
+const MAX_RESPONSE_LEN: usize = 1 << 20;
+// The timeout of the commands sent without one.
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Default)]
+struct sockaddr_vm {
+    svm_family: libc::sa_family_t,
+    svm_reserved1: u16,
+    svm_port: u32,
+    svm_cid: u32,
+    svm_zero: [u8; 4],
+}
+
+/// A command run by the guest agent.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AgentCommand {
+    /// Flushes the guest filesystems to their disks.
+    Flush,
+    /// Freezes the guest filesystems, e.g. before the disks are copied.
+    FreezeFs,
+    /// Thaws the guest filesystems frozen by `FreezeFs`.
+    ThawFs,
+    /// Runs a shell script in the guest.
+    RunScript {
+        /// The script to run.
+        script: String,
+    },
+}
+
+/// The result of a command, as reported by the guest agent.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AgentResponse {
+    /// Whether the command succeeded.
+    pub success: bool,
+    /// What the command printed, or why it failed.
+    #[serde(default)]
+    pub output: String,
+}
+
+/// Errors associated with the guest agent channel.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot set up the epoll context of the channel.
+    Epoll(io::Error),
+    /// Cannot listen for the agent on the vsock port.
+    Listen(u32, io::Error),
+    /// There is no agent channel for the vsock device.
+    NoChannel(String),
+    /// The agent didn't connect yet.
+    NotConnected,
+    /// The connection to the agent was lost before it answered.
+    ConnectionLost,
+    /// The agent didn't answer before the timeout of the command.
+    Timeout,
+    /// Cannot send the command to the agent.
+    Send(io::Error),
+    /// The agent answered with a line which is not a response.
+    InvalidResponse(serde_json::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match *self {
+            Epoll(ref e) => write!(f, "Cannot set up the guest agent channel: {}", e),
+            Listen(port, ref e) => write!(
+                f,
+                "Cannot listen for the guest agent on the vsock port {}: {}",
+                port, e
+            ),
+            NoChannel(ref id) => write!(
+                f,
+                "The vsock device {} of the running microVM has no guest agent port.",
+                id
+            ),
+            NotConnected => write!(f, "The guest agent is not connected."),
+            ConnectionLost => write!(f, "The connection to the guest agent was lost."),
+            Timeout => write!(f, "The guest agent didn't answer in time."),
+            Send(ref e) => write!(f, "Cannot send the command to the guest agent: {}", e),
+            InvalidResponse(ref e) => write!(f, "Invalid guest agent response: {}", e),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+// A command the agent didn't answer yet.
+struct Pending<T> {
+    // `None` once the command timed out, or for the commands sent on behalf of the channel
+    // itself: their answers are dropped.
+    token: Option<T>,
+    deadline: Option<Instant>,
+    // Whether the guest filesystems are thawed when the command times out.
+    thaw_on_timeout: bool,
+}
+
+/// The host end of the channel to a guest agent. Each command sent carries a token of type `T`,
+/// which is handed back together with the outcome of the command.
+pub struct GuestAgent<T> {
+    guest_cid: u32,
+    epoll_fd: File,
+    listener: Option<File>,
+    connection: Option<File>,
+    // Expires at the earliest deadline of the pending commands.
+    timer: TimerFd,
+    // The bytes received past the last complete response.
+    received: Vec<u8>,
+    // The commands the agent didn't answer yet, in the order they were sent.
+    pending: VecDeque<Pending<T>>,
+    default_timeout: Duration,
+}
+
+impl<T> GuestAgent<T> {
+    /// Listens for the agent of the guest `guest_cid` on the vsock `port`. The connections
+    /// coming from other guests or from the host are refused.
+    pub fn listen(guest_cid: u32, port: u32) -> Result<Self> {
+        let listener = listen_vsock(port).map_err(|e| Error::Listen(port, e))?;
+        Self::new(guest_cid, Some(listener))
+    }
+
+    fn new(guest_cid: u32, listener: Option<File>) -> Result<Self> {
+        let epoll_fd = epoll::create(true).map_err(Error::Epoll)?;
+        let agent = GuestAgent {
+            guest_cid,
+            // This is safe because we own the epoll fd we have just created.
+            epoll_fd: unsafe { File::from_raw_fd(epoll_fd) },
+            listener,
+            connection: None,
+            // non-blocking & close on exec
+            timer: TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::Epoll)?,
+            received: Vec::new(),
+            pending: VecDeque::new(),
+            default_timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+        };
+        if let Some(ref listener) = agent.listener {
+            agent.watch(listener.as_raw_fd(), LISTENER_TOKEN)?;
+        }
+        agent.watch(agent.timer.as_raw_fd(), TIMER_TOKEN)?;
+        Ok(agent)
+    }
+
+    fn watch(&self, fd: RawFd, token: u64) -> Result<()> {
+        epoll::ctl(
+            self.epoll_fd.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, token),
+        )
+        .map_err(Error::Epoll)
+    }
+
+    /// Returns whether the agent is connected.
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Sends `command` to the agent. The outcome comes from `handle_event` once the agent
+    /// answered, or once the `timeout` expired, 60 seconds when it is `None`. If the command
+    /// can't be sent, the token is handed back with the error.
+    pub fn send(
+        &mut self,
+        command: &AgentCommand,
+        token: T,
+        timeout: Option<Duration>,
+    ) -> result::Result<(), (Error, T)> {
+        if let Err(e) = self.write_command(command) {
+            return Err((e, token));
+        }
+        self.pending.push_back(Pending {
+            token: Some(token),
+            deadline: Some(Instant::now() + timeout.unwrap_or(self.default_timeout)),
+            thaw_on_timeout: *command == AgentCommand::FreezeFs,
+        });
+        self.arm_timer();
+        Ok(())
+    }
+
+    fn write_command(&mut self, command: &AgentCommand) -> Result<()> {
+        let mut line = serde_json::to_vec(command).map_err(|e| Error::Send(e.into()))?;
+        line.push(b'\n');
+
+        let result = match self.connection {
+            Some(ref mut connection) => connection.write_all(&line),
+            None => return Err(Error::NotConnected),
+        };
+        if let Err(e) = result {
+            // The agent can reconnect, but the commands in flight are lost.
+            warn!("Lost the connection to the guest agent: {}", e);
+            self.disconnect();
+            return Err(Error::Send(e));
+        }
+        Ok(())
+    }
+
+    /// Accepts the connection of the agent and reads its answers. Returns the outcome of each
+    /// answered command, together with its token. When the connection is lost, all the pending
+    /// commands fail.
+    pub fn handle_event(&mut self) -> Vec<(T, Result<AgentResponse>)> {
+        let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 3];
+        let num_events = epoll::wait(self.epoll_fd.as_raw_fd(), 0, &mut events).unwrap_or(0);
+
+        let mut outcomes = Vec::new();
+        for event in events.iter().take(num_events) {
+            match event.data {
+                LISTENER_TOKEN => self.accept_connections(&mut outcomes),
+                CONNECTION_TOKEN => self.receive(&mut outcomes),
+                TIMER_TOKEN => {
+                    self.timer.read();
+                    self.expire(&mut outcomes);
+                }
+                _ => (),
+            }
+        }
+        outcomes
+    }
+
+    fn accept_connections(&mut self, outcomes: &mut Vec<(T, Result<AgentResponse>)>) {
+        loop {
+            let result = match self.listener {
+                Some(ref listener) => accept_vsock(listener),
+                None => return,
+            };
+            let (connection, cid) = match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        warn!("Cannot accept the guest agent connection: {}", e);
+                    }
+                    return;
+                }
+            };
+            if cid != self.guest_cid {
+                warn!(
+                    "Refused a guest agent connection from the vsock CID {}.",
+                    cid
+                );
+                continue;
+            }
+            if let Err(e) = set_send_timeout(&connection) {
+                warn!("Cannot set up the guest agent connection: {}", e);
+                continue;
+            }
+            // A restarted agent replaces the previous connection.
+            self.fail_pending(outcomes);
+            if let Err(e) = self.connect(connection) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    fn connect(&mut self, connection: File) -> Result<()> {
+        self.disconnect();
+        self.watch(connection.as_raw_fd(), CONNECTION_TOKEN)?;
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        // Closing the connection also removes it from the epoll context.
+        self.connection = None;
+        self.received.clear();
+    }
+
+    fn fail_pending(&mut self, outcomes: &mut Vec<(T, Result<AgentResponse>)>) {
+        outcomes.extend(
+            self.pending
+                .drain(..)
+                .filter_map(|pending| pending.token)
+                .map(|token| (token, Err(Error::ConnectionLost))),
+        );
+        self.arm_timer();
+    }
+
+    // Fails the commands whose deadline passed. The agent may still answer them, so they stay
+    // in line until it does.
+    fn expire(&mut self, outcomes: &mut Vec<(T, Result<AgentResponse>)>) {
+        let now = Instant::now();
+        let mut thaw = false;
+        for pending in self.pending.iter_mut() {
+            match pending.deadline {
+                Some(deadline) if deadline <= now => (),
+                _ => continue,
+            }
+            pending.deadline = None;
+            if let Some(token) = pending.token.take() {
+                outcomes.push((token, Err(Error::Timeout)));
+            }
+            thaw |= pending.thaw_on_timeout;
+        }
+
+        if thaw {
+            // The agent may still freeze the filesystems, so the thaw is sent right away. The
+            // agent runs the commands in order, so it thaws them right after.
+            warn!("The guest agent didn't freeze the filesystems in time. Thawing them.");
+            match self.write_command(&AgentCommand::ThawFs) {
+                Ok(()) => self.pending.push_back(Pending {
+                    token: None,
+                    deadline: None,
+                    thaw_on_timeout: false,
+                }),
+                Err(e) => warn!("Cannot thaw the guest filesystems: {}", e),
+            }
+        }
+        self.arm_timer();
+    }
+
+    fn arm_timer(&mut self) {
+        let deadline = self
+            .pending
+            .iter()
+            .filter_map(|pending| pending.deadline)
+            .min();
+        let state = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                // A zero duration would disarm the timer.
+                let remaining = if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_nanos(1)
+                };
+                TimerState::Oneshot(remaining)
+            }
+            None => TimerState::Disarmed,
+        };
+        self.timer.set_state(state, SetTimeFlags::Default);
+    }
+
+    fn receive(&mut self, outcomes: &mut Vec<(T, Result<AgentResponse>)>) {
+        let mut buf = [0u8; 4096];
+        let result = match self.connection {
+            Some(ref mut connection) => connection.read(&mut buf),
+            None => return,
+        };
+        match result {
+            Ok(count) if count > 0 && self.received.len() + count <= MAX_RESPONSE_LEN => {
+                self.received.extend_from_slice(&buf[..count]);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return,
+            _ => {
+                warn!("Lost the connection to the guest agent.");
+                self.disconnect();
+                self.fail_pending(outcomes);
+                return;
+            }
+        }
+
+        while let Some(end) = self.received.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.received.drain(..=end).collect();
+            let response =
+                serde_json::from_slice::<AgentResponse>(&line).map_err(Error::InvalidResponse);
+            match self.pending.pop_front() {
+                Some(Pending {
+                    token: Some(token), ..
+                }) => outcomes.push((token, response)),
+                // The command timed out, or was sent on behalf of the channel.
+                Some(_) => (),
+                None => warn!("Unexpected guest agent response."),
+            }
+        }
+    }
+}
+
+impl<T> AsRawFd for GuestAgent<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll_fd.as_raw_fd()
+    }
+}
+
+/// Listens on the vsock `port` of the host, without blocking the accepts.
+pub(crate) fn listen_vsock(port: u32) -> io::Result<File> {
+    // This is safe because we check the return value.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_VSOCK,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is safe because we own the socket we have just created.
+    let listener = unsafe { File::from_raw_fd(fd) };
+
+    let addr = sockaddr_vm {
+        svm_family: libc::AF_VSOCK as libc::sa_family_t,
+        svm_port: port,
+        svm_cid: VMADDR_CID_ANY,
+        ..Default::default()
+    };
+    // This is safe because the address outlives the call, and we check the return values.
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const sockaddr_vm as *const libc::sockaddr,
+            mem::size_of::<sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 || unsafe { libc::listen(fd, 1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+/// Accepts a connection on the vsock `listener`. Returns the connection, which blocks, together
+/// with the CID it comes from. Fails with `WouldBlock` when there is no connection to accept.
+pub(crate) fn accept_vsock(listener: &File) -> io::Result<(File, u32)> {
+    let mut addr = sockaddr_vm::default();
+    let mut addr_len = mem::size_of::<sockaddr_vm>() as libc::socklen_t;
+    // This is safe because the address outlives the call, and we check the return value.
+    let fd = unsafe {
+        libc::accept4(
+            listener.as_raw_fd(),
+            &mut addr as *mut sockaddr_vm as *mut libc::sockaddr,
+            &mut addr_len,
+            libc::SOCK_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is safe because we own the socket we have just accepted.
+    Ok((unsafe { File::from_raw_fd(fd) }, addr.svm_cid))
+}
+
+fn set_send_timeout(connection: &File) -> io::Result<()> {
+    let timeout = libc::timeval {
+        tv_sec: SEND_TIMEOUT_SECS,
+        tv_usec: 0,
+    };
+    // This is safe because the timeout outlives the call, and we check the return value.
+    let ret = unsafe {
+        libc::setsockopt(
+            connection.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_SNDTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    // Waits for the agent to answer, then returns the outcomes.
+    fn wait_outcomes(agent: &mut GuestAgent<u32>) -> Vec<(u32, Result<AgentResponse>)> {
+        let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 1];
+        assert_eq!(
+            epoll::wait(agent.as_raw_fd(), 5000, &mut events).unwrap(),
+            1
+        );
+        agent.handle_event()
+    }
+
+    #[test]
+    fn test_agent_commands() {
+        assert_eq!(
+            serde_json::to_string(&AgentCommand::FreezeFs).unwrap(),
+            r#"{"command":"freeze_fs"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<AgentCommand>(
+                r#"{"command": "run_script", "script": "echo foo"}"#
+            )
+            .unwrap(),
+            AgentCommand::RunScript {
+                script: String::from("echo foo"),
+            }
+        );
+        assert!(serde_json::from_str::<AgentCommand>(r#"{"command": "reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn test_mock_agent() {
+        let mut agent = GuestAgent::new(3, None).unwrap();
+        assert!(!agent.is_connected());
+        match agent.send(&AgentCommand::Flush, 0, None) {
+            Err((Error::NotConnected, 0)) => (),
+            _ => panic!("The agent should not be connected"),
+        }
+
+        // The mock agent echoes each command, then hangs up.
+        let (host_end, guest_end) = UnixStream::pair().unwrap();
+        let mock_agent = thread::spawn(move || {
+            let mut reader = BufReader::new(guest_end.try_clone().unwrap());
+            let mut writer = guest_end;
+            for _ in 0..2 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let response = AgentResponse {
+                    success: true,
+                    output: line.trim_end().to_string(),
+                };
+                let mut answer = serde_json::to_vec(&response).unwrap();
+                answer.push(b'\n');
+                writer.write_all(&answer).unwrap();
+            }
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+        });
+        // This is safe because the fd comes from a stream we own.
+        let connection = unsafe { File::from_raw_fd(host_end.into_raw_fd()) };
+        agent.connect(connection).unwrap();
+        assert!(agent.is_connected());
+
+        let command = AgentCommand::RunScript {
+            script: String::from("sync"),
+        };
+        agent.send(&command, 1, None).unwrap();
+        let outcomes = wait_outcomes(&mut agent);
+        assert_eq!(outcomes.len(), 1);
+        match outcomes[0] {
+            (1, Ok(ref response)) => {
+                assert!(response.success);
+                assert_eq!(
+                    response.output,
+                    r#"{"command":"run_script","script":"sync"}"#
+                );
+            }
+            _ => panic!("The agent should have echoed the command"),
+        }
+
+        agent.send(&AgentCommand::Flush, 2, None).unwrap();
+        let outcomes = wait_outcomes(&mut agent);
+        match outcomes[0] {
+            (2, Ok(ref response)) => assert_eq!(response.output, r#"{"command":"flush"}"#),
+            _ => panic!("The agent should have echoed the command"),
+        }
+
+        // The agent goes away without answering: the command fails, and the channel waits for
+        // the agent to connect again.
+        agent.send(&AgentCommand::ThawFs, 3, None).unwrap();
+        mock_agent.join().unwrap();
+        let outcomes = wait_outcomes(&mut agent);
+        match outcomes[0] {
+            (3, Err(Error::ConnectionLost)) => (),
+            _ => panic!("The connection should be lost"),
+        }
+        assert!(!agent.is_connected());
+    }
+
+    #[test]
+    fn test_freeze_thaw() {
+        let mut agent = GuestAgent::new(3, None).unwrap();
+
+        // The mock agent acknowledges the commands, except for the second freeze, which it only
+        // acknowledges after the thaw which follows.
+        let (host_end, guest_end) = UnixStream::pair().unwrap();
+        let mock_agent = thread::spawn(move || {
+            let mut reader = BufReader::new(guest_end.try_clone().unwrap());
+            let mut writer = guest_end;
+            let mut commands = Vec::new();
+            for i in 0..4 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                commands.push(line.trim_end().to_string());
+                match i {
+                    2 => (),
+                    3 => writer
+                        .write_all(b"{\"success\": true}\n{\"success\": true}\n")
+                        .unwrap(),
+                    _ => writer.write_all(b"{\"success\": true}\n").unwrap(),
+                }
+            }
+            commands
+        });
+        // This is safe because the fd comes from a stream we own.
+        let connection = unsafe { File::from_raw_fd(host_end.into_raw_fd()) };
+        agent.connect(connection).unwrap();
+
+        let timeout = Some(Duration::from_secs(5));
+        agent.send(&AgentCommand::FreezeFs, 1, timeout).unwrap();
+        match wait_outcomes(&mut agent)[0] {
+            (1, Ok(ref response)) => assert!(response.success),
+            _ => panic!("The agent should have frozen the filesystems"),
+        }
+        agent.send(&AgentCommand::ThawFs, 2, timeout).unwrap();
+        match wait_outcomes(&mut agent)[0] {
+            (2, Ok(ref response)) => assert!(response.success),
+            _ => panic!("The agent should have thawed the filesystems"),
+        }
+
+        // The freeze times out, so the filesystems are thawed.
+        let timeout = Some(Duration::from_millis(100));
+        agent.send(&AgentCommand::FreezeFs, 3, timeout).unwrap();
+        match wait_outcomes(&mut agent)[0] {
+            (3, Err(Error::Timeout)) => (),
+            _ => panic!("The freeze should time out"),
+        }
+        // The late answers to the freeze and to the thaw are dropped.
+        assert!(wait_outcomes(&mut agent).is_empty());
+        assert!(agent.pending.is_empty());
+
+        assert_eq!(
+            mock_agent.join().unwrap(),
+            vec![
+                r#"{"command":"freeze_fs"}"#,
+                r#"{"command":"thaw_fs"}"#,
+                r#"{"command":"freeze_fs"}"#,
+                r#"{"command":"thaw_fs"}"#,
+            ]
+        );
+        assert!(agent.is_connected());
+    }
+
+    #[test]
+    fn test_default_timeout() {
+        let mut agent = GuestAgent::new(3, None).unwrap();
+        agent.default_timeout = Duration::from_millis(100);
+        // The mock agent never answers.
+        let (host_end, _guest_end) = UnixStream::pair().unwrap();
+        // This is safe because the fd comes from a stream we own.
+        let connection = unsafe { File::from_raw_fd(host_end.into_raw_fd()) };
+        agent.connect(connection).unwrap();
+
+        agent.send(&AgentCommand::Flush, 1, None).unwrap();
+        match wait_outcomes(&mut agent)[0] {
+            (1, Err(Error::Timeout)) => (),
+            _ => panic!("The command should time out"),
+        }
+        // The agent may still answer, so the connection is kept.
+        assert!(agent.is_connected());
+    }
+
+    #[test]
+    fn test_invalid_response() {
+        let mut agent = GuestAgent::new(3, None).unwrap();
+        let (host_end, mut guest_end) = UnixStream::pair().unwrap();
+        // This is safe because the fd comes from a stream we own.
+        let connection = unsafe { File::from_raw_fd(host_end.into_raw_fd()) };
+        agent.connect(connection).unwrap();
+
+        agent.send(&AgentCommand::Flush, 1, None).unwrap();
+        guest_end.write_all(b"done\n").unwrap();
+        match wait_outcomes(&mut agent)[0] {
+            (1, Err(Error::InvalidResponse(_))) => (),
+            _ => panic!("The response should be invalid"),
+        }
+        // An invalid response doesn't drop the connection.
+        assert!(agent.is_connected());
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(
+            Error::NoChannel(String::from("vsock0")).to_string(),
+            "The vsock device vsock0 of the running microVM has no guest agent port."
+        );
+        assert_eq!(
+            Error::ConnectionLost.to_string(),
+            "The connection to the guest agent was lost."
+        );
+        assert_eq!(
+            Error::Timeout.to_string(),
+            "The guest agent didn't answer in time."
+        );
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Monitor which detects a microVM nobody uses anymore.
+//!
+//! The microVM is idle when its vCPUs didn't exit to the VMM, its devices didn't handle any
+//! event, e.g. a queue notification of the guest, and the API didn't serve any request for a
+//! whole check interval.
+
+use std::time::Duration;
+
+use event_counter::EventCounter;
+
+/// Checks periodically whether the microVM is idle.
+pub struct IdleMonitor {
+    timeout: Duration,
+    vcpu_activity: EventCounter,
+    device_activity: EventCounter,
+    api_activity: EventCounter,
+    last_counts: (usize, usize, usize),
+}
+
+impl IdleMonitor {
+    /// Creates a monitor which considers the microVM idle once neither the guest nor the API
+    /// clients were active for a whole `timeout` interval.
+    pub fn new(timeout: Duration) -> Self {
+        IdleMonitor {
+            timeout,
+            vcpu_activity: EventCounter::default(),
+            device_activity: EventCounter::default(),
+            api_activity: EventCounter::default(),
+            last_counts: (0, 0, 0),
+        }
+    }
+
+    /// Returns the interval after which an inactive microVM is idle.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns a handle for recording the vCPU exits.
+    pub fn vcpu_activity(&self) -> EventCounter {
+        self.vcpu_activity.clone()
+    }
+
+    /// Returns a handle for recording the events handled by the devices. The guest notifies the
+    /// virtio devices through ioeventfds, without its vCPUs exiting to the VMM.
+    pub fn device_activity(&self) -> EventCounter {
+        self.device_activity.clone()
+    }
+
+    /// Returns a handle for recording the API requests.
+    pub fn api_activity(&self) -> EventCounter {
+        self.api_activity.clone()
+    }
+
+    /// Returns whether neither the guest nor the API clients were active since the previous
+    /// check.
+    pub fn check(&mut self) -> bool {
+        let counts = (
+            self.vcpu_activity.count(),
+            self.device_activity.count(),
+            self.api_activity.count(),
+        );
+        let idle = counts == self.last_counts;
+        self.last_counts = counts;
+        idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_monitor() {
+        let mut monitor = IdleMonitor::new(Duration::from_millis(100));
+        assert_eq!(monitor.timeout(), Duration::from_millis(100));
+
+        // Nothing happened yet.
+        assert!(monitor.check());
+
+        let vcpu_activity = monitor.vcpu_activity();
+        let api_activity = monitor.api_activity();
+        vcpu_activity.record();
+        assert!(!monitor.check());
+        // The previous activity doesn't count for the next check.
+        assert!(monitor.check());
+
+        // The API activity alone keeps the microVM busy.
+        api_activity.record();
+        assert!(!monitor.check());
+        assert!(monitor.check());
+
+        // So does the device activity alone.
+        monitor.device_activity().record();
+        assert!(!monitor.check());
+        assert!(monitor.check());
+
+        vcpu_activity.record();
+        api_activity.record();
+        assert!(!monitor.check());
+        assert!(monitor.check());
+    }
+}
@@ -14,6 +14,8 @@ extern crate epoll;
 extern crate futures;
 extern crate kvm_bindings;
 extern crate kvm_ioctls;
+#[macro_use]
+extern crate lazy_static;
 extern crate libc;
 extern crate serde;
 #[macro_use]
@@ -24,6 +26,8 @@ extern crate timerfd;
 
 extern crate arch;
 #[cfg(target_arch = "x86_64")]
+extern crate backtrace;
+#[cfg(target_arch = "x86_64")]
 extern crate cpuid;
 extern crate devices;
 extern crate fc_util;
@@ -31,28 +35,57 @@ extern crate kernel;
 #[macro_use]
 extern crate logger;
 extern crate memory_model;
+extern crate mmds;
 extern crate net_util;
 extern crate rate_limiter;
 extern crate seccomp;
+#[macro_use]
 extern crate sys_util;
 
 /// Syscalls allowed through the seccomp filter.
 pub mod default_syscalls;
 mod device_manager;
+/// Counting the events recorded by other threads.
+pub mod event_counter;
+#[cfg(feature = "vsock")]
+/// Receiving the files sent by the guest, over vsock.
+pub mod file_transfer;
+#[cfg(feature = "vsock")]
+/// Control channel to an agent running in the guest, over vsock.
+pub mod guest_agent;
+/// Detecting a microVM nobody uses anymore.
+pub mod idle_monitor;
+/// Placing the microVM on host NUMA nodes and CPUs.
+pub mod numa;
+mod sched;
+#[cfg(target_arch = "x86_64")]
+mod sev;
 /// Signal handling utilities.
 pub mod signal_handler;
+/// Saving the state of a paused microVM to a snapshot file and reading it back. Experimental.
+pub mod snapshot;
 /// Wrappers over structures used to configure the VMM.
 pub mod vmm_config;
 mod vstate;
+mod watchdog;
 
 use futures::sync::oneshot;
+#[cfg(target_arch = "aarch64")]
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::fs::{metadata, File, OpenOptions};
 use std::io;
+#[cfg(target_arch = "x86_64")]
+use std::io::{BufReader, BufWriter};
+use std::num::NonZeroU16;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
 use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(feature = "virtio-fs")]
+use std::path::Path;
 use std::path::PathBuf;
 use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::sync::{Arc, Barrier, RwLock};
 use std::thread;
@@ -68,29 +101,47 @@ use device_manager::mmio::MMIODeviceInfo;
 use device_manager::mmio::MMIODeviceManager;
 use devices::legacy::I8042DeviceError;
 use devices::virtio;
-use devices::{DeviceEventT, EpollHandler, EpollHandlerPayload};
+use devices::{DeviceEventT, DeviceHealth, EpollHandler, EpollHandlerPayload};
 use fc_util::now_cputime_us;
+#[cfg(feature = "vsock")]
+use file_transfer::{FileReceiver, Limits};
+#[cfg(feature = "vsock")]
+use guest_agent::{AgentCommand, AgentResponse, GuestAgent};
+use idle_monitor::IdleMonitor;
 use kernel::cmdline as kernel_cmdline;
 use kernel::loader as kernel_loader;
 use logger::error::LoggerError;
+use logger::metrics::{BootMetrics, BootMilestone};
 use logger::{AppInfo, Level, LogOption, Metric, LOGGER, METRICS};
 use memory_model::{GuestAddress, GuestMemory};
 use net_util::TapError;
 #[cfg(target_arch = "aarch64")]
 use serde_json::Value;
+use snapshot::Snapshot;
+#[cfg(target_arch = "x86_64")]
+use snapshot::{SectionKind, SnapshotWriter};
 use sys_util::{EventFd, Terminal};
-use vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
+use vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError, ConsoleDevice};
+use vmm_config::device::{DeviceInfo, DeviceType};
 use vmm_config::drive::{BlockDeviceConfig, BlockDeviceConfigs, DriveError};
-use vmm_config::instance_info::{InstanceInfo, InstanceState, StartMicrovmError};
+use vmm_config::instance_info::{InstanceInfo, InstanceState, StartMicrovmError, StopReason};
 use vmm_config::logger::{LoggerConfig, LoggerConfigError, LoggerLevel};
-use vmm_config::machine_config::{VmConfig, VmConfigError};
+use vmm_config::machine_config::{VcpuSchedPolicy, VmConfig, VmConfigError};
 use vmm_config::net::{
     NetworkInterfaceConfig, NetworkInterfaceConfigs, NetworkInterfaceError,
     NetworkInterfaceUpdateConfig,
 };
+use vmm_config::snapshot::SnapshotConfig;
+#[cfg(feature = "vhost-user-block")]
+use vmm_config::vhost_user_block::{
+    VhostUserBlockDeviceConfig, VhostUserBlockDeviceConfigs, VhostUserBlockError,
+};
+#[cfg(feature = "virtio-fs")]
+use vmm_config::virtio_fs::{VirtioFsDeviceConfig, VirtioFsDeviceConfigs, VirtioFsError};
 #[cfg(feature = "vsock")]
 use vmm_config::vsock::{VsockDeviceConfig, VsockDeviceConfigs, VsockError};
-use vstate::{Vcpu, Vm};
+use vstate::{Vcpu, VcpuHandle, Vm};
+use watchdog::Watchdog;
 
 /// Default guest kernel command line:
 /// - `reboot=k` shut down the guest on reboot, instead of well... rebooting;
@@ -104,7 +155,15 @@ use vstate::{Vcpu, Vm};
 /// - `i8042.dumbkbd` do not attempt to control kbd state via the i8042 (save boot time).
 const DEFAULT_KERNEL_CMDLINE: &str = "reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0 \
                                       i8042.noaux i8042.nomux i8042.nopnp i8042.dumbkbd";
+/// Keys of the boot arguments which Firecracker sets itself for the attached devices, and which
+/// therefore can't be appended through the `AppendBootArgs` action.
+const RESERVED_BOOT_ARGS: [&str; 5] = ["earlycon", "ro", "root", "rw", "virtio_mmio.device"];
 const WRITE_METRICS_PERIOD_SECONDS: u64 = 60;
+/// Size in bytes of the random seed passed to the guest kernel.
+const RNG_SEED_SIZE: usize = 32;
+/// How long the guest agent has for freezing or thawing the guest filesystems.
+#[cfg(feature = "vsock")]
+const GUEST_AGENT_FS_TIMEOUT_SECONDS: u64 = 10;
 
 /// Success exit code.
 pub const FC_EXIT_CODE_OK: u8 = 0;
@@ -118,6 +177,24 @@ pub const FC_EXIT_CODE_BAD_SYSCALL: u8 = 148;
 pub const FC_EXIT_CODE_SIGBUS: u8 = 149;
 /// Firecracker was shut down after intercepting `SIGSEGV`.
 pub const FC_EXIT_CODE_SIGSEGV: u8 = 150;
+/// Firecracker cannot create the API socket because of its path.
+pub const FC_EXIT_CODE_BAD_API_SOCK_PATH: u8 = 151;
+/// Firecracker cannot bind the API socket because the address is already in use.
+pub const FC_EXIT_CODE_API_SOCK_IN_USE: u8 = 152;
+/// Firecracker cannot bind the API socket because it is not allowed to.
+pub const FC_EXIT_CODE_API_SOCK_PERMISSION_DENIED: u8 = 153;
+/// Firecracker cannot install the seccomp filters.
+pub const FC_EXIT_CODE_SECCOMP_FAILURE: u8 = 154;
+/// Firecracker cannot load the guest kernel while configuring the microVM at startup.
+pub const FC_EXIT_CODE_KERNEL_LOAD_FAILURE: u8 = 155;
+/// Firecracker was shut down by the watchdog because the guest stopped making progress.
+pub const FC_EXIT_CODE_WATCHDOG: u8 = 156;
+/// Firecracker cannot set the strict scheduling policy of the vCPU threads.
+pub const FC_EXIT_CODE_VCPU_SCHEDULING: u8 = 157;
+/// Firecracker was shut down because neither the guest nor the API clients were active.
+pub const FC_EXIT_CODE_IDLE: u8 = 158;
+/// Firecracker was shut down because the guest panicked.
+pub const FC_EXIT_CODE_GUEST_PANIC: u8 = 159;
 
 /// Errors associated with the VMM internal logic. These errors cannot be generated by direct user
 /// input, but can result from bad configuration of the host (for example if Firecracker doesn't
@@ -125,6 +202,8 @@ pub const FC_EXIT_CODE_SIGSEGV: u8 = 150;
 pub enum Error {
     /// Cannot receive message from the API.
     ApiChannel,
+    /// The API server stopped, so no more requests can be received.
+    ApiChannelClosed,
     /// Legacy devices work with Event file descriptors and the creation can fail because
     /// of resource exhaustion.
     CreateLegacyDevice(device_manager::legacy::Error),
@@ -158,6 +237,7 @@ impl std::fmt::Debug for Error {
 
         match self {
             ApiChannel => write!(f, "ApiChannel: error receiving data from the API server"),
+            ApiChannelClosed => write!(f, "ApiChannelClosed: the API server stopped"),
             CreateLegacyDevice(e) => write!(f, "Error creating legacy device: {:?}", e),
             EpollFd(e) => write!(f, "Epoll fd error: {}", e.to_string()),
             EventFd(e) => write!(f, "Event fd error: {}", e.to_string()),
@@ -184,6 +264,8 @@ pub enum ErrorKind {
     /// Internal Errors are unrelated to the user and usually refer to logical errors
     /// or bad management of resources (memory, file descriptors & others).
     Internal,
+    /// NotFound Errors describe requests targeting a resource which doesn't exist.
+    NotFound,
 }
 
 impl PartialEq for ErrorKind {
@@ -191,6 +273,7 @@ impl PartialEq for ErrorKind {
         match (self, other) {
             (&ErrorKind::User, &ErrorKind::User) => true,
             (&ErrorKind::Internal, &ErrorKind::Internal) => true,
+            (&ErrorKind::NotFound, &ErrorKind::NotFound) => true,
             _ => false,
         }
     }
@@ -209,11 +292,12 @@ pub enum VmmActionError {
     /// The action `ConfigureLogger` failed either because of bad user input (`ErrorKind::User`) or
     /// an internal error (`ErrorKind::Internal`).
     Logger(ErrorKind, LoggerConfigError),
-    /// One of the actions `GetVmConfiguration` or `SetVmConfiguration` failed either because of bad
-    /// input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    /// One of the actions `GetVmConfiguration` or `SetVmConfiguration` failed either because of
+    /// bad input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
     MachineConfig(ErrorKind, VmConfigError),
-    /// The action `InsertNetworkDevice` failed either because of bad user input (`ErrorKind::User`)
-    /// or an internal error (`ErrorKind::Internal`).
+    /// One of the actions `InsertNetworkDevice`, `UpdateNetworkInterface` or
+    /// `DetachNetworkDevice` failed either because of bad user input (`ErrorKind::User`), an
+    /// unknown interface (`ErrorKind::NotFound`) or an internal error (`ErrorKind::Internal`).
     NetworkConfig(ErrorKind, NetworkInterfaceError),
     /// The action `StartMicroVm` failed either because of bad user input (`ErrorKind::User`) or
     /// an internal error (`ErrorKind::Internal`).
@@ -221,10 +305,29 @@ pub enum VmmActionError {
     /// The action `SendCtrlAltDel` failed. Details are provided by the device-specific error
     /// `I8042DeviceError`.
     SendCtrlAltDel(ErrorKind, I8042DeviceError),
+    /// The action `SendReset` failed. Details are provided by the device-specific error
+    /// `I8042DeviceError`.
+    SendReset(ErrorKind, I8042DeviceError),
+    /// The action `CreateSnapshot` or `LoadSnapshot` failed either because of bad user input
+    /// (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    Snapshot(ErrorKind, snapshot::Error),
+    #[cfg(feature = "vhost-user-block")]
+    /// The action `InsertVhostUserBlockDevice` failed either because of bad user input
+    /// (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    VhostUserBlockConfig(ErrorKind, VhostUserBlockError),
+    #[cfg(feature = "virtio-fs")]
+    /// The action `InsertVirtioFsDevice` failed either because of bad user input
+    /// (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    VirtioFsConfig(ErrorKind, VirtioFsError),
     #[cfg(feature = "vsock")]
     /// The action `insert_vsock_device` failed either because of bad user input (`ErrorKind::User`)
     /// or an internal error (`ErrorKind::Internal`).
     VsockConfig(ErrorKind, VsockError),
+    #[cfg(feature = "vsock")]
+    /// One of the actions `SendAgentCommand`, `FreezeFilesystems` or `ThawFilesystems` failed
+    /// either because of bad user input (`ErrorKind::User`) or because the guest agent couldn't
+    /// run the command (`ErrorKind::Internal`).
+    GuestAgent(ErrorKind, guest_agent::Error),
 }
 
 // It's convenient to turn DriveErrors into VmmActionErrors directly.
@@ -235,11 +338,20 @@ impl std::convert::From<DriveError> for VmmActionError {
             DriveError::CannotOpenBlockDevice
             | DriveError::InvalidBlockDeviceID
             | DriveError::InvalidBlockDevicePath
+            | DriveError::InvalidSerial
+            | DriveError::InvalidRamdisk
+            | DriveError::InvalidRamdiskSize(_)
+            | DriveError::InvalidIoRetry
+            | DriveError::InvalidVirtioFeatureMask
+            | DriveError::InvalidMaxInFlightRequests
             | DriveError::BlockDevicePathAlreadyExists
             | DriveError::BlockDeviceUpdateFailed
             | DriveError::OperationNotAllowedPreBoot
             | DriveError::UpdateNotAllowedPostBoot
-            | DriveError::RootBlockDeviceAlreadyAdded => ErrorKind::User,
+            | DriveError::RootBlockDeviceAlreadyAdded
+            | DriveError::NotABlockDevice => ErrorKind::User,
+            // Internal errors.
+            DriveError::BlockDeviceFlushFailed(_) => ErrorKind::Internal,
         };
         VmmActionError::DriveConfig(kind, e)
     }
@@ -252,8 +364,17 @@ impl std::convert::From<VmConfigError> for VmmActionError {
             match e {
                 // User errors.
                 VmConfigError::InvalidVcpuCount
+                | VmConfigError::InvalidCpuTopology
                 | VmConfigError::InvalidMemorySize
-                | VmConfigError::UpdateNotAllowedPostBoot => ErrorKind::User,
+                | VmConfigError::InvalidNumaNode
+                | VmConfigError::InvalidVcpuAffinityIndex(_)
+                | VmConfigError::IrqAffinityNotSupported
+                | VmConfigError::InvalidHostCpu(_)
+                | VmConfigError::InvalidVcpuScheduling
+                | VmConfigError::InvalidHighMmio
+                | VmConfigError::InvalidReservedMemory
+                | VmConfigError::UpdateNotAllowedPostBoot
+                | VmConfigError::FdtNotAvailable => ErrorKind::User,
             },
             e,
         )
@@ -268,9 +389,14 @@ impl std::convert::From<NetworkInterfaceError> for VmmActionError {
             NetworkInterfaceError::GuestMacAddressInUse(_)
             | NetworkInterfaceError::HostDeviceNameInUse(_)
             | NetworkInterfaceError::DeviceIdNotFound
+            | NetworkInterfaceError::InvalidRxCoalescing
+            | NetworkInterfaceError::InvalidRxBufferFrames
+            | NetworkInterfaceError::InvalidVirtioFeatureMask
+            | NetworkInterfaceError::InvalidMmdsMssClamp
             | NetworkInterfaceError::UpdateNotAllowedPostBoot => ErrorKind::User,
             // Internal errors.
-            NetworkInterfaceError::EpollHandlerNotFound(_)
+            NetworkInterfaceError::DetachFailed(_)
+            | NetworkInterfaceError::EpollHandlerNotFound(_)
             | NetworkInterfaceError::RateLimiterUpdateFailed(_) => ErrorKind::Internal,
             NetworkInterfaceError::OpenTap(ref te) => match te {
                 // User errors.
@@ -290,34 +416,69 @@ impl std::convert::From<StartMicrovmError> for VmmActionError {
     fn from(e: StartMicrovmError) -> Self {
         let kind = match e {
             // User errors.
+            #[cfg(feature = "vhost-user-block")]
+            StartMicrovmError::CreateVhostUserBlockDevice(_) => ErrorKind::User,
+            #[cfg(feature = "virtio-fs")]
+            StartMicrovmError::RegisterFsDevice(device_manager::mmio::Error::CreateFsDevice(_)) => {
+                ErrorKind::User
+            }
             #[cfg(feature = "vsock")]
             StartMicrovmError::CreateVsockDevice(_) => ErrorKind::User,
+            #[cfg(feature = "vsock")]
+            StartMicrovmError::FileTransfer(file_transfer::Error::Listen(_, _)) => ErrorKind::User,
+            #[cfg(feature = "vsock")]
+            StartMicrovmError::GuestAgent(guest_agent::Error::Listen(_, _)) => ErrorKind::User,
+            #[cfg(target_arch = "aarch64")]
+            StartMicrovmError::GicVersionNotSupported(_) => ErrorKind::User,
+            #[cfg(target_arch = "aarch64")]
+            StartMicrovmError::SerialConsoleDisabled => ErrorKind::User,
+            #[cfg(target_arch = "x86_64")]
+            StartMicrovmError::ClockSourceNotSupported(_) => ErrorKind::User,
+            #[cfg(target_arch = "x86_64")]
+            StartMicrovmError::Sev(sev::Error::NotSupported) => ErrorKind::User,
+            #[cfg(target_arch = "x86_64")]
+            StartMicrovmError::X2ApicNotSupported => ErrorKind::User,
             StartMicrovmError::CreateBlockDevice(_)
             | StartMicrovmError::CreateNetDevice(_)
             | StartMicrovmError::KernelCmdline(_)
+            | StartMicrovmError::KernelEntryNotMapped(_)
             | StartMicrovmError::KernelLoader(_)
             | StartMicrovmError::MicroVMAlreadyRunning
             | StartMicrovmError::MissingKernelConfig
+            | StartMicrovmError::MissingRootfs
             | StartMicrovmError::NetDeviceNotConfigured
             | StartMicrovmError::OpenBlockDevice(_)
             | StartMicrovmError::VcpusNotConfigured => ErrorKind::User,
             // Internal errors.
+            #[cfg(feature = "vhost-user-block")]
+            StartMicrovmError::RegisterVhostUserBlockDevice(_) => ErrorKind::Internal,
+            #[cfg(feature = "virtio-fs")]
+            StartMicrovmError::RegisterFsDevice(_) => ErrorKind::Internal,
             #[cfg(feature = "vsock")]
-            StartMicrovmError::RegisterVsockDevice(_) => ErrorKind::Internal,
+            StartMicrovmError::FileTransfer(_)
+            | StartMicrovmError::GuestAgent(_)
+            | StartMicrovmError::RegisterVsockDevice(_) => ErrorKind::Internal,
+            #[cfg(target_arch = "x86_64")]
+            StartMicrovmError::Sev(_) => ErrorKind::Internal,
             StartMicrovmError::ConfigureSystem(_)
             | StartMicrovmError::ConfigureVm(_)
             | StartMicrovmError::CreateRateLimiter(_)
+            | StartMicrovmError::CreateRxCoalescing(_)
             | StartMicrovmError::DeviceManager
             | StartMicrovmError::EventFd
             | StartMicrovmError::GuestMemory(_)
             | StartMicrovmError::LegacyIOBus(_)
+            | StartMicrovmError::NumaNodeCpus(_)
             | StartMicrovmError::RegisterBlockDevice(_)
+            | StartMicrovmError::RegisterConsoleDevice(_)
             | StartMicrovmError::RegisterEvent
             | StartMicrovmError::RegisterMMIODevice(_)
             | StartMicrovmError::RegisterNetDevice(_)
+            | StartMicrovmError::RngSeed(_)
             | StartMicrovmError::SeccompFilters(_)
             | StartMicrovmError::Vcpu(_)
             | StartMicrovmError::VcpuConfigure(_)
+            | StartMicrovmError::VcpuScheduling(..)
             | StartMicrovmError::VcpuSpawn(_) => ErrorKind::Internal,
             // The only user `LoadCommandline` error is `CommandLineOverflow`.
             StartMicrovmError::LoadCommandline(ref cle) => match cle {
@@ -342,8 +503,34 @@ impl VmmActionError {
             NetworkConfig(ref kind, _) => kind,
             StartMicrovm(ref kind, _) => kind,
             SendCtrlAltDel(ref kind, _) => kind,
+            SendReset(ref kind, _) => kind,
+            Snapshot(ref kind, _) => kind,
+            #[cfg(feature = "vhost-user-block")]
+            VhostUserBlockConfig(ref kind, _) => kind,
+            #[cfg(feature = "virtio-fs")]
+            VirtioFsConfig(ref kind, _) => kind,
             #[cfg(feature = "vsock")]
             VsockConfig(ref kind, _) => kind,
+            #[cfg(feature = "vsock")]
+            GuestAgent(ref kind, _) => kind,
+        }
+    }
+
+    /// Returns the code Firecracker exits with when the error is fatal.
+    pub fn exit_code(&self) -> u8 {
+        match *self {
+            VmmActionError::BootSource(_, BootSourceConfigError::InvalidKernelPath)
+            | VmmActionError::StartMicrovm(_, StartMicrovmError::KernelLoader(_))
+            | VmmActionError::StartMicrovm(_, StartMicrovmError::KernelEntryNotMapped(_)) => {
+                FC_EXIT_CODE_KERNEL_LOAD_FAILURE
+            }
+            VmmActionError::StartMicrovm(_, StartMicrovmError::SeccompFilters(_)) => {
+                FC_EXIT_CODE_SECCOMP_FAILURE
+            }
+            VmmActionError::StartMicrovm(_, StartMicrovmError::VcpuScheduling(..)) => {
+                FC_EXIT_CODE_VCPU_SCHEDULING
+            }
+            _ => FC_EXIT_CODE_GENERIC_ERROR,
         }
     }
 }
@@ -360,8 +547,16 @@ impl Display for VmmActionError {
             NetworkConfig(_, ref err) => write!(f, "{}", err.to_string()),
             StartMicrovm(_, ref err) => write!(f, "{}", err.to_string()),
             SendCtrlAltDel(_, ref err) => write!(f, "{}", err.to_string()),
+            SendReset(_, ref err) => write!(f, "{}", err.to_string()),
+            Snapshot(_, ref err) => write!(f, "{}", err.to_string()),
+            #[cfg(feature = "vhost-user-block")]
+            VhostUserBlockConfig(_, ref err) => write!(f, "{}", err.to_string()),
+            #[cfg(feature = "virtio-fs")]
+            VirtioFsConfig(_, ref err) => write!(f, "{}", err.to_string()),
             #[cfg(feature = "vsock")]
             VsockConfig(_, ref err) => write!(f, "{}", err.to_string()),
+            #[cfg(feature = "vsock")]
+            GuestAgent(_, ref err) => write!(f, "{}", err.to_string()),
         }
     }
 }
@@ -371,6 +566,10 @@ impl Display for VmmActionError {
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum VmmAction {
+    /// Append the boot arguments given as a string to the kernel command line. This action can
+    /// only be called after the boot source is configured and before the microVM has booted. The
+    /// response is sent using the `OutcomeSender`.
+    AppendBootArgs(String, OutcomeSender),
     /// Configure the boot source of the microVM using as input the `ConfigureBootSource`. This
     /// action can only be called before the microVM has booted. The response is sent using the
     /// `OutcomeSender`.
@@ -378,11 +577,35 @@ pub enum VmmAction {
     /// Configure the logger using as input the `LoggerConfig`. This action can only be called
     /// before the microVM has booted. The response is sent using the `OutcomeSender`.
     ConfigureLogger(LoggerConfig, OutcomeSender),
+    /// Save the microVM in the snapshot file described by `SnapshotConfig`. This action can only
+    /// be called after the microVM is started. The vCPUs are paused while the snapshot is
+    /// written. The response is sent using the `OutcomeSender`.
+    CreateSnapshot(SnapshotConfig, OutcomeSender),
+    /// Remove the network interface with the ID associated with this enum variant. Before boot,
+    /// only its configuration is dropped. After boot, the device is detached from the running
+    /// microVM and its tap device is closed. The response is sent using the `OutcomeSender`.
+    DetachNetworkDevice(String, OutcomeSender),
     /// Get the configuration of the microVM. The action response is sent using the `OutcomeSender`.
     GetVmConfiguration(OutcomeSender),
+    #[cfg(target_arch = "aarch64")]
+    /// Get the flattened device tree passed to the guest. This action can only be called after
+    /// the microVM is started. The response is sent using the `OutcomeSender`.
+    GetFdt(OutcomeSender),
+    /// Get the devices attached to the microVM, together with their health status. The action
+    /// response is sent using the `OutcomeSender`.
+    GetDevices(OutcomeSender),
+    /// Persist on the host the data written to the backing files of all the block devices. This
+    /// action can only be called after the microVM is started. The response is sent using the
+    /// `OutcomeSender`, once all the backing files were synced.
+    FlushBlockDevices(OutcomeSender),
     /// Flush the metrics. This action can only be called after the logger has been configured.
     /// The response is sent using the `OutcomeSender`.
     FlushMetrics(OutcomeSender),
+    #[cfg(feature = "vsock")]
+    /// Freeze the guest filesystems through the guest agent listening on the vsock device
+    /// specified by an ID. If the agent doesn't acknowledge the freeze in time, the filesystems
+    /// are thawed. The response is sent using the `OutcomeSender`.
+    FreezeFilesystems(String, OutcomeSender),
     /// Add a new block device or update one that already exists using the `BlockDeviceConfig` as
     /// input. This action can only be called before the microVM has booted. The response
     /// is sent using the `OutcomeSender`.
@@ -391,11 +614,26 @@ pub enum VmmAction {
     /// `NetworkInterfaceConfig` as input. This action can only be called before the microVM has
     /// booted. The response is sent using the `OutcomeSender`.
     InsertNetworkDevice(NetworkInterfaceConfig, OutcomeSender),
+    #[cfg(feature = "vhost-user-block")]
+    /// Add a new vhost-user block device or update one that already exists using the
+    /// `VhostUserBlockDeviceConfig` as input. This action can only be called before the microVM
+    /// has booted. The response is sent using the `OutcomeSender`.
+    InsertVhostUserBlockDevice(VhostUserBlockDeviceConfig, OutcomeSender),
+    #[cfg(feature = "virtio-fs")]
+    /// Add a new virtio-fs device or update one that already exists using the
+    /// `VirtioFsDeviceConfig` as input. This action can only be called before the microVM has
+    /// booted. The response is sent using the `OutcomeSender`.
+    InsertVirtioFsDevice(VirtioFsDeviceConfig, OutcomeSender),
     #[cfg(feature = "vsock")]
     /// Add a new vsock device or update one that already exists using the
     /// `VsockDeviceConfig` as input. This action can only be called before the microVM has
     /// booted. The response is sent using the `OutcomeSender`.
     InsertVsockDevice(VsockDeviceConfig, OutcomeSender),
+    /// Restore the microVM from the snapshot file described by `SnapshotConfig` instead of
+    /// booting its kernel. This action can only be called before the microVM has booted, once it
+    /// is configured with the vCPUs, memory and devices of the saved microVM. The response is
+    /// sent using the `OutcomeSender`.
+    LoadSnapshot(SnapshotConfig, OutcomeSender),
     /// Update the size of an existing block device specified by an ID. The ID is the first data
     /// associated with this enum variant. This action can only be called after the microVM is
     /// started. The response is sent using the `OutcomeSender`.
@@ -410,6 +648,19 @@ pub enum VmmAction {
     /// Send CTRL+ALT+DEL to the microVM, using the i8042 keyboard function. If an AT-keyboard
     /// driver is listening on the guest end, this can be used to shut down the microVM gracefully.
     SendCtrlAltDel(OutcomeSender),
+    /// Assert the CPU reset line of the i8042 controller, as a guest writing the reset command
+    /// would. This stops the microVM and exits Firecracker with a success code. This action can
+    /// only be called after the microVM is started.
+    SendReset(OutcomeSender),
+    #[cfg(feature = "vsock")]
+    /// Send the `AgentCommand` to the guest agent listening on the vsock device specified by an
+    /// ID. This action can only be called after the microVM is started. The response is sent
+    /// using the `OutcomeSender`, once the guest agent answered.
+    SendAgentCommand(String, AgentCommand, OutcomeSender),
+    #[cfg(feature = "vsock")]
+    /// Thaw the guest filesystems frozen by `FreezeFilesystems`. The response is sent using the
+    /// `OutcomeSender`.
+    ThawFilesystems(String, OutcomeSender),
     /// Update the path of an existing block device. The data associated with this variant
     /// represents the `drive_id` and the `path_on_host`. The response is sent using
     /// the `OutcomeSender`.
@@ -427,6 +678,14 @@ pub enum VmmData {
     Empty,
     /// The microVM configuration represented by `VmConfig`.
     MachineConfiguration(VmConfig),
+    /// The devices attached to the microVM and their health status.
+    Devices(Vec<DeviceInfo>),
+    #[cfg(target_arch = "aarch64")]
+    /// The flattened device tree blob passed to the guest.
+    Fdt(Vec<u8>),
+    #[cfg(feature = "vsock")]
+    /// The answer of the guest agent to a command.
+    AgentResponse(AgentResponse),
 }
 
 /// Data type used to communicate between the API and the VMM.
@@ -453,6 +712,80 @@ fn get_time_us() -> u64 {
     (chrono::Utc::now().timestamp_nanos() / 1000) as u64
 }
 
+/// Logs a backtrace of each thread registered through `register_stack_dump_thread`, i.e. the API
+/// server, VMM and vCPU threads. Used for debugging a live VMM on `SIGUSR2`.
+fn log_stack_dump() {
+    warn!("Dumping the thread stacks after intercepting SIGUSR2.");
+    #[cfg(target_arch = "x86_64")]
+    for thread in signal_handler::stack_dump_threads() {
+        match signal_handler::capture_thread_stack(&thread) {
+            Some(frames) => warn!(
+                "Stack of the {} thread ({}):\n{}",
+                thread.name,
+                thread.tid,
+                format_stack_frames(&frames)
+            ),
+            None => warn!(
+                "The {} thread ({}) didn't capture its stack.",
+                thread.name, thread.tid
+            ),
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    warn!("Stack dumps are not supported on this architecture.");
+}
+
+/// Resolves the symbols of the instruction pointers in `frames`, one line per frame, plus one
+/// for the source location when it is known.
+#[cfg(target_arch = "x86_64")]
+fn format_stack_frames(frames: &[usize]) -> String {
+    let mut lines = Vec::with_capacity(frames.len());
+    for (index, ip) in frames.iter().enumerate() {
+        let mut name = None;
+        let mut location = None;
+        backtrace::resolve(*ip as *mut libc::c_void, |symbol| {
+            if name.is_none() {
+                name = symbol.name().map(|name| name.to_string());
+                location = match (symbol.filename(), symbol.lineno()) {
+                    (Some(file), Some(line)) => Some(format!("{}:{}", file.display(), line)),
+                    _ => None,
+                };
+            }
+        });
+        lines.push(format!(
+            "{:4}: {:#x} - {}",
+            index,
+            ip,
+            name.unwrap_or_else(|| "<unknown>".to_string())
+        ));
+        if let Some(location) = location {
+            lines.push(format!("             at {}", location));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Returns whether the kernel parameters of `cmdline`, i.e. those before `--`, set the root
+/// filesystem.
+fn has_root_boot_arg(cmdline: &str) -> bool {
+    cmdline
+        .split_whitespace()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg.starts_with("root="))
+}
+
+/// Checks that the kernel entry point lands in a mapped guest memory region, so that a
+/// misconfigured memory layout is reported before the vCPUs start executing.
+fn validate_kernel_entry(
+    guest_memory: &GuestMemory,
+    entry_addr: GuestAddress,
+) -> std::result::Result<(), StartMicrovmError> {
+    if !guest_memory.address_in_range(entry_addr) {
+        return Err(StartMicrovmError::KernelEntryNotMapped(entry_addr));
+    }
+    Ok(())
+}
+
 /// Describes a KVM context that gets attached to the micro vm instance.
 /// It gives access to the functionality of the KVM wrapper as long as every required
 /// KVM capability is present on the host.
@@ -504,9 +837,18 @@ impl KvmContext {
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum EpollDispatch {
     Exit,
+    I8042Reset,
     Stdin,
     DeviceHandler(usize, DeviceEventT),
+    #[cfg(feature = "vsock")]
+    FileReceiver(usize),
+    #[cfg(feature = "vsock")]
+    GuestAgent(usize),
+    IdleMonitor,
+    StackDump,
+    Terminate,
     VmmActionRequest,
+    Watchdog,
     WriteMetrics,
 }
 
@@ -656,6 +998,26 @@ impl EpollContext {
         )
     }
 
+    fn allocate_virtio_console_tokens(&mut self) -> virtio::console::EpollConfig {
+        let (dispatch_base, sender) = self.allocate_tokens(virtio::console::CONSOLE_EVENTS_COUNT);
+        virtio::console::EpollConfig::new(dispatch_base, self.epoll_raw_fd, sender)
+    }
+
+    #[cfg(feature = "vhost-user-block")]
+    fn allocate_virtio_vhost_user_block_tokens(
+        &mut self,
+    ) -> virtio::vhost::user_block::EpollConfig {
+        let (dispatch_base, sender) =
+            self.allocate_tokens(virtio::vhost::user_block::VHOST_USER_BLOCK_EVENTS_COUNT);
+        virtio::vhost::user_block::EpollConfig::new(dispatch_base, self.epoll_raw_fd, sender)
+    }
+
+    #[cfg(feature = "virtio-fs")]
+    fn allocate_virtio_fs_tokens(&mut self) -> virtio::fs::EpollConfig {
+        let (dispatch_base, sender) = self.allocate_tokens(virtio::fs::FS_EVENTS_COUNT);
+        virtio::fs::EpollConfig::new(dispatch_base, self.epoll_raw_fd, sender)
+    }
+
     #[cfg(feature = "vsock")]
     fn allocate_virtio_vsock_tokens(&mut self) -> virtio::vhost::handle::VhostEpollConfig {
         let (dispatch_base, sender) =
@@ -681,6 +1043,30 @@ impl EpollContext {
             }
         }
     }
+
+    // Drops the epoll handler of a detached device. Closing the file descriptors it owns takes
+    // them out of epoll, while the tokens of the device are not handed out again.
+    fn remove_device_handler(&mut self, device_idx: usize) {
+        for dispatch in self.dispatch_table.iter_mut() {
+            if let Some(EpollDispatch::DeviceHandler(idx, _)) = *dispatch {
+                if idx == device_idx {
+                    *dispatch = None;
+                }
+            }
+        }
+        let maybe = &mut self.device_handlers[device_idx];
+        maybe.handler = None;
+        // The device may have been activated without any event being handled since.
+        while maybe.receiver.try_recv().is_ok() {}
+    }
+
+    fn get_device_health(&mut self, device_idx: usize) -> DeviceHealth {
+        // A device without a handler hasn't been activated by the guest yet, so it couldn't
+        // have failed.
+        self.get_device_handler(device_idx)
+            .map(|handler| handler.health())
+            .unwrap_or(DeviceHealth::Healthy)
+    }
 }
 
 impl Drop for EpollContext {
@@ -697,6 +1083,9 @@ struct KernelConfig {
     kernel_file: File,
     #[cfg(target_arch = "x86_64")]
     cmdline_addr: GuestAddress,
+    rng_seed: bool,
+    console: Option<ConsoleDevice>,
+    diskless: bool,
 }
 
 struct Vmm {
@@ -708,9 +1097,21 @@ struct Vmm {
     // Guest VM core resources.
     guest_memory: Option<GuestMemory>,
     kernel_config: Option<KernelConfig>,
-    vcpus_handles: Vec<thread::JoinHandle<()>>,
+    // The handles of the vCPU threads, in the order of the vCPUs.
+    vcpus_handles: Vec<VcpuHandle>,
+    // Pausing one vCPU pauses them all.
+    vcpus_paused: Arc<AtomicBool>,
+    // Signaled by the vCPUs once they stop, after they recorded why.
     exit_evt: Option<EpollEvent<EventFd>>,
+    // Signaled by the i8042 controller when the guest resets through it.
+    i8042_reset_evt: Option<EpollEvent<EventFd>>,
     vm: Vm,
+    // Drives the launch of the guest when its memory is encrypted.
+    #[cfg(target_arch = "x86_64")]
+    sev: Option<sev::Sev>,
+    // The flattened device tree passed to the guest, once the system is configured.
+    #[cfg(target_arch = "aarch64")]
+    fdt: Option<Vec<u8>>,
 
     // Guest VM devices.
     mmio_device_manager: Option<MMIODeviceManager>,
@@ -723,8 +1124,18 @@ struct Vmm {
     // This is necessary because we want the root to always be mounted on /dev/vda.
     block_device_configs: BlockDeviceConfigs,
     network_interface_configs: NetworkInterfaceConfigs,
+    #[cfg(feature = "vhost-user-block")]
+    vhost_user_block_device_configs: VhostUserBlockDeviceConfigs,
+    #[cfg(feature = "virtio-fs")]
+    virtio_fs_device_configs: VirtioFsDeviceConfigs,
     #[cfg(feature = "vsock")]
     vsock_device_configs: VsockDeviceConfigs,
+    // The channels to the guest agents, with the IDs of their vsock devices.
+    #[cfg(feature = "vsock")]
+    guest_agents: Vec<(String, EpollEvent<GuestAgent<OutcomeSender>>)>,
+    // The channels receiving the files sent by the guest.
+    #[cfg(feature = "vsock")]
+    file_receivers: Vec<EpollEvent<FileReceiver>>,
 
     epoll_context: EpollContext,
 
@@ -733,6 +1144,18 @@ struct Vmm {
     from_api: Receiver<Box<VmmAction>>,
 
     write_metrics_event: EpollEvent<TimerFd>,
+    // Signaled by the `SIGUSR2` handler to request a stack dump of the VMM thread.
+    stack_dump_event: EpollEvent<EventFd>,
+    // Signaled by the `SIGTERM` handler to stop the microVM.
+    terminate_event: EpollEvent<EventFd>,
+    // Checks that the guest is alive, if enabled.
+    watchdog: Option<Watchdog>,
+    watchdog_event: EpollEvent<TimerFd>,
+    // Checks whether the microVM is idle, if enabled.
+    idle_monitor: Option<IdleMonitor>,
+    idle_monitor_event: EpollEvent<TimerFd>,
+    // Where the boot milestones are recorded.
+    boot_metrics: &'static BootMetrics,
 
     // The level of seccomp filtering used. Seccomp filters are loaded before executing guest code.
     seccomp_level: u32,
@@ -759,6 +1182,36 @@ impl Vmm {
             )
             .expect("Cannot add write metrics TimerFd to epoll.");
 
+        let stack_dump_event = epoll_context
+            .add_event(
+                EventFd::new().map_err(Error::EventFd)?,
+                EpollDispatch::StackDump,
+            )
+            .expect("Cannot add stack dump eventfd to epoll.");
+
+        let terminate_event = epoll_context
+            .add_event(
+                EventFd::new().map_err(Error::EventFd)?,
+                EpollDispatch::Terminate,
+            )
+            .expect("Cannot add terminate eventfd to epoll.");
+
+        let watchdog_event = epoll_context
+            .add_event(
+                // non-blocking & close on exec
+                TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::TimerFd)?,
+                EpollDispatch::Watchdog,
+            )
+            .expect("Cannot add watchdog TimerFd to epoll.");
+
+        let idle_monitor_event = epoll_context
+            .add_event(
+                // non-blocking & close on exec
+                TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::TimerFd)?,
+                EpollDispatch::IdleMonitor,
+            )
+            .expect("Cannot add idle monitor TimerFd to epoll.");
+
         let block_device_configs = BlockDeviceConfigs::new();
         let kvm = KvmContext::new()?;
         let vm = Vm::new(kvm.fd()).map_err(Error::Vm)?;
@@ -770,20 +1223,41 @@ impl Vmm {
             guest_memory: None,
             kernel_config: None,
             vcpus_handles: vec![],
+            vcpus_paused: Arc::new(AtomicBool::new(false)),
             exit_evt: None,
+            i8042_reset_evt: None,
             vm,
+            #[cfg(target_arch = "x86_64")]
+            sev: None,
+            #[cfg(target_arch = "aarch64")]
+            fdt: None,
             mmio_device_manager: None,
             legacy_device_manager: LegacyDeviceManager::new().map_err(Error::CreateLegacyDevice)?,
             block_device_configs,
             drive_handler_id_map: HashMap::new(),
             net_handler_id_map: HashMap::new(),
             network_interface_configs: NetworkInterfaceConfigs::new(),
+            #[cfg(feature = "vhost-user-block")]
+            vhost_user_block_device_configs: VhostUserBlockDeviceConfigs::new(),
+            #[cfg(feature = "virtio-fs")]
+            virtio_fs_device_configs: VirtioFsDeviceConfigs::new(),
             #[cfg(feature = "vsock")]
             vsock_device_configs: VsockDeviceConfigs::new(),
+            #[cfg(feature = "vsock")]
+            guest_agents: Vec::new(),
+            #[cfg(feature = "vsock")]
+            file_receivers: Vec::new(),
             epoll_context,
             api_event,
             from_api,
             write_metrics_event,
+            stack_dump_event,
+            terminate_event,
+            watchdog: None,
+            watchdog_event,
+            idle_monitor: None,
+            idle_monitor_event,
+            boot_metrics: &METRICS.boot,
             seccomp_level,
         })
     }
@@ -856,13 +1330,6 @@ impl Vmm {
         let device_manager = self.mmio_device_manager.as_mut().unwrap();
 
         for drive_config in self.block_device_configs.config_list.iter_mut() {
-            // Add the block device from file.
-            let block_file = OpenOptions::new()
-                .read(true)
-                .write(!drive_config.is_read_only)
-                .open(&drive_config.path_on_host)
-                .map_err(StartMicrovmError::OpenBlockDevice)?;
-
             if drive_config.is_root_device && drive_config.get_partuuid().is_some() {
                 kernel_config
                     .cmdline
@@ -897,15 +1364,48 @@ impl Vmm {
                 None => None,
             };
 
-            let block_box = Box::new(
-                devices::virtio::Block::new(
-                    block_file,
-                    drive_config.is_read_only,
+            let block = match drive_config.ramdisk_size() {
+                Some(size) => devices::virtio::Block::new_ramdisk(
+                    size,
+                    Some(drive_config.serial().clone()),
                     epoll_config,
                     rate_limiter,
-                )
-                .map_err(StartMicrovmError::CreateBlockDevice)?,
-            );
+                ),
+                None => {
+                    // Add the block device from file.
+                    let block_file = OpenOptions::new()
+                        .read(true)
+                        .write(!drive_config.is_read_only)
+                        .custom_flags(if drive_config.is_direct_io() {
+                            libc::O_DIRECT
+                        } else {
+                            0
+                        })
+                        .open(&drive_config.path_on_host)
+                        .map_err(StartMicrovmError::OpenBlockDevice)?;
+                    devices::virtio::Block::new(
+                        block_file,
+                        drive_config.is_read_only,
+                        drive_config.is_direct_io(),
+                        Some(drive_config.serial().clone()),
+                        epoll_config,
+                        rate_limiter,
+                    )
+                }
+            };
+            let mut block = block.map_err(StartMicrovmError::CreateBlockDevice)?;
+            if let Some(io_retry) = drive_config.io_retry {
+                block
+                    .set_io_retry_policy(io_retry.policy())
+                    .map_err(StartMicrovmError::CreateBlockDevice)?;
+            }
+            if let Some(max_in_flight) = drive_config.max_in_flight_requests {
+                block.set_max_in_flight_requests(max_in_flight);
+            }
+            let mut block_box: Box<devices::virtio::VirtioDevice> = Box::new(block);
+            if let Some(mask) = drive_config.virtio_feature_mask {
+                block_box = Box::new(devices::virtio::FeatureMask::new(block_box, mask));
+            }
             device_manager
                 .register_virtio_device(
                     self.vm.get_fd(),
@@ -950,19 +1450,35 @@ impl Vmm {
                 ),
                 None => None,
             };
+            let rx_coalescing = match cfg.rx_coalescing {
+                Some(coalescing) => Some(
+                    coalescing
+                        .into_rx_coalescing()
+                        .map_err(StartMicrovmError::CreateRxCoalescing)?,
+                ),
+                None => None,
+            };
 
             if let Some(tap) = cfg.take_tap() {
-                let net_box = Box::new(
+                let mut net_box: Box<devices::virtio::VirtioDevice> = Box::new(
                     devices::virtio::Net::new_with_tap(
                         tap,
                         cfg.guest_mac(),
                         epoll_config,
                         rx_rate_limiter,
                         tx_rate_limiter,
+                        rx_coalescing,
+                        cfg.rx_buffer_frames
+                            .map(|frames| devices::virtio::RxBuffer::new(frames as usize)),
                         allow_mmds_requests,
+                        cfg.mmds_mss_clamp.and_then(NonZeroU16::new),
+                        cfg.gso_passthrough,
                     )
                     .map_err(StartMicrovmError::CreateNetDevice)?,
                 );
+                if let Some(mask) = cfg.virtio_feature_mask {
+                    net_box = Box::new(devices::virtio::FeatureMask::new(net_box, mask));
+                }
 
                 device_manager
                     .register_virtio_device(
@@ -979,6 +1495,64 @@ impl Vmm {
         Ok(())
     }
 
+    #[cfg(feature = "vhost-user-block")]
+    fn attach_vhost_user_block_devices(&mut self) -> std::result::Result<(), StartMicrovmError> {
+        let kernel_config = self
+            .kernel_config
+            .as_mut()
+            .ok_or(StartMicrovmError::MissingKernelConfig)?;
+        // `unwrap` is suitable for this context since this should be called only after the
+        // device manager has been initialized.
+        let device_manager = self.mmio_device_manager.as_mut().unwrap();
+
+        for cfg in self.vhost_user_block_device_configs.iter() {
+            let epoll_config = self.epoll_context.allocate_virtio_vhost_user_block_tokens();
+
+            // The control channel is set up here, while the queues are handed over to the
+            // backend when the guest driver activates the device.
+            let block_box = Box::new(
+                devices::virtio::VhostUserBlock::new(&cfg.socket_path, epoll_config)
+                    .map_err(StartMicrovmError::CreateVhostUserBlockDevice)?,
+            );
+            device_manager
+                .register_virtio_device(
+                    self.vm.get_fd(),
+                    block_box,
+                    &mut kernel_config.cmdline,
+                    &cfg.id,
+                )
+                .map_err(StartMicrovmError::RegisterVhostUserBlockDevice)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "virtio-fs")]
+    fn attach_virtio_fs_devices(&mut self) -> std::result::Result<(), StartMicrovmError> {
+        let kernel_config = self
+            .kernel_config
+            .as_mut()
+            .ok_or(StartMicrovmError::MissingKernelConfig)?;
+        // `unwrap` is suitable for this context since this should be called only after the
+        // device manager has been initialized.
+        let device_manager = self.mmio_device_manager.as_mut().unwrap();
+
+        for cfg in self.virtio_fs_device_configs.iter() {
+            let epoll_config = self.epoll_context.allocate_virtio_fs_tokens();
+
+            device_manager
+                .register_virtiofs_device(
+                    self.vm.get_fd(),
+                    Path::new(&cfg.shared_dir),
+                    &cfg.tag,
+                    epoll_config,
+                    &mut kernel_config.cmdline,
+                    &cfg.id,
+                )
+                .map_err(StartMicrovmError::RegisterFsDevice)?;
+        }
+        Ok(())
+    }
+
     #[cfg(feature = "vsock")]
     fn attach_vsock_devices(
         &mut self,
@@ -1007,14 +1581,130 @@ impl Vmm {
                     &cfg.id,
                 )
                 .map_err(StartMicrovmError::RegisterVsockDevice)?;
+
+            if let Some(port) = cfg.agent_port {
+                let agent = GuestAgent::listen(cfg.guest_cid, port)
+                    .map_err(StartMicrovmError::GuestAgent)?;
+                let agent_index = self.guest_agents.len();
+                let agent_event = self
+                    .epoll_context
+                    .add_event(agent, EpollDispatch::GuestAgent(agent_index))
+                    .map_err(|_| StartMicrovmError::RegisterEvent)?;
+                self.guest_agents.push((cfg.id.clone(), agent_event));
+            }
+
+            if let Some(ref file_transfer) = cfg.file_transfer {
+                let receiver = FileReceiver::listen(
+                    cfg.guest_cid,
+                    file_transfer.port,
+                    &file_transfer.directory,
+                    Limits {
+                        max_file_size: u64::from(file_transfer.max_file_size_mib) << 20,
+                        max_total_size: u64::from(file_transfer.max_total_size_mib) << 20,
+                        max_file_count: u64::from(file_transfer.max_file_count),
+                    },
+                )
+                .map_err(StartMicrovmError::FileTransfer)?;
+                let receiver_index = self.file_receivers.len();
+                let receiver_event = self
+                    .epoll_context
+                    .add_event(receiver, EpollDispatch::FileReceiver(receiver_index))
+                    .map_err(|_| StartMicrovmError::RegisterEvent)?;
+                self.file_receivers.push(receiver_event);
+            }
         }
         Ok(())
     }
 
+    // Points the guest to its console through the `console=` boot argument. The legacy serial
+    // port is there unless it was disabled, while the virtio console is only added when it is
+    // the console.
+    fn attach_console_device(&mut self) -> std::result::Result<(), StartMicrovmError> {
+        #[cfg(target_arch = "aarch64")]
+        let serial_enabled = self.serial_enabled();
+        let kernel_config = self
+            .kernel_config
+            .as_mut()
+            .ok_or(StartMicrovmError::MissingKernelConfig)?;
+        let console = match kernel_config.console {
+            #[cfg(target_arch = "aarch64")]
+            Some(ConsoleDevice::Serial) if !serial_enabled => {
+                return Err(StartMicrovmError::SerialConsoleDisabled);
+            }
+            Some(console) => console,
+            None => return Ok(()),
+        };
+
+        if console == ConsoleDevice::Virtio {
+            // `unwrap` is suitable for this context since this should be called only after the
+            // device manager has been initialized.
+            let device_manager = self.mmio_device_manager.as_mut().unwrap();
+            let epoll_config = self.epoll_context.allocate_virtio_console_tokens();
+            let console_box = Box::new(devices::virtio::Console::new(
+                Box::new(io::stdout()),
+                epoll_config,
+            ));
+            device_manager
+                .register_virtio_device(
+                    self.vm.get_fd(),
+                    console_box,
+                    &mut kernel_config.cmdline,
+                    "console",
+                )
+                .map_err(StartMicrovmError::RegisterConsoleDevice)?;
+        }
+        // The console replaces the one from the boot arguments, and the serial console needs the
+        // 8250 driver which the default boot arguments disable.
+        kernel_config.cmdline.remove("console");
+        if console == ConsoleDevice::Serial {
+            kernel_config.cmdline.remove("8250.nr_uarts");
+        }
+        kernel_config
+            .cmdline
+            .insert("console", console.guest_name())
+            .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))
+    }
+
     fn configure_kernel(&mut self, kernel_config: KernelConfig) {
         self.kernel_config = Some(kernel_config);
     }
 
+    fn get_devices(&mut self) -> Vec<DeviceInfo> {
+        let mut devices = Vec::new();
+        for drive_config in self.block_device_configs.config_list.iter() {
+            let health = match self.drive_handler_id_map.get(&drive_config.drive_id) {
+                Some(device_idx) => self.epoll_context.get_device_health(*device_idx),
+                None => DeviceHealth::Healthy,
+            };
+            devices.push(DeviceInfo::new(
+                drive_config.drive_id.clone(),
+                DeviceType::Block,
+                &health,
+            ));
+        }
+        for netif_config in self.network_interface_configs.iter() {
+            let health = match self.net_handler_id_map.get(&netif_config.iface_id) {
+                Some(device_idx) => self.epoll_context.get_device_health(*device_idx),
+                None => DeviceHealth::Healthy,
+            };
+            devices.push(DeviceInfo::new(
+                netif_config.iface_id.clone(),
+                DeviceType::Net,
+                &health,
+            ));
+        }
+        devices
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn get_fdt(&self) -> std::result::Result<VmmData, VmmActionError> {
+        // The FDT describes the devices too, so it is only built once they are all attached.
+        match self.fdt {
+            Some(ref fdt) => Ok(VmmData::Fdt(fdt.clone())),
+            None => Err(VmConfigError::FdtNotAvailable.into()),
+        }
+    }
+
     fn flush_metrics(&mut self) -> std::result::Result<VmmData, VmmActionError> {
         if let Err(e) = self.write_metrics() {
             if let LoggerError::NeverInitialized(s) = e {
@@ -1055,9 +1745,42 @@ impl Vmm {
                 memory_model::GuestMemoryError::MemoryNotInitialized,
             ))?
             << 20;
+        // A host which can't encrypt the guest memory is refused before allocating it.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.vm_config.sev_enabled == Some(true) {
+                self.sev = Some(sev::Sev::new().map_err(StartMicrovmError::Sev)?);
+            }
+        }
         let arch_mem_regions = arch::arch_memory_regions(mem_size);
-        self.guest_memory =
-            Some(GuestMemory::new(&arch_mem_regions).map_err(StartMicrovmError::GuestMemory)?);
+        // The vhost-user backends access the guest memory directly, so it must be backed by files
+        // which can be passed to them.
+        #[cfg(feature = "vhost-user-block")]
+        let shared = !self.vhost_user_block_device_configs.is_empty();
+        #[cfg(not(feature = "vhost-user-block"))]
+        let shared = false;
+        let guest_memory = if shared {
+            GuestMemory::new_shared(&arch_mem_regions)
+        } else {
+            GuestMemory::new(&arch_mem_regions)
+        };
+        let guest_memory = guest_memory.map_err(StartMicrovmError::GuestMemory)?;
+        // The memory has to be bound before it is touched, since resident pages are not moved.
+        if let Some(node) = self.vm_config.numa_node {
+            guest_memory
+                .bind_to_node(node)
+                .map_err(StartMicrovmError::GuestMemory)?;
+        }
+        self.touch_guest_memory(&guest_memory);
+        self.register_guest_memory(guest_memory)
+    }
+
+    // Hands the guest memory, allocated or restored from a snapshot, over to the VM.
+    fn register_guest_memory(
+        &mut self,
+        guest_memory: GuestMemory,
+    ) -> std::result::Result<(), StartMicrovmError> {
+        self.guest_memory = Some(guest_memory);
         self.vm
             .memory_init(
                 self.guest_memory
@@ -1068,12 +1791,38 @@ impl Vmm {
                 &self.kvm,
             )
             .map_err(StartMicrovmError::ConfigureVm)?;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let (Some(sev), Some(guest_memory)) = (&self.sev, &self.guest_memory) {
+                sev.init(self.vm.get_fd(), guest_memory)
+                    .map_err(StartMicrovmError::Sev)?;
+            }
+        }
         Ok(())
     }
 
+    // Zeroes or faults in the guest memory, as configured. Zeroing the memory faults it in as
+    // well.
+    fn touch_guest_memory(&self, guest_memory: &GuestMemory) {
+        if self.vm_config.zero_memory == Some(true) {
+            guest_memory.zero();
+        } else if self.vm_config.prefault_memory == Some(true) {
+            guest_memory.populate();
+        }
+    }
+
     fn check_health(&self) -> std::result::Result<(), StartMicrovmError> {
-        if self.kernel_config.is_none() {
-            return Err(StartMicrovmError::MissingKernelConfig)?;
+        let kernel_config = self
+            .kernel_config
+            .as_ref()
+            .ok_or(StartMicrovmError::MissingKernelConfig)?;
+
+        // Without a root filesystem, the guest hangs waiting for one instead of failing.
+        if !kernel_config.diskless
+            && !self.block_device_configs.has_root_block_device()
+            && !has_root_boot_arg(kernel_config.cmdline.as_str())
+        {
+            return Err(StartMicrovmError::MissingRootfs)?;
         }
         Ok(())
     }
@@ -1093,11 +1842,22 @@ impl Vmm {
         // Instantiate the MMIO device manager.
         // 'mmio_base' address has to be an address which is protected by the kernel
         // and is architectural specific.
-        let device_manager = MMIODeviceManager::new(
+        let mut device_manager = MMIODeviceManager::new_checked(
             guest_mem.clone(),
             arch::get_reserved_mem_addr() as u64,
             (arch::IRQ_BASE, arch::IRQ_MAX),
-        );
+        )
+        .map_err(StartMicrovmError::RegisterMMIODevice)?;
+        if let Some(ref high_mmio) = self.vm_config.high_mmio {
+            // The size was checked when the range was configured.
+            device_manager
+                .reserve_high_mmio(
+                    &high_mmio.name,
+                    high_mmio.base,
+                    high_mmio.size().unwrap_or_default(),
+                )
+                .map_err(StartMicrovmError::RegisterMMIODevice)?;
+        }
         self.mmio_device_manager = Some(device_manager);
 
         Ok(())
@@ -1107,7 +1867,11 @@ impl Vmm {
         self.init_mmio_device_manager()?;
 
         self.attach_block_devices()?;
+        #[cfg(feature = "vhost-user-block")]
+        self.attach_vhost_user_block_devices()?;
         self.attach_net_devices()?;
+        #[cfg(feature = "virtio-fs")]
+        self.attach_virtio_fs_devices()?;
         #[cfg(feature = "vsock")]
         {
             let guest_mem = self
@@ -1118,12 +1882,13 @@ impl Vmm {
                 ))?;
             self.attach_vsock_devices(&guest_mem)?;
         }
+        self.attach_console_device()?;
 
         Ok(())
     }
 
     #[cfg(target_arch = "aarch64")]
-    fn get_mmio_device_info(&self) -> Option<&HashMap<String, MMIODeviceInfo>> {
+    fn get_mmio_device_info(&self) -> Option<&BTreeMap<String, MMIODeviceInfo>> {
         if let Some(ref device_manager) = self.mmio_device_manager {
             Some(device_manager.get_device_info())
         } else {
@@ -1144,9 +1909,15 @@ impl Vmm {
             .vm_config
             .vcpu_count
             .ok_or(StartMicrovmError::VcpusNotConfigured)?;
+        let gic_version = self.vm_config.gic_version.map(Into::into);
         self.vm
-            .setup_irqchip(vcpu_count)
-            .map_err(StartMicrovmError::ConfigureVm)
+            .setup_irqchip(vcpu_count, gic_version)
+            .map_err(|e| match e {
+                vstate::Error::SetupGIC(arch::aarch64::gic::Error::UnsupportedVersion(version)) => {
+                    StartMicrovmError::GicVersionNotSupported(version.into())
+                }
+                e => StartMicrovmError::ConfigureVm(e),
+            })
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -1173,9 +1944,17 @@ impl Vmm {
             .map_err(|e| StartMicrovmError::LegacyIOBus(device_manager::legacy::Error::EventFd(e)))
     }
 
+    // Returns whether the guest has a serial device. Only aarch64 guests can go without one.
+    fn serial_enabled(&self) -> bool {
+        cfg!(target_arch = "x86_64") || self.vm_config.serial_enabled != Some(false)
+    }
+
     #[cfg(target_arch = "aarch64")]
     fn attach_legacy_devices(&mut self) -> std::result::Result<(), StartMicrovmError> {
+        use vmm_config::machine_config::GuestPanicAction;
+
         self.init_mmio_device_manager()?;
+        let serial_enabled = self.serial_enabled();
         // `unwrap` is suitable for this context since this should be called only after the
         // device manager has been initialized.
         let device_manager = self.mmio_device_manager.as_mut().unwrap();
@@ -1186,9 +1965,28 @@ impl Vmm {
             .as_mut()
             .ok_or(StartMicrovmError::MissingKernelConfig)?;
 
+        // Without the serial device, the guest has no early console either.
+        if serial_enabled {
+            device_manager
+                .register_mmio_serial(
+                    self.vm.get_fd(),
+                    &mut kernel_config.cmdline,
+                    "uart",
+                    Box::new(io::stdout()),
+                )
+                .map_err(StartMicrovmError::RegisterMMIODevice)?;
+        }
         device_manager
-            .enable_earlycon(self.vm.get_fd(), &mut kernel_config.cmdline)
+            .register_mmio_rtc(self.vm.get_fd(), self.vm_config.rtc_base_time)
             .map_err(StartMicrovmError::RegisterMMIODevice)?;
+        device_manager
+            .register_mmio_pvpanic(self.vm.get_fd())
+            .map_err(StartMicrovmError::RegisterMMIODevice)?;
+        // The guest signals its panic through the pvpanic device, and resetting right after
+        // (`panic=`) would stop the microVM instead of taking the configured action.
+        if self.vm_config.guest_panic_action.unwrap_or_default() != GuestPanicAction::Exit {
+            kernel_config.cmdline.remove("panic");
+        }
         Ok(())
     }
 
@@ -1207,12 +2005,49 @@ impl Vmm {
             .ok_or(StartMicrovmError::VcpusNotConfigured)?;
         let mut vcpus = Vec::with_capacity(vcpu_count as usize);
 
+        let node_cpus = match self.vm_config.numa_node {
+            Some(node) => numa::node_cpus(node).map_err(StartMicrovmError::NumaNodeCpus)?,
+            None => Vec::new(),
+        };
+
+        let guest_panic_action = self.vm_config.guest_panic_action.unwrap_or_default();
+
         for cpu_id in 0..vcpu_count {
             let io_bus = self.legacy_device_manager.io_bus.clone();
             let mut vcpu = Vcpu::new(cpu_id, &self.vm, io_bus, request_ts.clone())
                 .map_err(StartMicrovmError::Vcpu)?;
             vcpu.configure(&self.vm_config, entry_addr, &self.vm)
-                .map_err(StartMicrovmError::VcpuConfigure)?;
+                .map_err(|e| match e {
+                    #[cfg(target_arch = "x86_64")]
+                    vstate::Error::ClockSourceNotSupported(clock_source) => {
+                        StartMicrovmError::ClockSourceNotSupported(clock_source)
+                    }
+                    #[cfg(target_arch = "x86_64")]
+                    vstate::Error::X2ApicNotSupported => StartMicrovmError::X2ApicNotSupported,
+                    e => StartMicrovmError::VcpuConfigure(e),
+                })?;
+            if let Some(ref watchdog) = self.watchdog {
+                vcpu.set_heartbeat(watchdog.heartbeat());
+            }
+            if let Some(ref idle_monitor) = self.idle_monitor {
+                vcpu.set_activity(idle_monitor.vcpu_activity());
+            }
+            vcpu.set_instance_info(self.shared_info.clone());
+            vcpu.set_boot_metrics(self.boot_metrics);
+            vcpu.set_guest_panic_action(guest_panic_action, self.vcpus_paused.clone());
+            // A vCPU pinned explicitly isn't restricted to the CPUs of the NUMA node.
+            match self
+                .vm_config
+                .vcpu_affinity
+                .as_ref()
+                .and_then(|affinity| affinity.get(&cpu_id))
+            {
+                Some(&host_cpu) => vcpu.set_cpu_affinity(vec![host_cpu]),
+                None => vcpu.set_cpu_affinity(node_cpus.clone()),
+            }
+            if let Some(scheduling) = self.vm_config.vcpu_scheduling {
+                vcpu.set_scheduling(scheduling);
+            }
             vcpus.push(vcpu);
         }
         Ok(vcpus)
@@ -1231,19 +2066,20 @@ impl Vmm {
         );
 
         self.vcpus_handles.reserve(vcpu_count as usize);
+        Vcpu::register_kick_signal_handler().map_err(StartMicrovmError::Vcpu)?;
 
         let vcpus_thread_barrier = Arc::new(Barrier::new((vcpu_count + 1) as usize));
+        let (thread_setup_sender, thread_setup_receiver) = channel();
 
         // We're going in reverse so we can `.pop()` on the vec and still maintain order.
         for cpu_id in (0..vcpu_count).rev() {
             let vcpu_thread_barrier = vcpus_thread_barrier.clone();
-            // If the lock is poisoned, it's OK to panic.
             let vcpu_exit_evt = self
-                .legacy_device_manager
-                .i8042
-                .lock()
-                .expect("Failed to start VCPUs due to poisoned i8042 lock")
-                .get_reset_evt_clone()
+                .exit_evt
+                .as_ref()
+                .ok_or(StartMicrovmError::EventFd)?
+                .fd
+                .try_clone()
                 .map_err(|_| StartMicrovmError::EventFd)?;
 
             // `unwrap` is safe since we are asserting that the `vcpu_count` is equal to the number
@@ -1252,16 +2088,42 @@ impl Vmm {
 
             if let Some(ref mmio_device_manager) = self.mmio_device_manager {
                 vcpu.set_mmio_bus(mmio_device_manager.bus.clone());
+                #[cfg(target_arch = "aarch64")]
+                {
+                    use arch::aarch64::DeviceInfoForFDT;
+                    if let Some(info) = mmio_device_manager.get_device_info().get("pvpanic") {
+                        vcpu.set_pvpanic_addr(info.addr());
+                    }
+                }
             }
             let seccomp_level = self.seccomp_level;
-            self.vcpus_handles.push(
-                thread::Builder::new()
-                    .name(format!("fc_vcpu{}", cpu_id))
-                    .spawn(move || {
+            let thread_setup_sender = thread_setup_sender.clone();
+            let (requests, states) = vcpu.request_channels();
+            let thread = thread::Builder::new()
+                .name(format!("fc_vcpu{}", cpu_id))
+                .spawn(move || {
+                    signal_handler::register_stack_dump_thread(&format!("fc_vcpu{}", cpu_id));
+                    let thread_setup = vcpu
+                        .setup_thread()
+                        .map_err(|e| StartMicrovmError::VcpuScheduling(cpu_id, e));
+                    let thread_ready = thread_setup.is_ok();
+                    // The VMM thread waits for all the results, so this can't fail.
+                    let _ = thread_setup_sender.send(thread_setup);
+                    if thread_ready {
                         vcpu.run(vcpu_thread_barrier, seccomp_level, vcpu_exit_evt);
-                    })
-                    .map_err(StartMicrovmError::VcpuSpawn)?,
-            );
+                    }
+                })
+                .map_err(StartMicrovmError::VcpuSpawn)?;
+            self.vcpus_handles
+                .push(VcpuHandle::new(cpu_id, thread, requests, states));
+        }
+        self.vcpus_handles.reverse();
+
+        // The start fails before the guest runs if a vCPU thread can't be set up, rather than
+        // stopping a microVM the client was told is running. The vCPU threads that were set up
+        // stay blocked on the barrier.
+        for thread_setup in thread_setup_receiver.iter().take(vcpu_count as usize) {
+            thread_setup?;
         }
 
         // Load seccomp filters for the VMM thread.
@@ -1276,6 +2138,8 @@ impl Vmm {
     }
 
     fn load_kernel(&mut self) -> std::result::Result<GuestAddress, StartMicrovmError> {
+        #[cfg(target_arch = "x86_64")]
+        let reserved_memory = self.reserved_memory();
         // This is the easy way out of consuming the value of the kernel_cmdline.
         let kernel_config = self
             .kernel_config
@@ -1286,12 +2150,30 @@ impl Vmm {
         let vm_memory = self.vm.get_memory().ok_or(StartMicrovmError::GuestMemory(
             memory_model::GuestMemoryError::MemoryNotInitialized,
         ))?;
+        // The guest can't use the reserved memory ranges, so they can't hold the kernel.
+        #[cfg(target_arch = "x86_64")]
+        kernel_loader::check_kernel_placement(&mut kernel_config.kernel_file, &reserved_memory)
+            .map_err(StartMicrovmError::KernelLoader)?;
         let entry_addr = kernel_loader::load_kernel(
             vm_memory,
             &mut kernel_config.kernel_file,
             arch::get_kernel_start(),
+            None,
         )
         .map_err(StartMicrovmError::KernelLoader)?;
+        validate_kernel_entry(vm_memory, entry_addr)?;
+
+        // The vCPUs only expose the clock source the guest is pinned to, the command line makes
+        // the guest pick it without probing the others.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(clock_source) = self.vm_config.clock_source {
+                kernel_config
+                    .cmdline
+                    .insert_str(clock_source.cmdline_params())
+                    .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
+            }
+        }
 
         // This is x86_64 specific since on aarch64 the commandline will be specified through the FDT.
         #[cfg(target_arch = "x86_64")]
@@ -1308,7 +2190,7 @@ impl Vmm {
         Ok(entry_addr)
     }
 
-    fn configure_system(&self) -> std::result::Result<(), StartMicrovmError> {
+    fn configure_system(&mut self) -> std::result::Result<(), StartMicrovmError> {
         let kernel_config = self
             .kernel_config
             .as_ref()
@@ -1323,32 +2205,131 @@ impl Vmm {
             .vm_config
             .vcpu_count
             .ok_or(StartMicrovmError::VcpusNotConfigured)?;
+        // The seed is generated anew for each boot, so it is never reused across guests.
+        let mut seed = [0u8; RNG_SEED_SIZE];
+        let rng_seed = if kernel_config.rng_seed {
+            fc_util::get_random_bytes(&mut seed).map_err(StartMicrovmError::RngSeed)?;
+            Some(&seed[..])
+        } else {
+            None
+        };
+
         #[cfg(target_arch = "x86_64")]
         arch::x86_64::configure_system(
             vm_memory,
             kernel_config.cmdline_addr,
             kernel_config.cmdline.len() + 1,
             vcpu_count,
+            rng_seed,
+            &self.reserved_memory(),
         )
         .map_err(StartMicrovmError::ConfigureSystem)?;
 
         #[cfg(target_arch = "aarch64")]
         {
-            arch::aarch64::configure_system(
+            let vcpu_mpidr: Vec<u64> = (0..vcpu_count)
+                .map(|cpu_id| match self.vm_config.cpu_topology {
+                    Some(topology) => arch::aarch64::get_mpidr(
+                        cpu_id,
+                        topology.threads_per_core,
+                        topology.cores_per_socket,
+                    ),
+                    None => u64::from(cpu_id),
+                })
+                .collect();
+            let fdt = arch::aarch64::configure_system(
                 vm_memory,
                 &kernel_config
                     .cmdline
                     .as_cstring()
                     .map_err(StartMicrovmError::LoadCommandline)?,
-                vcpu_count,
+                &vcpu_mpidr,
+                // The GIC is created by `setup_interrupt_controller` before the system is
+                // configured, with GICv3 being the default.
+                self.vm
+                    .gic_version()
+                    .unwrap_or(arch::aarch64::gic::GicVersion::V3),
                 self.get_mmio_device_info(),
+                rng_seed,
             )
             .map_err(StartMicrovmError::ConfigureSystem)?;
+            self.fdt = Some(fdt);
+        }
+        Ok(())
+    }
+
+    // Returns the guest address ranges, given as start and size, which the memory map of the
+    // guest marks as reserved.
+    #[cfg(target_arch = "x86_64")]
+    fn reserved_memory(&self) -> Vec<(GuestAddress, usize)> {
+        self.vm_config
+            .reserved_memory
+            .iter()
+            .flatten()
+            .filter_map(|range| Some((GuestAddress(range.base as usize), range.size()? as usize)))
+            .collect()
+    }
+
+    // Encrypts what was written to the memory of a SEV guest while setting it up, i.e. the kernel
+    // and the boot structures. The guest memory can't be written by the host afterwards.
+    #[cfg(target_arch = "x86_64")]
+    fn launch_sev_guest(&mut self) -> std::result::Result<(), StartMicrovmError> {
+        if let (Some(sev), Some(guest_memory)) = (&self.sev, &self.guest_memory) {
+            let kernel_config = self
+                .kernel_config
+                .as_mut()
+                .ok_or(StartMicrovmError::MissingKernelConfig)?;
+            let vcpu_count = self
+                .vm_config
+                .vcpu_count
+                .ok_or(StartMicrovmError::VcpusNotConfigured)?;
+            let mut ranges = arch::x86_64::boot_data_ranges(
+                kernel_config.cmdline_addr,
+                kernel_config.cmdline.len() + 1,
+                vcpu_count,
+                if kernel_config.rng_seed {
+                    Some(RNG_SEED_SIZE)
+                } else {
+                    None
+                },
+            );
+            ranges.push(
+                kernel_loader::kernel_range(&mut kernel_config.kernel_file)
+                    .map_err(StartMicrovmError::KernelLoader)?,
+            );
+            sev.launch(self.vm.get_fd(), guest_memory, &ranges)
+                .map_err(StartMicrovmError::Sev)?;
         }
         Ok(())
     }
 
+    // Serves the MMIO layout of the devices to the guest through the MMDS. This has to be called
+    // again whenever devices are added to or removed from the MMIO bus.
+    fn publish_device_info(&self) {
+        if let Some(ref device_manager) = self.mmio_device_manager {
+            if let Err(e) = mmds::MMDS
+                .lock()
+                .expect("Failed to publish the device info due to poisoned MMDS lock")
+                .set_device_info(device_manager.device_info_metadata())
+            {
+                warn!(
+                    "Cannot publish the device info in the MMDS: {}",
+                    e.to_string()
+                );
+            }
+        }
+    }
+
     fn register_events(&mut self) -> std::result::Result<(), StartMicrovmError> {
+        // The vCPUs don't share the reset event of the i8042 controller, so that the VMM tells
+        // a reset of the guest from a vCPU stopping.
+        let event_fd = EventFd::new().map_err(|_| StartMicrovmError::EventFd)?;
+        let exit_epoll_evt = self
+            .epoll_context
+            .add_event(event_fd, EpollDispatch::Exit)
+            .map_err(|_| StartMicrovmError::RegisterEvent)?;
+        self.exit_evt = Some(exit_epoll_evt);
+
         // If the lock is poisoned, it's OK to panic.
         let event_fd = self
             .legacy_device_manager
@@ -1357,11 +2338,11 @@ impl Vmm {
             .expect("Failed to register events on the event fd due to poisoned lock")
             .get_reset_evt_clone()
             .map_err(|_| StartMicrovmError::EventFd)?;
-        let exit_epoll_evt = self
+        let reset_epoll_evt = self
             .epoll_context
-            .add_event(event_fd, EpollDispatch::Exit)
+            .add_event(event_fd, EpollDispatch::I8042Reset)
             .map_err(|_| StartMicrovmError::RegisterEvent)?;
-        self.exit_evt = Some(exit_epoll_evt);
+        self.i8042_reset_evt = Some(reset_epoll_evt);
 
         self.epoll_context
             .enable_stdin_event()
@@ -1372,6 +2353,23 @@ impl Vmm {
 
     fn start_microvm(&mut self) -> std::result::Result<VmmData, VmmActionError> {
         info!("VMM received instance start command");
+        self.start_microvm_from(None)
+    }
+
+    fn load_snapshot(
+        &mut self,
+        config: SnapshotConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        info!("VMM received snapshot load command");
+        self.start_microvm_from(Some(&config.snapshot_path))
+    }
+
+    // Boots the microVM, or restores it from the snapshot at `snapshot_path` instead of loading
+    // its kernel.
+    fn start_microvm_from(
+        &mut self,
+        snapshot_path: Option<&Path>,
+    ) -> std::result::Result<VmmData, VmmActionError> {
         if self.is_instance_initialized() {
             Err(StartMicrovmError::MicroVMAlreadyRunning)?;
         }
@@ -1381,13 +2379,20 @@ impl Vmm {
         };
 
         self.check_health()?;
+        let mut snapshot = match snapshot_path {
+            Some(path) => Some(self.read_snapshot(path)?),
+            None => None,
+        };
         // Use expect() to crash if the other thread poisoned this lock.
         self.shared_info
             .write()
             .expect("Failed to start microVM because shared info couldn't be written due to poisoned lock")
             .state = InstanceState::Starting;
 
-        self.init_guest_memory()?;
+        match snapshot.as_mut().and_then(|s| s.guest_memory.take()) {
+            Some(guest_memory) => self.register_guest_memory(guest_memory)?,
+            None => self.init_guest_memory()?,
+        }
 
         let vcpus;
 
@@ -1399,23 +2404,45 @@ impl Vmm {
             self.attach_virtio_devices()?;
             self.attach_legacy_devices()?;
 
-            let entry_addr = self.load_kernel()?;
+            // The memory of a snapshot already holds the kernel, and the registers of the vCPUs
+            // are restored from it.
+            let entry_addr = match snapshot {
+                Some(_) => GuestAddress(0),
+                None => {
+                    let entry_addr = self.load_kernel()?;
+                    self.boot_metrics.reach(BootMilestone::KernelLoaded);
+                    entry_addr
+                }
+            };
             vcpus = self.create_vcpus(entry_addr, request_ts)?;
+            self.boot_metrics.reach(BootMilestone::VcpusCreated);
+            if let Some(ref snapshot) = snapshot {
+                self.restore_snapshot(snapshot, &vcpus)?;
+            }
         }
 
         #[cfg(target_arch = "aarch64")]
         {
             let entry_addr = self.load_kernel()?;
+            self.boot_metrics.reach(BootMilestone::KernelLoaded);
             vcpus = self.create_vcpus(entry_addr, request_ts)?;
+            self.boot_metrics.reach(BootMilestone::VcpusCreated);
 
             self.setup_interrupt_controller()?;
             self.attach_virtio_devices()?;
             self.attach_legacy_devices()?;
         }
 
-        self.configure_system()?;
+        // The guest restored from a snapshot already went through its boot.
+        if snapshot.is_none() {
+            self.configure_system()?;
+            #[cfg(target_arch = "x86_64")]
+            self.launch_sev_guest()?;
+        }
+        self.publish_device_info();
 
         self.register_events()?;
+        self.boot_metrics.reach(BootMilestone::DevicesRegistered);
 
         self.start_vcpus(vcpus)?;
         // Use expect() to crash if the other thread poisoned this lock.
@@ -1434,6 +2461,28 @@ impl Vmm {
             .fd
             .set_state(timer_state, SetTimeFlags::Default);
 
+        // Arm the watchdog timer.
+        if let Some(ref watchdog) = self.watchdog {
+            let timer_state = TimerState::Periodic {
+                current: watchdog.timeout(),
+                interval: watchdog.timeout(),
+            };
+            self.watchdog_event
+                .fd
+                .set_state(timer_state, SetTimeFlags::Default);
+        }
+
+        // Arm the idle monitor timer.
+        if let Some(ref idle_monitor) = self.idle_monitor {
+            let timer_state = TimerState::Periodic {
+                current: idle_monitor.timeout(),
+                interval: idle_monitor.timeout(),
+            };
+            self.idle_monitor_event
+                .fd
+                .set_state(timer_state, SetTimeFlags::Default);
+        }
+
         // Log the metrics straight away to check the process startup time.
         if LOGGER.log_metrics().is_err() {
             METRICS.logger.missed_metrics_count.inc();
@@ -1452,10 +2501,241 @@ impl Vmm {
         Ok(VmmData::Empty)
     }
 
+    fn create_snapshot(
+        &mut self,
+        config: SnapshotConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        let instance_state = self
+            .shared_info
+            .read()
+            .expect("Failed to read the instance info due to poisoned lock")
+            .state
+            .clone();
+        // A microVM paused on a guest panic is saved as it is.
+        let was_running = match instance_state {
+            InstanceState::Running => true,
+            InstanceState::Paused => false,
+            _ => {
+                return Err(VmmActionError::Snapshot(
+                    ErrorKind::User,
+                    snapshot::Error::MicrovmNotStarted,
+                ))
+            }
+        };
+
+        if was_running {
+            self.set_vcpus_paused(true);
+        }
+        let result = self.write_snapshot(&config.snapshot_path);
+        if was_running {
+            self.set_vcpus_paused(false);
+        }
+
+        result.map(|()| VmmData::Empty).map_err(|e| {
+            let kind = match e {
+                snapshot::Error::CreateFile(_) | snapshot::Error::NotSupported => ErrorKind::User,
+                _ => ErrorKind::Internal,
+            };
+            VmmActionError::Snapshot(kind, e)
+        })
+    }
+
+    // Pauses the vCPUs, which leave the guest once they are asked for their state, or lets them
+    // run the guest again.
+    fn set_vcpus_paused(&self, paused: bool) {
+        self.vcpus_paused.store(paused, Ordering::SeqCst);
+        if !paused {
+            for handle in self.vcpus_handles.iter() {
+                handle.resume();
+            }
+        }
+        self.shared_info
+            .write()
+            .expect("Failed to update the instance info due to poisoned lock")
+            .state = if paused {
+            InstanceState::Paused
+        } else {
+            InstanceState::Running
+        };
+    }
+
+    // Writes the snapshot of the paused microVM to `path`, and syncs it to the disk.
+    #[cfg(target_arch = "x86_64")]
+    fn write_snapshot(&self, path: &Path) -> snapshot::Result<()> {
+        let mut vcpu_states = Vec::with_capacity(self.vcpus_handles.len());
+        for handle in self.vcpus_handles.iter() {
+            vcpu_states.push(handle.save_state()?);
+        }
+        let vm_state = snapshot::vm_state(self.vm.get_fd())?;
+        let guest_memory = self
+            .vm
+            .get_memory()
+            .ok_or(snapshot::Error::MicrovmNotStarted)?;
+
+        let file = File::create(path).map_err(snapshot::Error::CreateFile)?;
+        let instance_state = self
+            .shared_info
+            .read()
+            .expect("Failed to read the instance info due to poisoned lock")
+            .state
+            .clone();
+        let mut writer = SnapshotWriter::new(BufWriter::new(file), &instance_state)?;
+        for state in vcpu_states.iter() {
+            writer.write_section(SectionKind::Vcpu, state)?;
+        }
+        writer.write_section(SectionKind::Vm, &vm_state)?;
+        if let Some(ref mmio_device_manager) = self.mmio_device_manager {
+            for (id, state) in mmio_device_manager.virtio_device_states() {
+                writer.write_device(&id, &state)?;
+            }
+        }
+        writer.write_memory(guest_memory)?;
+        let file = writer
+            .finish()?
+            .into_inner()
+            .map_err(|e| snapshot::Error::Write(e.into()))?;
+        file.sync_data().map_err(snapshot::Error::Write)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn write_snapshot(&self, _: &Path) -> snapshot::Result<()> {
+        Err(snapshot::Error::NotSupported)
+    }
+
+    // Reads the snapshot at `path`, refusing it unless it was saved from a microVM configured
+    // with the same vCPUs and memory.
+    #[cfg(target_arch = "x86_64")]
+    fn read_snapshot(&self, path: &Path) -> std::result::Result<Snapshot, VmmActionError> {
+        let user_error = |e| VmmActionError::Snapshot(ErrorKind::User, e);
+        // The memory of an encrypted guest can't be restored as it was saved.
+        if self.vm_config.sev_enabled == Some(true) {
+            return Err(user_error(snapshot::Error::NotSupported));
+        }
+        let file = File::open(path)
+            .map_err(snapshot::Error::Read)
+            .map_err(user_error)?;
+        let snapshot = snapshot::read_snapshot(BufReader::new(file)).map_err(user_error)?;
+
+        // vm_config has default values for vcpu_count and mem_size_mib.
+        let vcpu_count = self.vm_config.vcpu_count.unwrap_or(1);
+        snapshot.check_vcpus(vcpu_count).map_err(user_error)?;
+        let mem_size = self.vm_config.mem_size_mib.unwrap_or(0) << 20;
+        snapshot
+            .check_memory(&arch::arch_memory_regions(mem_size))
+            .map_err(user_error)?;
+        if snapshot.vm.is_none() {
+            return Err(user_error(snapshot::Error::MissingSection(
+                SectionKind::Vm as u32,
+            )));
+        }
+        Ok(snapshot)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn read_snapshot(&self, _: &Path) -> std::result::Result<Snapshot, VmmActionError> {
+        Err(VmmActionError::Snapshot(
+            ErrorKind::User,
+            snapshot::Error::NotSupported,
+        ))
+    }
+
+    // Loads the state of the VM, of its vCPUs and of its devices from the snapshot, once they are
+    // created afresh from the configuration of the microVM.
+    #[cfg(target_arch = "x86_64")]
+    fn restore_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        vcpus: &[Vcpu],
+    ) -> std::result::Result<(), VmmActionError> {
+        let internal_error = |e| VmmActionError::Snapshot(ErrorKind::Internal, e);
+        if let Some(ref vm_state) = snapshot.vm {
+            snapshot::restore_vm_state(self.vm.get_fd(), vm_state).map_err(internal_error)?;
+        }
+        for (vcpu, state) in vcpus.iter().zip(snapshot.vcpus.iter()) {
+            vcpu.restore_state(state).map_err(internal_error)?;
+        }
+
+        let mmio_device_manager = self
+            .mmio_device_manager
+            .as_ref()
+            .ok_or(StartMicrovmError::DeviceManager)?;
+        // A device missing on either side fails the restore rather than being dropped.
+        snapshot
+            .check_devices(&mmio_device_manager.virtio_device_ids())
+            .map_err(|e| VmmActionError::Snapshot(ErrorKind::User, e))?;
+        for &(ref id, ref state) in snapshot.devices.iter() {
+            mmio_device_manager
+                .restore_virtio_device(id, state)
+                .map_err(StartMicrovmError::RegisterMMIODevice)?;
+        }
+        Ok(())
+    }
+
+    fn send_reset(&mut self) -> std::result::Result<VmmData, VmmActionError> {
+        // The reset event is only handled once the microVM is started.
+        if !self.is_instance_initialized() {
+            return Err(VmmActionError::SendReset(
+                ErrorKind::User,
+                I8042DeviceError::ResetNotAllowedPreBoot,
+            ));
+        }
+
+        self.legacy_device_manager
+            .i8042
+            .lock()
+            .expect("i8042 lock was poisoned")
+            .trigger_reset()
+            .map_err(|e| VmmActionError::SendReset(ErrorKind::Internal, e))?;
+        // The reset goes through the i8042 controller, but the guest didn't ask for it.
+        self.record_stop_reason(StopReason::VmmStop);
+        Ok(VmmData::Empty)
+    }
+
+    // Records why the microVM stopped, unless the reason is already known, and returns the
+    // recorded reason.
+    fn record_stop_reason(&self, stop_reason: StopReason) -> StopReason {
+        *self
+            .shared_info
+            .write()
+            .expect("Failed to update the instance info due to poisoned lock")
+            .stop_reason
+            .get_or_insert(stop_reason)
+    }
+
     /// Waits for all vCPUs to exit and terminates the Firecracker process.
     fn stop(&mut self, exit_code: i32) {
+        // Unless a vCPU or the guest stopped first, the VMM is the one stopping the guest.
+        let stop_reason = self.record_stop_reason(StopReason::VmmStop);
+        info!("The microVM stopped: {}.", stop_reason);
+        // The process exits right away, so the metrics flushed below are the only place the
+        // reason can be read from, along with the log.
+        let metric = match stop_reason {
+            StopReason::GuestShutdown => &METRICS.stop_reason.guest_shutdown,
+            StopReason::GuestReset => &METRICS.stop_reason.guest_reset,
+            StopReason::GuestPanic => &METRICS.stop_reason.guest_panic,
+            StopReason::InternalError => &METRICS.stop_reason.internal_error,
+            StopReason::VmmStop => &METRICS.stop_reason.vmm_stop,
+        };
+        metric.inc();
+
+        self.shutdown();
+
+        // Exit from Firecracker using the provided exit code. Safe because we're terminating
+        // the process anyway.
+        unsafe {
+            libc::_exit(exit_code);
+        }
+    }
+
+    // Flushes the block devices, restores the terminal and flushes the metrics, leaving the exit
+    // to the caller.
+    fn shutdown(&mut self) {
         info!("Vmm is stopping.");
 
+        // The writes the guest saw completing must reach the backing files before exiting. The
+        // failures are logged, there is nothing else to do about them at this point.
+        self.flush_drives();
+
         if let Err(e) = self.epoll_context.disable_stdin_event() {
             warn!("Cannot disable the STDIN event. {:?}", e);
         }
@@ -1473,12 +2753,31 @@ impl Vmm {
         if let Err(e) = LOGGER.log_metrics() {
             error!("Failed to log metrics while stopping: {}", e);
         }
+    }
 
-        // Exit from Firecracker using the provided exit code. Safe because we're terminating
-        // the process anyway.
-        unsafe {
-            libc::_exit(exit_code);
-        }
+    // Returns whether a vCPU stopped because the guest panicked.
+    fn guest_panicked(&self) -> bool {
+        self.shared_info
+            .read()
+            .expect("Failed to read the instance info due to poisoned lock")
+            .stop_reason
+            == Some(StopReason::GuestPanic)
+    }
+
+    // Returns whether the watchdog is enabled and the guest did not signal it is alive since the
+    // previous check.
+    fn guest_stalled(&mut self) -> bool {
+        self.watchdog
+            .as_mut()
+            .map_or(false, |watchdog| !watchdog.check())
+    }
+
+    // Returns whether the idle monitor is enabled and neither the guest, its devices nor the API
+    // clients were active since the previous check.
+    fn microvm_idle(&mut self) -> bool {
+        self.idle_monitor
+            .as_mut()
+            .map_or(false, |idle_monitor| idle_monitor.check())
     }
 
     fn is_instance_initialized(&self) -> bool {
@@ -1494,6 +2793,15 @@ impl Vmm {
         }
     }
 
+    fn is_instance_starting(&self) -> bool {
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .read()
+            .expect("Failed to read the instance state due to poisoned lock")
+            .state
+            == InstanceState::Starting
+    }
+
     #[allow(clippy::unused_label)]
     fn run_control(&mut self) -> Result<()> {
         const EPOLL_EVENTS_LEN: usize = 100;
@@ -1504,7 +2812,13 @@ impl Vmm {
 
         // TODO: try handling of errors/failures without breaking this main loop.
         'poll: loop {
-            let num_events = epoll::wait(epoll_raw_fd, -1, &mut events[..]).map_err(Error::Poll)?;
+            let num_events = match epoll::wait(epoll_raw_fd, -1, &mut events[..]) {
+                Ok(num_events) => num_events,
+                // The wait can be interrupted by signals which don't terminate the process,
+                // such as `SIGUSR2`.
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Poll(e)),
+            };
 
             for event in events.iter().take(num_events) {
                 let dispatch_idx = event.data as usize;
@@ -1518,6 +2832,21 @@ impl Vmm {
                                 }
                                 None => warn!("leftover exit-evt in epollcontext!"),
                             }
+                            let exit_code = if self.guest_panicked() {
+                                FC_EXIT_CODE_GUEST_PANIC
+                            } else {
+                                FC_EXIT_CODE_OK
+                            };
+                            self.stop(i32::from(exit_code));
+                        }
+                        EpollDispatch::I8042Reset => {
+                            match self.i8042_reset_evt {
+                                Some(ref ev) => {
+                                    ev.fd.read().map_err(Error::EventFd)?;
+                                }
+                                None => warn!("leftover i8042 reset event in epollcontext!"),
+                            }
+                            self.record_stop_reason(StopReason::GuestReset);
                             self.stop(i32::from(FC_EXIT_CODE_OK));
                         }
                         EpollDispatch::Stdin => {
@@ -1548,6 +2877,10 @@ impl Vmm {
                         }
                         EpollDispatch::DeviceHandler(device_idx, device_token) => {
                             METRICS.vmm.device_events.inc();
+                            // The guest notifies the virtio devices without exiting to the VMM.
+                            if let Some(ref idle_monitor) = self.idle_monitor {
+                                idle_monitor.device_activity().record();
+                            }
                             match self.epoll_context.get_device_handler(device_idx) {
                                 Ok(handler) => {
                                     match handler.handle_event(
@@ -1569,11 +2902,49 @@ impl Vmm {
                                 }
                             }
                         }
+                        #[cfg(feature = "vsock")]
+                        EpollDispatch::FileReceiver(receiver_index) => {
+                            for path in self.file_receivers[receiver_index].fd.handle_event() {
+                                info!("Received {} from the guest.", path.display());
+                            }
+                        }
+                        #[cfg(feature = "vsock")]
+                        EpollDispatch::GuestAgent(agent_index) => {
+                            self.handle_guest_agent_event(agent_index);
+                        }
+                        EpollDispatch::StackDump => {
+                            self.stack_dump_event.fd.read().map_err(Error::EventFd)?;
+                            log_stack_dump();
+                        }
+                        EpollDispatch::Terminate => {
+                            self.terminate_event.fd.read().map_err(Error::EventFd)?;
+                            info!("Received SIGTERM.");
+                            self.stop(i32::from(FC_EXIT_CODE_OK));
+                        }
                         EpollDispatch::VmmActionRequest => {
                             self.api_event.fd.read().map_err(Error::EventFd)?;
-                            self.run_vmm_action().unwrap_or_else(|_| {
-                                warn!("got spurious notification from api thread");
-                            });
+                            match self.run_vmm_action() {
+                                Ok(()) => (),
+                                // The main thread is shutting down Firecracker.
+                                Err(Error::ApiChannelClosed) => return Ok(()),
+                                Err(_) => warn!("got spurious notification from api thread"),
+                            }
+                        }
+                        EpollDispatch::Watchdog => {
+                            self.watchdog_event.fd.read();
+                            if self.guest_stalled() {
+                                error!("The guest stopped signaling it is alive.");
+                                METRICS.vmm.watchdog_expired.inc();
+                                self.stop(i32::from(FC_EXIT_CODE_WATCHDOG));
+                            }
+                        }
+                        EpollDispatch::IdleMonitor => {
+                            self.idle_monitor_event.fd.read();
+                            if self.microvm_idle() {
+                                info!("Neither the guest nor the API clients were active.");
+                                METRICS.vmm.idle_timeout_expired.inc();
+                                self.stop(i32::from(FC_EXIT_CODE_IDLE));
+                            }
                         }
                         EpollDispatch::WriteMetrics => {
                             self.write_metrics_event.fd.read();
@@ -1597,11 +2968,8 @@ impl Vmm {
         if let Some(ref mem) = self.guest_memory {
             let dirty_pages = mem.map_and_fold(
                 0,
-                |(slot, memory_region)| {
-                    let bitmap = self
-                        .vm
-                        .get_fd()
-                        .get_dirty_log(slot as u32, memory_region.size());
+                |(slot, _)| {
+                    let bitmap = self.vm.get_dirty_log(slot);
                     match bitmap {
                         Ok(v) => v
                             .iter()
@@ -1620,6 +2988,10 @@ impl Vmm {
         &mut self,
         kernel_image_path: String,
         kernel_cmdline: Option<String>,
+        cmdline_max_size: Option<usize>,
+        rng_seed: bool,
+        console: Option<ConsoleDevice>,
+        diskless: bool,
     ) -> std::result::Result<VmmData, VmmActionError> {
         if self.is_instance_initialized() {
             return Err(VmmActionError::BootSource(
@@ -1628,10 +3000,18 @@ impl Vmm {
             ));
         }
 
+        let cmdline_max_size = cmdline_max_size.unwrap_or(arch::CMDLINE_MAX_SIZE);
+        if cmdline_max_size == 0 || cmdline_max_size > arch::CMDLINE_MAX_SIZE {
+            return Err(VmmActionError::BootSource(
+                ErrorKind::User,
+                BootSourceConfigError::InvalidKernelCommandLineMaxSize,
+            ));
+        }
+
         let kernel_file = File::open(kernel_image_path).map_err(|_| {
             VmmActionError::BootSource(ErrorKind::User, BootSourceConfigError::InvalidKernelPath)
         })?;
-        let mut cmdline = kernel_cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
+        let mut cmdline = kernel_cmdline::Cmdline::new(cmdline_max_size);
         cmdline
             .insert_str(kernel_cmdline.unwrap_or_else(|| String::from(DEFAULT_KERNEL_CMDLINE)))
             .map_err(|_| {
@@ -1646,12 +3026,52 @@ impl Vmm {
             cmdline,
             #[cfg(target_arch = "x86_64")]
             cmdline_addr: GuestAddress(arch::x86_64::layout::CMDLINE_START),
+            rng_seed,
+            console,
+            diskless,
         };
         self.configure_kernel(kernel_config);
 
         Ok(VmmData::Empty)
     }
 
+    fn append_boot_args(
+        &mut self,
+        boot_args: String,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if self.is_instance_initialized() {
+            return Err(VmmActionError::BootSource(
+                ErrorKind::User,
+                BootSourceConfigError::UpdateNotAllowedPostBoot,
+            ));
+        }
+        let kernel_config = self.kernel_config.as_mut().ok_or_else(|| {
+            VmmActionError::BootSource(ErrorKind::User, BootSourceConfigError::MissingBootSource)
+        })?;
+
+        for arg in boot_args.split_whitespace() {
+            let key = arg.split('=').next().unwrap_or(arg);
+            if RESERVED_BOOT_ARGS.contains(&key) {
+                return Err(VmmActionError::BootSource(
+                    ErrorKind::User,
+                    BootSourceConfigError::ReservedBootArg(key.to_string()),
+                ));
+            }
+        }
+
+        // Append to a copy, so that the command line is left untouched if it overflows.
+        let mut cmdline = kernel_config.cmdline.clone();
+        cmdline.insert_str(boot_args.trim()).map_err(|_| {
+            VmmActionError::BootSource(
+                ErrorKind::User,
+                BootSourceConfigError::InvalidKernelCommandLine,
+            )
+        })?;
+        kernel_config.cmdline = cmdline;
+
+        Ok(VmmData::Empty)
+    }
+
     fn set_vm_configuration(
         &mut self,
         machine_config: VmConfig,
@@ -1674,6 +3094,14 @@ impl Vmm {
             }
         }
 
+        if let Some(node) = machine_config.numa_node {
+            // The vCPUs are pinned to the CPUs of the node, so it must have some.
+            match numa::node_cpus(node) {
+                Ok(ref cpus) if !cpus.is_empty() => (),
+                _ => Err(VmConfigError::InvalidNumaNode)?,
+            }
+        }
+
         let ht_enabled = match machine_config.ht_enabled {
             Some(value) => value,
             None => self.vm_config.ht_enabled.unwrap(),
@@ -1684,15 +3112,113 @@ impl Vmm {
             None => self.vm_config.vcpu_count.unwrap(),
         };
 
+        let cpu_topology = match machine_config.cpu_topology {
+            Some(value) => Some(value),
+            None => self.vm_config.cpu_topology,
+        };
+
+        // When a CPU topology is configured, hyperthreading follows the number of threads
+        // per core.
+        let ht_enabled = match cpu_topology {
+            Some(topology) => {
+                topology.validate(vcpu_count_value)?;
+                let topology_ht_enabled = topology.threads_per_core > 1;
+                if let Some(value) = machine_config.ht_enabled {
+                    if value != topology_ht_enabled {
+                        Err(VmConfigError::InvalidCpuTopology)?;
+                    }
+                }
+                topology_ht_enabled
+            }
+            None => ht_enabled,
+        };
+
         // If hyperthreading is enabled or is to be enabled in this call
         // only allow vcpu count to be 1 or even.
         if ht_enabled && vcpu_count_value > 1 && vcpu_count_value % 2 == 1 {
             Err(VmConfigError::InvalidVcpuCount)?;
         }
 
+        let vcpu_affinity = match machine_config.vcpu_affinity {
+            Some(value) => Some(value),
+            None => self.vm_config.vcpu_affinity.clone(),
+        };
+        if let Some(ref affinity) = vcpu_affinity {
+            // The vCPU threads can only be pinned to the CPUs Firecracker is allowed to run on.
+            let host_cpus = numa::allowed_cpus().unwrap_or_default();
+            for (&index, &cpu) in affinity.iter() {
+                if index >= vcpu_count_value {
+                    Err(VmConfigError::InvalidVcpuAffinityIndex(index))?;
+                }
+                if !host_cpus.contains(&cpu) {
+                    Err(VmConfigError::InvalidHostCpu(cpu))?;
+                }
+            }
+        }
+
+        // Linux ignores `interrupt-affinity` on the virtio-mmio nodes of the device tree, and
+        // programs the interrupt controller on its own.
+        if machine_config
+            .irq_affinity
+            .map_or(false, |affinity| !affinity.is_empty())
+        {
+            Err(VmConfigError::IrqAffinityNotSupported)?;
+        }
+
+        let vcpu_scheduling = match machine_config.vcpu_scheduling {
+            Some(value) => Some(value),
+            None => self.vm_config.vcpu_scheduling,
+        };
+        if let Some(scheduling) = vcpu_scheduling {
+            scheduling.validate()?;
+            // The kernel only admits deadline threads which can run on all the host CPUs.
+            let numa_node = machine_config.numa_node.or(self.vm_config.numa_node);
+            if scheduling.policy == VcpuSchedPolicy::Deadline
+                && (vcpu_affinity.is_some() || numa_node.is_some())
+            {
+                Err(VmConfigError::InvalidVcpuScheduling)?;
+            }
+        }
+
+        let high_mmio = match machine_config.high_mmio {
+            Some(value) => Some(value),
+            None => self.vm_config.high_mmio.clone(),
+        };
+        if let Some(ref high_mmio) = high_mmio {
+            let mem_size_mib = machine_config
+                .mem_size_mib
+                .or(self.vm_config.mem_size_mib)
+                .unwrap_or_default();
+            let base = high_mmio.base;
+            let end = high_mmio.end().ok_or(VmConfigError::InvalidHighMmio)?;
+            let overlaps = |start: u64, limit: u64| base < limit && start < end;
+            let overlaps_memory = arch::arch_memory_regions(mem_size_mib << 20)
+                .iter()
+                .any(|&(addr, size)| overlaps(addr.offset() as u64, (addr.offset() + size) as u64));
+            // The low MMIO range is where the other devices are placed.
+            let overlaps_low_mmio = overlaps(
+                arch::get_reserved_mem_addr() as u64,
+                arch::get_reserved_mem_end() as u64,
+            );
+            if high_mmio.size_mib == 0 || base % 0x1000 != 0 || overlaps_memory || overlaps_low_mmio
+            {
+                Err(VmConfigError::InvalidHighMmio)?;
+            }
+        }
+
+        if let Some(ref reserved_memory) = machine_config.reserved_memory {
+            for range in reserved_memory {
+                range.validate()?;
+            }
+        }
+
         // Update all the fields that have a new value.
         self.vm_config.vcpu_count = Some(vcpu_count_value);
         self.vm_config.ht_enabled = Some(ht_enabled);
+        self.vm_config.cpu_topology = cpu_topology;
+        self.vm_config.vcpu_affinity = vcpu_affinity;
+        self.vm_config.vcpu_scheduling = vcpu_scheduling;
+        self.vm_config.high_mmio = high_mmio;
 
         if machine_config.mem_size_mib.is_some() {
             self.vm_config.mem_size_mib = machine_config.mem_size_mib;
@@ -1702,26 +3228,70 @@ impl Vmm {
             self.vm_config.cpu_template = machine_config.cpu_template;
         }
 
-        Ok(VmmData::Empty)
-    }
+        if machine_config.prefault_memory.is_some() {
+            self.vm_config.prefault_memory = machine_config.prefault_memory;
+        }
 
-    fn insert_net_device(
-        &mut self,
-        body: NetworkInterfaceConfig,
-    ) -> std::result::Result<VmmData, VmmActionError> {
-        if self.is_instance_initialized() {
-            Err(NetworkInterfaceError::UpdateNotAllowedPostBoot)?;
+        if machine_config.zero_memory.is_some() {
+            self.vm_config.zero_memory = machine_config.zero_memory;
         }
-        self.network_interface_configs
-            .insert(body)
-            .map(|_| VmmData::Empty)
-            .map_err(|e| VmmActionError::NetworkConfig(ErrorKind::User, e))
-    }
 
-    fn update_net_device(
-        &mut self,
-        new_cfg: NetworkInterfaceUpdateConfig,
-    ) -> std::result::Result<VmmData, VmmActionError> {
+        if machine_config.numa_node.is_some() {
+            self.vm_config.numa_node = machine_config.numa_node;
+        }
+
+        if machine_config.reserved_memory.is_some() {
+            self.vm_config.reserved_memory = machine_config.reserved_memory;
+        }
+
+        if machine_config.gic_version.is_some() {
+            self.vm_config.gic_version = machine_config.gic_version;
+        }
+
+        if machine_config.serial_enabled.is_some() {
+            self.vm_config.serial_enabled = machine_config.serial_enabled;
+        }
+
+        if machine_config.rtc_base_time.is_some() {
+            self.vm_config.rtc_base_time = machine_config.rtc_base_time;
+        }
+
+        if machine_config.clock_source.is_some() {
+            self.vm_config.clock_source = machine_config.clock_source;
+        }
+
+        if machine_config.sev_enabled.is_some() {
+            self.vm_config.sev_enabled = machine_config.sev_enabled;
+        }
+
+        if machine_config.apic_mode.is_some() {
+            self.vm_config.apic_mode = machine_config.apic_mode;
+        }
+
+        if machine_config.guest_panic_action.is_some() {
+            self.vm_config.guest_panic_action = machine_config.guest_panic_action;
+        }
+
+        Ok(VmmData::Empty)
+    }
+
+    fn insert_net_device(
+        &mut self,
+        body: NetworkInterfaceConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if self.is_instance_initialized() {
+            Err(NetworkInterfaceError::UpdateNotAllowedPostBoot)?;
+        }
+        self.network_interface_configs
+            .insert(body)
+            .map(|_| VmmData::Empty)
+            .map_err(|e| VmmActionError::NetworkConfig(ErrorKind::User, e))
+    }
+
+    fn update_net_device(
+        &mut self,
+        new_cfg: NetworkInterfaceUpdateConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
         if !self.is_instance_initialized() {
             // VM not started yet, so we only need to update the device configs, not the actual
             // live device.
@@ -1799,6 +3369,79 @@ impl Vmm {
         Ok(VmmData::Empty)
     }
 
+    fn detach_net_device(
+        &mut self,
+        iface_id: &str,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        let not_found = || {
+            VmmActionError::NetworkConfig(
+                ErrorKind::NotFound,
+                NetworkInterfaceError::DeviceIdNotFound,
+            )
+        };
+        if !self.is_instance_initialized() {
+            return self
+                .network_interface_configs
+                .remove(iface_id)
+                .map(|_| VmmData::Empty)
+                .ok_or_else(not_found);
+        }
+
+        let handler_idx = *self
+            .net_handler_id_map
+            .get(iface_id)
+            .ok_or_else(not_found)?;
+        // The eventfds of the device are unregistered first, so that a failure leaves it
+        // attached and working.
+        // `unwrap` is suitable for this context since the device manager is initialized at boot.
+        self.mmio_device_manager
+            .as_mut()
+            .unwrap()
+            .detach_virtio_device(self.vm.get_fd(), iface_id)
+            .map_err(NetworkInterfaceError::DetachFailed)?;
+        // The epoll handler of an activated device owns its tap and its queues, which are
+        // closed along with it.
+        self.epoll_context.remove_device_handler(handler_idx);
+        self.net_handler_id_map.remove(iface_id);
+        self.network_interface_configs.remove(iface_id);
+
+        Ok(VmmData::Empty)
+    }
+
+    #[cfg(feature = "vhost-user-block")]
+    fn insert_vhost_user_block_device(
+        &mut self,
+        body: VhostUserBlockDeviceConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if self.is_instance_initialized() {
+            return Err(VmmActionError::VhostUserBlockConfig(
+                ErrorKind::User,
+                VhostUserBlockError::UpdateNotAllowedPostBoot,
+            ));
+        }
+        self.vhost_user_block_device_configs
+            .add(body)
+            .map(|_| VmmData::Empty)
+            .map_err(|e| VmmActionError::VhostUserBlockConfig(ErrorKind::User, e))
+    }
+
+    #[cfg(feature = "virtio-fs")]
+    fn insert_virtio_fs_device(
+        &mut self,
+        body: VirtioFsDeviceConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if self.is_instance_initialized() {
+            return Err(VmmActionError::VirtioFsConfig(
+                ErrorKind::User,
+                VirtioFsError::UpdateNotAllowedPostBoot,
+            ));
+        }
+        self.virtio_fs_device_configs
+            .add(body)
+            .map(|_| VmmData::Empty)
+            .map_err(|e| VmmActionError::VirtioFsConfig(ErrorKind::User, e))
+    }
+
     #[cfg(feature = "vsock")]
     fn insert_vsock_device(
         &mut self,
@@ -1816,6 +3459,52 @@ impl Vmm {
             .map_err(|e| VmmActionError::VsockConfig(ErrorKind::User, e))
     }
 
+    #[cfg(feature = "vsock")]
+    fn guest_agent_error(e: guest_agent::Error) -> VmmActionError {
+        let kind = match e {
+            guest_agent::Error::NoChannel(_) | guest_agent::Error::NotConnected => ErrorKind::User,
+            _ => ErrorKind::Internal,
+        };
+        VmmActionError::GuestAgent(kind, e)
+    }
+
+    // The agent answers later on, so the outcome is sent from `handle_guest_agent_event`, unless
+    // the command can't be sent at all.
+    #[cfg(feature = "vsock")]
+    fn send_agent_command(
+        &mut self,
+        vsock_id: &str,
+        command: &AgentCommand,
+        sender: OutcomeSender,
+        timeout: Option<Duration>,
+    ) {
+        let agent = self
+            .guest_agents
+            .iter_mut()
+            .find(|agent| agent.0 == vsock_id);
+        let result = match agent {
+            Some(agent) => (agent.1).fd.send(command, sender, timeout),
+            None => Err((guest_agent::Error::NoChannel(vsock_id.to_string()), sender)),
+        };
+        if let Err((e, sender)) = result {
+            Vmm::send_response(Err(Vmm::guest_agent_error(e)), sender);
+        }
+    }
+
+    #[cfg(feature = "vsock")]
+    fn handle_guest_agent_event(&mut self, agent_index: usize) {
+        let outcomes = (self.guest_agents[agent_index].1).fd.handle_event();
+        for (sender, outcome) in outcomes {
+            let outcome = outcome
+                .map(VmmData::AgentResponse)
+                .map_err(Vmm::guest_agent_error);
+            // The API client may have given up on the command in the meantime.
+            if sender.send(outcome).is_err() {
+                warn!("The guest agent answered a command nobody waits for anymore.");
+            }
+        }
+    }
+
     fn set_block_device_path(
         &mut self,
         drive_id: String,
@@ -1826,14 +3515,32 @@ impl Vmm {
             .block_device_configs
             .get_index_of_drive_id(&drive_id)
             .ok_or(DriveError::InvalidBlockDeviceID)?;
+        // A ramdisk can't be swapped for a file.
+        if self.block_device_configs.config_list[block_device_index]
+            .ramdisk_size()
+            .is_some()
+        {
+            Err(DriveError::InvalidRamdisk)?;
+        }
 
         let file_path = PathBuf::from(path_on_host);
+        let direct_io = self.block_device_configs.config_list[block_device_index].is_direct_io();
         // Try to open the file specified by path_on_host using the permissions of the block_device.
         let disk_file = OpenOptions::new()
             .read(true)
             .write(!self.block_device_configs.config_list[block_device_index].is_read_only())
+            .custom_flags(if direct_io { libc::O_DIRECT } else { 0 })
             .open(&file_path)
             .map_err(|_| DriveError::CannotOpenBlockDevice)?;
+        // Direct I/O drives can only be backed by host block devices.
+        if direct_io
+            && !disk_file
+                .metadata()
+                .map(|metadata| metadata.file_type().is_block_device())
+                .unwrap_or(false)
+        {
+            Err(DriveError::NotABlockDevice)?;
+        }
 
         // Update the path of the block device with the specified path_on_host.
         self.block_device_configs.config_list[block_device_index].path_on_host = file_path;
@@ -1886,6 +3593,49 @@ impl Vmm {
         }
     }
 
+    fn flush_block_devices(&mut self) -> std::result::Result<VmmData, VmmActionError> {
+        // The backing files are only written to after the guest is booted.
+        if !self.is_instance_initialized() {
+            Err(DriveError::OperationNotAllowedPreBoot)?;
+        }
+
+        let failed_drive_ids = self.flush_drives();
+        if !failed_drive_ids.is_empty() {
+            Err(DriveError::BlockDeviceFlushFailed(failed_drive_ids))?;
+        }
+        Ok(VmmData::Empty)
+    }
+
+    // Flushes the backing files of the block devices, returning the IDs of the drives which
+    // failed to flush.
+    fn flush_drives(&mut self) -> Vec<String> {
+        // The backing files are owned by the epoll handlers, which run on this thread, so no
+        // request of the guest is being served in the meantime.
+        let mut failed_drive_ids = Vec::new();
+        for drive_config in self.block_device_configs.config_list.iter() {
+            let device_idx = match self.drive_handler_id_map.get(&drive_config.drive_id) {
+                Some(&device_idx) => device_idx,
+                None => continue,
+            };
+            // A device without a handler hasn't been activated by the guest yet, so it couldn't
+            // have written anything.
+            if let Ok(handler) = self.epoll_context.get_device_handler(device_idx) {
+                if let Err(e) = handler.handle_event(
+                    virtio::block::FLUSH_EVENT,
+                    device_idx as u32,
+                    EpollHandlerPayload::Empty,
+                ) {
+                    error!(
+                        "Failed to flush block device {}: {:?}",
+                        drive_config.drive_id, e
+                    );
+                    failed_drive_ids.push(drive_config.drive_id.clone());
+                }
+            }
+        }
+        failed_drive_ids
+    }
+
     // Only call this function as part of the API.
     // If the drive_id does not exist, a new Block Device Config is added to the list.
     fn insert_block_device(
@@ -1932,6 +3682,7 @@ impl Vmm {
 
         LOGGER.set_include_origin(api_logger.show_log_origin, api_logger.show_log_origin);
         LOGGER.set_include_level(api_logger.show_level);
+        LOGGER.set_max_log_size(api_logger.max_log_size.unwrap_or(0));
 
         #[cfg(target_arch = "aarch64")]
         let options: &Vec<Value> = &vec![];
@@ -1962,6 +3713,20 @@ impl Vmm {
             .expect("one-shot channel closed");
     }
 
+    // Sends the outcome of a start, booting or restoring the microVM. A start failing past the
+    // health checks leaves a half built microVM behind, so Firecracker stops once the client got
+    // the error.
+    fn send_start_response(&mut self, outcome: VmmRequestOutcome, sender: OutcomeSender) {
+        let exit_code = match outcome {
+            Err(ref e) if self.is_instance_starting() => Some(e.exit_code()),
+            _ => None,
+        };
+        Vmm::send_response(outcome, sender);
+        if let Some(exit_code) = exit_code {
+            self.stop(i32::from(exit_code));
+        }
+    }
+
     fn run_vmm_action(&mut self) -> Result<()> {
         let request = match self.from_api.try_recv() {
             Ok(t) => *t,
@@ -1969,16 +3734,23 @@ impl Vmm {
                 return Err(Error::ApiChannel)?;
             }
             Err(TryRecvError::Disconnected) => {
-                panic!("The channel's sending half was disconnected. Cannot receive data.");
+                return Err(Error::ApiChannelClosed);
             }
         };
 
         match request {
+            VmmAction::AppendBootArgs(boot_args, sender) => {
+                Vmm::send_response(self.append_boot_args(boot_args), sender);
+            }
             VmmAction::ConfigureBootSource(boot_source_body, sender) => {
                 Vmm::send_response(
                     self.configure_boot_source(
                         boot_source_body.kernel_image_path,
                         boot_source_body.boot_args,
+                        boot_source_body.cmdline_max_size,
+                        boot_source_body.rng_seed,
+                        boot_source_body.console,
+                        boot_source_body.diskless,
                     ),
                     sender,
                 );
@@ -1986,34 +3758,83 @@ impl Vmm {
             VmmAction::ConfigureLogger(logger_description, sender) => {
                 Vmm::send_response(self.init_logger(logger_description), sender);
             }
+            VmmAction::CreateSnapshot(snapshot_config, sender) => {
+                Vmm::send_response(self.create_snapshot(snapshot_config), sender);
+            }
+            VmmAction::DetachNetworkDevice(iface_id, sender) => {
+                Vmm::send_response(self.detach_net_device(&iface_id), sender);
+            }
+            VmmAction::FlushBlockDevices(sender) => {
+                Vmm::send_response(self.flush_block_devices(), sender);
+            }
             VmmAction::FlushMetrics(sender) => {
                 Vmm::send_response(self.flush_metrics(), sender);
             }
+            #[cfg(feature = "vsock")]
+            VmmAction::FreezeFilesystems(vsock_id, sender) => {
+                let timeout = Duration::from_secs(GUEST_AGENT_FS_TIMEOUT_SECONDS);
+                self.send_agent_command(&vsock_id, &AgentCommand::FreezeFs, sender, Some(timeout));
+            }
             VmmAction::GetVmConfiguration(sender) => {
                 Vmm::send_response(
                     Ok(VmmData::MachineConfiguration(self.vm_config.clone())),
                     sender,
                 );
             }
+            VmmAction::GetDevices(sender) => {
+                Vmm::send_response(Ok(VmmData::Devices(self.get_devices())), sender);
+            }
+            #[cfg(target_arch = "aarch64")]
+            VmmAction::GetFdt(sender) => {
+                Vmm::send_response(self.get_fdt(), sender);
+            }
             VmmAction::InsertBlockDevice(block_device_config, sender) => {
                 Vmm::send_response(self.insert_block_device(block_device_config), sender);
             }
             VmmAction::InsertNetworkDevice(netif_body, sender) => {
                 Vmm::send_response(self.insert_net_device(netif_body), sender);
             }
+            #[cfg(feature = "vhost-user-block")]
+            VmmAction::InsertVhostUserBlockDevice(vhost_user_block_cfg, sender) => {
+                Vmm::send_response(
+                    self.insert_vhost_user_block_device(vhost_user_block_cfg),
+                    sender,
+                );
+            }
+            #[cfg(feature = "virtio-fs")]
+            VmmAction::InsertVirtioFsDevice(virtio_fs_cfg, sender) => {
+                Vmm::send_response(self.insert_virtio_fs_device(virtio_fs_cfg), sender);
+            }
             #[cfg(feature = "vsock")]
             VmmAction::InsertVsockDevice(vsock_cfg, sender) => {
                 Vmm::send_response(self.insert_vsock_device(vsock_cfg), sender);
             }
+            VmmAction::LoadSnapshot(snapshot_config, sender) => {
+                let result = self.load_snapshot(snapshot_config);
+                self.send_start_response(result, sender);
+            }
             VmmAction::RescanBlockDevice(drive_id, sender) => {
                 Vmm::send_response(self.rescan_block_device(&drive_id), sender);
             }
             VmmAction::StartMicroVm(sender) => {
-                Vmm::send_response(self.start_microvm(), sender);
+                let result = self.start_microvm();
+                self.send_start_response(result, sender);
             }
             VmmAction::SendCtrlAltDel(sender) => {
                 Vmm::send_response(self.send_ctrl_alt_del(), sender);
             }
+            VmmAction::SendReset(sender) => {
+                Vmm::send_response(self.send_reset(), sender);
+            }
+            #[cfg(feature = "vsock")]
+            VmmAction::SendAgentCommand(vsock_id, command, sender) => {
+                self.send_agent_command(&vsock_id, &command, sender, None);
+            }
+            #[cfg(feature = "vsock")]
+            VmmAction::ThawFilesystems(vsock_id, sender) => {
+                let timeout = Duration::from_secs(GUEST_AGENT_FS_TIMEOUT_SECONDS);
+                self.send_agent_command(&vsock_id, &AgentCommand::ThawFs, sender, Some(timeout));
+            }
             VmmAction::SetVmConfiguration(machine_config_body, sender) => {
                 Vmm::send_response(self.set_vm_configuration(machine_config_body), sender);
             }
@@ -2053,6 +3874,10 @@ impl PartialEq for VmmAction {
                 &VmmAction::UpdateBlockDevicePath(ref drive_id, ref path_on_host, _),
                 &VmmAction::UpdateBlockDevicePath(ref other_drive_id, ref other_path_on_host, _),
             ) => drive_id == other_drive_id && path_on_host == other_path_on_host,
+            (
+                &VmmAction::AppendBootArgs(ref boot_args, _),
+                &VmmAction::AppendBootArgs(ref other_boot_args, _),
+            ) => boot_args == other_boot_args,
             (
                 &VmmAction::ConfigureBootSource(ref boot_source, _),
                 &VmmAction::ConfigureBootSource(ref other_boot_source, _),
@@ -2065,6 +3890,14 @@ impl PartialEq for VmmAction {
                 &VmmAction::ConfigureLogger(ref log, _),
                 &VmmAction::ConfigureLogger(ref other_log, _),
             ) => log == other_log,
+            (
+                &VmmAction::CreateSnapshot(ref snapshot, _),
+                &VmmAction::CreateSnapshot(ref other_snapshot, _),
+            ) => snapshot == other_snapshot,
+            (
+                &VmmAction::LoadSnapshot(ref snapshot, _),
+                &VmmAction::LoadSnapshot(ref other_snapshot, _),
+            ) => snapshot == other_snapshot,
             (
                 &VmmAction::SetVmConfiguration(ref vm_config, _),
                 &VmmAction::SetVmConfiguration(ref other_vm_config, _),
@@ -2077,13 +3910,47 @@ impl PartialEq for VmmAction {
                 &VmmAction::UpdateNetworkInterface(ref net_dev, _),
                 &VmmAction::UpdateNetworkInterface(ref other_net_dev, _),
             ) => net_dev == other_net_dev,
+            (
+                &VmmAction::DetachNetworkDevice(ref iface_id, _),
+                &VmmAction::DetachNetworkDevice(ref other_iface_id, _),
+            ) => iface_id == other_iface_id,
+            #[cfg(feature = "vhost-user-block")]
+            (
+                &VmmAction::InsertVhostUserBlockDevice(ref block_dev, _),
+                &VmmAction::InsertVhostUserBlockDevice(ref other_block_dev, _),
+            ) => block_dev == other_block_dev,
+            #[cfg(feature = "virtio-fs")]
+            (
+                &VmmAction::InsertVirtioFsDevice(ref fs_dev, _),
+                &VmmAction::InsertVirtioFsDevice(ref other_fs_dev, _),
+            ) => fs_dev == other_fs_dev,
             (
                 &VmmAction::RescanBlockDevice(ref req, _),
                 &VmmAction::RescanBlockDevice(ref other_req, _),
             ) => req == other_req,
             (&VmmAction::StartMicroVm(_), &VmmAction::StartMicroVm(_)) => true,
             (&VmmAction::SendCtrlAltDel(_), &VmmAction::SendCtrlAltDel(_)) => true,
+            (&VmmAction::SendReset(_), &VmmAction::SendReset(_)) => true,
+            #[cfg(feature = "vsock")]
+            (
+                &VmmAction::SendAgentCommand(ref vsock_id, ref command, _),
+                &VmmAction::SendAgentCommand(ref other_vsock_id, ref other_command, _),
+            ) => vsock_id == other_vsock_id && command == other_command,
+            #[cfg(feature = "vsock")]
+            (
+                &VmmAction::FreezeFilesystems(ref vsock_id, _),
+                &VmmAction::FreezeFilesystems(ref other_vsock_id, _),
+            ) => vsock_id == other_vsock_id,
+            #[cfg(feature = "vsock")]
+            (
+                &VmmAction::ThawFilesystems(ref vsock_id, _),
+                &VmmAction::ThawFilesystems(ref other_vsock_id, _),
+            ) => vsock_id == other_vsock_id,
+            (&VmmAction::FlushBlockDevices(_), &VmmAction::FlushBlockDevices(_)) => true,
             (&VmmAction::FlushMetrics(_), &VmmAction::FlushMetrics(_)) => true,
+            (&VmmAction::GetDevices(_), &VmmAction::GetDevices(_)) => true,
+            #[cfg(target_arch = "aarch64")]
+            (&VmmAction::GetFdt(_), &VmmAction::GetFdt(_)) => true,
             _ => false,
         }
     }
@@ -2101,22 +3968,40 @@ impl PartialEq for VmmAction {
 ///                     number) or 2 (filter by syscall number and argument values).
 /// * `kvm_fd` - Provides the option of supplying an already existing raw file descriptor
 ///              associated with `/dev/kvm`.
+/// * `watchdog_timeout` - If set, Firecracker exits when the guest doesn't signal it is alive
+///                        within this interval once the microVM is started. x86_64 only.
+/// * `idle_monitor` - If set, Firecracker exits when neither the guest nor the API clients are
+///                    active for its timeout once the microVM is started.
+/// * `max_ramdisk_size_mib` - The host memory, in MiB, which all the ramdisk block devices can
+///                            take together.
 pub fn start_vmm_thread(
     api_shared_info: Arc<RwLock<InstanceInfo>>,
     api_event_fd: EventFd,
     from_api: Receiver<Box<VmmAction>>,
     seccomp_level: u32,
+    watchdog_timeout: Option<Duration>,
+    idle_monitor: Option<IdleMonitor>,
+    max_ramdisk_size_mib: u32,
 ) -> thread::JoinHandle<()> {
     thread::Builder::new()
         .name("fc_vmm".to_string())
         .spawn(move || {
+            signal_handler::register_stack_dump_thread("fc_vmm");
             // If this fails, consider it fatal. Use expect().
             let mut vmm = Vmm::new(api_shared_info, api_event_fd, from_api, seccomp_level)
                 .expect("Cannot create VMM");
+            vmm.watchdog = watchdog_timeout.map(Watchdog::new);
+            vmm.idle_monitor = idle_monitor;
+            vmm.block_device_configs
+                .set_max_ramdisk_size_mib(max_ramdisk_size_mib);
+            signal_handler::set_stack_dump_event(vmm.stack_dump_event.fd.as_raw_fd());
+            signal_handler::set_terminate_event(vmm.terminate_event.fd.as_raw_fd());
             match vmm.run_control() {
                 Ok(()) => {
+                    // The loop only ends once the API server is gone, in which case the main
+                    // thread picks the exit code.
                     info!("Gracefully terminated VMM control loop");
-                    vmm.stop(i32::from(FC_EXIT_CODE_OK))
+                    vmm.shutdown();
                 }
                 Err(e) => {
                     error!("Abruptly exited VMM control loop: {:?}", e);
@@ -2134,15 +4019,21 @@ mod tests {
     use super::*;
 
     use serde_json::Value;
+    use std::collections::BTreeMap;
     use std::fs::File;
     use std::io::BufRead;
     use std::io::BufReader;
-    use std::sync::atomic::AtomicUsize;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     use self::tempfile::NamedTempFile;
     use devices::virtio::ActivateResult;
     use net_util::MacAddr;
-    use vmm_config::machine_config::CpuFeaturesTemplate;
+    use vmm_config::machine_config::{
+        ApicMode, ClockSource, CpuFeaturesTemplate, CpuTopology, GicVersion, HighMmioConfig,
+        ReservedMemoryConfig, VcpuScheduling,
+    };
+    use vmm_config::net::RxCoalescingConfig;
     use vmm_config::{RateLimiterConfig, TokenBucketConfig};
 
     fn good_kernel_file() -> PathBuf {
@@ -2191,6 +4082,10 @@ mod tests {
                 kernel_file,
                 #[cfg(target_arch = "x86_64")]
                 cmdline_addr: GuestAddress(arch::x86_64::layout::CMDLINE_START),
+                rng_seed: false,
+                console: None,
+                // The test kernels don't need a root filesystem.
+                diskless: true,
             };
             self.configure_kernel(kernel_cfg);
         }
@@ -2287,6 +4182,7 @@ mod tests {
             state,
             id: "TEST_ID".to_string(),
             vmm_version: "1.0".to_string(),
+            stop_reason: None,
         }));
 
         let (_to_vmm, from_api) = channel();
@@ -2315,6 +4211,92 @@ mod tests {
         assert!(ep.get_device_handler(0).is_ok());
     }
 
+    #[test]
+    fn test_get_devices() {
+        struct FailedEpollHandler;
+
+        impl EpollHandler for FailedEpollHandler {
+            fn handle_event(
+                &mut self,
+                _: DeviceEventT,
+                _: u32,
+                _: EpollHandlerPayload,
+            ) -> std::result::Result<(), devices::Error> {
+                Ok(())
+            }
+
+            fn health(&self) -> DeviceHealth {
+                DeviceHealth::Failed(String::from("Read-only file system (os error 30)"))
+            }
+        }
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.get_devices().is_empty());
+
+        let f = NamedTempFile::new().unwrap();
+        let root_block_device = BlockDeviceConfig {
+            drive_id: String::from("root"),
+            path_on_host: f.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
+            rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
+        };
+        assert!(vmm.insert_block_device(root_block_device).is_ok());
+        let network_interface = NetworkInterfaceConfig {
+            iface_id: String::from("netif"),
+            host_dev_name: String::from("hostname"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
+            allow_mmds_requests: false,
+            mmds_mss_clamp: None,
+            tap: None,
+        };
+        assert!(vmm.insert_net_device(network_interface).is_ok());
+
+        // Devices which weren't attached yet are reported as healthy.
+        assert_eq!(
+            vmm.get_devices(),
+            vec![
+                DeviceInfo::new(
+                    String::from("root"),
+                    DeviceType::Block,
+                    &DeviceHealth::Healthy
+                ),
+                DeviceInfo::new(
+                    String::from("netif"),
+                    DeviceType::Net,
+                    &DeviceHealth::Healthy
+                ),
+            ]
+        );
+
+        // The health status of an attached device comes from its epoll handler.
+        let device_idx = vmm.epoll_context.device_handlers.len();
+        let (_, sender) = vmm.epoll_context.allocate_tokens(1);
+        assert!(sender.send(Box::new(FailedEpollHandler)).is_ok());
+        vmm.drive_handler_id_map
+            .insert(String::from("root"), device_idx);
+        let devices = vmm.get_devices();
+        assert_eq!(devices[0].health, "Failed");
+        assert_eq!(
+            devices[0].reason,
+            Some(String::from("Read-only file system (os error 30)"))
+        );
+        assert_eq!(devices[1].health, "Healthy");
+    }
+
     #[test]
     fn test_insert_block_device() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2326,7 +4308,13 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
         assert!(vmm
@@ -2341,7 +4329,13 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: true,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
         assert!(vmm
@@ -2356,7 +4350,13 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: true,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_err());
 
@@ -2369,7 +4369,13 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         assert!(vmm.insert_block_device(non_root).is_ok());
 
@@ -2381,7 +4387,13 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         assert!(vmm.insert_block_device(non_root).is_err());
 
@@ -2393,7 +4405,13 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: true,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         assert!(vmm.insert_block_device(root_block_device).is_err())
     }
@@ -2409,7 +4427,12 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         };
         assert!(vmm.insert_net_device(network_interface).is_ok());
@@ -2422,7 +4445,12 @@ mod tests {
             guest_mac: Some(mac),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         };
         assert!(vmm.insert_net_device(network_interface).is_ok());
@@ -2434,7 +4462,12 @@ mod tests {
             guest_mac: Some(mac),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         };
         assert!(vmm.insert_net_device(network_interface).is_err());
@@ -2447,12 +4480,93 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         };
         assert!(vmm.insert_net_device(network_interface).is_err());
     }
 
+    #[cfg(feature = "vhost-user-block")]
+    #[test]
+    fn test_insert_vhost_user_block_device() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+
+        let vhost_user_block = VhostUserBlockDeviceConfig {
+            id: String::from("blk"),
+            socket_path: String::from("/tmp/blk.sock"),
+        };
+        assert!(vmm
+            .insert_vhost_user_block_device(vhost_user_block.clone())
+            .is_ok());
+        assert!(!vmm.vhost_user_block_device_configs.is_empty());
+
+        // Test insert new device with the same socket path fails.
+        let other = VhostUserBlockDeviceConfig {
+            id: String::from("blk2"),
+            socket_path: String::from("/tmp/blk.sock"),
+        };
+        match vmm.insert_vhost_user_block_device(other) {
+            Err(VmmActionError::VhostUserBlockConfig(
+                ErrorKind::User,
+                VhostUserBlockError::SocketPathAlreadyInUse(_),
+            )) => (),
+            _ => panic!("Expected a socket path error."),
+        }
+
+        // Test that update post-boot fails.
+        vmm.set_instance_state(InstanceState::Running);
+        match vmm.insert_vhost_user_block_device(vhost_user_block) {
+            Err(VmmActionError::VhostUserBlockConfig(
+                ErrorKind::User,
+                VhostUserBlockError::UpdateNotAllowedPostBoot,
+            )) => (),
+            _ => panic!("Expected an update error."),
+        }
+    }
+
+    #[cfg(feature = "virtio-fs")]
+    #[test]
+    fn test_insert_virtio_fs_device() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let shared_dir = tempfile::tempdir().unwrap();
+
+        let virtio_fs = VirtioFsDeviceConfig {
+            id: String::from("fs"),
+            shared_dir: shared_dir.path().to_str().unwrap().to_string(),
+            tag: String::from("shared"),
+        };
+        assert!(vmm.insert_virtio_fs_device(virtio_fs.clone()).is_ok());
+        assert!(!vmm.virtio_fs_device_configs.is_empty());
+
+        // Test insert new device with the same tag fails.
+        let other = VirtioFsDeviceConfig {
+            id: String::from("fs2"),
+            ..virtio_fs.clone()
+        };
+        match vmm.insert_virtio_fs_device(other) {
+            Err(VmmActionError::VirtioFsConfig(
+                ErrorKind::User,
+                VirtioFsError::TagAlreadyInUse(_),
+            )) => (),
+            _ => panic!("Expected a tag error."),
+        }
+
+        // Test that update post-boot fails.
+        vmm.set_instance_state(InstanceState::Running);
+        match vmm.insert_virtio_fs_device(virtio_fs) {
+            Err(VmmActionError::VirtioFsConfig(
+                ErrorKind::User,
+                VirtioFsError::UpdateNotAllowedPostBoot,
+            )) => (),
+            _ => panic!("Expected an update error."),
+        }
+    }
+
     #[test]
     fn test_update_net_device() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2477,7 +4591,12 @@ mod tests {
                 ops: None,
             }),
             tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         })
         .unwrap();
@@ -2545,6 +4664,68 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn test_detach_net_device() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let network_interface = |iface_id: &str| NetworkInterfaceConfig {
+            iface_id: String::from(iface_id),
+            host_dev_name: String::from("hostname7"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
+            allow_mmds_requests: false,
+            mmds_mss_clamp: None,
+            tap: None,
+        };
+        let tap_path = Path::new("/sys/class/net/hostname7");
+
+        // Before boot, only the configuration is removed.
+        vmm.insert_net_device(network_interface("netif")).unwrap();
+        assert!(tap_path.exists());
+        vmm.detach_net_device("netif").unwrap();
+        assert!(vmm.network_interface_configs.iter().next().is_none());
+        assert!(!tap_path.exists());
+
+        vmm.insert_net_device(network_interface("netif")).unwrap();
+        vmm.init_guest_memory().unwrap();
+        vmm.default_kernel_config(None);
+        vmm.init_mmio_device_manager()
+            .expect("Cannot initialize mmio device manager");
+        vmm.attach_net_devices().unwrap();
+        vmm.set_instance_state(InstanceState::Running);
+        assert!(vmm
+            .mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .get_address("netif")
+            .is_some());
+
+        vmm.detach_net_device("netif").unwrap();
+        assert!(vmm
+            .mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .get_address("netif")
+            .is_none());
+        assert!(vmm.net_handler_id_map.is_empty());
+        assert!(vmm.network_interface_configs.iter().next().is_none());
+        assert!(vmm.get_devices().is_empty());
+        assert!(!tap_path.exists());
+
+        // Error case: the interface is already detached.
+        match vmm.detach_net_device("netif") {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::NotFound,
+                NetworkInterfaceError::DeviceIdNotFound,
+            )) => (),
+            _ => unreachable!(),
+        }
+    }
+
     #[test]
     #[allow(clippy::cyclomatic_complexity)]
     fn test_machine_configuration() {
@@ -2567,6 +4748,22 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2579,6 +4776,22 @@ mod tests {
             mem_size_mib: Some(256),
             ht_enabled: None,
             cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2592,6 +4805,22 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2603,6 +4832,22 @@ mod tests {
             mem_size_mib: Some(0),
             ht_enabled: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2618,6 +4863,22 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.ht_enabled, Some(false));
@@ -2628,31 +4889,325 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(2));
         assert_eq!(vmm.vm_config.ht_enabled, Some(true));
         assert_eq!(vmm.vm_config.cpu_template, Some(CpuFeaturesTemplate::T2));
 
-        // 3. Test update vm configuration after boot.
-        vmm.set_instance_state(InstanceState::Running);
+        // 3. Test the CPU topology.
+        // 1 socket, 2 cores per socket and 2 threads per core makes 4 vCPUs.
+        let topology = CpuTopology {
+            sockets: 1,
+            cores_per_socket: 2,
+            threads_per_core: 2,
+        };
         let machine_config = VmConfig {
-            vcpu_count: Some(2),
+            vcpu_count: Some(4),
             mem_size_mib: None,
-            ht_enabled: Some(true),
-            cpu_template: Some(CpuFeaturesTemplate::T2),
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: Some(topology),
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
         };
-        assert!(vmm.set_vm_configuration(machine_config).is_err());
-    }
-
-    #[test]
-    fn new_epoll_context_test() {
-        assert!(EpollContext::new().is_ok());
-    }
-
-    #[test]
-    fn enable_disable_stdin_test() {
-        let mut ep = EpollContext::new().unwrap();
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.vcpu_count, Some(4));
+        assert_eq!(vmm.vm_config.ht_enabled, Some(true));
+        assert_eq!(vmm.vm_config.cpu_topology, Some(topology));
+        // The topology must match the vCPU count.
+        let machine_config = VmConfig {
+            vcpu_count: Some(6),
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        match vmm.set_vm_configuration(machine_config) {
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::InvalidCpuTopology,
+            )) => (),
+            _ => assert!(false),
+        }
+        assert_eq!(vmm.vm_config.vcpu_count, Some(4));
+        // The hyperthreading flag can't contradict the topology.
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: Some(false),
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_err());
+        assert_eq!(vmm.vm_config.ht_enabled, Some(true));
+
+        // Prefaulting the guest memory is opt-in and unrelated updates don't reset it.
+        assert!(vmm.vm_config.prefault_memory.is_none());
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: Some(true),
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: Some(128),
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.prefault_memory, Some(true));
+
+        // Zeroing the guest memory is opt-in as well.
+        assert!(vmm.vm_config.zero_memory.is_none());
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: Some(true),
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.zero_memory, Some(true));
+
+        // The NUMA node must exist on the host.
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: Some(u32::max_value()),
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        match vmm.set_vm_configuration(machine_config) {
+            Err(VmmActionError::MachineConfig(ErrorKind::User, VmConfigError::InvalidNumaNode)) => {
+            }
+            _ => assert!(false),
+        }
+        assert!(vmm.vm_config.numa_node.is_none());
+        if std::path::Path::new("/sys/devices/system/node/node0").exists() {
+            let machine_config = VmConfig {
+                vcpu_count: None,
+                mem_size_mib: None,
+                ht_enabled: None,
+                cpu_template: None,
+                cpu_topology: None,
+                prefault_memory: None,
+                zero_memory: None,
+                numa_node: Some(0),
+                vcpu_affinity: None,
+                irq_affinity: None,
+                vcpu_scheduling: None,
+                high_mmio: None,
+                reserved_memory: None,
+                gic_version: None,
+                serial_enabled: None,
+                rtc_base_time: None,
+                clock_source: None,
+                sev_enabled: None,
+                apic_mode: None,
+                guest_panic_action: None,
+            };
+            assert!(vmm.set_vm_configuration(machine_config).is_ok());
+            assert_eq!(vmm.vm_config.numa_node, Some(0));
+        }
+        assert!(vmm.init_guest_memory().is_ok());
+
+        // 4. Test update vm configuration after boot.
+        vmm.set_instance_state(InstanceState::Running);
+        let machine_config = VmConfig {
+            vcpu_count: Some(2),
+            mem_size_mib: None,
+            ht_enabled: Some(true),
+            cpu_template: Some(CpuFeaturesTemplate::T2),
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_err());
+    }
+
+    #[test]
+    fn test_touch_guest_memory() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let addrs = [0x0, 0x1ff8, 0x4000, 0x4ff8];
+        let guest_memory =
+            GuestMemory::new(&[(GuestAddress(0x0), 0x2000), (GuestAddress(0x4000), 0x1000)])
+                .unwrap();
+        // The memory holds data left behind, as if it was handed over from a pool.
+        for addr in addrs.iter() {
+            guest_memory
+                .write_obj_at_addr(0xaa55_aa55_aa55_aa55u64, GuestAddress(*addr))
+                .unwrap();
+        }
+
+        // The memory is only zeroed on request.
+        vmm.vm_config.prefault_memory = Some(true);
+        vmm.touch_guest_memory(&guest_memory);
+        for addr in addrs.iter() {
+            assert_eq!(
+                guest_memory
+                    .read_obj_from_addr::<u64>(GuestAddress(*addr))
+                    .unwrap(),
+                0xaa55_aa55_aa55_aa55
+            );
+        }
+
+        vmm.vm_config.zero_memory = Some(true);
+        vmm.touch_guest_memory(&guest_memory);
+        for addr in addrs.iter() {
+            assert_eq!(
+                guest_memory
+                    .read_obj_from_addr::<u64>(GuestAddress(*addr))
+                    .unwrap(),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn new_epoll_context_test() {
+        assert!(EpollContext::new().is_ok());
+    }
+
+    #[test]
+    fn enable_disable_stdin_test() {
+        let mut ep = EpollContext::new().unwrap();
         // enabling stdin should work
         assert!(ep.enable_stdin_event().is_ok());
 
@@ -2715,6 +5270,72 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_send_reset() {
+        // Error case: the reset is not allowed before boot.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.send_reset() {
+            Err(VmmActionError::SendReset(
+                ErrorKind::User,
+                I8042DeviceError::ResetNotAllowedPreBoot,
+            )) => (),
+            _ => panic!("Expected a pre-boot reset error."),
+        }
+
+        // Register the reset event the same way as when starting the microVM.
+        vmm.set_instance_state(InstanceState::Running);
+        let reset_evt = vmm
+            .legacy_device_manager
+            .i8042
+            .lock()
+            .unwrap()
+            .get_reset_evt_clone()
+            .unwrap();
+        vmm.i8042_reset_evt = Some(
+            vmm.epoll_context
+                .add_event(reset_evt, EpollDispatch::I8042Reset)
+                .unwrap(),
+        );
+        assert!(vmm.send_reset().is_ok());
+        // The VMM requested the reset, not the guest.
+        assert_eq!(
+            vmm.shared_info.read().unwrap().stop_reason,
+            Some(StopReason::VmmStop)
+        );
+
+        // The VMM wakes up for stopping the microVM.
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 10];
+        let num_events = epoll::wait(vmm.epoll_context.epoll_raw_fd, 0, &mut events[..]).unwrap();
+        assert_eq!(num_events, 1);
+        assert_eq!(
+            vmm.epoll_context.dispatch_table[events[0].data as usize],
+            Some(EpollDispatch::I8042Reset)
+        );
+        // The recorded reason sticks.
+        assert_eq!(
+            vmm.record_stop_reason(StopReason::GuestReset),
+            StopReason::VmmStop
+        );
+    }
+
+    #[cfg(feature = "vsock")]
+    #[test]
+    fn test_send_agent_command() {
+        use futures::Future;
+
+        // The vsock device has no guest agent channel.
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        let (sender, receiver) = oneshot::channel();
+        vmm.send_agent_command("vsock0", &AgentCommand::Flush, sender, None);
+        match receiver.wait() {
+            Ok(Err(VmmActionError::GuestAgent(
+                ErrorKind::User,
+                guest_agent::Error::NoChannel(ref id),
+            ))) => assert_eq!(id, "vsock0"),
+            _ => panic!("Expected a missing channel error."),
+        }
+    }
+
     #[test]
     fn test_kvm_context() {
         use std::os::unix::fs::MetadataExt;
@@ -2744,10 +5365,89 @@ mod tests {
             cmdline_addr: dummy_addr,
             cmdline: kernel_cmdline::Cmdline::new(10),
             kernel_file: tempfile::tempfile().unwrap(),
+            rng_seed: false,
+            console: None,
+            diskless: true,
         });
         assert!(vmm.check_health().is_ok());
     }
 
+    #[test]
+    fn test_check_rootfs() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let kernel_file = NamedTempFile::new().unwrap();
+        let kernel_path = String::from(kernel_file.path().to_str().unwrap());
+        assert!(vmm
+            .configure_boot_source(kernel_path.clone(), None, None, false, None, false)
+            .is_ok());
+
+        // Neither a root block device nor a `root=` boot argument.
+        match vmm.start_microvm() {
+            Err(VmmActionError::StartMicrovm(
+                ErrorKind::User,
+                StartMicrovmError::MissingRootfs,
+            )) => {}
+            _ => panic!("Starting without a root filesystem should fail."),
+        }
+        assert_eq!(
+            vmm.shared_info.read().unwrap().state,
+            InstanceState::Uninitialized
+        );
+        // The init arguments don't count.
+        assert!(vmm
+            .configure_boot_source(
+                kernel_path.clone(),
+                Some(String::from("reboot=k -- root=/dev/vda")),
+                None,
+                false,
+                None,
+                false
+            )
+            .is_ok());
+        assert!(vmm.check_health().is_err());
+
+        // The root filesystem is set by the boot arguments.
+        assert!(vmm
+            .configure_boot_source(
+                kernel_path.clone(),
+                Some(String::from("reboot=k root=/dev/nfs")),
+                None,
+                false,
+                None,
+                false
+            )
+            .is_ok());
+        assert!(vmm.check_health().is_ok());
+
+        // The guest doesn't need a root filesystem.
+        assert!(vmm
+            .configure_boot_source(kernel_path.clone(), None, None, false, None, true)
+            .is_ok());
+        assert!(vmm.check_health().is_ok());
+
+        // The root block device provides it.
+        assert!(vmm
+            .configure_boot_source(kernel_path, None, None, false, None, false)
+            .is_ok());
+        let root_file = NamedTempFile::new().unwrap();
+        let root_block_device = BlockDeviceConfig {
+            drive_id: String::from("root"),
+            path_on_host: root_file.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
+            rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
+        };
+        assert!(vmm.insert_block_device(root_block_device).is_ok());
+        assert!(vmm.check_health().is_ok());
+    }
+
     #[test]
     fn test_microvm_start() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2756,10 +5456,164 @@ mod tests {
         // The kernel provided contains  "return 0" which will make the
         // advanced seccomp filter return bad syscall so we disable it.
         vmm.seccomp_level = seccomp::SECCOMP_LEVEL_NONE;
+        // The milestones are recorded apart from the global metrics, which the tests running
+        // in parallel update too.
+        let boot_metrics: &'static BootMetrics = Box::leak(Box::new(BootMetrics::default()));
+        boot_metrics.set_process_start_us(get_time_us());
+        vmm.boot_metrics = boot_metrics;
         let res = vmm.start_microvm();
         let stdin_handle = io::stdin();
         stdin_handle.lock().set_canon_mode().unwrap();
         assert!(res.is_ok());
+
+        // The boot sequence reached its milestones in order.
+        let kernel_loaded_us = boot_metrics.kernel_loaded_us.get();
+        let vcpus_created_us = boot_metrics.vcpus_created_us.get();
+        let devices_registered_us = boot_metrics.devices_registered_us.get();
+        assert!(kernel_loaded_us > 0);
+        assert!(vcpus_created_us >= kernel_loaded_us);
+        assert!(devices_registered_us >= vcpus_created_us);
+        // The boot vCPU reaches the last milestone on its own thread.
+        let mut guest_started_us = boot_metrics.guest_started_us.get();
+        for _ in 0..100 {
+            if guest_started_us > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            guest_started_us = boot_metrics.guest_started_us.get();
+        }
+        assert!(guest_started_us >= devices_registered_us);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_snapshot_round_trip() {
+        // The microVMs are configured alike, with or without their root block device.
+        let block_file = NamedTempFile::new().unwrap();
+        let configured_vmm = |with_root: bool| {
+            let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+            vmm.default_kernel_config(Some(good_kernel_file()));
+            vmm.seccomp_level = seccomp::SECCOMP_LEVEL_NONE;
+            vmm.vm_config.mem_size_mib = Some(32);
+            if with_root {
+                let root_block_device = BlockDeviceConfig {
+                    drive_id: String::from("root"),
+                    path_on_host: block_file.path().to_path_buf(),
+                    is_root_device: true,
+                    partuuid: None,
+                    is_read_only: false,
+                    direct_io: false,
+                    ramdisk_size_mib: None,
+                    serial: None,
+                    rate_limiter: None,
+                    io_retry: None,
+                    virtio_feature_mask: None,
+                    max_in_flight_requests: None,
+                };
+                assert!(vmm.insert_block_device(root_block_device).is_ok());
+            }
+            // The vCPUs stay out of the guest, which would otherwise reset right away.
+            vmm.vcpus_paused.store(true, Ordering::SeqCst);
+            vmm
+        };
+        let marker_addr = GuestAddress(0x50_0000);
+        let kernel_addr = GuestAddress(arch::x86_64::layout::HIMEM_START);
+
+        let mut vmm = configured_vmm(true);
+        assert!(vmm.start_microvm().is_ok());
+        vmm.guest_memory
+            .as_ref()
+            .unwrap()
+            .write_obj_at_addr(0x1234_5678_9abc_def0u64, marker_addr)
+            .unwrap();
+        let kernel_start: u64 = vmm
+            .guest_memory
+            .as_ref()
+            .unwrap()
+            .read_obj_from_addr(kernel_addr)
+            .unwrap();
+        let device_states = vmm
+            .mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .virtio_device_states();
+        let snapshot_file = NamedTempFile::new().unwrap();
+        let snapshot_config = SnapshotConfig {
+            snapshot_path: snapshot_file.path().to_path_buf(),
+        };
+        assert!(vmm.create_snapshot(snapshot_config.clone()).is_ok());
+        assert_eq!(
+            vmm.shared_info.read().unwrap().state,
+            InstanceState::Running
+        );
+
+        // The restored microVM holds the memory and the devices of the saved one.
+        let mut vmm = configured_vmm(true);
+        assert!(vmm.load_snapshot(snapshot_config.clone()).is_ok());
+        assert_eq!(
+            vmm.shared_info.read().unwrap().state,
+            InstanceState::Running
+        );
+        let guest_memory = vmm.guest_memory.as_ref().unwrap();
+        assert_eq!(
+            guest_memory.read_obj_from_addr::<u64>(marker_addr).unwrap(),
+            0x1234_5678_9abc_def0
+        );
+        assert_eq!(
+            guest_memory.read_obj_from_addr::<u64>(kernel_addr).unwrap(),
+            kernel_start
+        );
+        let device_manager = vmm.mmio_device_manager.as_ref().unwrap();
+        assert_eq!(device_manager.virtio_device_ids(), vec!["root"]);
+        assert_eq!(device_manager.virtio_device_states(), device_states);
+
+        // Error case: the microVM is already running.
+        match vmm.load_snapshot(snapshot_config.clone()) {
+            Err(VmmActionError::StartMicrovm(
+                ErrorKind::User,
+                StartMicrovmError::MicroVMAlreadyRunning,
+            )) => (),
+            _ => panic!("Expected a microVM already running error."),
+        }
+
+        // Error case: the vCPU count differs from the saved one.
+        let mut vmm = configured_vmm(true);
+        vmm.vm_config.vcpu_count = Some(2);
+        match vmm.load_snapshot(snapshot_config.clone()) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, snapshot::Error::VcpuCount(1))) => (),
+            _ => panic!("Expected a vCPU count error."),
+        }
+
+        // Error case: the memory size differs from the saved one.
+        let mut vmm = configured_vmm(true);
+        vmm.vm_config.mem_size_mib = Some(64);
+        match vmm.load_snapshot(snapshot_config.clone()) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, snapshot::Error::MemoryMismatch)) => (),
+            _ => panic!("Expected a memory mismatch error."),
+        }
+
+        // Error case: the saved root block device is not configured.
+        let mut vmm = configured_vmm(false);
+        match vmm.load_snapshot(snapshot_config) {
+            Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                snapshot::Error::UnknownDevice(ref id),
+            )) if id == "root" => (),
+            _ => panic!("Expected an unknown device error."),
+        }
+
+        // Error case: the snapshot file doesn't exist.
+        let mut vmm = configured_vmm(true);
+        let missing_config = SnapshotConfig {
+            snapshot_path: PathBuf::from("/foo/bar/snapshot"),
+        };
+        match vmm.load_snapshot(missing_config) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, snapshot::Error::Read(_))) => (),
+            _ => panic!("Expected a read error."),
+        }
+
+        let stdin_handle = io::stdin();
+        stdin_handle.lock().set_canon_mode().unwrap();
     }
 
     #[test]
@@ -2780,6 +5634,47 @@ mod tests {
         assert_eq!(vmm.is_instance_initialized(), true);
     }
 
+    #[test]
+    fn test_publish_device_info() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let block_file = NamedTempFile::new().unwrap();
+        let root_block_device = BlockDeviceConfig {
+            drive_id: String::from("root"),
+            path_on_host: block_file.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
+            rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
+        };
+        assert!(vmm.insert_block_device(root_block_device).is_ok());
+        assert!(vmm.init_guest_memory().is_ok());
+        vmm.default_kernel_config(None);
+        vmm.init_mmio_device_manager()
+            .expect("Cannot initialize mmio device manager");
+        assert!(vmm.attach_block_devices().is_ok());
+
+        vmm.publish_device_info();
+        let device_manager = vmm.mmio_device_manager.as_ref().unwrap();
+        let mmds = mmds::MMDS.lock().unwrap();
+        assert_eq!(
+            mmds.get_value("/firecracker/devices/root/addr".to_string()),
+            Ok(vec![format!(
+                "0x{:x}",
+                device_manager.get_address("root").unwrap()
+            )])
+        );
+        assert_eq!(
+            mmds.get_value("/firecracker/devices/root/irq".to_string()),
+            Ok(vec![device_manager.get_irq("root").unwrap().to_string()])
+        );
+    }
+
     #[test]
     fn test_attach_block_devices() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2792,7 +5687,13 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         // Test that creating a new block device returns the correct output.
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
@@ -2814,7 +5715,13 @@ mod tests {
             is_root_device: true,
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         // Test that creating a new block device returns the correct output.
@@ -2839,7 +5746,13 @@ mod tests {
             is_root_device: false,
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         // Test that creating a new block device returns the correct output.
@@ -2896,7 +5809,15 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_coalescing: Some(RxCoalescingConfig {
+                max_frames: 8,
+                timeout_us: 100,
+            }),
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         };
 
@@ -2908,6 +5829,58 @@ mod tests {
         assert!(vmm.attach_net_devices().is_err());
     }
 
+    #[test]
+    fn test_attach_console_device() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.init_guest_memory().is_ok());
+        vmm.default_kernel_config(None);
+        vmm.init_mmio_device_manager()
+            .expect("Cannot initialize mmio device manager");
+
+        // Without a console, the parameter is omitted.
+        assert!(vmm.attach_console_device().is_ok());
+        assert!(!vmm.get_kernel_cmdline_str().contains("console="));
+
+        // The console replaces the one from the boot arguments.
+        assert!(vmm
+            .kernel_config
+            .as_mut()
+            .unwrap()
+            .cmdline
+            .insert("console", "ttyS1")
+            .is_ok());
+        vmm.kernel_config.as_mut().unwrap().console = Some(ConsoleDevice::Virtio);
+        assert!(vmm.attach_console_device().is_ok());
+        let cmdline = vmm.get_kernel_cmdline_str();
+        assert!(cmdline.contains(" console=hvc0"));
+        assert!(!cmdline.contains("console=ttyS1"));
+        assert!(cmdline.contains("8250.nr_uarts=0"));
+        assert!(vmm
+            .mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .get_address("console")
+            .is_some());
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.init_guest_memory().is_ok());
+        vmm.default_kernel_config(None);
+        vmm.init_mmio_device_manager()
+            .expect("Cannot initialize mmio device manager");
+        vmm.kernel_config.as_mut().unwrap().console = Some(ConsoleDevice::Serial);
+        assert!(vmm.attach_console_device().is_ok());
+        let cmdline = vmm.get_kernel_cmdline_str();
+        assert!(cmdline.contains(" console=ttyS0"));
+        // The default boot arguments disable the serial port the guest needs.
+        assert!(!cmdline.contains("8250.nr_uarts"));
+        assert!(vmm
+            .mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .get_address("console")
+            .is_none());
+    }
+
     #[test]
     fn test_init_devices() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2925,7 +5898,7 @@ mod tests {
 
         // Test invalid kernel path.
         assert!(vmm
-            .configure_boot_source(String::from("dummy-path"), None)
+            .configure_boot_source(String::from("dummy-path"), None, None, false, None, false)
             .is_err());
 
         // Test valid kernel path and invalid cmdline.
@@ -2933,21 +5906,137 @@ mod tests {
         let kernel_path = String::from(kernel_file.path().to_path_buf().to_str().unwrap());
         let invalid_cmdline = String::from_utf8(vec![b'X'; arch::CMDLINE_MAX_SIZE + 1]).unwrap();
         assert!(vmm
-            .configure_boot_source(kernel_path.clone(), Some(invalid_cmdline))
+            .configure_boot_source(
+                kernel_path.clone(),
+                Some(invalid_cmdline),
+                None,
+                false,
+                None,
+                false
+            )
             .is_err());
 
         // Test valid configuration.
-        assert!(vmm.configure_boot_source(kernel_path.clone(), None).is_ok());
         assert!(vmm
-            .configure_boot_source(kernel_path.clone(), Some(String::from("reboot=k")))
+            .configure_boot_source(kernel_path.clone(), None, None, false, None, false)
             .is_ok());
+        assert!(vmm
+            .configure_boot_source(
+                kernel_path.clone(),
+                Some(String::from("reboot=k")),
+                None,
+                false,
+                None,
+                false
+            )
+            .is_ok());
+        assert!(vmm
+            .configure_boot_source(kernel_path.clone(), None, None, true, None, false)
+            .is_ok());
+        assert!(vmm.kernel_config.as_ref().unwrap().rng_seed);
 
-        // Test valid configuration after boot (should fail).
-        vmm.set_instance_state(InstanceState::Running);
+        // Test invalid maximum lengths of the cmdline.
         assert!(vmm
-            .configure_boot_source(kernel_path.clone(), None)
+            .configure_boot_source(kernel_path.clone(), None, Some(0), false, None, false)
             .is_err());
-    }
+        assert!(vmm
+            .configure_boot_source(
+                kernel_path.clone(),
+                None,
+                Some(arch::CMDLINE_MAX_SIZE + 1),
+                false,
+                None,
+                false
+            )
+            .is_err());
+
+        // Test a cmdline which doesn't fit in the configured maximum length.
+        assert!(vmm
+            .configure_boot_source(
+                kernel_path.clone(),
+                Some(String::from("reboot=k")),
+                Some(8),
+                false,
+                None,
+                false
+            )
+            .is_err());
+
+        // Test valid configuration after boot (should fail).
+        vmm.set_instance_state(InstanceState::Running);
+        assert!(vmm
+            .configure_boot_source(kernel_path.clone(), None, None, false, None, false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_append_boot_args() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+
+        // Test appending before the boot source is configured.
+        assert!(vmm.append_boot_args(String::from("foo=bar")).is_err());
+
+        let kernel_file = NamedTempFile::new().expect("Failed to create temporary kernel file.");
+        let kernel_path = String::from(kernel_file.path().to_path_buf().to_str().unwrap());
+        assert!(vmm
+            .configure_boot_source(
+                kernel_path,
+                Some(String::from("reboot=k")),
+                Some(64),
+                false,
+                None,
+                false
+            )
+            .is_ok());
+
+        // Test valid boot args.
+        assert!(vmm.append_boot_args(String::from("foo=bar quiet")).is_ok());
+        assert_eq!(vmm.get_kernel_cmdline_str(), "reboot=k foo=bar quiet");
+
+        // Test reserved keys, invalid characters and overflowing the capacity.
+        match vmm.append_boot_args(String::from("bar=baz root=/dev/vdb")) {
+            Err(VmmActionError::BootSource(
+                ErrorKind::User,
+                BootSourceConfigError::ReservedBootArg(ref key),
+            )) => assert_eq!(key, "root"),
+            _ => unreachable!(),
+        }
+        assert!(vmm.append_boot_args(String::from("foo=\u{e9}")).is_err());
+        assert!(vmm
+            .append_boot_args(String::from_utf8(vec![b'X'; 64]).unwrap())
+            .is_err());
+        // The failed appends left the cmdline untouched.
+        assert_eq!(vmm.get_kernel_cmdline_str(), "reboot=k foo=bar quiet");
+
+        // Test appending after boot (should fail).
+        vmm.set_instance_state(InstanceState::Running);
+        assert!(vmm.append_boot_args(String::from("baz")).is_err());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_configure_cmdline_max_size() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let kernel_file = NamedTempFile::new().expect("Failed to create temporary kernel file.");
+        let kernel_path = String::from(kernel_file.path().to_path_buf().to_str().unwrap());
+
+        assert!(vmm
+            .configure_boot_source(kernel_path, None, Some(8192), false, None, false)
+            .is_ok());
+        // Fill the cmdline past 4096 bytes, as attaching many devices would.
+        let kernel_config = vmm.kernel_config.as_mut().unwrap();
+        for _ in 0..64 {
+            assert!(kernel_config
+                .cmdline
+                .insert_str(String::from_utf8(vec![b'X'; 100]).unwrap())
+                .is_ok());
+        }
+        assert!(kernel_config.cmdline.as_str().len() > 4096);
+        assert!(kernel_config
+            .cmdline
+            .insert_str(String::from_utf8(vec![b'X'; 2048]).unwrap())
+            .is_err());
+    }
 
     #[test]
     fn test_rescan() {
@@ -2964,7 +6053,13 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         let non_root_block_device = BlockDeviceConfig {
             drive_id: scratch_id.clone(),
@@ -2972,7 +6067,13 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: true,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
@@ -3060,6 +6161,93 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_flush_block_devices() {
+        // Counts the flushes, and fails them if asked to.
+        struct FlushEpollHandler {
+            flush_count: Arc<AtomicUsize>,
+            fail: bool,
+        }
+
+        impl EpollHandler for FlushEpollHandler {
+            fn handle_event(
+                &mut self,
+                device_event: DeviceEventT,
+                _: u32,
+                _: EpollHandlerPayload,
+            ) -> std::result::Result<(), devices::Error> {
+                assert_eq!(device_event, virtio::block::FLUSH_EVENT);
+                self.flush_count.fetch_add(1, Ordering::SeqCst);
+                if self.fail {
+                    return Err(devices::Error::IoError(std::io::Error::from_raw_os_error(
+                        libc::EIO,
+                    )));
+                }
+                Ok(())
+            }
+        }
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let drive_files = vec![NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap()];
+        for (i, drive_id) in ["root", "scratch"].iter().enumerate() {
+            let block_device = BlockDeviceConfig {
+                drive_id: drive_id.to_string(),
+                path_on_host: drive_files[i].path().to_path_buf(),
+                is_root_device: i == 0,
+                partuuid: None,
+                is_read_only: false,
+                direct_io: false,
+                ramdisk_size_mib: None,
+                serial: None,
+                rate_limiter: None,
+                io_retry: None,
+                virtio_feature_mask: None,
+                max_in_flight_requests: None,
+            };
+            assert!(vmm.insert_block_device(block_device).is_ok());
+        }
+
+        // Error case: flush before boot.
+        match vmm.flush_block_devices() {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::OperationNotAllowedPreBoot,
+            )) => (),
+            _ => panic!("Flushing should not be allowed before boot."),
+        }
+
+        vmm.set_instance_state(InstanceState::Running);
+        // The devices weren't attached yet, so there is nothing to flush.
+        assert!(vmm.flush_block_devices().is_ok());
+
+        let flush_count = Arc::new(AtomicUsize::new(0));
+        for drive_id in &["root", "scratch"] {
+            let device_idx = vmm.epoll_context.device_handlers.len();
+            let (_, sender) = vmm.epoll_context.allocate_tokens(1);
+            vmm.drive_handler_id_map
+                .insert(drive_id.to_string(), device_idx);
+            let handler = FlushEpollHandler {
+                flush_count: flush_count.clone(),
+                fail: *drive_id == "scratch",
+            };
+            assert!(sender.send(Box::new(handler)).is_ok());
+        }
+
+        // All the devices are flushed, even if some of them fail.
+        match vmm.flush_block_devices() {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::Internal,
+                DriveError::BlockDeviceFlushFailed(ref drive_ids),
+            )) => assert_eq!(drive_ids, &vec![String::from("scratch")]),
+            _ => panic!("Flushing the scratch drive should fail."),
+        }
+        assert_eq!(flush_count.load(Ordering::SeqCst), 2);
+
+        // Stopping the VMM flushes all the devices too, and only logs the failures.
+        vmm.shutdown();
+        assert_eq!(flush_count.load(Ordering::SeqCst), 4);
+    }
+
     #[test]
     fn test_init_logger_from_api() {
         // Error case: update after instance is running
@@ -3071,6 +6259,7 @@ mod tests {
             level: LoggerLevel::Warning,
             show_level: true,
             show_log_origin: true,
+            max_log_size: None,
             #[cfg(target_arch = "x86_64")]
             options: Value::Array(vec![]),
         };
@@ -3088,6 +6277,7 @@ mod tests {
             level: LoggerLevel::Warning,
             show_level: false,
             show_log_origin: false,
+            max_log_size: None,
             #[cfg(target_arch = "x86_64")]
             options: Value::Array(vec![]),
         };
@@ -3100,6 +6290,7 @@ mod tests {
             level: LoggerLevel::Warning,
             show_level: false,
             show_log_origin: false,
+            max_log_size: None,
             #[cfg(target_arch = "x86_64")]
             options: Value::Array(vec![Value::String("foobar".to_string())]),
         };
@@ -3114,6 +6305,7 @@ mod tests {
             level: LoggerLevel::Info,
             show_level: true,
             show_log_origin: true,
+            max_log_size: Some(1 << 20),
             #[cfg(target_arch = "x86_64")]
             options: Value::Array(vec![Value::String("LogDirtyPages".to_string())]),
         };
@@ -3200,6 +6392,527 @@ mod tests {
             .is_ok());
     }
 
+    #[test]
+    fn test_create_vcpus_with_affinity() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.default_kernel_config(None);
+        let host_cpu = *numa::allowed_cpus().unwrap().last().unwrap();
+
+        let mut vcpu_affinity = BTreeMap::new();
+        vcpu_affinity.insert(1, host_cpu);
+        let machine_config = VmConfig {
+            vcpu_count: Some(2),
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: Some(vcpu_affinity.clone()),
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+
+        // Error case: there is no vCPU with the index 2.
+        vcpu_affinity.insert(2, host_cpu);
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: Some(vcpu_affinity),
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        match vmm.set_vm_configuration(machine_config) {
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::InvalidVcpuAffinityIndex(2),
+            )) => (),
+            _ => assert!(false),
+        }
+
+        // Error case: the host CPU doesn't exist.
+        let mut vcpu_affinity = BTreeMap::new();
+        vcpu_affinity.insert(0, 1 << 16);
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: Some(vcpu_affinity),
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        match vmm.set_vm_configuration(machine_config) {
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::InvalidHostCpu(65536),
+            )) => (),
+            _ => assert!(false),
+        }
+
+        // Error case: shrinking the vCPU count would leave a pinned vCPU behind.
+        let machine_config = VmConfig {
+            vcpu_count: Some(1),
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_err());
+        assert_eq!(vmm.vm_config.vcpu_count, Some(2));
+
+        assert!(vmm.init_guest_memory().is_ok());
+        #[cfg(target_arch = "x86_64")]
+        vmm.vm
+            .setup_irqchip()
+            .expect("Cannot create IRQCHIP or PIT");
+        let vcpus = vmm
+            .create_vcpus(GuestAddress(0x0), TimestampUs::default())
+            .unwrap();
+        assert!(vcpus[0].cpu_affinity().is_empty());
+        assert_eq!(vcpus[1].cpu_affinity(), &[host_cpu]);
+    }
+
+    #[test]
+    fn test_set_irq_affinity() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+
+        // An empty map steers nothing.
+        let machine_config = VmConfig {
+            vcpu_count: Some(2),
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: Some(BTreeMap::new()),
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.vcpu_count, Some(2));
+
+        // Error case: the IRQs can't be steered.
+        let mut irq_affinity = BTreeMap::new();
+        irq_affinity.insert(String::from("net0"), 1);
+        let machine_config = VmConfig {
+            vcpu_count: Some(1),
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: Some(irq_affinity),
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        match vmm.set_vm_configuration(machine_config) {
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::IrqAffinityNotSupported,
+            )) => (),
+            _ => assert!(false),
+        }
+        assert_eq!(vmm.vm_config.vcpu_count, Some(2));
+        assert!(vmm.vm_config.irq_affinity.is_none());
+    }
+
+    #[test]
+    fn test_set_vcpu_scheduling() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let fifo = VcpuScheduling {
+            policy: VcpuSchedPolicy::Fifo,
+            priority: Some(10),
+            runtime_ns: None,
+            deadline_ns: None,
+            period_ns: None,
+            strict: false,
+        };
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: Some(fifo),
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.vcpu_scheduling, Some(fifo));
+
+        // Error case: the parameters don't match the policy.
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: Some(VcpuScheduling {
+                priority: None,
+                ..fifo
+            }),
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        match vmm.set_vm_configuration(machine_config) {
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::InvalidVcpuScheduling,
+            )) => (),
+            _ => assert!(false),
+        }
+
+        // Error case: deadline threads can't be pinned.
+        let mut vcpu_affinity = BTreeMap::new();
+        vcpu_affinity.insert(0, *numa::allowed_cpus().unwrap().last().unwrap());
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: Some(vcpu_affinity),
+            irq_affinity: None,
+            vcpu_scheduling: Some(VcpuScheduling {
+                policy: VcpuSchedPolicy::Deadline,
+                priority: None,
+                runtime_ns: Some(500_000),
+                deadline_ns: Some(1_000_000),
+                period_ns: Some(1_000_000),
+                strict: false,
+            }),
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_err());
+        assert_eq!(vmm.vm_config.vcpu_scheduling, Some(fifo));
+        assert!(vmm.vm_config.vcpu_affinity.is_none());
+    }
+
+    #[test]
+    fn test_set_high_mmio() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let high_mmio = HighMmioConfig {
+            name: String::from("pci64"),
+            base: 1 << 36,
+            size_mib: 1024,
+        };
+        let mut machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: Some(high_mmio.clone()),
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        };
+        assert!(vmm.set_vm_configuration(machine_config.clone()).is_ok());
+        assert_eq!(vmm.vm_config.high_mmio, Some(high_mmio.clone()));
+
+        // Error cases: the range is empty, not aligned, overflows or overlaps with the guest
+        // memory or the low MMIO range.
+        let invalid_ranges = [
+            HighMmioConfig {
+                size_mib: 0,
+                ..high_mmio.clone()
+            },
+            HighMmioConfig {
+                base: (1 << 36) + 1,
+                ..high_mmio.clone()
+            },
+            HighMmioConfig {
+                base: u64::max_value() & !0xfff,
+                ..high_mmio.clone()
+            },
+            HighMmioConfig {
+                base: 0,
+                ..high_mmio.clone()
+            },
+            HighMmioConfig {
+                base: arch::get_reserved_mem_addr() as u64,
+                ..high_mmio.clone()
+            },
+        ];
+        for range in invalid_ranges.iter() {
+            machine_config.high_mmio = Some(range.clone());
+            match vmm.set_vm_configuration(machine_config.clone()) {
+                Err(VmmActionError::MachineConfig(
+                    ErrorKind::User,
+                    VmConfigError::InvalidHighMmio,
+                )) => (),
+                _ => assert!(false),
+            }
+        }
+
+        // Error case: the guest memory would grow over the configured range.
+        machine_config.high_mmio = None;
+        machine_config.mem_size_mib = Some(80 << 10);
+        assert!(vmm.set_vm_configuration(machine_config).is_err());
+        assert_eq!(vmm.vm_config.mem_size_mib, Some(128));
+        assert_eq!(vmm.vm_config.high_mmio, Some(high_mmio.clone()));
+
+        // The range is reserved when the MMIO device manager is set up.
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.init_mmio_device_manager().is_ok());
+    }
+
+    #[test]
+    fn test_set_gic_version() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.vm_config.gic_version.is_none());
+
+        let machine_config = VmConfig {
+            gic_version: Some(GicVersion::V2),
+            ..Default::default()
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.gic_version, Some(GicVersion::V2));
+
+        // Updating other fields keeps the configured version.
+        let machine_config = VmConfig {
+            vcpu_count: Some(2),
+            ..Default::default()
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.gic_version, Some(GicVersion::V2));
+    }
+
+    #[test]
+    fn test_set_clock_source() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.vm_config.clock_source.is_none());
+
+        let machine_config = VmConfig {
+            clock_source: Some(ClockSource::KvmClock),
+            ..Default::default()
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.clock_source, Some(ClockSource::KvmClock));
+
+        // Updating other fields keeps the configured clock source.
+        let machine_config = VmConfig {
+            vcpu_count: Some(2),
+            ..Default::default()
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.clock_source, Some(ClockSource::KvmClock));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_load_kernel_clock_source() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.clock_source = Some(ClockSource::Tsc);
+        vmm.default_kernel_config(Some(good_kernel_file()));
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.load_kernel().is_ok());
+        assert!(vmm
+            .kernel_config
+            .as_ref()
+            .unwrap()
+            .cmdline
+            .as_str()
+            .ends_with(" clocksource=tsc tsc=reliable"));
+    }
+
+    #[test]
+    fn test_set_sev_enabled() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.vm_config.sev_enabled.is_none());
+
+        let machine_config = VmConfig {
+            sev_enabled: Some(true),
+            ..Default::default()
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.sev_enabled, Some(true));
+    }
+
+    #[test]
+    fn test_set_apic_mode() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.vm_config.apic_mode.is_none());
+
+        let machine_config = VmConfig {
+            apic_mode: Some(ApicMode::X2Apic),
+            guest_panic_action: None,
+            ..Default::default()
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.apic_mode, Some(ApicMode::X2Apic));
+    }
+
+    #[test]
+    fn test_set_serial_enabled() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.serial_enabled());
+
+        let machine_config = VmConfig {
+            serial_enabled: Some(false),
+            ..Default::default()
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.serial_enabled, Some(false));
+        // Only aarch64 guests can go without a serial device.
+        assert_eq!(vmm.serial_enabled(), cfg!(target_arch = "x86_64"));
+    }
+
+    #[test]
+    fn test_set_rtc_base_time() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.vm_config.rtc_base_time.is_none());
+
+        let machine_config = VmConfig {
+            rtc_base_time: Some(1_000_000_000),
+            ..Default::default()
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.rtc_base_time, Some(1_000_000_000));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_init_guest_memory_sev() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.sev_enabled = Some(true);
+        vmm.default_kernel_config(Some(good_kernel_file()));
+
+        if !sev::host_supports_sev() {
+            // The guest memory is not allocated on hosts without SEV.
+            match vmm.init_guest_memory() {
+                Err(StartMicrovmError::Sev(sev::Error::NotSupported)) => (),
+                _ => panic!("SEV should not be supported"),
+            }
+            assert!(vmm.guest_memory.is_none());
+            return;
+        }
+
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.sev.is_some());
+        assert!(vmm.load_kernel().is_ok());
+        assert!(vmm.launch_sev_guest().is_ok());
+    }
+
     #[test]
     fn test_setup_interrupt_controller() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -3233,6 +6946,78 @@ mod tests {
         assert!(vmm.load_kernel().is_ok());
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_reserved_memory() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        // The test kernel spans from 0x10_0000 up to 0x10_102f.
+        let mut machine_config = VmConfig {
+            reserved_memory: Some(vec![ReservedMemoryConfig {
+                base: 0x10_2000,
+                size_kib: 4,
+            }]),
+            ..Default::default()
+        };
+        assert!(vmm.set_vm_configuration(machine_config.clone()).is_ok());
+        assert_eq!(
+            vmm.reserved_memory(),
+            vec![(GuestAddress(0x10_2000), 0x1000)]
+        );
+
+        vmm.default_kernel_config(Some(good_kernel_file()));
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.load_kernel().is_ok());
+        assert!(vmm.configure_system().is_ok());
+
+        // Error case: the range is not page aligned.
+        machine_config.reserved_memory = Some(vec![ReservedMemoryConfig {
+            base: 0x10_0800,
+            size_kib: 4,
+        }]);
+        match vmm.set_vm_configuration(machine_config.clone()) {
+            Err(VmmActionError::MachineConfig(
+                ErrorKind::User,
+                VmConfigError::InvalidReservedMemory,
+            )) => (),
+            _ => assert!(false),
+        }
+
+        // Error case: the range overlaps with the kernel.
+        machine_config.reserved_memory = Some(vec![ReservedMemoryConfig {
+            base: 0x10_1000,
+            size_kib: 4,
+        }]);
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        vmm.default_kernel_config(Some(good_kernel_file()));
+        assert_eq!(
+            vmm.load_kernel().unwrap_err().to_string(),
+            "Cannot load kernel due to invalid memory configuration or invalid kernel image. \
+             Kernel overlaps the reserved guest memory range starting at 0x101000"
+        );
+    }
+
+    #[test]
+    fn test_validate_kernel_entry() {
+        // A deliberately tiny memory region that cannot hold the kernel entry point.
+        let guest_memory = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+
+        assert!(validate_kernel_entry(&guest_memory, GuestAddress(0x800)).is_ok());
+        assert_eq!(
+            validate_kernel_entry(&guest_memory, GuestAddress(0x10_0000))
+                .unwrap_err()
+                .to_string(),
+            "The kernel entry point 0x100000 is not within the guest memory. Check the memory \
+             size and the kernel image."
+        );
+        // The end of the region is not mapped.
+        assert!(validate_kernel_entry(&guest_memory, GuestAddress(0x1000)).is_err());
+
+        let guest_memory = GuestMemory::new(&arch::arch_memory_regions(128 << 20)).unwrap();
+        assert!(
+            validate_kernel_entry(&guest_memory, GuestAddress(arch::get_kernel_start())).is_ok()
+        );
+    }
+
     #[test]
     fn test_configure_system() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -3251,31 +7036,106 @@ mod tests {
         assert!(vmm.init_guest_memory().is_ok());
         assert!(vmm.vm.get_memory().is_some());
 
-        assert!(vmm.configure_system().is_ok());
+        assert!(vmm.configure_system().is_ok());
+
+        // The random seed is generated from scratch at each boot.
+        #[cfg(target_arch = "x86_64")]
+        {
+            vmm.kernel_config.as_mut().unwrap().rng_seed = true;
+            let read_seed = |vmm: &Vmm| {
+                let mut seed = [0u8; RNG_SEED_SIZE];
+                vmm.vm
+                    .get_memory()
+                    .unwrap()
+                    .read_slice_at_addr(
+                        &mut seed,
+                        // The seed follows the 16 bytes header of the setup_data entry.
+                        GuestAddress(arch::x86_64::layout::SETUP_DATA_START + 16),
+                    )
+                    .unwrap();
+                seed
+            };
+
+            assert!(vmm.configure_system().is_ok());
+            let seed = read_seed(&vmm);
+            assert!(seed.iter().any(|&byte| byte != 0));
+            assert!(vmm.configure_system().is_ok());
+            assert_ne!(read_seed(&vmm), seed);
+        }
+    }
+
+    #[test]
+    fn test_attach_virtio_devices() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.default_kernel_config(None);
+
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.vm.get_memory().is_some());
+
+        // Create test network interface.
+        let network_interface = NetworkInterfaceConfig {
+            iface_id: String::from("netif"),
+            host_dev_name: String::from("hostname6"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
+            allow_mmds_requests: false,
+            mmds_mss_clamp: None,
+            tap: None,
+        };
+
+        assert!(vmm.insert_net_device(network_interface).is_ok());
+        assert!(vmm.attach_virtio_devices().is_ok());
+        assert!(vmm.mmio_device_manager.is_some());
     }
 
+    #[cfg(target_arch = "aarch64")]
     #[test]
-    fn test_attach_virtio_devices() {
+    fn test_get_fdt() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
         vmm.default_kernel_config(None);
-
         assert!(vmm.init_guest_memory().is_ok());
-        assert!(vmm.vm.get_memory().is_some());
 
-        // Create test network interface.
         let network_interface = NetworkInterfaceConfig {
             iface_id: String::from("netif"),
-            host_dev_name: String::from("hostname6"),
+            host_dev_name: String::from("hostname8"),
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         };
-
         assert!(vmm.insert_net_device(network_interface).is_ok());
         assert!(vmm.attach_virtio_devices().is_ok());
-        assert!(vmm.mmio_device_manager.is_some());
+
+        // The FDT is only built when the system is configured.
+        match vmm.get_fdt() {
+            Err(VmmActionError::MachineConfig(ErrorKind::User, VmConfigError::FdtNotAvailable)) => {
+            }
+            _ => assert!(false),
+        }
+
+        assert!(vmm.configure_system().is_ok());
+        match vmm.get_fdt() {
+            Ok(VmmData::Fdt(fdt)) => {
+                let read_be_u32 = |pos: usize| {
+                    u32::from_be_bytes([fdt[pos], fdt[pos + 1], fdt[pos + 2], fdt[pos + 3]])
+                };
+                assert_eq!(read_be_u32(0), arch::aarch64::FDT_MAGIC);
+                // The blob is exactly as long as its header says.
+                assert_eq!(read_be_u32(4) as usize, fdt.len());
+            }
+            _ => assert!(false),
+        }
     }
 
     #[test]
@@ -3292,6 +7152,67 @@ mod tests {
         stdin_handle.lock().set_canon_mode().unwrap();
     }
 
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_attach_legacy_devices_without_serial() {
+        use arch::aarch64::DeviceInfoForFDT;
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.serial_enabled = Some(false);
+        assert!(vmm.init_guest_memory().is_ok());
+        vmm.default_kernel_config(None);
+        vmm.kernel_config.as_mut().unwrap().console = Some(ConsoleDevice::Serial);
+        assert!(vmm.setup_interrupt_controller().is_ok());
+
+        assert!(vmm.attach_legacy_devices().is_ok());
+        match vmm.attach_console_device() {
+            Err(StartMicrovmError::SerialConsoleDisabled) => (),
+            _ => assert!(false),
+        }
+        let device_info = vmm.get_mmio_device_info().unwrap();
+        assert!(!device_info
+            .values()
+            .any(|info| *info.type_() == arch::DeviceType::Serial));
+        // The RTC is still there.
+        assert!(device_info
+            .values()
+            .any(|info| *info.type_() == arch::DeviceType::RTC));
+        let cmdline = vmm.get_kernel_cmdline_str();
+        assert!(!cmdline.contains("earlycon"));
+        assert!(!cmdline.contains("console="));
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_attach_legacy_devices_rtc_base_time() {
+        use arch::aarch64::DeviceInfoForFDT;
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.vm_config.rtc_base_time = Some(1_000_000_000);
+        assert!(vmm.init_guest_memory().is_ok());
+        vmm.default_kernel_config(None);
+        assert!(vmm.setup_interrupt_controller().is_ok());
+        assert!(vmm.attach_legacy_devices().is_ok());
+
+        // The RTC data register counts from the configured base time.
+        let rtc_addr = vmm
+            .get_mmio_device_info()
+            .unwrap()
+            .values()
+            .find(|info| *info.type_() == arch::DeviceType::RTC)
+            .map(|info| info.addr())
+            .unwrap();
+        let mut data = [0u8; 4];
+        assert!(vmm
+            .mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .bus
+            .read(rtc_addr, &mut data));
+        let counter = u32::from_le_bytes(data);
+        assert!(counter >= 1_000_000_000 && counter < 1_000_000_010);
+    }
+
     // Helper function to get ErrorKind of error.
     fn error_kind<T: std::convert::Into<VmmActionError>>(err: T) -> ErrorKind {
         let err: VmmActionError = err.into();
@@ -3309,6 +7230,21 @@ mod tests {
             error_kind(DriveError::InvalidBlockDevicePath),
             ErrorKind::User
         );
+        assert_eq!(error_kind(DriveError::InvalidSerial), ErrorKind::User);
+        assert_eq!(error_kind(DriveError::InvalidRamdisk), ErrorKind::User);
+        assert_eq!(error_kind(DriveError::InvalidIoRetry), ErrorKind::User);
+        assert_eq!(
+            error_kind(DriveError::InvalidVirtioFeatureMask),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(DriveError::InvalidMaxInFlightRequests),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(DriveError::InvalidRamdiskSize(1024)),
+            ErrorKind::User
+        );
         assert_eq!(
             error_kind(DriveError::BlockDevicePathAlreadyExists),
             ErrorKind::User
@@ -3317,6 +7253,10 @@ mod tests {
             error_kind(DriveError::BlockDeviceUpdateFailed),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(DriveError::BlockDeviceFlushFailed(vec![])),
+            ErrorKind::Internal
+        );
         assert_eq!(
             error_kind(DriveError::OperationNotAllowedPreBoot),
             ErrorKind::User
@@ -3329,9 +7269,14 @@ mod tests {
             error_kind(DriveError::RootBlockDeviceAlreadyAdded),
             ErrorKind::User
         );
+        assert_eq!(error_kind(DriveError::NotABlockDevice), ErrorKind::User);
 
         // Test `VmConfigError` conversion
         assert_eq!(error_kind(VmConfigError::InvalidVcpuCount), ErrorKind::User);
+        assert_eq!(
+            error_kind(VmConfigError::InvalidCpuTopology),
+            ErrorKind::User
+        );
         assert_eq!(
             error_kind(VmConfigError::InvalidMemorySize),
             ErrorKind::User
@@ -3340,6 +7285,16 @@ mod tests {
             error_kind(VmConfigError::UpdateNotAllowedPostBoot),
             ErrorKind::User
         );
+        assert_eq!(error_kind(VmConfigError::InvalidHighMmio), ErrorKind::User);
+        assert_eq!(
+            error_kind(VmConfigError::IrqAffinityNotSupported),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(VmConfigError::InvalidReservedMemory),
+            ErrorKind::User
+        );
+        assert_eq!(error_kind(VmConfigError::FdtNotAvailable), ErrorKind::User);
 
         // Test `NetworkInterfaceError` conversion
         assert_eq!(
@@ -3360,6 +7315,28 @@ mod tests {
             error_kind(NetworkInterfaceError::DeviceIdNotFound),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::DetachFailed(
+                device_manager::mmio::Error::UpdateFailed
+            )),
+            ErrorKind::Internal
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::InvalidRxCoalescing),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::InvalidRxBufferFrames),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::InvalidVirtioFeatureMask),
+            ErrorKind::User
+        );
+        assert_eq!(
+            error_kind(NetworkInterfaceError::InvalidMmdsMssClamp),
+            ErrorKind::User
+        );
         // NetworkInterfaceError::OpenTap can be of multiple kinds.
         {
             assert_eq!(
@@ -3436,6 +7413,28 @@ mod tests {
             )),
             ErrorKind::Internal
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::CreateRxCoalescing(
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::Internal
+        );
+        #[cfg(feature = "vhost-user-block")]
+        assert_eq!(
+            error_kind(StartMicrovmError::CreateVhostUserBlockDevice(
+                devices::virtio::vhost::Error::VhostUserConfigNotSupported
+            )),
+            ErrorKind::User
+        );
+        #[cfg(feature = "virtio-fs")]
+        assert_eq!(
+            error_kind(StartMicrovmError::RegisterFsDevice(
+                device_manager::mmio::Error::CreateFsDevice(
+                    devices::virtio::fs::Error::InvalidTag(String::new())
+                )
+            )),
+            ErrorKind::User
+        );
         #[cfg(feature = "vsock")]
         assert_eq!(
             error_kind(StartMicrovmError::CreateVsockDevice(
@@ -3443,6 +7442,35 @@ mod tests {
             )),
             ErrorKind::User
         );
+        #[cfg(feature = "vsock")]
+        assert_eq!(
+            error_kind(StartMicrovmError::FileTransfer(
+                file_transfer::Error::Listen(53, io::Error::from_raw_os_error(libc::EADDRINUSE))
+            )),
+            ErrorKind::User
+        );
+        #[cfg(feature = "vsock")]
+        assert_eq!(
+            error_kind(StartMicrovmError::FileTransfer(
+                file_transfer::Error::Epoll(io::Error::from_raw_os_error(libc::EMFILE))
+            )),
+            ErrorKind::Internal
+        );
+        #[cfg(feature = "vsock")]
+        assert_eq!(
+            error_kind(StartMicrovmError::GuestAgent(guest_agent::Error::Listen(
+                52,
+                io::Error::from_raw_os_error(libc::EADDRINUSE)
+            ))),
+            ErrorKind::User
+        );
+        #[cfg(feature = "vsock")]
+        assert_eq!(
+            error_kind(StartMicrovmError::GuestAgent(guest_agent::Error::Epoll(
+                io::Error::from_raw_os_error(libc::EMFILE)
+            ))),
+            ErrorKind::Internal
+        );
         assert_eq!(
             error_kind(StartMicrovmError::DeviceManager),
             ErrorKind::Internal
@@ -3458,6 +7486,10 @@ mod tests {
             error_kind(StartMicrovmError::KernelCmdline(String::new())),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::KernelEntryNotMapped(GuestAddress(0))),
+            ErrorKind::User
+        );
         assert_eq!(
             error_kind(StartMicrovmError::KernelLoader(
                 kernel_loader::Error::SeekKernelImage
@@ -3490,6 +7522,10 @@ mod tests {
             error_kind(StartMicrovmError::MissingKernelConfig),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::MissingRootfs),
+            ErrorKind::User
+        );
         assert_eq!(
             error_kind(StartMicrovmError::NetDeviceNotConfigured),
             ErrorKind::User
@@ -3510,18 +7546,74 @@ mod tests {
             error_kind(StartMicrovmError::RegisterEvent),
             ErrorKind::Internal
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::RngSeed(io::Error::from_raw_os_error(0))),
+            ErrorKind::Internal
+        );
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(
+            error_kind(StartMicrovmError::GicVersionNotSupported(GicVersion::V2)),
+            ErrorKind::User
+        );
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(
+            error_kind(StartMicrovmError::SerialConsoleDisabled),
+            ErrorKind::User
+        );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            error_kind(StartMicrovmError::ClockSourceNotSupported(ClockSource::Tsc)),
+            ErrorKind::User
+        );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            error_kind(StartMicrovmError::Sev(sev::Error::NotSupported)),
+            ErrorKind::User
+        );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            error_kind(StartMicrovmError::X2ApicNotSupported),
+            ErrorKind::User
+        );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            error_kind(StartMicrovmError::Sev(sev::Error::OpenDevice(
+                io::Error::from_raw_os_error(0)
+            ))),
+            ErrorKind::Internal
+        );
         assert_eq!(
             error_kind(StartMicrovmError::RegisterNetDevice(
                 device_manager::mmio::Error::IrqsExhausted
             )),
             ErrorKind::Internal
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::RegisterConsoleDevice(
+                device_manager::mmio::Error::IrqsExhausted
+            )),
+            ErrorKind::Internal
+        );
         assert_eq!(
             error_kind(StartMicrovmError::RegisterMMIODevice(
                 device_manager::mmio::Error::IrqsExhausted
             )),
             ErrorKind::Internal
         );
+        #[cfg(feature = "vhost-user-block")]
+        assert_eq!(
+            error_kind(StartMicrovmError::RegisterVhostUserBlockDevice(
+                device_manager::mmio::Error::IrqsExhausted
+            )),
+            ErrorKind::Internal
+        );
+        #[cfg(feature = "virtio-fs")]
+        assert_eq!(
+            error_kind(StartMicrovmError::RegisterFsDevice(
+                device_manager::mmio::Error::IrqsExhausted
+            )),
+            ErrorKind::Internal
+        );
         #[cfg(feature = "vsock")]
         assert_eq!(
             error_kind(StartMicrovmError::RegisterVsockDevice(
@@ -3536,7 +7628,9 @@ mod tests {
             ErrorKind::Internal
         );
         assert_eq!(
-            error_kind(StartMicrovmError::Vcpu(vstate::Error::VcpuUnhandledKvmExit)),
+            error_kind(StartMicrovmError::Vcpu(
+                vstate::Error::VcpuUnhandledKvmExit(StopReason::InternalError)
+            )),
             ErrorKind::Internal
         );
         assert_eq!(
@@ -3549,6 +7643,13 @@ mod tests {
             error_kind(StartMicrovmError::VcpusNotConfigured),
             ErrorKind::User
         );
+        assert_eq!(
+            error_kind(StartMicrovmError::VcpuScheduling(
+                0,
+                io::Error::from_raw_os_error(0)
+            )),
+            ErrorKind::Internal
+        );
         assert_eq!(
             error_kind(StartMicrovmError::VcpuSpawn(io::Error::from_raw_os_error(
                 0
@@ -3557,6 +7658,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_error_exit_code() {
+        assert_eq!(
+            VmmActionError::BootSource(ErrorKind::User, BootSourceConfigError::InvalidKernelPath)
+                .exit_code(),
+            FC_EXIT_CODE_KERNEL_LOAD_FAILURE
+        );
+        assert_eq!(
+            VmmActionError::from(StartMicrovmError::KernelEntryNotMapped(GuestAddress(0)))
+                .exit_code(),
+            FC_EXIT_CODE_KERNEL_LOAD_FAILURE
+        );
+        assert_eq!(
+            VmmActionError::from(StartMicrovmError::KernelLoader(
+                kernel_loader::Error::SeekKernelImage
+            ))
+            .exit_code(),
+            FC_EXIT_CODE_KERNEL_LOAD_FAILURE
+        );
+        assert_eq!(
+            VmmActionError::from(StartMicrovmError::SeccompFilters(
+                seccomp::Error::InvalidArgumentNumber
+            ))
+            .exit_code(),
+            FC_EXIT_CODE_SECCOMP_FAILURE
+        );
+        assert_eq!(
+            VmmActionError::from(StartMicrovmError::VcpuScheduling(
+                0,
+                io::Error::from_raw_os_error(libc::EPERM)
+            ))
+            .exit_code(),
+            FC_EXIT_CODE_VCPU_SCHEDULING
+        );
+        assert_eq!(
+            VmmActionError::from(StartMicrovmError::MissingKernelConfig).exit_code(),
+            FC_EXIT_CODE_GENERIC_ERROR
+        );
+    }
+
     #[test]
     fn test_error_messages() {
         // Enum `Error`
@@ -3565,6 +7706,10 @@ mod tests {
             format!("{:?}", Error::ApiChannel),
             "ApiChannel: error receiving data from the API server"
         );
+        assert_eq!(
+            format!("{:?}", Error::ApiChannelClosed),
+            "ApiChannelClosed: the API server stopped"
+        );
         assert_eq!(
             format!(
                 "{:?}",
@@ -3708,6 +7853,16 @@ mod tests {
                 .kind(),
             &ErrorKind::User
         );
+        assert_eq!(
+            format!(
+                "{}",
+                VmmActionError::SendReset(
+                    ErrorKind::User,
+                    I8042DeviceError::ResetNotAllowedPreBoot
+                )
+            ),
+            "The CPU reset is not allowed before boot."
+        );
         #[cfg(feature = "vsock")]
         assert_eq!(
             format!(
@@ -3716,5 +7871,149 @@ mod tests {
             ),
             "VsockConfig(User, UpdateNotAllowedPostBoot)"
         );
+        #[cfg(feature = "vsock")]
+        assert_eq!(
+            format!(
+                "{}",
+                VmmActionError::GuestAgent(ErrorKind::Internal, guest_agent::Error::ConnectionLost)
+            ),
+            "The connection to the guest agent was lost."
+        );
+    }
+
+    #[test]
+    fn test_guest_stalled() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        // The watchdog is disabled.
+        assert!(!vmm.guest_stalled());
+
+        vmm.watchdog = Some(Watchdog::new(Duration::from_millis(100)));
+        let heartbeat = vmm.watchdog.as_ref().unwrap().heartbeat();
+        // The guest did not signal it is alive.
+        assert!(vmm.guest_stalled());
+        heartbeat.record();
+        assert!(!vmm.guest_stalled());
+        // The guest stopped signaling it is alive.
+        assert!(vmm.guest_stalled());
+    }
+
+    #[test]
+    fn test_microvm_idle() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        // The idle monitor is disabled.
+        assert!(!vmm.microvm_idle());
+
+        vmm.idle_monitor = Some(IdleMonitor::new(Duration::from_millis(100)));
+        let vcpu_activity = vmm.idle_monitor.as_ref().unwrap().vcpu_activity();
+        let api_activity = vmm.idle_monitor.as_ref().unwrap().api_activity();
+        vcpu_activity.record();
+        assert!(!vmm.microvm_idle());
+        api_activity.record();
+        assert!(!vmm.microvm_idle());
+        // Neither the guest nor the API clients were active since, so the VMM stops.
+        assert!(vmm.microvm_idle());
+    }
+
+    // Creates a VMM whose idle monitor checks the activity every 100ms, along with a device
+    // event, such as a queue notification of the guest, which the VMM dispatches to a device.
+    fn create_idle_vmm() -> (Vmm, EventFd) {
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        let idle_monitor = IdleMonitor::new(Duration::from_millis(100));
+        vmm.idle_monitor_event.fd.set_state(
+            TimerState::Periodic {
+                current: idle_monitor.timeout(),
+                interval: idle_monitor.timeout(),
+            },
+            SetTimeFlags::Default,
+        );
+        vmm.idle_monitor = Some(idle_monitor);
+
+        let (dispatch_base, sender) = vmm.epoll_context.allocate_tokens(1);
+        let handler = DummyEpollHandler {
+            evt: None,
+            flags: None,
+            payload: None,
+        };
+        sender.send(Box::new(handler)).unwrap();
+        let device_evt = EventFd::new().unwrap();
+        // The handler leaves the event unread, so it is edge-triggered.
+        epoll::ctl(
+            vmm.epoll_context.epoll_raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            device_evt.as_raw_fd(),
+            epoll::Event::new(
+                epoll::Events::EPOLLIN | epoll::Events::EPOLLET,
+                dispatch_base,
+            ),
+        )
+        .unwrap();
+        (vmm, device_evt)
+    }
+
+    // Runs the event loop of `vmm` in a child process, since stopping the microVM exits the
+    // process. Returns the pid of the child.
+    fn run_control_in_child(vmm: &mut Vmm) -> libc::pid_t {
+        // This is safe because the child only runs the event loop before exiting.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let _ = vmm.run_control();
+            // This is safe because the child is done.
+            unsafe { libc::_exit(1) };
+        }
+        pid
+    }
+
+    // Waits for the child process `pid` to exit and returns its exit code. With `WNOHANG` in
+    // `options`, returns None if the child is still running.
+    fn wait_child(pid: libc::pid_t, options: libc::c_int) -> Option<i32> {
+        let mut status = 0;
+        // This is safe because we pass a valid pointer and check the return value.
+        let ret = unsafe { libc::waitpid(pid, &mut status, options) };
+        assert!(ret >= 0);
+        if ret == 0 {
+            return None;
+        }
+        assert!(libc::WIFEXITED(status));
+        Some(libc::WEXITSTATUS(status))
+    }
+
+    #[test]
+    fn test_idle_stop() {
+        let (mut vmm, _device_evt) = create_idle_vmm();
+        let pid = run_control_in_child(&mut vmm);
+        // Nothing happens, so the VMM stops once the first check finds the microVM idle.
+        assert_eq!(wait_child(pid, 0), Some(i32::from(FC_EXIT_CODE_IDLE)));
+    }
+
+    #[test]
+    fn test_watchdog_stop() {
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        let watchdog = Watchdog::new(Duration::from_millis(100));
+        vmm.watchdog_event.fd.set_state(
+            TimerState::Periodic {
+                current: watchdog.timeout(),
+                interval: watchdog.timeout(),
+            },
+            SetTimeFlags::Default,
+        );
+        vmm.watchdog = Some(watchdog);
+        let pid = run_control_in_child(&mut vmm);
+        // The guest never signals it is alive, so the first check stops the VMM.
+        assert_eq!(wait_child(pid, 0), Some(i32::from(FC_EXIT_CODE_WATCHDOG)));
+    }
+
+    #[test]
+    fn test_device_activity_prevents_idle_stop() {
+        let (mut vmm, device_evt) = create_idle_vmm();
+        let pid = run_control_in_child(&mut vmm);
+        // The guest only does device I/O, through ioeventfds, for 5 check intervals.
+        for _ in 0..50 {
+            device_evt.write(1).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(wait_child(pid, libc::WNOHANG), None);
+        // The VMM stops once the devices are quiet too.
+        assert_eq!(wait_child(pid, 0), Some(i32::from(FC_EXIT_CODE_IDLE)));
     }
 }
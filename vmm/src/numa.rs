@@ -0,0 +1,125 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for placing the microVM on host NUMA nodes and CPUs.
+
+use std::fs;
+use std::io;
+use std::mem;
+
+use libc;
+
+const NODE_SYSFS_PATH: &str = "/sys/devices/system/node";
+
+/// Returns the host CPUs which belong to the NUMA node `node`.
+pub fn node_cpus(node: u32) -> io::Result<Vec<usize>> {
+    let cpulist = fs::read_to_string(format!("{}/node{}/cpulist", NODE_SYSFS_PATH, node))?;
+    parse_cpu_list(cpulist.trim()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid CPU list of NUMA node {}: {}", node, cpulist.trim()),
+        )
+    })
+}
+
+// Parses a CPU list in the kernel format, e.g. "0-3,8,10-11".
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    if list.is_empty() {
+        return Some(cpus);
+    }
+
+    for range in list.split(',') {
+        let mut bounds = range.splitn(2, '-');
+        let first: usize = bounds.next()?.parse().ok()?;
+        let last: usize = match bounds.next() {
+            Some(last) => last.parse().ok()?,
+            None => first,
+        };
+        if last < first {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+/// Returns the host CPUs on which the calling thread is allowed to run.
+pub fn allowed_cpus() -> io::Result<Vec<usize>> {
+    // This is safe because an all-zeroes cpu_set_t is an empty set.
+    let mut cpuset: libc::cpu_set_t = unsafe { mem::zeroed() };
+    // This is safe because the kernel doesn't write past the size of the set.
+    let ret = unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut cpuset) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is safe because the CPU indexes are within the size of the set.
+    Ok((0..8 * mem::size_of::<libc::cpu_set_t>())
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &cpuset) })
+        .collect())
+}
+
+/// Restricts the calling thread to run only on the given host CPUs.
+pub fn set_thread_affinity(cpus: &[usize]) -> io::Result<()> {
+    // This is safe because an all-zeroes cpu_set_t is an empty set.
+    let mut cpuset: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= 8 * mem::size_of::<libc::cpu_set_t>() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // This is safe because the CPU index was checked against the size of the set.
+        unsafe { libc::CPU_SET(cpu, &mut cpuset) };
+    }
+
+    // This is safe because the set is valid and the kernel doesn't read past its size.
+    let ret = unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &cpuset) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list(""), Some(vec![]));
+        assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+
+        assert!(parse_cpu_list("3-1").is_none());
+        assert!(parse_cpu_list("0-").is_none());
+        assert!(parse_cpu_list("a").is_none());
+        assert!(parse_cpu_list("0,,1").is_none());
+    }
+
+    #[test]
+    fn test_node_cpus() {
+        // Every host with NUMA support has the node 0.
+        if fs::metadata(NODE_SYSFS_PATH).is_ok() {
+            assert!(node_cpus(0).is_ok());
+        }
+        assert!(node_cpus(u32::max_value()).is_err());
+    }
+
+    #[test]
+    fn test_set_thread_affinity() {
+        thread::spawn(|| {
+            let allowed = allowed_cpus().unwrap();
+            assert!(!allowed.is_empty());
+
+            assert!(set_thread_affinity(&[1 << 16]).is_err());
+            assert!(set_thread_affinity(&allowed[..1]).is_ok());
+            assert_eq!(allowed_cpus().unwrap(), vec![allowed[0]]);
+        })
+        .join()
+        .unwrap();
+    }
+}
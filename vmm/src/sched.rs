@@ -0,0 +1,95 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for setting a real-time scheduling policy on the vCPU threads.
+
+use std::io;
+use std::mem;
+
+use libc;
+
+use vmm_config::machine_config::{VcpuSchedPolicy, VcpuScheduling};
+
+// See include/uapi/linux/sched.h in the kernel code.
+const SCHED_DEADLINE: u32 = 6;
+
+// See include/uapi/linux/sched/types.h in the kernel code.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Default)]
+struct sched_attr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
+
+/// Sets the scheduling policy described by `scheduling` on the calling thread.
+///
+/// This needs the `CAP_SYS_NICE` capability, or a high enough `RLIMIT_RTPRIO` for `Fifo`.
+pub fn set_thread_scheduling(scheduling: &VcpuScheduling) -> io::Result<()> {
+    let ret = match scheduling.policy {
+        VcpuSchedPolicy::Fifo => {
+            let param = libc::sched_param {
+                sched_priority: i32::from(scheduling.priority.unwrap_or_default()),
+            };
+            // This is safe because the kernel only reads the parameter.
+            libc::c_long::from(unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) })
+        }
+        VcpuSchedPolicy::Deadline => {
+            let attr = sched_attr {
+                size: mem::size_of::<sched_attr>() as u32,
+                sched_policy: SCHED_DEADLINE,
+                sched_runtime: scheduling.runtime_ns.unwrap_or_default(),
+                sched_deadline: scheduling.deadline_ns.unwrap_or_default(),
+                sched_period: scheduling.period_ns.unwrap_or_default(),
+                ..Default::default()
+            };
+            // This is safe because the kernel doesn't read past the size given in the attributes.
+            unsafe { libc::syscall(libc::SYS_sched_setattr, 0, &attr as *const sched_attr, 0) }
+        }
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_set_thread_scheduling() {
+        thread::spawn(|| {
+            let scheduling = VcpuScheduling {
+                policy: VcpuSchedPolicy::Fifo,
+                priority: Some(1),
+                runtime_ns: None,
+                deadline_ns: None,
+                period_ns: None,
+                strict: false,
+            };
+            match set_thread_scheduling(&scheduling) {
+                Ok(()) => (),
+                // The host doesn't allow real-time scheduling for the tests.
+                Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => return,
+                Err(e) => panic!("Cannot set the scheduling policy: {}", e),
+            }
+
+            // This is safe because the calls only write to the given parameter.
+            assert_eq!(unsafe { libc::sched_getscheduler(0) }, libc::SCHED_FIFO);
+            let mut param = libc::sched_param { sched_priority: 0 };
+            assert_eq!(unsafe { libc::sched_getparam(0, &mut param) }, 0);
+            assert_eq!(param.sched_priority, 1);
+        })
+        .join()
+        .unwrap();
+    }
+}
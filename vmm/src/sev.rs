@@ -0,0 +1,347 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Launching AMD SEV guests, whose memory is encrypted with a key the host can't read.
+//!
+//! Experimental: the guest page tables don't set the encryption bit, so the guest can't boot
+//! with its memory encrypted yet.
+
+use std::cmp;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::result;
+
+use kvm_bindings::KVMIO;
+use kvm_ioctls::VmFd;
+use memory_model::{GuestAddress, GuestMemory};
+use sys_util::{ioctl_with_mut_ref, ioctl_with_ref};
+
+const SEV_DEVICE_PATH: &str = "/dev/sev";
+const KVM_AMD_SEV_PARAM_PATH: &str = "/sys/module/kvm_amd/parameters/sev";
+
+// The SEV commands, see `enum sev_cmd_id` in include/uapi/linux/kvm.h.
+const KVM_SEV_INIT: u32 = 0;
+const KVM_SEV_LAUNCH_START: u32 = 2;
+const KVM_SEV_LAUNCH_UPDATE_DATA: u32 = 3;
+const KVM_SEV_LAUNCH_FINISH: u32 = 7;
+
+// The guest can't be debugged by the host.
+const SEV_POLICY_NODBG: u32 = 0x1;
+
+// The length of the data encrypted by a single command has to fit in an u32, and the data has
+// to be 16 bytes aligned.
+const MAX_UPDATE_DATA_LEN: usize = 1 << 31;
+const UPDATE_DATA_ALIGNMENT: usize = 16;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct kvm_sev_cmd {
+    id: u32,
+    data: u64,
+    error: u32,
+    sev_fd: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct kvm_enc_region {
+    addr: u64,
+    size: u64,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct kvm_sev_launch_start {
+    handle: u32,
+    policy: u32,
+    dh_uaddr: u64,
+    dh_len: u32,
+    session_uaddr: u64,
+    session_len: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct kvm_sev_launch_update_data {
+    uaddr: u64,
+    len: u32,
+}
+
+ioctl_iowr_nr!(KVM_MEMORY_ENCRYPT_OP, KVMIO, 0xba, ::std::os::raw::c_ulong);
+ioctl_ior_nr!(KVM_MEMORY_ENCRYPT_REG_REGION, KVMIO, 0xbb, kvm_enc_region);
+
+/// Errors associated with launching SEV guests.
+#[derive(Debug)]
+pub enum Error {
+    /// The host can't run SEV guests.
+    NotSupported,
+    /// Cannot open the SEV firmware device.
+    OpenDevice(io::Error),
+    /// A SEV command failed, with the given firmware error code.
+    Command(&'static str, io::Error, u32),
+    /// Cannot register the guest memory as encrypted.
+    RegisterRegion(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match *self {
+            NotSupported => write!(
+                f,
+                "The host doesn't support SEV. The kvm_amd module must be loaded with sev=1."
+            ),
+            OpenDevice(ref e) => write!(f, "Cannot open {}: {}", SEV_DEVICE_PATH, e),
+            Command(name, ref e, code) => write!(
+                f,
+                "The SEV {} command failed: {} (firmware error {})",
+                name, e, code
+            ),
+            RegisterRegion(ref e) => {
+                write!(f, "Cannot register the guest memory as encrypted: {}", e)
+            }
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// Checks whether the `kvm_amd` module of the host has SEV turned on.
+pub fn host_supports_sev() -> bool {
+    fs::read_to_string(KVM_AMD_SEV_PARAM_PATH)
+        .map(|value| value.trim() == "1" || value.trim() == "Y")
+        .unwrap_or(false)
+}
+
+/// Drives the launch of a SEV guest through the SEV firmware of the host.
+pub struct Sev {
+    device: File,
+}
+
+impl Sev {
+    /// Opens the SEV firmware device. This fails with `Error::NotSupported` on hosts which can't
+    /// run SEV guests, so it is done before the guest memory is allocated.
+    pub fn new() -> Result<Sev> {
+        if !host_supports_sev() {
+            return Err(Error::NotSupported);
+        }
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(SEV_DEVICE_PATH)
+            .map_err(Error::OpenDevice)?;
+        Ok(Sev { device })
+    }
+
+    /// Turns the VM into a SEV guest, registers its memory as encrypted and starts the launch.
+    /// Until the launch is finished, the guest memory can be written in plain text by the host.
+    pub fn init(&self, vm: &VmFd, guest_mem: &GuestMemory) -> Result<()> {
+        self.command(vm, "INIT", KVM_SEV_INIT, 0)?;
+
+        guest_mem.with_regions(|_, _, size, host_addr| {
+            let region = kvm_enc_region {
+                addr: host_addr as u64,
+                size: size as u64,
+            };
+            // Safe because the kernel only reads the region descriptor, and the region stays
+            // mapped for as long as the guest memory exists.
+            let ret = unsafe { ioctl_with_ref(vm, KVM_MEMORY_ENCRYPT_REG_REGION(), &region) };
+            if ret < 0 {
+                return Err(Error::RegisterRegion(io::Error::last_os_error()));
+            }
+            Ok(())
+        })?;
+
+        let mut start = kvm_sev_launch_start {
+            policy: SEV_POLICY_NODBG,
+            ..Default::default()
+        };
+        self.command(
+            vm,
+            "LAUNCH_START",
+            KVM_SEV_LAUNCH_START,
+            &mut start as *mut kvm_sev_launch_start as u64,
+        )
+    }
+
+    /// Encrypts in place the guest address `ranges`, given as start and size, to which the host
+    /// wrote, i.e. the kernel, its command line and the boot structures, then finishes the
+    /// launch. The rest of the guest memory is left as it is.
+    pub fn launch(
+        &self,
+        vm: &VmFd,
+        guest_mem: &GuestMemory,
+        ranges: &[(GuestAddress, usize)],
+    ) -> Result<()> {
+        for (start, end) in update_data_ranges(ranges) {
+            guest_mem.with_regions(|_, region_base, region_size, host_addr| {
+                let region_start = region_base.offset();
+                let region_end = region_start + region_size;
+                let mut addr = cmp::max(start, region_start);
+                while addr < cmp::min(end, region_end) {
+                    let len = cmp::min(cmp::min(end, region_end) - addr, MAX_UPDATE_DATA_LEN);
+                    let mut update = kvm_sev_launch_update_data {
+                        uaddr: (host_addr + addr - region_start) as u64,
+                        len: len as u32,
+                    };
+                    self.command(
+                        vm,
+                        "LAUNCH_UPDATE_DATA",
+                        KVM_SEV_LAUNCH_UPDATE_DATA,
+                        &mut update as *mut kvm_sev_launch_update_data as u64,
+                    )?;
+                    addr += len;
+                }
+                Ok(())
+            })?;
+        }
+        self.command(vm, "LAUNCH_FINISH", KVM_SEV_LAUNCH_FINISH, 0)
+    }
+
+    // Runs the SEV command `id`, whose parameters are at the address `data`.
+    fn command(&self, vm: &VmFd, name: &'static str, id: u32, data: u64) -> Result<()> {
+        let mut cmd = kvm_sev_cmd {
+            id,
+            data,
+            error: 0,
+            sev_fd: self.device.as_raw_fd() as u32,
+        };
+        // Safe because the command and its parameters outlive the call, and the kernel doesn't
+        // write past them.
+        let ret = unsafe { ioctl_with_mut_ref(vm, KVM_MEMORY_ENCRYPT_OP(), &mut cmd) };
+        if ret < 0 {
+            return Err(Error::Command(name, io::Error::last_os_error(), cmd.error));
+        }
+        Ok(())
+    }
+}
+
+// Returns the `ranges`, given as start and size, as start and end addresses aligned for the
+// firmware, sorted and merged. Encrypting some data twice would garble it.
+fn update_data_ranges(ranges: &[(GuestAddress, usize)]) -> Vec<(usize, usize)> {
+    let mut aligned: Vec<(usize, usize)> = ranges
+        .iter()
+        .filter(|&&(_, size)| size != 0)
+        .map(|&(start, size)| {
+            let end = start.offset() + size;
+            (
+                start.offset() & !(UPDATE_DATA_ALIGNMENT - 1),
+                (end + UPDATE_DATA_ALIGNMENT - 1) & !(UPDATE_DATA_ALIGNMENT - 1),
+            )
+        })
+        .collect();
+    aligned.sort();
+
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(aligned.len());
+    for (start, end) in aligned {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = cmp::max(last.1, end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem;
+
+    use libc;
+    use vstate::Vm;
+    use KvmContext;
+
+    #[test]
+    fn test_structs_layout() {
+        // The sizes of the structures of include/uapi/linux/kvm.h.
+        assert_eq!(mem::size_of::<kvm_sev_cmd>(), 24);
+        assert_eq!(mem::size_of::<kvm_enc_region>(), 16);
+        assert_eq!(mem::size_of::<kvm_sev_launch_start>(), 40);
+        assert_eq!(mem::size_of::<kvm_sev_launch_update_data>(), 16);
+    }
+
+    #[test]
+    fn test_sev_launch() {
+        if !host_supports_sev() {
+            match Sev::new() {
+                Err(Error::NotSupported) => (),
+                _ => panic!("SEV should not be supported"),
+            }
+            return;
+        }
+
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(kvm.fd()).unwrap();
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        vm.memory_init(guest_mem.clone(), &kvm).unwrap();
+
+        let sev = Sev::new().unwrap();
+        sev.init(vm.get_fd(), &guest_mem).unwrap();
+        guest_mem
+            .write_obj_at_addr(0x1234_5678u32, GuestAddress(0x1000))
+            .unwrap();
+        guest_mem
+            .write_obj_at_addr(0x1234_5678u32, GuestAddress(0x2000))
+            .unwrap();
+        sev.launch(vm.get_fd(), &guest_mem, &[(GuestAddress(0x1000), 4)])
+            .unwrap();
+        // The host only sees the encrypted data anymore, outside of the ranges it is left as is.
+        assert_ne!(
+            guest_mem
+                .read_obj_from_addr::<u32>(GuestAddress(0x1000))
+                .unwrap(),
+            0x1234_5678
+        );
+        assert_eq!(
+            guest_mem
+                .read_obj_from_addr::<u32>(GuestAddress(0x2000))
+                .unwrap(),
+            0x1234_5678
+        );
+    }
+
+    #[test]
+    fn test_update_data_ranges() {
+        assert!(update_data_ranges(&[]).is_empty());
+        assert_eq!(
+            update_data_ranges(&[
+                (GuestAddress(0x9000), 0x3000),
+                (GuestAddress(0x500), 0x28),
+                (GuestAddress(0x7000), 0x1000),
+                (GuestAddress(0x10_0000), 0x102f),
+                (GuestAddress(0xc000), 0x30),
+                (GuestAddress(0x520), 0x8),
+                (GuestAddress(0x2_0000), 0),
+            ]),
+            vec![
+                (0x500, 0x530),
+                (0x7000, 0x8000),
+                (0x9000, 0xc030),
+                (0x10_0000, 0x10_1030),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(
+            Error::NotSupported.to_string(),
+            "The host doesn't support SEV. The kvm_amd module must be loaded with sev=1."
+        );
+        assert_eq!(
+            Error::Command("INIT", io::Error::from_raw_os_error(libc::EINVAL), 3).to_string(),
+            format!(
+                "The SEV INIT command failed: {} (firmware error 3)",
+                io::Error::from_raw_os_error(libc::EINVAL)
+            )
+        );
+    }
+}
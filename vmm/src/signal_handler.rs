@@ -5,9 +5,19 @@ extern crate logger;
 extern crate sys_util;
 
 use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+#[cfg(target_arch = "x86_64")]
+use std::ptr;
 use std::result::Result;
+use std::sync::atomic::{AtomicIsize, Ordering};
+#[cfg(target_arch = "x86_64")]
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::sync::Mutex;
+#[cfg(target_arch = "x86_64")]
+use std::time::{Duration, Instant};
 
-use libc::{_exit, c_int, c_void, siginfo_t, SIGBUS, SIGSEGV, SIGSYS};
+use libc::{_exit, c_int, c_void, pid_t, siginfo_t, SIGBUS, SIGSEGV, SIGSYS, SIGTERM, SIGUSR2};
 
 use logger::{Metric, LOGGER, METRICS};
 use sys_util::register_signal_handler;
@@ -21,6 +31,54 @@ const SI_OFF_SYSCALL: isize = 6;
 
 const SYS_SECCOMP_CODE: i32 = 1;
 
+// The `si_code` of the signals sent to a single thread through `tgkill`.
+#[cfg(target_arch = "x86_64")]
+const SI_TKILL: i32 = -6;
+
+// The most frames captured from the stack of a thread.
+#[cfg(target_arch = "x86_64")]
+const MAX_STACK_FRAMES: usize = 128;
+
+// How long a stack dump waits for a thread to capture its stack, e.g. a thread that is stuck
+// with the signals blocked.
+#[cfg(target_arch = "x86_64")]
+const STACK_CAPTURE_TIMEOUT: Duration = Duration::from_millis(100);
+
+// The event file descriptor the `SIGUSR2` handler writes to in order to request a stack dump.
+// A negative value means that nobody is listening for stack dump requests.
+static STACK_DUMP_EVENT_FD: AtomicIsize = AtomicIsize::new(-1);
+
+// The event file descriptor the `SIGTERM` handler writes to in order to stop the microVM. A
+// negative value means that nobody is listening for stop requests.
+static TERMINATE_EVENT_FD: AtomicIsize = AtomicIsize::new(-1);
+
+// The id of the thread asked to capture its stack, negated while the thread is capturing it,
+// or 0 once the stack is captured. The stacks are captured one thread at a time.
+#[cfg(target_arch = "x86_64")]
+static STACK_CAPTURE_TID: AtomicIsize = AtomicIsize::new(0);
+// The buffer of `MAX_STACK_FRAMES` frames the dumping thread lends for capturing a stack. The
+// `SIGUSR2` handler of the thread writes to it while `STACK_CAPTURE_TID` is negated.
+#[cfg(target_arch = "x86_64")]
+static STACK_FRAMES: AtomicPtr<usize> = AtomicPtr::new(ptr::null_mut());
+#[cfg(target_arch = "x86_64")]
+static STACK_FRAME_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    // The threads whose stacks are dumped on `SIGUSR2`.
+    static ref STACK_DUMP_THREADS: Mutex<Vec<StackDumpThread>> = Mutex::new(vec![]);
+}
+
+/// A thread whose stack is dumped on `SIGUSR2`.
+#[derive(Clone, Debug)]
+pub struct StackDumpThread {
+    /// The name of the thread in the stack dump.
+    pub name: String,
+    /// The id of the thread.
+    pub tid: pid_t,
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+    pid: pid_t,
+}
+
 /// Signal handler for `SIGSYS`.
 ///
 /// Increments the `seccomp.num_faults` metric, logs an error message and terminates the process
@@ -96,14 +154,186 @@ extern "C" fn sigbus_sigsegv_handler(num: c_int, info: *mut siginfo_t, _unused:
     };
 }
 
+/// Signal handler for `SIGUSR2`.
+///
+/// When sent to the process, requests a stack dump by writing to the event set through
+/// `set_stack_dump_event`. Resolving the symbols of a backtrace is not async-signal-safe, so
+/// the VMM thread does the dump when it handles the event. It then sends `SIGUSR2` to each
+/// thread registered through `register_stack_dump_thread`, which only records the frames of
+/// its stack here.
+///
+extern "C" fn sigusr2_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_void) {
+    // Safe because we're just reading some fields from a supposedly valid argument.
+    let si_signo = unsafe { (*info).si_signo };
+
+    // Sanity check. The condition should never be true.
+    if num != si_signo || num != SIGUSR2 {
+        return;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        // Safe because we're just reading a field from a supposedly valid argument.
+        if unsafe { (*info).si_code } == SI_TKILL {
+            record_stack_frames();
+            return;
+        }
+    }
+
+    let event_fd = STACK_DUMP_EVENT_FD.load(Ordering::SeqCst);
+    if event_fd < 0 {
+        return;
+    }
+    signal_event(event_fd as c_int);
+}
+
+/// Signal handler for `SIGTERM`.
+///
+/// Requests a graceful stop by writing to the event set through `set_terminate_event`, so the
+/// VMM thread flushes the block devices before exiting. The process exits right away if the
+/// VMM thread isn't listening yet.
+///
+extern "C" fn sigterm_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_void) {
+    // Safe because we're just reading some fields from a supposedly valid argument.
+    let si_signo = unsafe { (*info).si_signo };
+
+    // Sanity check. The condition should never be true.
+    if num != si_signo || num != SIGTERM {
+        return;
+    }
+
+    let event_fd = TERMINATE_EVENT_FD.load(Ordering::SeqCst);
+    if event_fd < 0 {
+        // Safe because we're terminating the process anyway.
+        unsafe { _exit(i32::from(super::FC_EXIT_CODE_OK)) };
+    }
+    signal_event(event_fd as c_int);
+}
+
+// Increments the counter of the event file descriptor `event_fd`.
+fn signal_event(event_fd: c_int) {
+    let value: u64 = 1;
+    // Safe because `write` is async-signal-safe and we're passing a valid buffer of the
+    // right size. There is nothing we could do about a failed write from a signal handler.
+    unsafe {
+        libc::write(
+            event_fd,
+            &value as *const u64 as *const c_void,
+            mem::size_of::<u64>(),
+        )
+    };
+}
+
+// Records the frames of the stack of the calling thread if it was asked to, from its `SIGUSR2`
+// handler. A late signal, sent to a thread which didn't capture its stack in time, is ignored.
+#[cfg(target_arch = "x86_64")]
+fn record_stack_frames() {
+    // Safe because `gettid` has no arguments and can't fail.
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as isize;
+    if STACK_CAPTURE_TID
+        .compare_exchange(tid, -tid, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return;
+    }
+
+    let frames = STACK_FRAMES.load(Ordering::SeqCst);
+    let mut count = 0;
+    // Safe because the buffer holds `MAX_STACK_FRAMES` frames, and only the thread which swapped
+    // `STACK_CAPTURE_TID` writes to it until the swap back to 0. Walking the stack doesn't
+    // allocate, unlike resolving the symbols.
+    unsafe {
+        ::backtrace::trace_unsynchronized(|frame| {
+            *frames.add(count) = frame.ip() as usize;
+            count += 1;
+            count < MAX_STACK_FRAMES
+        });
+    }
+    STACK_FRAME_COUNT.store(count, Ordering::SeqCst);
+    STACK_CAPTURE_TID.store(0, Ordering::SeqCst);
+}
+
+/// Registers the calling thread as one whose stack is dumped on `SIGUSR2`, under `name`. This
+/// has to happen before the thread installs its seccomp filters.
+///
+pub fn register_stack_dump_thread(name: &str) {
+    let thread = StackDumpThread {
+        name: name.to_string(),
+        // Safe because neither call has arguments, and neither can fail.
+        tid: unsafe { libc::syscall(libc::SYS_gettid) } as pid_t,
+        pid: unsafe { libc::getpid() },
+    };
+    STACK_DUMP_THREADS
+        .lock()
+        .expect("Failed to register the thread due to poisoned lock")
+        .push(thread);
+}
+
+/// Returns the threads whose stacks are dumped on `SIGUSR2`, in the order they registered.
+///
+pub fn stack_dump_threads() -> Vec<StackDumpThread> {
+    STACK_DUMP_THREADS
+        .lock()
+        .expect("Failed to read the threads due to poisoned lock")
+        .clone()
+}
+
+/// Asks `thread` to capture its stack by sending it `SIGUSR2`, and returns the instruction
+/// pointers of the frames, innermost first. Returns `None` if the thread is gone, or didn't
+/// capture its stack in time.
+///
+#[cfg(target_arch = "x86_64")]
+pub fn capture_thread_stack(thread: &StackDumpThread) -> Option<Vec<usize>> {
+    let tid = thread.tid as isize;
+    let mut frames = vec![0; MAX_STACK_FRAMES];
+    STACK_FRAMES.store(frames.as_mut_ptr(), Ordering::SeqCst);
+    STACK_CAPTURE_TID.store(tid, Ordering::SeqCst);
+    // Safe because we're passing valid ids and a valid signal, and we check the return value.
+    // The signal is handled right away when the calling thread sends it to itself.
+    if unsafe { libc::syscall(libc::SYS_tgkill, thread.pid, thread.tid, SIGUSR2) } < 0 {
+        STACK_CAPTURE_TID.store(0, Ordering::SeqCst);
+        return None;
+    }
+
+    // A blocking wait would hang the dump on a stuck thread.
+    let deadline = Instant::now() + STACK_CAPTURE_TIMEOUT;
+    while STACK_CAPTURE_TID.load(Ordering::SeqCst) != 0 {
+        if Instant::now() >= deadline
+            && STACK_CAPTURE_TID
+                .compare_exchange(tid, 0, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        {
+            // The thread didn't start capturing its stack, and won't anymore.
+            return None;
+        }
+    }
+
+    frames.truncate(STACK_FRAME_COUNT.load(Ordering::SeqCst));
+    Some(frames)
+}
+
+/// Sets the event file descriptor that is signaled when `SIGUSR2` requests a stack dump.
+///
+pub fn set_stack_dump_event(event_fd: RawFd) {
+    STACK_DUMP_EVENT_FD.store(event_fd as isize, Ordering::SeqCst);
+}
+
+/// Sets the event file descriptor that is signaled when `SIGTERM` requests a stop.
+///
+pub fn set_terminate_event(event_fd: RawFd) {
+    TERMINATE_EVENT_FD.store(event_fd as isize, Ordering::SeqCst);
+}
+
 /// Registers all the required signal handlers.
 ///
-/// Custom handlers are installed for: `SIGBUS`, `SIGSEGV`, `SIGSYS`.
+/// Custom handlers are installed for: `SIGBUS`, `SIGSEGV`, `SIGSYS`, `SIGTERM`, `SIGUSR2`.
 ///
 pub fn register_signal_handlers() -> Result<(), io::Error> {
     register_signal_handler(SIGSYS, sigsys_handler)?;
     register_signal_handler(SIGBUS, sigbus_sigsegv_handler)?;
     register_signal_handler(SIGSEGV, sigbus_sigsegv_handler)?;
+    register_signal_handler(SIGTERM, sigterm_handler)?;
+    register_signal_handler(SIGUSR2, sigusr2_handler)?;
     Ok(())
 }
 
@@ -112,9 +342,11 @@ mod tests {
     use super::*;
 
     use std::mem;
+    use std::os::unix::io::AsRawFd;
     use std::process;
 
     use libc::{cpu_set_t, syscall};
+    use sys_util::EventFd;
 
     use seccomp::{allow_syscall, SeccompAction, SeccompFilter};
 
@@ -192,4 +424,67 @@ mod tests {
         }
         assert!(true);
     }
+
+    #[test]
+    fn test_sigusr2_handler() {
+        assert!(register_signal_handlers().is_ok());
+
+        let stack_dump_evt = EventFd::new().unwrap();
+        set_stack_dump_event(stack_dump_evt.as_raw_fd());
+
+        unsafe {
+            syscall(libc::SYS_kill, process::id(), SIGUSR2);
+        }
+        // The handler requested a stack dump and left the process alive.
+        assert_eq!(stack_dump_evt.read().unwrap(), 1);
+
+        // Each registered thread captures its own stack.
+        #[cfg(target_arch = "x86_64")]
+        {
+            use std::sync::mpsc;
+            use std::thread;
+
+            register_stack_dump_thread("test_dumping");
+            let (registered_sender, registered_receiver) = mpsc::channel();
+            let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+            let other_thread = thread::spawn(move || {
+                register_stack_dump_thread("test_dumped");
+                registered_sender.send(()).unwrap();
+                // Blocks until the stacks are captured.
+                let _ = stop_receiver.recv();
+            });
+            registered_receiver.recv().unwrap();
+
+            let threads: Vec<StackDumpThread> = stack_dump_threads()
+                .into_iter()
+                .filter(|thread| thread.name.starts_with("test_"))
+                .collect();
+            assert_eq!(threads.len(), 2);
+            assert_ne!(threads[0].tid, threads[1].tid);
+            let own_frames = capture_thread_stack(&threads[0]).unwrap();
+            let other_frames = capture_thread_stack(&threads[1]).unwrap();
+            assert!(!own_frames.is_empty());
+            assert!(!other_frames.is_empty());
+            assert_ne!(own_frames, other_frames);
+
+            // A thread that is gone captures nothing.
+            drop(stop_sender);
+            other_thread.join().unwrap();
+            assert!(capture_thread_stack(&threads[1]).is_none());
+        }
+    }
+
+    #[test]
+    fn test_sigterm_handler() {
+        assert!(register_signal_handlers().is_ok());
+
+        let terminate_evt = EventFd::new().unwrap();
+        set_terminate_event(terminate_evt.as_raw_fd());
+
+        unsafe {
+            syscall(libc::SYS_kill, process::id(), SIGTERM);
+        }
+        // The handler requested a stop and left the stopping to the VMM thread.
+        assert_eq!(terminate_evt.read().unwrap(), 1);
+    }
 }
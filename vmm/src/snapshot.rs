@@ -0,0 +1,1083 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writes the state of a paused microVM to a snapshot file and reads it back.
+//!
+//! A snapshot starts with a header holding `SNAPSHOT_MAGIC` and `SNAPSHOT_VERSION`, followed by
+//! a list of sections ending with an `End` section. Each section starts with its kind and the
+//! length of its payload, so a reader can skip the sections it doesn't know about. All the
+//! integers are little endian.
+//!
+//! Snapshots are experimental. A `Vcpu` section holds the registers, the LAPIC, the FPU/XSAVE
+//! state, the pending events and the MSRs of a vCPU, the `Vm` section holds the clock and the
+//! in-kernel interrupt controllers and timer, and a `Device` section holds the transport state
+//! of a virtio device, whose queues keep their progress in the guest memory. Only paused
+//! microVMs can be saved, since the state of running vCPUs keeps changing, so the
+//! `CreateSnapshot` action pauses the vCPUs while it writes the snapshot.
+//!
+//! Restoring rebuilds the guest memory, loads the saved state into new vCPUs and a new VM, and
+//! restores the transport state of the virtio devices registered with the configuration of the
+//! saved microVM. The `LoadSnapshot` action drives a restore, and refuses a snapshot whose
+//! vCPUs, memory or devices don't match the configured ones.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+#[cfg(target_arch = "x86_64")]
+use std::os::raw::c_ulong;
+#[cfg(target_arch = "x86_64")]
+use std::os::unix::io::AsRawFd;
+#[cfg(target_arch = "x86_64")]
+use std::{mem, ptr, slice};
+
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{
+    kvm_clock_data, kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_msr_entry, kvm_msrs,
+    kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave, KVMIO,
+    KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+};
+#[cfg(target_arch = "x86_64")]
+use kvm_ioctls::{VcpuFd, VmFd};
+use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
+#[cfg(target_arch = "x86_64")]
+use sys_util::{ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_config::instance_info::InstanceState;
+
+/// Identifies a Firecracker snapshot file.
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"FCSNAPSH";
+/// The version of the snapshot format, increased on incompatible changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The size of the snapshot header and of the section headers.
+pub const HEADER_SIZE: usize = 16;
+
+/// The kinds of snapshot sections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SectionKind {
+    /// Marks the end of the snapshot.
+    End = 0,
+    /// The registers of a vCPU.
+    Vcpu = 1,
+    /// The state of a MMIO device, following its id.
+    Device = 2,
+    /// The content of the guest memory, region by region.
+    Memory = 3,
+    /// The state of the VM shared by the vCPUs: its clock and its in-kernel interrupt
+    /// controllers and timer.
+    Vm = 4,
+}
+
+/// Errors associated with saving and restoring a snapshot.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the snapshot file.
+    CreateFile(io::Error),
+    /// Cannot access the guest memory.
+    GuestMemory(GuestMemoryError),
+    /// The file doesn't start with the snapshot magic.
+    InvalidMagic,
+    /// A section of the given kind is malformed.
+    InvalidSection(u32),
+    /// The guest memory of the snapshot doesn't match the configured memory size.
+    MemoryMismatch,
+    /// The microVM was not started, so there is nothing to save.
+    MicrovmNotStarted,
+    /// The snapshot has no section of the given kind, which a restore needs.
+    MissingSection(u32),
+    /// A device configured for the microVM is not in the snapshot.
+    MissingDevice(String),
+    /// The microVM is not paused, so it can't be saved.
+    NotPaused,
+    /// Snapshots are not supported on this architecture.
+    NotSupported,
+    /// Cannot read the snapshot file.
+    Read(io::Error),
+    /// The snapshot holds a device which is not configured for the microVM.
+    UnknownDevice(String),
+    /// The snapshot was written in an unsupported version of the format.
+    UnsupportedVersion(u32),
+    /// The snapshot holds the given number of vCPUs, which doesn't match the configured count.
+    VcpuCount(usize),
+    /// The vCPU with the given id didn't hand its state over.
+    VcpuNotResponding(u8),
+    /// Cannot get or set the state of a vCPU.
+    VcpuState(io::Error),
+    /// Cannot get or set the state of the VM.
+    VmState(io::Error),
+    /// Cannot write to the snapshot file.
+    Write(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match *self {
+            CreateFile(ref e) => write!(f, "Cannot create the snapshot file: {}", e),
+            GuestMemory(ref e) => write!(f, "Cannot access the guest memory: {:?}", e),
+            InvalidMagic => write!(f, "The file is not a snapshot."),
+            InvalidSection(kind) => {
+                write!(f, "The snapshot section of kind {} is malformed.", kind)
+            }
+            MemoryMismatch => write!(
+                f,
+                "The guest memory of the snapshot doesn't match the configured memory size."
+            ),
+            MicrovmNotStarted => write!(f, "The microVM must be started to be saved."),
+            MissingSection(kind) => write!(f, "The snapshot has no section of kind {}.", kind),
+            MissingDevice(ref id) => write!(
+                f,
+                "The device {} is configured, but it is not in the snapshot.",
+                id
+            ),
+            NotPaused => write!(f, "The microVM must be paused to be saved."),
+            NotSupported => write!(f, "Snapshots are not supported on this architecture."),
+            Read(ref e) => write!(f, "Cannot read the snapshot: {}", e),
+            UnknownDevice(ref id) => write!(
+                f,
+                "The snapshot holds the device {}, which is not configured.",
+                id
+            ),
+            UnsupportedVersion(version) => write!(
+                f,
+                "The snapshot version {} is not supported. The supported version is {}.",
+                version, SNAPSHOT_VERSION
+            ),
+            VcpuCount(count) => write!(
+                f,
+                "The snapshot holds {} vCPUs, which doesn't match the configured vCPU count.",
+                count
+            ),
+            VcpuNotResponding(id) => write!(f, "The vCPU {} didn't hand its state over.", id),
+            VcpuState(ref e) => write!(f, "Cannot access the vCPU state: {}", e),
+            VmState(ref e) => write!(f, "Cannot access the VM state: {}", e),
+            Write(ref e) => write!(f, "Cannot write the snapshot: {}", e),
+        }
+    }
+}
+
+/// The result of the snapshot operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Writes a snapshot section by section.
+pub struct SnapshotWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Starts a snapshot of a microVM in the state `state` by writing its header to `writer`.
+    /// The microVM must be paused.
+    pub fn new(mut writer: W, state: &InstanceState) -> Result<Self> {
+        if *state != InstanceState::Paused {
+            return Err(Error::NotPaused);
+        }
+        writer.write_all(&SNAPSHOT_MAGIC).map_err(Error::Write)?;
+        writer
+            .write_all(&SNAPSHOT_VERSION.to_le_bytes())
+            .map_err(Error::Write)?;
+        // Reserved.
+        writer.write_all(&[0u8; 4]).map_err(Error::Write)?;
+        Ok(SnapshotWriter { writer })
+    }
+
+    fn write_section_header(&mut self, kind: SectionKind, len: u64) -> Result<()> {
+        self.writer
+            .write_all(&(kind as u32).to_le_bytes())
+            .map_err(Error::Write)?;
+        // Reserved.
+        self.writer.write_all(&[0u8; 4]).map_err(Error::Write)?;
+        self.writer
+            .write_all(&len.to_le_bytes())
+            .map_err(Error::Write)
+    }
+
+    /// Writes a section of kind `kind` holding `data`.
+    pub fn write_section(&mut self, kind: SectionKind, data: &[u8]) -> Result<()> {
+        self.write_section_header(kind, data.len() as u64)?;
+        self.writer.write_all(data).map_err(Error::Write)
+    }
+
+    /// Writes the state of the device `id` in a `Device` section.
+    pub fn write_device(&mut self, id: &str, state: &[u8]) -> Result<()> {
+        self.write_section_header(SectionKind::Device, (4 + id.len() + state.len()) as u64)?;
+        self.writer
+            .write_all(&(id.len() as u32).to_le_bytes())
+            .map_err(Error::Write)?;
+        self.writer.write_all(id.as_bytes()).map_err(Error::Write)?;
+        self.writer.write_all(state).map_err(Error::Write)
+    }
+
+    /// Writes the guest memory in a `Memory` section.
+    ///
+    /// The section starts with the number of regions and the guest address and the size of each
+    /// region, so that the reader maps all of them at once, followed by the content of the
+    /// regions in the same order. The content is written straight from the guest memory
+    /// mapping, so the host memory use doesn't grow with the size of the guest memory.
+    pub fn write_memory(&mut self, guest_mem: &GuestMemory) -> Result<()> {
+        let mut regions = Vec::new();
+        guest_mem
+            .with_regions_mut(|_, guest_addr, size, _| {
+                regions.push((guest_addr, size));
+                Ok(())
+            })
+            .map_err(Error::GuestMemory)?;
+
+        let len = regions
+            .iter()
+            .map(|&(_, size)| HEADER_SIZE as u64 + size as u64)
+            .sum::<u64>()
+            + 8;
+        self.write_section_header(SectionKind::Memory, len)?;
+        self.writer
+            .write_all(&(regions.len() as u64).to_le_bytes())
+            .map_err(Error::Write)?;
+        for &(guest_addr, size) in regions.iter() {
+            self.writer
+                .write_all(&(guest_addr.offset() as u64).to_le_bytes())
+                .map_err(Error::Write)?;
+            self.writer
+                .write_all(&(size as u64).to_le_bytes())
+                .map_err(Error::Write)?;
+        }
+        for (guest_addr, size) in regions {
+            guest_mem
+                .write_from_memory(guest_addr, &mut self.writer, size)
+                .map_err(Error::GuestMemory)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the snapshot and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_section_header(SectionKind::End, 0)?;
+        self.writer.flush().map_err(Error::Write)?;
+        Ok(self.writer)
+    }
+}
+
+/// The content of a snapshot.
+pub struct Snapshot {
+    /// The guest memory, with the regions and the content it had when it was saved.
+    pub guest_memory: Option<GuestMemory>,
+    /// The payloads of the `Vcpu` sections, in the order of the vCPUs.
+    pub vcpus: Vec<Vec<u8>>,
+    /// The payload of the `Vm` section.
+    pub vm: Option<Vec<u8>>,
+    /// The ids and the states of the devices.
+    pub devices: Vec<(String, Vec<u8>)>,
+}
+
+impl Snapshot {
+    /// Checks that the snapshot holds the state of `vcpu_count` vCPUs.
+    pub fn check_vcpus(&self, vcpu_count: u8) -> Result<()> {
+        if self.vcpus.len() != vcpu_count as usize {
+            return Err(Error::VcpuCount(self.vcpus.len()));
+        }
+        Ok(())
+    }
+
+    /// Checks that the guest memory of the snapshot is made of exactly the regions `regions`,
+    /// given as guest addresses and sizes.
+    pub fn check_memory(&self, regions: &[(GuestAddress, usize)]) -> Result<()> {
+        let guest_memory = self
+            .guest_memory
+            .as_ref()
+            .ok_or(Error::MissingSection(SectionKind::Memory as u32))?;
+        let mut saved_regions = Vec::new();
+        guest_memory
+            .with_regions_mut(|_, guest_addr, size, _| {
+                saved_regions.push((guest_addr, size));
+                Ok(())
+            })
+            .map_err(Error::GuestMemory)?;
+        if saved_regions != regions {
+            return Err(Error::MemoryMismatch);
+        }
+        Ok(())
+    }
+
+    /// Checks that the snapshot holds exactly the devices `ids`, so that no device is dropped or
+    /// left without its state on restore.
+    pub fn check_devices(&self, ids: &[&str]) -> Result<()> {
+        if let Some(&(ref id, _)) = self
+            .devices
+            .iter()
+            .find(|&&(ref id, _)| !ids.contains(&id.as_str()))
+        {
+            return Err(Error::UnknownDevice(id.clone()));
+        }
+        if let Some(id) = ids
+            .iter()
+            .find(|&&id| self.devices.iter().all(|&(ref other, _)| other != id))
+        {
+            return Err(Error::MissingDevice(id.to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes).map_err(Error::Read)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes).map_err(Error::Read)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader
+        .by_ref()
+        .take(len)
+        .read_to_end(&mut bytes)
+        .map_err(Error::Read)?;
+    if (bytes.len() as u64) < len {
+        return Err(Error::Read(io::Error::from(io::ErrorKind::UnexpectedEof)));
+    }
+    Ok(bytes)
+}
+
+// Reads the guest memory regions of a `Memory` section of `len` bytes, straight into the memory
+// mapping of each region.
+fn read_memory<R: Read>(reader: &mut R, len: u64) -> Result<GuestMemory> {
+    let invalid = || Error::InvalidSection(SectionKind::Memory as u32);
+    if len < 8 {
+        return Err(invalid());
+    }
+    let num_regions = read_u64(reader)?;
+    let mut left = len - 8;
+    if num_regions == 0 || num_regions > left / HEADER_SIZE as u64 {
+        return Err(invalid());
+    }
+
+    let mut regions = Vec::with_capacity(num_regions as usize);
+    for _ in 0..num_regions {
+        let guest_addr = GuestAddress(read_u64(reader)? as usize);
+        let size = read_u64(reader)?;
+        left -= HEADER_SIZE as u64;
+        if size > left {
+            return Err(invalid());
+        }
+        left -= size;
+        regions.push((guest_addr, size as usize));
+    }
+    if left != 0 {
+        return Err(invalid());
+    }
+
+    let guest_memory = GuestMemory::new(&regions).map_err(Error::GuestMemory)?;
+    for (guest_addr, size) in regions {
+        guest_memory
+            .read_to_memory(guest_addr, reader, size)
+            .map_err(Error::GuestMemory)?;
+    }
+    Ok(guest_memory)
+}
+
+/// Reads a snapshot written by `SnapshotWriter` from `reader`.
+///
+/// The guest memory is read straight into its memory mapping. The sections of an unknown kind
+/// are skipped.
+pub fn read_snapshot<R: Read>(mut reader: R) -> Result<Snapshot> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(Error::Read)?;
+    if magic != SNAPSHOT_MAGIC {
+        return Err(Error::InvalidMagic);
+    }
+    let version = read_u32(&mut reader)?;
+    if version != SNAPSHOT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    // Reserved.
+    read_u32(&mut reader)?;
+
+    let mut snapshot = Snapshot {
+        guest_memory: None,
+        vcpus: Vec::new(),
+        vm: None,
+        devices: Vec::new(),
+    };
+    loop {
+        let kind = read_u32(&mut reader)?;
+        // Reserved.
+        read_u32(&mut reader)?;
+        let len = read_u64(&mut reader)?;
+
+        match kind {
+            k if k == SectionKind::End as u32 => break,
+            k if k == SectionKind::Vcpu as u32 => {
+                snapshot.vcpus.push(read_bytes(&mut reader, len)?);
+            }
+            k if k == SectionKind::Vm as u32 => {
+                snapshot.vm = Some(read_bytes(&mut reader, len)?);
+            }
+            k if k == SectionKind::Device as u32 => {
+                let data = read_bytes(&mut reader, len)?;
+                let invalid = || Error::InvalidSection(kind);
+                if data.len() < 4 {
+                    return Err(invalid());
+                }
+                let mut id_len = [0u8; 4];
+                id_len.copy_from_slice(&data[..4]);
+                let id_end = 4 + u32::from_le_bytes(id_len) as usize;
+                if id_end > data.len() {
+                    return Err(invalid());
+                }
+                let id = String::from_utf8(data[4..id_end].to_vec()).map_err(|_| invalid())?;
+                snapshot.devices.push((id, data[id_end..].to_vec()));
+            }
+            k if k == SectionKind::Memory as u32 => {
+                snapshot.guest_memory = Some(read_memory(&mut reader, len)?);
+            }
+            _ => {
+                read_bytes(&mut reader, len)?;
+            }
+        }
+    }
+
+    Ok(snapshot)
+}
+
+// The MSRs saved with a vCPU, on top of the ones KVM keeps in the special registers. The MSRs
+// the host doesn't support are left out of the snapshot.
+#[cfg(target_arch = "x86_64")]
+const SAVED_MSRS: &[u32] = &[
+    0x10,        // MSR_IA32_TSC
+    0x11,        // MSR_KVM_WALL_CLOCK
+    0x12,        // MSR_KVM_SYSTEM_TIME
+    0x174,       // MSR_IA32_SYSENTER_CS
+    0x175,       // MSR_IA32_SYSENTER_ESP
+    0x176,       // MSR_IA32_SYSENTER_EIP
+    0x1a0,       // MSR_IA32_MISC_ENABLE
+    0x277,       // MSR_IA32_CR_PAT
+    0x2ff,       // MSR_MTRRdefType
+    0x6e0,       // MSR_IA32_TSCDEADLINE
+    0xc000_0081, // MSR_STAR
+    0xc000_0082, // MSR_LSTAR
+    0xc000_0083, // MSR_CSTAR
+    0xc000_0084, // MSR_SYSCALL_MASK
+    0xc000_0102, // MSR_KERNEL_GS_BASE
+    0xc000_0103, // MSR_TSC_AUX
+    0x4b56_4d00, // MSR_KVM_WALL_CLOCK_NEW
+    0x4b56_4d01, // MSR_KVM_SYSTEM_TIME_NEW
+    0x4b56_4d02, // MSR_KVM_ASYNC_PF_EN
+    0x4b56_4d03, // MSR_KVM_STEAL_TIME
+    0x4b56_4d04, // MSR_KVM_PV_EOI_EN
+];
+
+// The ioctls reading and writing the vCPU and VM state which kvm-ioctls doesn't wrap.
+#[cfg(target_arch = "x86_64")]
+ioctl_ior_nr!(KVM_GET_XSAVE, KVMIO, 0xa4, kvm_xsave);
+#[cfg(target_arch = "x86_64")]
+ioctl_ior_nr!(KVM_GET_XCRS, KVMIO, 0xa6, kvm_xcrs);
+#[cfg(target_arch = "x86_64")]
+ioctl_ior_nr!(KVM_GET_VCPU_EVENTS, KVMIO, 0x9f, kvm_vcpu_events);
+#[cfg(target_arch = "x86_64")]
+ioctl_ior_nr!(KVM_GET_MP_STATE, KVMIO, 0x98, kvm_mp_state);
+#[cfg(target_arch = "x86_64")]
+ioctl_ior_nr!(KVM_GET_CLOCK, KVMIO, 0x7c, kvm_clock_data);
+#[cfg(target_arch = "x86_64")]
+ioctl_iowr_nr!(KVM_GET_IRQCHIP, KVMIO, 0x62, kvm_irqchip);
+#[cfg(target_arch = "x86_64")]
+ioctl_ior_nr!(KVM_GET_PIT2, KVMIO, 0x9f, kvm_pit_state2);
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_XSAVE, KVMIO, 0xa5, kvm_xsave);
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_XCRS, KVMIO, 0xa7, kvm_xcrs);
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_VCPU_EVENTS, KVMIO, 0xa0, kvm_vcpu_events);
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_MP_STATE, KVMIO, 0x99, kvm_mp_state);
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_CLOCK, KVMIO, 0x7b, kvm_clock_data);
+#[cfg(target_arch = "x86_64")]
+ioctl_ior_nr!(KVM_SET_IRQCHIP, KVMIO, 0x63, kvm_irqchip);
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_PIT2, KVMIO, 0xa0, kvm_pit_state2);
+
+// Issues the ioctl `req` on `fd`, which fills the KVM structure `arg`.
+#[cfg(target_arch = "x86_64")]
+fn get_kvm_struct<F: AsRawFd, T>(fd: &F, req: c_ulong, arg: &mut T) -> io::Result<()> {
+    // This is safe because the ioctl number encodes the size of `T`, so the kernel doesn't write
+    // past `arg`.
+    let ret = unsafe { ioctl_with_mut_ref(fd, req, arg) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Issues the ioctl `req` on `fd`, which reads the KVM structure `arg`.
+#[cfg(target_arch = "x86_64")]
+fn set_kvm_struct<F: AsRawFd, T>(fd: &F, req: c_ulong, arg: &T) -> io::Result<()> {
+    // This is safe because the ioctl number encodes the size of `T`, so the kernel doesn't read
+    // past `arg`.
+    let ret = unsafe { ioctl_with_ref(fd, req, arg) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Appends the bytes of the KVM structure `value` to `data`.
+#[cfg(target_arch = "x86_64")]
+fn push_kvm_struct<T: Copy>(data: &mut Vec<u8>, value: &T) {
+    // This is safe because the KVM structures are plain integers and the slice doesn't outlive
+    // `value`.
+    data.extend_from_slice(unsafe {
+        slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+    });
+}
+
+// Reads a KVM structure from the start of `data`, which is then moved past it.
+#[cfg(target_arch = "x86_64")]
+fn read_kvm_struct<T: Copy>(data: &mut &[u8]) -> Option<T> {
+    if data.len() < mem::size_of::<T>() {
+        return None;
+    }
+    // This is safe because the KVM structures are plain integers, for which any content is
+    // valid, and the size was checked above.
+    let value = unsafe { ptr::read_unaligned(data.as_ptr() as *const T) };
+    *data = &data[mem::size_of::<T>()..];
+    Some(value)
+}
+
+// A `kvm_msrs` with room for a single entry.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Default)]
+struct SingleMsr {
+    header: kvm_msrs,
+    entry: kvm_msr_entry,
+}
+
+// The state of a vCPU, laid out in a `Vcpu` section in the order of the fields, with the
+// number of MSRs before their entries.
+#[cfg(target_arch = "x86_64")]
+struct VcpuState {
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    lapic: kvm_lapic_state,
+    xsave: kvm_xsave,
+    xcrs: kvm_xcrs,
+    events: kvm_vcpu_events,
+    mp_state: kvm_mp_state,
+    msrs: Vec<kvm_msr_entry>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl VcpuState {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        push_kvm_struct(&mut data, &self.regs);
+        push_kvm_struct(&mut data, &self.sregs);
+        push_kvm_struct(&mut data, &self.lapic);
+        push_kvm_struct(&mut data, &self.xsave);
+        push_kvm_struct(&mut data, &self.xcrs);
+        push_kvm_struct(&mut data, &self.events);
+        push_kvm_struct(&mut data, &self.mp_state);
+        data.extend_from_slice(&(self.msrs.len() as u32).to_le_bytes());
+        for entry in self.msrs.iter() {
+            push_kvm_struct(&mut data, entry);
+        }
+        data
+    }
+
+    fn from_bytes(mut data: &[u8]) -> Result<Self> {
+        let invalid = || Error::InvalidSection(SectionKind::Vcpu as u32);
+        let data = &mut data;
+        let mut state = VcpuState {
+            regs: read_kvm_struct(data).ok_or_else(invalid)?,
+            sregs: read_kvm_struct(data).ok_or_else(invalid)?,
+            lapic: read_kvm_struct(data).ok_or_else(invalid)?,
+            xsave: read_kvm_struct(data).ok_or_else(invalid)?,
+            xcrs: read_kvm_struct(data).ok_or_else(invalid)?,
+            events: read_kvm_struct(data).ok_or_else(invalid)?,
+            mp_state: read_kvm_struct(data).ok_or_else(invalid)?,
+            msrs: Vec::new(),
+        };
+        let num_msrs = read_kvm_struct(data).ok_or_else(invalid)?;
+        for _ in 0..u32::from_le_bytes(num_msrs) {
+            state.msrs.push(read_kvm_struct(data).ok_or_else(invalid)?);
+        }
+        if !data.is_empty() {
+            return Err(invalid());
+        }
+        Ok(state)
+    }
+}
+
+// Reads the `SAVED_MSRS` of `vcpu` one by one, since KVM stops at the first MSR the host
+// doesn't support.
+#[cfg(target_arch = "x86_64")]
+fn get_msrs(vcpu: &VcpuFd) -> Result<Vec<kvm_msr_entry>> {
+    let mut entries = Vec::new();
+    for &index in SAVED_MSRS {
+        let mut msr = SingleMsr::default();
+        msr.header.nmsrs = 1;
+        msr.entry.index = index;
+        if vcpu.get_msrs(&mut msr.header).map_err(Error::VcpuState)? == 1 {
+            entries.push(msr.entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Returns the payload of the `Vcpu` section of the paused vCPU `vcpu`: its registers, its
+/// LAPIC, its FPU/XSAVE state, its pending events and its MSRs, as laid out by KVM.
+#[cfg(target_arch = "x86_64")]
+pub fn vcpu_state(vcpu: &VcpuFd) -> Result<Vec<u8>> {
+    let mut xsave = kvm_xsave::default();
+    get_kvm_struct(vcpu, KVM_GET_XSAVE(), &mut xsave).map_err(Error::VcpuState)?;
+    let mut xcrs = kvm_xcrs::default();
+    get_kvm_struct(vcpu, KVM_GET_XCRS(), &mut xcrs).map_err(Error::VcpuState)?;
+    let mut events = kvm_vcpu_events::default();
+    get_kvm_struct(vcpu, KVM_GET_VCPU_EVENTS(), &mut events).map_err(Error::VcpuState)?;
+    let mut mp_state = kvm_mp_state::default();
+    get_kvm_struct(vcpu, KVM_GET_MP_STATE(), &mut mp_state).map_err(Error::VcpuState)?;
+
+    let state = VcpuState {
+        regs: vcpu.get_regs().map_err(Error::VcpuState)?,
+        sregs: vcpu.get_sregs().map_err(Error::VcpuState)?,
+        lapic: vcpu.get_lapic().map_err(Error::VcpuState)?,
+        xsave,
+        xcrs,
+        events,
+        mp_state,
+        msrs: get_msrs(vcpu)?,
+    };
+    Ok(state.to_bytes())
+}
+
+/// Loads the state saved by `vcpu_state` in `state` into the vCPU `vcpu`, whose CPUID must
+/// already be set up.
+///
+/// The LAPIC is loaded after the special registers, which hold its base, and the pending events
+/// last, so that loading the rest doesn't drop them.
+#[cfg(target_arch = "x86_64")]
+pub fn restore_vcpu_state(vcpu: &VcpuFd, state: &[u8]) -> Result<()> {
+    let state = VcpuState::from_bytes(state)?;
+    set_kvm_struct(vcpu, KVM_SET_MP_STATE(), &state.mp_state).map_err(Error::VcpuState)?;
+    vcpu.set_regs(&state.regs).map_err(Error::VcpuState)?;
+    vcpu.set_sregs(&state.sregs).map_err(Error::VcpuState)?;
+    set_kvm_struct(vcpu, KVM_SET_XSAVE(), &state.xsave).map_err(Error::VcpuState)?;
+    set_kvm_struct(vcpu, KVM_SET_XCRS(), &state.xcrs).map_err(Error::VcpuState)?;
+    vcpu.set_lapic(&state.lapic).map_err(Error::VcpuState)?;
+    for entry in state.msrs.iter() {
+        let mut msr = SingleMsr::default();
+        msr.header.nmsrs = 1;
+        msr.entry = *entry;
+        vcpu.set_msrs(&msr.header).map_err(Error::VcpuState)?;
+    }
+    set_kvm_struct(vcpu, KVM_SET_VCPU_EVENTS(), &state.events).map_err(Error::VcpuState)
+}
+
+/// Returns the payload of the `Vm` section of the VM `vm`, whose vCPUs are paused: its clock,
+/// followed by the state of its in-kernel PICs, IOAPIC and PIT.
+#[cfg(target_arch = "x86_64")]
+pub fn vm_state(vm: &VmFd) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut clock = kvm_clock_data::default();
+    get_kvm_struct(vm, KVM_GET_CLOCK(), &mut clock).map_err(Error::VmState)?;
+    push_kvm_struct(&mut data, &clock);
+    for &chip_id in &[
+        KVM_IRQCHIP_PIC_MASTER,
+        KVM_IRQCHIP_PIC_SLAVE,
+        KVM_IRQCHIP_IOAPIC,
+    ] {
+        let mut irqchip = kvm_irqchip {
+            chip_id,
+            ..Default::default()
+        };
+        get_kvm_struct(vm, KVM_GET_IRQCHIP(), &mut irqchip).map_err(Error::VmState)?;
+        push_kvm_struct(&mut data, &irqchip);
+    }
+    let mut pit = kvm_pit_state2::default();
+    get_kvm_struct(vm, KVM_GET_PIT2(), &mut pit).map_err(Error::VmState)?;
+    push_kvm_struct(&mut data, &pit);
+    Ok(data)
+}
+
+/// Loads the state saved by `vm_state` in `state` into the VM `vm`, whose in-kernel interrupt
+/// controllers and timer must already be created.
+#[cfg(target_arch = "x86_64")]
+pub fn restore_vm_state(vm: &VmFd, mut state: &[u8]) -> Result<()> {
+    let invalid = || Error::InvalidSection(SectionKind::Vm as u32);
+    let state = &mut state;
+    let mut clock: kvm_clock_data = read_kvm_struct(state).ok_or_else(invalid)?;
+    // KVM only reports the flags, and refuses them when the clock is set.
+    clock.flags = 0;
+    let mut irqchips = Vec::with_capacity(3);
+    for _ in 0..3 {
+        irqchips.push(read_kvm_struct::<kvm_irqchip>(state).ok_or_else(invalid)?);
+    }
+    let pit: kvm_pit_state2 = read_kvm_struct(state).ok_or_else(invalid)?;
+    if !state.is_empty() {
+        return Err(invalid());
+    }
+
+    set_kvm_struct(vm, KVM_SET_CLOCK(), &clock).map_err(Error::VmState)?;
+    for irqchip in irqchips.iter() {
+        set_kvm_struct(vm, KVM_SET_IRQCHIP(), irqchip).map_err(Error::VmState)?;
+    }
+    set_kvm_struct(vm, KVM_SET_PIT2(), &pit).map_err(Error::VmState)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn read_u64(data: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&data[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn test_snapshot_layout() {
+        let guest_mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000), (GuestAddress(0x10000), 0x1000)])
+                .unwrap();
+        guest_mem
+            .write_obj_at_addr(0xabu8, GuestAddress(0x10010))
+            .unwrap();
+
+        let mut writer = SnapshotWriter::new(Vec::new(), &InstanceState::Paused).unwrap();
+        writer.write_section(SectionKind::Vcpu, &[1, 2, 3]).unwrap();
+        writer.write_memory(&guest_mem).unwrap();
+        let snapshot = writer.finish().unwrap();
+
+        // The header.
+        assert_eq!(&snapshot[..8], b"FCSNAPSH");
+        assert_eq!(read_u32(&snapshot, 8), SNAPSHOT_VERSION);
+
+        // The vCPU section.
+        let mut offset = HEADER_SIZE;
+        assert_eq!(read_u32(&snapshot, offset), SectionKind::Vcpu as u32);
+        assert_eq!(read_u64(&snapshot, offset + 8), 3);
+        assert_eq!(&snapshot[offset + 16..offset + 19], &[1, 2, 3]);
+        offset += HEADER_SIZE + 3;
+
+        // The memory section, with the headers of the regions before their content.
+        assert_eq!(read_u32(&snapshot, offset), SectionKind::Memory as u32);
+        assert_eq!(read_u64(&snapshot, offset + 8), 8 + 2 * (16 + 0x1000));
+        offset += HEADER_SIZE;
+        assert_eq!(read_u64(&snapshot, offset), 2);
+        assert_eq!(read_u64(&snapshot, offset + 8), 0);
+        assert_eq!(read_u64(&snapshot, offset + 16), 0x1000);
+        assert_eq!(read_u64(&snapshot, offset + 24), 0x10000);
+        assert_eq!(read_u64(&snapshot, offset + 32), 0x1000);
+        offset += 8 + 2 * 16;
+        assert_eq!(snapshot[offset + 0x1000 + 0x10], 0xab);
+        offset += 2 * 0x1000;
+
+        // The end of the snapshot.
+        assert_eq!(read_u32(&snapshot, offset), SectionKind::End as u32);
+        assert_eq!(read_u64(&snapshot, offset + 8), 0);
+        assert_eq!(snapshot.len(), offset + HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let guest_mem =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000), (GuestAddress(0x10000), 0x2000)])
+                .unwrap();
+        guest_mem
+            .write_obj_at_addr(0x1234_5678u32, GuestAddress(0x100))
+            .unwrap();
+        guest_mem
+            .write_obj_at_addr(0xabu8, GuestAddress(0x11fff))
+            .unwrap();
+
+        let mut writer = SnapshotWriter::new(Vec::new(), &InstanceState::Paused).unwrap();
+        writer.write_section(SectionKind::Vcpu, &[1, 2]).unwrap();
+        writer.write_section(SectionKind::Vm, &[6]).unwrap();
+        writer.write_device("rootfs", &[3, 4, 5]).unwrap();
+        writer.write_device("net0", &[]).unwrap();
+        writer.write_memory(&guest_mem).unwrap();
+        let mut data = writer.finish().unwrap();
+        // Sections of unknown kinds are skipped.
+        let end = data.split_off(data.len() - HEADER_SIZE);
+        data.extend_from_slice(&42u32.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&7u64.to_le_bytes());
+        data.extend_from_slice(&[0xff; 7]);
+        data.extend_from_slice(&end);
+
+        let snapshot = read_snapshot(&data[..]).unwrap();
+        assert_eq!(snapshot.vcpus, vec![vec![1, 2]]);
+        assert_eq!(snapshot.vm, Some(vec![6]));
+        assert_eq!(
+            snapshot.devices,
+            vec![
+                (String::from("rootfs"), vec![3, 4, 5]),
+                (String::from("net0"), vec![])
+            ]
+        );
+
+        let restored_mem = snapshot.guest_memory.as_ref().unwrap();
+        assert_eq!(restored_mem.num_regions(), 2);
+        assert_eq!(restored_mem.end_addr(), GuestAddress(0x12000));
+        let value: u32 = restored_mem
+            .read_obj_from_addr(GuestAddress(0x100))
+            .unwrap();
+        assert_eq!(value, 0x1234_5678);
+        let value: u8 = restored_mem
+            .read_obj_from_addr(GuestAddress(0x11fff))
+            .unwrap();
+        assert_eq!(value, 0xab);
+        // The gap between the regions is not restored as memory.
+        assert!(!restored_mem.address_in_range(GuestAddress(0x1000)));
+
+        // The vCPUs and the memory must match the configured ones.
+        assert!(snapshot.check_vcpus(1).is_ok());
+        match snapshot.check_vcpus(2) {
+            Err(Error::VcpuCount(1)) => (),
+            _ => panic!("Expected a vCPU count error."),
+        }
+        assert!(snapshot
+            .check_memory(&[(GuestAddress(0), 0x1000), (GuestAddress(0x10000), 0x2000)])
+            .is_ok());
+        match snapshot.check_memory(&[(GuestAddress(0), 0x1000)]) {
+            Err(Error::MemoryMismatch) => (),
+            _ => panic!("Expected a memory mismatch error."),
+        }
+
+        // The device topology must match the configured one.
+        assert!(snapshot.check_devices(&["net0", "rootfs"]).is_ok());
+        match snapshot.check_devices(&["rootfs"]) {
+            Err(Error::UnknownDevice(id)) => assert_eq!(id, "net0"),
+            _ => panic!("Expected an unknown device error."),
+        }
+        match snapshot.check_devices(&["net0", "rootfs", "scratch"]) {
+            Err(Error::MissingDevice(id)) => assert_eq!(id, "scratch"),
+            _ => panic!("Expected a missing device error."),
+        }
+
+        // Error cases: not a snapshot, an unsupported version and a truncated snapshot.
+        match read_snapshot(&b"ELF"[..]) {
+            Err(Error::Read(_)) => (),
+            _ => panic!("Expected a read error."),
+        }
+        let mut bad_data = data.clone();
+        bad_data[0] = b'X';
+        match read_snapshot(&bad_data[..]) {
+            Err(Error::InvalidMagic) => (),
+            _ => panic!("Expected an invalid magic error."),
+        }
+        let mut bad_data = data.clone();
+        bad_data[8] = 2;
+        match read_snapshot(&bad_data[..]) {
+            Err(Error::UnsupportedVersion(2)) => (),
+            _ => panic!("Expected an unsupported version error."),
+        }
+        match read_snapshot(&data[..data.len() - HEADER_SIZE - 1]) {
+            Err(Error::Read(_)) => (),
+            _ => panic!("Expected a read error."),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_state() {
+        use kvm_ioctls::Kvm;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        // The LAPIC is emulated by the in-kernel irqchip.
+        vm.create_irq_chip().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = 0x1000;
+        vcpu.set_regs(&regs).unwrap();
+
+        let state = vcpu_state(&vcpu).unwrap();
+        // The instruction pointer follows the 16 general purpose registers.
+        assert_eq!(read_u64(&state, 16 * 8), 0x1000);
+        let parsed = VcpuState::from_bytes(&state).unwrap();
+        assert_eq!(parsed.regs.rip, 0x1000);
+        // Every host supports the TSC.
+        assert!(parsed.msrs.iter().any(|entry| entry.index == 0x10));
+        assert_eq!(parsed.to_bytes(), state);
+
+        // Restore the state in another vCPU, which then holds the same state.
+        let other_vcpu = vm.create_vcpu(1).unwrap();
+        restore_vcpu_state(&other_vcpu, &state).unwrap();
+        assert_eq!(other_vcpu.get_regs().unwrap().rip, 0x1000);
+        let other_state = VcpuState::from_bytes(&vcpu_state(&other_vcpu).unwrap()).unwrap();
+        assert_eq!(&other_state.lapic.regs[..], &parsed.lapic.regs[..]);
+        assert_eq!(&other_state.xsave.region[..], &parsed.xsave.region[..]);
+        assert_eq!(other_state.mp_state.mp_state, parsed.mp_state.mp_state);
+        let msr_value = |state: &VcpuState, index| {
+            state
+                .msrs
+                .iter()
+                .find(|entry| entry.index == index)
+                .map(|entry| entry.data)
+        };
+        // The SYSENTER_CS MSR keeps the value it was restored with.
+        assert_eq!(msr_value(&other_state, 0x174), msr_value(&parsed, 0x174));
+        match restore_vcpu_state(&other_vcpu, &state[1..]) {
+            Err(Error::InvalidSection(kind)) => assert_eq!(kind, SectionKind::Vcpu as u32),
+            _ => panic!("Expected an invalid section error."),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_save_restore_resume() {
+        use kvm_ioctls::VcpuExit;
+        use vstate::Vm;
+        use KvmContext;
+
+        // Real mode code writing 1, then 2 to the guest memory, exiting to the VMM after each
+        // write, since a halt is handled in the kernel with an irqchip:
+        //   mov byte [0x2000], 1
+        //   out 0x10, al
+        //   mov byte [0x2000], 2
+        //   out 0x10, al
+        let code = [
+            0xc6, 0x06, 0x00, 0x20, 0x01, 0xe6, 0x10, 0xc6, 0x06, 0x00, 0x20, 0x02, 0xe6, 0x10,
+        ];
+        let new_vm = |guest_mem: GuestMemory| {
+            let kvm = KvmContext::new().unwrap();
+            let mut vm = Vm::new(kvm.fd()).unwrap();
+            vm.memory_init(guest_mem, &kvm).unwrap();
+            vm.setup_irqchip().unwrap();
+            let vcpu = vm.get_fd().create_vcpu(0).unwrap();
+            (vm, vcpu)
+        };
+
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        guest_mem
+            .write_slice_at_addr(&code, GuestAddress(0x1000))
+            .unwrap();
+        let (vm, vcpu) = new_vm(guest_mem.clone());
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = 0x1000;
+        regs.rflags = 2;
+        vcpu.set_regs(&regs).unwrap();
+        // The vCPU stays out of the guest once it exits after the first write.
+        match vcpu.run() {
+            Ok(VcpuExit::IoOut(0x10, _)) => (),
+            _ => panic!("Expected the vCPU to exit on the port write."),
+        }
+
+        let mut writer = SnapshotWriter::new(Vec::new(), &InstanceState::Paused).unwrap();
+        writer
+            .write_section(SectionKind::Vcpu, &vcpu_state(&vcpu).unwrap())
+            .unwrap();
+        writer
+            .write_section(SectionKind::Vm, &vm_state(vm.get_fd()).unwrap())
+            .unwrap();
+        writer.write_device("rootfs", &[]).unwrap();
+        writer.write_memory(&guest_mem).unwrap();
+        let data = writer.finish().unwrap();
+
+        let snapshot = read_snapshot(&data[..]).unwrap();
+        assert!(snapshot.check_devices(&["rootfs"]).is_ok());
+        let restored_mem = snapshot.guest_memory.unwrap();
+        let value: u8 = restored_mem
+            .read_obj_from_addr(GuestAddress(0x2000))
+            .unwrap();
+        assert_eq!(value, 1);
+
+        // The restored vCPU resumes right after the first exit.
+        let (vm, vcpu) = new_vm(restored_mem.clone());
+        restore_vm_state(vm.get_fd(), snapshot.vm.as_ref().unwrap()).unwrap();
+        restore_vcpu_state(&vcpu, &snapshot.vcpus[0]).unwrap();
+        match vcpu.run() {
+            Ok(VcpuExit::IoOut(0x10, _)) => (),
+            _ => panic!("Expected the vCPU to exit on the port write."),
+        }
+        let value: u8 = restored_mem
+            .read_obj_from_addr(GuestAddress(0x2000))
+            .unwrap();
+        assert_eq!(value, 2);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vm_state() {
+        use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
+        use kvm_ioctls::Kvm;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        // Error case: there is no irqchip to save.
+        match vm_state(&vm) {
+            Err(Error::VmState(_)) => (),
+            _ => panic!("Expected a VM state error."),
+        }
+
+        vm.create_irq_chip().unwrap();
+        let pit_config = kvm_pit_config {
+            flags: KVM_PIT_SPEAKER_DUMMY,
+            ..Default::default()
+        };
+        vm.create_pit2(pit_config).unwrap();
+        let state = vm_state(&vm).unwrap();
+        assert_eq!(
+            state.len(),
+            mem::size_of::<kvm_clock_data>()
+                + 3 * mem::size_of::<kvm_irqchip>()
+                + mem::size_of::<kvm_pit_state2>()
+        );
+        // The chips follow the clock, in the order of their ids.
+        let chips = &state[mem::size_of::<kvm_clock_data>()..];
+        for chip_id in 0..3 {
+            let offset = chip_id as usize * mem::size_of::<kvm_irqchip>();
+            assert_eq!(read_u32(chips, offset), chip_id);
+        }
+
+        // Restore the state in another VM.
+        let other_vm = kvm.create_vm().unwrap();
+        other_vm.create_irq_chip().unwrap();
+        other_vm.create_pit2(pit_config).unwrap();
+        restore_vm_state(&other_vm, &state).unwrap();
+        let other_state = vm_state(&other_vm).unwrap();
+        let chips_len = 3 * mem::size_of::<kvm_irqchip>();
+        assert_eq!(
+            &other_state[mem::size_of::<kvm_clock_data>()..][..chips_len],
+            &chips[..chips_len]
+        );
+        match restore_vm_state(&other_vm, &state[1..]) {
+            Err(Error::InvalidSection(kind)) => assert_eq!(kind, SectionKind::Vm as u32),
+            _ => panic!("Expected an invalid section error."),
+        }
+    }
+
+    #[test]
+    fn test_save_running_microvm() {
+        // Error case: the vCPUs of the microVM are still running.
+        match SnapshotWriter::new(Vec::new(), &InstanceState::Running) {
+            Err(Error::NotPaused) => (),
+            _ => panic!("Expected a not paused error."),
+        }
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let e = Error::Write(io::Error::from_raw_os_error(28));
+        assert!(format!("{}", e).starts_with("Cannot write the snapshot: "));
+        let e = Error::VcpuNotResponding(1);
+        assert_eq!(format!("{}", e), "The vCPU 1 didn't hand its state over.");
+        let e = Error::VcpuCount(2);
+        assert_eq!(
+            format!("{}", e),
+            "The snapshot holds 2 vCPUs, which doesn't match the configured vCPU count."
+        );
+    }
+}
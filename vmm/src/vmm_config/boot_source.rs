@@ -3,6 +3,8 @@
 
 use std::fmt::{Display, Formatter, Result};
 
+use arch;
+
 /// Strongly typed data structure used to configure the boot source of the
 /// microvm.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
@@ -14,6 +16,46 @@ pub struct BootSourceConfig {
     /// kernel command line is used: `reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0`.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub boot_args: Option<String>,
+    /// The maximum length in bytes of the kernel command line, including the boot arguments
+    /// added for the attached devices. If this field is uninitialized, the maximum length
+    /// supported by the kernel on the current architecture is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmdline_max_size: Option<usize>,
+    /// Pass a random seed, freshly generated by the host at each boot, to the guest kernel. The
+    /// guest uses it to seed its RNG early during boot, before any entropy source is available.
+    #[serde(default)]
+    pub rng_seed: bool,
+    /// The device the guest uses as its console, passed to the kernel through the `console=`
+    /// boot argument, which replaces the one from the boot arguments. If this field is
+    /// uninitialized, no console is set up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub console: Option<ConsoleDevice>,
+    /// Boot without a root filesystem, e.g. from the network or from an initramfs built into the
+    /// kernel. Otherwise, starting the microVM fails unless a block device is marked as root or
+    /// the boot arguments set `root=`.
+    #[serde(default)]
+    pub diskless: bool,
+}
+
+/// Devices the guest can use as its console.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ConsoleDevice {
+    /// The legacy serial port.
+    #[serde(rename = "serial")]
+    Serial,
+    /// A virtio console, which only forwards the output of the guest.
+    #[serde(rename = "virtio")]
+    Virtio,
+}
+
+impl ConsoleDevice {
+    /// Returns the name of the console device in the guest.
+    pub fn guest_name(self) -> &'static str {
+        match self {
+            ConsoleDevice::Serial => "ttyS0",
+            ConsoleDevice::Virtio => "hvc0",
+        }
+    }
 }
 
 /// Errors associated with actions on `BootSourceConfig`.
@@ -23,6 +65,12 @@ pub enum BootSourceConfigError {
     InvalidKernelPath,
     /// The kernel command line is invalid.
     InvalidKernelCommandLine,
+    /// The maximum length of the kernel command line is zero or above the kernel limit.
+    InvalidKernelCommandLineMaxSize,
+    /// Boot arguments cannot be appended before the boot source is configured.
+    MissingBootSource,
+    /// The boot argument is set by Firecracker and cannot be appended.
+    ReservedBootArg(String),
     /// The boot source cannot be update post boot.
     UpdateNotAllowedPostBoot,
 }
@@ -37,6 +85,18 @@ impl Display for BootSourceConfigError {
                  invalid permissions.",
             ),
             InvalidKernelCommandLine => write!(f, "The kernel command line is invalid!"),
+            InvalidKernelCommandLineMaxSize => write!(
+                f,
+                "The maximum length of the kernel command line must be between 1 and {} bytes.",
+                arch::CMDLINE_MAX_SIZE
+            ),
+            MissingBootSource => write!(
+                f,
+                "The boot source must be configured before appending boot arguments."
+            ),
+            ReservedBootArg(ref arg) => {
+                write!(f, "The boot argument {} is reserved for Firecracker.", arg)
+            }
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.")
             }
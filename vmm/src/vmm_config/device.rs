@@ -0,0 +1,75 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use devices::DeviceHealth;
+
+/// The type of a device attached to the microVM.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum DeviceType {
+    /// A virtio block device.
+    #[serde(rename = "block")]
+    Block,
+    /// A virtio network device.
+    #[serde(rename = "net")]
+    Net,
+}
+
+/// The strongly typed that describes a device attached to the microVM and its health status.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    /// The ID of the device, as configured through the API.
+    pub id: String,
+    /// The type of the device.
+    #[serde(rename = "type")]
+    pub device_type: DeviceType,
+    /// The health status of the device: `Healthy`, `Degraded` or `Failed`.
+    pub health: String,
+    /// The reason why the device is not healthy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl DeviceInfo {
+    /// Creates the description of device `id`, given its current health status.
+    pub fn new(id: String, device_type: DeviceType, health: &DeviceHealth) -> Self {
+        let reason = match *health {
+            DeviceHealth::Healthy => None,
+            DeviceHealth::Degraded(ref reason) | DeviceHealth::Failed(ref reason) => {
+                Some(reason.clone())
+            }
+        };
+        DeviceInfo {
+            id,
+            device_type,
+            health: health.to_string(),
+            reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_info() {
+        let info = DeviceInfo::new(
+            String::from("rootfs"),
+            DeviceType::Block,
+            &DeviceHealth::Healthy,
+        );
+        assert_eq!(info.health, "Healthy");
+        assert!(info.reason.is_none());
+
+        let info = DeviceInfo::new(
+            String::from("rootfs"),
+            DeviceType::Block,
+            &DeviceHealth::Failed(String::from("Read-only file system (os error 30)")),
+        );
+        assert_eq!(info.health, "Failed");
+        assert_eq!(
+            info.reason,
+            Some(String::from("Read-only file system (os error 30)"))
+        );
+    }
+}
@@ -4,13 +4,26 @@
 use std;
 use std::collections::VecDeque;
 use std::fmt::{Display, Formatter};
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
 use std::path::PathBuf;
 use std::result;
+use std::time::Duration;
 
 use super::RateLimiterConfig;
+use devices::virtio::block::{IoRetryPolicy, MAX_IN_FLIGHT_REQUESTS, MAX_SERIAL_LEN};
+use devices::virtio::is_valid_feature_mask;
 
 type Result<T> = result::Result<T, DriveError>;
 
+/// The default maximum amount of host memory taken by all the ramdisks, in MiB.
+pub const DEFAULT_MAX_RAMDISK_SIZE_MIB: u32 = 1024;
+/// The maximum number of attempts of a block device request failing with transient errors.
+pub const MAX_IO_RETRY_ATTEMPTS: u32 = 5;
+/// The maximum delay in milliseconds before retrying a failed block device request for the
+/// first time. The device stalls while waiting, so the delays are kept short.
+pub const MAX_IO_RETRY_BACKOFF_MS: u64 = 100;
+
 /// Errors associated with the operations allowed on a drive.
 #[derive(Debug, PartialEq)]
 pub enum DriveError {
@@ -20,16 +33,33 @@ pub enum DriveError {
     InvalidBlockDeviceID,
     /// The block device path is invalid.
     InvalidBlockDevicePath,
+    /// The block device serial doesn't fit in the virtio device ID.
+    InvalidSerial,
+    /// A ramdisk block device has a path on the host, is read-only, or uses direct I/O.
+    InvalidRamdisk,
+    /// The ramdisk is empty, or the ramdisks would take more host memory than the given
+    /// amount, in MiB.
+    InvalidRamdiskSize(u32),
+    /// The I/O retry policy makes no attempt, too many attempts, or waits too long.
+    InvalidIoRetry,
+    /// The virtio feature mask clears a mandatory feature.
+    InvalidVirtioFeatureMask,
+    /// The maximum number of in-flight requests is 0 or larger than the queue.
+    InvalidMaxInFlightRequests,
     /// The block device path was already used for a different drive.
     BlockDevicePathAlreadyExists,
     /// Cannot update the block device.
     BlockDeviceUpdateFailed,
+    /// Cannot flush the block devices with the given IDs.
+    BlockDeviceFlushFailed(Vec<String>),
     /// Cannot perform the requested operation before booting the microVM.
     OperationNotAllowedPreBoot,
     /// Cannot perform the requested operation after booting the microVM.
     UpdateNotAllowedPostBoot,
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// Direct I/O was requested for a path that is not a block device.
+    NotABlockDevice,
 }
 
 impl Display for DriveError {
@@ -41,13 +71,50 @@ impl Display for DriveError {
             }
             InvalidBlockDeviceID => write!(f, "Invalid block device ID!"),
             InvalidBlockDevicePath => write!(f, "Invalid block device path!"),
+            InvalidSerial => write!(
+                f,
+                "The block device serial must be at most {} bytes long!",
+                MAX_SERIAL_LEN
+            ),
+            InvalidRamdisk => write!(
+                f,
+                "A ramdisk block device can't have a path on the host, be read-only or use \
+                 direct I/O!"
+            ),
+            InvalidRamdiskSize(max_size_mib) => write!(
+                f,
+                "The ramdisk size must be positive, and the ramdisks can take at most {} MiB \
+                 of host memory!",
+                max_size_mib
+            ),
+            InvalidIoRetry => write!(
+                f,
+                "The I/O retry policy must make between 1 and {} attempts, with a backoff of at \
+                 most {} ms!",
+                MAX_IO_RETRY_ATTEMPTS, MAX_IO_RETRY_BACKOFF_MS
+            ),
+            InvalidVirtioFeatureMask => write!(
+                f,
+                "The virtio feature mask must keep the VIRTIO_F_VERSION_1 feature (bit 32)!"
+            ),
+            InvalidMaxInFlightRequests => write!(
+                f,
+                "The maximum number of in-flight requests must be between 1 and {}!",
+                MAX_IN_FLIGHT_REQUESTS
+            ),
             BlockDevicePathAlreadyExists => write!(
                 f,
                 "The block device path was already added to a different drive!"
             ),
             BlockDeviceUpdateFailed => write!(f, "The update operation failed!"),
+            BlockDeviceFlushFailed(ref drive_ids) => write!(
+                f,
+                "Failed to flush the block devices: {}",
+                drive_ids.join(", ")
+            ),
             OperationNotAllowedPreBoot => write!(f, "Operation not allowed pre-boot!"),
             RootBlockDeviceAlreadyAdded => write!(f, "A root block device already exists!"),
+            NotABlockDevice => write!(f, "Direct I/O is only supported for host block devices!"),
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.")
             }
@@ -61,7 +128,8 @@ impl Display for DriveError {
 pub struct BlockDeviceConfig {
     /// Unique identifier of the drive.
     pub drive_id: String,
-    /// Path of the drive.
+    /// Path of the drive. It is left empty for ramdisks.
+    #[serde(default)]
     pub path_on_host: PathBuf,
     /// If set to true, it makes the current device the root block device.
     /// Setting this flag to true will mount the block device in the
@@ -71,10 +139,53 @@ pub struct BlockDeviceConfig {
     /// optional and it will be used only if the `is_root_device` field is true.
     pub partuuid: Option<String>,
     /// If set to true, the drive is opened in read-only mode. Otherwise, the
-    /// drive is opened as read-write.
+    /// drive is opened as read-write. The microVMs with read-only drives backed by the same
+    /// file read it from the same pages of the host page cache.
     pub is_read_only: bool,
+    /// If set to true, the drive is opened with O_DIRECT, bypassing the host page cache.
+    /// In this case, `path_on_host` must be a host block device, such as a raw partition. Guest
+    /// requests which are not aligned to its logical block size go through an intermediate
+    /// buffer.
+    #[serde(default)]
+    pub direct_io: bool,
+    /// If set, the drive is backed by this many MiB of host memory, which start zeroed and are
+    /// discarded when Firecracker exits, instead of a file on the host.
+    pub ramdisk_size_mib: Option<u32>,
+    /// The serial returned to the guest as the ID of the device. It defaults to the drive ID.
+    pub serial: Option<String>,
     /// Rate Limiter for I/O operations.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// Retry the requests failing with transient errors of the backing file, such as a network
+    /// backed storage timing out, before reporting the error to the guest.
+    pub io_retry: Option<IoRetryConfig>,
+    /// The virtio features the device may offer to the guest, ANDed with the features it
+    /// supports. It must keep VIRTIO_F_VERSION_1 (bit 32).
+    pub virtio_feature_mask: Option<u64>,
+    /// The most requests the device takes from its queue before completing them. The requests
+    /// the guest queued past this limit are deferred, so a guest flooding the device can't
+    /// monopolize the VMM.
+    pub max_in_flight_requests: Option<u16>,
+}
+
+/// How the requests failing with a transient error of the backing file, i.e. `EAGAIN`, `EINTR`
+/// or `ETIMEDOUT`, are retried. The other errors are reported to the guest right away.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IoRetryConfig {
+    /// The number of times a request is attempted, including the first attempt.
+    pub max_attempts: u32,
+    /// The delay in milliseconds before the first retry, doubled for each following retry.
+    pub backoff_ms: u64,
+}
+
+impl IoRetryConfig {
+    /// Returns the retry policy of the block device.
+    pub fn policy(&self) -> IoRetryPolicy {
+        IoRetryPolicy {
+            max_attempts: self.max_attempts,
+            backoff: Duration::from_millis(self.backoff_ms),
+        }
+    }
 }
 
 impl BlockDeviceConfig {
@@ -92,16 +203,51 @@ impl BlockDeviceConfig {
     pub fn path_on_host(&self) -> &PathBuf {
         &self.path_on_host
     }
+
+    /// Checks whether the drive is opened with O_DIRECT.
+    pub fn is_direct_io(&self) -> bool {
+        self.direct_io
+    }
+
+    // Direct I/O is only allowed on host block devices.
+    fn validate_direct_io(&self) -> Result<()> {
+        if self.direct_io {
+            let is_block_device = fs::metadata(&self.path_on_host)
+                .map(|metadata| metadata.file_type().is_block_device())
+                .unwrap_or(false);
+            if !is_block_device {
+                return Err(DriveError::NotABlockDevice);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the size in bytes of the ramdisk backing the drive, if it is a ramdisk.
+    pub fn ramdisk_size(&self) -> Option<u64> {
+        self.ramdisk_size_mib
+            .map(|size_mib| u64::from(size_mib) << 20)
+    }
+
+    /// Returns the serial advertised to the guest, which is the drive ID unless configured.
+    pub fn serial(&self) -> &String {
+        self.serial.as_ref().unwrap_or(&self.drive_id)
+    }
 }
 
 /// Wrapper for the collection that holds all the Block Devices Configs
-#[derive(Default)]
 pub struct BlockDeviceConfigs {
     /// A list of `BlockDeviceConfig` objects.
     pub config_list: VecDeque<BlockDeviceConfig>,
     has_root_block: bool,
     has_partuuid_root: bool,
     read_only_root: bool,
+    max_ramdisk_size_mib: u32,
+}
+
+impl Default for BlockDeviceConfigs {
+    fn default() -> Self {
+        BlockDeviceConfigs::new()
+    }
 }
 
 impl BlockDeviceConfigs {
@@ -112,9 +258,15 @@ impl BlockDeviceConfigs {
             has_root_block: false,
             has_partuuid_root: false,
             read_only_root: false,
+            max_ramdisk_size_mib: DEFAULT_MAX_RAMDISK_SIZE_MIB,
         }
     }
 
+    /// Sets the maximum amount of host memory, in MiB, which all the ramdisks can take.
+    pub fn set_max_ramdisk_size_mib(&mut self, max_size_mib: u32) {
+        self.max_ramdisk_size_mib = max_size_mib;
+    }
+
     /// Checks whether any of the added BlockDevice is the root.
     pub fn has_root_block_device(&self) -> bool {
         self.has_root_block
@@ -148,6 +300,36 @@ impl BlockDeviceConfigs {
     /// the existing entry.
     /// Inserting a secondary root block device will fail.
     pub fn insert(&mut self, block_device_config: BlockDeviceConfig) -> Result<()> {
+        if block_device_config
+            .serial
+            .as_ref()
+            .map_or(false, |serial| serial.len() > MAX_SERIAL_LEN)
+        {
+            return Err(DriveError::InvalidSerial);
+        }
+        if let Some(size_mib) = block_device_config.ramdisk_size_mib {
+            self.check_ramdisk(&block_device_config, size_mib)?;
+        }
+        if block_device_config.io_retry.map_or(false, |io_retry| {
+            io_retry.max_attempts == 0
+                || io_retry.max_attempts > MAX_IO_RETRY_ATTEMPTS
+                || io_retry.backoff_ms > MAX_IO_RETRY_BACKOFF_MS
+        }) {
+            return Err(DriveError::InvalidIoRetry);
+        }
+        if block_device_config
+            .virtio_feature_mask
+            .map_or(false, |mask| !is_valid_feature_mask(mask))
+        {
+            return Err(DriveError::InvalidVirtioFeatureMask);
+        }
+        if block_device_config
+            .max_in_flight_requests
+            .map_or(false, |max| max == 0 || max > MAX_IN_FLIGHT_REQUESTS)
+        {
+            return Err(DriveError::InvalidMaxInFlightRequests);
+        }
+
         // If the id of the drive already exists in the list, the operation is update.
         match self.get_index_of_drive_id(&block_device_config.drive_id) {
             Some(index) => self.update(index, block_device_config),
@@ -155,17 +337,46 @@ impl BlockDeviceConfigs {
         }
     }
 
-    fn create(&mut self, block_device_config: BlockDeviceConfig) -> Result<()> {
-        // check if the path exists
-        if !block_device_config.path_on_host.exists() {
-            return Err(DriveError::InvalidBlockDevicePath);
+    // Checks that a ramdisk has no backing file, and that it fits in the host memory allowed
+    // for the ramdisks along with the other ones.
+    fn check_ramdisk(&self, block_device_config: &BlockDeviceConfig, size_mib: u32) -> Result<()> {
+        if !block_device_config.path_on_host.as_os_str().is_empty()
+            || block_device_config.is_read_only
+            || block_device_config.direct_io
+        {
+            return Err(DriveError::InvalidRamdisk);
         }
-
-        if self
-            .get_index_of_drive_path(&block_device_config.path_on_host)
-            .is_some()
+        // The ramdisk replaces the drive with the same ID, if any.
+        let other_ramdisks_size_mib: u64 = self
+            .config_list
+            .iter()
+            .filter(|cfg| cfg.drive_id != block_device_config.drive_id)
+            .filter_map(|cfg| cfg.ramdisk_size_mib)
+            .map(u64::from)
+            .sum();
+        if size_mib == 0
+            || other_ramdisks_size_mib + u64::from(size_mib) > u64::from(self.max_ramdisk_size_mib)
         {
-            return Err(DriveError::BlockDevicePathAlreadyExists);
+            return Err(DriveError::InvalidRamdiskSize(self.max_ramdisk_size_mib));
+        }
+        Ok(())
+    }
+
+    fn create(&mut self, block_device_config: BlockDeviceConfig) -> Result<()> {
+        // Ramdisks have no backing file.
+        if block_device_config.ramdisk_size_mib.is_none() {
+            // check if the path exists
+            if !block_device_config.path_on_host.exists() {
+                return Err(DriveError::InvalidBlockDevicePath);
+            }
+            block_device_config.validate_direct_io()?;
+
+            if self
+                .get_index_of_drive_path(&block_device_config.path_on_host)
+                .is_some()
+            {
+                return Err(DriveError::BlockDevicePathAlreadyExists);
+            }
         }
 
         // check whether the Device Config belongs to a root device
@@ -192,9 +403,12 @@ impl BlockDeviceConfigs {
     /// Updates a Block Device Config. The update fails if it would result in two
     /// root block devices.
     fn update(&mut self, mut index: usize, new_config: BlockDeviceConfig) -> Result<()> {
-        // Check if the path exists
-        if !new_config.path_on_host.exists() {
-            return Err(DriveError::InvalidBlockDevicePath);
+        // Check if the path exists, unless the drive is a ramdisk.
+        if new_config.ramdisk_size_mib.is_none() {
+            if !new_config.path_on_host.exists() {
+                return Err(DriveError::InvalidBlockDevicePath);
+            }
+            new_config.validate_direct_io()?;
         }
 
         // Check if the root block device is being updated.
@@ -241,8 +455,14 @@ mod tests {
                 is_root_device: self.is_root_device,
                 partuuid: self.partuuid.clone(),
                 is_read_only: self.is_read_only,
+                direct_io: self.direct_io,
+                ramdisk_size_mib: self.ramdisk_size_mib,
+                serial: self.serial.clone(),
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
+                io_retry: self.io_retry,
+                virtio_feature_mask: self.virtio_feature_mask,
+                max_in_flight_requests: self.max_in_flight_requests,
             }
         }
     }
@@ -264,8 +484,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: dummy_id.clone(),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -286,6 +512,272 @@ mod tests {
             .is_some());
     }
 
+    #[test]
+    fn test_add_direct_io_block_device() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            direct_io: true,
+            ramdisk_size_mib: None,
+            serial: None,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert_eq!(
+            block_devices_configs.insert(dummy_block_device),
+            Err(DriveError::NotABlockDevice)
+        );
+        assert_eq!(block_devices_configs.config_list.len(), 0);
+    }
+
+    #[test]
+    fn test_block_device_serial() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
+        };
+        // The serial defaults to the drive ID.
+        assert_eq!(dummy_block_device.serial(), "1");
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        dummy_block_device.serial = Some(String::from("a-serial-which-does-not-fit"));
+        assert_eq!(
+            block_devices_configs.insert(dummy_block_device.clone()),
+            Err(DriveError::InvalidSerial)
+        );
+
+        dummy_block_device.serial = Some(String::from("custom-serial"));
+        assert!(block_devices_configs.insert(dummy_block_device).is_ok());
+        assert_eq!(
+            block_devices_configs.config_list[0].serial(),
+            "custom-serial"
+        );
+    }
+
+    #[test]
+    fn test_block_device_io_retry() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_retry: Some(IoRetryConfig {
+                max_attempts: 0,
+                backoff_ms: 10,
+            }),
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert_eq!(
+            block_devices_configs.insert(dummy_block_device.clone()),
+            Err(DriveError::InvalidIoRetry)
+        );
+        for &(max_attempts, backoff_ms) in &[
+            (MAX_IO_RETRY_ATTEMPTS + 1, 10),
+            (3, MAX_IO_RETRY_BACKOFF_MS + 1),
+        ] {
+            dummy_block_device.io_retry = Some(IoRetryConfig {
+                max_attempts,
+                backoff_ms,
+            });
+            assert_eq!(
+                block_devices_configs.insert(dummy_block_device.clone()),
+                Err(DriveError::InvalidIoRetry)
+            );
+        }
+        assert_eq!(
+            DriveError::InvalidIoRetry.to_string(),
+            "The I/O retry policy must make between 1 and 5 attempts, with a backoff of at most \
+             100 ms!"
+        );
+
+        dummy_block_device.io_retry = Some(IoRetryConfig {
+            max_attempts: MAX_IO_RETRY_ATTEMPTS,
+            backoff_ms: MAX_IO_RETRY_BACKOFF_MS,
+        });
+        assert!(block_devices_configs.insert(dummy_block_device).is_ok());
+        assert_eq!(
+            block_devices_configs.config_list[0]
+                .io_retry
+                .unwrap()
+                .policy(),
+            IoRetryPolicy {
+                max_attempts: 5,
+                backoff: Duration::from_millis(100),
+            }
+        );
+    }
+
+    #[test]
+    fn test_block_device_virtio_feature_mask() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_retry: None,
+            // Clears VIRTIO_F_VERSION_1.
+            virtio_feature_mask: Some(0xffff_ffff),
+            max_in_flight_requests: None,
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert_eq!(
+            block_devices_configs.insert(dummy_block_device.clone()),
+            Err(DriveError::InvalidVirtioFeatureMask)
+        );
+        assert!(block_devices_configs.config_list.is_empty());
+
+        // Clearing an optional feature, like VIRTIO_BLK_F_FLUSH, is fine.
+        dummy_block_device.virtio_feature_mask = Some(!(1u64 << 9));
+        assert!(block_devices_configs.insert(dummy_block_device).is_ok());
+        assert_eq!(
+            block_devices_configs.config_list[0].virtio_feature_mask,
+            Some(!(1u64 << 9))
+        );
+    }
+
+    #[test]
+    fn test_block_device_max_in_flight_requests() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: Some(0),
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        for &max in &[0, MAX_IN_FLIGHT_REQUESTS + 1] {
+            dummy_block_device.max_in_flight_requests = Some(max);
+            assert_eq!(
+                block_devices_configs.insert(dummy_block_device.clone()),
+                Err(DriveError::InvalidMaxInFlightRequests)
+            );
+        }
+        assert!(block_devices_configs.config_list.is_empty());
+        assert_eq!(
+            DriveError::InvalidMaxInFlightRequests.to_string(),
+            "The maximum number of in-flight requests must be between 1 and 256!"
+        );
+
+        dummy_block_device.max_in_flight_requests = Some(MAX_IN_FLIGHT_REQUESTS);
+        assert!(block_devices_configs.insert(dummy_block_device).is_ok());
+        assert_eq!(
+            block_devices_configs.config_list[0].max_in_flight_requests,
+            Some(256)
+        );
+    }
+
+    #[test]
+    fn test_block_device_ramdisk() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut ramdisk = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: Some(64),
+            serial: None,
+            drive_id: String::from("scratch"),
+            rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
+        };
+
+        // Error case: a ramdisk has no backing file.
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert_eq!(
+            block_devices_configs.insert(ramdisk.clone()),
+            Err(DriveError::InvalidRamdisk)
+        );
+        ramdisk.path_on_host = PathBuf::new();
+        ramdisk.is_read_only = true;
+        assert_eq!(
+            block_devices_configs.insert(ramdisk.clone()),
+            Err(DriveError::InvalidRamdisk)
+        );
+        ramdisk.is_read_only = false;
+
+        // Error case: the ramdisks don't fit in the allowed host memory.
+        block_devices_configs.set_max_ramdisk_size_mib(100);
+        ramdisk.ramdisk_size_mib = Some(0);
+        assert_eq!(
+            block_devices_configs.insert(ramdisk.clone()),
+            Err(DriveError::InvalidRamdiskSize(100))
+        );
+        ramdisk.ramdisk_size_mib = Some(101);
+        assert_eq!(
+            block_devices_configs.insert(ramdisk.clone()),
+            Err(DriveError::InvalidRamdiskSize(100))
+        );
+
+        ramdisk.ramdisk_size_mib = Some(64);
+        assert!(block_devices_configs.insert(ramdisk.clone()).is_ok());
+        assert_eq!(
+            block_devices_configs.config_list[0].ramdisk_size(),
+            Some(64 << 20)
+        );
+        // Resizing the ramdisk doesn't count its previous size.
+        ramdisk.ramdisk_size_mib = Some(100);
+        assert!(block_devices_configs.insert(ramdisk.clone()).is_ok());
+
+        ramdisk.drive_id = String::from("other");
+        ramdisk.ramdisk_size_mib = Some(1);
+        assert_eq!(
+            block_devices_configs.insert(ramdisk),
+            Err(DriveError::InvalidRamdiskSize(100))
+        );
+        assert_eq!(
+            DriveError::InvalidRamdiskSize(100).to_string(),
+            "The ramdisk size must be positive, and the ramdisks can take at most 100 MiB of \
+             host memory!"
+        );
+    }
+
     #[test]
     fn test_add_one_root_block_device() {
         let dummy_file = NamedTempFile::new().unwrap();
@@ -296,8 +788,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: true,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -321,8 +819,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -332,8 +836,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -356,8 +866,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -367,8 +883,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let dummy_file_3 = NamedTempFile::new().unwrap();
@@ -378,8 +900,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("3"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -413,8 +941,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -424,8 +958,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let dummy_file_3 = NamedTempFile::new().unwrap();
@@ -435,8 +975,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("3"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -471,8 +1017,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -482,8 +1034,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -549,16 +1107,28 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("1"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         let root_block_device_new = BlockDeviceConfig {
             path_on_host: dummy_path_2,
             is_root_device: true,
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
+            direct_io: false,
+            ramdisk_size_mib: None,
+            serial: None,
             drive_id: String::from("2"),
             rate_limiter: None,
+            io_retry: None,
+            virtio_feature_mask: None,
+            max_in_flight_requests: None,
         };
         let index1 = block_devices_configs
             .get_index_of_drive_id(&root_block_device_old.drive_id)
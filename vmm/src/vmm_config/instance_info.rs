@@ -6,14 +6,26 @@ use std::fmt::{Display, Formatter, Result};
 
 use device_manager;
 use devices;
+#[cfg(feature = "vsock")]
+use file_transfer;
+#[cfg(feature = "vsock")]
+use guest_agent;
 use kernel::loader as kernel_loader;
-use memory_model::GuestMemoryError;
+use memory_model::{GuestAddress, GuestMemoryError};
 use seccomp;
+#[cfg(target_arch = "x86_64")]
+use sev;
+#[cfg(target_arch = "x86_64")]
+use vmm_config::machine_config::ClockSource;
+#[cfg(target_arch = "aarch64")]
+use vmm_config::machine_config::GicVersion;
 use vstate;
 
 /// The microvm state. When Firecracker starts, the instance state is Uninitialized.
 /// Once start_microvm method is called, the state goes from Uninitialized to Starting.
 /// The state is changed to Running before ending the start_microvm method.
+/// The state is changed to Paused when the guest panics and the guest panic action is pause,
+/// and while a snapshot of a running microVM is written.
 /// Halting and Halted are currently unsupported.
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum InstanceState {
@@ -23,12 +35,46 @@ pub enum InstanceState {
     Starting,
     /// Microvm is running.
     Running,
+    /// Microvm is paused, i.e. its vCPUs don't run anymore.
+    Paused,
     /// Microvm received a halt instruction.
     Halting,
     /// Microvm is halted.
     Halted,
 }
 
+/// Why the microVM stopped running.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum StopReason {
+    /// The guest powered off, i.e. a vCPU halted or, on aarch64, the guest sent a PSCI
+    /// system event.
+    GuestShutdown,
+    /// The guest reset, either through the i8042 controller or with a triple fault, which KVM
+    /// reports as a shutdown exit, on x86_64.
+    GuestReset,
+    /// The guest panicked and the guest panic action is exit.
+    GuestPanic,
+    /// KVM failed to run a vCPU or exited for a reason the VMM can't handle.
+    InternalError,
+    /// The VMM stopped the microVM, e.g. because the guest watchdog expired or an API client
+    /// sent a reset.
+    VmmStop,
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::StopReason::*;
+
+        match *self {
+            GuestShutdown => write!(f, "guest shutdown"),
+            GuestReset => write!(f, "guest reset"),
+            GuestPanic => write!(f, "guest panic"),
+            InternalError => write!(f, "internal error"),
+            VmmStop => write!(f, "VMM-initiated stop"),
+        }
+    }
+}
+
 /// The strongly typed that contains general information about the microVM.
 #[derive(Debug, Serialize)]
 pub struct InstanceInfo {
@@ -38,12 +84,18 @@ pub struct InstanceInfo {
     pub state: InstanceState,
     /// The version of the VMM that runs the microVM.
     pub vmm_version: String,
+    /// Why the microVM stopped, once it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<StopReason>,
 }
 
 /// Errors associated with starting the instance.
 // TODO: add error kind to these variants because not all these errors are user or internal.
 #[derive(Debug)]
 pub enum StartMicrovmError {
+    #[cfg(target_arch = "x86_64")]
+    /// The host can't provide the clock source requested for the guest.
+    ClockSourceNotSupported(ClockSource),
     /// This error is thrown by the minimal boot loader implementation.
     /// It is related to a faulty memory configuration.
     ConfigureSystem(arch::Error),
@@ -58,6 +110,12 @@ pub enum StartMicrovmError {
     CreateNetDevice(devices::virtio::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(std::io::Error),
+    /// Failed to create a `RxCoalescing` object.
+    CreateRxCoalescing(std::io::Error),
+    #[cfg(feature = "vhost-user-block")]
+    /// Creating a vhost-user block device fails if the backend is unreachable or doesn't support
+    /// the required features.
+    CreateVhostUserBlockDevice(devices::virtio::vhost::Error),
     #[cfg(feature = "vsock")]
     /// Creating a vsock device can only fail if the /dev/vhost-vsock device cannot be open.
     CreateVsockDevice(devices::virtio::vhost::Error),
@@ -65,10 +123,21 @@ pub enum StartMicrovmError {
     DeviceManager,
     /// Cannot read from an Event file descriptor.
     EventFd,
+    #[cfg(feature = "vsock")]
+    /// Cannot set up the channel receiving the files sent by the guest over a vsock device.
+    FileTransfer(file_transfer::Error),
+    #[cfg(target_arch = "aarch64")]
+    /// The host kernel cannot emulate the requested version of the GIC.
+    GicVersionNotSupported(GicVersion),
+    #[cfg(feature = "vsock")]
+    /// Cannot set up the channel to the guest agent of a vsock device.
+    GuestAgent(guest_agent::Error),
     /// Memory regions are overlapping or mmap fails.
     GuestMemory(GuestMemoryError),
     /// The kernel command line is invalid.
     KernelCmdline(String),
+    /// The kernel entry point does not fall within any guest memory region.
+    KernelEntryNotMapped(GuestAddress),
     /// Cannot load kernel due to invalid memory configuration or invalid kernel image.
     KernelLoader(kernel_loader::Error),
     /// Cannot add devices to the Legacy I/O Bus.
@@ -79,31 +148,57 @@ pub enum StartMicrovmError {
     MicroVMAlreadyRunning,
     /// Cannot start the VM because the kernel was not configured.
     MissingKernelConfig,
+    /// Cannot start the VM because neither a root block device nor the `root=` boot argument
+    /// sets the root filesystem of the guest, which isn't configured as diskless.
+    MissingRootfs,
     /// The net device configuration is missing the tap device.
     NetDeviceNotConfigured,
+    /// Cannot find the host CPUs of the NUMA node.
+    NumaNodeCpus(std::io::Error),
     /// Cannot open the block device backing file.
     OpenBlockDevice(std::io::Error),
     /// Cannot initialize a MMIO Block Device or add a device to the MMIO Bus.
     RegisterBlockDevice(device_manager::mmio::Error),
+    /// Cannot add the virtio console device to the MMIO Bus.
+    RegisterConsoleDevice(device_manager::mmio::Error),
     /// Cannot add event to Epoll.
     RegisterEvent,
+    #[cfg(feature = "virtio-fs")]
+    /// Cannot create a virtio-fs Device or add it to the MMIO Bus.
+    RegisterFsDevice(device_manager::mmio::Error),
     /// Cannot add a device to the MMIO Bus.
     RegisterMMIODevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Network Device or add a device to the MMIO Bus.
     RegisterNetDevice(device_manager::mmio::Error),
+    #[cfg(feature = "vhost-user-block")]
+    /// Cannot initialize a MMIO vhost-user Block Device or add a device to the MMIO Bus.
+    RegisterVhostUserBlockDevice(device_manager::mmio::Error),
     #[cfg(feature = "vsock")]
     /// Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus.
     RegisterVsockDevice(device_manager::mmio::Error),
+    /// Cannot generate the random seed passed to the guest.
+    RngSeed(std::io::Error),
     /// Cannot build seccomp filters.
     SeccompFilters(seccomp::Error),
+    #[cfg(target_arch = "aarch64")]
+    /// The guest console is the serial device, which is disabled.
+    SerialConsoleDisabled,
+    #[cfg(target_arch = "x86_64")]
+    /// The guest memory cannot be encrypted with SEV.
+    Sev(sev::Error),
     /// Cannot create a new vCPU file descriptor.
     Vcpu(vstate::Error),
     /// vCPU configuration failed.
     VcpuConfigure(vstate::Error),
+    /// Cannot set the strict scheduling policy of the given vCPU.
+    VcpuScheduling(u8, std::io::Error),
     /// vCPUs were not configured.
     VcpusNotConfigured,
     /// Cannot spawn a new vCPU thread.
     VcpuSpawn(std::io::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// The host can't expose x2APIC to the guest.
+    X2ApicNotSupported,
 }
 
 impl Display for StartMicrovmError {
@@ -129,6 +224,14 @@ impl Display for StartMicrovmError {
                 err
             ),
             CreateRateLimiter(ref err) => write!(f, "Cannot create RateLimiter: {}", err),
+            CreateRxCoalescing(ref err) => write!(f, "Cannot create RxCoalescing: {}", err),
+            #[cfg(feature = "vhost-user-block")]
+            CreateVhostUserBlockDevice(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot create vhost-user block device. {}", err_msg)
+            }
             #[cfg(feature = "vsock")]
             CreateVsockDevice(ref err) => {
                 let mut err_msg = format!("{:?}", err);
@@ -142,8 +245,28 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot create network device. {}", err_msg)
             }
+            #[cfg(target_arch = "x86_64")]
+            ClockSourceNotSupported(clock_source) => match clock_source {
+                ClockSource::KvmClock => {
+                    write!(f, "The host doesn't expose the kvm-clock clock source.")
+                }
+                ClockSource::Tsc => write!(
+                    f,
+                    "The host TSC is not invariant, so it cannot be the guest clock source."
+                ),
+            },
             DeviceManager => write!(f, "The device manager was not configured."),
             EventFd => write!(f, "Cannot read from an Event file descriptor."),
+            #[cfg(feature = "vsock")]
+            FileTransfer(ref err) => write!(f, "{}", err),
+            #[cfg(target_arch = "aarch64")]
+            GicVersionNotSupported(version) => write!(
+                f,
+                "The host kernel doesn't support the {} interrupt controller.",
+                version
+            ),
+            #[cfg(feature = "vsock")]
+            GuestAgent(ref err) => write!(f, "{}", err),
             GuestMemory(ref err) => {
                 // Remove imbricated quotes from error message.
                 let mut err_msg = format!("{:?}", err);
@@ -151,6 +274,12 @@ impl Display for StartMicrovmError {
                 write!(f, "Invalid Memory Configuration: {}", err_msg)
             }
             KernelCmdline(ref err) => write!(f, "Invalid kernel command line: {}", err),
+            KernelEntryNotMapped(ref addr) => write!(
+                f,
+                "The kernel entry point {:#x} is not within the guest memory. Check the memory \
+                 size and the kernel image.",
+                addr.offset()
+            ),
             KernelLoader(ref err) => {
                 let mut err_msg = format!("{}", err);
                 err_msg = err_msg.replace("\"", "");
@@ -174,9 +303,15 @@ impl Display for StartMicrovmError {
             }
             MicroVMAlreadyRunning => write!(f, "Microvm already running."),
             MissingKernelConfig => write!(f, "Cannot start microvm without kernel configuration."),
+            MissingRootfs => write!(
+                f,
+                "Cannot start microvm without a root filesystem. Add a root block device, set \
+                 root= in the boot arguments, or configure the boot source as diskless."
+            ),
             NetDeviceNotConfigured => {
                 write!(f, "The net device configuration is missing the tap device.")
             }
+            NumaNodeCpus(ref err) => write!(f, "Cannot find the CPUs of the NUMA node. {}", err),
             OpenBlockDevice(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
@@ -192,7 +327,23 @@ impl Display for StartMicrovmError {
                     err_msg
                 )
             }
+            RegisterConsoleDevice(ref err) => write!(
+                f,
+                "Cannot add the virtio console device to the MMIO Bus. {}",
+                err
+            ),
             RegisterEvent => write!(f, "Cannot add event to Epoll."),
+            #[cfg(feature = "virtio-fs")]
+            RegisterFsDevice(ref err) => {
+                let mut err_msg = format!("{}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(
+                    f,
+                    "Cannot create a virtio-fs Device or add it to the MMIO Bus. {}",
+                    err_msg
+                )
+            }
             RegisterMMIODevice(ref err) => {
                 let mut err_msg = format!("{}", err);
                 err_msg = err_msg.replace("\"", "");
@@ -209,6 +360,18 @@ impl Display for StartMicrovmError {
                     err_msg
                 )
             }
+            #[cfg(feature = "vhost-user-block")]
+            RegisterVhostUserBlockDevice(ref err) => {
+                let mut err_msg = format!("{}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(
+                    f,
+                    "Cannot initialize a MMIO vhost-user Block Device or add a device to the MMIO \
+                     Bus. {}",
+                    err_msg
+                )
+            }
             #[cfg(feature = "vsock")]
             RegisterVsockDevice(ref err) => {
                 let mut err_msg = format!("{}", err);
@@ -220,12 +383,22 @@ impl Display for StartMicrovmError {
                     err_msg
                 )
             }
+            RngSeed(ref err) => {
+                write!(f, "Cannot generate the random seed for the guest. {}", err)
+            }
             SeccompFilters(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
 
                 write!(f, "Cannot build seccomp filters. {}", err_msg)
             }
+            #[cfg(target_arch = "aarch64")]
+            SerialConsoleDisabled => write!(
+                f,
+                "The guest console cannot be the serial device, which is disabled."
+            ),
+            #[cfg(target_arch = "x86_64")]
+            Sev(ref err) => write!(f, "Cannot launch the SEV guest. {}", err),
             Vcpu(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
@@ -238,6 +411,11 @@ impl Display for StartMicrovmError {
 
                 write!(f, "vCPU configuration failed. {}", err_msg)
             }
+            VcpuScheduling(id, ref err) => write!(
+                f,
+                "Cannot set the scheduling policy of vCPU {}. {}",
+                id, err
+            ),
             VcpusNotConfigured => write!(f, "vCPUs were not configured."),
             VcpuSpawn(ref err) => {
                 let mut err_msg = format!("{:?}", err);
@@ -245,6 +423,8 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot spawn vCPU thread. {}", err_msg)
             }
+            #[cfg(target_arch = "x86_64")]
+            X2ApicNotSupported => write!(f, "The host doesn't expose x2APIC to the guest."),
         }
     }
 }
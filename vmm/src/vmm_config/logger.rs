@@ -40,6 +40,9 @@ pub struct LoggerConfig {
     /// When enabled, the logger will append the origin of the log entry.
     #[serde(default)]
     pub show_log_origin: bool,
+    /// Size in bytes above which the log file is rotated. Rotation is disabled when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_log_size: Option<usize>,
     /// Additional logging options.
     #[cfg(target_arch = "x86_64")]
     #[serde(default = "default_log_options")]
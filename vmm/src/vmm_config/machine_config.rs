@@ -2,8 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use serde::{de, Deserialize};
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter, Result};
 
+#[cfg(target_arch = "aarch64")]
+use arch;
+
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
 pub const MAX_SUPPORTED_VCPUS: u8 = 32;
@@ -14,10 +18,30 @@ pub enum VmConfigError {
     /// The vcpu count is invalid. When hyperthreading is enabled, the `cpu_count` must be either
     /// 1 or an even number.
     InvalidVcpuCount,
+    /// The CPU topology is invalid. The number of sockets, cores per socket and threads per core
+    /// must multiply to the vcpu count and a core can have at most 2 threads.
+    InvalidCpuTopology,
     /// The memory size is invalid. The memory can only be an unsigned integer.
     InvalidMemorySize,
     /// Cannot update the configuration of the microvm post boot.
     UpdateNotAllowedPostBoot,
+    /// The NUMA node doesn't exist on the host or has no CPUs.
+    InvalidNumaNode,
+    /// The vCPU affinity refers to a vCPU index which is not lower than the vCPU count.
+    InvalidVcpuAffinityIndex(u8),
+    /// The vCPU affinity refers to a host CPU which doesn't exist or is not available.
+    InvalidHostCpu(usize),
+    /// The IRQ affinity can't be steered, the guest picks the vCPUs handling the interrupts.
+    IrqAffinityNotSupported,
+    /// The scheduling parameters don't match the scheduling policy of the vCPUs.
+    InvalidVcpuScheduling,
+    /// The high MMIO range is empty, not aligned or overlaps with the guest memory or the low
+    /// MMIO range.
+    InvalidHighMmio,
+    /// A reserved memory range is empty, not page aligned or overflows the address space.
+    InvalidReservedMemory,
+    /// The flattened device tree is only built when the microvm is started.
+    FdtNotAvailable,
 }
 
 impl Display for VmConfigError {
@@ -29,10 +53,55 @@ impl Display for VmConfigError {
                 "The vCPU number is invalid! The vCPU number can only \
                  be 1 or an even number when hyperthreading is enabled.",
             ),
+            InvalidCpuTopology => write!(
+                f,
+                "The CPU topology is invalid! The number of sockets, cores per socket and \
+                 threads per core must multiply to the vCPU number, a core can have at most \
+                 2 threads and a socket must hold a power of 2 threads when there are multiple \
+                 sockets.",
+            ),
             InvalidMemorySize => write!(f, "The memory size (MiB) is invalid.",),
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.")
             }
+            InvalidVcpuAffinityIndex(index) => write!(
+                f,
+                "The vCPU affinity is invalid! There is no vCPU with the index {}.",
+                index
+            ),
+            InvalidHostCpu(cpu) => write!(
+                f,
+                "The vCPU affinity is invalid! The host CPU {} doesn't exist or is not available.",
+                cpu
+            ),
+            IrqAffinityNotSupported => write!(
+                f,
+                "The IRQ affinity is not supported! The guest picks the vCPUs handling the \
+                 interrupts."
+            ),
+            InvalidVcpuScheduling => write!(
+                f,
+                "The vCPU scheduling is invalid! The Fifo policy needs a priority between 1 and \
+                 99. The Deadline policy needs runtime_ns (at least 1024), deadline_ns and \
+                 period_ns in increasing order and can't be combined with pinned vCPUs.",
+            ),
+            InvalidHighMmio => write!(
+                f,
+                "The high MMIO range is invalid! It must be a non-empty, page aligned range \
+                 which doesn't overlap with the guest memory or the low MMIO range.",
+            ),
+            InvalidReservedMemory => write!(
+                f,
+                "The reserved memory is invalid! Each range must be non-empty and page aligned.",
+            ),
+            InvalidNumaNode => write!(
+                f,
+                "The NUMA node is invalid. It must be a host node which has CPUs."
+            ),
+            FdtNotAvailable => write!(
+                f,
+                "The flattened device tree is only available after the microVM is started."
+            ),
         }
     }
 }
@@ -56,8 +125,78 @@ pub struct VmConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ht_enabled: Option<bool>,
     /// A CPU template that it is used to filter the CPU features exposed to the guest.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "validate_cpu_template"
+    )]
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// The topology of the vCPUs presented to the guest. When set, it takes precedence over
+    /// `ht_enabled`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_topology: Option<CpuTopology>,
+    /// Fault in all the guest memory when it is set up, so that the guest doesn't stall on
+    /// demand paging at runtime. This makes the boot slower.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefault_memory: Option<bool>,
+    /// Zero all the guest memory when it is set up, so that the guest can't read data left
+    /// behind by a previous user of the host memory, e.g. when it comes from a pool. This also
+    /// faults in the memory and makes the boot slower.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zero_memory: Option<bool>,
+    /// The host NUMA node to which the guest memory is bound and on whose CPUs the vCPUs run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numa_node: Option<u32>,
+    /// Maps vCPU indexes to the host CPUs on which their threads are pinned. Pinned vCPUs are
+    /// not restricted to the CPUs of `numa_node`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vcpu_affinity: Option<BTreeMap<u8, usize>>,
+    /// Maps device IDs to the index of the vCPU which handles their interrupts. Not supported:
+    /// Linux only reads `interrupt-affinity` from the device tree for the PMU, so only an empty
+    /// map is accepted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub irq_affinity: Option<BTreeMap<String, u8>>,
+    /// Real-time scheduling policy of the vCPU threads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vcpu_scheduling: Option<VcpuScheduling>,
+    /// A guest physical address range, distinct from the low MMIO range, reserved for the
+    /// MMIO windows of large devices.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub high_mmio: Option<HighMmioConfig>,
+    /// Guest physical address ranges which the memory map of the guest marks as reserved on
+    /// x86_64, e.g. for testing how the guest copes with holes in its RAM. They must not overlap
+    /// with the kernel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved_memory: Option<Vec<ReservedMemoryConfig>>,
+    /// The version of the interrupt controller presented to the guest on aarch64. If this field
+    /// is uninitialized, GICv3 is used when the host supports it, and GICv2 otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gic_version: Option<GicVersion>,
+    /// Whether the guest has a serial device on aarch64, where it takes an IRQ and an MMIO slot.
+    /// If this field is uninitialized, it does. On x86_64, the legacy serial ports are always
+    /// present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_enabled: Option<bool>,
+    /// The time, in seconds since the epoch, from which the RTC of aarch64 guests counts. If
+    /// this field is uninitialized, the RTC counts from the host time at boot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtc_base_time: Option<u64>,
+    /// The clock source the guest is pinned to on x86_64. If this field is uninitialized, the
+    /// guest picks one on its own.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_source: Option<ClockSource>,
+    /// Encrypt the guest memory with AMD SEV on x86_64. Starting the guest fails if the host
+    /// doesn't support SEV. Experimental.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sev_enabled: Option<bool>,
+    /// The mode of the local APIC of the guest on x86_64. If this field is uninitialized, the
+    /// guest sees x2APIC when KVM exposes it, and picks the mode on its own.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apic_mode: Option<ApicMode>,
+    /// What Firecracker does when the guest panics. If this field is uninitialized, Firecracker
+    /// exits with a dedicated exit code.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guest_panic_action: Option<GuestPanicAction>,
 }
 
 impl Default for VmConfig {
@@ -67,7 +206,317 @@ impl Default for VmConfig {
             mem_size_mib: Some(128),
             ht_enabled: Some(false),
             cpu_template: None,
+            cpu_topology: None,
+            prefault_memory: None,
+            zero_memory: None,
+            numa_node: None,
+            vcpu_affinity: None,
+            irq_affinity: None,
+            vcpu_scheduling: None,
+            high_mmio: None,
+            reserved_memory: None,
+            gic_version: None,
+            serial_enabled: None,
+            rtc_base_time: None,
+            clock_source: None,
+            sev_enabled: None,
+            apic_mode: None,
+            guest_panic_action: None,
+        }
+    }
+}
+
+/// Describes how the vCPUs are grouped in sockets, cores and threads.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuTopology {
+    /// Number of sockets.
+    pub sockets: u8,
+    /// Number of cores in each socket.
+    pub cores_per_socket: u8,
+    /// Number of threads in each core.
+    pub threads_per_core: u8,
+}
+
+impl CpuTopology {
+    /// Returns the total number of vCPUs described by this topology, or `None` if it
+    /// overflows.
+    pub fn vcpu_count(&self) -> Option<u8> {
+        self.sockets
+            .checked_mul(self.cores_per_socket)?
+            .checked_mul(self.threads_per_core)
+    }
+
+    /// Returns the number of vCPUs in each socket.
+    pub fn vcpus_per_socket(&self) -> u8 {
+        self.cores_per_socket * self.threads_per_core
+    }
+
+    /// Checks that the topology describes exactly `vcpu_count` vCPUs and that it can be
+    /// presented to the guest.
+    pub fn validate(&self, vcpu_count: u8) -> std::result::Result<(), VmConfigError> {
+        if self.sockets == 0
+            || self.cores_per_socket == 0
+            || self.threads_per_core == 0
+            || self.threads_per_core > 2
+            || self.vcpu_count() != Some(vcpu_count)
+        {
+            return Err(VmConfigError::InvalidCpuTopology);
         }
+        // The vCPU index is used as APIC ID, so the vCPUs of a socket must fill a whole
+        // power of 2 range of IDs for the guest to place them in the right socket.
+        if self.sockets > 1 && !self.vcpus_per_socket().is_power_of_two() {
+            return Err(VmConfigError::InvalidCpuTopology);
+        }
+        // The lowest affinity level of the MPIDR can address at most 16 vCPUs.
+        #[cfg(target_arch = "aarch64")]
+        {
+            if self.threads_per_core == 1 && self.cores_per_socket > 16 {
+                return Err(VmConfigError::InvalidCpuTopology);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Describes the guest physical address range reserved for the MMIO windows of large devices.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HighMmioConfig {
+    /// Name of the range.
+    pub name: String,
+    /// Guest physical address where the range starts.
+    pub base: u64,
+    /// Size of the range in MiB.
+    pub size_mib: u64,
+}
+
+impl HighMmioConfig {
+    /// Returns the size of the range in bytes, or `None` if it overflows.
+    pub fn size(&self) -> Option<u64> {
+        self.size_mib.checked_mul(1 << 20)
+    }
+
+    /// Returns the address right after the end of the range, or `None` if it overflows.
+    pub fn end(&self) -> Option<u64> {
+        self.size()?.checked_add(self.base)
+    }
+}
+
+/// Describes a guest physical address range which the guest must not use as RAM.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReservedMemoryConfig {
+    /// Guest physical address where the range starts.
+    pub base: u64,
+    /// Size of the range in KiB.
+    pub size_kib: u64,
+}
+
+impl ReservedMemoryConfig {
+    /// Returns the size of the range in bytes, or `None` if it overflows.
+    pub fn size(&self) -> Option<u64> {
+        self.size_kib.checked_mul(1 << 10)
+    }
+
+    /// Checks that the range is not empty, is page aligned and fits in the address space.
+    pub fn validate(&self) -> std::result::Result<(), VmConfigError> {
+        match self.size() {
+            Some(size)
+                if size != 0
+                    && self.base % 0x1000 == 0
+                    && size % 0x1000 == 0
+                    && self.base.checked_add(size).is_some() =>
+            {
+                Ok(())
+            }
+            _ => Err(VmConfigError::InvalidReservedMemory),
+        }
+    }
+}
+
+/// Versions of the aarch64 Generic Interrupt Controller.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum GicVersion {
+    /// GICv2, which supports at most 8 vCPUs.
+    V2,
+    /// GICv3.
+    V3,
+}
+
+impl Display for GicVersion {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            GicVersion::V2 => write!(f, "GICv2"),
+            GicVersion::V3 => write!(f, "GICv3"),
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl From<GicVersion> for arch::aarch64::gic::GicVersion {
+    fn from(version: GicVersion) -> Self {
+        match version {
+            GicVersion::V2 => arch::aarch64::gic::GicVersion::V2,
+            GicVersion::V3 => arch::aarch64::gic::GicVersion::V3,
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl From<arch::aarch64::gic::GicVersion> for GicVersion {
+    fn from(version: arch::aarch64::gic::GicVersion) -> Self {
+        match version {
+            arch::aarch64::gic::GicVersion::V2 => GicVersion::V2,
+            arch::aarch64::gic::GicVersion::V3 => GicVersion::V3,
+        }
+    }
+}
+
+/// Clock sources the x86_64 guest can be pinned to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ClockSource {
+    /// The paravirtualized clock of KVM.
+    #[serde(rename = "kvm-clock")]
+    KvmClock,
+    /// The TSC, which the host must keep invariant.
+    #[serde(rename = "tsc")]
+    Tsc,
+}
+
+impl ClockSource {
+    /// Returns the kernel command line parameters selecting this clock source in the guest.
+    pub fn cmdline_params(self) -> &'static str {
+        match self {
+            ClockSource::KvmClock => "clocksource=kvm-clock",
+            ClockSource::Tsc => "clocksource=tsc tsc=reliable",
+        }
+    }
+}
+
+impl Display for ClockSource {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            ClockSource::KvmClock => write!(f, "kvm-clock"),
+            ClockSource::Tsc => write!(f, "tsc"),
+        }
+    }
+}
+
+/// Modes the local APIC of the x86_64 guest can be set up in.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ApicMode {
+    /// The MMIO based xAPIC. x2APIC is hidden from the guest.
+    #[serde(rename = "xapic")]
+    XApic,
+    /// The MSR based x2APIC, which the vCPUs are switched to before the guest boots.
+    #[serde(rename = "x2apic")]
+    X2Apic,
+}
+
+impl Display for ApicMode {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            ApicMode::XApic => write!(f, "xAPIC"),
+            ApicMode::X2Apic => write!(f, "x2APIC"),
+        }
+    }
+}
+
+/// Actions Firecracker can take when the guest signals a panic through the pvpanic device. On
+/// x86_64 the device isn't advertised to the guest, whose kernel has to write to the I/O port
+/// 0x505 on its own.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum GuestPanicAction {
+    /// Firecracker exits with a dedicated exit code.
+    #[serde(rename = "exit")]
+    Exit,
+    /// The vCPUs are paused, so that the guest can be inspected post-mortem.
+    #[serde(rename = "pause")]
+    Pause,
+    /// The panic is logged and the guest keeps running.
+    #[serde(rename = "continue")]
+    Continue,
+}
+
+impl Default for GuestPanicAction {
+    fn default() -> Self {
+        GuestPanicAction::Exit
+    }
+}
+
+impl Display for GuestPanicAction {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            GuestPanicAction::Exit => write!(f, "exit"),
+            GuestPanicAction::Pause => write!(f, "pause"),
+            GuestPanicAction::Continue => write!(f, "continue"),
+        }
+    }
+}
+
+/// Real-time scheduling policies which can be set on the vCPU threads.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum VcpuSchedPolicy {
+    /// `SCHED_FIFO`, with a static priority.
+    Fifo,
+    /// `SCHED_DEADLINE`, with a runtime guaranteed in every period.
+    Deadline,
+}
+
+/// Describes the real-time scheduling policy of the vCPU threads.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VcpuScheduling {
+    /// The scheduling policy.
+    pub policy: VcpuSchedPolicy,
+    /// Priority of the vCPU threads, between 1 and 99. Only used by the `Fifo` policy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+    /// CPU time in nanoseconds which the vCPU threads get in every period. Only used by the
+    /// `Deadline` policy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime_ns: Option<u64>,
+    /// Time in nanoseconds from the start of the period by which the runtime has to be
+    /// received. Only used by the `Deadline` policy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline_ns: Option<u64>,
+    /// Length of the period in nanoseconds. Only used by the `Deadline` policy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period_ns: Option<u64>,
+    /// When set, Firecracker stops if the policy can't be set instead of logging a warning
+    /// and leaving the vCPU threads with the default policy.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+impl VcpuScheduling {
+    /// Checks that the parameters needed by the policy are present and valid.
+    pub fn validate(&self) -> std::result::Result<(), VmConfigError> {
+        let valid = match self.policy {
+            VcpuSchedPolicy::Fifo => {
+                self.runtime_ns.is_none()
+                    && self.deadline_ns.is_none()
+                    && self.period_ns.is_none()
+                    && self.priority.map_or(false, |p| p >= 1 && p <= 99)
+            }
+            // The kernel doesn't accept runtimes below 1024ns.
+            VcpuSchedPolicy::Deadline => {
+                match (self.runtime_ns, self.deadline_ns, self.period_ns) {
+                    (Some(runtime), Some(deadline), Some(period)) => {
+                        self.priority.is_none()
+                            && runtime >= 1024
+                            && runtime <= deadline
+                            && deadline <= period
+                    }
+                    _ => false,
+                }
+            }
+        };
+        if !valid {
+            return Err(VmConfigError::InvalidVcpuScheduling);
+        }
+        Ok(())
     }
 }
 
@@ -87,6 +536,24 @@ where
     Ok(val)
 }
 
+fn validate_cpu_template<'de, D>(d: D) -> std::result::Result<Option<CpuFeaturesTemplate>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let val = Option::<String>::deserialize(d)?;
+    match val {
+        Some(ref name) => match name.as_str() {
+            "C3" => Ok(Some(CpuFeaturesTemplate::C3)),
+            "T2" => Ok(Some(CpuFeaturesTemplate::T2)),
+            _ => Err(de::Error::invalid_value(
+                de::Unexpected::Str(name),
+                &"a supported CPU template (C3 or T2)",
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
 /// Template types available for configuring the CPU features that map
 /// to EC2 instances.
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
@@ -109,6 +576,47 @@ impl Display for CpuFeaturesTemplate {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_deserialize_cpu_template() {
+        let vm_config: VmConfig = serde_json::from_str(r#"{"cpu_template": "T2"}"#).unwrap();
+        assert_eq!(vm_config.cpu_template, Some(CpuFeaturesTemplate::T2));
+        let vm_config: VmConfig = serde_json::from_str(r#"{"cpu_template": "C3"}"#).unwrap();
+        assert_eq!(vm_config.cpu_template, Some(CpuFeaturesTemplate::C3));
+        let vm_config: VmConfig = serde_json::from_str(r#"{"vcpu_count": 2}"#).unwrap();
+        assert!(vm_config.cpu_template.is_none());
+
+        // Unknown templates are rejected with a descriptive error.
+        let err = serde_json::from_str::<VmConfig>(r#"{"cpu_template": "T3"}"#).unwrap_err();
+        assert!(err.to_string().contains(
+            "invalid value: string \"T3\", expected a supported CPU template (C3 or T2)"
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_vcpu_affinity() {
+        let vm_config: VmConfig =
+            serde_json::from_str(r#"{"vcpu_affinity": {"0": 4, "1": 6}}"#).unwrap();
+        let affinity = vm_config.vcpu_affinity.unwrap();
+        assert_eq!(affinity.get(&0), Some(&4));
+        assert_eq!(affinity.get(&1), Some(&6));
+        assert_eq!(affinity.len(), 2);
+
+        assert!(serde_json::from_str::<VmConfig>(r#"{"vcpu_affinity": {"a": 4}}"#).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_irq_affinity() {
+        let vm_config: VmConfig =
+            serde_json::from_str(r#"{"irq_affinity": {"net0": 1, "rootfs": 0}}"#).unwrap();
+        let affinity = vm_config.irq_affinity.unwrap();
+        assert_eq!(affinity.get("net0"), Some(&1));
+        assert_eq!(affinity.get("rootfs"), Some(&0));
+        assert_eq!(affinity.len(), 2);
+
+        assert!(serde_json::from_str::<VmConfig>(r#"{"irq_affinity": {"net0": -1}}"#).is_err());
+    }
 
     #[test]
     fn test_display_cpu_features_template() {
@@ -116,6 +624,172 @@ mod tests {
         assert_eq!(CpuFeaturesTemplate::T2.to_string(), "T2".to_string());
     }
 
+    #[test]
+    fn test_gic_version() {
+        let vm_config: VmConfig = serde_json::from_str(r#"{"gic_version": "V3"}"#).unwrap();
+        assert_eq!(vm_config.gic_version, Some(GicVersion::V3));
+        let vm_config: VmConfig = serde_json::from_str(r#"{"gic_version": "V2"}"#).unwrap();
+        assert_eq!(vm_config.gic_version, Some(GicVersion::V2));
+        assert!(serde_json::from_str::<VmConfig>(r#"{"gic_version": "V4"}"#).is_err());
+
+        assert_eq!(GicVersion::V2.to_string(), "GICv2");
+        assert_eq!(GicVersion::V3.to_string(), "GICv3");
+    }
+
+    #[test]
+    fn test_clock_source() {
+        let vm_config: VmConfig = serde_json::from_str(r#"{"clock_source": "kvm-clock"}"#).unwrap();
+        assert_eq!(vm_config.clock_source, Some(ClockSource::KvmClock));
+        let vm_config: VmConfig = serde_json::from_str(r#"{"clock_source": "tsc"}"#).unwrap();
+        assert_eq!(vm_config.clock_source, Some(ClockSource::Tsc));
+        assert!(serde_json::from_str::<VmConfig>(r#"{"clock_source": "hpet"}"#).is_err());
+
+        assert_eq!(ClockSource::KvmClock.to_string(), "kvm-clock");
+        assert_eq!(ClockSource::Tsc.to_string(), "tsc");
+        assert_eq!(
+            ClockSource::KvmClock.cmdline_params(),
+            "clocksource=kvm-clock"
+        );
+        assert_eq!(
+            ClockSource::Tsc.cmdline_params(),
+            "clocksource=tsc tsc=reliable"
+        );
+    }
+
+    #[test]
+    fn test_apic_mode() {
+        let vm_config: VmConfig = serde_json::from_str(r#"{"apic_mode": "xapic"}"#).unwrap();
+        assert_eq!(vm_config.apic_mode, Some(ApicMode::XApic));
+        let vm_config: VmConfig = serde_json::from_str(r#"{"apic_mode": "x2apic"}"#).unwrap();
+        assert_eq!(vm_config.apic_mode, Some(ApicMode::X2Apic));
+        assert!(serde_json::from_str::<VmConfig>(r#"{"apic_mode": "X2APIC"}"#).is_err());
+
+        assert_eq!(ApicMode::XApic.to_string(), "xAPIC");
+        assert_eq!(ApicMode::X2Apic.to_string(), "x2APIC");
+    }
+
+    #[test]
+    fn test_guest_panic_action() {
+        let vm_config: VmConfig =
+            serde_json::from_str(r#"{"guest_panic_action": "pause"}"#).unwrap();
+        assert_eq!(vm_config.guest_panic_action, Some(GuestPanicAction::Pause));
+        let vm_config: VmConfig =
+            serde_json::from_str(r#"{"guest_panic_action": "continue"}"#).unwrap();
+        assert_eq!(
+            vm_config.guest_panic_action,
+            Some(GuestPanicAction::Continue)
+        );
+        assert!(serde_json::from_str::<VmConfig>(r#"{"guest_panic_action": "stop"}"#).is_err());
+
+        assert_eq!(GuestPanicAction::default(), GuestPanicAction::Exit);
+        assert_eq!(GuestPanicAction::Exit.to_string(), "exit");
+        assert_eq!(GuestPanicAction::Pause.to_string(), "pause");
+        assert_eq!(GuestPanicAction::Continue.to_string(), "continue");
+    }
+
+    #[test]
+    fn test_cpu_topology() {
+        let topology = CpuTopology {
+            sockets: 1,
+            cores_per_socket: 2,
+            threads_per_core: 2,
+        };
+        assert_eq!(topology.vcpu_count(), Some(4));
+        assert_eq!(topology.vcpus_per_socket(), 4);
+        assert!(topology.validate(4).is_ok());
+        assert_eq!(topology.validate(2), Err(VmConfigError::InvalidCpuTopology));
+
+        // Cores can have at most 2 threads.
+        let topology = CpuTopology {
+            sockets: 1,
+            cores_per_socket: 1,
+            threads_per_core: 4,
+        };
+        assert_eq!(topology.validate(4), Err(VmConfigError::InvalidCpuTopology));
+
+        // Multiple sockets must hold a power of 2 vCPUs each.
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 3,
+            threads_per_core: 1,
+        };
+        assert_eq!(topology.validate(6), Err(VmConfigError::InvalidCpuTopology));
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 2,
+            threads_per_core: 2,
+        };
+        assert!(topology.validate(8).is_ok());
+
+        // Overflowing topologies are rejected.
+        let topology = CpuTopology {
+            sockets: 255,
+            cores_per_socket: 255,
+            threads_per_core: 1,
+        };
+        assert_eq!(topology.vcpu_count(), None);
+        assert_eq!(topology.validate(1), Err(VmConfigError::InvalidCpuTopology));
+        let topology = CpuTopology {
+            sockets: 0,
+            cores_per_socket: 2,
+            threads_per_core: 1,
+        };
+        assert_eq!(topology.validate(0), Err(VmConfigError::InvalidCpuTopology));
+    }
+
+    #[test]
+    fn test_vcpu_scheduling() {
+        let scheduling: VcpuScheduling =
+            serde_json::from_str(r#"{"policy": "Fifo", "priority": 10}"#).unwrap();
+        assert_eq!(scheduling.policy, VcpuSchedPolicy::Fifo);
+        assert!(!scheduling.strict);
+        assert!(scheduling.validate().is_ok());
+
+        // The priority must be between 1 and 99.
+        let mut fifo = scheduling;
+        fifo.priority = None;
+        assert_eq!(fifo.validate(), Err(VmConfigError::InvalidVcpuScheduling));
+        fifo.priority = Some(100);
+        assert_eq!(fifo.validate(), Err(VmConfigError::InvalidVcpuScheduling));
+        fifo.priority = Some(99);
+        fifo.period_ns = Some(1_000_000);
+        assert_eq!(fifo.validate(), Err(VmConfigError::InvalidVcpuScheduling));
+
+        let scheduling: VcpuScheduling = serde_json::from_str(
+            r#"{"policy": "Deadline", "runtime_ns": 500000, "deadline_ns": 1000000,
+                "period_ns": 1000000, "strict": true}"#,
+        )
+        .unwrap();
+        assert!(scheduling.strict);
+        assert!(scheduling.validate().is_ok());
+
+        // The runtime, deadline and period must be increasing.
+        let mut deadline = scheduling;
+        deadline.runtime_ns = Some(2_000_000);
+        assert_eq!(
+            deadline.validate(),
+            Err(VmConfigError::InvalidVcpuScheduling)
+        );
+        deadline.runtime_ns = Some(1000);
+        assert_eq!(
+            deadline.validate(),
+            Err(VmConfigError::InvalidVcpuScheduling)
+        );
+        deadline.runtime_ns = None;
+        assert_eq!(
+            deadline.validate(),
+            Err(VmConfigError::InvalidVcpuScheduling)
+        );
+        deadline.runtime_ns = Some(500_000);
+        deadline.priority = Some(1);
+        assert_eq!(
+            deadline.validate(),
+            Err(VmConfigError::InvalidVcpuScheduling)
+        );
+
+        assert!(serde_json::from_str::<VcpuScheduling>(r#"{"policy": "Other"}"#).is_err());
+    }
+
     #[test]
     fn test_display_vm_config_error() {
         let expected_str = "The vCPU number is invalid! The vCPU number can only \
@@ -130,5 +804,88 @@ mod tests {
             VmConfigError::UpdateNotAllowedPostBoot.to_string(),
             expected_str
         );
+
+        let expected_str = "The vCPU affinity is invalid! There is no vCPU with the index 2.";
+        assert_eq!(
+            VmConfigError::InvalidVcpuAffinityIndex(2).to_string(),
+            expected_str
+        );
+
+        let expected_str =
+            "The IRQ affinity is not supported! The guest picks the vCPUs handling the interrupts.";
+        assert_eq!(
+            VmConfigError::IrqAffinityNotSupported.to_string(),
+            expected_str
+        );
+
+        let expected_str =
+            "The vCPU affinity is invalid! The host CPU 7 doesn't exist or is not available.";
+        assert_eq!(VmConfigError::InvalidHostCpu(7).to_string(), expected_str);
+
+        let expected_str =
+            "The flattened device tree is only available after the microVM is started.";
+        assert_eq!(VmConfigError::FdtNotAvailable.to_string(), expected_str);
+
+        let expected_str =
+            "The reserved memory is invalid! Each range must be non-empty and page aligned.";
+        assert_eq!(
+            VmConfigError::InvalidReservedMemory.to_string(),
+            expected_str
+        );
+    }
+
+    #[test]
+    fn test_high_mmio_config() {
+        let json = r#"{
+            "vcpu_count": 2,
+            "mem_size_mib": 256,
+            "high_mmio": { "name": "pci64", "base": 68719476736, "size_mib": 1024 }
+        }"#;
+        let config: VmConfig = serde_json::from_str(json).unwrap();
+        let high_mmio = config.high_mmio.unwrap();
+        assert_eq!(high_mmio.name, "pci64");
+        assert_eq!(high_mmio.size(), Some(1 << 30));
+        assert_eq!(high_mmio.end(), Some((1 << 36) + (1 << 30)));
+
+        // Error case: the range overflows the address space.
+        let high_mmio = HighMmioConfig {
+            name: String::from("pci64"),
+            base: u64::max_value() - (1 << 20),
+            size_mib: 2,
+        };
+        assert_eq!(high_mmio.end(), None);
+        let high_mmio = HighMmioConfig {
+            size_mib: u64::max_value(),
+            ..high_mmio
+        };
+        assert_eq!(high_mmio.size(), None);
+    }
+
+    #[test]
+    fn test_reserved_memory_config() {
+        let json = r#"{
+            "vcpu_count": 1,
+            "mem_size_mib": 128,
+            "reserved_memory": [{ "base": 67108864, "size_kib": 1024 }]
+        }"#;
+        let config: VmConfig = serde_json::from_str(json).unwrap();
+        let reserved_memory = config.reserved_memory.unwrap();
+        assert_eq!(reserved_memory.len(), 1);
+        assert_eq!(reserved_memory[0].size(), Some(1 << 20));
+        assert!(reserved_memory[0].validate().is_ok());
+
+        // Error cases: the range is empty, not page aligned or overflows the address space.
+        for &(base, size_kib) in &[
+            (64 << 20, 0),
+            ((64 << 20) + 0x800, 4),
+            (64 << 20, 6),
+            (u64::max_value() - 0xfff, 8),
+            (0, u64::max_value()),
+        ] {
+            assert_eq!(
+                ReservedMemoryConfig { base, size_kib }.validate(),
+                Err(VmConfigError::InvalidReservedMemory)
+            );
+        }
     }
 }
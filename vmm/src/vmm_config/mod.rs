@@ -6,6 +6,8 @@ use std::io;
 
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
+/// Wrapper for the health status of the devices attached to the microVM.
+pub mod device;
 /// Wrapper for configuring the block devices.
 pub mod drive;
 /// Wrapper over the microVM general information attached to the microVM.
@@ -16,6 +18,14 @@ pub mod logger;
 pub mod machine_config;
 /// Wrapper for configuring the network devices attached to the microVM.
 pub mod net;
+/// Wrapper for configuring the snapshots of the microVM.
+pub mod snapshot;
+#[cfg(feature = "vhost-user-block")]
+/// Wrapper for configuring the vhost-user block devices attached to the microVM.
+pub mod vhost_user_block;
+#[cfg(feature = "virtio-fs")]
+/// Wrapper for configuring the virtio-fs devices attached to the microVM.
+pub mod virtio_fs;
 #[cfg(feature = "vsock")]
 /// Wrapper for configuring the vsock devices attached to the microVM.
 pub mod vsock;
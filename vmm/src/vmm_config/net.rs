@@ -2,13 +2,32 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fmt::{Display, Formatter, Result};
+use std::io;
 use std::result;
 
 use super::super::Error as VmmInternalError;
 use super::RateLimiterConfig;
+use device_manager;
 use devices;
 use net_util::{MacAddr, Tap, TapError};
 
+/// Settings for coalescing the interrupts that signal received frames to the guest.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RxCoalescingConfig {
+    /// Number of received frames that triggers an interrupt.
+    pub max_frames: u32,
+    /// Maximum time, in microseconds, that a received frame waits before being signaled.
+    pub timeout_us: u64,
+}
+
+impl RxCoalescingConfig {
+    /// Convert the stateless `self` into a live `RxCoalescing` object.
+    pub fn into_rx_coalescing(self) -> result::Result<devices::virtio::RxCoalescing, io::Error> {
+        devices::virtio::RxCoalescing::new(self.max_frames, self.timeout_us)
+    }
+}
+
 /// This struct represents the strongly typed equivalent of the json body from net iface
 /// related requests.
 #[derive(Debug, Deserialize, PartialEq)]
@@ -24,6 +43,18 @@ pub struct NetworkInterfaceConfig {
     pub rx_rate_limiter: Option<RateLimiterConfig>,
     /// Rate Limiter for transmitted packages.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// Interrupt coalescing for received packages.
+    pub rx_coalescing: Option<RxCoalescingConfig>,
+    /// Number of received frames buffered on the host side while the guest has no buffer
+    /// available for them. No frames are buffered if this is None.
+    pub rx_buffer_frames: Option<u32>,
+    /// The virtio features the device may offer to the guest, ANDed with the features it
+    /// supports. It must keep VIRTIO_F_VERSION_1 (bit 32).
+    pub virtio_feature_mask: Option<u64>,
+    /// Whether the guest and the tap exchange TCP segments larger than the MTU, the guest
+    /// receiving them in mergeable buffers. The segmentation is otherwise left to the host.
+    #[serde(default)]
+    pub gso_passthrough: bool,
     #[serde(default = "default_allow_mmds_requests")]
     /// If this field is set, the device model will reply to HTTP GET
     /// requests sent to the MMDS address via this interface. In this case,
@@ -31,6 +62,9 @@ pub struct NetworkInterfaceConfig {
     /// same address are intercepted by the device model, and do not reach
     /// the associated TAP device.
     pub allow_mmds_requests: bool,
+    /// Upper bound on the MSS of the TCP connections to the MMDS, advertised to the guest
+    /// instead of a larger MSS it asks for. The MSS is not clamped if this is None.
+    pub mmds_mss_clamp: Option<u16>,
     /// Handle for a network tap interface created using `host_dev_name`.
     #[serde(skip)]
     pub tap: Option<Tap>,
@@ -86,8 +120,18 @@ pub enum NetworkInterfaceError {
     EpollHandlerNotFound(VmmInternalError),
     /// The host device name is already in use.
     HostDeviceNameInUse(String),
-    /// Couldn't find the interface to update (patch).
+    /// Cannot detach the device from the running microVM.
+    DetachFailed(device_manager::mmio::Error),
+    /// Couldn't find the interface to update (patch) or detach.
     DeviceIdNotFound,
+    /// The RX interrupt coalescing settings must be non-zero.
+    InvalidRxCoalescing,
+    /// The RX buffer must hold between 1 and `MAX_RX_BUFFER_FRAMES` frames.
+    InvalidRxBufferFrames,
+    /// The virtio feature mask clears a mandatory feature.
+    InvalidVirtioFeatureMask,
+    /// The MMDS MSS clamp must be between 1 and `MAX_MMDS_MSS_CLAMP`.
+    InvalidMmdsMssClamp,
     /// Cannot open/create tap device.
     OpenTap(TapError),
     /// Error updating (patching) the rate limiters.
@@ -113,7 +157,28 @@ impl Display for NetworkInterfaceError {
                 "{}",
                 format!("The host device name {} is already in use.", host_dev_name)
             ),
+            DetachFailed(ref e) => write!(f, "Cannot detach the network interface: {}", e),
             DeviceIdNotFound => write!(f, "Invalid interface ID - not found."),
+            InvalidRxCoalescing => write!(
+                f,
+                "The RX coalescing max_frames and timeout_us must be greater than 0."
+            ),
+            InvalidRxBufferFrames => write!(
+                f,
+                "The RX buffer must hold between 1 and {} frames.",
+                devices::virtio::MAX_RX_BUFFER_FRAMES
+            ),
+            InvalidVirtioFeatureMask => write!(
+                f,
+                "The virtio feature mask must keep the VIRTIO_F_VERSION_1 feature (bit 32)."
+            ),
+            InvalidMmdsMssClamp => write!(
+                f,
+                "The MMDS MSS clamp must be between 1 and {} bytes to fit in the {} bytes MTU \
+                 of the link.",
+                devices::virtio::MAX_MMDS_MSS_CLAMP,
+                devices::virtio::MMDS_LINK_MTU
+            ),
             OpenTap(ref e) => {
                 // We are propagating the Tap Error. This error can contain
                 // imbricated quotes which would result in an invalid json.
@@ -149,6 +214,11 @@ impl NetworkInterfaceConfigs {
         }
     }
 
+    /// Returns an iterator over the network interfaces.
+    pub fn iter(&self) -> ::std::slice::Iter<NetworkInterfaceConfig> {
+        self.if_list.iter()
+    }
+
     /// Returns a mutable iterator over the network interfaces.
     pub fn iter_mut(&mut self) -> ::std::slice::IterMut<NetworkInterfaceConfig> {
         self.if_list.iter_mut()
@@ -161,6 +231,27 @@ impl NetworkInterfaceConfigs {
         &mut self,
         netif_config: NetworkInterfaceConfig,
     ) -> result::Result<(), NetworkInterfaceError> {
+        if let Some(coalescing) = netif_config.rx_coalescing {
+            if coalescing.max_frames == 0 || coalescing.timeout_us == 0 {
+                return Err(NetworkInterfaceError::InvalidRxCoalescing);
+            }
+        }
+        if let Some(frames) = netif_config.rx_buffer_frames {
+            if frames == 0 || frames as usize > devices::virtio::MAX_RX_BUFFER_FRAMES {
+                return Err(NetworkInterfaceError::InvalidRxBufferFrames);
+            }
+        }
+        if let Some(mask) = netif_config.virtio_feature_mask {
+            if !devices::virtio::is_valid_feature_mask(mask) {
+                return Err(NetworkInterfaceError::InvalidVirtioFeatureMask);
+            }
+        }
+        if let Some(clamp) = netif_config.mmds_mss_clamp {
+            if clamp == 0 || clamp > devices::virtio::MAX_MMDS_MSS_CLAMP {
+                return Err(NetworkInterfaceError::InvalidMmdsMssClamp);
+            }
+        }
+
         match self
             .if_list
             .iter()
@@ -171,6 +262,15 @@ impl NetworkInterfaceConfigs {
         }
     }
 
+    /// Removes the network interface with the given id from the list, closing its tap device
+    /// if it wasn't handed over to the virtio device yet.
+    pub fn remove(&mut self, iface_id: &str) -> Option<NetworkInterfaceConfig> {
+        self.if_list
+            .iter()
+            .position(|netif| netif.iface_id == iface_id)
+            .map(|index| self.if_list.remove(index))
+    }
+
     fn get_index_of_mac(&self, mac: MacAddr) -> Option<usize> {
         self.if_list
             .iter()
@@ -292,7 +392,12 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
+            rx_coalescing: None,
+            rx_buffer_frames: None,
+            virtio_feature_mask: None,
+            gso_passthrough: false,
             allow_mmds_requests: false,
+            mmds_mss_clamp: None,
             tap: None,
         }
     }
@@ -307,7 +412,12 @@ mod tests {
                 guest_mac: self.guest_mac,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                rx_coalescing: self.rx_coalescing,
+                rx_buffer_frames: self.rx_buffer_frames,
+                virtio_feature_mask: self.virtio_feature_mask,
+                gso_passthrough: self.gso_passthrough,
                 allow_mmds_requests: self.allow_mmds_requests,
+                mmds_mss_clamp: self.mmds_mss_clamp,
                 tap: None,
             }
         }
@@ -340,6 +450,24 @@ mod tests {
         assert_eq!(netif_configs.if_list.len(), 1);
     }
 
+    #[test]
+    fn test_remove() {
+        let mut netif_configs = NetworkInterfaceConfigs::new();
+        let netif_1 = create_netif("id_1", "dev6", "01:23:45:67:89:0a");
+        assert!(netif_configs.insert(netif_1).is_ok());
+        let netif_2 = create_netif("id_2", "dev7", "01:23:45:67:89:0b");
+        assert!(netif_configs.insert(netif_2).is_ok());
+
+        assert_eq!(netif_configs.remove("id_1").unwrap().host_dev_name, "dev6");
+        assert!(netif_configs.remove("id_1").is_none());
+        assert_eq!(netif_configs.if_list.len(), 1);
+        assert_eq!(netif_configs.if_list[0].iface_id, "id_2");
+
+        // The host device name and the MAC address can be used again.
+        let netif_1 = create_netif("id_3", "dev6", "01:23:45:67:89:0a");
+        assert!(netif_configs.insert(netif_1).is_ok());
+    }
+
     #[test]
     fn test_insert_error_cases() {
         let mut netif_configs = NetworkInterfaceConfigs::new();
@@ -419,6 +547,72 @@ mod tests {
                 .to_string(),
             expected_error
         );
+
+        // Error Case: Zero-valued RX coalescing settings.
+        let mut netif_3 = create_netif("id_3", "dev5", "01:23:45:67:89:0c");
+        netif_3.rx_coalescing = Some(RxCoalescingConfig {
+            max_frames: 0,
+            timeout_us: 100,
+        });
+        assert_eq!(
+            netif_configs
+                .insert(netif_3.clone())
+                .unwrap_err()
+                .to_string(),
+            NetworkInterfaceError::InvalidRxCoalescing.to_string()
+        );
+        netif_3.rx_coalescing = Some(RxCoalescingConfig {
+            max_frames: 4,
+            timeout_us: 0,
+        });
+        assert_eq!(
+            netif_configs
+                .insert(netif_3.clone())
+                .unwrap_err()
+                .to_string(),
+            NetworkInterfaceError::InvalidRxCoalescing.to_string()
+        );
+        assert_eq!(netif_configs.if_list.len(), 2);
+
+        // Error Case: RX buffer sizes out of bounds.
+        let mut netif_4 = create_netif("id_4", "dev6", "01:23:45:67:89:0d");
+        for &frames in &[0, devices::virtio::MAX_RX_BUFFER_FRAMES as u32 + 1] {
+            netif_4.rx_buffer_frames = Some(frames);
+            assert_eq!(
+                netif_configs
+                    .insert(netif_4.clone())
+                    .unwrap_err()
+                    .to_string(),
+                NetworkInterfaceError::InvalidRxBufferFrames.to_string()
+            );
+        }
+        assert_eq!(netif_configs.if_list.len(), 2);
+
+        // Error Case: the virtio feature mask clears VIRTIO_F_VERSION_1.
+        let mut netif_5 = create_netif("id_5", "dev7", "01:23:45:67:89:0e");
+        netif_5.virtio_feature_mask = Some(!(1u64 << 32));
+        assert_eq!(
+            netif_configs
+                .insert(netif_5.clone())
+                .unwrap_err()
+                .to_string(),
+            NetworkInterfaceError::InvalidVirtioFeatureMask.to_string()
+        );
+        assert_eq!(netif_configs.if_list.len(), 2);
+
+        // Error Case: the MMDS MSS clamp doesn't fit in the link MTU.
+        let mut netif_6 = create_netif("id_6", "dev8", "01:23:45:67:89:0f");
+        for &clamp in &[0, devices::virtio::MAX_MMDS_MSS_CLAMP + 1] {
+            netif_6.mmds_mss_clamp = Some(clamp);
+            assert_eq!(
+                netif_configs
+                    .insert(netif_6.clone())
+                    .unwrap_err()
+                    .to_string(),
+                NetworkInterfaceError::InvalidMmdsMssClamp.to_string()
+            );
+        }
+        assert_eq!(netif_configs.if_list.len(), 2);
     }
 
     #[test]
@@ -442,11 +636,40 @@ mod tests {
             NetworkInterfaceError::HostDeviceNameInUse("hostdev".to_string()),
             NetworkInterfaceError::HostDeviceNameInUse("hostdev".to_string())
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::DetachFailed(device_manager::mmio::Error::DeviceNotFound(
+                "id_1".to_string()
+            )),
+            NetworkInterfaceError::DetachFailed(device_manager::mmio::Error::DeviceNotFound(
+                "id_1".to_string()
+            ))
+        );
         let _ = format!(
             "{}{:?}",
             NetworkInterfaceError::DeviceIdNotFound,
             NetworkInterfaceError::DeviceIdNotFound
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidRxCoalescing,
+            NetworkInterfaceError::InvalidRxCoalescing
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidRxBufferFrames,
+            NetworkInterfaceError::InvalidRxBufferFrames
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidVirtioFeatureMask,
+            NetworkInterfaceError::InvalidVirtioFeatureMask
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::InvalidMmdsMssClamp,
+            NetworkInterfaceError::InvalidMmdsMssClamp
+        );
         let _ = format!(
             "{}{:?}",
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname),
@@ -0,0 +1,12 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::PathBuf;
+
+/// Strongly typed structure used to describe the snapshot file of the microVM.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// The host path of the snapshot file.
+    pub snapshot_path: PathBuf,
+}
@@ -0,0 +1,145 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::result;
+
+/// This struct represents the strongly typed equivalent of the json body
+/// from vhost-user block device related requests.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VhostUserBlockDeviceConfig {
+    /// ID of the vhost-user block device.
+    pub id: String,
+    /// Path of the Unix domain socket on which the vhost-user backend listens.
+    pub socket_path: String,
+}
+
+/// Errors associated with `VhostUserBlockDeviceConfig`.
+#[derive(Debug)]
+pub enum VhostUserBlockError {
+    /// The socket path is already used by another vhost-user block device.
+    SocketPathAlreadyInUse(String),
+    /// The update is not allowed after booting the microvm.
+    UpdateNotAllowedPostBoot,
+}
+
+impl Display for VhostUserBlockError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::VhostUserBlockError::*;
+        match *self {
+            SocketPathAlreadyInUse(ref path) => write!(
+                f,
+                "The socket path {} is already used by another vhost-user block device.",
+                path
+            ),
+            UpdateNotAllowedPostBoot => {
+                write!(f, "The update operation is not allowed after boot.")
+            }
+        }
+    }
+}
+
+/// A list with all the vhost-user block devices.
+#[derive(Default)]
+pub struct VhostUserBlockDeviceConfigs {
+    configs: Vec<VhostUserBlockDeviceConfig>,
+}
+
+impl VhostUserBlockDeviceConfigs {
+    /// Creates an empty list of VhostUserBlockDeviceConfig.
+    pub fn new() -> Self {
+        VhostUserBlockDeviceConfigs {
+            configs: Vec::new(),
+        }
+    }
+
+    /// Returns whether there are no vhost-user block devices configured.
+    pub fn is_empty(&self) -> bool {
+        self.configs.is_empty()
+    }
+
+    /// Adds `cfg` in the list of vhost-user block device configurations.
+    /// If an entry with the same id already exists, it will update the existing
+    /// entry.
+    pub fn add(
+        &mut self,
+        cfg: VhostUserBlockDeviceConfig,
+    ) -> result::Result<(), VhostUserBlockError> {
+        // A backend serves a single connection, so the devices can't share it.
+        if self
+            .configs
+            .iter()
+            .any(|other| other.id != cfg.id && other.socket_path == cfg.socket_path)
+        {
+            return Err(VhostUserBlockError::SocketPathAlreadyInUse(cfg.socket_path));
+        }
+
+        match self
+            .configs
+            .iter()
+            .position(|cfg_from_list| cfg_from_list.id == cfg.id)
+        {
+            Some(index) => self.configs[index] = cfg,
+            None => self.configs.push(cfg),
+        }
+
+        Ok(())
+    }
+
+    /// Returns an immutable iterator over the vhost-user block device configurations.
+    pub fn iter(&self) -> ::std::slice::Iter<VhostUserBlockDeviceConfig> {
+        self.configs.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(id: &str, socket_path: &str) -> VhostUserBlockDeviceConfig {
+        VhostUserBlockDeviceConfig {
+            id: String::from(id),
+            socket_path: String::from(socket_path),
+        }
+    }
+
+    #[test]
+    fn test_add_vhost_user_block_configs() {
+        let mut configs = VhostUserBlockDeviceConfigs::new();
+        assert!(configs.is_empty());
+
+        assert!(configs.add(config("blk0", "/tmp/blk0.sock")).is_ok());
+        assert!(configs.add(config("blk1", "/tmp/blk1.sock")).is_ok());
+        assert!(!configs.is_empty());
+
+        // Updating a device keeps its position in the list.
+        assert!(configs.add(config("blk0", "/tmp/blk2.sock")).is_ok());
+        let paths: Vec<&str> = configs.iter().map(|cfg| cfg.socket_path.as_str()).collect();
+        assert_eq!(paths, vec!["/tmp/blk2.sock", "/tmp/blk1.sock"]);
+
+        // Error case: the socket path is used by another device.
+        match configs.add(config("blk3", "/tmp/blk1.sock")) {
+            Err(VhostUserBlockError::SocketPathAlreadyInUse(path)) => {
+                assert_eq!(path, "/tmp/blk1.sock")
+            }
+            _ => panic!("Expected a socket path error."),
+        }
+        assert_eq!(configs.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_vhost_user_block_error_display() {
+        assert_eq!(
+            format!(
+                "{}",
+                VhostUserBlockError::SocketPathAlreadyInUse(String::from("/tmp/blk.sock"))
+            ),
+            "The socket path /tmp/blk.sock is already used by another vhost-user block device."
+        );
+        assert_eq!(
+            format!("{}", VhostUserBlockError::UpdateNotAllowedPostBoot),
+            "The update operation is not allowed after boot."
+        );
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::path::Path;
+use std::result;
+
+use devices::virtio::fs::MAX_TAG_LEN;
+
+/// This struct represents the strongly typed equivalent of the json body
+/// from virtio-fs device related requests.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioFsDeviceConfig {
+    /// ID of the virtio-fs device.
+    pub id: String,
+    /// Host level path to the directory shared with the guest.
+    pub shared_dir: String,
+    /// The tag by which the guest mounts the shared directory.
+    pub tag: String,
+}
+
+/// Errors associated with `VirtioFsDeviceConfig`.
+#[derive(Debug)]
+pub enum VirtioFsError {
+    /// The shared directory does not exist or is not a directory.
+    InvalidSharedDir(String),
+    /// The tag is empty or too long.
+    InvalidTag(String),
+    /// The tag is already used by another virtio-fs device.
+    TagAlreadyInUse(String),
+    /// The update is not allowed after booting the microvm.
+    UpdateNotAllowedPostBoot,
+}
+
+impl Display for VirtioFsError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::VirtioFsError::*;
+        match *self {
+            InvalidSharedDir(ref path) => write!(f, "The shared directory {} is invalid.", path),
+            InvalidTag(ref tag) => write!(
+                f,
+                "The tag {} is invalid. It must be between 1 and {} bytes long.",
+                tag, MAX_TAG_LEN
+            ),
+            TagAlreadyInUse(ref tag) => write!(
+                f,
+                "The tag {} is already used by another virtio-fs device.",
+                tag
+            ),
+            UpdateNotAllowedPostBoot => {
+                write!(f, "The update operation is not allowed after boot.")
+            }
+        }
+    }
+}
+
+/// A list with all the virtio-fs devices.
+#[derive(Default)]
+pub struct VirtioFsDeviceConfigs {
+    configs: Vec<VirtioFsDeviceConfig>,
+}
+
+impl VirtioFsDeviceConfigs {
+    /// Creates an empty list of VirtioFsDeviceConfig.
+    pub fn new() -> Self {
+        VirtioFsDeviceConfigs {
+            configs: Vec::new(),
+        }
+    }
+
+    /// Returns whether there are no virtio-fs devices configured.
+    pub fn is_empty(&self) -> bool {
+        self.configs.is_empty()
+    }
+
+    /// Adds `cfg` in the list of virtio-fs device configurations.
+    /// If an entry with the same id already exists, it will update the existing
+    /// entry.
+    pub fn add(&mut self, cfg: VirtioFsDeviceConfig) -> result::Result<(), VirtioFsError> {
+        if !Path::new(&cfg.shared_dir).is_dir() {
+            return Err(VirtioFsError::InvalidSharedDir(cfg.shared_dir));
+        }
+        if cfg.tag.is_empty() || cfg.tag.len() > MAX_TAG_LEN {
+            return Err(VirtioFsError::InvalidTag(cfg.tag));
+        }
+        // The guest could not tell the devices apart when mounting them.
+        if self
+            .configs
+            .iter()
+            .any(|other| other.id != cfg.id && other.tag == cfg.tag)
+        {
+            return Err(VirtioFsError::TagAlreadyInUse(cfg.tag));
+        }
+
+        match self
+            .configs
+            .iter()
+            .position(|cfg_from_list| cfg_from_list.id == cfg.id)
+        {
+            Some(index) => self.configs[index] = cfg,
+            None => self.configs.push(cfg),
+        }
+
+        Ok(())
+    }
+
+    /// Returns an immutable iterator over the virtio-fs device configurations.
+    pub fn iter(&self) -> ::std::slice::Iter<VirtioFsDeviceConfig> {
+        self.configs.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::{tempdir, NamedTempFile};
+    use super::*;
+
+    fn config(id: &str, shared_dir: &Path, tag: &str) -> VirtioFsDeviceConfig {
+        VirtioFsDeviceConfig {
+            id: String::from(id),
+            shared_dir: shared_dir.to_str().unwrap().to_string(),
+            tag: String::from(tag),
+        }
+    }
+
+    #[test]
+    fn test_add_virtio_fs_configs() {
+        let dir = tempdir().unwrap();
+        let mut configs = VirtioFsDeviceConfigs::new();
+        assert!(configs.is_empty());
+
+        assert!(configs.add(config("fs0", dir.path(), "tag0")).is_ok());
+        assert!(configs.add(config("fs1", dir.path(), "tag1")).is_ok());
+        assert!(!configs.is_empty());
+
+        // Updating a device keeps its position in the list.
+        assert!(configs.add(config("fs0", dir.path(), "tag2")).is_ok());
+        let tags: Vec<&str> = configs.iter().map(|cfg| cfg.tag.as_str()).collect();
+        assert_eq!(tags, vec!["tag2", "tag1"]);
+
+        // Error case: the tag is used by another device.
+        match configs.add(config("fs3", dir.path(), "tag1")) {
+            Err(VirtioFsError::TagAlreadyInUse(tag)) => assert_eq!(tag, "tag1"),
+            _ => panic!("Expected a tag error."),
+        }
+
+        // Error case: the tag doesn't fit in the configuration space of the device.
+        match configs.add(config("fs3", dir.path(), &"t".repeat(MAX_TAG_LEN + 1))) {
+            Err(VirtioFsError::InvalidTag(_)) => (),
+            _ => panic!("Expected a tag error."),
+        }
+        match configs.add(config("fs3", dir.path(), "")) {
+            Err(VirtioFsError::InvalidTag(_)) => (),
+            _ => panic!("Expected a tag error."),
+        }
+
+        // Error case: the shared directory is missing or is a file.
+        match configs.add(config("fs3", &dir.path().join("missing"), "tag3")) {
+            Err(VirtioFsError::InvalidSharedDir(_)) => (),
+            _ => panic!("Expected a shared directory error."),
+        }
+        let file = NamedTempFile::new().unwrap();
+        match configs.add(config("fs3", file.path(), "tag3")) {
+            Err(VirtioFsError::InvalidSharedDir(_)) => (),
+            _ => panic!("Expected a shared directory error."),
+        }
+        assert_eq!(configs.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_virtio_fs_error_display() {
+        assert_eq!(
+            format!("{}", VirtioFsError::InvalidSharedDir(String::from("/srv"))),
+            "The shared directory /srv is invalid."
+        );
+        assert_eq!(
+            format!("{}", VirtioFsError::InvalidTag(String::from(""))),
+            "The tag  is invalid. It must be between 1 and 36 bytes long."
+        );
+        assert_eq!(
+            format!("{}", VirtioFsError::TagAlreadyInUse(String::from("shared"))),
+            "The tag shared is already used by another virtio-fs device."
+        );
+        assert_eq!(
+            format!("{}", VirtioFsError::UpdateNotAllowedPostBoot),
+            "The update operation is not allowed after boot."
+        );
+    }
+}
@@ -2,8 +2,16 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fmt::{Display, Formatter, Result};
+use std::path::PathBuf;
 use std::result;
 
+/// The largest file the guest can send by default, in MiB.
+const DEFAULT_MAX_FILE_SIZE_MIB: u32 = 1024;
+/// The total size of the files the guest can send by default, in MiB.
+const DEFAULT_MAX_TOTAL_SIZE_MIB: u32 = 4096;
+/// The number of files the guest can send by default.
+const DEFAULT_MAX_FILE_COUNT: u32 = 1024;
+
 /// This struct represents the strongly typed equivalent of the json body
 /// from vsock related requests.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -13,6 +21,46 @@ pub struct VsockDeviceConfig {
     pub id: String,
     /// A 32-bit Context Identifier (CID) used to identify the guest.
     pub guest_cid: u32,
+    /// The vsock port on which the host listens for the guest agent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_port: Option<u32>,
+    /// Receives the files sent by the guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_transfer: Option<FileTransferConfig>,
+}
+
+/// Where the host receives the files sent by the guest over vsock.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileTransferConfig {
+    /// The vsock port on which the host listens for the files.
+    pub port: u32,
+    /// The host directory in which the received files are written.
+    pub directory: PathBuf,
+    /// The largest file the guest can send, in MiB.
+    #[serde(default = "default_max_file_size_mib")]
+    pub max_file_size_mib: u32,
+    /// The total size of the files the guest can send, in MiB.
+    #[serde(default = "default_max_total_size_mib")]
+    pub max_total_size_mib: u32,
+    /// The number of files the guest can send.
+    #[serde(default = "default_max_file_count")]
+    pub max_file_count: u32,
+}
+
+// Serde does not allow specifying a default value for a field
+// that is not required. The workaround is to specify a function
+// that returns the value.
+fn default_max_file_size_mib() -> u32 {
+    DEFAULT_MAX_FILE_SIZE_MIB
+}
+
+fn default_max_total_size_mib() -> u32 {
+    DEFAULT_MAX_TOTAL_SIZE_MIB
+}
+
+fn default_max_file_count() -> u32 {
+    DEFAULT_MAX_FILE_COUNT
 }
 
 /// Errors associated with `VsockDeviceConfig`.
@@ -22,6 +70,10 @@ pub enum VsockError {
     GuestCIDAlreadyInUse(u32),
     /// The update is not allowed after booting the microvm.
     UpdateNotAllowedPostBoot,
+    /// The directory receiving the files sent by the guest doesn't exist.
+    FileTransferDirectoryNotFound(PathBuf),
+    /// The guest agent and the file transfers use the same vsock port.
+    FileTransferPortInUse(u32),
 }
 
 impl Display for VsockError {
@@ -34,6 +86,16 @@ impl Display for VsockError {
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.",)
             }
+            FileTransferDirectoryNotFound(ref path) => write!(
+                f,
+                "The file transfer directory {} does not exist.",
+                path.display()
+            ),
+            FileTransferPortInUse(port) => write!(
+                f,
+                "The vsock port {} is already used by the guest agent.",
+                port
+            ),
         }
     }
 }
@@ -67,6 +129,16 @@ impl VsockDeviceConfigs {
         if self.contains_cid(cfg.guest_cid) {
             return Err(VsockError::GuestCIDAlreadyInUse(cfg.guest_cid));
         }
+        if let Some(ref file_transfer) = cfg.file_transfer {
+            if !file_transfer.directory.is_dir() {
+                return Err(VsockError::FileTransferDirectoryNotFound(
+                    file_transfer.directory.clone(),
+                ));
+            }
+            if cfg.agent_port == Some(file_transfer.port) {
+                return Err(VsockError::FileTransferPortInUse(file_transfer.port));
+            }
+        }
 
         match self
             .configs
@@ -85,3 +157,58 @@ impl VsockDeviceConfigs {
         self.configs.iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    use serde_json;
+
+    #[test]
+    fn test_add_file_transfer() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut cfg = VsockDeviceConfig {
+            id: String::from("vsock0"),
+            guest_cid: 3,
+            agent_port: Some(52),
+            file_transfer: Some(FileTransferConfig {
+                port: 52,
+                directory: directory.path().to_path_buf(),
+                max_file_size_mib: default_max_file_size_mib(),
+                max_total_size_mib: default_max_total_size_mib(),
+                max_file_count: default_max_file_count(),
+            }),
+        };
+        let mut configs = VsockDeviceConfigs::new();
+
+        match configs.add(cfg.clone()) {
+            Err(VsockError::FileTransferPortInUse(52)) => (),
+            _ => panic!("The guest agent port should be in use"),
+        }
+
+        cfg.file_transfer.as_mut().unwrap().directory = directory.path().join("missing");
+        match configs.add(cfg.clone()) {
+            Err(VsockError::FileTransferDirectoryNotFound(ref path)) => {
+                assert_eq!(path, &directory.path().join("missing"))
+            }
+            _ => panic!("The directory should not exist"),
+        }
+
+        cfg.file_transfer = Some(
+            serde_json::from_str(&format!(
+                r#"{{"port": 53, "directory": "{}"}}"#,
+                directory.path().display()
+            ))
+            .unwrap(),
+        );
+        {
+            let file_transfer = cfg.file_transfer.as_ref().unwrap();
+            assert_eq!(file_transfer.max_file_size_mib, DEFAULT_MAX_FILE_SIZE_MIB);
+            assert_eq!(file_transfer.max_total_size_mib, DEFAULT_MAX_TOTAL_SIZE_MIB);
+            assert_eq!(file_transfer.max_file_count, DEFAULT_MAX_FILE_COUNT);
+        }
+        assert!(configs.add(cfg).is_ok());
+    }
+}
@@ -6,27 +6,54 @@
 // found in the THIRD-PARTY file.
 
 use std::io;
+use std::process;
 use std::result;
-use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Barrier, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use super::{KvmContext, TimestampUs};
+use libc::{c_int, c_void, siginfo_t};
+
+use super::{KvmContext, TimestampUs, FC_EXIT_CODE_SECCOMP_FAILURE};
 use arch;
+#[cfg(target_arch = "aarch64")]
+use arch::aarch64::gic::{self, GicVersion};
 #[cfg(target_arch = "x86_64")]
-use cpuid::{c3, filter_cpuid, t2};
+use cpuid::{apic, c3, clock, filter_cpuid, t2};
 use default_syscalls;
+use devices::legacy::PVPANIC_PANICKED;
+use event_counter::EventCounter;
 use kvm_bindings::{kvm_pit_config, kvm_userspace_memory_region, KVM_PIT_SPEAKER_DUMMY};
 use kvm_ioctls::*;
+use logger::metrics::{BootMetrics, BootMilestone, VcpuExitMetrics};
 use logger::{LogOption, Metric, LOGGER, METRICS};
 use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
-use sys_util::EventFd;
+use numa;
+use sched;
+use snapshot;
+use sys_util::{register_vcpu_signal_handler, EventFd, Killable};
+use vmm_config::instance_info::{InstanceInfo, InstanceState, StopReason};
 #[cfg(target_arch = "x86_64")]
-use vmm_config::machine_config::CpuFeaturesTemplate;
-use vmm_config::machine_config::VmConfig;
+use vmm_config::machine_config::{ApicMode, ClockSource, CpuFeaturesTemplate};
+use vmm_config::machine_config::{GuestPanicAction, VcpuScheduling, VmConfig};
 
 const KVM_MEM_LOG_DIRTY_PAGES: u32 = 0x1;
 
 const MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE: u16 = 0x03f0;
 const MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE: u8 = 123;
+const MAGIC_VALUE_SIGNAL_GUEST_HEARTBEAT: u8 = 124;
+
+// The guest signals a panic by writing the PANICKED bit to the I/O port of the pvpanic device.
+// There is no ACPI for advertising the port on x86_64, so only guests which know about it use it.
+const PVPANIC_IOPORT: u16 = 0x0505;
+
+// The offset from `SIGRTMIN` of the signal kicking a vCPU out of the guest.
+const VCPU_RTSIG_OFFSET: i32 = 0;
+// How often a paused vCPU is kicked until it serves a request, and for how long.
+const VCPU_KICK_INTERVAL: Duration = Duration::from_millis(1);
+const VCPU_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Errors associated with the wrappers over KVM ioctls.
 #[derive(Debug)]
@@ -34,6 +61,12 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     /// A call to cpuid instruction failed.
     CpuId(cpuid::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// The host can't provide the clock source requested for the guest.
+    ClockSourceNotSupported(ClockSource),
+    #[cfg(target_arch = "x86_64")]
+    /// KVM can't expose x2APIC to the guest.
+    X2ApicNotSupported,
     /// Invalid guest memory configuration.
     GuestMemory(GuestMemoryError),
     /// Hyperthreading flag is not initialized.
@@ -48,10 +81,18 @@ pub enum Error {
     VmSetup(io::Error),
     /// Cannot run the VCPUs.
     VcpuRun(io::Error),
+    /// Cannot register the handler of the signal kicking the vCPUs out of the guest.
+    SignalHandler(io::Error),
     /// The call to KVM_SET_CPUID2 failed.
     SetSupportedCpusFailed(io::Error),
     /// The number of configured slots is bigger than the maximum reported by KVM.
     NotEnoughMemorySlots,
+    /// No memory region is registered in the given slot.
+    InvalidMemorySlot(usize),
+    /// The logging of the dirty pages is not enabled on the memory region in the given slot.
+    DirtyPageLoggingDisabled(usize),
+    /// Cannot get the dirty page bitmap of a memory region.
+    GetDirtyLog(io::Error),
     #[cfg(target_arch = "x86_64")]
     /// Cannot set the local interruption due to bad configuration.
     LocalIntConfiguration(arch::x86_64::interrupts::Error),
@@ -76,8 +117,8 @@ pub enum Error {
     Irq(io::Error),
     /// Cannot spawn a new vCPU thread.
     VcpuSpawn(io::Error),
-    /// Unexpected KVM_RUN exit reason
-    VcpuUnhandledKvmExit,
+    /// Unexpected KVM_RUN exit reason, which stops the guest for the given reason.
+    VcpuUnhandledKvmExit(StopReason),
     #[cfg(target_arch = "aarch64")]
     /// Error setting up the global interrupt controller.
     SetupGIC(arch::aarch64::gic::Error),
@@ -94,6 +135,8 @@ pub type Result<T> = result::Result<T, Error>;
 pub struct Vm {
     fd: VmFd,
     guest_mem: Option<GuestMemory>,
+    // The memory regions registered with KVM, indexed by slot.
+    memory_regions: Vec<kvm_userspace_memory_region>,
 
     // X86 specific fields.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -103,6 +146,8 @@ pub struct Vm {
     // On aarch64 we need to keep around the fd obtained by creating the VGIC device.
     #[cfg(target_arch = "aarch64")]
     irqchip_handle: Option<DeviceFd>,
+    #[cfg(target_arch = "aarch64")]
+    gic_version: Option<GicVersion>,
 }
 
 impl Vm {
@@ -119,8 +164,11 @@ impl Vm {
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             supported_cpuid: cpuid,
             guest_mem: None,
+            memory_regions: Vec::new(),
             #[cfg(target_arch = "aarch64")]
             irqchip_handle: None,
+            #[cfg(target_arch = "aarch64")]
+            gic_version: None,
         })
     }
 
@@ -136,23 +184,9 @@ impl Vm {
             return Err(Error::NotEnoughMemorySlots);
         }
         guest_mem
-            .with_regions(|index, guest_addr, size, host_addr| {
+            .with_regions_mut(|index, guest_addr, size, host_addr| {
                 info!("Guest memory starts at {:x?}", host_addr);
-
-                let flags = if LOGGER.flags() & LogOption::LogDirtyPages as usize > 0 {
-                    KVM_MEM_LOG_DIRTY_PAGES
-                } else {
-                    0
-                };
-
-                let memory_region = kvm_userspace_memory_region {
-                    slot: index as u32,
-                    guest_phys_addr: guest_addr.offset() as u64,
-                    memory_size: size as u64,
-                    userspace_addr: host_addr as u64,
-                    flags,
-                };
-                self.fd.set_user_memory_region(memory_region)
+                self.set_user_memory_region(index, guest_addr, size, host_addr)
             })
             .map_err(Error::SetUserMemoryRegion)?;
         self.guest_mem = Some(guest_mem);
@@ -165,6 +199,78 @@ impl Vm {
         Ok(())
     }
 
+    fn set_user_memory_region(
+        &mut self,
+        slot: usize,
+        guest_addr: GuestAddress,
+        size: usize,
+        host_addr: usize,
+    ) -> result::Result<(), io::Error> {
+        let flags = if LOGGER.flags() & LogOption::LogDirtyPages as usize > 0 {
+            KVM_MEM_LOG_DIRTY_PAGES
+        } else {
+            0
+        };
+
+        let memory_region = kvm_userspace_memory_region {
+            slot: slot as u32,
+            guest_phys_addr: guest_addr.offset() as u64,
+            memory_size: size as u64,
+            userspace_addr: host_addr as u64,
+            flags,
+        };
+        self.fd.set_user_memory_region(memory_region)?;
+
+        if slot < self.memory_regions.len() {
+            self.memory_regions[slot] = memory_region;
+        } else {
+            self.memory_regions.push(memory_region);
+        }
+        Ok(())
+    }
+
+    /// Enables or disables the logging of the pages written by the guest in the memory region
+    /// registered in `slot`.
+    ///
+    /// The region doesn't need to have been registered with the logging enabled.
+    // The live migration, which needs this, is not implemented yet.
+    #[allow(dead_code)]
+    pub fn set_dirty_page_logging(&mut self, slot: usize, enable: bool) -> Result<()> {
+        let mut memory_region = *self
+            .memory_regions
+            .get(slot)
+            .ok_or(Error::InvalidMemorySlot(slot))?;
+        if enable {
+            memory_region.flags |= KVM_MEM_LOG_DIRTY_PAGES;
+        } else {
+            memory_region.flags &= !KVM_MEM_LOG_DIRTY_PAGES;
+        }
+
+        self.fd
+            .set_user_memory_region(memory_region)
+            .map_err(Error::SetUserMemoryRegion)?;
+        self.memory_regions[slot] = memory_region;
+        Ok(())
+    }
+
+    /// Returns the bitmap of the pages written by the guest in the memory region registered in
+    /// `slot` since the previous call, with one bit per page. KVM clears the bitmap as it
+    /// returns it.
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_dirty_log(&self, slot: usize) -> Result<Vec<u64>> {
+        let memory_region = self
+            .memory_regions
+            .get(slot)
+            .ok_or(Error::InvalidMemorySlot(slot))?;
+        if memory_region.flags & KVM_MEM_LOG_DIRTY_PAGES == 0 {
+            return Err(Error::DirtyPageLoggingDisabled(slot));
+        }
+
+        self.fd
+            .get_dirty_log(slot as u32, memory_region.memory_size as usize)
+            .map_err(Error::GetDirtyLog)
+    }
+
     /// Creates the irq chip and an in-kernel device model for the PIT.
     #[cfg(target_arch = "x86_64")]
     pub fn setup_irqchip(&self) -> Result<()> {
@@ -176,14 +282,29 @@ impl Vm {
         self.fd.create_pit2(pit_config).map_err(Error::VmSetup)
     }
 
-    /// Creates the GIC (Global Interrupt Controller).
+    /// Creates the GIC (Global Interrupt Controller) of the requested version. Without a
+    /// requested version, a GICv3 is created if the host supports it, and a GICv2 otherwise.
     #[cfg(target_arch = "aarch64")]
-    pub fn setup_irqchip(&mut self, vcpu_count: u8) -> Result<()> {
-        self.irqchip_handle =
-            Some(arch::aarch64::gic::create_gicv3(&self.fd, vcpu_count).map_err(Error::SetupGIC)?);
+    pub fn setup_irqchip(&mut self, vcpu_count: u8, version: Option<GicVersion>) -> Result<()> {
+        let (irqchip_handle, version) =
+            match gic::create_gic(&self.fd, vcpu_count, version.unwrap_or(GicVersion::V3)) {
+                Err(gic::Error::UnsupportedVersion(_)) if version.is_none() => (
+                    gic::create_gic(&self.fd, vcpu_count, GicVersion::V2),
+                    GicVersion::V2,
+                ),
+                result => (result, version.unwrap_or(GicVersion::V3)),
+            };
+        self.irqchip_handle = Some(irqchip_handle.map_err(Error::SetupGIC)?);
+        self.gic_version = Some(version);
         Ok(())
     }
 
+    /// Gets the version of the GIC, once it is created.
+    #[cfg(target_arch = "aarch64")]
+    pub fn gic_version(&self) -> Option<GicVersion> {
+        self.gic_version
+    }
+
     /// Gets a reference to the guest memory owned by this VM.
     ///
     /// Note that `GuestMemory` does not include any device memory that may have been added after
@@ -207,7 +328,19 @@ pub struct Vcpu {
     id: u8,
     io_bus: devices::Bus,
     mmio_bus: Option<devices::Bus>,
+    pvpanic_addr: Option<u64>,
     create_ts: TimestampUs,
+    heartbeat: Option<EventCounter>,
+    activity: Option<EventCounter>,
+    cpu_affinity: Vec<usize>,
+    scheduling: Option<VcpuScheduling>,
+    instance_info: Option<Arc<RwLock<InstanceInfo>>>,
+    guest_panic_action: GuestPanicAction,
+    paused: Arc<AtomicBool>,
+    // The requests the VMM sends while the vCPU is paused, and the channel for the states it
+    // asks for.
+    requests: Option<(Receiver<VcpuRequest>, Sender<snapshot::Result<Vec<u8>>>)>,
+    boot_metrics: &'static BootMetrics,
 }
 
 impl Vcpu {
@@ -228,14 +361,88 @@ impl Vcpu {
             id,
             io_bus,
             mmio_bus: None,
+            pvpanic_addr: None,
             create_ts,
+            heartbeat: None,
+            activity: None,
+            cpu_affinity: Vec::new(),
+            scheduling: None,
+            instance_info: None,
+            guest_panic_action: GuestPanicAction::default(),
+            paused: Arc::new(AtomicBool::new(false)),
+            requests: None,
+            boot_metrics: &METRICS.boot,
         })
     }
 
+    /// Registers the handler of the signal kicking the vCPUs out of the guest, which does
+    /// nothing but make KVM_RUN return. Signal handlers are process wide, so this is done once,
+    /// before the vCPU threads start.
+    pub fn register_kick_signal_handler() -> Result<()> {
+        extern "C" fn handle_signal(_: c_int, _: *mut siginfo_t, _: *mut c_void) {}
+        // This is safe because the handler doesn't do anything.
+        unsafe { register_vcpu_signal_handler(VCPU_RTSIG_OFFSET, handle_signal) }
+            .map_err(Error::SignalHandler)
+    }
+
+    /// Creates the channels through which the VMM drives the vCPU while it is paused, and
+    /// returns their VMM ends, to be handed to `VcpuHandle::new`.
+    pub fn request_channels(
+        &mut self,
+    ) -> (Sender<VcpuRequest>, Receiver<snapshot::Result<Vec<u8>>>) {
+        let (request_sender, request_receiver) = channel();
+        let (state_sender, state_receiver) = channel();
+        self.requests = Some((request_receiver, state_sender));
+        (request_sender, state_receiver)
+    }
+
     pub fn set_mmio_bus(&mut self, mmio_bus: devices::Bus) {
         self.mmio_bus = Some(mmio_bus);
     }
 
+    /// Sets the MMIO address of the pvpanic device, at which the guest signals a panic.
+    pub fn set_pvpanic_addr(&mut self, addr: u64) {
+        self.pvpanic_addr = Some(addr);
+    }
+
+    /// Sets the host CPUs on which the vCPU thread is allowed to run. An empty list leaves the
+    /// thread free to run on any CPU.
+    pub fn set_cpu_affinity(&mut self, cpus: Vec<usize>) {
+        self.cpu_affinity = cpus;
+    }
+
+    /// Sets the real-time scheduling policy of the vCPU thread.
+    pub fn set_scheduling(&mut self, scheduling: VcpuScheduling) {
+        self.scheduling = Some(scheduling);
+    }
+
+    /// Sets the handle through which the signs of life from the guest are recorded.
+    pub fn set_heartbeat(&mut self, heartbeat: EventCounter) {
+        self.heartbeat = Some(heartbeat);
+    }
+
+    /// Sets the handle through which the exits of the vCPU are recorded as guest activity.
+    pub fn set_activity(&mut self, activity: EventCounter) {
+        self.activity = Some(activity);
+    }
+
+    /// Sets what the vCPU does when the guest panics. `paused` is shared by all the vCPUs of the
+    /// guest, so that pausing one pauses them all.
+    pub fn set_guest_panic_action(&mut self, action: GuestPanicAction, paused: Arc<AtomicBool>) {
+        self.guest_panic_action = action;
+        self.paused = paused;
+    }
+
+    /// Sets the instance information in which the vCPU records why the guest stopped.
+    pub fn set_instance_info(&mut self, instance_info: Arc<RwLock<InstanceInfo>>) {
+        self.instance_info = Some(instance_info);
+    }
+
+    /// Sets where the vCPU records reaching the guest code.
+    pub fn set_boot_metrics(&mut self, boot_metrics: &'static BootMetrics) {
+        self.boot_metrics = boot_metrics;
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Configures a x86_64 specific vcpu and should be called once per vcpu from the vcpu's thread.
     ///
@@ -257,6 +464,7 @@ impl Vcpu {
                 .vcpu_count
                 .ok_or(Error::VcpuCountNotInitialized)?,
             machine_config.ht_enabled.ok_or(Error::HTNotInitialized)?,
+            machine_config.cpu_topology.map_or(1, |t| t.sockets),
             &mut self.cpuid,
         )
         .map_err(Error::CpuId)?;
@@ -268,6 +476,21 @@ impl Vcpu {
             }
         }
 
+        if let Some(clock_source) = machine_config.clock_source {
+            match clock_source {
+                ClockSource::KvmClock => clock::set_kvm_clock(self.cpuid.mut_entries_slice()),
+                ClockSource::Tsc => clock::set_tsc(self.cpuid.mut_entries_slice()),
+            }
+            .map_err(|_| Error::ClockSourceNotSupported(clock_source))?;
+        }
+
+        match machine_config.apic_mode {
+            Some(ApicMode::XApic) => apic::set_xapic(self.cpuid.mut_entries_slice()),
+            Some(ApicMode::X2Apic) => apic::set_x2apic(self.cpuid.mut_entries_slice())
+                .map_err(|_| Error::X2ApicNotSupported)?,
+            None => (),
+        }
+
         self.fd
             .set_cpuid2(&self.cpuid)
             .map_err(Error::SetSupportedCpusFailed)?;
@@ -282,6 +505,11 @@ impl Vcpu {
         arch::x86_64::regs::setup_fpu(&self.fd).map_err(Error::FPUConfiguration)?;
         arch::x86_64::regs::setup_sregs(vm_memory, &self.fd).map_err(Error::SREGSConfiguration)?;
         arch::x86_64::interrupts::set_lint(&self.fd).map_err(Error::LocalIntConfiguration)?;
+        // The switch to x2APIC is only accepted once the CPUID advertising it is set.
+        if machine_config.apic_mode == Some(ApicMode::X2Apic) {
+            arch::x86_64::interrupts::enable_x2apic(&self.fd)
+                .map_err(Error::LocalIntConfiguration)?;
+        }
         Ok(())
     }
 
@@ -290,12 +518,12 @@ impl Vcpu {
     ///
     /// # Arguments
     ///
-    /// * `_machine_config` - Specifies necessary info used for the CPUID configuration.
+    /// * `machine_config` - Specifies necessary info used for the MPIDR configuration.
     /// * `kernel_load_addr` - Offset from `guest_mem` at which the kernel is loaded.
     /// * `vm` - The virtual machine this vcpu will get attached to.
     pub fn configure(
         &mut self,
-        _machine_config: &VmConfig,
+        machine_config: &VmConfig,
         kernel_load_addr: GuestAddress,
         vm: &Vm,
     ) -> Result<()> {
@@ -319,11 +547,26 @@ impl Vcpu {
         self.fd.vcpu_init(&kvi).map_err(Error::VcpuArmInit)?;
         arch::aarch64::regs::setup_regs(&self.fd, self.id, kernel_load_addr.offset(), vm_memory)
             .map_err(Error::REGSConfiguration)?;
+        // Without an explicit topology, KVM's default MPIDR is kept.
+        if let Some(topology) = machine_config.cpu_topology {
+            let mpidr = arch::aarch64::get_mpidr(
+                self.id,
+                topology.threads_per_core,
+                topology.cores_per_socket,
+            );
+            arch::aarch64::regs::setup_mpidr(&self.fd, mpidr).map_err(Error::REGSConfiguration)?;
+        }
         Ok(())
     }
 
     fn run_emulation(&mut self) -> Result<()> {
-        match self.fd.run() {
+        let run = self.fd.run();
+        if let Ok(ref exit) = run {
+            if let Some(exits) = METRICS.vcpu_exits.get(usize::from(self.id)) {
+                record_exit(exits, exit);
+            }
+        }
+        match run {
             Ok(run) => match run {
                 VcpuExit::IoIn(addr, data) => {
                     self.io_bus.read(u64::from(addr), data);
@@ -331,10 +574,22 @@ impl Vcpu {
                     Ok(())
                 }
                 VcpuExit::IoOut(addr, data) => {
-                    if addr == MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE
-                        && data[0] == MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE
-                    {
-                        super::Vmm::log_boot_time(&self.create_ts);
+                    if addr == PVPANIC_IOPORT && data[0] & PVPANIC_PANICKED != 0 {
+                        METRICS.vcpu.exit_io_out.inc();
+                        return self.handle_guest_panic();
+                    }
+                    if addr == MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE {
+                        match data[0] {
+                            MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE => {
+                                super::Vmm::log_boot_time(&self.create_ts)
+                            }
+                            MAGIC_VALUE_SIGNAL_GUEST_HEARTBEAT => {
+                                if let Some(ref heartbeat) = self.heartbeat {
+                                    heartbeat.record();
+                                }
+                            }
+                            _ => (),
+                        }
                     }
                     self.io_bus.write(u64::from(addr), data);
                     METRICS.vcpu.exit_io_out.inc();
@@ -348,6 +603,10 @@ impl Vcpu {
                     Ok(())
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    if Some(addr) == self.pvpanic_addr && data[0] & PVPANIC_PANICKED != 0 {
+                        METRICS.vcpu.exit_mmio_write.inc();
+                        return self.handle_guest_panic();
+                    }
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.write(addr, data);
                         METRICS.vcpu.exit_mmio_write.inc();
@@ -356,30 +615,39 @@ impl Vcpu {
                 }
                 VcpuExit::Hlt => {
                     info!("Received KVM_EXIT_HLT signal");
-                    Err(Error::VcpuUnhandledKvmExit)
+                    Err(Error::VcpuUnhandledKvmExit(StopReason::GuestShutdown))
                 }
+                // KVM reports a triple fault, which is how the x86_64 guests reset, as a
+                // shutdown.
                 VcpuExit::Shutdown => {
                     info!("Received KVM_EXIT_SHUTDOWN signal");
-                    Err(Error::VcpuUnhandledKvmExit)
+                    Err(Error::VcpuUnhandledKvmExit(StopReason::GuestReset))
+                }
+                // On aarch64, the guest powers off or resets through PSCI calls, which KVM
+                // handles and forwards as system events. Stopping the vCPU signals the exit
+                // event, so the VMM exits cleanly.
+                VcpuExit::SystemEvent => {
+                    info!("Received KVM_EXIT_SYSTEM_EVENT signal");
+                    Err(Error::VcpuUnhandledKvmExit(StopReason::GuestShutdown))
                 }
                 // Documentation specifies that below kvm exits are considered
                 // errors.
                 VcpuExit::FailEntry => {
                     METRICS.vcpu.failures.inc();
                     error!("Received KVM_EXIT_FAIL_ENTRY signal");
-                    Err(Error::VcpuUnhandledKvmExit)
+                    Err(Error::VcpuUnhandledKvmExit(StopReason::InternalError))
                 }
                 VcpuExit::InternalError => {
                     METRICS.vcpu.failures.inc();
                     error!("Received KVM_EXIT_INTERNAL_ERROR signal");
-                    Err(Error::VcpuUnhandledKvmExit)
+                    Err(Error::VcpuUnhandledKvmExit(StopReason::InternalError))
                 }
                 r => {
                     METRICS.vcpu.failures.inc();
                     // TODO: Are we sure we want to finish running a vcpu upon
                     // receiving a vm exit that is not necessarily an error?
                     error!("Unexpected exit reason on vcpu run: {:?}", r);
-                    Err(Error::VcpuUnhandledKvmExit)
+                    Err(Error::VcpuUnhandledKvmExit(StopReason::InternalError))
                 }
             },
             // The unwrap on raw_os_error can only fail if we have a logic
@@ -391,13 +659,44 @@ impl Vcpu {
                     _ => {
                         METRICS.vcpu.failures.inc();
                         error!("Failure during vcpu run: {}", e);
-                        Err(Error::VcpuUnhandledKvmExit)
+                        Err(Error::VcpuUnhandledKvmExit(StopReason::InternalError))
                     }
                 }
             }
         }
     }
 
+    /// Sets the CPU affinity and the scheduling policy of the calling vCPU thread. This has to
+    /// happen before `run` loads the seccomp filters.
+    ///
+    /// Only a strict scheduling policy that can't be set is an error, the other failures are
+    /// logged.
+    pub fn setup_thread(&self) -> io::Result<()> {
+        if !self.cpu_affinity.is_empty() {
+            if let Err(e) = numa::set_thread_affinity(&self.cpu_affinity) {
+                METRICS.vcpu.failures.inc();
+                error!("Failed to set the CPU affinity of vCPU {}: {}", self.id, e);
+            }
+        }
+        if let Some(scheduling) = self.scheduling {
+            if let Err(e) = sched::set_thread_scheduling(&scheduling) {
+                if scheduling.strict {
+                    error!(
+                        "Failed to set the scheduling policy of vCPU {}: {}",
+                        self.id, e
+                    );
+                    return Err(e);
+                }
+                warn!(
+                    "Failed to set the scheduling policy of vCPU {}, it keeps the default \
+                     policy: {}",
+                    self.id, e
+                );
+            }
+        }
+        Ok(())
+    }
+
     /// Main loop of the vCPU thread.
     ///
     ///
@@ -412,18 +711,35 @@ impl Vcpu {
         vcpu_exit_evt: EventFd,
     ) {
         // Load seccomp filters for this vCPU thread.
-        // Execution panics if filters cannot be loaded, use --seccomp-level=0 if skipping filters
+        // Firecracker exits if filters cannot be loaded, use --seccomp-level=0 if skipping filters
         // altogether is the desired behaviour.
         if let Err(e) = default_syscalls::set_seccomp_level(seccomp_level) {
-            panic!(
+            error!(
                 "Failed to set the requested seccomp filters on vCPU {}: Error: {}",
                 self.id, e
             );
+            process::exit(i32::from(FC_EXIT_CODE_SECCOMP_FAILURE));
         }
 
         thread_barrier.wait();
 
-        while self.run_emulation().is_ok() {}
+        if self.id == 0 {
+            self.boot_metrics.reach(BootMilestone::GuestStarted);
+        }
+
+        let stop_reason = loop {
+            self.wait_while_paused();
+            match self.run_emulation() {
+                Ok(()) => {
+                    if let Some(ref activity) = self.activity {
+                        activity.record();
+                    }
+                }
+                Err(Error::VcpuUnhandledKvmExit(reason)) => break reason,
+                Err(_) => break StopReason::InternalError,
+            }
+        };
+        self.record_stop_reason(stop_reason);
 
         // Nothing we need do for the success case.
         if let Err(e) = vcpu_exit_evt.write(1) {
@@ -431,6 +747,175 @@ impl Vcpu {
             error!("Failed signaling vcpu exit event: {}", e);
         }
     }
+
+    // Carries out the guest panic action.
+    fn handle_guest_panic(&self) -> Result<()> {
+        METRICS.vcpu.guest_panics.inc();
+        match self.guest_panic_action {
+            GuestPanicAction::Exit => {
+                error!("The guest panicked.");
+                Err(Error::VcpuUnhandledKvmExit(StopReason::GuestPanic))
+            }
+            GuestPanicAction::Pause => {
+                error!("The guest panicked, pausing the vCPUs.");
+                self.paused.store(true, Ordering::SeqCst);
+                if let Some(ref instance_info) = self.instance_info {
+                    instance_info
+                        .write()
+                        .expect("Failed to update the instance info due to poisoned lock")
+                        .state = InstanceState::Paused;
+                }
+                Ok(())
+            }
+            GuestPanicAction::Continue => {
+                warn!("The guest panicked, it keeps running.");
+                Ok(())
+            }
+        }
+    }
+
+    // Keeps the vCPU out of the guest once the vCPUs are paused, serving the requests of the
+    // VMM until it resumes the vCPUs. The vCPUs already running the guest are only paused on
+    // their next exit, which the VMM forces by kicking them, but a panicking Linux guest stops
+    // its other CPUs before it signals the panic. Without a VMM to resume it, the thread stays
+    // parked until Firecracker exits.
+    fn wait_while_paused(&self) {
+        while self.paused.load(Ordering::SeqCst) {
+            match self.requests {
+                Some((ref requests, ref states)) => match requests.recv() {
+                    Ok(VcpuRequest::SaveState) => {
+                        // The VMM gave up on the state if it is gone.
+                        let _ = states.send(self.save_state());
+                    }
+                    Ok(VcpuRequest::Resume) => (),
+                    Err(_) => thread::park(),
+                },
+                None => thread::park(),
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn save_state(&self) -> snapshot::Result<Vec<u8>> {
+        snapshot::vcpu_state(&self.fd)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn save_state(&self) -> snapshot::Result<Vec<u8>> {
+        Err(snapshot::Error::NotSupported)
+    }
+
+    /// Loads the state saved in a snapshot into the vCPU, once it is configured.
+    #[cfg(target_arch = "x86_64")]
+    pub fn restore_state(&self, state: &[u8]) -> snapshot::Result<()> {
+        snapshot::restore_vcpu_state(&self.fd, state)
+    }
+
+    // Records why the guest stopped. The first vCPU to stop tells the reason, since the VMM
+    // stops the others right after.
+    fn record_stop_reason(&self, stop_reason: StopReason) {
+        if let Some(ref instance_info) = self.instance_info {
+            let mut instance_info = instance_info
+                .write()
+                .expect("Failed to update the instance info due to poisoned lock");
+            if instance_info.stop_reason.is_none() {
+                instance_info.stop_reason = Some(stop_reason);
+            }
+        }
+    }
+}
+
+/// The requests the VMM sends to a paused vCPU.
+#[derive(Debug, PartialEq)]
+pub enum VcpuRequest {
+    /// Hands the state of the vCPU over, as laid out in a snapshot.
+    SaveState,
+    /// Runs the guest again, once the vCPUs are no longer paused.
+    Resume,
+}
+
+/// The handle of the VMM on a vCPU thread, through which it drives the vCPU while the vCPUs
+/// are paused.
+pub struct VcpuHandle {
+    id: u8,
+    thread: thread::JoinHandle<()>,
+    requests: Sender<VcpuRequest>,
+    states: Receiver<snapshot::Result<Vec<u8>>>,
+}
+
+impl VcpuHandle {
+    /// Creates the handle of the thread running the vCPU `id`, from the channels returned by
+    /// `Vcpu::request_channels`.
+    pub fn new(
+        id: u8,
+        thread: thread::JoinHandle<()>,
+        requests: Sender<VcpuRequest>,
+        states: Receiver<snapshot::Result<Vec<u8>>>,
+    ) -> Self {
+        VcpuHandle {
+            id,
+            thread,
+            requests,
+            states,
+        }
+    }
+
+    /// Gets the state of the vCPU, as laid out in a snapshot. The vCPUs must be paused: the
+    /// vCPU is kicked out of the guest until it hands its state over, which requires the
+    /// handler registered by `Vcpu::register_kick_signal_handler`.
+    pub fn save_state(&self) -> snapshot::Result<Vec<u8>> {
+        let not_responding = || snapshot::Error::VcpuNotResponding(self.id);
+        // Drop the state of a previous request which timed out.
+        while self.states.try_recv().is_ok() {}
+        self.requests
+            .send(VcpuRequest::SaveState)
+            .map_err(|_| not_responding())?;
+
+        // A blocking wait would hang the VMM on a stuck vCPU. The kicks are repeated, since a
+        // kick landing right before the vCPU enters the guest is lost.
+        let deadline = Instant::now() + VCPU_RESPONSE_TIMEOUT;
+        let mut next_kick = Instant::now();
+        loop {
+            match self.states.try_recv() {
+                Ok(state) => return state,
+                Err(TryRecvError::Disconnected) => return Err(not_responding()),
+                Err(TryRecvError::Empty) => (),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(not_responding());
+            }
+            if now >= next_kick {
+                self.thread
+                    .kill(VCPU_RTSIG_OFFSET)
+                    .map_err(|_| not_responding())?;
+                next_kick = now + VCPU_KICK_INTERVAL;
+            }
+        }
+    }
+
+    /// Lets the vCPU run the guest again. The vCPUs must no longer be paused.
+    pub fn resume(&self) {
+        // The thread is gone if the guest stopped, and then there's nothing to resume.
+        let _ = self.requests.send(VcpuRequest::Resume);
+    }
+}
+
+// Counts the KVM exit `exit` in the exit metrics of its vCPU.
+fn record_exit(exits: &VcpuExitMetrics, exit: &VcpuExit) {
+    let metric = match *exit {
+        VcpuExit::IoIn(..) => &exits.io_in,
+        VcpuExit::IoOut(..) => &exits.io_out,
+        VcpuExit::MmioRead(..) => &exits.mmio_read,
+        VcpuExit::MmioWrite(..) => &exits.mmio_write,
+        VcpuExit::Hlt => &exits.hlt,
+        VcpuExit::Shutdown => &exits.shutdown,
+        VcpuExit::SystemEvent => &exits.system_event,
+        VcpuExit::FailEntry => &exits.fail_entry,
+        VcpuExit::InternalError => &exits.internal_error,
+        _ => &exits.other,
+    };
+    metric.inc();
 }
 
 #[cfg(test)]
@@ -440,10 +925,18 @@ mod tests {
 
     use super::super::devices;
     use super::*;
+    use vmm_config::instance_info::InstanceState;
+    use vmm_config::machine_config::VcpuSchedPolicy;
 
     use libc::{c_int, c_void, siginfo_t};
     use sys_util::{register_vcpu_signal_handler, Killable};
 
+    impl Vcpu {
+        pub fn cpu_affinity(&self) -> &[usize] {
+            &self.cpu_affinity
+        }
+    }
+
     // Auxiliary function being used throughout the tests.
     fn setup_vcpu() -> (Vm, Vcpu) {
         let kvm = KvmContext::new().unwrap();
@@ -462,12 +955,91 @@ mod tests {
         .unwrap();
         #[cfg(target_arch = "aarch64")]
         {
-            vm.setup_irqchip(1).expect("Cannot setup irqchip");
+            vm.setup_irqchip(1, None).expect("Cannot setup irqchip");
         }
 
         (vm, vcpu)
     }
 
+    // Returns the host CPUs on which the thread named `name` is allowed to run.
+    #[cfg(target_arch = "x86_64")]
+    fn thread_cpu_affinity(name: &str) -> Vec<usize> {
+        for entry in std::fs::read_dir("/proc/self/task").unwrap() {
+            let path = entry.unwrap().path();
+            let comm = std::fs::read_to_string(path.join("comm")).unwrap();
+            if comm.trim() != name {
+                continue;
+            }
+
+            let tid: libc::pid_t = path.file_name().unwrap().to_str().unwrap().parse().unwrap();
+            let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            let ret = unsafe {
+                libc::sched_getaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &mut cpuset)
+            };
+            assert_eq!(ret, 0);
+            return (0..8 * std::mem::size_of::<libc::cpu_set_t>())
+                .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &cpuset) })
+                .collect();
+        }
+        panic!("No thread named {}", name);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_cpu_affinity() {
+        let host_cpu = *numa::allowed_cpus().unwrap().last().unwrap();
+        let (vm, mut vcpu) = setup_vcpu();
+        assert!(vcpu
+            .configure(&VmConfig::default(), GuestAddress(0), &vm)
+            .is_ok());
+        vcpu.set_cpu_affinity(vec![host_cpu]);
+        assert_eq!(vcpu.cpu_affinity(), &[host_cpu]);
+
+        let thread_barrier = Arc::new(Barrier::new(2));
+        let vcpu_thread_barrier = thread_barrier.clone();
+        let vcpu_exit_evt = EventFd::new().unwrap();
+        thread::Builder::new()
+            .name("fc_vcpu_pinned".to_string())
+            .spawn(move || {
+                vcpu.setup_thread().unwrap();
+                vcpu.run(vcpu_thread_barrier, 0, vcpu_exit_evt);
+            })
+            .expect("failed to spawn thread ");
+
+        // The affinity is set before the vCPU thread waits on the barrier.
+        thread_barrier.wait();
+        assert_eq!(thread_cpu_affinity("fc_vcpu_pinned"), vec![host_cpu]);
+    }
+
+    #[test]
+    fn test_vcpu_setup_thread() {
+        let (_, mut vcpu) = setup_vcpu();
+        // `SCHED_FIFO` needs a priority of at least 1, so the kernel always rejects this one.
+        let mut scheduling = VcpuScheduling {
+            policy: VcpuSchedPolicy::Fifo,
+            priority: Some(0),
+            runtime_ns: None,
+            deadline_ns: None,
+            period_ns: None,
+            strict: false,
+        };
+        vcpu.set_scheduling(scheduling);
+
+        thread::spawn(move || {
+            // Without the strict mode, the vCPU keeps the default policy.
+            assert!(vcpu.setup_thread().is_ok());
+
+            scheduling.strict = true;
+            vcpu.set_scheduling(scheduling);
+            assert_eq!(
+                vcpu.setup_thread().unwrap_err().raw_os_error(),
+                Some(libc::EINVAL)
+            );
+        })
+        .join()
+        .unwrap();
+    }
+
     #[test]
     fn test_set_mmio_bus() {
         let (_, mut vcpu) = setup_vcpu();
@@ -529,6 +1101,69 @@ mod tests {
         assert!(vm.memory_init(gm, &kvm).is_err());
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_dirty_page_logging() {
+        let kvm = KvmContext::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = Vm::new(kvm.fd()).expect("Cannot create new vm");
+        assert!(vm.memory_init(gm, &kvm).is_ok());
+
+        // The logging can be toggled whatever the flags the region was registered with.
+        vm.set_dirty_page_logging(0, false).unwrap();
+        match vm.get_dirty_log(0) {
+            Err(Error::DirtyPageLoggingDisabled(0)) => (),
+            _ => panic!("Expected a disabled logging error."),
+        }
+        vm.set_dirty_page_logging(0, true).unwrap();
+
+        // Real mode code writing to the page at 0x3000:
+        //   mov byte [0x3000], 1
+        //   hlt
+        let code = [0xc6, 0x06, 0x00, 0x30, 0x01, 0xf4];
+        vm.get_memory()
+            .unwrap()
+            .write_slice_at_addr(&code, GuestAddress(0x1000))
+            .unwrap();
+
+        // Without an irqchip, the vcpu exits to userspace on hlt.
+        let vcpu = Vcpu::new(
+            0,
+            &vm,
+            devices::Bus::new(),
+            super::super::TimestampUs::default(),
+        )
+        .unwrap();
+        let mut sregs = vcpu.fd.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.fd.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.fd.get_regs().unwrap();
+        regs.rip = 0x1000;
+        regs.rflags = 2;
+        vcpu.fd.set_regs(&regs).unwrap();
+        match vcpu.fd.run() {
+            Ok(VcpuExit::Hlt) => (),
+            _ => panic!("Expected the vcpu to halt."),
+        }
+
+        let bitmap = vm.get_dirty_log(0).unwrap();
+        assert_eq!(bitmap.len(), 1);
+        assert_ne!(bitmap[0] & (1 << 3), 0);
+        // Getting the bitmap cleared it.
+        assert_eq!(vm.get_dirty_log(0).unwrap(), vec![0]);
+
+        // Error case: there is no region in slot 1.
+        match vm.set_dirty_page_logging(1, true) {
+            Err(Error::InvalidMemorySlot(1)) => (),
+            _ => panic!("Expected an invalid slot error."),
+        }
+        match vm.get_dirty_log(1) {
+            Err(Error::InvalidMemorySlot(1)) => (),
+            _ => panic!("Expected an invalid slot error."),
+        }
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_setup_irqchip() {
@@ -563,9 +1198,11 @@ mod tests {
         )
         .unwrap();
 
-        vm.setup_irqchip(vcpu_count).expect("Cannot setup irqchip");
+        vm.setup_irqchip(vcpu_count, None)
+            .expect("Cannot setup irqchip");
+        assert_eq!(vm.gic_version(), Some(GicVersion::V3));
         // Trying to setup two irqchips will result in EEXIST error.
-        assert!(vm.setup_irqchip(vcpu_count).is_err());
+        assert!(vm.setup_irqchip(vcpu_count, None).is_err());
     }
 
     #[test]
@@ -587,7 +1224,7 @@ mod tests {
         assert!(vm.setup_irqchip().is_err());
         #[cfg(target_arch = "aarch64")]
         // Trying to setup irqchip after KVM_VCPU_CREATE is actually the way to go on aarch64.
-        assert!(vm.setup_irqchip(1).is_ok());
+        assert!(vm.setup_irqchip(1, None).is_ok());
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -602,11 +1239,82 @@ mod tests {
         let mut vm_config = VmConfig::default();
         vm_config.cpu_template = Some(CpuFeaturesTemplate::T2);
         assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+        // The features hidden by the template must be masked in the CPUID exposed to the guest.
+        for entry in vcpu.cpuid.mut_entries_slice().iter() {
+            match (entry.function, entry.index) {
+                // Leaf 0x1: ECX[2] DTES64, ECX[3] MONITOR, EDX[22] ACPI.
+                (0x1, _) => {
+                    assert_eq!(entry.ecx & (1 << 2), 0);
+                    assert_eq!(entry.ecx & (1 << 3), 0);
+                    assert_eq!(entry.edx & (1 << 22), 0);
+                }
+                // Leaf 0x7, subleaf 0: EBX[2] SGX, EBX[4] HLE, EBX[11] RTM.
+                (0x7, 0) => {
+                    assert_eq!(entry.ebx & (1 << 2), 0);
+                    assert_eq!(entry.ebx & (1 << 4), 0);
+                    assert_eq!(entry.ebx & (1 << 11), 0);
+                }
+                // Leaf 0x80000001: EDX[26] PDPE1GB.
+                (0x8000_0001, _) => assert_eq!(entry.edx & (1 << 26), 0),
+                _ => (),
+            }
+        }
 
         // Test configure while using the C3 template.
         let mut vm_config = VmConfig::default();
         vm_config.cpu_template = Some(CpuFeaturesTemplate::C3);
         assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+
+        // Leaf 0x40000001: EAX[0] and EAX[3] kvm-clock, EAX[24] stable kvm-clock.
+        let kvm_clock_bits = |vcpu: &mut Vcpu| {
+            vcpu.cpuid
+                .mut_entries_slice()
+                .iter()
+                .find(|entry| entry.function == 0x4000_0001)
+                .map(|entry| entry.eax & ((1 << 24) | (1 << 3) | 1))
+        };
+
+        // Test configure while pinning the guest to kvm-clock.
+        let (vm, mut vcpu) = setup_vcpu();
+        let mut vm_config = VmConfig::default();
+        vm_config.clock_source = Some(ClockSource::KvmClock);
+        assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+        let bits = kvm_clock_bits(&mut vcpu).unwrap();
+        assert_eq!(bits & ((1 << 3) | 1), (1 << 3) | 1);
+
+        // Test configure while pinning the guest to the TSC, which the host may not support.
+        let (vm, mut vcpu) = setup_vcpu();
+        vm_config.clock_source = Some(ClockSource::Tsc);
+        match vcpu.configure(&vm_config, GuestAddress(0), &vm) {
+            Ok(()) => assert_eq!(kvm_clock_bits(&mut vcpu), Some(0)),
+            Err(Error::ClockSourceNotSupported(ClockSource::Tsc)) => (),
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+
+        // Leaf 0x1: ECX[21] x2APIC.
+        let x2apic_bit = |vcpu: &mut Vcpu| {
+            vcpu.cpuid
+                .mut_entries_slice()
+                .iter()
+                .find(|entry| entry.function == 0x1)
+                .map(|entry| entry.ecx & (1 << 21))
+        };
+
+        // Test configure while selecting x2APIC.
+        let (vm, mut vcpu) = setup_vcpu();
+        let mut vm_config = VmConfig::default();
+        vm_config.apic_mode = Some(ApicMode::X2Apic);
+        assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+        assert_eq!(x2apic_bit(&mut vcpu), Some(1 << 21));
+        // The LAPIC is in x2APIC mode.
+        assert_ne!(vcpu.fd.get_sregs().unwrap().apic_base & (1 << 10), 0);
+
+        // Test configure while selecting xAPIC.
+        let (vm, mut vcpu) = setup_vcpu();
+        vm_config.apic_mode = Some(ApicMode::XApic);
+        assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+        assert_eq!(x2apic_bit(&mut vcpu), Some(0));
+        assert_eq!(vcpu.fd.get_sregs().unwrap().apic_base & (1 << 10), 0);
     }
 
     #[cfg(target_arch = "aarch64")]
@@ -693,6 +1401,285 @@ mod tests {
         assert_eq!(err.raw_os_error().unwrap(), libc::EAGAIN);
     }
 
+    // Sets up the vCPU `id` running the real mode `code`. Without an irqchip, the vCPU exits to
+    // userspace on hlt.
+    #[cfg(target_arch = "x86_64")]
+    fn setup_real_mode_vcpu(id: u8, code: &[u8]) -> (Vm, Vcpu) {
+        setup_real_mode_vcpu_with_irqchip(id, code, false)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn setup_real_mode_vcpu_with_irqchip(id: u8, code: &[u8], irqchip: bool) -> (Vm, Vcpu) {
+        let kvm = KvmContext::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = Vm::new(kvm.fd()).expect("Cannot create new vm");
+        assert!(vm.memory_init(gm, &kvm).is_ok());
+        if irqchip {
+            vm.setup_irqchip().unwrap();
+        }
+        vm.get_memory()
+            .unwrap()
+            .write_slice_at_addr(code, GuestAddress(0x1000))
+            .unwrap();
+
+        let vcpu = Vcpu::new(
+            id,
+            &vm,
+            devices::Bus::new(),
+            super::super::TimestampUs::default(),
+        )
+        .unwrap();
+        let mut sregs = vcpu.fd.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.fd.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.fd.get_regs().unwrap();
+        regs.rip = 0x1000;
+        regs.rflags = 2;
+        vcpu.fd.set_regs(&regs).unwrap();
+
+        (vm, vcpu)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_stop_reason() {
+        // Real mode code powering the guest off:
+        //   hlt
+        let (_vm, mut vcpu) = setup_real_mode_vcpu(0, &[0xf4]);
+
+        let instance_info = Arc::new(RwLock::new(InstanceInfo {
+            id: "stop-reason".to_string(),
+            state: InstanceState::Running,
+            vmm_version: "1.0".to_string(),
+            stop_reason: None,
+        }));
+        vcpu.set_instance_info(instance_info.clone());
+        let exit_evt = EventFd::new().unwrap();
+        vcpu.run(Arc::new(Barrier::new(1)), 0, exit_evt.try_clone().unwrap());
+
+        assert_eq!(exit_evt.read().unwrap(), 1);
+        assert_eq!(
+            instance_info.read().unwrap().stop_reason,
+            Some(StopReason::GuestShutdown)
+        );
+
+        // Only the first vCPU to stop records the reason.
+        vcpu.record_stop_reason(StopReason::InternalError);
+        assert_eq!(
+            instance_info.read().unwrap().stop_reason,
+            Some(StopReason::GuestShutdown)
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_stop_reason_triple_fault() {
+        // Real mode code raising a breakpoint exception:
+        //   int3
+        // The interrupt vector table is empty, so the exception escalates to a general
+        // protection fault, then a double fault and finally a triple fault, the way Linux resets
+        // with `reboot=t`.
+        let (_vm, mut vcpu) = setup_real_mode_vcpu(0, &[0xcc]);
+        let mut sregs = vcpu.fd.get_sregs().unwrap();
+        sregs.idt.base = 0;
+        sregs.idt.limit = 0;
+        vcpu.fd.set_sregs(&sregs).unwrap();
+
+        let instance_info = Arc::new(RwLock::new(InstanceInfo {
+            id: "stop-reason".to_string(),
+            state: InstanceState::Running,
+            vmm_version: "1.0".to_string(),
+            stop_reason: None,
+        }));
+        vcpu.set_instance_info(instance_info.clone());
+        let exit_evt = EventFd::new().unwrap();
+        vcpu.run(Arc::new(Barrier::new(1)), 0, exit_evt.try_clone().unwrap());
+
+        // KVM reports the triple fault as a shutdown exit, which resets the guest.
+        assert_eq!(exit_evt.read().unwrap(), 1);
+        assert_eq!(
+            instance_info.read().unwrap().stop_reason,
+            Some(StopReason::GuestReset)
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_exit_metrics() {
+        // Real mode code exiting for port I/O and MMIO, outside the guest memory, then powering
+        // the guest off:
+        //   mov al, 0x42
+        //   out 0x80, al
+        //   in al, 0x80
+        //   mov ax, 0x2000
+        //   mov ds, ax
+        //   mov [0], al
+        //   mov al, [0]
+        //   hlt
+        let code = [
+            0xb0, 0x42, 0xe6, 0x80, 0xe4, 0x80, 0xb8, 0x00, 0x20, 0x8e, 0xd8, 0xa2, 0x00, 0x00,
+            0xa0, 0x00, 0x00, 0xf4,
+        ];
+        // The other tests run vCPU 0.
+        let vcpu_id = 5;
+        let (_vm, mut vcpu) = setup_real_mode_vcpu(vcpu_id, &code);
+        vcpu.run(Arc::new(Barrier::new(1)), 0, EventFd::new().unwrap());
+
+        let exits = METRICS.vcpu_exits.get(usize::from(vcpu_id)).unwrap();
+        assert_eq!(exits.io_out.count(), 1);
+        assert_eq!(exits.io_in.count(), 1);
+        assert_eq!(exits.mmio_write.count(), 1);
+        assert_eq!(exits.mmio_read.count(), 1);
+        assert_eq!(exits.hlt.count(), 1);
+        assert_eq!(exits.shutdown.count(), 0);
+        assert_eq!(exits.other.count(), 0);
+        // The exits of a vCPU don't count for the others.
+        assert_eq!(
+            METRICS
+                .vcpu_exits
+                .get(usize::from(vcpu_id) + 1)
+                .unwrap()
+                .hlt
+                .count(),
+            0
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_guest_panic_action() {
+        // Real mode code signaling a guest panic, then powering the guest off:
+        //   mov dx, 0x505
+        //   mov al, 1
+        //   out dx, al
+        //   hlt
+        let code = [0xba, 0x05, 0x05, 0xb0, 0x01, 0xee, 0xf4];
+        let new_instance_info = || {
+            Arc::new(RwLock::new(InstanceInfo {
+                id: "guest-panic".to_string(),
+                state: InstanceState::Running,
+                vmm_version: "1.0".to_string(),
+                stop_reason: None,
+            }))
+        };
+
+        // The default action stops the guest.
+        let (_vm, mut vcpu) = setup_real_mode_vcpu(0, &code);
+        let instance_info = new_instance_info();
+        vcpu.set_instance_info(instance_info.clone());
+        vcpu.run(Arc::new(Barrier::new(1)), 0, EventFd::new().unwrap());
+        assert_eq!(
+            instance_info.read().unwrap().stop_reason,
+            Some(StopReason::GuestPanic)
+        );
+
+        // The guest keeps running until it powers off.
+        let (_vm, mut vcpu) = setup_real_mode_vcpu(0, &code);
+        let instance_info = new_instance_info();
+        vcpu.set_instance_info(instance_info.clone());
+        let paused = Arc::new(AtomicBool::new(false));
+        vcpu.set_guest_panic_action(GuestPanicAction::Continue, paused.clone());
+        vcpu.run(Arc::new(Barrier::new(1)), 0, EventFd::new().unwrap());
+        assert_eq!(
+            instance_info.read().unwrap().stop_reason,
+            Some(StopReason::GuestShutdown)
+        );
+        assert!(!paused.load(Ordering::SeqCst));
+
+        // The vCPU pauses, and never gets to power the guest off.
+        let (_vm, mut vcpu) = setup_real_mode_vcpu(0, &code);
+        let instance_info = new_instance_info();
+        vcpu.set_instance_info(instance_info.clone());
+        vcpu.set_guest_panic_action(GuestPanicAction::Pause, paused.clone());
+        // The paused vCPU thread is left parked.
+        thread::spawn(move || vcpu.run(Arc::new(Barrier::new(1)), 0, EventFd::new().unwrap()));
+        let mut retries = 100;
+        while instance_info.read().unwrap().state != InstanceState::Paused {
+            retries -= 1;
+            assert!(retries > 0, "The vCPU didn't pause");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(paused.load(Ordering::SeqCst));
+        thread::sleep(Duration::from_millis(50));
+        assert!(instance_info.read().unwrap().stop_reason.is_none());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vcpu_handle() {
+        Vcpu::register_kick_signal_handler().unwrap();
+
+        // Real mode code looping in the guest, where only a kick gets the vCPU out:
+        //   jmp $
+        let (_vm, mut vcpu) = setup_real_mode_vcpu_with_irqchip(0, &[0xeb, 0xfe], true);
+        let paused = Arc::new(AtomicBool::new(false));
+        vcpu.set_guest_panic_action(GuestPanicAction::default(), paused.clone());
+        let (requests, states) = vcpu.request_channels();
+        let thread =
+            thread::spawn(move || vcpu.run(Arc::new(Barrier::new(1)), 0, EventFd::new().unwrap()));
+        let handle = VcpuHandle::new(0, thread, requests, states);
+        let rip = |state: &[u8]| {
+            // The instruction pointer follows the 16 general purpose registers.
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&state[16 * 8..17 * 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        paused.store(true, Ordering::SeqCst);
+        assert_eq!(rip(&handle.save_state().unwrap()), 0x1000);
+        // The state can be saved again while the vCPU is paused.
+        assert_eq!(rip(&handle.save_state().unwrap()), 0x1000);
+
+        // The vCPU runs the guest again until the next pause.
+        paused.store(false, Ordering::SeqCst);
+        handle.resume();
+        thread::sleep(Duration::from_millis(10));
+        paused.store(true, Ordering::SeqCst);
+        assert_eq!(rip(&handle.save_state().unwrap()), 0x1000);
+        // The paused vCPU thread is left parked once the handle is dropped.
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_guest_panic_mmio() {
+        // Real mode code signaling a guest panic to a pvpanic device past the guest memory, then
+        // powering the guest off:
+        //   mov ax, 0x2000
+        //   mov ds, ax
+        //   mov byte [0], 1
+        //   hlt
+        let code = [
+            0xb8, 0x00, 0x20, 0x8e, 0xd8, 0xc6, 0x06, 0x00, 0x00, 0x01, 0xf4,
+        ];
+        let instance_info = Arc::new(RwLock::new(InstanceInfo {
+            id: "guest-panic".to_string(),
+            state: InstanceState::Running,
+            vmm_version: "1.0".to_string(),
+            stop_reason: None,
+        }));
+
+        // Without a pvpanic device there, the write is a plain MMIO write.
+        let (_vm, mut vcpu) = setup_real_mode_vcpu(0, &code);
+        vcpu.set_instance_info(instance_info.clone());
+        vcpu.set_mmio_bus(devices::Bus::new());
+        vcpu.run(Arc::new(Barrier::new(1)), 0, EventFd::new().unwrap());
+        assert_eq!(
+            instance_info.write().unwrap().stop_reason.take(),
+            Some(StopReason::GuestShutdown)
+        );
+
+        let (_vm, mut vcpu) = setup_real_mode_vcpu(0, &code);
+        vcpu.set_instance_info(instance_info.clone());
+        vcpu.set_mmio_bus(devices::Bus::new());
+        vcpu.set_pvpanic_addr(0x20000);
+        vcpu.run(Arc::new(Barrier::new(1)), 0, EventFd::new().unwrap());
+        assert_eq!(
+            instance_info.read().unwrap().stop_reason,
+            Some(StopReason::GuestPanic)
+        );
+    }
+
     #[test]
     fn not_enough_mem_slots() {
         let kvm_fd = Kvm::new().unwrap();
@@ -0,0 +1,72 @@
+// Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Watchdog which detects a guest that stopped making forward progress.
+//!
+//! The guest signals that it is alive by periodically writing a magic value to the magic I/O
+//! port which is also used for signaling the end of the boot. This is only supported on x86_64.
+
+use std::time::Duration;
+
+use event_counter::EventCounter;
+
+/// Checks periodically that the guest is alive.
+pub struct Watchdog {
+    timeout: Duration,
+    heartbeat: EventCounter,
+    last_count: usize,
+}
+
+impl Watchdog {
+    /// Creates a watchdog which expects the guest to signal it is alive at least once in
+    /// every `timeout` interval.
+    pub fn new(timeout: Duration) -> Self {
+        Watchdog {
+            timeout,
+            heartbeat: EventCounter::default(),
+            last_count: 0,
+        }
+    }
+
+    /// Returns the interval in which the guest is expected to signal it is alive.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns a handle through which the vCPUs record the signs of life from the guest.
+    pub fn heartbeat(&self) -> EventCounter {
+        self.heartbeat.clone()
+    }
+
+    /// Returns whether the guest signaled it is alive since the previous check.
+    pub fn check(&mut self) -> bool {
+        let count = self.heartbeat.count();
+        let alive = count != self.last_count;
+        self.last_count = count;
+        alive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let mut watchdog = Watchdog::new(Duration::from_millis(100));
+        assert_eq!(watchdog.timeout(), Duration::from_millis(100));
+
+        // The guest is stalled.
+        assert!(!watchdog.check());
+
+        let heartbeat = watchdog.heartbeat();
+        heartbeat.record();
+        heartbeat.record();
+        assert!(watchdog.check());
+        // The previous heartbeats don't count for the next check.
+        assert!(!watchdog.check());
+
+        heartbeat.record();
+        assert!(watchdog.check());
+    }
+}